@@ -15,7 +15,7 @@ pub enum AnalogActuatorError {
 }
 
 /// Different modes to determine when a switch is actuated
-#[derive(Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub enum AnalogAcutationMode {
     /// Key is considered actuated when the key is past the actuation point, and released when it
     /// is above the actuation point.
@@ -33,8 +33,8 @@ pub enum AnalogAcutationMode {
 
 /// Analog matrix actuator
 pub struct AnalogActuator<const CS: usize, const RS: usize> {
-    press_threshold: u8,
-    release_threshold: u8,
+    press_thresholds: [[u8; CS]; RS],
+    release_thresholds: [[u8; CS]; RS],
     modes: [[AnalogAcutationMode; CS]; RS],
     actuation_points: [[u8; CS]; RS],
     cur_state: [[u8; CS]; RS],
@@ -53,8 +53,8 @@ impl<const CS: usize, const RS: usize> AnalogActuator<CS, RS> {
     ) -> Self {
         Self {
             modes,
-            press_threshold: 5,
-            release_threshold: 5,
+            press_thresholds: [[5; CS]; RS],
+            release_thresholds: [[5; CS]; RS],
             cur_state: [[0; CS]; RS],
             new_state: [[0; CS]; RS],
             cur_actuated: [[false; CS]; RS],
@@ -97,14 +97,39 @@ impl<const CS: usize, const RS: usize> AnalogActuator<CS, RS> {
             .ok_or(AnalogActuatorError::InvalidLocation)
     }
 
-    /// Update the threshold to register a key press
+    /// Update the threshold to register a key press, for all keys
     pub fn set_press_threshold(&mut self, press_threshold: u8) {
-        self.press_threshold = press_threshold;
+        self.press_thresholds = [[press_threshold; CS]; RS];
     }
 
-    /// Update the threshold to register a key release
+    /// Update the threshold to register a key release, for all keys
     pub fn set_release_threshold(&mut self, release_threshold: u8) {
-        self.release_threshold = release_threshold;
+        self.release_thresholds = [[release_threshold; CS]; RS];
+    }
+
+    /// Set the thresholds to register a press and a release for a given key. In the rapid
+    /// modes, this is how far the key must travel in the opposite direction to be pressed or
+    /// released again, so lower values make the key more sensitive.
+    pub fn set_thresholds(
+        &mut self,
+        row: usize,
+        col: usize,
+        press_threshold: u8,
+        release_threshold: u8,
+    ) -> Result<(), AnalogActuatorError> {
+        self.press_thresholds
+            .get_mut(row)
+            .and_then(|row| row.get_mut(col))
+            .zip(
+                self.release_thresholds
+                    .get_mut(row)
+                    .and_then(|row| row.get_mut(col)),
+            )
+            .map(|(press, release)| {
+                *press = press_threshold;
+                *release = release_threshold;
+            })
+            .ok_or(AnalogActuatorError::InvalidLocation)
     }
 
     /// Iterates on the `Event`s generated by the update.
@@ -133,25 +158,34 @@ impl<const CS: usize, const RS: usize> AnalogActuator<CS, RS> {
     pub fn events(&mut self, new: [[u8; CS]; RS]) -> impl Iterator<Item = Event> + '_ {
         self.new_state = new;
 
-        let press_threshold = self.press_threshold;
-        let release_threshold = self.release_threshold;
-
         self.cur_state
             .iter_mut()
             .zip(self.cur_actuated.iter_mut())
             .zip(self.actuation_points.iter())
             .zip(self.modes.iter())
             .zip(self.new_state.iter())
+            .zip(
+                self.press_thresholds
+                    .iter()
+                    .zip(self.release_thresholds.iter()),
+            )
             .enumerate()
-            .flat_map(move |(row, ((((o, a), p), m), n))| {
+            .flat_map(move |(row, (((((o, a), p), m), n), (pt, rt)))| {
                 o.iter_mut()
                     .zip(a.iter_mut())
                     .zip(p.iter())
                     .zip(m.iter())
                     .zip(n.iter())
+                    .zip(pt.iter().zip(rt.iter()))
                     .enumerate()
                     .filter_map(
-                        move |(col, ((((cur, actuated), actuation_point), mode), new))| {
+                        move |(
+                            col,
+                            (
+                                ((((cur, actuated), actuation_point), mode), new),
+                                (&press_threshold, &release_threshold),
+                            ),
+                        )| {
                             let mut event = None;
 
                             match mode {
@@ -229,3 +263,111 @@ impl<const CS: usize, const RS: usize> AnalogActuator<CS, RS> {
             })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{AnalogActuator, AnalogAcutationMode};
+    use crate::layout::{Event, Event::*};
+
+    fn actuator(mode: AnalogAcutationMode) -> AnalogActuator<1, 1> {
+        // Actuation point at 100, and the default thresholds of 5
+        AnalogActuator::new([[mode]], [[100]])
+    }
+
+    fn update(actuator: &mut AnalogActuator<1, 1>, value: u8) -> Option<Event> {
+        actuator.events([[value]]).next()
+    }
+
+    #[test]
+    fn rapid_actuate() {
+        let mut actuator = actuator(AnalogAcutationMode::Rapid);
+
+        assert_eq!(None, update(&mut actuator, 0));
+        assert_eq!(None, update(&mut actuator, 99));
+        assert_eq!(Some(Press(0, 0)), update(&mut actuator, 100));
+
+        // Pressing further doesn't actuate again
+        assert_eq!(None, update(&mut actuator, 200));
+        assert_eq!(None, update(&mut actuator, 255));
+    }
+
+    #[test]
+    fn rapid_release_on_reverse() {
+        let mut actuator = actuator(AnalogAcutationMode::Rapid);
+
+        assert_eq!(Some(Press(0, 0)), update(&mut actuator, 200));
+
+        // Moving back up by less than the release threshold is ignored
+        assert_eq!(None, update(&mut actuator, 196));
+        assert_eq!(None, update(&mut actuator, 195));
+
+        // The key is released as soon as it moves back up past the threshold, even though it is
+        // still past the actuation point
+        assert_eq!(Some(Release(0, 0)), update(&mut actuator, 194));
+        assert_eq!(None, update(&mut actuator, 150));
+    }
+
+    #[test]
+    fn rapid_release_threshold_follows_deepest_point() {
+        let mut actuator = actuator(AnalogAcutationMode::Rapid);
+
+        assert_eq!(Some(Press(0, 0)), update(&mut actuator, 150));
+        assert_eq!(None, update(&mut actuator, 220));
+
+        // Releases are measured from the deepest point reached, not from the actuation
+        assert_eq!(None, update(&mut actuator, 215));
+        assert_eq!(Some(Release(0, 0)), update(&mut actuator, 214));
+    }
+
+    #[test]
+    fn rapid_re_actuate() {
+        let mut actuator = actuator(AnalogAcutationMode::Rapid);
+
+        assert_eq!(Some(Press(0, 0)), update(&mut actuator, 200));
+        assert_eq!(Some(Release(0, 0)), update(&mut actuator, 180));
+
+        // Presses are measured from the highest point reached after the release
+        assert_eq!(None, update(&mut actuator, 170));
+        assert_eq!(None, update(&mut actuator, 175));
+        assert_eq!(Some(Press(0, 0)), update(&mut actuator, 176));
+
+        assert_eq!(Some(Release(0, 0)), update(&mut actuator, 170));
+        assert_eq!(Some(Press(0, 0)), update(&mut actuator, 176));
+    }
+
+    #[test]
+    fn rapid_deadzone() {
+        let mut actuator = actuator(AnalogAcutationMode::Rapid);
+
+        // Movement above the actuation point never actuates the key
+        assert_eq!(None, update(&mut actuator, 50));
+        assert_eq!(None, update(&mut actuator, 20));
+        assert_eq!(None, update(&mut actuator, 90));
+
+        assert_eq!(Some(Press(0, 0)), update(&mut actuator, 120));
+        assert_eq!(Some(Release(0, 0)), update(&mut actuator, 80));
+
+        // After a release, the key must go past the actuation point again
+        assert_eq!(None, update(&mut actuator, 60));
+        assert_eq!(None, update(&mut actuator, 99));
+        assert_eq!(Some(Press(0, 0)), update(&mut actuator, 100));
+    }
+
+    #[test]
+    fn continuous_rapid_ignores_deadzone_until_fully_released() {
+        let mut actuator = actuator(AnalogAcutationMode::ContinuousRapid);
+
+        assert_eq!(None, update(&mut actuator, 99));
+        assert_eq!(Some(Press(0, 0)), update(&mut actuator, 100));
+        assert_eq!(Some(Release(0, 0)), update(&mut actuator, 40));
+
+        // The key can be actuated again above the actuation point
+        assert_eq!(None, update(&mut actuator, 30));
+        assert_eq!(Some(Press(0, 0)), update(&mut actuator, 36));
+
+        // Once fully released, the key must go past the actuation point again
+        assert_eq!(Some(Release(0, 0)), update(&mut actuator, 0));
+        assert_eq!(None, update(&mut actuator, 50));
+        assert_eq!(Some(Press(0, 0)), update(&mut actuator, 100));
+    }
+}
@@ -77,6 +77,26 @@ pub type Layers<
 /// Events can be retrieved by iterating over this struct and calling [Stacked::event].
 type Stack = ArrayDeque<[Stacked; 16], arraydeque::behavior::Wrapping>;
 
+/// A function that can change the timeout and configuration of a
+/// [`HoldTapAction`] when its key is pressed.
+///
+/// It is called with the coordinates of the key, and the timeout and
+/// configuration defined in the layout. It returns the timeout and
+/// configuration to use for this key press.
+pub type HoldTapOverride = fn((u8, u8), u16, HoldTapConfig) -> (u16, HoldTapConfig);
+
+/// Row used by the coordinates of virtual keys.
+///
+/// Virtual keys are not part of the layers. Their actions are set with
+/// [`Layout::set_virtual_key`], and they are pressed and released with
+/// events on `(VIRTUAL_ROW, index)`. This can be used to trigger
+/// actions that are not on the switch matrix, like the result of a
+/// combo.
+pub const VIRTUAL_ROW: u8 = u8::MAX;
+
+/// The number of virtual keys available in a [`Layout`].
+pub const VIRTUAL_KEYS: usize = 16;
+
 /// The layout manager. It takes `Event`s and `tick`s as input, and
 /// generate keyboard reports.
 pub struct Layout<
@@ -98,6 +118,9 @@ pub struct Layout<
     active_sequences: ArrayDeque<[SequenceState; 4], arraydeque::behavior::Wrapping>,
     stacked: Stack,
     tap_hold_tracker: TapHoldTracker,
+    hold_tap_override: Option<HoldTapOverride>,
+    virtual_keys: [Action<T, K>; VIRTUAL_KEYS],
+    sequence_delay: u32,
 }
 
 /// An event on the key matrix.
@@ -595,8 +618,22 @@ impl<
             active_sequences: ArrayDeque::new(),
             stacked: ArrayDeque::new(),
             tap_hold_tracker: Default::default(),
+            hold_tap_override: None,
+            virtual_keys: [Action::NoOp; VIRTUAL_KEYS],
+            sequence_delay: 0,
         }
     }
+    /// Sets a function that can change the timeout and configuration of
+    /// `HoldTap` actions when they are pressed. See [`HoldTapOverride`].
+    pub fn set_hold_tap_override(&mut self, hold_tap_override: Option<HoldTapOverride>) {
+        self.hold_tap_override = hold_tap_override;
+    }
+    /// Sets the number of ticks to wait after each key press or
+    /// release done by a sequence, before processing the rest of the
+    /// sequence. Some hosts drop keys that are typed too quickly.
+    pub fn set_sequence_delay(&mut self, ticks: u32) {
+        self.sequence_delay = ticks;
+    }
     /// Iterates on the key codes of the current state.
     pub fn keycodes(&self) -> impl Iterator<Item = K> + '_ {
         self.states.iter().filter_map(State::keycode)
@@ -697,6 +734,8 @@ impl<
                     continue;
                 }
 
+                let is_delay = matches!(sequence.remaining_bytes, [1, 4, ..]);
+
                 match sequence.remaining_bytes {
                     [1, 1, keycode, ..] if !sequence.tap_in_progress => {
                         sequence.tap_in_progress = true;
@@ -791,6 +830,11 @@ impl<
                     }
                 };
 
+                // wait before the next key event, unless a delay was just set
+                if !is_delay {
+                    sequence.delay = self.sequence_delay;
+                }
+
                 // if the sequence is not done, add it back to the list of active sequences
                 self.active_sequences.push_back(sequence);
             }
@@ -913,6 +957,12 @@ impl<
     }
     fn press_as_action(&self, coord: (u8, u8), layer: usize) -> Action<T, K> {
         use crate::action::Action::*;
+        if coord.0 == VIRTUAL_ROW {
+            return match self.virtual_keys.get(coord.1 as usize) {
+                Some(Trans) | None => NoOp,
+                Some(&action) => action,
+            };
+        }
         let action = self
             .layers
             .get(layer)
@@ -944,6 +994,35 @@ impl<
             .map(|a| *a = action)
             .ok_or(ChangeActionError::OutOfBounds)
     }
+    /// Sets the action of the virtual key at `(VIRTUAL_ROW, index)`. See
+    /// [`VIRTUAL_ROW`].
+    pub fn set_virtual_key(
+        &mut self,
+        index: u8,
+        action: Action<T, K>,
+    ) -> Result<(), ChangeActionError> {
+        self.virtual_keys
+            .get_mut(index as usize)
+            .map(|a| *a = action)
+            .ok_or(ChangeActionError::OutOfBounds)
+    }
+    /// Start typing a sequence, in the same format as
+    /// [`Action::Sequence`], without it being in the layout. This can be
+    /// used to type sequences created at runtime.
+    pub fn start_sequence(&mut self, sequence: &'static [u8]) {
+        self.active_sequences.push_back(SequenceState {
+            remaining_bytes: sequence,
+            delay: 0,
+            tap_in_progress: false,
+            ascii_in_progress: false,
+        });
+    }
+    /// Get the action that would be done if the given key was pressed
+    /// on the current layer. Transparent actions are resolved using
+    /// the default layer.
+    pub fn resolve_action(&self, coord: (u8, u8)) -> Action<T, K> {
+        self.press_as_action(coord, self.current_layer())
+    }
     /// Get a copy of the action for a given key
     pub fn get_action(&mut self, coord: (u8, u8), layer: usize) -> Option<Action<T, K>> {
         self.layers
@@ -976,13 +1055,17 @@ impl<
                     || coord != self.tap_hold_tracker.coord
                     || self.tap_hold_tracker.timeout == 0
                 {
+                    let (timeout, config) = match self.hold_tap_override {
+                        Some(hold_tap_override) => hold_tap_override(coord, *timeout, *config),
+                        None => (*timeout, *config),
+                    };
                     let waiting: WaitingState<T, K> = WaitingState {
                         coord,
-                        timeout: *timeout,
+                        timeout,
                         delay,
                         hold,
                         tap,
-                        config: *config,
+                        config,
                     };
                     self.waiting = Some(waiting);
                     self.tap_hold_tracker.timeout = *tap_hold_interval;
@@ -1153,6 +1236,17 @@ impl<
             .unwrap_or(self.default_layer)
     }
 
+    /// Obtain the indices of all active layers, starting with the default layer, followed by the
+    /// layers in the order that they were activated
+    pub fn active_layers(&self) -> impl Iterator<Item = usize> + '_ {
+        core::iter::once(self.default_layer).chain(self.states.iter().filter_map(State::get_layer))
+    }
+
+    /// Obtain the index of the default layer
+    pub fn default_layer(&self) -> usize {
+        self.default_layer
+    }
+
     /// Sets the default layer for the layout
     pub fn set_default_layer(&mut self, value: usize) {
         if value < self.layers.len() {
@@ -1169,7 +1263,7 @@ mod test {
     use crate::action::HoldTapConfig;
     use crate::action::OneShotAction;
     use crate::action::TapDanceAction;
-    use crate::action::{k, l, m, t};
+    use crate::action::{d, k, l, m, t};
     use crate::key_code::KeyCode;
     use crate::key_code::KeyCode::*;
     use std::collections::BTreeSet;
@@ -1231,6 +1325,185 @@ mod test {
         assert_keys(&[], layout.keycodes());
     }
 
+    #[test]
+    fn hold_tap_override() {
+        static mut LAYERS: Layers<2, 1, 1> = [[[
+            HoldTap(&HoldTapAction {
+                timeout: 200,
+                hold: k(LAlt),
+                tap: k(Space),
+                config: HoldTapConfig::Default,
+                tap_hold_interval: 0,
+            }),
+            k(Enter),
+        ]]];
+        let mut layout = Layout::new(unsafe { &mut LAYERS });
+        layout.set_hold_tap_override(Some(|_, _, _| (10, HoldTapConfig::HoldOnOtherKeyPress)));
+
+        // Hold after the overridden timeout
+        layout.event(Press(0, 0));
+        for _ in 0..10 {
+            assert_eq!(CustomEvent::NoEvent, layout.tick());
+            assert_keys(&[], layout.keycodes());
+        }
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[LAlt], layout.keycodes());
+        layout.event(Release(0, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[], layout.keycodes());
+
+        // Hold when another key is pressed, using the overridden config
+        layout.event(Press(0, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        layout.event(Press(0, 1));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[LAlt], layout.keycodes());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[LAlt, Enter], layout.keycodes());
+        layout.event(Release(0, 1));
+        layout.event(Release(0, 0));
+        for _ in 0..3 {
+            assert_eq!(CustomEvent::NoEvent, layout.tick());
+        }
+        assert_keys(&[], layout.keycodes());
+
+        // Removing the override restores the timeout from the layout
+        layout.set_hold_tap_override(None);
+        layout.event(Press(0, 0));
+        for _ in 0..200 {
+            assert_eq!(CustomEvent::NoEvent, layout.tick());
+            assert_keys(&[], layout.keycodes());
+        }
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[LAlt], layout.keycodes());
+    }
+
+    #[test]
+    fn virtual_keys() {
+        static mut LAYERS: Layers<2, 1, 2> = [[[l(1), k(A)]], [[Trans, k(B)]]];
+        let mut layout = Layout::new(unsafe { &mut LAYERS });
+        assert!(layout.set_virtual_key(VIRTUAL_KEYS as u8, k(C)).is_err());
+        layout.set_virtual_key(0, k(C)).unwrap();
+        layout.set_virtual_key(1, l(1)).unwrap();
+
+        layout.event(Press(VIRTUAL_ROW, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[C], layout.keycodes());
+        layout.event(Release(VIRTUAL_ROW, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[], layout.keycodes());
+
+        // Virtual keys can change the layer, and don't depend on it
+        assert!(matches!(layout.resolve_action((0, 1)), KeyCode(A)));
+        layout.event(Press(VIRTUAL_ROW, 1));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert!(matches!(layout.resolve_action((0, 1)), KeyCode(B)));
+        assert!(matches!(layout.resolve_action((0, 0)), Layer(1)));
+        layout.event(Press(VIRTUAL_ROW, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[C], layout.keycodes());
+        layout.event(Release(VIRTUAL_ROW, 0));
+        layout.event(Release(VIRTUAL_ROW, 1));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[], layout.keycodes());
+        assert_eq!(0, layout.current_layer());
+
+        // Unset virtual keys do nothing
+        layout.event(Press(VIRTUAL_ROW, 2));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[], layout.keycodes());
+    }
+
+    #[test]
+    fn start_sequence() {
+        static mut LAYERS: Layers<1, 1, 1> = [[[k(A)]]];
+        static SEQUENCE: [u8; 9] = [1, 2, LShift as u8, 1, 1, B as u8, 1, 3, LShift as u8];
+        let mut layout = Layout::new(unsafe { &mut LAYERS });
+
+        layout.start_sequence(&SEQUENCE);
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[LShift], layout.keycodes());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[LShift, B], layout.keycodes());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[LShift], layout.keycodes());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[], layout.keycodes());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[], layout.keycodes());
+    }
+
+    #[test]
+    fn sequence_delay() {
+        static mut LAYERS: Layers<1, 1, 1> = [[[k(A)]]];
+        static SEQUENCE: [u8; 6] = [1, 1, B as u8, 1, 1, C as u8];
+        let mut layout = Layout::new(unsafe { &mut LAYERS });
+        layout.set_sequence_delay(1);
+
+        layout.start_sequence(&SEQUENCE);
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[B], layout.keycodes());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[B], layout.keycodes());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[], layout.keycodes());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[], layout.keycodes());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[C], layout.keycodes());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[C], layout.keycodes());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[], layout.keycodes());
+    }
+
+    #[test]
+    fn default_layer() {
+        static mut LAYERS: Layers<2, 1, 2> = [[[d(1), k(A)]], [[d(0), k(B)]]];
+        let mut layout = Layout::new(unsafe { &mut LAYERS });
+        assert_eq!(0, layout.default_layer());
+
+        layout.event(Press(0, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_eq!(1, layout.default_layer());
+        layout.event(Release(0, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        layout.event(Press(0, 1));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[B], layout.keycodes());
+        layout.event(Release(0, 1));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+
+        // Layers that don't exist are ignored
+        layout.set_default_layer(2);
+        assert_eq!(1, layout.default_layer());
+        layout.set_default_layer(0);
+        assert_eq!(0, layout.default_layer());
+    }
+
+    #[test]
+    fn active_layers() {
+        static mut LAYERS: Layers<2, 1, 3> = [[[l(1), t(2)]], [[Trans, Trans]], [[Trans, Trans]]];
+        let mut layout = Layout::new(unsafe { &mut LAYERS });
+        assert!(layout.active_layers().eq([0]));
+
+        layout.event(Press(0, 1));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        layout.event(Release(0, 1));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert!(layout.active_layers().eq([0, 2]));
+
+        layout.event(Press(0, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert!(layout.active_layers().eq([0, 2, 1]));
+        assert_eq!(1, layout.current_layer());
+
+        layout.event(Release(0, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert!(layout.active_layers().eq([0, 2]));
+    }
+
     #[test]
     fn hold_tap_interleaved_timeout() {
         static mut LAYERS: Layers<2, 1, 1> = [[[
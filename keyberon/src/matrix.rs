@@ -90,6 +90,348 @@ where
     {
         self.get_with_delay(|| ())
     }
+
+    /// Pulls every row pin low, so that pressing any key pulls its
+    /// column low. This can be used to wait for a key to be pressed
+    /// without scanning the matrix. Call [`Matrix::release_rows`] before
+    /// scanning the matrix again.
+    pub fn drive_all_rows<E>(&mut self) -> Result<(), E>
+    where
+        C: InputPin<Error = E>,
+        R: OutputPin<Error = E>,
+    {
+        for r in self.rows.iter_mut() {
+            r.set_low()?;
+        }
+        Ok(())
+    }
+
+    /// Sets every row pin high, which is the state they are left in
+    /// after scanning the matrix.
+    pub fn release_rows<E>(&mut self) -> Result<(), E>
+    where
+        C: InputPin<Error = E>,
+        R: OutputPin<Error = E>,
+    {
+        self.clear()
+    }
+
+    /// Returns the column pins.
+    pub fn cols_mut(&mut self) -> &mut [C; CS] {
+        &mut self.cols
+    }
+}
+
+/// Keys found by scanning a [`DuplexMatrix`]: the keys found by pulling
+/// the rows low, followed by the keys found by pulling the columns low.
+/// Both are indexed by row, then column.
+pub type DuplexScan<const CS: usize, const RS: usize> = ([[bool; CS]; RS], [[bool; CS]; RS]);
+
+/// Matrix where each row and column pair is connected to two switches,
+/// with their diodes facing opposite directions (a "duplex matrix").
+/// This allows twice as many switches to be scanned using the same
+/// number of pins.
+///
+/// The matrix is scanned in both directions: first, every row pin in
+/// order is pulled low while reading the columns, and then every
+/// column pin in order is pulled low while reading the rows. For this
+/// to work, every pin must be configured as an open-drain output with
+/// a pull-up, which can still be read while it is not pulled low.
+///
+/// Generic parameters are in order: The type of pins, the number of
+/// columns and rows.
+pub struct DuplexMatrix<P, const CS: usize, const RS: usize>
+where
+    P: InputPin + OutputPin,
+{
+    cols: [P; CS],
+    rows: [P; RS],
+}
+
+impl<P, const CS: usize, const RS: usize> DuplexMatrix<P, CS, RS>
+where
+    P: InputPin + OutputPin,
+{
+    /// Creates a new DuplexMatrix.
+    ///
+    /// Assumes all pins are open-drain outputs with pull-ups, which are
+    /// set high (released) when not being scanned.
+    pub fn new<E>(cols: [P; CS], rows: [P; RS]) -> Result<Self, E>
+    where
+        P: InputPin<Error = E> + OutputPin<Error = E>,
+    {
+        let mut res = Self { cols, rows };
+        res.clear()?;
+        Ok(res)
+    }
+    fn clear<E>(&mut self) -> Result<(), E>
+    where
+        P: InputPin<Error = E> + OutputPin<Error = E>,
+    {
+        for p in self.rows.iter_mut().chain(self.cols.iter_mut()) {
+            p.set_high()?;
+        }
+        Ok(())
+    }
+    /// Scans the matrix and checks which keys are pressed.
+    ///
+    /// Returns the keys that were found by pulling the rows low, followed
+    /// by the keys that were found by pulling the columns low. Both are
+    /// indexed by row, then column.
+    ///
+    /// Delay function allows pause to let input pins settle
+    pub fn get_with_delay<F: FnMut(), E>(&mut self, mut delay: F) -> Result<DuplexScan<CS, RS>, E>
+    where
+        P: InputPin<Error = E> + OutputPin<Error = E>,
+    {
+        let mut row_to_col = [[false; CS]; RS];
+        let mut col_to_row = [[false; CS]; RS];
+
+        for (ri, row) in self.rows.iter_mut().enumerate() {
+            row.set_low()?;
+            delay();
+            for (ci, col) in self.cols.iter().enumerate() {
+                if col.is_low()? {
+                    row_to_col[ri][ci] = true;
+                }
+            }
+            row.set_high()?;
+        }
+
+        for (ci, col) in self.cols.iter_mut().enumerate() {
+            col.set_low()?;
+            delay();
+            for (ri, row) in self.rows.iter().enumerate() {
+                if row.is_low()? {
+                    col_to_row[ri][ci] = true;
+                }
+            }
+            col.set_high()?;
+        }
+
+        Ok((row_to_col, col_to_row))
+    }
+
+    /// Scans the matrix and checks which keys are pressed.
+    ///
+    /// Returns the keys that were found by pulling the rows low, followed
+    /// by the keys that were found by pulling the columns low. Both are
+    /// indexed by row, then column.
+    pub fn get<E>(&mut self) -> Result<DuplexScan<CS, RS>, E>
+    where
+        P: InputPin<Error = E> + OutputPin<Error = E>,
+    {
+        self.get_with_delay(|| ())
+    }
+}
+
+/// Matrix without diodes, where a switch is connected between every
+/// pair of pins (a "round-robin matrix"). `N` pins can scan up to
+/// `N * (N - 1) / 2` switches, which is useful for small macropads.
+///
+/// Every pin in order is pulled low while reading the pins after it.
+/// For this to work, every pin must be configured as an open-drain
+/// output with a pull-up, which can still be read while it is not
+/// pulled low. Since there are no diodes, pressing 3 switches that
+/// share pins may cause ghosting.
+///
+/// Generic parameters are in order: The type of pins, and the number of
+/// pins.
+pub struct RoundRobinMatrix<P, const N: usize>
+where
+    P: InputPin + OutputPin,
+{
+    pins: [P; N],
+}
+
+impl<P, const N: usize> RoundRobinMatrix<P, N>
+where
+    P: InputPin + OutputPin,
+{
+    /// Creates a new RoundRobinMatrix.
+    ///
+    /// Assumes all pins are open-drain outputs with pull-ups, which are
+    /// set high (released) when not being scanned.
+    pub fn new<E>(pins: [P; N]) -> Result<Self, E>
+    where
+        P: InputPin<Error = E> + OutputPin<Error = E>,
+    {
+        let mut res = Self { pins };
+        res.clear()?;
+        Ok(res)
+    }
+    fn clear<E>(&mut self) -> Result<(), E>
+    where
+        P: InputPin<Error = E> + OutputPin<Error = E>,
+    {
+        for p in self.pins.iter_mut() {
+            p.set_high()?;
+        }
+        Ok(())
+    }
+    /// Scans the matrix and checks which keys are pressed.
+    ///
+    /// The switch between pin `a` and pin `b` (where `a < b`) is at row
+    /// `a` and column `b`. Keys where the column is not greater than the
+    /// row are never pressed.
+    ///
+    /// Delay function allows pause to let input pins settle
+    pub fn get_with_delay<F: FnMut(), E>(&mut self, mut delay: F) -> Result<[[bool; N]; N], E>
+    where
+        P: InputPin<Error = E> + OutputPin<Error = E>,
+    {
+        let mut keys = [[false; N]; N];
+
+        for (a, row) in keys.iter_mut().enumerate() {
+            let (pin, rest) = self.pins[a..].split_first_mut().unwrap();
+            pin.set_low()?;
+            delay();
+            for (b, other) in rest.iter().enumerate() {
+                if other.is_low()? {
+                    row[a + 1 + b] = true;
+                }
+            }
+            pin.set_high()?;
+        }
+
+        Ok(keys)
+    }
+
+    /// Scans the matrix and checks which keys are pressed.
+    ///
+    /// The switch between pin `a` and pin `b` (where `a < b`) is at row
+    /// `a` and column `b`. Keys where the column is not greater than the
+    /// row are never pressed.
+    pub fn get<E>(&mut self) -> Result<[[bool; N]; N], E>
+    where
+        P: InputPin<Error = E> + OutputPin<Error = E>,
+    {
+        self.get_with_delay(|| ())
+    }
+}
+
+/// Matrix where the columns are strobed using one or more chained
+/// 74HC595 shift registers, instead of MCU pins.
+///
+/// Generic parameters are in order: The type of row pins, the type of
+/// shift register pins, the number of columns and rows. The first column
+/// is connected to the first output (QA) of the first shift register,
+/// and the following columns are connected to the next outputs in the
+/// chain.
+pub struct ShiftRegisterMatrix<R, O, const CS: usize, const RS: usize>
+where
+    R: InputPin,
+    O: OutputPin,
+{
+    rows: [R; RS],
+    data: O,
+    clock: O,
+    latch: O,
+}
+
+impl<R, O, const CS: usize, const RS: usize> ShiftRegisterMatrix<R, O, CS, RS>
+where
+    R: InputPin,
+    O: OutputPin,
+{
+    /// Creates a new ShiftRegisterMatrix.
+    ///
+    /// Assumes rows are pull-up inputs. `data`, `clock` and `latch`
+    /// are the output pins connected to the serial input (SER), shift
+    /// register clock (SRCLK) and storage register clock (RCLK) of the
+    /// first shift register. The outputs of the shift registers are set
+    /// high when not being scanned.
+    pub fn new<E>(rows: [R; RS], data: O, clock: O, latch: O) -> Result<Self, E>
+    where
+        R: InputPin<Error = E>,
+        O: OutputPin<Error = E>,
+    {
+        let mut res = Self {
+            rows,
+            data,
+            clock,
+            latch,
+        };
+        res.clear()?;
+        Ok(res)
+    }
+    fn clear<E>(&mut self) -> Result<(), E>
+    where
+        R: InputPin<Error = E>,
+        O: OutputPin<Error = E>,
+    {
+        self.clock.set_low()?;
+        self.latch.set_low()?;
+        for _ in 0..CS {
+            self.shift(true)?;
+        }
+        self.latch()
+    }
+    fn shift<E>(&mut self, high: bool) -> Result<(), E>
+    where
+        R: InputPin<Error = E>,
+        O: OutputPin<Error = E>,
+    {
+        if high {
+            self.data.set_high()?;
+        } else {
+            self.data.set_low()?;
+        }
+        self.clock.set_high()?;
+        self.clock.set_low()
+    }
+    fn latch<E>(&mut self) -> Result<(), E>
+    where
+        R: InputPin<Error = E>,
+        O: OutputPin<Error = E>,
+    {
+        self.latch.set_high()?;
+        self.latch.set_low()
+    }
+    /// Scans the matrix and checks which keys are pressed.
+    ///
+    /// A single low bit is shifted through the shift registers, so
+    /// that every column in order is pulled low, and then each row
+    /// pin is tested; if it's low, the key is marked as pressed.
+    ///
+    /// Delay function allows pause to let input pins settle
+    pub fn get_with_delay<F: FnMut(), E>(&mut self, mut delay: F) -> Result<[[bool; CS]; RS], E>
+    where
+        R: InputPin<Error = E>,
+        O: OutputPin<Error = E>,
+    {
+        let mut keys = [[false; CS]; RS];
+
+        for ci in 0..CS {
+            // Shifting the low bit forward selects the next column
+            self.shift(ci != 0)?;
+            self.latch()?;
+            delay();
+            for (row, row_keys) in self.rows.iter().zip(keys.iter_mut()) {
+                if row.is_low()? {
+                    row_keys[ci] = true;
+                }
+            }
+        }
+
+        // Shift the low bit past the last column, so that no column is selected
+        self.shift(true)?;
+        self.latch()?;
+        Ok(keys)
+    }
+
+    /// Scans the matrix and checks which keys are pressed.
+    ///
+    /// A single low bit is shifted through the shift registers, so
+    /// that every column in order is pulled low, and then each row
+    /// pin is tested; if it's low, the key is marked as pressed.
+    pub fn get<E>(&mut self) -> Result<[[bool; CS]; RS], E>
+    where
+        R: InputPin<Error = E>,
+        O: OutputPin<Error = E>,
+    {
+        self.get_with_delay(|| ())
+    }
 }
 
 /// Matrix-representation of switches directly attached to the pins ("diodeless").
@@ -158,6 +500,22 @@ impl<T, const CS: usize, const RS: usize> AnalogMatrix<T, CS, RS> {
     pub fn new(ranges: [[Range<T>; CS]; RS]) -> Self {
         Self { ranges }
     }
+
+    /// Obtain the range of raw ADC samples generated by the given key, from unpressed to fully
+    /// pressed. Returns `None` if the key does not exist.
+    pub fn range(&self, row: usize, col: usize) -> Option<&Range<T>> {
+        self.ranges.get(row).and_then(|row| row.get(col))
+    }
+
+    /// Change the range of raw ADC samples generated by the given key, from unpressed to fully
+    /// pressed. This can be used to calibrate the matrix. Returns the previous range, or `None`
+    /// if the key does not exist.
+    pub fn set_range(&mut self, row: usize, col: usize, range: Range<T>) -> Option<Range<T>> {
+        self.ranges
+            .get_mut(row)
+            .and_then(|row| row.get_mut(col))
+            .map(|key| core::mem::replace(key, range))
+    }
 }
 
 impl<T: SaturatingSub + PartialOrd, const CS: usize, const RS: usize> AnalogMatrix<T, CS, RS>
@@ -176,9 +534,14 @@ where
             cols.iter_mut().enumerate().try_for_each(|(col, key)| {
                 let value = get_press_value(row, col)?;
                 let Range { start, end } = &self.ranges[row][col];
-                *key = ((u32::from(clamp(&value, start, end).saturating_sub(start)))
+                // Unused positions have an empty range, and are never pressed
+                let range = u32::from(end.saturating_sub(start));
+                if let Some(pressed) = u32::from(clamp(&value, start, end).saturating_sub(start))
                     .saturating_mul(255)
-                    / u32::from(end.saturating_sub(start))) as u8;
+                    .checked_div(range)
+                {
+                    *key = pressed as u8;
+                }
                 Ok(())
             })?;
             Ok(())
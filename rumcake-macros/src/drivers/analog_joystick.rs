@@ -0,0 +1,20 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+
+pub fn driver_trait() -> TokenStream {
+    quote! {
+        /// A trait that keyboards must implement to set up the analog joystick driver. The axes are
+        /// read using the ADC sampler created by [`rumcake::hw::mcu::setup_adc_sampler`].
+        pub(crate) trait AnalogJoystickDriverSettings {
+            /// ADC channel and range of values for the X axis of the joystick.
+            const X_AXIS: ::rumcake::drivers::analog_joystick::JoystickAxis;
+
+            /// ADC channel and range of values for the Y axis of the joystick.
+            const Y_AXIS: ::rumcake::drivers::analog_joystick::JoystickAxis;
+
+            /// Joystick mode, dead zone, response curve and speed.
+            const JOYSTICK_CONFIG: ::rumcake::drivers::analog_joystick::JoystickConfig =
+                ::rumcake::drivers::analog_joystick::JoystickConfig::DEFAULT;
+        }
+    }
+}
@@ -1,9 +1,12 @@
 use proc_macro2::TokenStream;
 use quote::quote;
 
+pub mod analog_joystick;
 pub mod is31fl3731;
 pub mod nrf_ble;
+pub mod pmw33xx;
 pub mod ssd1306;
+pub mod trackpoint;
 pub mod ws2812;
 
 pub fn serial_driver_trait() -> TokenStream {
@@ -17,3 +20,15 @@ pub fn serial_driver_trait() -> TokenStream {
         }
     }
 }
+
+pub fn custom_pointing_driver_trait() -> TokenStream {
+    quote! {
+        /// A trait that must be implemented to use your own pointing device.
+        pub(crate) trait CustomPointingDriverSettings {
+            /// Set up the pointing device. This can return any implementor of
+            /// [`rumcake::pointing::PointingDevice`], including arrays and pairs of pointing
+            /// devices, which can be used to combine multiple devices.
+            fn setup_pointing_device() -> impl ::rumcake::pointing::PointingDevice;
+        }
+    }
+}
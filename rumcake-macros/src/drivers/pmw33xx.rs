@@ -0,0 +1,23 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+
+pub fn driver_trait() -> TokenStream {
+    quote! {
+        /// A trait that keyboards must implement to set up the PMW33xx driver.
+        pub(crate) trait Pmw33xxDriverSettings {
+            /// SROM firmware to upload to the sensor when it is initialized. The firmware is
+            /// provided by PixArt, and is not included in rumcake. If this is `None`, no firmware
+            /// is uploaded.
+            const FIRMWARE: Option<&'static [u8]> = None;
+
+            /// Setup the SPI bus used to communicate with the sensor. The bus must use SPI mode 3,
+            /// at a frequency of up to 2 MHz.
+            fn setup_spi() -> impl ::rumcake::embedded_hal_async::spi::SpiBus;
+
+            /// Setup the output pin connected to the sensor's chip select (`NCS`) pin.
+            ///
+            /// It is recommended to use [`rumcake::hw::mcu::output_pin`] to implement this function.
+            fn setup_cs_pin() -> impl ::rumcake::embedded_hal::digital::v2::OutputPin;
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+
+pub fn driver_trait() -> TokenStream {
+    quote! {
+        /// A trait that keyboards must implement to set up the TrackPoint driver.
+        pub(crate) trait TrackPointDriverSettings {
+            /// Sensitivity of the TrackPoint. Higher values make the cursor move faster.
+            const SENSITIVITY: u8 = ::rumcake::drivers::trackpoint::DEFAULT_SENSITIVITY;
+
+            /// Setup the pin connected to the TrackPoint's clock line. This must be an open-drain
+            /// pin with a pull-up, which supports waiting for edges.
+            ///
+            /// It is recommended to use [`rumcake::hw::mcu::open_drain_pin`] to implement this
+            /// function.
+            fn setup_clock_pin() -> impl ::rumcake::embedded_hal::digital::v2::InputPin
+                + ::rumcake::embedded_hal::digital::v2::OutputPin
+                + ::rumcake::embedded_hal_async::digital::Wait;
+
+            /// Setup the pin connected to the TrackPoint's data line. This must be an open-drain
+            /// pin with a pull-up.
+            ///
+            /// It is recommended to use [`rumcake::hw::mcu::open_drain_pin`] to implement this
+            /// function.
+            fn setup_data_pin() -> impl ::rumcake::embedded_hal::digital::v2::InputPin
+                + ::rumcake::embedded_hal::digital::v2::OutputPin;
+        }
+    }
+}
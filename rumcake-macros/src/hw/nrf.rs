@@ -1,4 +1,4 @@
-use proc_macro2::{Ident, TokenStream};
+use proc_macro2::{Ident, Span, TokenStream};
 use proc_macro_error::{abort, OptionExt};
 use quote::quote;
 use syn::parse::Parse;
@@ -9,19 +9,32 @@ use crate::common::{AnalogPinType, DirectPinDefinition, MultiplexerDefinition};
 
 pub const HAL_CRATE: &'static str = "embassy_nrf";
 
+pub const INTERRUPT_INPUT_PIN_TYPE: &'static str = "gpio::Input";
+
+pub const OPEN_DRAIN_PIN_TYPE: &'static str = "Flex";
+
 pub fn input_pin(ident: Ident) -> TokenStream {
+    input_pin_with_pull(ident, Ident::new("Up", Span::call_site()))
+}
+
+pub fn input_pin_with_pull(ident: Ident, pull: Ident) -> TokenStream {
     quote! {
         unsafe {
             ::rumcake::hw::mcu::embassy_nrf::gpio::Input::new(
                 ::rumcake::hw::mcu::embassy_nrf::gpio::Pin::degrade(
                     ::rumcake::hw::mcu::embassy_nrf::peripherals::#ident::steal(),
                 ),
-                ::rumcake::hw::mcu::embassy_nrf::gpio::Pull::Up,
+                ::rumcake::hw::mcu::embassy_nrf::gpio::Pull::#pull,
             )
         }
     }
 }
 
+pub fn interrupt_input_pin(ident: Ident) -> TokenStream {
+    // Input pins can already wait for edges
+    input_pin(ident)
+}
+
 pub fn output_pin(ident: Ident) -> TokenStream {
     quote! {
         unsafe {
@@ -36,6 +49,48 @@ pub fn output_pin(ident: Ident) -> TokenStream {
     }
 }
 
+pub fn open_drain_pin(ident: Ident) -> TokenStream {
+    quote! {
+        unsafe {
+            let mut pin = ::rumcake::hw::mcu::embassy_nrf::gpio::Flex::new(
+                ::rumcake::hw::mcu::embassy_nrf::gpio::Pin::degrade(
+                    ::rumcake::hw::mcu::embassy_nrf::peripherals::#ident::steal(),
+                ),
+            );
+            pin.set_high();
+            pin.set_as_input_output(
+                ::rumcake::hw::mcu::embassy_nrf::gpio::Pull::Up,
+                ::rumcake::hw::mcu::embassy_nrf::gpio::OutputDrive::Standard0Disconnect1,
+            );
+            pin
+        }
+    }
+}
+
+pub fn qdec_encoder(a: &Ident, b: &Ident, resolution: TokenStream) -> TokenStream {
+    quote! {
+        unsafe {
+            ::rumcake::hw::mcu::embassy_nrf::bind_interrupts! {
+                struct Irqs {
+                    QDEC => ::rumcake::hw::mcu::embassy_nrf::qdec::InterruptHandler<::rumcake::hw::mcu::embassy_nrf::peripherals::QDEC>;
+                }
+            };
+            let mut config = ::rumcake::hw::mcu::embassy_nrf::qdec::Config::default();
+            config.debounce = true;
+            ::rumcake::hw::mcu::QdecEncoder::new(
+                ::rumcake::hw::mcu::embassy_nrf::qdec::Qdec::new(
+                    ::rumcake::hw::mcu::embassy_nrf::peripherals::QDEC::steal(),
+                    Irqs,
+                    ::rumcake::hw::mcu::embassy_nrf::peripherals::#a::steal(),
+                    ::rumcake::hw::mcu::embassy_nrf::peripherals::#b::steal(),
+                    config,
+                ),
+                #resolution,
+            )
+        }
+    }
+}
+
 fn setup_i2c_inner(args: Punctuated<Ident, Token![,]>) -> TokenStream {
     let mut args = args.iter();
 
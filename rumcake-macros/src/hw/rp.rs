@@ -1,4 +1,4 @@
-use proc_macro2::{Ident, TokenStream};
+use proc_macro2::{Ident, Span, TokenStream};
 use proc_macro_error::OptionExt;
 use quote::quote;
 use syn::punctuated::Punctuated;
@@ -8,19 +8,32 @@ use crate::common::{AnalogPinType, DirectPinDefinition, MultiplexerDefinition};
 
 pub const HAL_CRATE: &'static str = "embassy_rp";
 
+pub const INTERRUPT_INPUT_PIN_TYPE: &'static str = "gpio::Input";
+
+pub const OPEN_DRAIN_PIN_TYPE: &'static str = "OutputOpenDrain";
+
 pub fn input_pin(ident: Ident) -> TokenStream {
+    input_pin_with_pull(ident, Ident::new("Up", Span::call_site()))
+}
+
+pub fn input_pin_with_pull(ident: Ident, pull: Ident) -> TokenStream {
     quote! {
         unsafe {
             ::rumcake::hw::mcu::embassy_rp::gpio::Input::new(
                 ::rumcake::hw::mcu::embassy_rp::gpio::Pin::degrade(
                     ::rumcake::hw::mcu::embassy_rp::peripherals::#ident::steal(),
                 ),
-                ::rumcake::hw::mcu::embassy_rp::gpio::Pull::Up,
+                ::rumcake::hw::mcu::embassy_rp::gpio::Pull::#pull,
             )
         }
     }
 }
 
+pub fn interrupt_input_pin(ident: Ident) -> TokenStream {
+    // Input pins can already wait for edges
+    input_pin(ident)
+}
+
 pub fn output_pin(ident: Ident) -> TokenStream {
     quote! {
         unsafe {
@@ -34,6 +47,19 @@ pub fn output_pin(ident: Ident) -> TokenStream {
     }
 }
 
+pub fn open_drain_pin(ident: Ident) -> TokenStream {
+    quote! {
+        unsafe {
+            ::rumcake::hw::mcu::embassy_rp::gpio::OutputOpenDrain::new(
+                ::rumcake::hw::mcu::embassy_rp::gpio::Pin::degrade(
+                    ::rumcake::hw::mcu::embassy_rp::peripherals::#ident::steal(),
+                ),
+                ::rumcake::hw::mcu::embassy_rp::gpio::Level::High,
+            )
+        }
+    }
+}
+
 pub fn internal_storage_trait() -> TokenStream {
     quote! {
         /// A trait that must be implemented to use the flash chip connected to your RP2040 for storage..
@@ -1,4 +1,4 @@
-use proc_macro2::{Ident, TokenStream};
+use proc_macro2::{Ident, Span, TokenStream};
 use proc_macro_error::{abort, OptionExt};
 use quote::quote;
 use syn::parse::Parse;
@@ -9,13 +9,41 @@ use crate::common::{AnalogPinType, DirectPinDefinition, MultiplexerDefinition};
 
 pub const HAL_CRATE: &'static str = "embassy_stm32";
 
+pub const INTERRUPT_INPUT_PIN_TYPE: &'static str = "exti::ExtiInput";
+
+pub const OPEN_DRAIN_PIN_TYPE: &'static str = "OutputOpenDrain";
+
 pub fn input_pin(ident: Ident) -> TokenStream {
+    input_pin_with_pull(ident, Ident::new("Up", Span::call_site()))
+}
+
+pub fn input_pin_with_pull(ident: Ident, pull: Ident) -> TokenStream {
     quote! {
         unsafe {
             ::rumcake::hw::mcu::embassy_stm32::gpio::Input::new(
                 ::rumcake::hw::mcu::embassy_stm32::gpio::Pin::degrade(
                     ::rumcake::hw::mcu::embassy_stm32::peripherals::#ident::steal(),
                 ),
+                ::rumcake::hw::mcu::embassy_stm32::gpio::Pull::#pull,
+            )
+        }
+    }
+}
+
+pub fn interrupt_input_pin(ident: Ident) -> TokenStream {
+    // Pins use the EXTI channel with the same number (e.g. PA1 and PB1 both use EXTI1)
+    let pin_number = ident
+        .to_string()
+        .get(2..)
+        .and_then(|number| number.parse::<u8>().ok())
+        .unwrap_or_else(|| abort!(ident, "Could not find the EXTI channel for this pin."));
+    let channel = Ident::new(&format!("EXTI{}", pin_number), ident.span());
+
+    quote! {
+        unsafe {
+            ::rumcake::hw::mcu::embassy_stm32::exti::ExtiInput::new(
+                ::rumcake::hw::mcu::embassy_stm32::peripherals::#ident::steal(),
+                ::rumcake::hw::mcu::embassy_stm32::peripherals::#channel::steal(),
                 ::rumcake::hw::mcu::embassy_stm32::gpio::Pull::Up,
             )
         }
@@ -36,6 +64,21 @@ pub fn output_pin(ident: Ident) -> TokenStream {
     }
 }
 
+pub fn open_drain_pin(ident: Ident) -> TokenStream {
+    quote! {
+        unsafe {
+            ::rumcake::hw::mcu::embassy_stm32::gpio::OutputOpenDrain::new(
+                ::rumcake::hw::mcu::embassy_stm32::gpio::Pin::degrade(
+                    ::rumcake::hw::mcu::embassy_stm32::peripherals::#ident::steal(),
+                ),
+                ::rumcake::hw::mcu::embassy_stm32::gpio::Level::High,
+                ::rumcake::hw::mcu::embassy_stm32::gpio::Speed::Low,
+                ::rumcake::hw::mcu::embassy_stm32::gpio::Pull::Up,
+            )
+        }
+    }
+}
+
 fn setup_i2c_inner(args: Punctuated<Ident, Token![,]>) -> TokenStream {
     let mut args = args.iter();
 
@@ -2,12 +2,16 @@ use std::collections::HashMap;
 
 use darling::util::Override;
 use darling::FromMeta;
-use proc_macro2::{Ident, TokenStream, TokenTree};
+use proc_macro2::{Ident, Span, TokenStream, TokenTree};
 use proc_macro_error::OptionExt;
 use quote::{quote, quote_spanned, ToTokens};
 use syn::parse::Parse;
+use syn::punctuated::Punctuated;
 use syn::spanned::Spanned;
-use syn::{braced, bracketed, custom_keyword, ExprRange, ItemStruct, PathSegment};
+use syn::{
+    braced, bracketed, custom_keyword, parenthesized, ExprRange, ItemStruct, LitInt, PathSegment,
+    Token,
+};
 
 use crate::TuplePair;
 
@@ -15,18 +19,36 @@ use crate::TuplePair;
 #[darling(default)]
 pub(crate) struct KeyboardSettings {
     no_matrix: bool,
+    extra_keys: bool,
+    encoders: bool,
     bluetooth: bool,
+    esb: bool,
+    dongle: Option<DongleSettings>,
     usb: bool,
+    mouse_keys: bool,
+    gamepad: bool,
+    digitizer: bool,
+    midi: bool,
+    console: bool,
+    hid_console: bool,
+    nus_console: bool,
+    nfc_pairing: bool,
+    bluetooth_status_led: bool,
+    dfu: bool,
+    raw_hid: bool,
     storage: Option<StorageSettings>,
     simple_backlight: Option<LightingSettings>,
     simple_backlight_matrix: Option<LightingSettings>,
     rgb_backlight_matrix: Option<LightingSettings>,
     underglow: Option<LightingSettings>,
     display: Option<DisplaySettings>,
+    pointing: Option<PointingSettings>,
     split_peripheral: Option<SplitPeripheralSettings>,
     split_central: Option<SplitCentralSettings>,
     via: Option<Override<ViaSettings>>,
     vial: Option<Override<ViaSettings>>,
+    xap: Option<Override<ViaSettings>>,
+    lamp_array: bool,
     bootloader_double_tap_reset: Option<Override<u64>>,
 }
 
@@ -43,6 +65,12 @@ pub(crate) struct DisplaySettings {
     driver: String,
 }
 
+#[derive(Debug, FromMeta, Default)]
+#[darling(default)]
+pub(crate) struct PointingSettings {
+    driver: String,
+}
+
 #[derive(Debug, FromMeta, Default)]
 #[darling(default)]
 pub(crate) struct SplitCentralSettings {
@@ -55,6 +83,13 @@ pub(crate) struct SplitPeripheralSettings {
     driver: String,
 }
 
+#[derive(Debug, FromMeta, Default)]
+#[darling(default)]
+pub(crate) struct DongleSettings {
+    ble: bool,
+    esb: bool,
+}
+
 #[derive(Debug, FromMeta, Default)]
 #[darling(default)]
 pub(crate) struct ViaSettings {
@@ -66,6 +101,7 @@ pub(crate) struct ViaSettings {
 pub(crate) struct StorageSettings {
     driver: String,
     flash_size: usize,
+    use_memory_x: bool,
 }
 
 enum SplitSettings<'a> {
@@ -258,6 +294,69 @@ fn setup_display_driver(
     });
 }
 
+fn setup_pointing_driver(
+    initialization: &mut TokenStream,
+    traits: &mut HashMap<String, TokenStream>,
+    kb_name: &Ident,
+    config: &PointingSettings,
+) {
+    let sensor = match config.driver.as_str() {
+        "pmw3360" => quote! { Pmw3360 },
+        "pmw3389" => quote! { Pmw3389 },
+        "trackpoint" => {
+            return {
+                traits.insert(
+                    config.driver.clone(),
+                    crate::drivers::trackpoint::driver_trait(),
+                );
+                initialization.extend(quote! {
+                    let pointing_driver = ::rumcake::drivers::trackpoint::setup_driver(<#kb_name as TrackPointDriverSettings>::setup_clock_pin(), <#kb_name as TrackPointDriverSettings>::setup_data_pin(), <#kb_name as TrackPointDriverSettings>::SENSITIVITY);
+                });
+            };
+        }
+        "analog_joystick" => {
+            return {
+                traits.insert(
+                    config.driver.clone(),
+                    crate::drivers::analog_joystick::driver_trait(),
+                );
+                initialization.extend(quote! {
+                    let pointing_driver = ::rumcake::drivers::analog_joystick::setup_driver(setup_adc_sampler(), <#kb_name as AnalogJoystickDriverSettings>::X_AXIS, <#kb_name as AnalogJoystickDriverSettings>::Y_AXIS, <#kb_name as AnalogJoystickDriverSettings>::JOYSTICK_CONFIG);
+                });
+            };
+        }
+        "peripheral" => {
+            return initialization.extend(quote! {
+                let pointing_driver = ::rumcake::split::central::PeripheralPointingDevice::new();
+            });
+        }
+        "custom" => {
+            return {
+                traits.insert(
+                    config.driver.clone(),
+                    crate::drivers::custom_pointing_driver_trait(),
+                );
+                initialization.extend(quote! {
+                    let pointing_driver = <#kb_name as CustomPointingDriverSettings>::setup_pointing_device();
+                });
+            };
+        }
+        _ => {
+            return initialization.extend(quote_spanned! {
+                config.driver.span() => compile_error!("Unknown pointing device driver.");
+            });
+        }
+    };
+
+    traits.insert(
+        config.driver.clone(),
+        crate::drivers::pmw33xx::driver_trait(),
+    );
+    initialization.extend(quote! {
+        let pointing_driver = ::rumcake::drivers::pmw33xx::setup_driver(<#kb_name as Pmw33xxDriverSettings>::setup_spi(), <#kb_name as Pmw33xxDriverSettings>::setup_cs_pin(), ::rumcake::drivers::pmw33xx::Sensor::#sensor, <#kb_name as Pmw33xxDriverSettings>::FIRMWARE);
+    });
+}
+
 fn setup_storage_driver(
     initialization: &mut TokenStream,
     traits: &mut HashMap<String, TokenStream>,
@@ -265,6 +364,24 @@ fn setup_storage_driver(
     config: &StorageSettings,
     uses_bluetooth: bool,
 ) {
+    // Use the partition reserved with `config_partition!`, unless the keyboard uses the bounds
+    // from `memory.x`
+    let partition = if config.use_memory_x {
+        quote! {
+            let config_start = unsafe { &::rumcake::hw::__config_start as *const u32 as usize };
+            let config_end = unsafe { &::rumcake::hw::__config_end as *const u32 as usize };
+        }
+    } else {
+        quote! {
+            let ::rumcake::storage::ConfigPartition { start: config_start, end: config_end } = ::rumcake::storage::config_partition();
+        }
+    };
+
+    // `PERSISTED_STATES` is defined by `keyboard_main`, once all storage tasks have been added
+    initialization.extend(quote! {
+        static FLUSH_CHANNEL: ::rumcake::storage::FlushChannel<PERSISTED_STATES> = ::rumcake::storage::FlushChannel::new();
+    });
+
     match config.driver.as_str() {
         "internal" => {
             return if cfg!(feature = "nrf") && uses_bluetooth {
@@ -272,23 +389,21 @@ fn setup_storage_driver(
                 initialization.extend(quote! {
                     use ::rumcake::storage::FlashStorage;
                     let flash = ::rumcake::hw::mcu::setup_internal_softdevice_flash(sd);
-                    let config_start = unsafe { &::rumcake::hw::__config_start as *const u32 as usize };
-                    let config_end = unsafe { &::rumcake::hw::__config_end as *const u32 as usize };
-                    static mut READ_BUF: [u8; ::rumcake::hw::mcu::nrf_softdevice::Flash::ERASE_SIZE] = [0; ::rumcake::hw::mcu::nrf_softdevice::Flash::ERASE_SIZE];
+                    #partition
+                    static mut READ_BUF: [u8; <::rumcake::hw::mcu::nrf_softdevice::Flash as FlashStorage>::REGION_SIZE] = [0; <::rumcake::hw::mcu::nrf_softdevice::Flash as FlashStorage>::REGION_SIZE];
                     static mut OP_BUF: [u8; ::rumcake::hw::mcu::nrf_softdevice::Flash::ERASE_SIZE] = [0; ::rumcake::hw::mcu::nrf_softdevice::Flash::ERASE_SIZE];
                     static DATABASE: ::rumcake::storage::StorageService<'static, ::rumcake::hw::mcu::nrf_softdevice::Flash> = ::rumcake::storage::StorageService::new();
-                    unsafe { DATABASE.setup(flash, config_start, config_end, &mut READ_BUF, &mut OP_BUF).await; }
+                    unsafe { DATABASE.setup(flash, config_start, config_end, &mut READ_BUF, &mut OP_BUF, &FLUSH_CHANNEL).await; }
                 })
             } else if cfg!(any(feature = "stm32", feature = "nrf")) {
                 initialization.extend(quote! {
                     use ::rumcake::storage::FlashStorage;
                     let flash = ::rumcake::hw::mcu::setup_internal_flash();
-                    let config_start = unsafe { &::rumcake::hw::__config_start as *const u32 as usize };
-                    let config_end = unsafe { &::rumcake::hw::__config_end as *const u32 as usize };
-                    static mut READ_BUF: [u8; ::rumcake::hw::mcu::Flash::ERASE_SIZE] = [0; ::rumcake::hw::mcu::Flash::ERASE_SIZE];
+                    #partition
+                    static mut READ_BUF: [u8; <::rumcake::hw::mcu::Flash as FlashStorage>::REGION_SIZE] = [0; <::rumcake::hw::mcu::Flash as FlashStorage>::REGION_SIZE];
                     static mut OP_BUF: [u8; ::rumcake::hw::mcu::Flash::ERASE_SIZE] = [0; ::rumcake::hw::mcu::Flash::ERASE_SIZE];
                     static DATABASE: ::rumcake::storage::StorageService<'static, ::rumcake::hw::mcu::Flash> = ::rumcake::storage::StorageService::new();
-                    unsafe { DATABASE.setup(flash, config_start, config_end, &mut READ_BUF, &mut OP_BUF).await; }
+                    unsafe { DATABASE.setup(flash, config_start, config_end, &mut READ_BUF, &mut OP_BUF, &FLUSH_CHANNEL).await; }
                 })
             } else if cfg!(feature = "rp") {
                 #[cfg(feature = "rp")]
@@ -302,12 +417,11 @@ fn setup_storage_driver(
                     initialization.extend(quote! {
                         use ::rumcake::storage::FlashStorage;
                         let flash = ::rumcake::hw::mcu::setup_internal_flash::<#size>(<#kb_name as RP2040FlashSettings>::setup_dma_channel());
-                        let config_start = unsafe { &::rumcake::hw::__config_start as *const u32 as usize };
-                        let config_end = unsafe { &::rumcake::hw::__config_end as *const u32 as usize };
-                        static mut READ_BUF: [u8; ::rumcake::hw::mcu::embassy_rp::flash::ERASE_SIZE] = [0; ::rumcake::hw::mcu::embassy_rp::flash::ERASE_SIZE];
+                        #partition
+                        static mut READ_BUF: [u8; <::rumcake::hw::mcu::Flash<#size> as FlashStorage>::REGION_SIZE] = [0; <::rumcake::hw::mcu::Flash<#size> as FlashStorage>::REGION_SIZE];
                         static mut OP_BUF: [u8; ::rumcake::hw::mcu::embassy_rp::flash::ERASE_SIZE] = [0; ::rumcake::hw::mcu::embassy_rp::flash::ERASE_SIZE];
                         static DATABASE: ::rumcake::storage::StorageService<'static, ::rumcake::hw::mcu::Flash<#size>> = ::rumcake::storage::StorageService::new();
-                        unsafe { DATABASE.setup(flash, config_start, config_end, &mut READ_BUF, &mut OP_BUF).await; }
+                        unsafe { DATABASE.setup(flash, config_start, config_end, &mut READ_BUF, &mut OP_BUF, &FLUSH_CHANNEL).await; }
                     })
                 }
             } else {
@@ -332,6 +446,8 @@ pub(crate) fn keyboard_main(
     let mut initialization = TokenStream::new();
     let mut spawning = TokenStream::new();
     let mut traits: HashMap<String, TokenStream> = HashMap::new();
+    // Number of spawned storage tasks that use `persist_state`, used to size the flush channel
+    let mut persisted_states: usize = 0;
 
     let uses_bluetooth = keyboard.bluetooth
         || keyboard
@@ -341,7 +457,10 @@ pub(crate) fn keyboard_main(
         || keyboard
             .split_central
             .as_ref()
-            .is_some_and(|args| args.driver == "ble");
+            .is_some_and(|args| args.driver == "ble")
+        // ESB shares the radio with the softdevice
+        || keyboard.esb
+        || keyboard.dongle.is_some();
 
     // Setup microcontroller
     initialization.extend(quote! {
@@ -372,6 +491,40 @@ pub(crate) fn keyboard_main(
                 .spawn(::rumcake::matrix_poll!(#kb_name))
                 .unwrap();
         });
+
+        // Matrix settings, per-key analog actuation settings and calibration persistence
+        if keyboard.storage.is_some() && cfg!(feature = "storage") {
+            spawning.extend(quote! {
+                spawner.spawn(::rumcake::matrix_config_storage_task!(#kb_name, &DATABASE)).unwrap();
+                spawner.spawn(::rumcake::analog_key_configs_storage_task!(#kb_name, &DATABASE)).unwrap();
+                spawner.spawn(::rumcake::analog_calibration_storage_task!(#kb_name, &DATABASE)).unwrap();
+            });
+            persisted_states += 2;
+        }
+    }
+
+    if keyboard.extra_keys {
+        spawning.extend(quote! {
+            spawner
+                .spawn(::rumcake::extra_keys_poll!(#kb_name))
+                .unwrap();
+        });
+    }
+
+    if keyboard.encoders {
+        spawning.extend(quote! {
+            spawner
+                .spawn(::rumcake::encoder_poll!(#kb_name))
+                .unwrap();
+        });
+
+        // Encoder acceleration settings persistence
+        if keyboard.storage.is_some() && cfg!(feature = "storage") {
+            spawning.extend(quote! {
+                spawner.spawn(::rumcake::encoder_configs_storage_task!(#kb_name, &DATABASE)).unwrap();
+            });
+            persisted_states += 1;
+        }
     }
 
     // Flash setup
@@ -388,19 +541,44 @@ pub(crate) fn keyboard_main(
                 driver,
                 uses_bluetooth,
             );
+            spawning.extend(quote! {
+                spawner.spawn(::rumcake::storage_gc_task!(&DATABASE)).unwrap();
+            });
         }
     };
 
-    if keyboard.bluetooth || keyboard.usb {
+    // A dongle forwards the reports of other keyboards instead of using its own layout
+    if (keyboard.bluetooth || keyboard.usb || keyboard.esb) && keyboard.dongle.is_none() {
         spawning.extend(quote! {
             spawner.spawn(::rumcake::layout_collect!(#kb_name)).unwrap();
         });
+
+        // Tap-hold settings and dynamic macro persistence
+        if keyboard.storage.is_some() && cfg!(feature = "storage") {
+            spawning.extend(quote! {
+                spawner.spawn(::rumcake::tap_hold_config_storage_task!(#kb_name, &DATABASE)).unwrap();
+                spawner.spawn(::rumcake::default_layer_storage_task!(#kb_name, &DATABASE)).unwrap();
+                spawner.spawn(::rumcake::dynamic_macros_storage_task!(#kb_name, &DATABASE)).unwrap();
+                spawner.spawn(::rumcake::unicode_mode_storage_task!(#kb_name, &DATABASE)).unwrap();
+                spawner.spawn(::rumcake::accessibility_config_storage_task!(#kb_name, &DATABASE)).unwrap();
+            });
+            persisted_states += 5;
+        }
     }
 
     spawning.extend(quote! {
         spawner.spawn(::rumcake::output_switcher!()).unwrap();
     });
 
+    // Output mode persistence, only needed if there is more than one output to choose from
+    if keyboard.usb && (keyboard.bluetooth || keyboard.esb) && keyboard.storage.is_some() && cfg!(feature = "storage")
+    {
+        spawning.extend(quote! {
+            spawner.spawn(::rumcake::output_mode_storage_task!(#kb_name, &DATABASE)).unwrap();
+        });
+        persisted_states += 1;
+    }
+
     #[cfg(feature = "nrf")]
     if keyboard.bluetooth {
         initialization.extend(quote! {
@@ -409,6 +587,94 @@ pub(crate) fn keyboard_main(
         spawning.extend(quote! {
             spawner.spawn(::rumcake::nrf_ble_task!(#kb_name, sd, hid_server)).unwrap();
         });
+
+        // Bluetooth profile, bond and transmit power persistence
+        if keyboard.storage.is_some() && cfg!(feature = "storage") {
+            spawning.extend(quote! {
+                spawner.spawn(::rumcake::bluetooth_profiles_storage_task!(#kb_name, &DATABASE)).unwrap();
+                spawner.spawn(::rumcake::tx_power_storage_task!(#kb_name, &DATABASE)).unwrap();
+            });
+            persisted_states += 2;
+        }
+
+        // Bluetooth (NUS) console input. The command shell is shared with the USB serial console.
+        if keyboard.nus_console && !(keyboard.usb && keyboard.console) {
+            spawning.extend(quote! {
+                spawner.spawn(::rumcake::console_task!()).unwrap();
+            });
+        }
+
+        // NFC tag containing out-of-band pairing data
+        if keyboard.nfc_pairing {
+            spawning.extend(quote! {
+                spawner.spawn(::rumcake::nfc_pairing_task!(#kb_name, sd)).unwrap();
+            });
+        }
+    }
+
+    if keyboard.bluetooth_status_led {
+        if keyboard.bluetooth {
+            spawning.extend(quote! {
+                spawner.spawn(::rumcake::bluetooth_status_led_task!(#kb_name, <#kb_name as ::rumcake::bluetooth::BluetoothStatusLed>::status_led_pin())).unwrap();
+            });
+        } else {
+            initialization.extend(quote_spanned! {
+                str.span() => compile_error!("The bluetooth status LED requires `bluetooth` to be enabled.");
+            });
+        }
+    }
+
+    if keyboard.nfc_pairing && !keyboard.bluetooth {
+        initialization.extend(quote_spanned! {
+            str.span() => compile_error!("NFC pairing requires `bluetooth` to be enabled.");
+        });
+    }
+
+    #[cfg(feature = "nrf")]
+    if keyboard.esb {
+        spawning.extend(quote! {
+            spawner.spawn(::rumcake::esb_transmitter_task!(#kb_name, sd)).unwrap();
+        });
+    }
+
+    if let Some(ref dongle) = keyboard.dongle {
+        if !keyboard.usb {
+            initialization.extend(quote_spanned! {
+                str.span() => compile_error!("A dongle forwards HID reports to a USB host, so it requires `usb` to be enabled.");
+            });
+        }
+
+        if !dongle.ble && !dongle.esb {
+            initialization.extend(quote_spanned! {
+                str.span() => compile_error!("A dongle must receive HID reports using `ble`, `esb`, or both. Please specify them in `dongle(...)`.");
+            });
+        }
+
+        spawning.extend(quote! {
+            spawner.spawn(::rumcake::dongle_task!()).unwrap();
+        });
+
+        #[cfg(feature = "nrf")]
+        if dongle.ble {
+            spawning.extend(quote! {
+                spawner.spawn(::rumcake::nrf_ble_dongle_task!(sd)).unwrap();
+            });
+
+            // Paired keyboard persistence
+            if keyboard.storage.is_some() && cfg!(feature = "storage") {
+                spawning.extend(quote! {
+                    spawner.spawn(::rumcake::dongle_paired_keyboards_storage_task!(#kb_name, &DATABASE)).unwrap();
+                });
+                persisted_states += 1;
+            }
+        }
+
+        #[cfg(feature = "nrf")]
+        if dongle.esb {
+            spawning.extend(quote! {
+                spawner.spawn(::rumcake::esb_receiver_task!(#kb_name, sd)).unwrap();
+            });
+        }
     }
 
     // USB Configuration
@@ -417,7 +683,7 @@ pub(crate) fn keyboard_main(
             let mut builder = ::rumcake::hw::mcu::setup_usb_driver::<#kb_name>();
 
             // HID Class setup
-            let kb_class = ::rumcake::usb::setup_usb_hid_nkro_writer(&mut builder);
+            let kb_class = ::rumcake::usb::setup_usb_hid_nkro_writer::<#kb_name>(&mut builder);
         });
         spawning.extend(quote! {
             let usb = builder.build();
@@ -428,37 +694,226 @@ pub(crate) fn keyboard_main(
 
             // HID Keyboard Report sending
             spawner.spawn(::rumcake::usb_hid_kb_write_task!(kb_class)).unwrap();
+
+            // Host OS detection
+            spawner.spawn(::rumcake::os_detection_task!(#kb_name)).unwrap();
         });
 
         if cfg!(feature = "media-keycodes") {
             initialization.extend(quote! {
                 // HID consumer
-                let consumer_class = ::rumcake::usb::setup_usb_hid_consumer_writer(&mut builder);
+                let consumer_class = ::rumcake::usb::setup_usb_hid_consumer_writer::<#kb_name>(&mut builder);
+
+                // HID system control
+                let system_control_class = ::rumcake::usb::setup_usb_hid_system_control_writer::<#kb_name>(&mut builder);
             });
             spawning.extend(quote! {
                 // HID Consumer Report sending
                 spawner.spawn(::rumcake::usb_hid_consumer_write_task!(consumer_class)).unwrap();
+
+                // HID System Control Report sending
+                spawner.spawn(::rumcake::usb_hid_system_control_write_task!(system_control_class)).unwrap();
+            });
+        }
+
+        if keyboard.mouse_keys || keyboard.pointing.is_some() {
+            initialization.extend(quote! {
+                // HID mouse
+                let mouse_class = ::rumcake::usb::setup_usb_hid_mouse_writer::<#kb_name>(&mut builder);
+            });
+            spawning.extend(quote! {
+                // HID Mouse Report sending
+                spawner.spawn(::rumcake::usb_hid_mouse_write_task!(mouse_class)).unwrap();
+            });
+        }
+
+        if keyboard.gamepad {
+            initialization.extend(quote! {
+                // HID gamepad
+                let gamepad_class = ::rumcake::usb::setup_usb_hid_gamepad_writer::<#kb_name>(&mut builder);
+            });
+            spawning.extend(quote! {
+                // HID Gamepad Report sending
+                spawner.spawn(::rumcake::usb_hid_gamepad_write_task!(gamepad_class)).unwrap();
+            });
+        }
+
+        if keyboard.digitizer {
+            initialization.extend(quote! {
+                // HID digitizer
+                let digitizer_class = ::rumcake::usb::setup_usb_hid_digitizer_writer::<#kb_name>(&mut builder);
+            });
+            spawning.extend(quote! {
+                // HID Digitizer Report sending
+                spawner.spawn(::rumcake::usb_hid_digitizer_write_task!(digitizer_class)).unwrap();
+            });
+        }
+
+        if keyboard.midi {
+            initialization.extend(quote! {
+                // USB MIDI
+                let midi_class = ::rumcake::usb::setup_usb_midi(&mut builder);
+            });
+            spawning.extend(quote! {
+                // MIDI message sending
+                spawner.spawn(::rumcake::usb_midi_write_task!(midi_class)).unwrap();
+            });
+        }
+
+        if keyboard.console {
+            initialization.extend(quote! {
+                // USB serial console
+                let console_class = ::rumcake::usb::setup_usb_console(&mut builder);
+            });
+            spawning.extend(quote! {
+                // Console input and output
+                spawner.spawn(::rumcake::usb_console_task!(console_class)).unwrap();
+                spawner.spawn(::rumcake::console_task!()).unwrap();
+            });
+        }
+
+        if keyboard.hid_console {
+            initialization.extend(quote! {
+                // HID console
+                let hid_console_writer = ::rumcake::usb::setup_usb_hid_console_writer(&mut builder);
+            });
+            spawning.extend(quote! {
+                // HID console output
+                spawner.spawn(::rumcake::usb_hid_console_write_task!(hid_console_writer)).unwrap();
+            });
+        }
+
+        if keyboard.dongle.is_some() {
+            initialization.extend(quote! {
+                // Battery level of the dongle's keyboards
+                let battery_writer = ::rumcake::usb::setup_usb_hid_battery_writer(&mut builder);
+            });
+            spawning.extend(quote! {
+                // Battery level reporting
+                spawner.spawn(::rumcake::usb_hid_battery_write_task!(battery_writer)).unwrap();
+            });
+        }
+
+        if keyboard.dfu {
+            initialization.extend(quote! {
+                // DFU runtime interface
+                ::rumcake::usb::setup_usb_dfu_runtime(&mut builder);
+            });
+            spawning.extend(quote! {
+                // DFU detach handling
+                spawner.spawn(::rumcake::usb_dfu_detach_task!()).unwrap();
             });
         }
+
+        if keyboard.lamp_array {
+            if keyboard.rgb_backlight_matrix.is_none() {
+                initialization.extend(quote_spanned! {
+                    str.span() => compile_error!("LampArray requires an `rgb_backlight_matrix` to be specified.");
+                });
+            } else {
+                initialization.extend(quote! {
+                    // HID LampArray
+                    ::rumcake::usb::setup_usb_lamp_array::<#kb_name>(&mut builder);
+                });
+                spawning.extend(quote! {
+                    // LampArray report processing
+                    spawner.spawn(::rumcake::lamp_array_task!(#kb_name)).unwrap();
+                });
+            }
+        }
+    }
+
+    if keyboard.mouse_keys {
+        spawning.extend(quote! {
+            spawner.spawn(::rumcake::mouse_keys_task!(#kb_name)).unwrap();
+        });
+    }
+
+    if keyboard.gamepad {
+        spawning.extend(quote! {
+            spawner.spawn(::rumcake::gamepad_task!()).unwrap();
+        });
     }
 
-    if keyboard.usb && (keyboard.via.is_some() || keyboard.vial.is_some()) {
+    let uses_raw_hid = keyboard.raw_hid || keyboard.via.is_some() || keyboard.vial.is_some();
+
+    if keyboard.usb && uses_raw_hid {
         initialization.extend(quote! {
-            // Via HID setup
-            let (via_reader, via_writer) =
-                ::rumcake::usb::setup_usb_via_hid_reader_writer(&mut builder).split();
+            // Raw HID setup
+            let (raw_hid_reader, raw_hid_writer) =
+                ::rumcake::usb::setup_usb_raw_hid_reader_writer(&mut builder).split();
         });
         spawning.extend(quote! {
-            // HID raw report (for VIA) reading and writing
+            // HID raw report (for VIA, Vial, or custom handlers) reading and writing
             spawner
-                .spawn(::rumcake::usb_hid_via_read_task!(via_reader))
+                .spawn(::rumcake::usb_hid_raw_read_task!(raw_hid_reader))
                 .unwrap();
         });
         spawning.extend(quote! {
-            spawner.spawn(::rumcake::usb_hid_via_write_task!(via_writer)).unwrap();
+            spawner.spawn(::rumcake::usb_hid_raw_write_task!(raw_hid_writer)).unwrap();
         });
     }
 
+    if keyboard.raw_hid {
+        if keyboard.via.is_some() || keyboard.vial.is_some() {
+            initialization.extend(quote_spanned! {
+                str.span() => compile_error!("Raw HID reports are already handled by Via/Vial. Please remove `raw_hid`, and use `ViaKeyboard::handle_via_command` to handle custom reports instead.");
+            });
+        } else {
+            spawning.extend(quote! {
+                spawner.spawn(::rumcake::raw_hid_process_task!(#kb_name)).unwrap();
+            });
+        }
+    }
+
+    let via_uses_storage = [&keyboard.via, &keyboard.vial].iter().any(|settings| {
+        matches!(
+            settings,
+            Some(Override::Explicit(ViaSettings { use_storage: true }))
+        )
+    });
+
+    if let Some(args) = keyboard.xap {
+        let args = args.unwrap_or_default();
+
+        if !keyboard.usb {
+            initialization.extend(quote_spanned! {
+                str.span() => compile_error!("XAP requires `usb` to be enabled.");
+            });
+        } else {
+            initialization.extend(quote! {
+                // XAP setup
+                let (xap_reader, xap_writer) =
+                    ::rumcake::usb::setup_usb_xap_reader_writer(&mut builder).split();
+            });
+            spawning.extend(quote! {
+                // XAP report reading and writing
+                spawner
+                    .spawn(::rumcake::usb_hid_xap_read_task!(xap_reader))
+                    .unwrap();
+                spawner
+                    .spawn(::rumcake::usb_hid_xap_write_task!(xap_writer))
+                    .unwrap();
+                spawner
+                    .spawn(::rumcake::xap_process_task!(#kb_name))
+                    .unwrap();
+            });
+        }
+
+        if args.use_storage && keyboard.storage.is_none() {
+            initialization.extend(quote_spanned! {
+                args.use_storage.span() => compile_error!("XAP uses storage but no `storage` driver was specified. Either specify a `storage` driver, or remove `use_storage` from your XAP settings.");
+            });
+        } else if args.use_storage && !via_uses_storage {
+            // XAP saves keymap changes using Via's storage
+            spawning.extend(quote! {
+                spawner
+                    .spawn(::rumcake::via_storage_task!(#kb_name, &DATABASE))
+                    .unwrap();
+            });
+        }
+    }
+
     if keyboard.via.is_some() && keyboard.vial.is_some() {
         initialization.extend(quote_spanned! {
             str.span() => compile_error!("Via and Vial are both specified. Please only choose one.");
@@ -491,7 +946,11 @@ pub(crate) fn keyboard_main(
                 args.use_storage.span() => compile_error!("Vial uses storage but no `storage` driver was specified. Either specify a `storage` driver, or remove `use_storage` from your Vial settings.");
             });
         } else if args.use_storage {
+            // Vial uses Via's storage for the dynamic keymap, encoders and macros
             spawning.extend(quote! {
+                spawner
+                    .spawn(::rumcake::via_storage_task!(#kb_name, &DATABASE))
+                    .unwrap();
                 spawner
                     .spawn(::rumcake::vial_storage_task!(#kb_name, &DATABASE))
                     .unwrap();
@@ -568,6 +1027,7 @@ pub(crate) fn keyboard_main(
                 spawning.extend(quote! {
                     spawner.spawn(::rumcake::underglow_storage_task!(#kb_name, &DATABASE)).unwrap();
                 });
+                persisted_states += 1;
             }
             spawning.extend(quote! {
                 spawner.spawn(::rumcake::underglow_task!(#kb_name, underglow_driver)).unwrap();
@@ -597,6 +1057,7 @@ pub(crate) fn keyboard_main(
                 spawning.extend(quote! {
                     spawner.spawn(::rumcake::simple_backlight_storage_task!(#kb_name, &DATABASE)).unwrap();
                 });
+                persisted_states += 1;
             }
             spawning.extend(quote! {
                 spawner.spawn(::rumcake::simple_backlight_task!(#kb_name, backlight_driver)).unwrap();
@@ -625,6 +1086,7 @@ pub(crate) fn keyboard_main(
                 spawning.extend(quote! {
                     spawner.spawn(::rumcake::simple_backlight_matrix_storage_task!(#kb_name, &DATABASE)).unwrap();
                 });
+                persisted_states += 1;
             }
             spawning.extend(quote! {
                 spawner.spawn(::rumcake::simple_backlight_matrix_task!(#kb_name, backlight_driver)).unwrap();
@@ -653,6 +1115,7 @@ pub(crate) fn keyboard_main(
                 spawning.extend(quote! {
                     spawner.spawn(::rumcake::rgb_backlight_matrix_storage_task!(#kb_name, &DATABASE)).unwrap();
                 });
+                persisted_states += 1;
             }
             spawning.extend(quote! {
                 spawner.spawn(::rumcake::rgb_backlight_matrix_task!(#kb_name, backlight_driver)).unwrap();
@@ -674,10 +1137,40 @@ pub(crate) fn keyboard_main(
         }
     }
 
-    if let Some(arg) = keyboard.bootloader_double_tap_reset {
-        let timeout = arg.unwrap_or(200);
+    // Pointing device setup
+    if let Some(args) = keyboard.pointing {
+        if args.driver.is_empty() {
+            initialization.extend(quote_spanned! {
+                args.driver.span() => compile_error!("You must specify a pointing device driver.");
+            })
+        } else {
+            setup_pointing_driver(&mut initialization, &mut traits, &kb_name, &args);
 
-        if timeout == 0 {
+            if keyboard.split_peripheral.is_some() {
+                // Movement is forwarded to the central device, which sends it to the host
+                spawning.extend(quote! {
+                    spawner.spawn(::rumcake::peripheral_pointing_task!(#kb_name, pointing_driver)).unwrap();
+                });
+            } else {
+                spawning.extend(quote! {
+                    spawner.spawn(::rumcake::pointing_task!(#kb_name, pointing_driver)).unwrap();
+                });
+
+                // Pointing device settings persistence
+                if keyboard.storage.is_some() && cfg!(feature = "storage") {
+                    spawning.extend(quote! {
+                        spawner.spawn(::rumcake::pointing_config_storage_task!(#kb_name, &DATABASE)).unwrap();
+                    });
+                    persisted_states += 1;
+                }
+            }
+        }
+    }
+
+    if let Some(arg) = keyboard.bootloader_double_tap_reset {
+        let timeout = arg.unwrap_or(200);
+
+        if timeout == 0 {
             initialization.extend(quote! {
                 compile_error!("The timeout for double tapping the reset button should be > 0");
             })
@@ -691,6 +1184,12 @@ pub(crate) fn keyboard_main(
     }
 
 
+    if keyboard.storage.is_some() && cfg!(feature = "storage") {
+        initialization.extend(quote! {
+            const PERSISTED_STATES: usize = #persisted_states;
+        });
+    }
+
     let final_traits = traits.values();
 
     quote! {
@@ -742,6 +1241,24 @@ pub struct StandardMatrixDefinition {
     pub rows: Vec<Ident>,
     pub col_brace: syn::token::Brace,
     pub cols: Vec<Ident>,
+    pub options: Punctuated<MatrixOption, Token![,]>,
+}
+
+#[derive(Debug)]
+pub struct MatrixOption {
+    pub name: Ident,
+    pub eq_token: Token![=],
+    pub value: Ident,
+}
+
+impl syn::parse::Parse for MatrixOption {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        Ok(Self {
+            name: input.parse()?,
+            eq_token: input.parse()?,
+            value: input.parse()?,
+        })
+    }
 }
 
 impl syn::parse::Parse for StandardMatrixDefinition {
@@ -772,21 +1289,155 @@ impl syn::parse::Parse for StandardMatrixDefinition {
             ));
         }
 
+        // Options can optionally follow the columns, e.g. `{ ... } { ... } strobe = high`
+        let options = Punctuated::parse_terminated(input)?;
+
         Ok(Self {
             row_brace,
             rows,
             col_brace,
             cols,
+            options,
         })
     }
 }
 
+fn reject_matrix_options(options: &Punctuated<MatrixOption, Token![,]>) -> Option<TokenStream> {
+    options.first().map(|option| {
+        quote_spanned! {
+            option.name.span() => compile_error!("This matrix type does not support any options.");
+        }
+    })
+}
+
 pub fn build_standard_matrix(input: StandardMatrixDefinition) -> TokenStream {
-    let StandardMatrixDefinition { rows, cols, .. } = input;
+    let StandardMatrixDefinition {
+        rows,
+        cols,
+        options,
+        ..
+    } = input;
+    let row_count = rows.len();
+    let col_count = cols.len();
+
+    let mut row2col = false;
+    let mut active_high = false;
+    let mut pull = None;
+
+    for option in options {
+        match (
+            option.name.to_string().as_str(),
+            option.value.to_string().as_str(),
+        ) {
+            ("diode_direction", "col2row") => row2col = false,
+            ("diode_direction", "row2col") => row2col = true,
+            ("strobe", "low") => active_high = false,
+            ("strobe", "high") => active_high = true,
+            ("pull", "up") => pull = Some("Up"),
+            ("pull", "down") => pull = Some("Down"),
+            ("pull", "none") => pull = Some("None"),
+            _ => {
+                return quote_spanned! {
+                    option.name.span() => compile_error!("Unknown matrix option. Valid options are `diode_direction = col2row | row2col`, `strobe = low | high` and `pull = up | down | none`.");
+                }
+            }
+        }
+    }
+
+    // Inputs are pulled towards the opposite level of the strobe by default
+    let pull = Ident::new(
+        pull.unwrap_or(if active_high { "Down" } else { "Up" }),
+        Span::call_site(),
+    );
+
+    let hal_name: PathSegment = syn::parse_str(crate::hw::HAL_CRATE).unwrap();
+
+    let (inputs, outputs) = if row2col {
+        (&rows, &cols)
+    } else {
+        (&cols, &rows)
+    };
+
+    let mut input_type = quote! { ::rumcake::hw::mcu::#hal_name::gpio::Input<'static> };
+    let mut output_type = quote! { ::rumcake::hw::mcu::#hal_name::gpio::Output<'static> };
+    let mut input_pins: Vec<TokenStream> = inputs
+        .iter()
+        .map(|pin| quote! { ::rumcake::hw::mcu::input_pin!(#pin, #pull) })
+        .collect();
+    let mut output_pins: Vec<TokenStream> = outputs
+        .iter()
+        .map(|pin| quote! { ::rumcake::hw::mcu::output_pin!(#pin) })
+        .collect();
+
+    if active_high {
+        input_type = quote! { ::rumcake::keyboard::ActiveHigh<#input_type> };
+        output_type = quote! { ::rumcake::keyboard::ActiveHigh<#output_type> };
+        for pin in input_pins.iter_mut().chain(output_pins.iter_mut()) {
+            *pin = quote! { ::rumcake::keyboard::ActiveHigh(#pin) };
+        }
+    }
+
+    let (matrix_type, setup_fn) = if row2col {
+        (
+            quote! { PollableRow2ColMatrix },
+            quote! { setup_row2col_keyboard_matrix },
+        )
+    } else {
+        (
+            quote! { PollableStandardMatrix },
+            quote! { setup_standard_keyboard_matrix },
+        )
+    };
+
+    quote! {
+        const MATRIX_ROWS: usize = #row_count;
+        const MATRIX_COLS: usize = #col_count;
+
+        fn get_matrix() -> &'static ::rumcake::keyboard::PollableMatrix<impl ::rumcake::keyboard::Pollable> {
+            static MATRIX: ::rumcake::once_cell::sync::OnceCell<
+                ::rumcake::keyboard::PollableMatrix<
+                    ::rumcake::keyboard::#matrix_type<
+                        #input_type,
+                        #output_type,
+                        #col_count,
+                        #row_count
+                    >
+                >
+            > = ::rumcake::once_cell::sync::OnceCell::new();
+            MATRIX.get_or_init(|| {
+                ::rumcake::keyboard::PollableMatrix::new(
+                    ::rumcake::keyboard::#setup_fn(
+                        [
+                            #(#input_pins),*
+                        ],
+                        [
+                            #(#output_pins),*
+                        ],
+                        Self::DEBOUNCE_MS
+                    ).unwrap()
+                )
+            })
+        }
+    }
+}
+
+pub fn build_interrupt_matrix(input: StandardMatrixDefinition) -> TokenStream {
+    let StandardMatrixDefinition {
+        rows,
+        cols,
+        options,
+        ..
+    } = input;
+
+    if let Some(error) = reject_matrix_options(&options) {
+        return error;
+    }
+
     let row_count = rows.len();
     let col_count = cols.len();
 
     let hal_name: PathSegment = syn::parse_str(crate::hw::HAL_CRATE).unwrap();
+    let input_type: syn::Path = syn::parse_str(crate::hw::INTERRUPT_INPUT_PIN_TYPE).unwrap();
 
     quote! {
         const MATRIX_ROWS: usize = #row_count;
@@ -795,8 +1446,8 @@ pub fn build_standard_matrix(input: StandardMatrixDefinition) -> TokenStream {
         fn get_matrix() -> &'static ::rumcake::keyboard::PollableMatrix<impl ::rumcake::keyboard::Pollable> {
             static MATRIX: ::rumcake::once_cell::sync::OnceCell<
                 ::rumcake::keyboard::PollableMatrix<
-                    ::rumcake::keyboard::PollableStandardMatrix<
-                        ::rumcake::hw::mcu::#hal_name::gpio::Input<'static>,
+                    ::rumcake::keyboard::PollableInterruptMatrix<
+                        ::rumcake::hw::mcu::#hal_name::#input_type<'static>,
                         ::rumcake::hw::mcu::#hal_name::gpio::Output<'static>,
                         #col_count,
                         #row_count
@@ -805,10 +1456,10 @@ pub fn build_standard_matrix(input: StandardMatrixDefinition) -> TokenStream {
             > = ::rumcake::once_cell::sync::OnceCell::new();
             MATRIX.get_or_init(|| {
                 ::rumcake::keyboard::PollableMatrix::new(
-                    ::rumcake::keyboard::setup_standard_keyboard_matrix(
+                    ::rumcake::keyboard::setup_interrupt_keyboard_matrix(
                         [
                             #(
-                                ::rumcake::hw::mcu::input_pin!(#cols)
+                                ::rumcake::hw::mcu::interrupt_input_pin!(#cols)
                             ),*
                         ],
                         [
@@ -824,6 +1475,225 @@ pub fn build_standard_matrix(input: StandardMatrixDefinition) -> TokenStream {
     }
 }
 
+pub fn build_duplex_matrix(input: StandardMatrixDefinition) -> TokenStream {
+    let StandardMatrixDefinition {
+        rows,
+        cols,
+        options,
+        ..
+    } = input;
+
+    if let Some(error) = reject_matrix_options(&options) {
+        return error;
+    }
+
+    let row_count = rows.len();
+    let col_count = cols.len();
+    // Each row pin is split into two rows, one for each direction the switches are scanned in
+    let matrix_row_count = row_count * 2;
+
+    let hal_name: PathSegment = syn::parse_str(crate::hw::HAL_CRATE).unwrap();
+    let pin_type: PathSegment = syn::parse_str(crate::hw::OPEN_DRAIN_PIN_TYPE).unwrap();
+
+    quote! {
+        const MATRIX_ROWS: usize = #matrix_row_count;
+        const MATRIX_COLS: usize = #col_count;
+
+        fn get_matrix() -> &'static ::rumcake::keyboard::PollableMatrix<impl ::rumcake::keyboard::Pollable> {
+            static MATRIX: ::rumcake::once_cell::sync::OnceCell<
+                ::rumcake::keyboard::PollableMatrix<
+                    ::rumcake::keyboard::PollableDuplexMatrix<
+                        ::rumcake::hw::mcu::#hal_name::gpio::#pin_type<'static>,
+                        #col_count,
+                        #row_count
+                    >
+                >
+            > = ::rumcake::once_cell::sync::OnceCell::new();
+            MATRIX.get_or_init(|| {
+                ::rumcake::keyboard::PollableMatrix::new(
+                    ::rumcake::keyboard::setup_duplex_keyboard_matrix(
+                        [
+                            #(
+                                ::rumcake::hw::mcu::open_drain_pin!(#cols)
+                            ),*
+                        ],
+                        [
+                            #(
+                                ::rumcake::hw::mcu::open_drain_pin!(#rows)
+                            ),*
+                        ],
+                        Self::DEBOUNCE_MS
+                    ).unwrap()
+                )
+            })
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RoundRobinMatrixDefinition {
+    pub pin_brace: syn::token::Brace,
+    pub pins: Vec<Ident>,
+}
+
+impl syn::parse::Parse for RoundRobinMatrixDefinition {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let pin_content;
+        let pin_brace = braced!(pin_content in input);
+        let mut pins = Vec::new();
+        while let Ok(t) = pin_content.parse() {
+            pins.push(t)
+        }
+        if !pin_content.is_empty() {
+            return Err(syn::Error::new(
+                pin_content.span(),
+                "Encountered an invalid token.",
+            ));
+        }
+
+        if pins.len() < 2 {
+            return Err(syn::Error::new(
+                input.span(),
+                "A round-robin matrix needs at least 2 pins.",
+            ));
+        }
+
+        Ok(Self { pin_brace, pins })
+    }
+}
+
+pub fn build_round_robin_matrix(input: RoundRobinMatrixDefinition) -> TokenStream {
+    let RoundRobinMatrixDefinition { pins, .. } = input;
+    let pin_count = pins.len();
+
+    let hal_name: PathSegment = syn::parse_str(crate::hw::HAL_CRATE).unwrap();
+    let pin_type: PathSegment = syn::parse_str(crate::hw::OPEN_DRAIN_PIN_TYPE).unwrap();
+
+    quote! {
+        const MATRIX_ROWS: usize = #pin_count;
+        const MATRIX_COLS: usize = #pin_count;
+
+        fn get_matrix() -> &'static ::rumcake::keyboard::PollableMatrix<impl ::rumcake::keyboard::Pollable> {
+            static MATRIX: ::rumcake::once_cell::sync::OnceCell<
+                ::rumcake::keyboard::PollableMatrix<
+                    ::rumcake::keyboard::PollableRoundRobinMatrix<
+                        ::rumcake::hw::mcu::#hal_name::gpio::#pin_type<'static>,
+                        #pin_count
+                    >
+                >
+            > = ::rumcake::once_cell::sync::OnceCell::new();
+            MATRIX.get_or_init(|| {
+                ::rumcake::keyboard::PollableMatrix::new(
+                    ::rumcake::keyboard::setup_round_robin_keyboard_matrix(
+                        [
+                            #(
+                                ::rumcake::hw::mcu::open_drain_pin!(#pins)
+                            ),*
+                        ],
+                        Self::DEBOUNCE_MS
+                    ).unwrap()
+                )
+            })
+        }
+    }
+}
+
+pub struct ShiftRegisterMatrixDefinition {
+    pub row_brace: syn::token::Brace,
+    pub rows: Vec<Ident>,
+    pub pin_brace: syn::token::Brace,
+    pub data: Ident,
+    pub clock: Ident,
+    pub latch: Ident,
+    pub col_count: syn::LitInt,
+}
+
+impl syn::parse::Parse for ShiftRegisterMatrixDefinition {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let row_content;
+        let row_brace = braced!(row_content in input);
+        let mut rows = Vec::new();
+        while let Ok(t) = row_content.parse() {
+            rows.push(t)
+        }
+        if !row_content.is_empty() {
+            return Err(syn::Error::new(
+                row_content.span(),
+                "Encountered an invalid token.",
+            ));
+        }
+
+        let pin_content;
+        let pin_brace = braced!(pin_content in input);
+        let data = pin_content.parse()?;
+        let clock = pin_content.parse()?;
+        let latch = pin_content.parse()?;
+        if !pin_content.is_empty() {
+            return Err(syn::Error::new(
+                pin_content.span(),
+                "Expected exactly 3 pins for the shift register: data, clock and latch.",
+            ));
+        }
+
+        Ok(Self {
+            row_brace,
+            rows,
+            pin_brace,
+            data,
+            clock,
+            latch,
+            col_count: input.parse()?,
+        })
+    }
+}
+
+pub fn build_shift_register_matrix(input: ShiftRegisterMatrixDefinition) -> TokenStream {
+    let ShiftRegisterMatrixDefinition {
+        rows,
+        data,
+        clock,
+        latch,
+        col_count,
+        ..
+    } = input;
+    let row_count = rows.len();
+
+    let hal_name: PathSegment = syn::parse_str(crate::hw::HAL_CRATE).unwrap();
+
+    quote! {
+        const MATRIX_ROWS: usize = #row_count;
+        const MATRIX_COLS: usize = #col_count;
+
+        fn get_matrix() -> &'static ::rumcake::keyboard::PollableMatrix<impl ::rumcake::keyboard::Pollable> {
+            static MATRIX: ::rumcake::once_cell::sync::OnceCell<
+                ::rumcake::keyboard::PollableMatrix<
+                    ::rumcake::keyboard::PollableShiftRegisterMatrix<
+                        ::rumcake::hw::mcu::#hal_name::gpio::Input<'static>,
+                        ::rumcake::hw::mcu::#hal_name::gpio::Output<'static>,
+                        #col_count,
+                        #row_count
+                    >
+                >
+            > = ::rumcake::once_cell::sync::OnceCell::new();
+            MATRIX.get_or_init(|| {
+                ::rumcake::keyboard::PollableMatrix::new(
+                    ::rumcake::keyboard::setup_shift_register_keyboard_matrix(
+                        [
+                            #(
+                                ::rumcake::hw::mcu::input_pin!(#rows)
+                            ),*
+                        ],
+                        ::rumcake::hw::mcu::output_pin!(#data),
+                        ::rumcake::hw::mcu::output_pin!(#clock),
+                        ::rumcake::hw::mcu::output_pin!(#latch),
+                        Self::DEBOUNCE_MS
+                    ).unwrap()
+                )
+            })
+        }
+    }
+}
+
 pub fn build_direct_pin_matrix(input: MatrixLike<OptionalItem<Ident>>) -> TokenStream {
     let values = input.rows.iter().map(|row| {
         let items = row.cols.iter().map(|item| match item {
@@ -873,6 +1743,276 @@ pub fn build_direct_pin_matrix(input: MatrixLike<OptionalItem<Ident>>) -> TokenS
     }
 }
 
+#[derive(Debug)]
+pub struct ExtraKeysDefinition {
+    pub pin_brace: syn::token::Brace,
+    pub pins: Vec<Ident>,
+    pub position_brace: syn::token::Brace,
+    pub positions: Vec<TuplePair>,
+}
+
+impl syn::parse::Parse for ExtraKeysDefinition {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let pin_content;
+        let pin_brace = braced!(pin_content in input);
+        let mut pins = Vec::new();
+        while let Ok(t) = pin_content.parse() {
+            pins.push(t)
+        }
+        if !pin_content.is_empty() {
+            return Err(syn::Error::new(
+                pin_content.span(),
+                "Encountered an invalid token.",
+            ));
+        }
+
+        let position_content;
+        let position_brace = braced!(position_content in input);
+        let mut positions = Vec::new();
+        while let Ok(t) = position_content.parse() {
+            positions.push(t)
+        }
+        if !position_content.is_empty() {
+            return Err(syn::Error::new(
+                position_content.span(),
+                "Encountered an invalid token.",
+            ));
+        }
+
+        if pins.len() != positions.len() {
+            return Err(syn::Error::new(
+                input.span(),
+                "Each extra key pin must have exactly one layout position.",
+            ));
+        }
+
+        Ok(Self {
+            pin_brace,
+            pins,
+            position_brace,
+            positions,
+        })
+    }
+}
+
+pub fn build_extra_keys(input: ExtraKeysDefinition) -> TokenStream {
+    let ExtraKeysDefinition {
+        pins, positions, ..
+    } = input;
+    let key_count = pins.len();
+
+    let hal_name: PathSegment = syn::parse_str(crate::hw::HAL_CRATE).unwrap();
+
+    quote! {
+        const EXTRA_KEY_POSITIONS: &'static [(u8, u8)] = &[#(#positions),*];
+
+        fn get_extra_keys() -> &'static ::rumcake::keyboard::PollableMatrix<impl ::rumcake::keyboard::Pollable> {
+            static EXTRA_KEYS: ::rumcake::once_cell::sync::OnceCell<
+                ::rumcake::keyboard::PollableMatrix<
+                    ::rumcake::keyboard::PollableDirectPinMatrix<
+                        ::rumcake::hw::mcu::#hal_name::gpio::Input<'static>,
+                        #key_count,
+                        1
+                    >
+                >
+            > = ::rumcake::once_cell::sync::OnceCell::new();
+            EXTRA_KEYS.get_or_init(|| {
+                ::rumcake::keyboard::PollableMatrix::new(
+                    ::rumcake::keyboard::setup_direct_pin_keyboard_matrix(
+                        [
+                            [ #(Some(::rumcake::hw::mcu::input_pin!(#pins))),* ]
+                        ],
+                        Self::EXTRA_KEYS_DEBOUNCE_MS
+                    ).unwrap()
+                )
+            })
+        }
+    }
+}
+
+custom_keyword!(qdec);
+
+#[derive(Debug)]
+pub struct EncoderPins {
+    pub qdec: Option<qdec>,
+    pub parenthesis_token: syn::token::Paren,
+    pub a: Ident,
+    pub b: Ident,
+    pub resolution: Option<LitInt>,
+}
+
+impl syn::parse::Parse for EncoderPins {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let qdec = input.parse()?;
+        let content;
+        let parenthesis_token = parenthesized!(content in input);
+        let a = content.parse()?;
+        let b = content.parse()?;
+        let resolution = if content.is_empty() {
+            None
+        } else {
+            Some(content.parse()?)
+        };
+
+        Ok(Self {
+            qdec,
+            parenthesis_token,
+            a,
+            b,
+            resolution,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct EncoderPositions {
+    pub parenthesis_token: syn::token::Paren,
+    pub clockwise: TuplePair,
+    pub comma_token: Token![,],
+    pub counter_clockwise: TuplePair,
+}
+
+impl syn::parse::Parse for EncoderPositions {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let content;
+        let parenthesis_token = parenthesized!(content in input);
+
+        Ok(Self {
+            parenthesis_token,
+            clockwise: content.parse()?,
+            comma_token: content.parse()?,
+            counter_clockwise: content.parse()?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct EncodersDefinition {
+    pub pin_brace: syn::token::Brace,
+    pub pins: Vec<EncoderPins>,
+    pub position_brace: syn::token::Brace,
+    pub positions: Vec<EncoderPositions>,
+}
+
+impl syn::parse::Parse for EncodersDefinition {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let pin_content;
+        let pin_brace = braced!(pin_content in input);
+        let mut pins = Vec::new();
+        while !pin_content.is_empty() {
+            pins.push(pin_content.parse()?)
+        }
+
+        let position_content;
+        let position_brace = braced!(position_content in input);
+        let mut positions = Vec::new();
+        while !position_content.is_empty() {
+            positions.push(position_content.parse()?)
+        }
+
+        if pins.len() != positions.len() {
+            return Err(syn::Error::new(
+                input.span(),
+                "Each encoder must have exactly one pair of layout positions.",
+            ));
+        }
+
+        Ok(Self {
+            pin_brace,
+            pins,
+            position_brace,
+            positions,
+        })
+    }
+}
+
+pub fn build_encoders(input: EncodersDefinition) -> TokenStream {
+    let EncodersDefinition {
+        mut pins,
+        positions,
+        ..
+    } = input;
+
+    // Only the first encoder can use the QDEC peripheral, so that the indices of the encoders
+    // still match the order that they are defined in
+    if let Some(pins) = pins.iter().skip(1).find(|pins| pins.qdec.is_some()) {
+        return quote_spanned! {
+            pins.qdec.unwrap().span => compile_error!("Only the first encoder can use the QDEC peripheral.");
+        };
+    }
+
+    let qdec_encoder = if pins.first().is_some_and(|pins| pins.qdec.is_some()) {
+        Some(pins.remove(0))
+    } else {
+        None
+    };
+    let encoder_count = pins.len();
+
+    let hal_name: PathSegment = syn::parse_str(crate::hw::HAL_CRATE).unwrap();
+
+    let resolution = |pins: &EncoderPins| {
+        pins.resolution
+            .as_ref()
+            .map(|resolution| quote! { #resolution })
+            .unwrap_or(quote! { ::rumcake::encoder::DEFAULT_ENCODER_RESOLUTION })
+    };
+    let encoders = pins.iter().map(|pins| {
+        let EncoderPins { a, b, .. } = pins;
+        let resolution = resolution(pins);
+
+        quote! {
+            ::rumcake::encoder::GpioEncoder::new(
+                ::rumcake::hw::mcu::input_pin!(#a),
+                ::rumcake::hw::mcu::input_pin!(#b),
+                #resolution
+            )
+        }
+    });
+    let positions = positions.iter().map(|positions| {
+        let EncoderPositions {
+            clockwise,
+            counter_clockwise,
+            ..
+        } = positions;
+        quote! { (#clockwise, #counter_clockwise) }
+    });
+
+    let mut encoders_type = quote! {
+        [
+            ::rumcake::encoder::GpioEncoder<
+                ::rumcake::hw::mcu::#hal_name::gpio::Input<'static>
+            >;
+            #encoder_count
+        ]
+    };
+    let mut encoders = quote! { [#(#encoders),*] };
+
+    if let Some(pins) = qdec_encoder {
+        #[cfg(feature = "nrf")]
+        let qdec_encoder = crate::hw::qdec_encoder(&pins.a, &pins.b, resolution(&pins));
+        #[cfg(not(feature = "nrf"))]
+        let qdec_encoder = quote_spanned! {
+            pins.qdec.unwrap().span => compile_error!("The QDEC peripheral is only available on nRF-based keyboards.");
+        };
+
+        encoders_type = quote! { (::rumcake::hw::mcu::QdecEncoder, #encoders_type) };
+        encoders = quote! { (#qdec_encoder, #encoders) };
+    }
+
+    quote! {
+        const ENCODER_POSITIONS: &'static [((u8, u8), (u8, u8))] = &[#(#positions),*];
+
+        fn get_encoders() -> &'static ::rumcake::encoder::PollableEncoders<impl ::rumcake::encoder::EncoderSet> {
+            static ENCODERS: ::rumcake::once_cell::sync::OnceCell<
+                ::rumcake::encoder::PollableEncoders<#encoders_type>
+            > = ::rumcake::once_cell::sync::OnceCell::new();
+            ENCODERS.get_or_init(|| {
+                ::rumcake::encoder::PollableEncoders::new(#encoders)
+            })
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct AnalogMatrixDefinition {
     pub pos_to_ch_brace: syn::token::Brace,
@@ -947,6 +2087,9 @@ pub fn build_analog_matrix(input: AnalogMatrixDefinition) -> TokenStream {
                         [
                             #([ #ranges ]),*
                         ],
+                        Self::ANALOG_ACTUATION_POINT,
+                        Self::ANALOG_ACTUATION_MODE,
+                        Self::analog_rapid_trigger_sensitivity
                     )
                 )
             })
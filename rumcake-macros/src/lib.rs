@@ -166,12 +166,48 @@ pub fn build_standard_matrix(input: proc_macro::TokenStream) -> proc_macro::Toke
     keyboard::build_standard_matrix(matrix).into()
 }
 
+#[proc_macro]
+pub fn build_interrupt_matrix(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let matrix = parse_macro_input!(input as keyboard::StandardMatrixDefinition);
+    keyboard::build_interrupt_matrix(matrix).into()
+}
+
+#[proc_macro]
+pub fn build_duplex_matrix(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let matrix = parse_macro_input!(input as keyboard::StandardMatrixDefinition);
+    keyboard::build_duplex_matrix(matrix).into()
+}
+
+#[proc_macro]
+pub fn build_round_robin_matrix(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let matrix = parse_macro_input!(input as keyboard::RoundRobinMatrixDefinition);
+    keyboard::build_round_robin_matrix(matrix).into()
+}
+
+#[proc_macro]
+pub fn build_shift_register_matrix(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let matrix = parse_macro_input!(input as keyboard::ShiftRegisterMatrixDefinition);
+    keyboard::build_shift_register_matrix(matrix).into()
+}
+
 #[proc_macro]
 pub fn build_direct_pin_matrix(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let matrix = parse_macro_input!(input as keyboard::MatrixLike<keyboard::OptionalItem<Ident>>);
     keyboard::build_direct_pin_matrix(matrix).into()
 }
 
+#[proc_macro]
+pub fn build_extra_keys(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let extra_keys = parse_macro_input!(input as keyboard::ExtraKeysDefinition);
+    keyboard::build_extra_keys(extra_keys).into()
+}
+
+#[proc_macro]
+pub fn build_encoders(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let encoders = parse_macro_input!(input as keyboard::EncodersDefinition);
+    keyboard::build_encoders(encoders).into()
+}
+
 #[proc_macro]
 pub fn build_analog_matrix(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let matrix = parse_macro_input!(input as keyboard::AnalogMatrixDefinition);
@@ -191,6 +227,14 @@ pub fn remap_matrix(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     keyboard::remap_matrix(remap).into()
 }
 
+mod send_string;
+
+#[proc_macro]
+pub fn send_string(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as send_string::SendStringMacroInput);
+    send_string::send_string(input).into()
+}
+
 mod backlight;
 
 #[proc_macro]
@@ -350,8 +394,20 @@ pub(crate) mod common {
 
 #[proc_macro]
 pub fn input_pin(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let ident = parse_macro_input!(input as Ident);
-    hw::input_pin(ident).into()
+    let args = parse_macro_input!(input with Punctuated<Ident, Token![,]>::parse_terminated);
+    let span = args.span();
+    let mut args = args.into_iter();
+
+    match (args.next(), args.next(), args.next()) {
+        (Some(ident), None, None) => hw::input_pin(ident).into(),
+        (Some(ident), Some(pull), None) => hw::input_pin_with_pull(ident, pull).into(),
+        _ => syn::Error::new(
+            span,
+            "Expected a pin, optionally followed by a pull (`Up`, `Down` or `None`).",
+        )
+        .to_compile_error()
+        .into(),
+    }
 }
 
 #[proc_macro]
@@ -360,6 +416,18 @@ pub fn output_pin(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     hw::output_pin(ident).into()
 }
 
+#[proc_macro]
+pub fn interrupt_input_pin(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let ident = parse_macro_input!(input as Ident);
+    hw::interrupt_input_pin(ident).into()
+}
+
+#[proc_macro]
+pub fn open_drain_pin(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let ident = parse_macro_input!(input as Ident);
+    hw::open_drain_pin(ident).into()
+}
+
 #[proc_macro]
 pub fn setup_i2c(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let args = parse_macro_input!(input with Punctuated<Ident, Token![,]>::parse_terminated);
@@ -409,6 +477,15 @@ pub fn setup_adc_sampler(input: proc_macro::TokenStream) -> proc_macro::TokenStr
     hw::setup_adc_sampler(channels).into()
 }
 
+mod storage;
+
+#[proc_macro]
+#[proc_macro_error]
+pub fn config_partition(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let args = parse_macro_input!(input with Punctuated<storage::ConfigPartitionArg, Token![,]>::parse_terminated);
+    storage::config_partition(args).into()
+}
+
 mod via;
 
 #[proc_macro]
@@ -417,6 +494,13 @@ pub fn setup_macro_buffer(input: proc_macro::TokenStream) -> proc_macro::TokenSt
     via::setup_macro_buffer(args).into()
 }
 
+#[proc_macro]
+#[proc_macro_error]
+pub fn setup_encoder_map(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let args = parse_macro_input!(input with Punctuated<Literal, Token![,]>::parse_terminated);
+    via::setup_encoder_map(args).into()
+}
+
 mod vial;
 
 #[proc_macro]
@@ -424,6 +508,13 @@ pub fn enable_vial_rgb(_input: proc_macro::TokenStream) -> proc_macro::TokenStre
     vial::enable_vial_rgb().into()
 }
 
+#[proc_macro]
+#[proc_macro_error]
+pub fn setup_vial_dynamic_entries(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let args = parse_macro_input!(input with Punctuated<Literal, Token![,]>::parse_terminated);
+    vial::setup_vial_dynamic_entries(args).into()
+}
+
 #[proc_macro_attribute]
 pub fn task(
     _args: proc_macro::TokenStream,
@@ -0,0 +1,153 @@
+use proc_macro2::{Ident, TokenStream};
+use quote::{quote, quote_spanned};
+use syn::parse::Parse;
+use syn::punctuated::Punctuated;
+use syn::spanned::Spanned;
+use syn::{custom_keyword, parenthesized, LitInt, LitStr, Token};
+
+custom_keyword!(delay);
+custom_keyword!(Tap);
+custom_keyword!(Press);
+custom_keyword!(Release);
+custom_keyword!(Delay);
+
+pub enum SendStringItem {
+    Text(LitStr),
+    Tap(Ident),
+    Press(Ident),
+    Release(Ident),
+    Delay(LitInt),
+}
+
+impl Parse for SendStringItem {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let lookahead = input.lookahead1();
+        if lookahead.peek(LitStr) {
+            return input.parse().map(SendStringItem::Text);
+        }
+
+        let content;
+        if lookahead.peek(Tap) {
+            input.parse::<Tap>()?;
+            parenthesized!(content in input);
+            content.parse().map(SendStringItem::Tap)
+        } else if lookahead.peek(Press) {
+            input.parse::<Press>()?;
+            parenthesized!(content in input);
+            content.parse().map(SendStringItem::Press)
+        } else if lookahead.peek(Release) {
+            input.parse::<Release>()?;
+            parenthesized!(content in input);
+            content.parse().map(SendStringItem::Release)
+        } else if lookahead.peek(Delay) {
+            input.parse::<Delay>()?;
+            parenthesized!(content in input);
+            content.parse().map(SendStringItem::Delay)
+        } else {
+            Err(lookahead.error())
+        }
+    }
+}
+
+pub struct SendStringMacroInput {
+    pub delay: Option<LitInt>,
+    pub items: Punctuated<SendStringItem, Token![,]>,
+}
+
+impl Parse for SendStringMacroInput {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let delay = if input.peek(delay) {
+            input.parse::<delay>()?;
+            input.parse::<Token![=]>()?;
+            let value = input.parse()?;
+            input.parse::<Token![;]>()?;
+            Some(value)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            delay,
+            items: Punctuated::parse_terminated(input)?,
+        })
+    }
+}
+
+fn encode_delay(ms: u32) -> Vec<TokenStream> {
+    let mut bytes = vec![quote! { 1 }, quote! { 4 }];
+    bytes.extend(ms.to_string().bytes().map(|digit| quote! { #digit }));
+    bytes.push(quote! { b'|' });
+    bytes
+}
+
+fn parse_delay(lit: &LitInt) -> Result<u32, TokenStream> {
+    match lit.base10_parse::<u32>() {
+        Ok(ms) => Ok(ms),
+        Err(err) => Err(err.to_compile_error()),
+    }
+}
+
+pub fn send_string(input: SendStringMacroInput) -> TokenStream {
+    let delay = match input.delay.as_ref().map(parse_delay).transpose() {
+        Ok(delay) => delay.filter(|ms| *ms > 0),
+        Err(err) => return err,
+    };
+
+    // Each element is a group of bytes for a single key event, or an explicit delay
+    let mut events: Vec<(bool, Vec<TokenStream>)> = Vec::new();
+
+    for item in input.items.iter() {
+        match item {
+            SendStringItem::Text(text) => {
+                for char in text.value().chars() {
+                    if !char.is_ascii() || char == '\x00' || char == '\x01' {
+                        return quote_spanned! {
+                            text.span() => compile_error!("Strings can only contain ASCII characters, excluding NUL and SOH.")
+                        };
+                    }
+                    let byte = char as u8;
+                    events.push((false, vec![quote! { #byte }]));
+                }
+            }
+            SendStringItem::Tap(key)
+            | SendStringItem::Press(key)
+            | SendStringItem::Release(key) => {
+                let variant: u8 = match item {
+                    SendStringItem::Tap(_) => 1,
+                    SendStringItem::Press(_) => 2,
+                    _ => 3,
+                };
+                events.push((
+                    false,
+                    vec![
+                        quote! { 1 },
+                        quote! { #variant },
+                        quote! { ::rumcake::keyberon::key_code::KeyCode::#key as u8 },
+                    ],
+                ));
+            }
+            SendStringItem::Delay(lit) => match parse_delay(lit) {
+                // Delays can't start with a 0 digit, so a delay of 0 is skipped
+                Ok(0) => {}
+                Ok(ms) => events.push((true, encode_delay(ms))),
+                Err(err) => return err,
+            },
+        }
+    }
+
+    let mut bytes: Vec<TokenStream> = Vec::new();
+    let mut last_was_delay = true;
+    for (is_delay, event) in events {
+        if let Some(ms) = delay {
+            if !is_delay && !last_was_delay {
+                bytes.extend(encode_delay(ms));
+            }
+        }
+        last_was_delay = is_delay;
+        bytes.extend(event);
+    }
+
+    quote! {
+        ::rumcake::keyberon::action::Action::Sequence(&[#(#bytes),*].as_slice())
+    }
+}
@@ -0,0 +1,107 @@
+use proc_macro2::TokenStream;
+use proc_macro_error::abort;
+use quote::quote;
+use syn::parse::Parse;
+use syn::punctuated::Punctuated;
+use syn::spanned::Spanned;
+use syn::{Ident, LitInt, Token};
+
+pub struct ConfigPartitionArg {
+    name: Ident,
+    value: LitInt,
+}
+
+impl Parse for ConfigPartitionArg {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        input.parse::<Token![=]>()?;
+        let value = input.parse()?;
+        Ok(Self { name, value })
+    }
+}
+
+/// Parse a size in bytes. Sizes can optionally have a `K` suffix, to specify a size in KiB.
+fn parse_size(lit: &LitInt) -> usize {
+    let multiplier = match lit.suffix() {
+        "" => 1,
+        "K" | "k" => 1024,
+        _ => abort!(
+            lit.span(),
+            "Unknown size suffix. Use `K` to specify a size in KiB."
+        ),
+    };
+
+    match lit.base10_digits().parse::<usize>() {
+        Ok(value) => value * multiplier,
+        Err(_) => abort!(lit.span(), "Invalid size."),
+    }
+}
+
+pub fn config_partition(args: Punctuated<ConfigPartitionArg, Token![,]>) -> TokenStream {
+    let mut size = None;
+    let mut align = None;
+
+    for arg in args.iter() {
+        match arg.name.to_string().as_str() {
+            "size" => size = Some(parse_size(&arg.value)),
+            "align" => align = Some(parse_size(&arg.value)),
+            _ => abort!(
+                arg.name.span(),
+                "Unknown argument. Expected `size` or `align`."
+            ),
+        }
+    }
+
+    let Some(size) = size else {
+        abort!(
+            args.span(),
+            "`size` must be specified. To use the `CONFIG` section in `memory.x` instead, remove this macro invocation, and add `use_memory_x` to the `storage` settings of your `#[keyboard]` macro."
+        )
+    };
+
+    // Default to 4KiB alignment, which is a multiple of the erase size of the flash of all
+    // currently supported MCUs.
+    let align = align.unwrap_or(4096);
+
+    if size == 0 {
+        abort!(args.span(), "`size` must be greater than 0.")
+    }
+
+    if !align.is_power_of_two() {
+        abort!(args.span(), "`align` must be a power of two.")
+    }
+
+    if size % align != 0 {
+        abort!(args.span(), "`size` must be a multiple of `align`.")
+    }
+
+    let align = proc_macro2::Literal::usize_unsuffixed(align);
+
+    quote! {
+        #[no_mangle]
+        fn __rumcake_config_partition() -> ::rumcake::storage::ConfigPartition {
+            #[repr(C, align(#align))]
+            struct ConfigPartitionData([u8; #size]);
+
+            // Placed by `rumcake_config.x` in a NOLOAD section, so this is never written to the
+            // firmware image
+            #[used]
+            #[link_section = ".rumcake_config"]
+            static CONFIG_PARTITION: ConfigPartitionData = ConfigPartitionData([0xFF; #size]);
+
+            extern "C" {
+                static __rumcake_config_start: u8;
+                static __rumcake_config_end: u8;
+            }
+
+            unsafe {
+                ::rumcake::storage::ConfigPartition {
+                    start: ::core::ptr::addr_of!(__rumcake_config_start) as usize
+                        - ::rumcake::hw::mcu::FLASH_ORIGIN,
+                    end: ::core::ptr::addr_of!(__rumcake_config_end) as usize
+                        - ::rumcake::hw::mcu::FLASH_ORIGIN,
+                }
+            }
+        }
+    }
+}
@@ -31,3 +31,24 @@ pub fn setup_macro_buffer(args: Punctuated<Literal, Token![,]>) -> TokenStream {
         }
     }
 }
+
+pub fn setup_encoder_map(args: Punctuated<Literal, Token![,]>) -> TokenStream {
+    let mut args = args.iter();
+
+    let layer_count = args.next().expect_or_abort("Missing layer count argument.");
+    let encoder_count = args
+        .next()
+        .expect_or_abort("Missing encoder count argument.");
+
+    if let Some(literal) = args.next() {
+        abort!(literal.span(), "Unexpected extra arguments.")
+    }
+
+    quote! {
+        fn get_encoder_map() -> Option<&'static mut [[u16; 2]]> {
+            static mut ENCODER_MAP: [[u16; 2]; #layer_count * #encoder_count] =
+                [[0; 2]; #layer_count * #encoder_count];
+            Some(unsafe { &mut ENCODER_MAP })
+        }
+    }
+}
@@ -1,5 +1,8 @@
-use proc_macro2::TokenStream;
+use proc_macro2::{Literal, TokenStream};
+use proc_macro_error::{abort, OptionExt};
 use quote::quote;
+use syn::punctuated::Punctuated;
+use syn::Token;
 
 pub fn enable_vial_rgb() -> TokenStream {
     quote! {
@@ -15,3 +18,43 @@ pub fn enable_vial_rgb() -> TokenStream {
         }
     }
 }
+
+pub fn setup_vial_dynamic_entries(args: Punctuated<Literal, Token![,]>) -> TokenStream {
+    let mut args = args.iter();
+
+    let tap_dance_count = args
+        .next()
+        .expect_or_abort("Missing tap dance count argument.");
+    let combo_count = args.next().expect_or_abort("Missing combo count argument.");
+    let key_override_count = args
+        .next()
+        .expect_or_abort("Missing key override count argument.");
+
+    if let Some(literal) = args.next() {
+        abort!(literal.span(), "Unexpected extra arguments.")
+    }
+
+    quote! {
+        const VIAL_TAP_DANCE_ENTRIES: u8 = #tap_dance_count;
+        const VIAL_COMBO_ENTRIES: u8 = #combo_count;
+        const VIAL_KEY_OVERRIDE_ENTRIES: u8 = #key_override_count;
+
+        fn get_tap_dance_entries() -> &'static mut [::rumcake::vial::TapDanceEntry] {
+            static mut TAP_DANCE_ENTRIES: [::rumcake::vial::TapDanceEntry; #tap_dance_count] =
+                [::rumcake::vial::TapDanceEntry::EMPTY; #tap_dance_count];
+            unsafe { &mut TAP_DANCE_ENTRIES }
+        }
+
+        fn get_combo_entries() -> &'static mut [::rumcake::vial::ComboEntry] {
+            static mut COMBO_ENTRIES: [::rumcake::vial::ComboEntry; #combo_count] =
+                [::rumcake::vial::ComboEntry::EMPTY; #combo_count];
+            unsafe { &mut COMBO_ENTRIES }
+        }
+
+        fn get_key_override_entries() -> &'static mut [::rumcake::vial::KeyOverrideEntry] {
+            static mut KEY_OVERRIDE_ENTRIES: [::rumcake::vial::KeyOverrideEntry; #key_override_count] =
+                [::rumcake::vial::KeyOverrideEntry::EMPTY; #key_override_count];
+            unsafe { &mut KEY_OVERRIDE_ENTRIES }
+        }
+    }
+}
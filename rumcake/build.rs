@@ -0,0 +1,17 @@
+use std::path::PathBuf;
+use std::{env, fs};
+
+fn main() {
+    // Make `rumcake_config.x` available to the linker, so that keyboards can place the config
+    // partition reserved by `config_partition!` using `-Trumcake_config.x`
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::write(
+        out.join("rumcake_config.x"),
+        include_bytes!("rumcake_config.x"),
+    )
+    .unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+
+    println!("cargo:rerun-if-changed=rumcake_config.x");
+    println!("cargo:rerun-if-changed=build.rs");
+}
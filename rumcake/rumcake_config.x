@@ -0,0 +1,20 @@
+/* Places the config partition reserved with `rumcake::storage::config_partition!` right after the
+   vector table, so that its address does not change when the size of your firmware changes. The
+   section is marked as NOLOAD, so it is not part of your firmware image, and your saved settings
+   are kept when you flash your firmware.
+
+   To use this file, add `-Trumcake_config.x` to the linker arguments of your keyboard crate. */
+
+SECTIONS
+{
+  .rumcake_config (NOLOAD) :
+  {
+    __rumcake_config_start = .;
+    KEEP(*(.rumcake_config .rumcake_config.*));
+    __rumcake_config_end = .;
+  } > FLASH
+
+  /* cortex-m-rt places `.text` right after the vector table, unless `_stext` is defined */
+  _stext = ADDR(.rumcake_config) + SIZEOF(.rumcake_config);
+}
+INSERT AFTER .vector_table;
@@ -0,0 +1,324 @@
+//! Accessibility input filters, which make your keyboard easier to use for people with motor
+//! impairments.
+//!
+//! The filters are applied to matrix events before they are sent to the layout, and are all
+//! disabled by default:
+//! - Sticky keys: tapping a modifier latches it, so that it applies to the next key that is
+//!   typed. Tapping a latched modifier again releases it.
+//! - Slow keys: keys must be held for a minimum amount of time before they are pressed. Keys that
+//!   are released before then are ignored.
+//! - Bounce keys: after a key is released, presses of the same key are ignored for an amount of
+//!   time.
+//!
+//! The filters can be changed at runtime using [`AccessibilityCommand`]s. If you specified a
+//! storage driver, and enabled the `storage` feature, the settings are saved, and restored when
+//! your keyboard restarts.
+
+use defmt::{debug, info, Debug2Format};
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use keyberon::action::Action;
+use keyberon::key_code::KeyCode;
+use keyberon::layout::{Event, Layout as KeyberonLayout};
+use serde::{Deserialize, Serialize};
+
+use crate::keyboard::Keycode;
+use crate::State;
+
+/// Maximum number of keys that each filter can track at once.
+const MAX_FILTERED_KEYS: usize = 8;
+
+/// Settings used by the accessibility input filters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessibilityConfig {
+    /// Whether tapping a modifier latches it until the next key is typed.
+    pub sticky_keys: bool,
+    /// How long a key must be held before it is pressed, in milliseconds. Set to 0 to disable.
+    pub slow_keys: u16,
+    /// How long presses of a key are ignored after it is released, in milliseconds. Set to 0 to
+    /// disable.
+    pub bounce_keys: u16,
+}
+
+impl AccessibilityConfig {
+    /// A config with all filters disabled.
+    pub const DISABLED: Self = Self {
+        sticky_keys: false,
+        slow_keys: 0,
+        bounce_keys: 0,
+    };
+}
+
+#[cfg(feature = "storage")]
+impl crate::storage::StoredData for AccessibilityConfig {
+    const SCHEMA_VERSION: u16 = 1;
+}
+
+#[derive(Debug, Clone, Copy)]
+/// An enumeration of possible commands used to change the state of
+/// [`ACCESSIBILITY_CONFIG_STATE`].
+pub enum AccessibilityCommand {
+    /// Enable or disable sticky keys.
+    SetStickyKeys(bool),
+    /// Toggle sticky keys.
+    ToggleStickyKeys,
+    /// Set the slow keys time, in milliseconds. Set to 0 to disable.
+    SetSlowKeys(u16),
+    /// Set the bounce keys time, in milliseconds. Set to 0 to disable.
+    SetBounceKeys(u16),
+    /// Disable all filters.
+    Reset,
+}
+
+/// State that contains the settings used by the accessibility input filters.
+pub static ACCESSIBILITY_CONFIG_STATE: State<AccessibilityConfig> = State::new(
+    AccessibilityConfig::DISABLED,
+    &[
+        #[cfg(feature = "storage")]
+        &storage::ACCESSIBILITY_CONFIG_STATE_STORAGE_LISTENER,
+    ],
+);
+
+pub(crate) async fn process_accessibility_command(command: AccessibilityCommand) {
+    ACCESSIBILITY_CONFIG_STATE
+        .update(|config| match command {
+            AccessibilityCommand::SetStickyKeys(enabled) => config.sticky_keys = enabled,
+            AccessibilityCommand::ToggleStickyKeys => config.sticky_keys = !config.sticky_keys,
+            AccessibilityCommand::SetSlowKeys(time) => config.slow_keys = time,
+            AccessibilityCommand::SetBounceKeys(time) => config.bounce_keys = time,
+            AccessibilityCommand::Reset => **config = AccessibilityConfig::DISABLED,
+        })
+        .await;
+
+    info!(
+        "[ACCESSIBILITY] Accessibility config: {:?}",
+        Debug2Format(&ACCESSIBILITY_CONFIG_STATE.get().await)
+    );
+}
+
+/// Events produced by [`AccessibilityFilter`], which should be sent to the layout in order.
+pub(crate) type AccessibilityEvents = Vec<Event, { MAX_FILTERED_KEYS * 2 }>;
+
+/// Applies the accessibility input filters to matrix events.
+pub(crate) struct AccessibilityFilter {
+    // Keys whose press was ignored, so their release should be ignored too
+    ignored: Vec<(u8, u8), MAX_FILTERED_KEYS>,
+    // Keys that were released recently, used by bounce keys
+    released: Vec<((u8, u8), Instant), MAX_FILTERED_KEYS>,
+    // Keys that must be held for the slow keys time before they are pressed
+    waiting: Vec<((u8, u8), Instant), MAX_FILTERED_KEYS>,
+    // Modifiers that are held, and whether another key was pressed while they were held
+    held_modifiers: Vec<((u8, u8), bool), MAX_FILTERED_KEYS>,
+    // Modifiers that were tapped, and are still pressed in the layout
+    latched: Vec<(u8, u8), MAX_FILTERED_KEYS>,
+    // Latched modifiers that were pressed again, and should be released when they are released
+    unlatching: Vec<(u8, u8), MAX_FILTERED_KEYS>,
+}
+
+impl AccessibilityFilter {
+    pub(crate) const fn new() -> Self {
+        Self {
+            ignored: Vec::new(),
+            released: Vec::new(),
+            waiting: Vec::new(),
+            held_modifiers: Vec::new(),
+            latched: Vec::new(),
+            unlatching: Vec::new(),
+        }
+    }
+
+    /// Process a matrix event, returning the events that should be sent to the layout.
+    pub(crate) fn event<const C: usize, const R: usize, const L: usize>(
+        &mut self,
+        layout: &KeyberonLayout<C, R, L, Keycode>,
+        config: &AccessibilityConfig,
+        event: Event,
+    ) -> AccessibilityEvents {
+        let mut events = AccessibilityEvents::new();
+        let coord = event.coord();
+        let now = Instant::now();
+
+        match event {
+            Event::Press(_, _) => {
+                let bounce_time = Duration::from_millis(config.bounce_keys as u64);
+                self.released
+                    .retain(|(_, time)| now.duration_since(*time) < bounce_time);
+
+                if self.released.iter().any(|(key, _)| *key == coord) {
+                    debug!("[ACCESSIBILITY] Ignoring bounced key {}", coord);
+                    let _ = self.ignored.push(coord);
+                    return events;
+                }
+
+                if config.slow_keys > 0 && self.waiting.push((coord, now)).is_ok() {
+                    return events;
+                }
+
+                self.press(layout, config, coord, &mut events);
+            }
+            Event::Release(_, _) => {
+                if let Some(idx) = self.ignored.iter().position(|key| *key == coord) {
+                    self.ignored.swap_remove(idx);
+                    return events;
+                }
+
+                // The key was released before the slow keys time, so it was never pressed
+                if let Some(idx) = self.waiting.iter().position(|(key, _)| *key == coord) {
+                    self.waiting.swap_remove(idx);
+                    return events;
+                }
+
+                if config.bounce_keys > 0 {
+                    self.released.retain(|(key, _)| *key != coord);
+                    if self.released.is_full() {
+                        self.released.remove(0);
+                    }
+                    let _ = self.released.push((coord, now));
+                }
+
+                self.release(config, coord, &mut events);
+            }
+        }
+
+        events
+    }
+
+    /// Press the keys that have been held for the slow keys time, and release latched modifiers if
+    /// sticky keys was disabled, returning the events that should be sent to the layout.
+    pub(crate) fn tick<const C: usize, const R: usize, const L: usize>(
+        &mut self,
+        layout: &KeyberonLayout<C, R, L, Keycode>,
+        config: &AccessibilityConfig,
+    ) -> AccessibilityEvents {
+        let mut events = AccessibilityEvents::new();
+
+        if !config.sticky_keys {
+            self.release_latched(&mut events);
+        }
+
+        let slow_time = Duration::from_millis(config.slow_keys as u64);
+        let mut i = 0;
+        while i < self.waiting.len() {
+            if self.waiting[i].1.elapsed() >= slow_time {
+                let (coord, _) = self.waiting.swap_remove(i);
+                self.press(layout, config, coord, &mut events);
+            } else {
+                i += 1;
+            }
+        }
+
+        events
+    }
+
+    fn press<const C: usize, const R: usize, const L: usize>(
+        &mut self,
+        layout: &KeyberonLayout<C, R, L, Keycode>,
+        config: &AccessibilityConfig,
+        coord: (u8, u8),
+        events: &mut AccessibilityEvents,
+    ) {
+        if config.sticky_keys {
+            for (_, used) in self.held_modifiers.iter_mut() {
+                *used = true;
+            }
+
+            if is_modifier(layout, coord) {
+                // The modifier is still pressed in the layout, so it is released when this key is
+                // released
+                if let Some(idx) = self.latched.iter().position(|key| *key == coord) {
+                    self.latched.swap_remove(idx);
+                    let _ = self.unlatching.push(coord);
+                    return;
+                }
+
+                let _ = self.held_modifiers.push((coord, false));
+            }
+        }
+
+        let _ = events.push(Event::Press(coord.0, coord.1));
+    }
+
+    fn release(
+        &mut self,
+        config: &AccessibilityConfig,
+        coord: (u8, u8),
+        events: &mut AccessibilityEvents,
+    ) {
+        let _ = events.push(Event::Release(coord.0, coord.1));
+
+        if let Some(idx) = self.unlatching.iter().position(|key| *key == coord) {
+            self.unlatching.swap_remove(idx);
+            return;
+        }
+
+        if let Some(idx) = self
+            .held_modifiers
+            .iter()
+            .position(|(key, _)| *key == coord)
+        {
+            let (_, used) = self.held_modifiers.swap_remove(idx);
+
+            // The modifier was tapped, so keep it pressed until the next key is typed
+            if !used && config.sticky_keys && self.latched.push(coord).is_ok() {
+                debug!("[ACCESSIBILITY] Latched modifier {}", coord);
+                events.pop();
+            }
+            return;
+        }
+
+        self.release_latched(events);
+    }
+
+    fn release_latched(&mut self, events: &mut AccessibilityEvents) {
+        for (row, col) in self.latched.iter() {
+            let _ = events.push(Event::Release(*row, *col));
+        }
+        self.latched.clear();
+    }
+}
+
+fn is_modifier<const C: usize, const R: usize, const L: usize>(
+    layout: &KeyberonLayout<C, R, L, Keycode>,
+    coord: (u8, u8),
+) -> bool {
+    matches!(
+        layout.resolve_action(coord),
+        Action::KeyCode(key) if (KeyCode::LCtrl..=KeyCode::RGui).contains(&key)
+    )
+}
+
+#[cfg(feature = "storage")]
+pub mod storage {
+    use embassy_sync::signal::Signal;
+
+    use crate::hw::mcu::RawMutex;
+    use crate::storage::{FlashStorage, StorageDevice};
+
+    use super::ACCESSIBILITY_CONFIG_STATE;
+
+    pub(super) static ACCESSIBILITY_CONFIG_STATE_STORAGE_LISTENER: Signal<RawMutex, ()> =
+        Signal::new();
+
+    /// Signal used to save the accessibility settings immediately, instead of waiting for the
+    /// save policy defined in [`StorageDevice`].
+    pub(crate) static ACCESSIBILITY_CONFIG_SAVE_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+    /// Task that restores the accessibility settings, and saves them when they are changed.
+    #[rumcake_macros::task]
+    pub async fn accessibility_config_storage_task<K: StorageDevice, F: FlashStorage>(
+        _k: K,
+        database: &crate::storage::StorageService<'static, F>,
+    ) where
+        [(); F::ERASE_SIZE]:,
+        [(); F::REGION_SIZE]:,
+    {
+        database
+            .persist_state::<K, _>(
+                crate::storage::StorageKey::AccessibilityConfig,
+                &ACCESSIBILITY_CONFIG_STATE,
+                &ACCESSIBILITY_CONFIG_STATE_STORAGE_LISTENER,
+                &ACCESSIBILITY_CONFIG_SAVE_SIGNAL,
+            )
+            .await
+    }
+}
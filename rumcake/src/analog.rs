@@ -0,0 +1,506 @@
+//! Per-key actuation settings for analog matrices, which can be changed at runtime.
+//!
+//! By default, every key in an analog matrix uses the actuation point, actuation mode and rapid
+//! trigger sensitivity defined in [`KeyboardMatrix`]. The settings of individual keys can be
+//! changed from your own code using [`set_key_config`], or by a host app using raw HID reports
+//! handled by [`handle_raw_hid_report`]. Changes are applied the next time the matrix is scanned.
+//!
+//! If you specified a storage driver, and enabled the `storage` feature, changed settings are
+//! saved, and restored when your keyboard restarts. Only keys whose settings have been changed are
+//! stored, so the rest of your keys keep using the defaults defined in your firmware.
+//!
+//! Analog matrices can also be calibrated, to account for differences between the sensors of each
+//! key. While calibrating (see [`start_calibration`]), the lowest and highest raw readings of each
+//! key are recorded, and no keys are pressed. After you press every key all the way down, the
+//! recorded readings replace the ranges defined in your firmware. Calibration finishes after
+//! [`KeyboardMatrix::ANALOG_CALIBRATION_TIMEOUT_MS`], or when [`finish_calibration`] is called.
+//! With storage enabled, the calibration is also saved.
+
+use core::ops::Range;
+
+use defmt::{info, warn};
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use keyberon::analog::AnalogAcutationMode;
+use serde::{Deserialize, Serialize};
+
+use crate::hw::mcu::RawMutex;
+use crate::keyboard::{KeyboardMatrix, Pollable};
+use crate::State;
+
+/// Maximum number of keys whose settings can be changed from the defaults.
+pub const MAX_ANALOG_KEY_CONFIGS: usize = 128;
+
+/// First byte of the raw HID reports handled by [`handle_raw_hid_report`].
+pub const ANALOG_CONFIG_COMMAND_ID: u8 = 0xA0;
+
+/// Actuation settings of a key in an analog matrix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnalogKeyConfig {
+    /// Actuation point, from 0 (unpressed) to 255 (fully pressed).
+    pub actuation_point: u8,
+    /// Actuation mode. Use [`AnalogAcutationMode::Rapid`] or
+    /// [`AnalogAcutationMode::ContinuousRapid`] to enable rapid trigger.
+    #[serde(with = "mode")]
+    pub mode: AnalogAcutationMode,
+    /// How far the key must travel in the opposite direction to be released or pressed again,
+    /// from 0 to 255. See [`KeyboardMatrix::ANALOG_RAPID_TRIGGER_SENSITIVITY`].
+    pub rapid_trigger_sensitivity: u8,
+}
+
+impl AnalogKeyConfig {
+    /// Obtain the default settings of the key at the given matrix position, as defined in
+    /// [`KeyboardMatrix`].
+    pub fn default_for<K: KeyboardMatrix>(row: u8, col: u8) -> Self {
+        Self {
+            actuation_point: K::ANALOG_ACTUATION_POINT,
+            mode: K::ANALOG_ACTUATION_MODE,
+            rapid_trigger_sensitivity: K::analog_rapid_trigger_sensitivity(row, col),
+        }
+    }
+}
+
+/// Settings of the keys that have been changed from the defaults, along with their matrix
+/// position (row, column).
+pub type AnalogKeyConfigs = Vec<((u8, u8), AnalogKeyConfig), MAX_ANALOG_KEY_CONFIGS>;
+
+#[cfg(feature = "storage")]
+impl crate::storage::StoredData for AnalogKeyConfigs {
+    const SCHEMA_VERSION: u16 = 1;
+}
+
+pub(crate) static ANALOG_KEY_CONFIGS_MATRIX_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+/// State that contains the settings of the keys that have been changed from the defaults.
+pub static ANALOG_KEY_CONFIGS_STATE: State<AnalogKeyConfigs> = State::new(
+    Vec::new(),
+    &[
+        &ANALOG_KEY_CONFIGS_MATRIX_LISTENER,
+        #[cfg(feature = "storage")]
+        &storage::ANALOG_KEY_CONFIGS_STATE_STORAGE_LISTENER,
+    ],
+);
+
+/// Obtain the settings of a key from `configs`, or its default settings if they haven't been
+/// changed. Returns `None` if the key is out of bounds.
+fn find_key_config<K: KeyboardMatrix>(
+    configs: &AnalogKeyConfigs,
+    row: u8,
+    col: u8,
+) -> Option<AnalogKeyConfig> {
+    if row as usize >= K::MATRIX_ROWS || col as usize >= K::MATRIX_COLS {
+        return None;
+    }
+
+    Some(
+        configs
+            .iter()
+            .find(|(key, _)| *key == (row, col))
+            .map_or_else(
+                || AnalogKeyConfig::default_for::<K>(row, col),
+                |(_, config)| *config,
+            ),
+    )
+}
+
+/// Change the settings of a key in `configs`. Returns `false` if the key is out of bounds, or if
+/// there are too many changed keys.
+fn update_key_config<K: KeyboardMatrix>(
+    configs: &mut AnalogKeyConfigs,
+    row: u8,
+    col: u8,
+    config: AnalogKeyConfig,
+) -> bool {
+    if row as usize >= K::MATRIX_ROWS || col as usize >= K::MATRIX_COLS {
+        warn!("[ANALOG] Tried to change the settings of a key that is out of bounds.");
+        return false;
+    }
+
+    if let Some((_, existing)) = configs.iter_mut().find(|(key, _)| *key == (row, col)) {
+        *existing = config;
+        return true;
+    }
+
+    if configs.push(((row, col), config)).is_err() {
+        warn!("[ANALOG] Too many keys have changed settings.");
+        return false;
+    }
+
+    true
+}
+
+/// Obtain the settings of the key at the given matrix position (row, column). Returns `None` if
+/// the key is out of bounds.
+pub async fn get_key_config<K: KeyboardMatrix>(row: u8, col: u8) -> Option<AnalogKeyConfig> {
+    find_key_config::<K>(&ANALOG_KEY_CONFIGS_STATE.get().await, row, col)
+}
+
+/// Change the settings of the key at the given matrix position (row, column). If storage is
+/// enabled, the new settings are saved. Returns `false` if the key is out of bounds, or if the
+/// settings of too many keys have been changed (see [`MAX_ANALOG_KEY_CONFIGS`]).
+pub async fn set_key_config<K: KeyboardMatrix>(row: u8, col: u8, config: AnalogKeyConfig) -> bool {
+    ANALOG_KEY_CONFIGS_STATE
+        .update(|configs| update_key_config::<K>(configs, row, col, config))
+        .await
+}
+
+/// Restore the default settings of every key, as defined in [`KeyboardMatrix`]. If storage is
+/// enabled, the saved settings are also erased.
+pub async fn reset() {
+    ANALOG_KEY_CONFIGS_STATE.set(Vec::new()).await;
+}
+
+/// Apply the settings in [`ANALOG_KEY_CONFIGS_STATE`] to the matrix. Keys that don't have changed
+/// settings are set back to the defaults.
+pub(crate) fn apply_key_configs<K: KeyboardMatrix>(
+    matrix: &mut impl Pollable,
+    configs: &AnalogKeyConfigs,
+) {
+    for row in 0..K::MATRIX_ROWS as u8 {
+        for col in 0..K::MATRIX_COLS as u8 {
+            if let Some(config) = find_key_config::<K>(configs, row, col) {
+                matrix.set_analog_key_config(row, col, config);
+            }
+        }
+    }
+}
+
+/// Commands used to start or finish calibrating the analog matrix.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum CalibrationCommand {
+    Start,
+    Finish,
+}
+
+/// Channel used to send calibration commands to the matrix polling task.
+pub(crate) static ANALOG_CALIBRATION_CHANNEL: Channel<RawMutex, CalibrationCommand, 1> =
+    Channel::new();
+
+/// Start calibrating the analog matrix. Returns `false` if another calibration command is still
+/// waiting to be processed.
+pub fn start_calibration() -> bool {
+    ANALOG_CALIBRATION_CHANNEL
+        .try_send(CalibrationCommand::Start)
+        .is_ok()
+}
+
+/// Finish calibrating the analog matrix early, instead of waiting for
+/// [`KeyboardMatrix::ANALOG_CALIBRATION_TIMEOUT_MS`]. Returns `false` if another calibration
+/// command is still waiting to be processed.
+pub fn finish_calibration() -> bool {
+    ANALOG_CALIBRATION_CHANNEL
+        .try_send(CalibrationCommand::Finish)
+        .is_ok()
+}
+
+/// Records the lowest and highest raw readings of each key in an analog matrix while it is being
+/// calibrated.
+pub struct AnalogCalibrator<const CS: usize, const RS: usize> {
+    active: bool,
+    min: [[u16; CS]; RS],
+    max: [[u16; CS]; RS],
+}
+
+impl<const CS: usize, const RS: usize> AnalogCalibrator<CS, RS> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            active: false,
+            min: [[u16::MAX; CS]; RS],
+            max: [[0; CS]; RS],
+        }
+    }
+
+    pub(crate) fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Start recording readings, discarding readings from the last calibration.
+    pub(crate) fn start(&mut self) {
+        *self = Self::new();
+        self.active = true;
+    }
+
+    /// Stop recording readings. Returns `false` if the calibration wasn't started.
+    pub(crate) fn stop(&mut self) -> bool {
+        core::mem::replace(&mut self.active, false)
+    }
+
+    pub(crate) fn record(&mut self, row: usize, col: usize, value: u16) {
+        if let (Some(min), Some(max)) = (
+            self.min.get_mut(row).and_then(|row| row.get_mut(col)),
+            self.max.get_mut(row).and_then(|row| row.get_mut(col)),
+        ) {
+            *min = (*min).min(value);
+            *max = (*max).max(value);
+        }
+    }
+
+    /// Obtain the range of readings recorded for the given key, from the lowest reading (the key
+    /// at rest) to the highest reading (the key fully pressed). Returns `None` if the key travelled
+    /// less than `min_travel`.
+    pub(crate) fn recorded_range(
+        &self,
+        row: usize,
+        col: usize,
+        min_travel: u16,
+    ) -> Option<Range<u16>> {
+        let min = *self.min.get(row)?.get(col)?;
+        let max = *self.max.get(row)?.get(col)?;
+        (max >= min && max - min >= min_travel).then_some(min..max)
+    }
+}
+
+/// Process calibration commands, and finish the calibration once it times out. `started` keeps
+/// track of when the current calibration was started.
+pub(crate) fn poll_calibration<K: KeyboardMatrix>(
+    matrix: &mut impl Pollable,
+    started: &mut Option<Instant>,
+) {
+    let timeout = Duration::from_millis(K::ANALOG_CALIBRATION_TIMEOUT_MS as u64);
+
+    let finish = match ANALOG_CALIBRATION_CHANNEL.try_receive() {
+        Ok(CalibrationCommand::Start) => {
+            if matrix.start_analog_calibration() {
+                info!("[ANALOG] Started calibration, press every key all the way down.");
+                *started = Some(Instant::now());
+            }
+            false
+        }
+        Ok(CalibrationCommand::Finish) => true,
+        Err(_) => started.is_some_and(|started| started.elapsed() >= timeout),
+    };
+
+    if finish
+        && started.take().is_some()
+        && matrix.finish_analog_calibration(K::ANALOG_CALIBRATION_MIN_TRAVEL)
+    {
+        info!("[ANALOG] Finished calibration.");
+
+        #[cfg(feature = "storage")]
+        storage::ANALOG_CALIBRATION_SAVE_SIGNAL.signal(());
+    }
+}
+
+const SUBCOMMAND_GET: u8 = 0x01;
+const SUBCOMMAND_SET: u8 = 0x02;
+const SUBCOMMAND_RESET: u8 = 0x03;
+const SUBCOMMAND_SAVE: u8 = 0x04;
+const SUBCOMMAND_START_CALIBRATION: u8 = 0x05;
+const SUBCOMMAND_FINISH_CALIBRATION: u8 = 0x06;
+const SUBCOMMAND_ERROR: u8 = 0xFF;
+
+fn mode_to_u8(mode: AnalogAcutationMode) -> u8 {
+    match mode {
+        AnalogAcutationMode::Static => 0,
+        AnalogAcutationMode::Rapid => 1,
+        AnalogAcutationMode::ContinuousRapid => 2,
+    }
+}
+
+fn mode_from_u8(mode: u8) -> Option<AnalogAcutationMode> {
+    match mode {
+        0 => Some(AnalogAcutationMode::Static),
+        1 => Some(AnalogAcutationMode::Rapid),
+        2 => Some(AnalogAcutationMode::ContinuousRapid),
+        _ => None,
+    }
+}
+
+mod mode {
+    use keyberon::analog::AnalogAcutationMode;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(
+        mode: &AnalogAcutationMode,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(super::mode_to_u8(*mode))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<AnalogAcutationMode, D::Error> {
+        super::mode_from_u8(u8::deserialize(deserializer)?)
+            .ok_or_else(|| D::Error::custom("invalid actuation mode"))
+    }
+}
+
+/// Handle a raw HID report used to get or change the per-key actuation settings. This can be
+/// called from [`crate::raw_hid::RawHIDDevice::handle_raw_hid_report`], or
+/// [`crate::via::ViaKeyboard::handle_via_command`] if you are using Via or Vial.
+///
+/// Reports that start with [`ANALOG_CONFIG_COMMAND_ID`] are handled, and modified in place to form
+/// a response. Returns `false` for other reports, so that they can be handled by your own code.
+/// The second byte contains a subcommand:
+/// - `0x01` (get): bytes 2 and 3 contain the row and column of a key. The response contains the
+///   key's actuation point, actuation mode (0 = static, 1 = rapid, 2 = continuous rapid) and
+///   rapid trigger sensitivity, in bytes 4, 5 and 6.
+/// - `0x02` (set): bytes 2 to 6 are laid out like the response to `0x01`, and contain the key's
+///   new settings.
+/// - `0x03` (reset): restore the default settings of every key.
+/// - `0x04` (save): save the settings immediately, instead of waiting for the save policy defined
+///   in [`crate::storage::StorageDevice`].
+/// - `0x05` (start calibration): see [`start_calibration`].
+/// - `0x06` (finish calibration): see [`finish_calibration`].
+///
+/// If the subcommand fails, the second byte of the response is set to `0xFF`.
+pub fn handle_raw_hid_report<K: KeyboardMatrix>(data: &mut [u8]) -> bool {
+    if data.len() < 7 || data[0] != ANALOG_CONFIG_COMMAND_ID {
+        return false;
+    }
+
+    let (row, col) = (data[2], data[3]);
+
+    let success = match data[1] {
+        SUBCOMMAND_GET => match ANALOG_KEY_CONFIGS_STATE
+            .try_get()
+            .and_then(|configs| find_key_config::<K>(&configs, row, col))
+        {
+            Some(config) => {
+                data[4] = config.actuation_point;
+                data[5] = mode_to_u8(config.mode);
+                data[6] = config.rapid_trigger_sensitivity;
+                true
+            }
+            None => false,
+        },
+        SUBCOMMAND_SET => match (mode_from_u8(data[5]), ANALOG_KEY_CONFIGS_STATE.try_get()) {
+            (Some(mode), Some(mut configs)) => {
+                let config = AnalogKeyConfig {
+                    actuation_point: data[4],
+                    mode,
+                    rapid_trigger_sensitivity: data[6],
+                };
+                update_key_config::<K>(&mut configs, row, col, config)
+                    && ANALOG_KEY_CONFIGS_STATE.try_set(configs)
+            }
+            _ => false,
+        },
+        SUBCOMMAND_RESET => ANALOG_KEY_CONFIGS_STATE.try_set(Vec::new()),
+        SUBCOMMAND_SAVE => {
+            #[cfg(feature = "storage")]
+            storage::ANALOG_KEY_CONFIGS_SAVE_SIGNAL.signal(());
+            true
+        }
+        SUBCOMMAND_START_CALIBRATION => start_calibration(),
+        SUBCOMMAND_FINISH_CALIBRATION => finish_calibration(),
+        _ => false,
+    };
+
+    if !success {
+        warn!(
+            "[ANALOG] Could not handle analog config command {:X}",
+            data[1]
+        );
+        data[1] = SUBCOMMAND_ERROR;
+    }
+
+    true
+}
+
+#[cfg(feature = "storage")]
+pub mod storage {
+    use defmt::warn;
+    use embassy_sync::signal::Signal;
+
+    use crate::hw::mcu::RawMutex;
+    use crate::keyboard::{KeyboardMatrix, Pollable};
+    use crate::storage::{FlashStorage, StorageDevice, StorageKey};
+
+    use super::ANALOG_KEY_CONFIGS_STATE;
+
+    pub(super) static ANALOG_KEY_CONFIGS_STATE_STORAGE_LISTENER: Signal<RawMutex, ()> =
+        Signal::new();
+
+    /// Signal used to save the per-key actuation settings immediately, instead of waiting for the
+    /// save policy defined in [`StorageDevice`].
+    pub(crate) static ANALOG_KEY_CONFIGS_SAVE_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+    /// Task that restores the per-key actuation settings, and saves them when they are changed.
+    #[rumcake_macros::task]
+    pub async fn analog_key_configs_storage_task<K: StorageDevice, F: FlashStorage>(
+        _k: K,
+        database: &crate::storage::StorageService<'static, F>,
+    ) where
+        [(); F::ERASE_SIZE]:,
+        [(); F::REGION_SIZE]:,
+    {
+        database
+            .persist_state::<K, _>(
+                StorageKey::AnalogKeyConfigs,
+                &ANALOG_KEY_CONFIGS_STATE,
+                &ANALOG_KEY_CONFIGS_STATE_STORAGE_LISTENER,
+                &ANALOG_KEY_CONFIGS_SAVE_SIGNAL,
+            )
+            .await
+    }
+
+    /// Signal used to save the calibration of the analog matrix once it is finished.
+    pub(super) static ANALOG_CALIBRATION_SAVE_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+    /// Task that restores the calibration of the analog matrix, and saves it when calibration is
+    /// finished. Each key's calibration is stored as two big-endian `u16`s (the readings at rest
+    /// and when fully pressed), ordered by row, then column.
+    #[rumcake_macros::task]
+    pub async fn analog_calibration_storage_task<
+        K: KeyboardMatrix + StorageDevice,
+        F: FlashStorage,
+    >(
+        _k: K,
+        database: &crate::storage::StorageService<'static, F>,
+    ) where
+        [(); F::ERASE_SIZE]:,
+        [(); F::REGION_SIZE]:,
+        [(); K::MATRIX_ROWS * K::MATRIX_COLS * 4]:,
+    {
+        let matrix = K::get_matrix();
+
+        match database
+            .read_raw(K::get_storage_buffer(), StorageKey::AnalogCalibration)
+            .await
+        {
+            Ok((data, len)) if len == K::MATRIX_ROWS * K::MATRIX_COLS * 4 => {
+                let mut matrix = matrix.lock().await;
+                for (idx, key) in data[..len].chunks_exact(4).enumerate() {
+                    let (row, col) = (idx / K::MATRIX_COLS, idx % K::MATRIX_COLS);
+                    let rest = u16::from_be_bytes([key[0], key[1]]);
+                    let bottom_out = u16::from_be_bytes([key[2], key[3]]);
+                    if rest < bottom_out {
+                        matrix.set_analog_calibration(row as u8, col as u8, rest..bottom_out);
+                    }
+                }
+            }
+            Ok(_) => {
+                warn!("[ANALOG] Stored calibration does not match the size of the matrix, ignoring it.");
+            }
+            Err(_) => {}
+        }
+
+        loop {
+            ANALOG_CALIBRATION_SAVE_SIGNAL.wait().await;
+
+            let mut data = [0; K::MATRIX_ROWS * K::MATRIX_COLS * 4];
+            {
+                let matrix = matrix.lock().await;
+                for (idx, key) in data.chunks_exact_mut(4).enumerate() {
+                    let (row, col) = (idx / K::MATRIX_COLS, idx % K::MATRIX_COLS);
+                    if let Some(range) = matrix.analog_calibration(row as u8, col as u8) {
+                        key[..2].copy_from_slice(&range.start.to_be_bytes());
+                        key[2..].copy_from_slice(&range.end.to_be_bytes());
+                    }
+                }
+            }
+
+            let _ = database
+                .write_raw(
+                    K::get_storage_buffer(),
+                    StorageKey::AnalogCalibration,
+                    &data,
+                )
+                .await;
+        }
+    }
+}
@@ -173,17 +173,18 @@ macro_rules! backlight_task_fn {
             animator.tick().await; // Force a frame to be rendered in the event that the initial effect is static.
 
             loop {
-                let command = if !(animator.config.enabled && animator.config.effect.is_animated())
+                let command = if animator.suspended
+                    || !(animator.config.enabled && animator.config.effect.is_animated())
                 {
                     // We want to wait for a command if the animator is not rendering any animated effects. This allows the task to sleep when the LEDs are static.
                     Some(BACKLIGHT_COMMAND_CHANNEL.receive().await)
                 } else {
-                    #[cfg(feature = "vial")]
+                    #[cfg(any(feature = "vial", feature = "lamp-array"))]
                     {
                         backlight_task_fn!(true, $name, $gen, animator, subscriber, ticker)
                     }
 
-                    #[cfg(not(feature = "vial"))]
+                    #[cfg(not(any(feature = "vial", feature = "lamp-array")))]
                     {
                         backlight_task_fn!(false, $name, $gen, animator, subscriber, ticker)
                     }
@@ -235,7 +236,7 @@ macro_rules! backlight_task_fn {
         match select::select3(
             $ticker.next(),
             BACKLIGHT_COMMAND_CHANNEL.receive(),
-            crate::vial::VIAL_DIRECT_SET_CHANNEL.receive(),
+            DIRECT_SET_CHANNEL.receive(),
         )
         .await
         {
@@ -257,7 +258,7 @@ macro_rules! backlight_task_fn {
             }
         }
     };
-    ($vial_enabled:literal, $name:tt, $gen:ident, $animator:ident, $subscriber:ident, $ticker:ident) => {
+    ($direct_set_enabled:literal, $name:tt, $gen:ident, $animator:ident, $subscriber:ident, $ticker:ident) => {
         match select::select($ticker.next(), BACKLIGHT_COMMAND_CHANNEL.receive()).await {
             select::Either::First(()) => {
                 while let Some(event) = $subscriber.try_next_message_pure() {
@@ -307,19 +308,11 @@ macro_rules! backlight_module {
 
 macro_rules! storage_module {
     () => {
-        use core::any::TypeId;
-
-        use defmt::{info, warn, Debug2Format};
-        use embassy_futures::select;
-        use embassy_futures::select::Either;
         use embassy_sync::signal::Signal;
-        use embassy_time::Duration;
-        use embassy_time::Timer;
 
         use crate::hw::mcu::RawMutex;
         use crate::storage::{FlashStorage, StorageDevice};
 
-        use super::BacklightConfig;
         use super::BACKLIGHT_CONFIG_STATE;
 
         pub(super) static BACKLIGHT_CONFIG_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
@@ -336,82 +329,18 @@ macro_rules! storage_task_fn {
             database: &crate::storage::StorageService<'static, F>,
         ) where
             [(); F::ERASE_SIZE]:,
+            [(); F::REGION_SIZE]:,
         {
-            {
-                // Check stored backlight config metadata (type id) to see if it has changed
-                let metadata: [u8; core::mem::size_of::<TypeId>()] =
-                    unsafe { core::mem::transmute(TypeId::of::<BacklightConfig>()) };
-                let _ = database
-                    .check_metadata(
-                        K::get_storage_buffer(),
-                        crate::storage::StorageKey::$key,
-                        &metadata,
-                    )
-                    .await;
-
-                // Get backlight config from storage
-                if let Ok(config) = database
-                    .read(
-                        K::get_storage_buffer(),
-                        crate::storage::StorageKey::$key,
-                    )
-                    .await
-                {
-                    info!(
-                        "[BACKLIGHT] Obtained backlight config from storage: {}",
-                        Debug2Format(&config)
-                    );
-                    // Quietly update the config state so that we don't save the config to storage again
-                    BACKLIGHT_CONFIG_STATE.quiet_set(config).await;
-                } else {
-                    warn!("[BACKLIGHT] Could not get backlight config from storage, using default config.",);
-                };
-            }
-
-            let save = || async {
-                let _ = database
-                    .write(
-                        K::get_storage_buffer(),
-                        crate::storage::StorageKey::$key,
-                        BACKLIGHT_CONFIG_STATE.get().await,
-                    )
-                    .await;
-            };
-
-            // Save the backlight config if it hasn't been changed in 5 seconds, or if a save was signalled
-            loop {
-                match select::select(
-                    BACKLIGHT_SAVE_SIGNAL.wait(),
-                    BACKLIGHT_CONFIG_STATE_LISTENER.wait(),
+            database
+                .persist_state::<K, _>(
+                    crate::storage::StorageKey::$key,
+                    &BACKLIGHT_CONFIG_STATE,
+                    &BACKLIGHT_CONFIG_STATE_LISTENER,
+                    &BACKLIGHT_SAVE_SIGNAL,
                 )
                 .await
-                {
-                    Either::First(_) => {
-                        save().await;
-                    }
-                    Either::Second(_) => {
-                        match select::select(
-                            select::select(
-                                Timer::after(Duration::from_secs(5)),
-                                BACKLIGHT_SAVE_SIGNAL.wait(),
-                            ),
-                            BACKLIGHT_CONFIG_STATE_LISTENER.wait(),
-                        )
-                        .await
-                        {
-                            Either::First(_) => {
-                                save().await;
-                            }
-                            Either::Second(_) => {
-                                // Re-signal, so that we skip the `wait()` call at the beginning of this loop
-                                BACKLIGHT_CONFIG_STATE_LISTENER.signal(());
-                            }
-                        }
-                    }
-                };
-            }
         }
-    }
+    };
 }
 
 #[cfg(feature = "simple-backlight")]
@@ -561,6 +490,12 @@ pub mod rgb_backlight_matrix {
 
     backlight_module!();
 
+    /// Channel used to update the frame buffer for the [`animations::BacklightEffect::DirectSet`]
+    /// effect. Messages contain the index of an LED (`row * LIGHTING_COLS + col`), and its new color.
+    #[cfg(any(feature = "vial", feature = "lamp-array"))]
+    pub(crate) static DIRECT_SET_CHANNEL: Channel<RawMutex, (u16, smart_leds::RGB8), 4> =
+        Channel::new();
+
     backlight_task_fn!(
         rgb_backlight_matrix_task,
         D: BacklightMatrixDevice + 'static,
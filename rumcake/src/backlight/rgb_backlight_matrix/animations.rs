@@ -45,6 +45,11 @@ impl Default for BacklightConfig {
     }
 }
 
+#[cfg(feature = "storage")]
+impl crate::storage::StoredData for BacklightConfig {
+    const SCHEMA_VERSION: u16 = 1;
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, MaxSize)]
 pub enum BacklightCommand {
     Toggle,
@@ -68,6 +73,8 @@ pub enum BacklightCommand {
     #[cfg(feature = "storage")]
     SaveConfig,
     ResetTime, // normally used internally for syncing LEDs for split keyboards
+    Suspend,   // normally used internally to turn off LEDs while the USB host is asleep
+    Resume,    // normally used internally to turn LEDs back on when the USB host wakes up
 }
 
 #[generate_items_from_enum_variants(
@@ -224,7 +231,7 @@ pub enum BacklightEffect {
     #[reactive]
     SolidMultiSplash,
 
-    #[cfg(feature = "vial")]
+    #[cfg(any(feature = "vial", feature = "lamp-array"))]
     #[animated]
     DirectSet,
 }
@@ -304,7 +311,7 @@ impl BacklightEffect {
             BacklightEffect::MultiSplash => D::RGB_BACKLIGHT_MATRIX_MULTI_SPLASH_ENABLED,
             BacklightEffect::SolidSplash => D::RGB_BACKLIGHT_MATRIX_SOLID_SPLASH_ENABLED,
             BacklightEffect::SolidMultiSplash => D::RGB_BACKLIGHT_MATRIX_SOLID_MULTI_SPLASH_ENABLED,
-            #[cfg(feature = "vial")]
+            #[cfg(any(feature = "vial", feature = "lamp-array"))]
             BacklightEffect::DirectSet => D::RGB_BACKLIGHT_MATRIX_DIRECT_SET_ENABLED,
         }
     }
@@ -319,6 +326,7 @@ where
     pub(super) buf: [[RGB8; K::LIGHTING_COLS]; K::LIGHTING_ROWS], // Stores the brightness/value of each LED
     pub(super) last_presses: ConstGenericRingBuffer<((u8, u8), u32), 8>, // Stores the row and col of the last 8 key presses, and the time (in ticks) it was pressed
     pub(super) tick: u32,
+    pub(super) suspended: bool, // Whether the LEDs have been turned off due to the USB host being asleep
     pub(super) driver: D,
     pub(super) bounds: LayoutBounds,
     pub(super) rng: SmallRng,
@@ -333,6 +341,7 @@ where
         Self {
             config,
             tick: 0,
+            suspended: false,
             driver,
             last_presses: ConstGenericRingBuffer::new(),
             buf: [[RGB8::new(0, 0, 0); K::LIGHTING_COLS]; K::LIGHTING_ROWS],
@@ -342,6 +351,10 @@ where
     }
 
     pub async fn turn_on(&mut self) {
+        if self.suspended {
+            return;
+        }
+
         if let Err(err) = self.driver.turn_on().await {
             warn!("[BACKLIGHT] Animations have been enabled, but the backlight LEDs could not be turned on: {}", Debug2Format(&err));
         };
@@ -422,6 +435,16 @@ where
             BacklightCommand::ResetTime => {
                 self.tick = 0;
             }
+            BacklightCommand::Suspend => {
+                self.suspended = true;
+                self.turn_off().await;
+            }
+            BacklightCommand::Resume => {
+                self.suspended = false;
+                if self.config.enabled {
+                    self.turn_on().await;
+                }
+            }
         };
     }
 
@@ -460,7 +483,7 @@ where
     }
 
     pub async fn tick(&mut self) {
-        if !self.config.enabled {
+        if !self.config.enabled || self.suspended {
             return;
         }
 
@@ -510,7 +533,7 @@ where
             BacklightEffect::MultiSplash => todo!(),
             BacklightEffect::SolidSplash => todo!(),
             BacklightEffect::SolidMultiSplash => todo!(),
-            #[cfg(feature = "vial")]
+            #[cfg(any(feature = "vial", feature = "lamp-array"))]
             BacklightEffect::DirectSet => {} // We just move onto calling the driver, since the frame buffer is updated by the backlight task
         }
 
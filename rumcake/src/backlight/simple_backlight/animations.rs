@@ -39,6 +39,11 @@ impl Default for BacklightConfig {
     }
 }
 
+#[cfg(feature = "storage")]
+impl crate::storage::StoredData for BacklightConfig {
+    const SCHEMA_VERSION: u16 = 1;
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, MaxSize)]
 pub enum BacklightCommand {
     Toggle,
@@ -56,6 +61,8 @@ pub enum BacklightCommand {
     #[cfg(feature = "storage")]
     SaveConfig,
     ResetTime, // normally used internally for syncing LEDs for split keyboards
+    Suspend,   // normally used internally to turn off LEDs while the USB host is asleep
+    Resume,    // normally used internally to turn LEDs back on when the USB host wakes up
 }
 
 #[generate_items_from_enum_variants(
@@ -100,6 +107,7 @@ pub(super) struct BacklightAnimator<K: BacklightDevice, D: SimpleBacklightDriver
     pub(super) buf: u8, // Stores the current brightness/value. Different from `self.config.val`.
     pub(super) time_of_last_press: u32,
     pub(super) tick: u32,
+    pub(super) suspended: bool, // Whether the LEDs have been turned off due to the USB host being asleep
     pub(super) driver: D,
     pub(super) rng: SmallRng,
     pub(super) phantom: PhantomData<K>,
@@ -110,6 +118,7 @@ impl<K: BacklightDevice, D: SimpleBacklightDriver<K>> BacklightAnimator<K, D> {
         Self {
             config,
             tick: 0,
+            suspended: false,
             driver,
             buf: 0,
             time_of_last_press: 0,
@@ -119,6 +128,10 @@ impl<K: BacklightDevice, D: SimpleBacklightDriver<K>> BacklightAnimator<K, D> {
     }
 
     pub async fn turn_on(&mut self) {
+        if self.suspended {
+            return;
+        }
+
         if let Err(err) = self.driver.turn_on().await {
             warn!("[BACKLIGHT] Animations have been enabled, but the backlight LEDs could not be turned on: {}", Debug2Format(&err));
         };
@@ -181,6 +194,16 @@ impl<K: BacklightDevice, D: SimpleBacklightDriver<K>> BacklightAnimator<K, D> {
             BacklightCommand::ResetTime => {
                 self.tick = 0;
             }
+            BacklightCommand::Suspend => {
+                self.suspended = true;
+                self.turn_off().await;
+            }
+            BacklightCommand::Resume => {
+                self.suspended = false;
+                if self.config.enabled {
+                    self.turn_on().await;
+                }
+            }
         }
     }
 
@@ -204,7 +227,7 @@ impl<K: BacklightDevice, D: SimpleBacklightDriver<K>> BacklightAnimator<K, D> {
     }
 
     pub async fn tick(&mut self) {
-        if !self.config.enabled {
+        if !self.config.enabled || self.suspended {
             return;
         }
 
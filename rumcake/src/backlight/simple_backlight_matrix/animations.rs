@@ -42,6 +42,11 @@ impl Default for BacklightConfig {
     }
 }
 
+#[cfg(feature = "storage")]
+impl crate::storage::StoredData for BacklightConfig {
+    const SCHEMA_VERSION: u16 = 1;
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, MaxSize)]
 pub enum BacklightCommand {
     Toggle,
@@ -59,6 +64,8 @@ pub enum BacklightCommand {
     #[cfg(feature = "storage")]
     SaveConfig,
     ResetTime, // normally used internally for syncing LEDs for split keyboards
+    Suspend,   // normally used internally to turn off LEDs while the USB host is asleep
+    Resume,    // normally used internally to turn LEDs back on when the USB host wakes up
 }
 
 #[generate_items_from_enum_variants(
@@ -175,6 +182,7 @@ where
     pub(super) buf: [[u8; K::LIGHTING_COLS]; K::LIGHTING_ROWS], // Stores the brightness/value of each LED
     pub(super) last_presses: ConstGenericRingBuffer<((u8, u8), u32), 8>, // Stores the row and col of the last 8 key presses, and the time (in ticks) it was pressed
     pub(super) tick: u32,
+    pub(super) suspended: bool, // Whether the LEDs have been turned off due to the USB host being asleep
     pub(super) driver: D,
     pub(super) bounds: LayoutBounds,
     pub(super) rng: SmallRng,
@@ -189,6 +197,7 @@ where
         Self {
             config,
             tick: 0,
+            suspended: false,
             driver,
             buf: [[0; K::LIGHTING_COLS]; K::LIGHTING_ROWS],
             last_presses: ConstGenericRingBuffer::new(),
@@ -198,6 +207,10 @@ where
     }
 
     pub async fn turn_on(&mut self) {
+        if self.suspended {
+            return;
+        }
+
         if let Err(err) = self.driver.turn_on().await {
             warn!("[BACKLIGHT] Animations have been enabled, but the backlight LEDs could not be turned on: {}", Debug2Format(&err));
         };
@@ -260,6 +273,16 @@ where
             BacklightCommand::ResetTime => {
                 self.tick = 0;
             }
+            BacklightCommand::Suspend => {
+                self.suspended = true;
+                self.turn_off().await;
+            }
+            BacklightCommand::Resume => {
+                self.suspended = false;
+                if self.config.enabled {
+                    self.turn_on().await;
+                }
+            }
         };
     }
 
@@ -318,7 +341,7 @@ where
     }
 
     pub async fn tick(&mut self) {
-        if !self.config.enabled {
+        if !self.config.enabled || self.suspended {
             return;
         }
 
@@ -6,25 +6,369 @@
 #[cfg(any(all(feature = "nrf", feature = "bluetooth"), doc))]
 pub mod nrf_ble;
 
+#[cfg(feature = "nfc-pairing")]
+pub mod nrf_nfc;
+
+use core::cell::RefCell;
+
+use embassy_futures::select::{self, select4};
 use embassy_sync::channel::Channel;
 use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use embedded_hal::digital::v2::{OutputPin, PinState};
+use heapless::Vec;
+use packed_struct::prelude::{PackedStruct, PrimitiveEnum};
+use serde::{Deserialize, Serialize};
+use usbd_human_interface_device::device::consumer::MultipleConsumerReport;
+use usbd_human_interface_device::device::keyboard::NKROBootKeyboardReport;
+use usbd_human_interface_device::page::Keyboard as KeyboardKeycode;
 
-use crate::hw::mcu::RawMutex;
-use crate::keyboard::{Keyboard, KeyboardLayout};
+use crate::hw::mcu::{BlockingMutex, RawMutex};
+#[cfg(any(feature = "usb", feature = "esb"))]
+use crate::hw::{OutputMode, OUTPUT_MODE_STATE};
+use crate::keyboard::{
+    Keyboard, KeyboardLayout, CONSUMER_REPORT_HID_SEND_CHANNEL, KEYBOARD_REPORT_HID_SEND_CHANNEL,
+    MATRIX_EVENTS,
+};
+use crate::system_control::{SystemControlReport, SYSTEM_CONTROL_REPORT_HID_SEND_CHANNEL};
 use crate::State;
 
 /// A trait that keyboards must implement to communicate with host devices over Bluetooth (LE).
 pub trait BluetoothKeyboard: Keyboard + KeyboardLayout {
-    /// Vendor ID for the keyboard.
+    /// Vendor ID for the keyboard, reported in the PnP ID of the device information service.
     const BLE_VID: u16;
 
-    /// Product ID for the keyboard.
+    /// Product ID for the keyboard, reported in the PnP ID of the device information service.
     const BLE_PID: u16;
 
-    /// Product version for the keyboard.
-    const BLE_PRODUCT_VERSION: &'static str = Self::HARDWARE_REVISION;
+    /// Organization that assigned [`BluetoothKeyboard::BLE_VID`]. If you are using a USB vendor
+    /// ID (e.g. the same one as your USB configuration), set this to [`VidSource::UsbIF`].
+    /// Defaults to [`VidSource::BluetoothSIG`].
+    const BLE_VID_SOURCE: VidSource = VidSource::BluetoothSIG;
+
+    /// Product version for the keyboard, reported in the PnP ID of the device information
+    /// service.
+    const BLE_PRODUCT_VERSION: u16 = 1;
+
+    /// Whether the number of the active bluetooth profile should be added to the name of the
+    /// keyboard (e.g. "MyKeyboard #2" for the second profile), so that you can tell profiles apart
+    /// when pairing. Defaults to `false`.
+    const BLE_PROFILE_NAME_SUFFIX: bool = false;
+
+    /// How new host devices are authenticated when pairing. Defaults to
+    /// [`PairingMode::JustWorks`].
+    const BLE_PAIRING_MODE: PairingMode = PairingMode::JustWorks;
+
+    #[cfg(feature = "usb")]
+    /// What to do when the bluetooth connection is lost while [`OutputMode::Bluetooth`] is
+    /// selected, and a USB host is connected. Defaults to [`UsbFallback::UntilReconnect`].
+    ///
+    /// [`OutputMode::Bluetooth`]: crate::hw::OutputMode::Bluetooth
+    const BLE_USB_FALLBACK: UsbFallback = UsbFallback::UntilReconnect;
+
+    /// How the keyboard advertises when the active profile is bonded with a host device.
+    /// Defaults to [`ReconnectAdvertising::Undirected`].
+    const BLE_RECONNECT_ADVERTISING: ReconnectAdvertising = ReconnectAdvertising::Undirected;
+
+    /// How long the keyboard will advertise before pausing advertising to save power, in seconds.
+    /// Advertising resumes when a key is pressed. If set to 0, the keyboard will advertise until a
+    /// host device connects.
+    const BLE_ADVERTISING_TIMEOUT_SECS: u16 = 0;
+
+    /// Connection parameters requested from the host device when using
+    /// [`LatencyMode::LowLatency`].
+    const BLE_LOW_LATENCY_CONNECTION_PARAMETERS: ConnectionParameters = ConnectionParameters {
+        min_interval_us: 7_500,
+        max_interval_us: 15_000,
+        peripheral_latency: 30,
+        supervision_timeout_ms: 4_000,
+    };
+
+    /// Connection parameters requested from the host device when using
+    /// [`LatencyMode::PowerSave`].
+    const BLE_POWER_SAVE_CONNECTION_PARAMETERS: ConnectionParameters = ConnectionParameters {
+        min_interval_us: 30_000,
+        max_interval_us: 50_000,
+        peripheral_latency: 10,
+        supervision_timeout_ms: 6_000,
+    };
+}
+
+/// Organizations that can assign a vendor ID.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PrimitiveEnum, Default)]
+pub enum VidSource {
+    /// Vendor ID assigned by the Bluetooth SIG.
+    #[default]
+    BluetoothSIG = 1,
+    /// Vendor ID assigned by the USB Implementers Forum.
+    UsbIF = 2,
+}
+
+#[derive(Clone, Copy, PackedStruct, Default)]
+#[packed_struct(endian = "lsb", bit_numbering = "msb0")]
+pub struct PnPID {
+    #[packed_field(bytes = "0", ty = "enum")]
+    pub vid_source: VidSource,
+    #[packed_field()]
+    pub vendor_id: u16,
+    #[packed_field()]
+    pub product_id: u16,
+    #[packed_field()]
+    pub product_version: u16,
+}
+
+#[cfg(feature = "usb")]
+/// Possible ways of handling a lost bluetooth connection while a USB host is connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbFallback {
+    /// Keep using the bluetooth output. HID reports will be discarded until the bluetooth host
+    /// reconnects.
+    Disabled,
+    /// Send HID reports to the USB host until the bluetooth host reconnects.
+    UntilReconnect,
+    /// Switch the output mode to [`OutputMode::Usb`](crate::hw::OutputMode::Usb).
+    Permanent,
+}
+
+/// Possible ways of advertising to the host device bonded with the active profile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReconnectAdvertising {
+    /// Advertise to any host device. Host devices that aren't bonded with the active profile can
+    /// still connect, but they can't pair.
+    Undirected,
+    /// Advertise to any host device, but only accept connections from the bonded host device.
+    Whitelist,
+    /// Advertise directly to the bonded host device for a short time, allowing it to reconnect
+    /// quickly, then fall back to [`ReconnectAdvertising::Whitelist`].
+    Directed,
+}
+
+/// Connection parameters that the keyboard will request from the host device. The host device
+/// makes the final decision, so these may not be used exactly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionParameters {
+    /// Minimum connection interval in microseconds. Must be a multiple of 1250, and at least
+    /// 7500.
+    pub min_interval_us: u32,
+    /// Maximum connection interval in microseconds. Must be a multiple of 1250, and at most
+    /// 4000000.
+    pub max_interval_us: u32,
+    /// Number of connection events that the keyboard can skip if it has nothing to send. Key
+    /// presses are still sent at the next connection event, so a higher value saves power without
+    /// affecting typing latency, but increases the latency of messages from the host device (e.g.
+    /// LED indicator changes).
+    pub peripheral_latency: u16,
+    /// Time without a response from the host device before the connection is considered lost, in
+    /// milliseconds. Must be a multiple of 10.
+    pub supervision_timeout_ms: u16,
+}
+
+/// Possible latency modes for the bluetooth connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LatencyMode {
+    /// Use [`BluetoothKeyboard::BLE_LOW_LATENCY_CONNECTION_PARAMETERS`].
+    LowLatency,
+    /// Use [`BluetoothKeyboard::BLE_POWER_SAVE_CONNECTION_PARAMETERS`].
+    PowerSave,
+}
+
+/// State that contains the latency mode used for the bluetooth connection. This can be changed
+/// at runtime using [`BluetoothCommand::LowLatencyMode`], [`BluetoothCommand::PowerSaveMode`] and
+/// [`BluetoothCommand::ToggleLatencyMode`].
+pub static LATENCY_MODE_STATE: State<LatencyMode> =
+    State::new(LatencyMode::LowLatency, &[&LATENCY_MODE_STATE_LISTENER]);
+
+/// Transmit power levels for the bluetooth radio. A higher transmit power increases the range of
+/// your keyboard, at the cost of battery life.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[repr(i8)]
+pub enum TxPower {
+    Minus40dBm = -40,
+    Minus20dBm = -20,
+    Minus16dBm = -16,
+    Minus12dBm = -12,
+    Minus8dBm = -8,
+    Minus4dBm = -4,
+    ZerodBm = 0,
+    Plus2dBm = 2,
+    Plus3dBm = 3,
+    Plus4dBm = 4,
+    Plus5dBm = 5,
+    Plus6dBm = 6,
+    Plus7dBm = 7,
+    Plus8dBm = 8,
+}
+
+impl TxPower {
+    const LEVELS: [TxPower; 14] = [
+        TxPower::Minus40dBm,
+        TxPower::Minus20dBm,
+        TxPower::Minus16dBm,
+        TxPower::Minus12dBm,
+        TxPower::Minus8dBm,
+        TxPower::Minus4dBm,
+        TxPower::ZerodBm,
+        TxPower::Plus2dBm,
+        TxPower::Plus3dBm,
+        TxPower::Plus4dBm,
+        TxPower::Plus5dBm,
+        TxPower::Plus6dBm,
+        TxPower::Plus7dBm,
+        TxPower::Plus8dBm,
+    ];
+
+    fn index(self) -> usize {
+        Self::LEVELS
+            .iter()
+            .position(|level| *level == self)
+            .unwrap()
+    }
+
+    /// Returns the transmit power in dBm.
+    pub fn dbm(self) -> i8 {
+        self as i8
+    }
+
+    /// Returns the next higher transmit power level, or the same level if it is already the
+    /// highest.
+    pub fn increase(self) -> Self {
+        Self::LEVELS[(self.index() + 1).min(Self::LEVELS.len() - 1)]
+    }
+
+    /// Returns the next lower transmit power level, or the same level if it is already the
+    /// lowest.
+    pub fn decrease(self) -> Self {
+        Self::LEVELS[self.index().saturating_sub(1)]
+    }
+}
+
+#[cfg(feature = "storage")]
+impl crate::storage::StoredData for TxPower {
+    const SCHEMA_VERSION: u16 = 1;
+}
+
+/// State that contains the transmit power used for advertising and for the bluetooth connection.
+/// This can be changed at runtime using [`BluetoothCommand::SetTxPower`],
+/// [`BluetoothCommand::IncreaseTxPower`] and [`BluetoothCommand::DecreaseTxPower`].
+pub static TX_POWER_STATE: State<TxPower> = State::new(
+    TxPower::ZerodBm,
+    &[
+        &TX_POWER_STATE_LISTENER,
+        #[cfg(feature = "storage")]
+        &storage::TX_POWER_STATE_STORAGE_LISTENER,
+    ],
+);
+
+/// Possible methods of authenticating a host device when pairing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairingMode {
+    /// Pair without any user interaction. This offers no protection against man-in-the-middle
+    /// attacks.
+    JustWorks,
+    /// The host device displays a passkey, which must be typed on the keyboard, followed by
+    /// Enter. Pressing Escape rejects the pairing request.
+    PasskeyEntry,
+    /// The keyboard displays a passkey, which must be typed on the host device. The passkey is
+    /// shown on the keyboard's display (if the `display` feature is enabled), and printed to the
+    /// console (if the `console` or `hid-console` feature is enabled).
+    PasskeyDisplay,
+}
+
+/// The passkey prompt for a pairing request that is currently in progress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasskeyPrompt {
+    /// No passkey is required.
+    None,
+    /// The given passkey (in ASCII digits) must be typed on the host device.
+    Display([u8; 6]),
+    /// The passkey shown on the host device must be typed on the keyboard. Contains the number
+    /// of digits that have been typed so far.
+    Entry(u8),
+}
+
+/// State that contains the passkey prompt for the current pairing request. This can be used to
+/// show the passkey, or the progress of entering it, on your own indicators.
+pub static PASSKEY_PROMPT_STATE: State<PasskeyPrompt> = State::new(
+    PasskeyPrompt::None,
+    &[
+        #[cfg(feature = "display")]
+        &crate::display::PASSKEY_PROMPT_LISTENER,
+    ],
+);
+
+/// Digits of the passkey that is being typed on the keyboard.
+static PASSKEY_DIGITS: BlockingMutex<RefCell<Vec<u8, 6>>> =
+    BlockingMutex::new(RefCell::new(Vec::new()));
+
+/// Start a passkey prompt, requiring the user to type the passkey shown on the host device.
+pub(crate) fn start_passkey_entry() {
+    PASSKEY_DIGITS.lock(|digits| digits.borrow_mut().clear());
+    PASSKEY_PROMPT_STATE.try_set(PasskeyPrompt::Entry(0));
 }
 
+/// Convert a number key (on the number row or the numpad) to an ASCII digit.
+fn passkey_digit(key: KeyboardKeycode) -> Option<u8> {
+    match key as u8 {
+        code @ 0x1E..=0x26 => Some(b'1' + code - 0x1E), // 1 to 9
+        code @ 0x59..=0x61 => Some(b'1' + code - 0x59), // Keypad 1 to 9
+        0x27 | 0x62 => Some(b'0'),                      // 0 and Keypad 0
+        _ => None,
+    }
+}
+
+/// Process keys pressed while the user is entering a passkey. Returns `false` if no passkey is
+/// being entered, in which case the keys should be sent to the host device as usual.
+pub(crate) async fn process_passkey_keys(
+    last_keys: &[KeyboardKeycode],
+    keys: &[KeyboardKeycode],
+) -> bool {
+    if !matches!(PASSKEY_PROMPT_STATE.get().await, PasskeyPrompt::Entry(_)) {
+        return false;
+    }
+
+    for key in keys.iter().filter(|key| !last_keys.contains(key)) {
+        let result = PASSKEY_DIGITS.lock(|digits| {
+            let mut digits = digits.borrow_mut();
+
+            match key {
+                KeyboardKeycode::DeleteBackspace => {
+                    digits.pop();
+                }
+                KeyboardKeycode::ReturnEnter | KeyboardKeycode::KeypadEnter => {
+                    if digits.is_full() {
+                        let mut passkey = [0; 6];
+                        passkey.copy_from_slice(&digits);
+                        return Some(Some(passkey));
+                    }
+                }
+                KeyboardKeycode::Escape => return Some(None),
+                _ => {
+                    if let Some(digit) = passkey_digit(*key) {
+                        let _ = digits.push(digit);
+                    }
+                }
+            }
+
+            PASSKEY_PROMPT_STATE.try_set(PasskeyPrompt::Entry(digits.len() as u8));
+            None
+        });
+
+        if let Some(passkey) = result {
+            #[cfg(feature = "nrf")]
+            nrf_ble::reply_passkey(passkey.as_ref());
+
+            PASSKEY_PROMPT_STATE.set(PasskeyPrompt::None).await;
+            break;
+        }
+    }
+
+    true
+}
+
+/// Number of bluetooth profiles. Each profile can be bonded with a different host device.
+pub const BLUETOOTH_PROFILE_COUNT: usize = 5;
+
 #[derive(Debug, Clone, Copy)]
 /// An enumeration of possible commands that will be processed by the bluetooth task.
 pub enum BluetoothCommand {
@@ -46,6 +390,62 @@ pub enum BluetoothCommand {
     /// If your keyboard is connected to a USB device, this will **NOT** disconnect your keyboard
     /// from it. It will simply output the HID reports to the connected bluetooth device.
     OutputBluetooth,
+    #[cfg(feature = "usb")]
+    /// Automatically switch between USB and bluetooth operation.
+    ///
+    /// HID reports will be sent to the USB host when a USB cable is connected, and to the
+    /// bluetooth host otherwise.
+    OutputAuto,
+    #[cfg(feature = "esb")]
+    /// Switch to ESB operation. HID reports will be sent to the ESB dongle, or to the bluetooth
+    /// host while the dongle is out of range.
+    ///
+    /// If your keyboard is connected to a bluetooth device, this will **NOT** disconnect your
+    /// keyboard from it.
+    OutputEsb,
+    /// Switch to the bluetooth profile with the given index (starting from 0). Indices greater
+    /// than or equal to [`BLUETOOTH_PROFILE_COUNT`] are ignored.
+    ///
+    /// This will disconnect your keyboard from the current host device, and connect to the host
+    /// device bonded with the selected profile.
+    SelectProfile(u8),
+    /// Switch to the next bluetooth profile, wrapping around to the first profile.
+    NextProfile,
+    /// Switch to the previous bluetooth profile, wrapping around to the last profile.
+    PreviousProfile,
+    /// Forget the host device bonded with the current bluetooth profile, so that a new host
+    /// device can be paired with it.
+    ClearProfile,
+    /// Forget the host device bonded with the bluetooth profile with the given index (starting
+    /// from 0). Indices greater than or equal to [`BLUETOOTH_PROFILE_COUNT`] are ignored.
+    ///
+    /// If your keyboard is connected to that host device, it will be disconnected, and your
+    /// keyboard will start advertising so that a new host device can be paired.
+    UnpairProfile(u8),
+    /// Forget the host devices bonded with every bluetooth profile.
+    ///
+    /// If your keyboard is connected to a bonded host device, it will be disconnected, and your
+    /// keyboard will start advertising so that a new host device can be paired.
+    UnpairAll,
+    /// Request low latency connection parameters from the host device. See
+    /// [`LatencyMode::LowLatency`].
+    LowLatencyMode,
+    /// Request power saving connection parameters from the host device. See
+    /// [`LatencyMode::PowerSave`].
+    PowerSaveMode,
+    /// Switch between low latency and power saving connection parameters.
+    ToggleLatencyMode,
+    /// Set the transmit power of the bluetooth radio. See [`TX_POWER_STATE`].
+    SetTxPower(TxPower),
+    /// Increase the transmit power of the bluetooth radio by one level.
+    IncreaseTxPower,
+    /// Decrease the transmit power of the bluetooth radio by one level.
+    DecreaseTxPower,
+    /// Start advertising to any host device, so that a new host device can pair with the current
+    /// bluetooth profile, even if it is already bonded. The existing bond will be replaced once a
+    /// new host device pairs. If your keyboard is connected to a host device, it will be
+    /// disconnected.
+    OpenPairing,
 }
 
 /// Channel for sending [`BluetoothCommand`]s.
@@ -59,5 +459,395 @@ pub static BLUETOOTH_COMMAND_CHANNEL: Channel<RawMutex, BluetoothCommand, 2> = C
 pub(crate) static BLUETOOTH_CONNECTED_STATE: State<bool> =
     State::new(false, &[&crate::hw::BLUETOOTH_CONNECTED_STATE_LISTENER]);
 
+/// Possible states of the connection to the host device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BluetoothConnectionStatus {
+    /// Not connected, and not advertising. Advertising resumes when a key is pressed. See
+    /// [`BluetoothKeyboard::BLE_ADVERTISING_TIMEOUT_SECS`].
+    Idle,
+    /// Advertising, waiting for the host device bonded with the active profile to reconnect.
+    Advertising,
+    /// Advertising, waiting for a new host device to pair with the active profile.
+    Pairing,
+    /// Connected to a host device, but the link is not encrypted yet.
+    Connected,
+    /// Connected to a host device that is bonded with the active profile, using an encrypted
+    /// link.
+    Bonded,
+}
+
+/// The status of the bluetooth connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BluetoothStatus {
+    /// State of the connection to the host device.
+    pub connection: BluetoothConnectionStatus,
+    /// The active bluetooth profile.
+    pub profile: u8,
+    /// Signal strength of the connection to the host device in dBm, if it is known. This is
+    /// updated every few seconds while connected.
+    pub rssi: Option<i8>,
+}
+
+/// State that contains the status of the bluetooth connection. This can be used to show the
+/// connection status on your own indicators (e.g. underglow), by waiting on
+/// [`BLUETOOTH_STATUS_LISTENER`].
+pub static BLUETOOTH_STATUS_STATE: State<BluetoothStatus> = State::new(
+    BluetoothStatus {
+        connection: BluetoothConnectionStatus::Idle,
+        profile: 0,
+        rssi: None,
+    },
+    &[
+        &BLUETOOTH_STATUS_LISTENER,
+        &BLUETOOTH_STATUS_LED_LISTENER,
+        #[cfg(feature = "display")]
+        &crate::display::BLUETOOTH_STATUS_LISTENER,
+    ],
+);
+
+/// Signal that is notified when [`BLUETOOTH_STATUS_STATE`] changes. This is reserved for
+/// user-level code, so only one of your tasks should wait on it.
+pub static BLUETOOTH_STATUS_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+static BLUETOOTH_STATUS_LED_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+/// Update the bluetooth status from a non-async context. Returns `false` if the status could not
+/// be updated.
+pub(crate) fn update_bluetooth_status(f: impl FnOnce(&mut BluetoothStatus)) -> bool {
+    let Some(mut status) = BLUETOOTH_STATUS_STATE.try_get() else {
+        return false;
+    };
+
+    f(&mut status);
+    BLUETOOTH_STATUS_STATE.try_set(status)
+}
+
+/// How often the signal strength of the connection is updated, in seconds.
+pub(crate) const RSSI_UPDATE_INTERVAL_SECS: u64 = 5;
+
+/// A trait that keyboards must implement to show the bluetooth connection status on an LED.
+///
+/// The LED blinks slowly while advertising to a bonded host device, and quickly while waiting for
+/// a new host device to pair. Once connected, it stays on for
+/// [`BluetoothStatusLed::STATUS_LED_CONNECTED_SECS`], and it is turned off while the keyboard is
+/// idle.
+pub trait BluetoothStatusLed {
+    /// Whether the status LED turns on when its pin is driven high. Defaults to `true`.
+    const STATUS_LED_ACTIVE_HIGH: bool = true;
+
+    /// How long the status LED stays on after connecting to a host device, in seconds. If set to
+    /// 0, the LED stays on while connected. Defaults to 3.
+    const STATUS_LED_CONNECTED_SECS: u16 = 3;
+
+    /// Setup the GPIO pin connected to the status LED.
+    ///
+    /// It is recommended to use [`rumcake::hw::mcu::output_pin`] to implement this function.
+    fn status_led_pin() -> impl OutputPin;
+}
+
+fn set_status_led<K: BluetoothStatusLed>(led: &mut impl OutputPin, on: bool) {
+    let _ = led.set_state(PinState::from(on == K::STATUS_LED_ACTIVE_HIGH));
+}
+
+async fn blink_status_led<K: BluetoothStatusLed>(
+    led: &mut impl OutputPin,
+    on: Duration,
+    off: Duration,
+) {
+    loop {
+        set_status_led::<K>(led, true);
+        Timer::after(on).await;
+        set_status_led::<K>(led, false);
+        Timer::after(off).await;
+    }
+}
+
+#[rumcake_macros::task]
+pub async fn bluetooth_status_led_task<K: BluetoothStatusLed>(_k: K, mut led: impl OutputPin) {
+    loop {
+        let connection = BLUETOOTH_STATUS_STATE.get().await.connection;
+
+        let pattern_fut = async {
+            match connection {
+                BluetoothConnectionStatus::Idle => {
+                    set_status_led::<K>(&mut led, false);
+                }
+                BluetoothConnectionStatus::Advertising => {
+                    blink_status_led::<K>(
+                        &mut led,
+                        Duration::from_millis(500),
+                        Duration::from_millis(1500),
+                    )
+                    .await;
+                }
+                BluetoothConnectionStatus::Pairing => {
+                    blink_status_led::<K>(
+                        &mut led,
+                        Duration::from_millis(100),
+                        Duration::from_millis(100),
+                    )
+                    .await;
+                }
+                BluetoothConnectionStatus::Connected | BluetoothConnectionStatus::Bonded => {
+                    set_status_led::<K>(&mut led, true);
+                    if K::STATUS_LED_CONNECTED_SECS > 0 {
+                        Timer::after(Duration::from_secs(K::STATUS_LED_CONNECTED_SECS as u64))
+                            .await;
+                        set_status_led::<K>(&mut led, false);
+                    }
+                }
+            }
+
+            core::future::pending::<()>().await;
+        };
+
+        // Changes to the signal strength or profile don't change the pattern
+        let status_change_fut = async {
+            loop {
+                BLUETOOTH_STATUS_LED_LISTENER.wait().await;
+                if BLUETOOTH_STATUS_STATE.get().await.connection != connection {
+                    break;
+                }
+            }
+        };
+
+        select::select(pattern_fut, status_change_fut).await;
+    }
+}
+
+#[cfg(feature = "usb")]
+/// Whether HID reports should be sent to the USB host while the bluetooth connection is lost. See
+/// [`UsbFallback::UntilReconnect`].
+pub(crate) static USB_FALLBACK_STATE: State<bool> =
+    State::new(false, &[&crate::hw::BLUETOOTH_CONNECTED_STATE_LISTENER]);
+
 pub(crate) static CURRENT_OUTPUT_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
 pub(crate) static BATTERY_LEVEL_LISTENER: Signal<RawMutex, ()> = Signal::new();
+pub(crate) static LATENCY_MODE_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+pub(crate) static TX_POWER_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+#[cfg(feature = "split-central")]
+pub(crate) static PERIPHERAL_BATTERY_LEVEL_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+/// Report descriptor with NKRO, consumer control, system control and raw HID functionality. This
+/// is basically a combination of
+/// [`usbd_human_interface_device::device::keyboard::NKRO_BOOT_KEYBOARD_REPORT_DESCRIPTOR`],
+/// [`usbd_human_interface_device::device::consumer::MULTIPLE_CODE_REPORT_DESCRIPTOR`],
+/// [`crate::system_control::SYSTEM_CONTROL_REPORT_DESCRIPTOR`], and
+/// [`crate::raw_hid::RAW_HID_REPORT_DESCRIPTOR`], with report IDs included. Without report IDs, some
+/// functionality doesn't seem to work as expected. In testing, exclusion of a report ID seems to
+/// prevent raw HID output reports from being received. Potentially related:
+/// https://devzone.nordicsemi.com/f/nordic-q-a/24486/hid-get-report-from-a-mac-not-as-expected
+pub(crate) const REPORT_MAP: &[u8] = &[
+    // NKRO reports
+    0x05, 0x01, // Usage Page (Generic Desktop),
+    0x09, 0x06, // Usage (Keyboard),
+    0xA1, 0x01, // Collection (Application),
+    // bitmap of modifiers
+    0x85, 0x01, //   Report ID (1)
+    0x75, 0x01, //   Report Size (1),
+    0x95, 0x08, //   Report Count (8),
+    0x05, 0x07, //   Usage Page (Key Codes),
+    0x19, 0xE0, //   Usage Minimum (224),
+    0x29, 0xE7, //   Usage Maximum (231),
+    0x15, 0x00, //   Logical Minimum (0),
+    0x25, 0x01, //   Logical Maximum (1),
+    0x81, 0x02, //   Input (Data, Variable, Absolute), ;Modifier byte
+    // 7 bytes of padding
+    0x75, 0x38, //   Report Size (0x38),
+    0x95, 0x01, //   Report Count (1),
+    0x81, 0x01, //   Input (Constant), ;Reserved byte
+    // LED output report
+    0x95, 0x05, //   Report Count (5),
+    0x75, 0x01, //   Report Size (1),
+    0x05, 0x08, //   Usage Page (LEDs),
+    0x19, 0x01, //   Usage Minimum (1),
+    0x29, 0x05, //   Usage Maximum (5),
+    0x91, 0x02, //   Output (Data, Variable, Absolute),
+    0x95, 0x01, //   Report Count (1),
+    0x75, 0x03, //   Report Size (3),
+    0x91, 0x03, //   Output (Constant),
+    // bitmap of keys
+    0x95, 0x88, //   Report Count () - (REPORT_BYTES-1)*8
+    0x75, 0x01, //   Report Size (1),
+    0x15, 0x00, //   Logical Minimum (0),
+    0x25, 0x01, //   Logical Maximum(1),
+    0x05, 0x07, //   Usage Page (Key Codes),
+    0x19, 0x00, //   Usage Minimum (0),
+    0x29, 0x87, //   Usage Maximum (), - (REPORT_BYTES-1)*8-1
+    0x81, 0x02, //   Input (Data, Variable, Absolute),
+    0xc0, // End Collection
+    // Consumer reports
+    0x05, 0x0C, // Usage Page (Consumer),
+    0x09, 0x01, // Usage (Consumer Control),
+    0xA1, 0x01, // Collection (Application),
+    0x85, 0x02, //   Report ID (2)
+    0x75, 0x10, //     Report Size(16)
+    0x95, 0x04, //     Report Count(4)
+    0x15, 0x00, //     Logical Minimum(0)
+    0x26, 0x9C, 0x02, //     Logical Maximum(0x029C)
+    0x19, 0x00, //     Usage Minimum(0)
+    0x2A, 0x9C, 0x02, //     Usage Maximum(0x029C)
+    0x81, 0x00, //     Input (Array, Data, Variable)
+    0xC0, // End Collection
+    // System control reports
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x80, // Usage (System Control)
+    0xA1, 0x01, // Collection (Application)
+    0x85, 0x04, //   Report ID (4)
+    0x19, 0x01, //   Usage Minimum (Pointer)
+    0x2A, 0xB7, 0x00, //   Usage Maximum (System Display LCD Autoscale)
+    0x15, 0x01, //   Logical Minimum (1)
+    0x26, 0xB7, 0x00, //   Logical Maximum (0xB7)
+    0x75, 0x10, //   Report Size (16)
+    0x95, 0x01, //   Report Count (1)
+    0x81, 0x00, //   Input (Data, Array, Absolute)
+    0xC0, // End Collection
+    // Raw HID reports
+    0x06, 0x60, 0xFF, // Usage Page (Vendor Defined)
+    0x09, 0x61, // Usage (Vendor Defined)
+    0xA1, 0x01, // Collection (Application)
+    0x85, 0x03, //   Report ID (3)
+    // Data to host
+    0x09, 0x62, //   Usage (Vendor Defined)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x95, 0x20, //   Report Count
+    0x75, 0x08, //   Report Size (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    // Data from host
+    0x09, 0x63, //   Usage (Vendor Defined)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x95, 0x20, //   Report Count
+    0x75, 0x08, //   Report Size (8)
+    0x91, 0x02, //   Output (Data, Variable, Absolute)
+    0xC0, // End Collection
+];
+
+/// Wait for a key to be pressed.
+pub(crate) async fn wait_for_key_press() {
+    let Ok(mut subscriber) = MATRIX_EVENTS.subscriber() else {
+        defmt::warn!("[BT_HID] No matrix event subscriber available, advertising will stay paused");
+        return core::future::pending().await;
+    };
+
+    while !subscriber.next_message_pure().await.is_press() {}
+}
+
+/// HID reports that can be sent to the host over bluetooth.
+pub(crate) enum HIDReport {
+    Keyboard(NKROBootKeyboardReport),
+    Consumer(MultipleConsumerReport),
+    SystemControl(SystemControlReport),
+    #[cfg(feature = "raw-hid")]
+    RawHID([u8; 32]),
+}
+
+/// Wait for the next HID report to send to the host.
+pub(crate) async fn receive_hid_report() -> HIDReport {
+    let keyboard_fut =
+        async { HIDReport::Keyboard(KEYBOARD_REPORT_HID_SEND_CHANNEL.receive().await) };
+    let consumer_fut =
+        async { HIDReport::Consumer(CONSUMER_REPORT_HID_SEND_CHANNEL.receive().await) };
+    let system_control_fut =
+        async { HIDReport::SystemControl(SYSTEM_CONTROL_REPORT_HID_SEND_CHANNEL.receive().await) };
+
+    #[cfg(feature = "raw-hid")]
+    let raw_hid_fut =
+        async { HIDReport::RawHID(crate::raw_hid::RAW_HID_REPORT_SEND_CHANNEL.receive().await) };
+    #[cfg(not(feature = "raw-hid"))]
+    let raw_hid_fut = core::future::pending();
+
+    match select4(keyboard_fut, consumer_fut, system_control_fut, raw_hid_fut).await {
+        select::Either4::First(report)
+        | select::Either4::Second(report)
+        | select::Either4::Third(report)
+        | select::Either4::Fourth(report) => report,
+    }
+}
+
+#[cfg(any(feature = "usb", feature = "esb"))]
+/// Set the output mode, and save it immediately.
+pub(crate) async fn set_output_mode(mode: OutputMode) {
+    OUTPUT_MODE_STATE.set(mode).await;
+
+    // Output mode changes are infrequent, so save them immediately in case the keyboard
+    // gets unplugged right after switching.
+    #[cfg(feature = "storage")]
+    crate::hw::storage::OUTPUT_MODE_SAVE_SIGNAL.signal(());
+}
+
+#[cfg(feature = "usb")]
+/// Switch between USB and bluetooth output.
+pub(crate) async fn toggle_output_mode() {
+    let mode = match OUTPUT_MODE_STATE.get().await {
+        OutputMode::Usb => OutputMode::Bluetooth,
+        OutputMode::Bluetooth => OutputMode::Usb,
+        #[cfg(feature = "esb")]
+        OutputMode::Esb => OutputMode::Usb,
+        // Switch away from the host that is currently receiving HID reports
+        OutputMode::Auto => match crate::hw::CURRENT_OUTPUT_STATE.get().await {
+            Some(crate::hw::HIDOutput::Usb) => OutputMode::Bluetooth,
+            _ => OutputMode::Usb,
+        },
+    };
+    set_output_mode(mode).await;
+}
+
+#[cfg(feature = "usb")]
+/// Handle a lost bluetooth connection according to [`BluetoothKeyboard::BLE_USB_FALLBACK`].
+pub(crate) async fn fall_back_to_usb<K: BluetoothKeyboard>() {
+    if OUTPUT_MODE_STATE.get().await != OutputMode::Bluetooth {
+        return;
+    }
+
+    match K::BLE_USB_FALLBACK {
+        UsbFallback::Disabled => {}
+        UsbFallback::UntilReconnect => {
+            USB_FALLBACK_STATE.set(true).await;
+        }
+        UsbFallback::Permanent => {
+            if crate::usb::USB_RUNNING_STATE.get().await
+                && crate::usb::USB_CONFIGURED_STATE.get().await
+            {
+                defmt::info!("[BT_HID] Bluetooth connection lost, switching to USB output");
+                set_output_mode(OutputMode::Usb).await;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "storage")]
+pub mod storage {
+    use embassy_sync::signal::Signal;
+
+    use crate::hw::mcu::RawMutex;
+    use crate::storage::{FlashStorage, StorageDevice};
+
+    use super::TX_POWER_STATE;
+
+    pub(super) static TX_POWER_STATE_STORAGE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+    /// Signal used to save the transmit power immediately, instead of waiting for the save policy
+    /// defined in [`StorageDevice`].
+    pub(crate) static TX_POWER_SAVE_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+    /// Task that restores the transmit power that was last selected, and saves any changes to it.
+    #[rumcake_macros::task]
+    pub async fn tx_power_storage_task<K: StorageDevice, F: FlashStorage>(
+        _k: K,
+        database: &crate::storage::StorageService<'static, F>,
+    ) where
+        [(); F::ERASE_SIZE]:,
+        [(); F::REGION_SIZE]:,
+    {
+        database
+            .persist_state::<K, _>(
+                crate::storage::StorageKey::TxPower,
+                &TX_POWER_STATE,
+                &TX_POWER_STATE_STORAGE_LISTENER,
+                &TX_POWER_SAVE_SIGNAL,
+            )
+            .await
+    }
+}
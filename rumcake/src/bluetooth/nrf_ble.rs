@@ -2,7 +2,9 @@ use core::cell::{Cell, RefCell};
 
 use defmt::{debug, error, info, warn, Debug2Format};
 use embassy_futures::join;
-use embassy_futures::select::{self, select3, select4};
+use embassy_futures::select::{self, select, select3, select4};
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Timer};
 use heapless::Vec;
 use nrf_softdevice::ble::gatt_server::builder::ServiceBuilder;
 use nrf_softdevice::ble::gatt_server::characteristic::{Attribute, Metadata, Properties};
@@ -10,69 +12,560 @@ use nrf_softdevice::ble::gatt_server::{
     self, get_sys_attrs, run, set_sys_attrs, GetValueError, NotifyValueError, RegisterError,
     Service, SetValueError,
 };
-use nrf_softdevice::ble::peripheral::{advertise_pairable, ConnectableAdvertisement};
+use nrf_softdevice::ble::peripheral::{
+    self, advertise_pairable, AdvertiseError, ConnectableAdvertisement, FilterPolicy,
+};
 use nrf_softdevice::ble::security::{IoCapabilities, SecurityHandler};
 use nrf_softdevice::ble::{
-    Connection, EncryptionInfo, GattValue, IdentityKey, MasterId, SecurityMode, Uuid,
+    Address, AddressType, Connection, EncryptionInfo, GattValue, IdentityKey,
+    IdentityResolutionKey, MasterId, PasskeyReply, SecurityMode, Uuid,
 };
 use nrf_softdevice::Softdevice;
-use packed_struct::prelude::{PackedStruct, PrimitiveEnum};
+use packed_struct::prelude::PackedStruct;
+use serde::{Deserialize, Serialize};
 use static_cell::StaticCell;
 use usbd_human_interface_device::device::consumer::MultipleConsumerReport;
 use usbd_human_interface_device::device::keyboard::NKROBootKeyboardReport;
 
-use crate::hw::mcu::BLUETOOTH_ADVERTISING_MUTEX;
+use crate::hw::mcu::{BlockingMutex, RawMutex, BLUETOOTH_ADVERTISING_MUTEX};
 use crate::hw::{
-    HIDOutput, OutputMode, BATTERY_LEVEL_STATE, CURRENT_OUTPUT_STATE, OUTPUT_MODE_STATE,
+    BatteryChargeState, BatteryStatus, HIDOutput, LedIndicators, OutputMode, BATTERY_STATE,
+    CURRENT_OUTPUT_STATE, LED_INDICATORS_STATE,
 };
 use crate::keyboard::{CONSUMER_REPORT_HID_SEND_CHANNEL, KEYBOARD_REPORT_HID_SEND_CHANNEL};
+use crate::system_control::{SystemControlReport, SYSTEM_CONTROL_REPORT_HID_SEND_CHANNEL};
+use crate::State;
+
+#[cfg(any(feature = "usb", feature = "esb"))]
+use crate::bluetooth::set_output_mode;
+#[cfg(feature = "usb")]
+use crate::bluetooth::{fall_back_to_usb, toggle_output_mode, USB_FALLBACK_STATE};
+#[cfg(feature = "dongle")]
+use crate::dongle::{
+    MessageToDongle, MessageToKeyboard, DONGLE_SERVICE_UUID, MESSAGE_TO_DONGLE_BUFFER_SIZE,
+    MESSAGE_TO_DONGLE_UUID, MESSAGE_TO_KEYBOARD_BUFFER_SIZE, MESSAGE_TO_KEYBOARD_UUID,
+};
 
 use crate::bluetooth::{
-    BluetoothCommand, BluetoothKeyboard, BATTERY_LEVEL_LISTENER, BLUETOOTH_COMMAND_CHANNEL,
-    BLUETOOTH_CONNECTED_STATE, CURRENT_OUTPUT_STATE_LISTENER,
+    receive_hid_report, update_bluetooth_status, wait_for_key_press, BluetoothCommand,
+    BluetoothConnectionStatus, BluetoothKeyboard, BluetoothStatus, ConnectionParameters, HIDReport,
+    LatencyMode, PairingMode, PasskeyPrompt, PnPID, ReconnectAdvertising, TxPower,
+    BATTERY_LEVEL_LISTENER, BLUETOOTH_COMMAND_CHANNEL, BLUETOOTH_CONNECTED_STATE,
+    BLUETOOTH_PROFILE_COUNT, BLUETOOTH_STATUS_STATE, CURRENT_OUTPUT_STATE_LISTENER,
+    LATENCY_MODE_STATE, LATENCY_MODE_STATE_LISTENER, PASSKEY_PROMPT_STATE, REPORT_MAP,
+    RSSI_UPDATE_INTERVAL_SECS,
 };
 
-#[derive(Clone, Copy)]
-struct Peer {
-    master_id: MasterId,
-    key: EncryptionInfo,
-    peer_id: IdentityKey,
+/// Bond information for a host device, stored in a bluetooth profile.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BondedPeer {
+    ediv: u16,
+    rand: [u8; 8],
+    ltk: [u8; 16],
+    ltk_flags: u8,
+    irk: [u8; 16],
+    address_type: u8,
+    address: [u8; 6],
+    sys_attrs: Vec<u8, 62>,
 }
 
-pub struct Bonder {
-    peer: Cell<Option<Peer>>,
-    sys_attrs: RefCell<Vec<u8, 62>>,
+impl BondedPeer {
+    fn new(master_id: MasterId, key: EncryptionInfo, peer_id: IdentityKey) -> Self {
+        Self {
+            ediv: master_id.ediv,
+            rand: master_id.rand,
+            ltk: key.ltk,
+            ltk_flags: key.flags,
+            irk: peer_id.irk.as_raw().irk,
+            address_type: peer_id.addr.address_type() as u8,
+            address: peer_id.addr.bytes(),
+            sys_attrs: Vec::new(),
+        }
+    }
+
+    fn master_id(&self) -> MasterId {
+        MasterId {
+            ediv: self.ediv,
+            rand: self.rand,
+        }
+    }
+
+    fn key(&self) -> EncryptionInfo {
+        EncryptionInfo {
+            ltk: self.ltk,
+            flags: self.ltk_flags,
+        }
+    }
+
+    fn identity(&self) -> Option<IdentityKey> {
+        let address_type = AddressType::try_from(self.address_type).ok()?;
+
+        Some(IdentityKey {
+            irk: IdentityResolutionKey::from_raw(nrf_softdevice::raw::ble_gap_irk_t {
+                irk: self.irk,
+            }),
+            addr: Address::new(address_type, self.address),
+        })
+    }
+
+    fn is_match(&self, address: Address) -> bool {
+        self.identity()
+            .is_some_and(|identity| identity.is_match(address))
+    }
+}
+
+/// Bluetooth profiles, and the host devices that they are bonded with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BluetoothProfiles {
+    /// Index of the profile currently in use.
+    pub active: u8,
+    peers: [Option<BondedPeer>; BLUETOOTH_PROFILE_COUNT],
+}
+
+impl BluetoothProfiles {
+    /// Returns `true` if the given profile is bonded with a host device.
+    pub fn is_bonded(&self, profile: u8) -> bool {
+        self.peers
+            .get(profile as usize)
+            .is_some_and(|peer| peer.is_some())
+    }
+
+    fn active_peer(&self) -> Option<&BondedPeer> {
+        self.peers[self.active as usize].as_ref()
+    }
+
+    fn active_peer_mut(&mut self) -> &mut Option<BondedPeer> {
+        &mut self.peers[self.active as usize]
+    }
+}
+
+#[cfg(feature = "storage")]
+impl crate::storage::StoredData for BluetoothProfiles {
+    const SCHEMA_VERSION: u16 = 1;
+}
+
+const NO_PEER: Option<BondedPeer> = None;
+
+/// State that contains the active bluetooth profile, and the bonds for each profile. Changes to
+/// the active profile should be made using [`BluetoothCommand`]s.
+pub static BLUETOOTH_PROFILES_STATE: State<BluetoothProfiles> = State::new(
+    BluetoothProfiles {
+        active: 0,
+        peers: [NO_PEER; BLUETOOTH_PROFILE_COUNT],
+    },
+    &[
+        &BLUETOOTH_PROFILES_STATE_LISTENER,
+        #[cfg(feature = "storage")]
+        &storage::BLUETOOTH_PROFILES_STATE_STORAGE_LISTENER,
+    ],
+);
+
+static BLUETOOTH_PROFILES_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+/// Update the bond of the active profile from a non-async context. Returns `false` if the
+/// profiles could not be updated.
+fn update_active_peer(f: impl FnOnce(&mut Option<BondedPeer>)) -> bool {
+    let Some(mut profiles) = BLUETOOTH_PROFILES_STATE.try_get() else {
+        return false;
+    };
+
+    f(profiles.active_peer_mut());
+    BLUETOOTH_PROFILES_STATE.try_set(profiles)
+}
+
+/// Wait for the given profile to stop being the active profile, or for the host device bonded
+/// with it to be forgotten.
+async fn wait_for_profile_change(profile: u8) {
+    let mut bonded = BLUETOOTH_PROFILES_STATE.get().await.is_bonded(profile);
+
+    loop {
+        BLUETOOTH_PROFILES_STATE_LISTENER.wait().await;
+        let profiles = BLUETOOTH_PROFILES_STATE.get().await;
+
+        if profiles.active != profile || (bonded && !profiles.is_bonded(profile)) {
+            break;
+        }
+
+        bonded = profiles.is_bonded(profile);
+    }
+}
+
+/// Whether the keyboard should accept pairing requests from any host device, even if the active
+/// profile is already bonded. Set by [`BluetoothCommand::OpenPairing`].
+static OPEN_PAIRING: BlockingMutex<Cell<bool>> = BlockingMutex::new(Cell::new(false));
+
+static OPEN_PAIRING_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+/// Only accept connections from the given host device while advertising. If `peer` is `None`,
+/// connections from any host device will be accepted.
+fn set_whitelist(peer: Option<&BondedPeer>) {
+    use nrf_softdevice::raw;
+
+    let identity = peer.and_then(BondedPeer::identity);
+
+    let result = unsafe {
+        match identity {
+            Some(identity) => {
+                let addr = raw::ble_gap_addr_t {
+                    _bitfield_1: raw::ble_gap_addr_t::new_bitfield_1(
+                        0,
+                        identity.addr.address_type() as u8,
+                    ),
+                    addr: identity.addr.bytes(),
+                };
+                let id_key = raw::ble_gap_id_key_t {
+                    id_info: *identity.irk.as_raw(),
+                    id_addr_info: addr,
+                };
+
+                // The device identity allows the softdevice to resolve the private addresses used
+                // by most host devices
+                let id_keys = [&id_key as *const raw::ble_gap_id_key_t];
+                let addrs = [&addr as *const raw::ble_gap_addr_t];
+                let result =
+                    raw::sd_ble_gap_device_identities_set(id_keys.as_ptr(), core::ptr::null(), 1);
+                if result == raw::NRF_SUCCESS {
+                    raw::sd_ble_gap_whitelist_set(addrs.as_ptr(), 1)
+                } else {
+                    result
+                }
+            }
+            None => {
+                let result = raw::sd_ble_gap_whitelist_set(core::ptr::null(), 0);
+                if result == raw::NRF_SUCCESS {
+                    raw::sd_ble_gap_device_identities_set(core::ptr::null(), core::ptr::null(), 0)
+                } else {
+                    result
+                }
+            }
+        }
+    };
+
+    if result != raw::NRF_SUCCESS {
+        warn!("[BT_HID] Could not set whitelist: {}", result);
+    }
+}
+
+/// Advertise until a host device connects, using [`BluetoothKeyboard::BLE_RECONNECT_ADVERTISING`]
+/// if `peer` is bonded with the active profile.
+async fn advertise<K: BluetoothKeyboard>(
+    sd: &Softdevice,
+    adv_data: &[u8],
+    scan_data: &[u8],
+    peer: Option<&BondedPeer>,
+    bonder: &'static Bonder,
+) -> Result<Connection, AdvertiseError> {
+    let mut config = peripheral::Config {
+        tx_power: TX_POWER_STATE.get().await.into(),
+        ..Default::default()
+    };
+
+    let reconnect_advertising = if peer.is_some() {
+        K::BLE_RECONNECT_ADVERTISING
+    } else {
+        ReconnectAdvertising::Undirected
+    };
+
+    if let (ReconnectAdvertising::Directed, Some(identity)) =
+        (reconnect_advertising, peer.and_then(BondedPeer::identity))
+    {
+        // High duty cycle directed advertising times out after 1.28 seconds
+        set_whitelist(peer);
+        match advertise_pairable(
+            sd,
+            ConnectableAdvertisement::NonscannableDirectedHighDuty {
+                peer: identity.addr,
+            },
+            &config,
+            bonder,
+        )
+        .await
+        {
+            Err(AdvertiseError::Timeout) => {
+                debug!("[BT_HID] Directed advertising timed out");
+            }
+            result => return result,
+        }
+    }
+
+    if reconnect_advertising == ReconnectAdvertising::Undirected {
+        set_whitelist(None);
+    } else {
+        set_whitelist(peer);
+        config.filter_policy = FilterPolicy::Both;
+    }
+
+    advertise_pairable(
+        sd,
+        ConnectableAdvertisement::ScannableUndirected {
+            adv_data,
+            scan_data,
+        },
+        &config,
+        bonder,
+    )
+    .await
+}
+
+/// Maximum length of the name in the advertising data. Advertising data is limited to 31 bytes,
+/// and 15 of them are used by the other fields in [`advertising_data`].
+const ADV_NAME_MAX_LEN: usize = 16;
+
+/// Suffix added to the name of the keyboard when using the given profile, if
+/// [`BluetoothKeyboard::BLE_PROFILE_NAME_SUFFIX`] is enabled.
+fn profile_name_suffix<K: BluetoothKeyboard>(profile: u8) -> Vec<u8, 3> {
+    if K::BLE_PROFILE_NAME_SUFFIX {
+        Vec::from_slice(&[b' ', b'#', b'1' + profile]).unwrap()
+    } else {
+        Vec::new()
+    }
+}
+
+/// Build the advertising data for the given profile. If the name of the keyboard is too long, it
+/// is shortened, keeping the profile name suffix.
+fn advertising_data<K: BluetoothKeyboard>(profile: u8) -> Vec<u8, 31> {
+    let name = K::PRODUCT.as_bytes();
+    let suffix = profile_name_suffix::<K>(profile);
+    let name_len = name.len().min(ADV_NAME_MAX_LEN - suffix.len());
+    let name_type = if name_len < name.len() {
+        0x08 // Shortened name
+    } else {
+        0x09 // Complete name
+    };
+
+    #[rustfmt::skip]
+    let header = [
+        0x02, 0x01, nrf_softdevice::raw::BLE_GAP_ADV_FLAGS_LE_ONLY_GENERAL_DISC_MODE as u8,
+        0x05, 0x03, 0x12, 0x18, 0x0F, 0x18, // Incomplete list of 16 bit services: HID service and battery service
+        0x03, 0x19, 0xC1, 0x03, // Appearance: Keyboard
+        (name_len + suffix.len() + 1) as u8, name_type, // Name: keyboard name, followed by the suffix
+    ];
+
+    let mut adv_data = Vec::from_slice(&header).unwrap();
+    adv_data.extend_from_slice(&name[..name_len]).unwrap();
+    adv_data.extend_from_slice(&suffix).unwrap();
+
+    adv_data
+}
+
+/// Set the GAP device name, which host devices read after connecting, to the name of the keyboard
+/// followed by the suffix for the given profile.
+fn set_device_name<K: BluetoothKeyboard>(profile: u8) {
+    use nrf_softdevice::raw;
+
+    let product = K::PRODUCT.as_bytes();
+    let suffix = profile_name_suffix::<K>(profile);
+    let mut name: Vec<u8, { raw::BLE_GAP_DEVNAME_MAX_LEN as usize }> = Vec::new();
+    let name_len = product.len().min(name.capacity() - suffix.len());
+    name.extend_from_slice(&product[..name_len]).unwrap();
+    name.extend_from_slice(&suffix).unwrap();
+
+    let result = unsafe {
+        // The device name can't be written by host devices
+        let write_perm: raw::ble_gap_conn_sec_mode_t = core::mem::zeroed();
+        raw::sd_ble_gap_device_name_set(&write_perm, name.as_ptr(), name.len() as u16)
+    };
+
+    if result != raw::NRF_SUCCESS {
+        warn!("[BT_HID] Could not set device name: {}", result);
+    }
+}
+
+impl From<TxPower> for nrf_softdevice::ble::TxPower {
+    fn from(tx_power: TxPower) -> Self {
+        match tx_power {
+            TxPower::Minus40dBm => Self::Minus40dBm,
+            TxPower::Minus20dBm => Self::Minus20dBm,
+            TxPower::Minus16dBm => Self::Minus16dBm,
+            TxPower::Minus12dBm => Self::Minus12dBm,
+            TxPower::Minus8dBm => Self::Minus8dBm,
+            TxPower::Minus4dBm => Self::Minus4dBm,
+            TxPower::ZerodBm => Self::ZerodBm,
+            TxPower::Plus2dBm => Self::Plus2dBm,
+            TxPower::Plus3dBm => Self::Plus3dBm,
+            TxPower::Plus4dBm => Self::Plus4dBm,
+            TxPower::Plus5dBm => Self::Plus5dBm,
+            TxPower::Plus6dBm => Self::Plus6dBm,
+            TxPower::Plus7dBm => Self::Plus7dBm,
+            TxPower::Plus8dBm => Self::Plus8dBm,
+        }
+    }
+}
+
+/// Apply the transmit power in [`TX_POWER_STATE`] to an existing connection. New connections
+/// inherit the transmit power used for advertising.
+async fn set_connection_tx_power(connection: &Connection) {
+    use nrf_softdevice::raw;
+
+    let Some(handle) = connection.handle() else {
+        return;
+    };
+    let tx_power = TX_POWER_STATE.get().await;
+
+    let result = unsafe {
+        raw::sd_ble_gap_tx_power_set(
+            raw::BLE_GAP_TX_POWER_ROLES_BLE_GAP_TX_POWER_ROLE_CONN as u8,
+            handle,
+            tx_power.dbm(),
+        )
+    };
+
+    if result == raw::NRF_SUCCESS {
+        debug!("[BT_HID] Transmit power set to {} dBm", tx_power.dbm());
+    } else {
+        warn!("[BT_HID] Could not set transmit power: {}", result);
+    }
+}
+
+#[cfg(feature = "storage")]
+async fn set_tx_power(tx_power: TxPower) {
+    TX_POWER_STATE.set(tx_power).await;
+
+    // Transmit power changes are infrequent, so save them immediately
+    crate::bluetooth::storage::TX_POWER_SAVE_SIGNAL.signal(());
+}
+
+#[cfg(not(feature = "storage"))]
+async fn set_tx_power(tx_power: TxPower) {
+    TX_POWER_STATE.set(tx_power).await;
+}
+
+impl From<ConnectionParameters> for nrf_softdevice::raw::ble_gap_conn_params_t {
+    fn from(params: ConnectionParameters) -> Self {
+        // Connection intervals are in units of 1.25ms, and the supervision timeout is in units of
+        // 10ms
+        Self {
+            min_conn_interval: (params.min_interval_us / 1250) as u16,
+            max_conn_interval: (params.max_interval_us / 1250) as u16,
+            slave_latency: params.peripheral_latency,
+            conn_sup_timeout: params.supervision_timeout_ms / 10,
+        }
+    }
+}
+
+/// Request the connection parameters for the current latency mode from the host device.
+async fn request_connection_parameters<K: BluetoothKeyboard>(connection: &Connection) {
+    let params = match LATENCY_MODE_STATE.get().await {
+        LatencyMode::LowLatency => K::BLE_LOW_LATENCY_CONNECTION_PARAMETERS,
+        LatencyMode::PowerSave => K::BLE_POWER_SAVE_CONNECTION_PARAMETERS,
+    };
+
+    match connection.set_conn_params(params.into()) {
+        Ok(()) => {
+            debug!(
+                "[BT_HID] Requested connection parameters: {}",
+                Debug2Format(&params)
+            );
+        }
+        Err(error) => {
+            warn!(
+                "[BT_HID] Could not request connection parameters: {}",
+                Debug2Format(&error)
+            );
+        }
+    }
 }
 
-impl Default for Bonder {
-    fn default() -> Self {
-        Bonder {
-            peer: Cell::new(None),
-            sys_attrs: Default::default(),
+/// Reply for a passkey that is being typed on the keyboard.
+static PASSKEY_REPLY: BlockingMutex<RefCell<Option<PasskeyReply>>> =
+    BlockingMutex::new(RefCell::new(None));
+
+/// Reply to the pending pairing request with the passkey typed on the keyboard. If `passkey` is
+/// `None`, the pairing request is rejected.
+pub(crate) fn reply_passkey(passkey: Option<&[u8; 6]>) {
+    if let Some(reply) = PASSKEY_REPLY.lock(|reply| reply.borrow_mut().take()) {
+        if let Err(error) = reply.reply(passkey) {
+            error!(
+                "[BT_HID] Could not reply with passkey: {}",
+                Debug2Format(&error)
+            );
         }
     }
 }
 
+/// Stop showing the passkey prompt, and discard any pending passkey reply.
+fn clear_passkey_prompt() {
+    PASSKEY_REPLY.lock(|reply| reply.borrow_mut().take());
+    PASSKEY_PROMPT_STATE.try_set(PasskeyPrompt::None);
+}
+
+pub struct Bonder {
+    pairing_mode: PairingMode,
+}
+
 impl SecurityHandler for Bonder {
     fn io_capabilities(&self) -> IoCapabilities {
-        IoCapabilities::None
+        match self.pairing_mode {
+            PairingMode::JustWorks => IoCapabilities::None,
+            PairingMode::PasskeyEntry => IoCapabilities::KeyboardOnly,
+            PairingMode::PasskeyDisplay => IoCapabilities::DisplayOnly,
+        }
+    }
+
+    fn can_bond(&self, conn: &Connection) -> bool {
+        // A new host device can only bond with a profile that is free, unless pairing was opened
+        // with `BluetoothCommand::OpenPairing`. Otherwise, existing bonds must be cleared with
+        // `BluetoothCommand::ClearProfile` first.
+        OPEN_PAIRING.lock(Cell::get)
+            || BLUETOOTH_PROFILES_STATE.try_get().is_some_and(|profiles| {
+                profiles
+                    .active_peer()
+                    .map_or(true, |peer| peer.is_match(conn.peer_address()))
+            })
+    }
+
+    fn display_passkey(&self, passkey: &[u8; 6]) {
+        info!("[BT_HID] Displaying passkey for pairing request");
+
+        #[cfg(any(feature = "console", feature = "hid-console", feature = "nus-console"))]
+        if let Ok(passkey) = core::str::from_utf8(passkey) {
+            crate::console_println!(
+                "[BT_HID] Type this passkey on your host device: {}",
+                passkey
+            );
+        }
+
+        PASSKEY_PROMPT_STATE.try_set(PasskeyPrompt::Display(*passkey));
+    }
+
+    fn enter_passkey(&self, reply: PasskeyReply) {
+        info!("[BT_HID] Waiting for passkey to be typed on the keyboard");
+
+        PASSKEY_REPLY.lock(|pending| pending.replace(Some(reply)));
+        crate::bluetooth::start_passkey_entry();
     }
 
-    fn can_bond(&self, _conn: &Connection) -> bool {
-        true
+    #[cfg(feature = "nfc-pairing")]
+    fn can_recv_out_of_band(&self, _conn: &Connection) -> bool {
+        // OOB data is only used if the host device also has it, i.e. it has read the NFC tag.
+        // Otherwise, the pairing mode is used.
+        crate::bluetooth::nrf_nfc::oob_available()
     }
 
-    // fn display_passkey(&self, passkey: &[u8; 6]) {
-    //     info!("[BT_HID] Passkey: {}", Debug2Format(passkey));
-    // }
+    #[cfg(feature = "nfc-pairing")]
+    fn recv_out_of_band(&self, reply: nrf_softdevice::ble::OutOfBandReply) {
+        info!("[BT_HID] Pairing using OOB data from the NFC tag");
 
-    // fn enter_passkey(&self, _reply: nrf_softdevice::ble::PasskeyReply) {}
+        crate::bluetooth::nrf_nfc::reply_oob(reply);
+    }
 
     fn on_security_update(&self, _conn: &Connection, security_mode: SecurityMode) {
         debug!(
             "[BT_HID] new security mode: {}",
             Debug2Format(&security_mode)
         );
+
+        // Pairing has finished
+        clear_passkey_prompt();
+
+        // Host devices bonded with the active profile re-encrypt the link when they reconnect
+        if security_mode != SecurityMode::Open
+            && BLUETOOTH_PROFILES_STATE
+                .try_get()
+                .is_some_and(|profiles| profiles.is_bonded(profiles.active))
+        {
+            update_bluetooth_status(|status| status.connection = BluetoothConnectionStatus::Bonded);
+        }
     }
 
     fn on_bonded(
@@ -85,22 +578,30 @@ impl SecurityHandler for Bonder {
         // First time
         debug!("[BT_HID] storing bond for: id: {}, key: {}", master_id, key);
 
-        // TODO: save keys
-        self.sys_attrs.borrow_mut().clear();
-        self.peer.set(Some(Peer {
-            master_id,
-            key,
-            peer_id,
-        }))
+        // A new bond replaces the existing one, if pairing was opened with
+        // `BluetoothCommand::OpenPairing`
+        OPEN_PAIRING.lock(|open_pairing| open_pairing.set(false));
+
+        if update_active_peer(|peer| *peer = Some(BondedPeer::new(master_id, key, peer_id))) {
+            update_bluetooth_status(|status| status.connection = BluetoothConnectionStatus::Bonded);
+
+            // Bonds are infrequent, and losing one would require the host to pair again
+            #[cfg(feature = "storage")]
+            storage::BLUETOOTH_PROFILES_SAVE_SIGNAL.signal(());
+        } else {
+            error!("[BT_HID] Could not store bond");
+        }
     }
 
     fn get_key(&self, _conn: &Connection, master_id: MasterId) -> Option<EncryptionInfo> {
         // Reconnecting with an existing bond
         debug!("[BT_HID] getting bond for: id: {}", master_id);
 
-        self.peer
-            .get()
-            .and_then(|peer| (master_id == peer.master_id).then_some(peer.key))
+        BLUETOOTH_PROFILES_STATE.try_get().and_then(|profiles| {
+            profiles
+                .active_peer()
+                .and_then(|peer| (master_id == peer.master_id()).then(|| peer.key()))
+        })
     }
 
     fn save_sys_attrs(&self, conn: &Connection) {
@@ -110,15 +611,20 @@ impl SecurityHandler for Bonder {
             conn.peer_address()
         );
 
-        if let Some(peer) = self.peer.get() {
-            if peer.peer_id.is_match(conn.peer_address()) {
-                let mut sys_attrs = self.sys_attrs.borrow_mut();
-                let capacity = sys_attrs.capacity();
-                sys_attrs.resize(capacity, 0).unwrap();
-                let len = get_sys_attrs(conn, &mut sys_attrs).unwrap() as u16;
-                sys_attrs.truncate(len as usize);
-                // TODO: save sys_attrs for peer
+        let updated = update_active_peer(|peer| {
+            if let Some(peer) = peer
+                .as_mut()
+                .filter(|peer| peer.is_match(conn.peer_address()))
+            {
+                let capacity = peer.sys_attrs.capacity();
+                peer.sys_attrs.resize(capacity, 0).unwrap();
+                let len = get_sys_attrs(conn, &mut peer.sys_attrs).unwrap() as u16;
+                peer.sys_attrs.truncate(len as usize);
             }
+        });
+
+        if !updated {
+            warn!("[BT_HID] Could not save system attributes");
         }
     }
 
@@ -126,19 +632,12 @@ impl SecurityHandler for Bonder {
         let addr = conn.peer_address();
         debug!("[BT_HID] loading system attributes for: {}", addr);
 
-        let attrs = self.sys_attrs.borrow();
-
-        // TODO: search stored peers
-        let attrs = if self
-            .peer
-            .get()
-            .map(|peer| peer.peer_id.is_match(addr))
-            .unwrap_or(false)
-        {
-            (!attrs.is_empty()).then_some(attrs.as_slice())
-        } else {
-            None
-        };
+        let profiles = BLUETOOTH_PROFILES_STATE.try_get();
+        let attrs = profiles
+            .as_ref()
+            .and_then(|profiles| profiles.active_peer())
+            .filter(|peer| peer.is_match(addr) && !peer.sys_attrs.is_empty())
+            .map(|peer| peer.sys_attrs.as_slice());
 
         if let Err(err) = set_sys_attrs(conn, attrs) {
             warn!(
@@ -149,27 +648,16 @@ impl SecurityHandler for Bonder {
     }
 }
 
-#[repr(u8)]
-#[derive(Clone, Copy, PrimitiveEnum, Default)]
-pub enum VidSource {
-    #[default]
-    BluetoothSIG = 1,
-    UsbIF = 2,
-}
+/// Maximum length of the strings in the device information service. Longer strings are truncated.
+const DIS_STRING_MAX_LEN: usize = 32;
 
-#[derive(Clone, Copy, PackedStruct, Default)]
-#[packed_struct(endian = "lsb", bit_numbering = "msb0")]
-pub struct PnPID {
-    #[packed_field(bytes = "0", ty = "enum")]
-    pub vid_source: VidSource,
-    #[packed_field()]
-    pub vendor_id: u16,
-    #[packed_field()]
-    pub product_id: u16,
-    #[packed_field()]
-    pub product_version: u16,
+fn dis_string(str: &str) -> &[u8] {
+    let bytes = str.as_bytes();
+    &bytes[..bytes.len().min(DIS_STRING_MAX_LEN)]
 }
 
+/// Device information service. The characteristics in this service can be read before pairing,
+/// so that host devices can identify the keyboard when it is discovered.
 pub struct DeviceInformationService {
     model_number_value_handle: u16,
     serial_number_value_handle: u16,
@@ -189,8 +677,8 @@ impl DeviceInformationService {
             .add_characteristic(
                 Uuid::new_16(0x2a24),
                 Attribute::new("")
-                    .variable_len(32)
-                    .read_security(SecurityMode::JustWorks),
+                    .variable_len(DIS_STRING_MAX_LEN as u16)
+                    .read_security(SecurityMode::Open),
                 Metadata::new(Properties::new().read()),
             )
             .unwrap()
@@ -200,8 +688,8 @@ impl DeviceInformationService {
             .add_characteristic(
                 Uuid::new_16(0x2a25),
                 Attribute::new("")
-                    .variable_len(32)
-                    .read_security(SecurityMode::JustWorks),
+                    .variable_len(DIS_STRING_MAX_LEN as u16)
+                    .read_security(SecurityMode::Open),
                 Metadata::new(Properties::new().read()),
             )
             .unwrap()
@@ -211,8 +699,8 @@ impl DeviceInformationService {
             .add_characteristic(
                 Uuid::new_16(0x2a26),
                 Attribute::new("")
-                    .variable_len(32)
-                    .read_security(SecurityMode::JustWorks),
+                    .variable_len(DIS_STRING_MAX_LEN as u16)
+                    .read_security(SecurityMode::Open),
                 Metadata::new(Properties::new().read()),
             )
             .unwrap()
@@ -222,8 +710,8 @@ impl DeviceInformationService {
             .add_characteristic(
                 Uuid::new_16(0x2a27),
                 Attribute::new("")
-                    .variable_len(32)
-                    .read_security(SecurityMode::JustWorks),
+                    .variable_len(DIS_STRING_MAX_LEN as u16)
+                    .read_security(SecurityMode::Open),
                 Metadata::new(Properties::new().read()),
             )
             .unwrap()
@@ -233,8 +721,8 @@ impl DeviceInformationService {
             .add_characteristic(
                 Uuid::new_16(0x2a29),
                 Attribute::new("")
-                    .variable_len(32)
-                    .read_security(SecurityMode::JustWorks),
+                    .variable_len(DIS_STRING_MAX_LEN as u16)
+                    .read_security(SecurityMode::Open),
                 Metadata::new(Properties::new().read()),
             )
             .unwrap()
@@ -243,8 +731,7 @@ impl DeviceInformationService {
         let pnp_id_handles = sb
             .add_characteristic(
                 Uuid::new_16(0x2a50),
-                Attribute::new(PnPID::default().pack().unwrap())
-                    .read_security(SecurityMode::JustWorks),
+                Attribute::new(PnPID::default().pack().unwrap()).read_security(SecurityMode::Open),
                 Metadata::new(Properties::new().read()),
             )
             .unwrap()
@@ -267,7 +754,7 @@ impl DeviceInformationService {
         sd: &Softdevice,
         str: &'static str,
     ) -> Result<(), SetValueError> {
-        gatt_server::set_value(sd, self.model_number_value_handle, str.as_bytes())?;
+        gatt_server::set_value(sd, self.model_number_value_handle, dis_string(str))?;
         Ok(())
     }
 
@@ -276,7 +763,7 @@ impl DeviceInformationService {
         sd: &Softdevice,
         str: &'static str,
     ) -> Result<(), SetValueError> {
-        gatt_server::set_value(sd, self.serial_number_value_handle, str.as_bytes())?;
+        gatt_server::set_value(sd, self.serial_number_value_handle, dis_string(str))?;
         Ok(())
     }
 
@@ -285,7 +772,7 @@ impl DeviceInformationService {
         sd: &Softdevice,
         str: &'static str,
     ) -> Result<(), SetValueError> {
-        gatt_server::set_value(sd, self.firmware_revision_value_handle, str.as_bytes())?;
+        gatt_server::set_value(sd, self.firmware_revision_value_handle, dis_string(str))?;
         Ok(())
     }
 
@@ -294,7 +781,7 @@ impl DeviceInformationService {
         sd: &Softdevice,
         str: &'static str,
     ) -> Result<(), SetValueError> {
-        gatt_server::set_value(sd, self.hardware_revision_value_handle, str.as_bytes())?;
+        gatt_server::set_value(sd, self.hardware_revision_value_handle, dis_string(str))?;
         Ok(())
     }
 
@@ -303,7 +790,7 @@ impl DeviceInformationService {
         sd: &Softdevice,
         str: &'static str,
     ) -> Result<(), SetValueError> {
-        gatt_server::set_value(sd, self.manufacturer_name_value_handle, str.as_bytes())?;
+        gatt_server::set_value(sd, self.manufacturer_name_value_handle, dis_string(str))?;
         Ok(())
     }
 
@@ -324,96 +811,17 @@ impl Service for DeviceInformationService {
 pub struct HIDService {
     keyboard_report_value_handle: u16,
     keyboard_report_cccd_handle: u16,
+    keyboard_led_report_value_handle: u16,
     consumer_report_value_handle: u16,
     consumer_report_cccd_handle: u16,
-    via_input_report_value_handle: u16,
-    via_input_report_cccd_handle: u16,
-    via_output_report_value_handle: u16,
+    system_control_report_value_handle: u16,
+    system_control_report_cccd_handle: u16,
+    raw_hid_input_report_value_handle: u16,
+    raw_hid_input_report_cccd_handle: u16,
+    raw_hid_output_report_value_handle: u16,
     hid_control_value_handle: u16,
 }
 
-/// Report descriptor with NKRO, consumer control and Via functionality. This is basically a
-/// combination of
-/// [`usbd_human_interface_device::device::keyboard::NKRO_BOOT_KEYBOARD_REPORT_DESCRIPTOR`],
-/// [`usbd_human_interface_device::device::consumer::MULTIPLE_CODE_REPORT_DESCRIPTOR`], and
-/// [`crate::via::VIA_REPORT_DESCRIPTOR`], with report IDs included. Without report IDs, some
-/// functionality doesn't seem to work as expected. In testing, exclusion of a report ID seems to
-/// prevent Via output reports from being received. Potentially related:
-/// https://devzone.nordicsemi.com/f/nordic-q-a/24486/hid-get-report-from-a-mac-not-as-expected
-pub(crate) const REPORT_MAP: &[u8] = &[
-    // NKRO reports
-    0x05, 0x01, // Usage Page (Generic Desktop),
-    0x09, 0x06, // Usage (Keyboard),
-    0xA1, 0x01, // Collection (Application),
-    // bitmap of modifiers
-    0x85, 0x01, //   Report ID (1)
-    0x75, 0x01, //   Report Size (1),
-    0x95, 0x08, //   Report Count (8),
-    0x05, 0x07, //   Usage Page (Key Codes),
-    0x19, 0xE0, //   Usage Minimum (224),
-    0x29, 0xE7, //   Usage Maximum (231),
-    0x15, 0x00, //   Logical Minimum (0),
-    0x25, 0x01, //   Logical Maximum (1),
-    0x81, 0x02, //   Input (Data, Variable, Absolute), ;Modifier byte
-    // 7 bytes of padding
-    0x75, 0x38, //   Report Size (0x38),
-    0x95, 0x01, //   Report Count (1),
-    0x81, 0x01, //   Input (Constant), ;Reserved byte
-    // LED output report
-    0x95, 0x05, //   Report Count (5),
-    0x75, 0x01, //   Report Size (1),
-    0x05, 0x08, //   Usage Page (LEDs),
-    0x19, 0x01, //   Usage Minimum (1),
-    0x29, 0x05, //   Usage Maximum (5),
-    0x91, 0x02, //   Output (Data, Variable, Absolute),
-    0x95, 0x01, //   Report Count (1),
-    0x75, 0x03, //   Report Size (3),
-    0x91, 0x03, //   Output (Constant),
-    // bitmap of keys
-    0x95, 0x88, //   Report Count () - (REPORT_BYTES-1)*8
-    0x75, 0x01, //   Report Size (1),
-    0x15, 0x00, //   Logical Minimum (0),
-    0x25, 0x01, //   Logical Maximum(1),
-    0x05, 0x07, //   Usage Page (Key Codes),
-    0x19, 0x00, //   Usage Minimum (0),
-    0x29, 0x87, //   Usage Maximum (), - (REPORT_BYTES-1)*8-1
-    0x81, 0x02, //   Input (Data, Variable, Absolute),
-    0xc0, // End Collection
-    // Consumer reports
-    0x05, 0x0C, // Usage Page (Consumer),
-    0x09, 0x01, // Usage (Consumer Control),
-    0xA1, 0x01, // Collection (Application),
-    0x85, 0x02, //   Report ID (2)
-    0x75, 0x10, //     Report Size(16)
-    0x95, 0x04, //     Report Count(4)
-    0x15, 0x00, //     Logical Minimum(0)
-    0x26, 0x9C, 0x02, //     Logical Maximum(0x029C)
-    0x19, 0x00, //     Usage Minimum(0)
-    0x2A, 0x9C, 0x02, //     Usage Maximum(0x029C)
-    0x81, 0x00, //     Input (Array, Data, Variable)
-    0xC0, // End Collection
-    // Via reports
-    0x06, 0x60, 0xFF, // Usage Page (Vendor Defined)
-    0x09, 0x61, // Usage (Vendor Defined)
-    0xA1, 0x01, // Collection (Application)
-    0x85, 0x03, //   Report ID (3)
-    // Data to host
-    0x09, 0x62, //   Usage (Vendor Defined)
-    0x15, 0x00, //   Logical Minimum (0)
-    0x26, 0xFF, 0x00, //   Logical Maximum (255)
-    0x95, 0x20, //   Report Count
-    0x75, 0x08, //   Report Size (8)
-    0x81, 0x02, //   Input (Data, Variable, Absolute)
-    // Data from host
-    0x09, 0x63, //   Usage (Vendor Defined)
-    0x15, 0x00, //   Logical Minimum (0)
-    0x26, 0xFF, 0x00, //   Logical Maximum (255)
-    0x95, 0x20, //   Report Count
-    0x75, 0x08, //   Report Size (8)
-    0x91, 0x02, //   Output (Data, Variable, Absolute)
-    0xC0, // End Collection
-];
-
 impl HIDService {
     pub fn new(sd: &mut Softdevice) -> Result<Self, RegisterError> {
         let mut sb = ServiceBuilder::new(sd, Uuid::new_16(0x1812)).unwrap();
@@ -456,6 +864,28 @@ impl HIDService {
             .unwrap();
         let keyboard_report_handles = keyboard_report_builder.build();
 
+        let mut keyboard_led_report_builder = sb
+            .add_characteristic(
+                Uuid::new_16(0x2a4d),
+                Attribute::new([0]).security(SecurityMode::JustWorks),
+                Metadata::with_security(
+                    Properties::new().read().write().write_without_response(),
+                    SecurityMode::JustWorks,
+                ),
+            )
+            .unwrap();
+        keyboard_led_report_builder
+            .add_descriptor(
+                Uuid::new_16(0x2908),
+                Attribute::new(&[
+                    0x01, // ID
+                    0x02, // Output
+                ])
+                .security(SecurityMode::JustWorks),
+            )
+            .unwrap();
+        let keyboard_led_report_handles = keyboard_led_report_builder.build();
+
         let mut consumer_report_builder = sb
             .add_characteristic(
                 Uuid::new_16(0x2a4d),
@@ -476,14 +906,34 @@ impl HIDService {
             .unwrap();
         let consumer_report_handles = consumer_report_builder.build();
 
-        let mut via_input_report_builder = sb
+        let mut system_control_report_builder = sb
+            .add_characteristic(
+                Uuid::new_16(0x2a4d),
+                Attribute::new(SystemControlReport::default().pack().unwrap())
+                    .security(SecurityMode::JustWorks),
+                Metadata::with_security(Properties::new().read().notify(), SecurityMode::JustWorks),
+            )
+            .unwrap();
+        system_control_report_builder
+            .add_descriptor(
+                Uuid::new_16(0x2908),
+                Attribute::new(&[
+                    0x04, // ID
+                    0x01, // Input
+                ])
+                .security(SecurityMode::JustWorks),
+            )
+            .unwrap();
+        let system_control_report_handles = system_control_report_builder.build();
+
+        let mut raw_hid_input_report_builder = sb
             .add_characteristic(
                 Uuid::new_16(0x2a4d),
                 Attribute::new([0; 32]).security(SecurityMode::JustWorks),
                 Metadata::with_security(Properties::new().read().notify(), SecurityMode::JustWorks),
             )
             .unwrap();
-        via_input_report_builder
+        raw_hid_input_report_builder
             .add_descriptor(
                 Uuid::new_16(0x2908),
                 Attribute::new(&[
@@ -493,9 +943,9 @@ impl HIDService {
                 .security(SecurityMode::JustWorks),
             )
             .unwrap();
-        let via_input_report_handles = via_input_report_builder.build();
+        let raw_hid_input_report_handles = raw_hid_input_report_builder.build();
 
-        let mut via_output_report_builder = sb
+        let mut raw_hid_output_report_builder = sb
             .add_characteristic(
                 Uuid::new_16(0x2a4d),
                 Attribute::new([0; 32]).security(SecurityMode::JustWorks),
@@ -505,7 +955,7 @@ impl HIDService {
                 ),
             )
             .unwrap();
-        via_output_report_builder
+        raw_hid_output_report_builder
             .add_descriptor(
                 Uuid::new_16(0x2908),
                 Attribute::new(&[
@@ -515,7 +965,7 @@ impl HIDService {
                 .security(SecurityMode::JustWorks),
             )
             .unwrap();
-        let via_output_report_handles = via_output_report_builder.build();
+        let raw_hid_output_report_handles = raw_hid_output_report_builder.build();
 
         let hid_control_builder = sb
             .add_characteristic(
@@ -531,11 +981,14 @@ impl HIDService {
         Ok(Self {
             keyboard_report_value_handle: keyboard_report_handles.value_handle,
             keyboard_report_cccd_handle: keyboard_report_handles.cccd_handle,
+            keyboard_led_report_value_handle: keyboard_led_report_handles.value_handle,
             consumer_report_value_handle: consumer_report_handles.value_handle,
             consumer_report_cccd_handle: consumer_report_handles.cccd_handle,
-            via_input_report_value_handle: via_input_report_handles.value_handle,
-            via_input_report_cccd_handle: via_input_report_handles.cccd_handle,
-            via_output_report_value_handle: via_output_report_handles.value_handle,
+            system_control_report_value_handle: system_control_report_handles.value_handle,
+            system_control_report_cccd_handle: system_control_report_handles.cccd_handle,
+            raw_hid_input_report_value_handle: raw_hid_input_report_handles.value_handle,
+            raw_hid_input_report_cccd_handle: raw_hid_input_report_handles.cccd_handle,
+            raw_hid_output_report_value_handle: raw_hid_output_report_handles.value_handle,
             hid_control_value_handle: hid_control_handles.value_handle,
         })
     }
@@ -566,24 +1019,46 @@ impl HIDService {
         Ok(())
     }
 
-    pub fn via_report_notify(
+    pub fn system_control_report_notify(
+        &self,
+        connection: &Connection,
+        report: SystemControlReport,
+    ) -> Result<(), NotifyValueError> {
+        gatt_server::notify_value(
+            connection,
+            self.system_control_report_value_handle,
+            &report.pack().unwrap(),
+        )?;
+        Ok(())
+    }
+
+    pub fn raw_hid_report_notify(
         &self,
         connection: &Connection,
         report: [u8; 32],
     ) -> Result<(), NotifyValueError> {
-        gatt_server::notify_value(connection, self.via_input_report_value_handle, &report)?;
+        gatt_server::notify_value(connection, self.raw_hid_input_report_value_handle, &report)?;
         Ok(())
     }
 
-    pub fn unsafe_via_report_get(&self) -> Result<[u8; 32], GetValueError> {
+    pub fn unsafe_raw_hid_report_get(&self) -> Result<[u8; 32], GetValueError> {
         unsafe {
             let sd = nrf_softdevice::Softdevice::steal();
             let buf = &mut [0; 32];
-            gatt_server::get_value(sd, self.via_output_report_value_handle, buf)?;
+            gatt_server::get_value(sd, self.raw_hid_output_report_value_handle, buf)?;
             Ok(*buf)
         }
     }
 
+    pub fn unsafe_keyboard_led_report_get(&self) -> Result<u8, GetValueError> {
+        unsafe {
+            let sd = nrf_softdevice::Softdevice::steal();
+            let buf = &mut [0];
+            gatt_server::get_value(sd, self.keyboard_led_report_value_handle, buf)?;
+            Ok(buf[0])
+        }
+    }
+
     pub fn unsafe_hid_control_get(&self) -> Result<u8, GetValueError> {
         unsafe {
             let sd = nrf_softdevice::Softdevice::steal();
@@ -596,9 +1071,11 @@ impl HIDService {
 
 pub enum HIDServiceEvent {
     KeyboardReportCccdWrite { notifications: bool },
+    KeyboardLedReportWrite(u8),
     ConsumerReportCccdWrite { notifications: bool },
-    ViaReportCccdWrite { notifications: bool },
-    ViaReportWrite([u8; 32]),
+    SystemControlReportCccdWrite { notifications: bool },
+    RawHIDReportCccdWrite { notifications: bool },
+    RawHIDReportWrite([u8; 32]),
     HidControlWrite(u8),
 }
 
@@ -636,29 +1113,56 @@ impl Service for HIDService {
                 _ => {}
             }
         }
-        if handle == self.via_input_report_cccd_handle {
+        if handle == self.system_control_report_cccd_handle {
+            match data[0] & 0x01 {
+                0x00 => {
+                    return Some(HIDServiceEvent::SystemControlReportCccdWrite {
+                        notifications: false,
+                    })
+                }
+                0x01 => {
+                    return Some(HIDServiceEvent::SystemControlReportCccdWrite {
+                        notifications: true,
+                    })
+                }
+                _ => {}
+            }
+        }
+        if handle == self.raw_hid_input_report_cccd_handle {
             match data[0] & 0x01 {
                 0x00 => {
-                    return Some(HIDServiceEvent::ViaReportCccdWrite {
+                    return Some(HIDServiceEvent::RawHIDReportCccdWrite {
                         notifications: false,
                     })
                 }
                 0x01 => {
-                    return Some(HIDServiceEvent::ViaReportCccdWrite {
+                    return Some(HIDServiceEvent::RawHIDReportCccdWrite {
                         notifications: true,
                     })
                 }
                 _ => {}
             }
         }
-        if handle == self.via_output_report_value_handle {
+        if handle == self.raw_hid_output_report_value_handle {
+            if data.len() < <u8 as GattValue>::MIN_SIZE {
+                return self
+                    .unsafe_raw_hid_report_get()
+                    .ok()
+                    .map(HIDServiceEvent::RawHIDReportWrite);
+            } else {
+                return Some(HIDServiceEvent::RawHIDReportWrite(<[u8; 32]>::from_gatt(
+                    data,
+                )));
+            }
+        }
+        if handle == self.keyboard_led_report_value_handle {
             if data.len() < <u8 as GattValue>::MIN_SIZE {
                 return self
-                    .unsafe_via_report_get()
+                    .unsafe_keyboard_led_report_get()
                     .ok()
-                    .map(HIDServiceEvent::ViaReportWrite);
+                    .map(HIDServiceEvent::KeyboardLedReportWrite);
             } else {
-                return Some(HIDServiceEvent::ViaReportWrite(<[u8; 32]>::from_gatt(data)));
+                return Some(HIDServiceEvent::KeyboardLedReportWrite(u8::from_gatt(data)));
             }
         }
         if handle == self.hid_control_value_handle {
@@ -675,42 +1179,499 @@ impl Service for HIDService {
     }
 }
 
-#[nrf_softdevice::gatt_service(uuid = "180f")]
-pub struct BatteryService {
-    #[characteristic(uuid = "2a19", read, notify, security = "justworks")]
-    battery_level: u8,
-}
+/// UUID of the legacy Nordic DFU service (`00001530-1212-EFDE-1523-785FEABCD123`).
+#[cfg(feature = "ota")]
+const DFU_SERVICE_UUID: [u8; 16] = [
+    0x23, 0xD1, 0xBC, 0xEA, 0x5F, 0x78, 0x23, 0x15, 0xDE, 0xEF, 0x12, 0x12, 0x30, 0x15, 0x00, 0x00,
+];
 
-#[nrf_softdevice::gatt_server]
-pub struct Server {
-    bas: BatteryService,
-    dis: DeviceInformationService,
-    hids: HIDService,
+/// UUID of the legacy Nordic DFU control point (`00001531-1212-EFDE-1523-785FEABCD123`).
+#[cfg(feature = "ota")]
+const DFU_CONTROL_POINT_UUID: [u8; 16] = [
+    0x23, 0xD1, 0xBC, 0xEA, 0x5F, 0x78, 0x23, 0x15, 0xDE, 0xEF, 0x12, 0x12, 0x31, 0x15, 0x00, 0x00,
+];
+
+/// Opcode written to the DFU control point to start a firmware update.
+const DFU_OP_START_DFU: u8 = 0x01;
+
+/// Legacy Nordic DFU service, used for "buttonless" DFU. When a DFU app (like nRF Connect) starts
+/// a firmware update, the keyboard restarts into the bootloader's OTA DFU mode, so that the
+/// firmware can be updated without pressing a reset button.
+///
+/// This service is only registered if the `ota` feature is enabled.
+pub struct DfuService {
+    control_point_value_handle: u16,
+    control_point_cccd_handle: u16,
 }
 
-#[rumcake_macros::task]
-pub async fn nrf_ble_task<K: BluetoothKeyboard>(_k: K, sd: &'static Softdevice, server: Server)
-where
-    [(); K::PRODUCT.len() + 15]:,
-{
-    #[rustfmt::skip]
-    let adv_data: Vec<u8, { K::PRODUCT.len() + 15 }> = [
-        0x02, 0x01, nrf_softdevice::raw::BLE_GAP_ADV_FLAGS_LE_ONLY_GENERAL_DISC_MODE as u8,
-        0x05, 0x03, 0x12, 0x18, 0x0F, 0x18, // Incomplete list of 16 bit services: HID service and battery service
-        0x03, 0x19, 0xC1, 0x03, // Appearance: Keyboard
-        (K::PRODUCT.len() + 1) as u8, 0x09, // Complete name: keyboard name
-    ].iter().cloned().chain(K::PRODUCT.as_bytes().iter().cloned()).collect();
+impl DfuService {
+    #[cfg(feature = "ota")]
+    pub fn new(sd: &mut Softdevice) -> Result<Self, RegisterError> {
+        let mut sb = ServiceBuilder::new(sd, Uuid::new_128(&DFU_SERVICE_UUID)).unwrap();
 
-    #[rustfmt::skip]
-    let scan_data = [
-        0x05, 0x03, 0x12, 0x18, 0x0F, 0x18, // Incomplete list of 16 bit services: HID service and battery service
-    ];
+        let control_point_handles = sb
+            .add_characteristic(
+                Uuid::new_128(&DFU_CONTROL_POINT_UUID),
+                Attribute::new([0; 20])
+                    .variable_len(20)
+                    .security(SecurityMode::JustWorks),
+                Metadata::with_security(
+                    Properties::new().write().notify(),
+                    SecurityMode::JustWorks,
+                ),
+            )
+            .unwrap()
+            .build();
 
-    // Setup device information service
-    server.dis.model_number_set(sd, K::PRODUCT).unwrap();
-    server.dis.serial_number_set(sd, K::SERIAL_NUMBER).unwrap();
-    server
-        .dis
+        sb.build();
+
+        Ok(Self {
+            control_point_value_handle: control_point_handles.value_handle,
+            control_point_cccd_handle: control_point_handles.cccd_handle,
+        })
+    }
+
+    #[cfg(not(feature = "ota"))]
+    pub fn new(_sd: &mut Softdevice) -> Result<Self, RegisterError> {
+        // Attribute handles start at 1, so these will never match a write
+        Ok(Self {
+            control_point_value_handle: 0,
+            control_point_cccd_handle: 0,
+        })
+    }
+}
+
+pub enum DfuServiceEvent {
+    ControlPointCccdWrite { notifications: bool },
+    StartDfu,
+}
+
+impl Service for DfuService {
+    type Event = DfuServiceEvent;
+
+    fn on_write(&self, handle: u16, data: &[u8]) -> Option<Self::Event> {
+        if handle == self.control_point_cccd_handle && !data.is_empty() {
+            return Some(DfuServiceEvent::ControlPointCccdWrite {
+                notifications: data[0] & 0x01 != 0,
+            });
+        }
+        if handle == self.control_point_value_handle && data.first() == Some(&DFU_OP_START_DFU) {
+            return Some(DfuServiceEvent::StartDfu);
+        }
+        None
+    }
+}
+
+/// UUID of the Nordic UART Service (`6E400001-B5A3-F393-E0A9-E50E24DCCA9E`).
+#[cfg(feature = "nus-console")]
+const NUS_SERVICE_UUID: [u8; 16] = [
+    0x9E, 0xCA, 0xDC, 0x24, 0x0E, 0xE5, 0xA9, 0xE0, 0x93, 0xF3, 0xA3, 0xB5, 0x01, 0x00, 0x40, 0x6E,
+];
+
+/// UUID of the NUS RX characteristic (`6E400002-B5A3-F393-E0A9-E50E24DCCA9E`), written by the host.
+#[cfg(feature = "nus-console")]
+const NUS_RX_UUID: [u8; 16] = [
+    0x9E, 0xCA, 0xDC, 0x24, 0x0E, 0xE5, 0xA9, 0xE0, 0x93, 0xF3, 0xA3, 0xB5, 0x02, 0x00, 0x40, 0x6E,
+];
+
+/// UUID of the NUS TX characteristic (`6E400003-B5A3-F393-E0A9-E50E24DCCA9E`), notified to the
+/// host.
+#[cfg(feature = "nus-console")]
+const NUS_TX_UUID: [u8; 16] = [
+    0x9E, 0xCA, 0xDC, 0x24, 0x0E, 0xE5, 0xA9, 0xE0, 0x93, 0xF3, 0xA3, 0xB5, 0x03, 0x00, 0x40, 0x6E,
+];
+
+/// Maximum number of bytes carried by a single NUS write or notification. This is the largest
+/// payload that fits in the default ATT MTU (23 bytes).
+const NUS_MAX_LEN: usize = 20;
+
+/// Nordic UART Service, used to carry the console over bluetooth. Console output is notified on
+/// the TX characteristic, and commands are written to the RX characteristic.
+///
+/// This service is only registered if the `nus-console` feature is enabled.
+pub struct NusService {
+    rx_value_handle: u16,
+    tx_value_handle: u16,
+    tx_cccd_handle: u16,
+}
+
+impl NusService {
+    #[cfg(feature = "nus-console")]
+    pub fn new(sd: &mut Softdevice) -> Result<Self, RegisterError> {
+        let mut sb = ServiceBuilder::new(sd, Uuid::new_128(&NUS_SERVICE_UUID)).unwrap();
+
+        let rx_handles = sb
+            .add_characteristic(
+                Uuid::new_128(&NUS_RX_UUID),
+                Attribute::new([0; NUS_MAX_LEN])
+                    .variable_len(NUS_MAX_LEN as u16)
+                    .security(SecurityMode::JustWorks),
+                Metadata::with_security(
+                    Properties::new().write().write_without_response(),
+                    SecurityMode::JustWorks,
+                ),
+            )
+            .unwrap()
+            .build();
+
+        let tx_handles = sb
+            .add_characteristic(
+                Uuid::new_128(&NUS_TX_UUID),
+                Attribute::new([0; NUS_MAX_LEN])
+                    .variable_len(NUS_MAX_LEN as u16)
+                    .security(SecurityMode::JustWorks),
+                Metadata::with_security(Properties::new().notify(), SecurityMode::JustWorks),
+            )
+            .unwrap()
+            .build();
+
+        sb.build();
+
+        Ok(Self {
+            rx_value_handle: rx_handles.value_handle,
+            tx_value_handle: tx_handles.value_handle,
+            tx_cccd_handle: tx_handles.cccd_handle,
+        })
+    }
+
+    #[cfg(not(feature = "nus-console"))]
+    pub fn new(_sd: &mut Softdevice) -> Result<Self, RegisterError> {
+        // Attribute handles start at 1, so these will never match a write
+        Ok(Self {
+            rx_value_handle: 0,
+            tx_value_handle: 0,
+            tx_cccd_handle: 0,
+        })
+    }
+
+    pub fn tx_notify(&self, connection: &Connection, data: &[u8]) -> Result<(), NotifyValueError> {
+        gatt_server::notify_value(connection, self.tx_value_handle, data)
+    }
+}
+
+pub enum NusServiceEvent {
+    TxCccdWrite { notifications: bool },
+    RxWrite(Vec<u8, NUS_MAX_LEN>),
+}
+
+impl Service for NusService {
+    type Event = NusServiceEvent;
+
+    fn on_write(&self, handle: u16, data: &[u8]) -> Option<Self::Event> {
+        if handle == self.tx_cccd_handle && !data.is_empty() {
+            return Some(NusServiceEvent::TxCccdWrite {
+                notifications: data[0] & 0x01 != 0,
+            });
+        }
+        if handle == self.rx_value_handle {
+            return Vec::from_slice(data).ok().map(NusServiceEvent::RxWrite);
+        }
+        None
+    }
+}
+
+/// Service used by a `rumcake` dongle to receive HID reports and battery levels from the
+/// keyboard, and to send lock LED changes back. Once the dongle subscribes to the
+/// [`MessageToDongle`] characteristic, HID reports are sent using this service instead of the HID
+/// service. See [`crate::dongle`].
+///
+/// This service is only registered if the `dongle` feature is enabled.
+pub struct DongleService {
+    #[cfg(feature = "dongle")]
+    message_to_dongle_value_handle: u16,
+    message_to_dongle_cccd_handle: u16,
+    #[cfg(feature = "dongle")]
+    message_to_keyboard_value_handle: u16,
+}
+
+impl DongleService {
+    #[cfg(feature = "dongle")]
+    pub fn new(sd: &mut Softdevice) -> Result<Self, RegisterError> {
+        let mut sb = ServiceBuilder::new(sd, Uuid::new_128(&DONGLE_SERVICE_UUID)).unwrap();
+
+        let message_to_dongle_handles = sb
+            .add_characteristic(
+                Uuid::new_128(&MESSAGE_TO_DONGLE_UUID),
+                Attribute::new([0; MESSAGE_TO_DONGLE_BUFFER_SIZE])
+                    .security(SecurityMode::JustWorks),
+                Metadata::with_security(Properties::new().read().notify(), SecurityMode::JustWorks),
+            )
+            .unwrap()
+            .build();
+
+        let message_to_keyboard_handles = sb
+            .add_characteristic(
+                Uuid::new_128(&MESSAGE_TO_KEYBOARD_UUID),
+                Attribute::new([0; MESSAGE_TO_KEYBOARD_BUFFER_SIZE])
+                    .security(SecurityMode::JustWorks),
+                Metadata::with_security(Properties::new().write(), SecurityMode::JustWorks),
+            )
+            .unwrap()
+            .build();
+
+        sb.build();
+
+        Ok(Self {
+            message_to_dongle_value_handle: message_to_dongle_handles.value_handle,
+            message_to_dongle_cccd_handle: message_to_dongle_handles.cccd_handle,
+            message_to_keyboard_value_handle: message_to_keyboard_handles.value_handle,
+        })
+    }
+
+    #[cfg(not(feature = "dongle"))]
+    pub fn new(_sd: &mut Softdevice) -> Result<Self, RegisterError> {
+        // Attribute handles start at 1, so this will never match a write
+        Ok(Self {
+            message_to_dongle_cccd_handle: 0,
+        })
+    }
+
+    #[cfg(feature = "dongle")]
+    pub fn message_to_dongle_notify(
+        &self,
+        connection: &Connection,
+        message: &MessageToDongle,
+    ) -> Result<(), NotifyValueError> {
+        let mut buf = [0; MESSAGE_TO_DONGLE_BUFFER_SIZE];
+        postcard::to_slice_cobs(message, &mut buf).unwrap();
+        gatt_server::notify_value(connection, self.message_to_dongle_value_handle, &buf)
+    }
+}
+
+pub enum DongleServiceEvent {
+    MessageToDongleCccdWrite {
+        notifications: bool,
+    },
+    #[cfg(feature = "dongle")]
+    MessageToKeyboardWrite(MessageToKeyboard),
+}
+
+impl Service for DongleService {
+    type Event = DongleServiceEvent;
+
+    fn on_write(&self, handle: u16, data: &[u8]) -> Option<Self::Event> {
+        if handle == self.message_to_dongle_cccd_handle && !data.is_empty() {
+            return Some(DongleServiceEvent::MessageToDongleCccdWrite {
+                notifications: data[0] & 0x01 != 0,
+            });
+        }
+
+        #[cfg(feature = "dongle")]
+        if handle == self.message_to_keyboard_value_handle {
+            let mut buf = [0; MESSAGE_TO_KEYBOARD_BUFFER_SIZE];
+            let len = data.len().min(buf.len());
+            buf[..len].copy_from_slice(&data[..len]);
+
+            return match postcard::from_bytes_cobs(&mut buf) {
+                Ok(message) => Some(DongleServiceEvent::MessageToKeyboardWrite(message)),
+                Err(err) => {
+                    warn!(
+                        "[BT_HID] Invalid message from dongle: {:?}",
+                        Debug2Format(&err)
+                    );
+                    None
+                }
+            };
+        }
+
+        None
+    }
+}
+
+#[nrf_softdevice::gatt_service(uuid = "180f")]
+pub struct BatteryService {
+    #[characteristic(uuid = "2a19", read, notify, security = "justworks")]
+    battery_level: u8,
+    #[characteristic(uuid = "2bed", read, notify, security = "justworks")]
+    battery_level_status: [u8; 4],
+    #[characteristic(uuid = "2bf0", read, notify, security = "justworks")]
+    battery_energy_status: [u8; 3],
+}
+
+/// Encode a [`BatteryStatus`] as a Battery Level Status characteristic value.
+fn battery_level_status(status: &BatteryStatus) -> [u8; 4] {
+    let wired_power: u16 = match status.charge_state {
+        BatteryChargeState::Unknown => 0b10,
+        BatteryChargeState::Discharging => 0b00,
+        BatteryChargeState::Charging | BatteryChargeState::Full => 0b01,
+    };
+    let charge_state: u16 = match status.charge_state {
+        BatteryChargeState::Unknown => 0,
+        BatteryChargeState::Charging => 1,
+        BatteryChargeState::Discharging => 2, // Discharging: Active
+        BatteryChargeState::Full => 3,        // Discharging: Inactive
+    };
+    let charge_level: u16 = match status.level {
+        0..=5 => 3,  // Critical
+        6..=20 => 2, // Low
+        _ => 1,      // Good
+    };
+
+    // Battery present, no wireless power source
+    let power_state = 1 | wired_power << 1 | charge_state << 5 | charge_level << 7;
+    let [low, high] = power_state.to_le_bytes();
+
+    // Flags: only the optional battery level field is present
+    [0b010, low, high, status.level]
+}
+
+/// Encode a [`BatteryStatus`] as a Battery Energy Status characteristic value, which only
+/// contains the battery voltage.
+fn battery_energy_status(status: &BatteryStatus) -> [u8; 3] {
+    // Voltage is an IEEE-11073 16-bit SFLOAT in volts. We use an exponent of -2 (10mV steps).
+    let voltage: u16 = match status.voltage_mv {
+        Some(mv) => 0xE000 | (mv / 10).min(0x07FD),
+        None => 0x07FF, // NaN
+    };
+    let [low, high] = voltage.to_le_bytes();
+
+    // Flags: only the present voltage field is present
+    [0b10, low, high]
+}
+
+#[cfg(feature = "split-central")]
+/// A second battery service, used to report the battery level of a split peripheral.
+#[nrf_softdevice::gatt_service(uuid = "180f")]
+pub struct PeripheralBatteryService {
+    #[characteristic(uuid = "2a19", read, notify, security = "justworks")]
+    battery_level: u8,
+}
+
+#[cfg(not(feature = "split-central"))]
+#[nrf_softdevice::gatt_server]
+pub struct Server {
+    bas: BatteryService,
+    dis: DeviceInformationService,
+    hids: HIDService,
+    dfu: DfuService,
+    nus: NusService,
+    dongle: DongleService,
+}
+
+#[cfg(feature = "split-central")]
+#[nrf_softdevice::gatt_server]
+pub struct Server {
+    bas: BatteryService,
+    peripheral_bas: PeripheralBatteryService,
+    dis: DeviceInformationService,
+    hids: HIDService,
+    dfu: DfuService,
+    nus: NusService,
+    dongle: DongleService,
+}
+
+#[cfg(feature = "dongle")]
+/// Whether the connected host is a dongle that has subscribed to the dongle service.
+static DONGLE_SUBSCRIBED: BlockingMutex<Cell<bool>> = BlockingMutex::new(Cell::new(false));
+
+/// Send a HID report to the connected host. If the host is a dongle, the report is sent using the
+/// dongle service instead of the HID service.
+fn notify_hid_report(
+    server: &Server,
+    connection: &Connection,
+    report: HIDReport,
+) -> Result<(), NotifyValueError> {
+    #[cfg(feature = "dongle")]
+    if DONGLE_SUBSCRIBED.lock(Cell::get) {
+        let message = match report {
+            HIDReport::Keyboard(report) => MessageToDongle::Keyboard(report.pack().unwrap()),
+            HIDReport::Consumer(report) => MessageToDongle::Consumer(report.pack().unwrap()),
+            HIDReport::SystemControl(report) => {
+                MessageToDongle::SystemControl(report.pack().unwrap())
+            }
+            #[cfg(feature = "raw-hid")]
+            HIDReport::RawHID(_) => {
+                // Dongles don't forward raw HID reports
+                debug!("[BT_HID] Dropping raw HID report, since the host is a dongle");
+                return Ok(());
+            }
+        };
+
+        return server.dongle.message_to_dongle_notify(connection, &message);
+    }
+
+    match report {
+        HIDReport::Keyboard(report) => server.hids.keyboard_report_notify(connection, report),
+        HIDReport::Consumer(report) => server.hids.consumer_report_notify(connection, report),
+        HIDReport::SystemControl(report) => {
+            server.hids.system_control_report_notify(connection, report)
+        }
+        #[cfg(feature = "raw-hid")]
+        HIDReport::RawHID(report) => server.hids.raw_hid_report_notify(connection, report),
+    }
+}
+
+#[cfg(feature = "nus-console")]
+/// Signal used to notify the NUS console output task that the host has subscribed to (or
+/// unsubscribed from) console output.
+static NUS_CONSOLE_SUBSCRIBED_SIGNAL: Signal<RawMutex, bool> = Signal::new();
+
+#[cfg(feature = "nus-console")]
+/// Forward console output to the host over the Nordic UART Service. Output is dropped if the host
+/// has not subscribed to the TX characteristic.
+async fn nus_console_output(connection: &Connection, server: &Server) {
+    let mut buf = [0; NUS_MAX_LEN];
+
+    NUS_CONSOLE_SUBSCRIBED_SIGNAL.reset();
+
+    loop {
+        match select(
+            crate::console::NUS_CONSOLE_OUTPUT_PIPE.read(&mut buf),
+            NUS_CONSOLE_SUBSCRIBED_SIGNAL.wait(),
+        )
+        .await
+        {
+            select::Either::First(len) => {
+                let _ = server.nus.tx_notify(connection, &buf[..len]);
+            }
+            select::Either::Second(true) => {
+                info!("[BT_HID] NUS console connected");
+
+                // Ignore any unprocessed output due to lack of a subscriber
+                while crate::console::NUS_CONSOLE_OUTPUT_PIPE
+                    .try_read(&mut buf)
+                    .is_ok()
+                {}
+                crate::console_println!("rumcake console. Type `help` for a list of commands.");
+                crate::console::print_prompt();
+            }
+            select::Either::Second(false) => {}
+        }
+    }
+}
+
+async fn update_profiles(f: impl FnOnce(&mut BluetoothProfiles)) {
+    // Pairing is only opened for the profile that was active at the time
+    OPEN_PAIRING.lock(|open_pairing| open_pairing.set(false));
+
+    BLUETOOTH_PROFILES_STATE
+        .update(|profiles| {
+            f(profiles);
+            info!(
+                "[BT_HID] Active bluetooth profile: {}, bonded: {}",
+                profiles.active,
+                profiles.is_bonded(profiles.active)
+            );
+        })
+        .await;
+
+    // Profile changes are infrequent, so save them immediately
+    #[cfg(feature = "storage")]
+    storage::BLUETOOTH_PROFILES_SAVE_SIGNAL.signal(());
+}
+
+#[rumcake_macros::task]
+pub async fn nrf_ble_task<K: BluetoothKeyboard>(_k: K, sd: &'static Softdevice, server: Server) {
+    #[rustfmt::skip]
+    let scan_data = [
+        0x05, 0x03, 0x12, 0x18, 0x0F, 0x18, // Incomplete list of 16 bit services: HID service and battery service
+    ];
+
+    // Setup device information service
+    server.dis.model_number_set(sd, K::PRODUCT).unwrap();
+    server.dis.serial_number_set(sd, K::SERIAL_NUMBER).unwrap();
+    server
+        .dis
         .manufacturer_name_set(sd, K::MANUFACTURER)
         .unwrap();
     server
@@ -726,10 +1687,10 @@ where
         .pnp_id_set(
             sd,
             &PnPID {
-                vid_source: VidSource::BluetoothSIG,
+                vid_source: K::BLE_VID_SOURCE,
                 product_id: K::BLE_PID,
                 vendor_id: K::BLE_VID,
-                product_version: 1,
+                product_version: K::BLE_PRODUCT_VERSION,
             },
         )
         .unwrap();
@@ -737,27 +1698,101 @@ where
     info!("[BT_HID] Bluetooth services started");
 
     static BONDER: StaticCell<Bonder> = StaticCell::new();
-    let bonder = BONDER.init(Bonder::default());
+    let bonder = BONDER.init(Bonder {
+        pairing_mode: K::BLE_PAIRING_MODE,
+    });
 
     let connection_fut = async {
         loop {
-            let advertisement = ConnectableAdvertisement::ScannableUndirected {
-                adv_data: &adv_data,
-                scan_data: &scan_data,
+            let profiles = BLUETOOTH_PROFILES_STATE.get().await;
+            let profile = profiles.active;
+            let peer = if OPEN_PAIRING.lock(Cell::get) {
+                None
+            } else {
+                profiles.active_peer().cloned()
             };
 
+            let adv_data = advertising_data::<K>(profile);
+            if K::BLE_PROFILE_NAME_SUFFIX {
+                set_device_name::<K>(profile);
+            }
+
             let connection = {
-                let _lock = BLUETOOTH_ADVERTISING_MUTEX.lock().await;
-                match advertise_pairable(sd, advertisement, &Default::default(), bonder).await {
-                    Ok(connection) => {
+                let lock = BLUETOOTH_ADVERTISING_MUTEX.lock().await;
+                info!("[BT_HID] Advertising using profile {}", profile);
+                BLUETOOTH_STATUS_STATE
+                    .set(BluetoothStatus {
+                        connection: if peer.is_some() {
+                            BluetoothConnectionStatus::Advertising
+                        } else {
+                            BluetoothConnectionStatus::Pairing
+                        },
+                        profile,
+                        rssi: None,
+                    })
+                    .await;
+
+                let advertise_fut = async {
+                    let advertise_fut =
+                        advertise::<K>(sd, &adv_data, &scan_data, peer.as_ref(), bonder);
+
+                    if K::BLE_ADVERTISING_TIMEOUT_SECS == 0 {
+                        return Some(advertise_fut.await);
+                    }
+
+                    with_timeout(
+                        Duration::from_secs(K::BLE_ADVERTISING_TIMEOUT_SECS as u64),
+                        advertise_fut,
+                    )
+                    .await
+                    .ok()
+                };
+
+                match select3(
+                    advertise_fut,
+                    wait_for_profile_change(profile),
+                    OPEN_PAIRING_SIGNAL.wait(),
+                )
+                .await
+                {
+                    select::Either3::First(Some(Ok(connection))) => {
                         info!("[BT_HID] Connection established with host device");
                         BLUETOOTH_CONNECTED_STATE.set(true).await;
+                        BLUETOOTH_STATUS_STATE
+                            .update(|status| {
+                                status.connection = BluetoothConnectionStatus::Connected
+                            })
+                            .await;
+
+                        #[cfg(feature = "dongle")]
+                        DONGLE_SUBSCRIBED.lock(|subscribed| subscribed.set(false));
+
+                        #[cfg(feature = "usb")]
+                        USB_FALLBACK_STATE.set(false).await;
                         connection
                     }
-                    Err(error) => {
+                    select::Either3::First(Some(Err(error))) => {
                         warn!("[BT_HID] BLE advertising error: {}", Debug2Format(&error));
                         continue;
                     }
+                    select::Either3::First(None) => {
+                        info!("[BT_HID] Advertising paused, press a key to resume advertising");
+                        drop(lock);
+                        BLUETOOTH_STATUS_STATE
+                            .update(|status| status.connection = BluetoothConnectionStatus::Idle)
+                            .await;
+                        select3(
+                            wait_for_key_press(),
+                            wait_for_profile_change(profile),
+                            OPEN_PAIRING_SIGNAL.wait(),
+                        )
+                        .await;
+                        continue;
+                    }
+                    select::Either3::Second(()) | select::Either3::Third(()) => {
+                        // Restart advertising using the new profile, or open pairing
+                        continue;
+                    }
                 }
             };
 
@@ -766,35 +1801,148 @@ where
                     BatteryServiceEvent::BatteryLevelCccdWrite { notifications } => {
                         debug!("[BT_HID] Battery value CCCD updated: {}", notifications);
                     }
+                    BatteryServiceEvent::BatteryLevelStatusCccdWrite { notifications } => {
+                        debug!(
+                            "[BT_HID] Battery level status CCCD updated: {}",
+                            notifications
+                        );
+                    }
+                    BatteryServiceEvent::BatteryEnergyStatusCccdWrite { notifications } => {
+                        debug!(
+                            "[BT_HID] Battery energy status CCCD updated: {}",
+                            notifications
+                        );
+                    }
+                },
+                #[cfg(feature = "split-central")]
+                ServerEvent::PeripheralBas(bas_event) => match bas_event {
+                    PeripheralBatteryServiceEvent::BatteryLevelCccdWrite { notifications } => {
+                        debug!(
+                            "[BT_HID] Peripheral battery value CCCD updated: {}",
+                            notifications
+                        );
+                    }
+                },
+                ServerEvent::Dfu(dfu_event) => match dfu_event {
+                    DfuServiceEvent::ControlPointCccdWrite { notifications } => {
+                        debug!("[BT_HID] DFU control point CCCD updated: {}", notifications);
+                    }
+                    DfuServiceEvent::StartDfu => {
+                        #[cfg(feature = "ota")]
+                        {
+                            warn!("[BT_HID] DFU requested, jumping to bootloader in OTA DFU mode.");
+                            crate::hw::mcu::jump_to_ota_bootloader();
+                        }
+                    }
+                },
+                ServerEvent::Nus(nus_event) => match nus_event {
+                    NusServiceEvent::TxCccdWrite { notifications } => {
+                        debug!("[BT_HID] NUS TX CCCD updated: {}", notifications);
+                        #[cfg(feature = "nus-console")]
+                        NUS_CONSOLE_SUBSCRIBED_SIGNAL.signal(notifications);
+                    }
+                    NusServiceEvent::RxWrite(data) => {
+                        #[cfg(feature = "nus-console")]
+                        if crate::console::CONSOLE_INPUT_PIPE.try_write(&data).is_err() {
+                            warn!("[BT_HID] Console input buffer is full, dropping NUS input");
+                        }
+                        #[cfg(not(feature = "nus-console"))]
+                        let _ = data;
+                    }
+                },
+                ServerEvent::Dongle(dongle_event) => match dongle_event {
+                    DongleServiceEvent::MessageToDongleCccdWrite { notifications } => {
+                        debug!("[BT_HID] Dongle CCCD updated: {}", notifications);
+
+                        // Once a dongle subscribes, it receives our HID reports instead of the HID
+                        // service, and needs to know our battery level
+                        #[cfg(feature = "dongle")]
+                        {
+                            DONGLE_SUBSCRIBED.lock(|subscribed| subscribed.set(notifications));
+
+                            if let Some(status) = BATTERY_STATE.try_get().filter(|_| notifications)
+                            {
+                                let _ = server.dongle.message_to_dongle_notify(
+                                    &connection,
+                                    &MessageToDongle::BatteryLevel(status.level),
+                                );
+                            }
+                        }
+                    }
+                    #[cfg(feature = "dongle")]
+                    DongleServiceEvent::MessageToKeyboardWrite(message) => match message {
+                        MessageToKeyboard::LedIndicators(bits) => {
+                            // Only use LED reports from the dongle if it is the one receiving our
+                            // HID reports
+                            if matches!(
+                                CURRENT_OUTPUT_STATE.try_get(),
+                                Some(Some(HIDOutput::Bluetooth))
+                            ) {
+                                let leds = LedIndicators::from_bits_truncate(bits);
+                                debug!(
+                                    "[BT_HID] Received lock LED report from dongle: {:?}",
+                                    Debug2Format(&leds)
+                                );
+                                if !LED_INDICATORS_STATE.try_set(leds) {
+                                    warn!("[BT_HID] Could not update lock LED state");
+                                }
+                            }
+                        }
+                    },
                 },
                 ServerEvent::Dis(dis_event) => match dis_event {},
                 ServerEvent::Hids(hids_event) => match hids_event {
                     HIDServiceEvent::KeyboardReportCccdWrite { notifications } => {
                         debug!("[BT_HID] Keyboard report CCCD updated: {}", notifications);
                     }
+                    HIDServiceEvent::KeyboardLedReportWrite(report) => {
+                        // Only use LED reports from this host if it is the one receiving our HID reports
+                        if matches!(
+                            CURRENT_OUTPUT_STATE.try_get(),
+                            Some(Some(HIDOutput::Bluetooth))
+                        ) {
+                            let leds = LedIndicators::from_bits_truncate(report);
+                            debug!(
+                                "[BT_HID] Received lock LED report: {:?}",
+                                Debug2Format(&leds)
+                            );
+                            if !LED_INDICATORS_STATE.try_set(leds) {
+                                warn!("[BT_HID] Could not update lock LED state");
+                            }
+                        }
+                    }
                     HIDServiceEvent::ConsumerReportCccdWrite { notifications } => {
                         debug!("[BT_HID] Consumer report CCCD updated: {}", notifications);
                     }
-                    HIDServiceEvent::ViaReportCccdWrite { notifications } => {
-                        debug!("[BT_HID] Via report CCCD updated: {}", notifications);
+                    HIDServiceEvent::SystemControlReportCccdWrite { notifications } => {
+                        debug!(
+                            "[BT_HID] System control report CCCD updated: {}",
+                            notifications
+                        );
                     }
-                    HIDServiceEvent::ViaReportWrite(report) => {
-                        #[cfg(feature = "via")]
-                        match crate::via::VIA_REPORT_HID_RECEIVE_CHANNEL.try_send(report) {
+                    HIDServiceEvent::RawHIDReportCccdWrite { notifications } => {
+                        debug!("[BT_HID] Raw HID report CCCD updated: {}", notifications);
+                    }
+                    HIDServiceEvent::RawHIDReportWrite(report) => {
+                        #[cfg(feature = "raw-hid")]
+                        match crate::raw_hid::RAW_HID_REPORT_RECEIVE_CHANNEL.try_send(report) {
                             Ok(()) => {
-                                debug!("[BT_HID] Received Via report: {}", report);
+                                debug!("[BT_HID] Received raw HID report: {}", report);
                             }
                             Err(err) => {
                                 error!(
-                                    "[BT_HID] Could not consume Via report. data: {:?} error: {:?}",
+                                    "[BT_HID] Could not consume raw HID report. data: {:?} error: {:?}",
                                     Debug2Format(&report),
                                     Debug2Format(&err)
                                 );
                             }
                         }
 
-                        #[cfg(not(feature = "via"))]
-                        warn!("[BT_HID] Via is not enabled. Ignoring report: {}", report);
+                        #[cfg(not(feature = "raw-hid"))]
+                        warn!(
+                            "[BT_HID] Raw HID is not enabled. Ignoring report: {}",
+                            report
+                        );
                     }
                     HIDServiceEvent::HidControlWrite(val) => {
                         debug!("[BT_HID] Received HID control value: {=u8}", val);
@@ -805,7 +1953,16 @@ where
             let adc_fut = async {
                 loop {
                     BATTERY_LEVEL_LISTENER.wait().await;
-                    let pct = BATTERY_LEVEL_STATE.get().await;
+                    let status = BATTERY_STATE.get().await;
+                    let pct = status.level;
+                    let level_status = battery_level_status(&status);
+                    let energy_status = battery_energy_status(&status);
+
+                    // Update the stored values first, so that they can be read by hosts that
+                    // have not enabled notifications.
+                    let _ = server.bas.battery_level_set(&pct);
+                    let _ = server.bas.battery_level_status_set(&level_status);
+                    let _ = server.bas.battery_energy_status_set(&energy_status);
 
                     match server.bas.battery_level_notify(&connection, &pct) {
                         Ok(_) => {
@@ -822,138 +1979,320 @@ where
                             );
                         }
                     }
+
+                    // Most hosts only subscribe to the battery level, so failures here are expected
+                    if let Err(error) = server
+                        .bas
+                        .battery_level_status_notify(&connection, &level_status)
+                    {
+                        debug!(
+                            "[BT_HID] Could not notify connection of new battery level status: {}",
+                            Debug2Format(&error)
+                        );
+                    }
+
+                    if let Err(error) = server
+                        .bas
+                        .battery_energy_status_notify(&connection, &energy_status)
+                    {
+                        debug!(
+                            "[BT_HID] Could not notify connection of new battery energy status: {}",
+                            Debug2Format(&error)
+                        );
+                    }
+
+                    #[cfg(feature = "dongle")]
+                    if DONGLE_SUBSCRIBED.lock(Cell::get) {
+                        if let Err(error) = server.dongle.message_to_dongle_notify(
+                            &connection,
+                            &MessageToDongle::BatteryLevel(pct),
+                        ) {
+                            error!(
+                                "[BT_HID] Could not notify dongle of new battery level ({=u8}): {}",
+                                pct,
+                                Debug2Format(&error)
+                            );
+                        }
+                    }
                 }
             };
 
+            #[cfg(feature = "split-central")]
+            let adc_fut = {
+                let peripheral_adc_fut = async {
+                    loop {
+                        crate::bluetooth::PERIPHERAL_BATTERY_LEVEL_LISTENER
+                            .wait()
+                            .await;
+                        let pct = crate::split::central::PERIPHERAL_BATTERY_LEVEL_STATE
+                            .get()
+                            .await;
+
+                        let _ = server.peripheral_bas.battery_level_set(&pct);
+
+                        match server
+                            .peripheral_bas
+                            .battery_level_notify(&connection, &pct)
+                        {
+                            Ok(_) => {
+                                debug!(
+                                    "[BT_HID] Notified connection of new peripheral battery level: {=u8}",
+                                    pct
+                                );
+                            }
+                            Err(error) => {
+                                error!(
+                                    "[BT_HID] Could not notify connection of new peripheral battery level ({=u8}): {}",
+                                    pct,
+                                    Debug2Format(&error)
+                                );
+                            }
+                        }
+                    }
+                };
+
+                select(adc_fut, peripheral_adc_fut)
+            };
+
             let hid_fut = async {
                 // Discard any reports that haven't been processed due to lack of a connection
                 while KEYBOARD_REPORT_HID_SEND_CHANNEL.try_receive().is_ok() {}
                 while CONSUMER_REPORT_HID_SEND_CHANNEL.try_receive().is_ok() {}
+                while SYSTEM_CONTROL_REPORT_HID_SEND_CHANNEL.try_receive().is_ok() {}
 
-                #[cfg(feature = "via")]
-                while crate::via::VIA_REPORT_HID_SEND_CHANNEL
+                #[cfg(feature = "raw-hid")]
+                while crate::raw_hid::RAW_HID_REPORT_SEND_CHANNEL
                     .try_receive()
                     .is_ok()
                 {}
 
+                // Last reports sent to the host, used to skip sending duplicate reports
+                let mut last_keyboard_report = None;
+                let mut last_consumer_report = None;
+                let mut last_system_control_report = None;
+
                 loop {
-                    if matches!(CURRENT_OUTPUT_STATE.get().await, Some(HIDOutput::Bluetooth)) {
-                        #[cfg(feature = "via")]
-                        match select4(
-                            CURRENT_OUTPUT_STATE_LISTENER.wait(),
-                            KEYBOARD_REPORT_HID_SEND_CHANNEL.receive(),
-                            CONSUMER_REPORT_HID_SEND_CHANNEL.receive(),
-                            crate::via::VIA_REPORT_HID_SEND_CHANNEL.receive(),
-                        )
-                        .await
+                    if !matches!(CURRENT_OUTPUT_STATE.get().await, Some(HIDOutput::Bluetooth)) {
+                        CURRENT_OUTPUT_STATE_LISTENER.wait().await;
+                        last_keyboard_report = None;
+                        last_consumer_report = None;
+                        last_system_control_report = None;
+                        continue;
+                    }
+
+                    let report =
+                        match select(CURRENT_OUTPUT_STATE_LISTENER.wait(), receive_hid_report())
+                            .await
                         {
-                            select::Either4::First(()) => {}
-                            select::Either4::Second(report) => {
-                                info!(
-                                    "[BT_HID] Writing NKRO HID report to bluetooth: {:?}",
+                            select::Either::First(()) => {
+                                // The output may have changed, so the next reports must be sent
+                                // even if they are identical to the last ones
+                                last_keyboard_report = None;
+                                last_consumer_report = None;
+                                last_system_control_report = None;
+                                continue;
+                            }
+                            select::Either::Second(report) => report,
+                        };
+
+                    match report {
+                        HIDReport::Keyboard(report) => {
+                            let bytes = report.pack().unwrap();
+                            if last_keyboard_report == Some(bytes) {
+                                debug!(
+                                    "[BT_HID] Skipping duplicate NKRO HID report: {:?}",
                                     Debug2Format(&report)
                                 );
+                                continue;
+                            }
+
+                            info!(
+                                "[BT_HID] Writing NKRO HID report to bluetooth: {:?}",
+                                Debug2Format(&report)
+                            );
 
-                                if let Err(err) =
-                                    server.hids.keyboard_report_notify(&connection, report)
-                                {
+                            match notify_hid_report(
+                                &server,
+                                &connection,
+                                HIDReport::Keyboard(report),
+                            ) {
+                                Ok(()) => last_keyboard_report = Some(bytes),
+                                Err(err) => {
                                     error!(
                                         "[BT_HID] Couldn't write NKRO HID report: {:?}",
                                         Debug2Format(&err)
                                     );
-                                };
-                            }
-                            select::Either4::Third(report) => {
-                                info!(
-                                    "[BT_HID] Writing consumer HID report to bluetooth: {:?}",
+                                }
+                            };
+                        }
+                        HIDReport::Consumer(report) => {
+                            let bytes = report.pack().unwrap();
+                            if last_consumer_report == Some(bytes) {
+                                debug!(
+                                    "[BT_HID] Skipping duplicate consumer HID report: {:?}",
                                     Debug2Format(&report)
                                 );
+                                continue;
+                            }
+
+                            info!(
+                                "[BT_HID] Writing consumer HID report to bluetooth: {:?}",
+                                Debug2Format(&report)
+                            );
 
-                                if let Err(err) =
-                                    server.hids.consumer_report_notify(&connection, report)
-                                {
+                            match notify_hid_report(
+                                &server,
+                                &connection,
+                                HIDReport::Consumer(report),
+                            ) {
+                                Ok(()) => last_consumer_report = Some(bytes),
+                                Err(err) => {
                                     error!(
                                         "[BT_HID] Couldn't write consumer HID report: {:?}",
                                         Debug2Format(&err)
                                     );
-                                };
-                            }
-                            select::Either4::Fourth(report) => {
-                                info!(
-                                    "[BT_HID] Writing Via HID report to bluetooth: {:?}",
+                                }
+                            };
+                        }
+                        HIDReport::SystemControl(report) => {
+                            if last_system_control_report == Some(report) {
+                                debug!(
+                                    "[BT_HID] Skipping duplicate system control HID report: {:?}",
                                     Debug2Format(&report)
                                 );
-
-                                if let Err(err) = server.hids.via_report_notify(&connection, report)
-                                {
-                                    error!(
-                                        "[BT_HID] Couldn't write Via HID report: {:?}",
-                                        Debug2Format(&err)
-                                    );
-                                };
+                                continue;
                             }
-                        };
 
-                        #[cfg(not(feature = "via"))]
-                        match select3(
-                            CURRENT_OUTPUT_STATE_LISTENER.wait(),
-                            KEYBOARD_REPORT_HID_SEND_CHANNEL.receive(),
-                            CONSUMER_REPORT_HID_SEND_CHANNEL.receive(),
-                        )
-                        .await
-                        {
-                            select::Either3::First(()) => {}
-                            select::Either3::Second(report) => {
-                                info!(
-                                    "[BT_HID] Writing NKRO HID report to bluetooth: {:?}",
-                                    Debug2Format(&report)
-                                );
+                            info!(
+                                "[BT_HID] Writing system control HID report to bluetooth: {:?}",
+                                Debug2Format(&report)
+                            );
 
-                                if let Err(err) =
-                                    server.hids.keyboard_report_notify(&connection, report)
-                                {
+                            match notify_hid_report(
+                                &server,
+                                &connection,
+                                HIDReport::SystemControl(report),
+                            ) {
+                                Ok(()) => last_system_control_report = Some(report),
+                                Err(err) => {
                                     error!(
-                                        "[BT_HID] Couldn't write NKRO HID report: {:?}",
+                                        "[BT_HID] Couldn't write system control HID report: {:?}",
                                         Debug2Format(&err)
                                     );
-                                };
-                            }
-                            select::Either3::Third(report) => {
-                                info!(
-                                    "[BT_HID] Writing consumer HID report to bluetooth: {:?}",
-                                    Debug2Format(&report)
-                                );
+                                }
+                            };
+                        }
+                        #[cfg(feature = "raw-hid")]
+                        HIDReport::RawHID(report) => {
+                            info!(
+                                "[BT_HID] Writing raw HID report to bluetooth: {:?}",
+                                Debug2Format(&report)
+                            );
 
-                                if let Err(err) =
-                                    server.hids.consumer_report_notify(&connection, report)
-                                {
-                                    error!(
-                                        "[BT_HID] Couldn't write consumer HID report: {:?}",
-                                        Debug2Format(&err)
-                                    );
-                                };
-                            }
-                        };
-                    } else {
-                        CURRENT_OUTPUT_STATE_LISTENER.wait().await;
+                            if let Err(err) =
+                                notify_hid_report(&server, &connection, HIDReport::RawHID(report))
+                            {
+                                error!(
+                                    "[BT_HID] Couldn't write raw HID report: {:?}",
+                                    Debug2Format(&err)
+                                );
+                            };
+                        }
                     }
                 }
             };
 
-            match select3(conn_fut, adc_fut, hid_fut).await {
-                select::Either3::First(error) => {
+            let conn_params_fut = async {
+                loop {
+                    request_connection_parameters::<K>(&connection).await;
+                    LATENCY_MODE_STATE_LISTENER.wait().await;
+                }
+            };
+
+            let tx_power_fut = async {
+                loop {
+                    TX_POWER_STATE_LISTENER.wait().await;
+                    set_connection_tx_power(&connection).await;
+                }
+            };
+
+            let rssi_fut = async {
+                connection.start_rssi();
+
+                loop {
+                    Timer::after(Duration::from_secs(RSSI_UPDATE_INTERVAL_SECS)).await;
+                    let rssi = connection.rssi();
+                    BLUETOOTH_STATUS_STATE
+                        .update(|status| status.rssi = rssi)
+                        .await;
+                }
+            };
+
+            let nus_fut = async {
+                #[cfg(feature = "nus-console")]
+                nus_console_output(&connection, &server).await;
+                #[cfg(not(feature = "nus-console"))]
+                core::future::pending::<()>().await;
+            };
+
+            match select4(
+                conn_fut,
+                select4(
+                    adc_fut,
+                    conn_params_fut,
+                    select(tx_power_fut, rssi_fut),
+                    nus_fut,
+                ),
+                hid_fut,
+                select(wait_for_profile_change(profile), OPEN_PAIRING_SIGNAL.wait()),
+            )
+            .await
+            {
+                select::Either4::First(error) => {
                     warn!(
                         "[BT_HID] Connection has been lost: {}",
                         Debug2Format(&error)
                     );
                     BLUETOOTH_CONNECTED_STATE.set(false).await;
+                    BLUETOOTH_STATUS_STATE
+                        .update(|status| {
+                            status.connection = BluetoothConnectionStatus::Idle;
+                            status.rssi = None;
+                        })
+                        .await;
+
+                    // Pairing can't continue without a connection
+                    clear_passkey_prompt();
+                    OPEN_PAIRING.lock(|open_pairing| open_pairing.set(false));
+
+                    #[cfg(feature = "usb")]
+                    fall_back_to_usb::<K>().await;
                 }
-                select::Either3::Second(_) => {
-                    error!("[BT_HID] Battery task failed. This should not happen.");
+                select::Either4::Second(_) => {
+                    error!(
+                        "[BT_HID] Battery, connection parameter, transmit power, RSSI or console task failed. This should not happen."
+                    );
                 }
-                select::Either3::Third(_) => {
+                select::Either4::Third(_) => {
                     error!("[BT_HID] HID task failed. This should not happen.");
                 }
+                select::Either4::Fourth(_) => {
+                    info!("[BT_HID] Disconnecting to change profiles or open pairing");
+                    if let Err(error) = connection.disconnect() {
+                        warn!(
+                            "[BT_HID] Could not disconnect from host device: {}",
+                            Debug2Format(&error)
+                        );
+                    }
+                    BLUETOOTH_CONNECTED_STATE.set(false).await;
+                    BLUETOOTH_STATUS_STATE
+                        .update(|status| {
+                            status.connection = BluetoothConnectionStatus::Idle;
+                            status.rssi = None;
+                        })
+                        .await;
+                    clear_passkey_prompt();
+                }
             };
         }
     };
@@ -964,20 +2303,91 @@ where
             match command {
                 #[cfg(feature = "usb")]
                 BluetoothCommand::ToggleOutput => {
-                    OUTPUT_MODE_STATE
-                        .set(match OUTPUT_MODE_STATE.get().await {
-                            OutputMode::Usb => OutputMode::Bluetooth,
-                            OutputMode::Bluetooth => OutputMode::Usb,
-                        })
-                        .await;
+                    toggle_output_mode().await;
                 }
                 #[cfg(feature = "usb")]
                 BluetoothCommand::OutputUSB => {
-                    OUTPUT_MODE_STATE.set(OutputMode::Usb).await;
+                    set_output_mode(OutputMode::Usb).await;
                 }
                 #[cfg(feature = "usb")]
                 BluetoothCommand::OutputBluetooth => {
-                    OUTPUT_MODE_STATE.set(OutputMode::Bluetooth).await;
+                    set_output_mode(OutputMode::Bluetooth).await;
+                }
+                #[cfg(feature = "usb")]
+                BluetoothCommand::OutputAuto => {
+                    set_output_mode(OutputMode::Auto).await;
+                }
+                #[cfg(feature = "esb")]
+                BluetoothCommand::OutputEsb => {
+                    set_output_mode(OutputMode::Esb).await;
+                }
+                BluetoothCommand::SelectProfile(profile) => {
+                    if profile as usize >= BLUETOOTH_PROFILE_COUNT {
+                        warn!("[BT_HID] Bluetooth profile {} does not exist", profile);
+                        continue;
+                    }
+
+                    update_profiles(|profiles| profiles.active = profile).await;
+                }
+                BluetoothCommand::NextProfile => {
+                    update_profiles(|profiles| {
+                        profiles.active = (profiles.active + 1) % BLUETOOTH_PROFILE_COUNT as u8
+                    })
+                    .await;
+                }
+                BluetoothCommand::PreviousProfile => {
+                    update_profiles(|profiles| {
+                        profiles.active = (profiles.active + BLUETOOTH_PROFILE_COUNT as u8 - 1)
+                            % BLUETOOTH_PROFILE_COUNT as u8
+                    })
+                    .await;
+                }
+                BluetoothCommand::ClearProfile => {
+                    update_profiles(|profiles| *profiles.active_peer_mut() = None).await;
+                }
+                BluetoothCommand::UnpairProfile(profile) => {
+                    if profile as usize >= BLUETOOTH_PROFILE_COUNT {
+                        warn!("[BT_HID] Bluetooth profile {} does not exist", profile);
+                        continue;
+                    }
+
+                    update_profiles(|profiles| profiles.peers[profile as usize] = None).await;
+                }
+                BluetoothCommand::UnpairAll => {
+                    update_profiles(|profiles| profiles.peers.fill(NO_PEER)).await;
+                }
+                BluetoothCommand::LowLatencyMode => {
+                    LATENCY_MODE_STATE.set(LatencyMode::LowLatency).await;
+                }
+                BluetoothCommand::PowerSaveMode => {
+                    LATENCY_MODE_STATE.set(LatencyMode::PowerSave).await;
+                }
+                BluetoothCommand::OpenPairing => {
+                    info!(
+                        "[BT_HID] Opening pairing for profile {}",
+                        BLUETOOTH_PROFILES_STATE.get().await.active
+                    );
+                    OPEN_PAIRING.lock(|open_pairing| open_pairing.set(true));
+                    OPEN_PAIRING_SIGNAL.signal(());
+                }
+                BluetoothCommand::SetTxPower(tx_power) => {
+                    set_tx_power(tx_power).await;
+                }
+                BluetoothCommand::IncreaseTxPower => {
+                    set_tx_power(TX_POWER_STATE.get().await.increase()).await;
+                }
+                BluetoothCommand::DecreaseTxPower => {
+                    set_tx_power(TX_POWER_STATE.get().await.decrease()).await;
+                }
+                BluetoothCommand::ToggleLatencyMode => {
+                    LATENCY_MODE_STATE
+                        .update(|mode| {
+                            **mode = match **mode {
+                                LatencyMode::LowLatency => LatencyMode::PowerSave,
+                                LatencyMode::PowerSave => LatencyMode::LowLatency,
+                            }
+                        })
+                        .await;
                 }
             }
         }
@@ -985,3 +2395,40 @@ where
 
     join::join(command_fut, connection_fut).await;
 }
+
+#[cfg(feature = "storage")]
+pub mod storage {
+    use embassy_sync::signal::Signal;
+
+    use crate::hw::mcu::RawMutex;
+    use crate::storage::{FlashStorage, StorageDevice};
+
+    use super::BLUETOOTH_PROFILES_STATE;
+
+    pub(super) static BLUETOOTH_PROFILES_STATE_STORAGE_LISTENER: Signal<RawMutex, ()> =
+        Signal::new();
+
+    /// Signal used to save the bluetooth profiles immediately, instead of waiting for the save
+    /// policy defined in [`StorageDevice`].
+    pub(super) static BLUETOOTH_PROFILES_SAVE_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+    /// Task that restores the bluetooth profiles and their bonds, and saves any changes to them,
+    /// so that host devices do not need to be paired again after a reboot.
+    #[rumcake_macros::task]
+    pub async fn bluetooth_profiles_storage_task<K: StorageDevice, F: FlashStorage>(
+        _k: K,
+        database: &crate::storage::StorageService<'static, F>,
+    ) where
+        [(); F::ERASE_SIZE]:,
+        [(); F::REGION_SIZE]:,
+    {
+        database
+            .persist_state::<K, _>(
+                crate::storage::StorageKey::BluetoothProfiles,
+                &BLUETOOTH_PROFILES_STATE,
+                &BLUETOOTH_PROFILES_STATE_STORAGE_LISTENER,
+                &BLUETOOTH_PROFILES_SAVE_SIGNAL,
+            )
+            .await
+    }
+}
@@ -0,0 +1,403 @@
+//! NFC out-of-band (OOB) pairing for nRF5x MCUs.
+//!
+//! The NFCT peripheral is used to emulate a read-only NFC Forum Type 2 Tag, containing a Bluetooth
+//! LE OOB record with the keyboard's address and a random temporary key (TK). When a host device
+//! (e.g. a phone) reads the tag, it can pair with the keyboard using LE legacy pairing with OOB
+//! data. Since the TK is only shared over NFC, this protects against MITM attacks without having
+//! to type a passkey. A new TK is generated after each pairing attempt.
+//!
+//! The softdevice doesn't use the NFCT peripheral, so it is controlled directly. Readers expect a
+//! response within a few milliseconds, so the peripheral is polled frequently while a reader is
+//! nearby.
+
+#[cfg(feature = "nfc-pins-as-gpio")]
+compile_error!("NFC pairing requires the NFC antenna pins. Please disable rumcake's `nfc-pins-as-gpio` feature.");
+
+use core::cell::Cell;
+
+use defmt::{debug, error, info, warn, Debug2Format};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use heapless::Vec;
+use nrf_softdevice::ble::{get_address, Address, AddressType, OutOfBandReply};
+use nrf_softdevice::{raw, Softdevice};
+
+use crate::hw::mcu::{BlockingMutex, RawMutex};
+
+use super::BluetoothKeyboard;
+
+const NFCT: usize = 0x4000_5000;
+const NFCT_TASKS_ACTIVATE: usize = 0x000;
+const NFCT_TASKS_SENSE: usize = 0x008;
+const NFCT_TASKS_STARTTX: usize = 0x00C;
+const NFCT_TASKS_ENABLERXDATA: usize = 0x01C;
+const NFCT_TASKS_GOSLEEP: usize = 0x028;
+const NFCT_EVENTS_FIELDDETECTED: usize = 0x104;
+const NFCT_EVENTS_FIELDLOST: usize = 0x108;
+const NFCT_EVENTS_TXFRAMEEND: usize = 0x110;
+const NFCT_EVENTS_RXFRAMEEND: usize = 0x118;
+const NFCT_EVENTS_RXERROR: usize = 0x128;
+const NFCT_EVENTS_SELECTED: usize = 0x14C;
+const NFCT_SHORTS: usize = 0x200;
+const NFCT_FRAMESTATUS_RX: usize = 0x40C;
+const NFCT_FRAMEDELAYMODE: usize = 0x50C;
+const NFCT_PACKETPTR: usize = 0x510;
+const NFCT_MAXLEN: usize = 0x514;
+const NFCT_TXD_FRAMECONFIG: usize = 0x518;
+const NFCT_TXD_AMOUNT: usize = 0x51C;
+const NFCT_RXD_FRAMECONFIG: usize = 0x520;
+const NFCT_RXD_AMOUNT: usize = 0x524;
+const NFCT_NFCID1_LAST: usize = 0x590;
+const NFCT_NFCID1_2ND_LAST: usize = 0x594;
+const NFCT_SENSRES: usize = 0x5A0;
+const NFCT_SELRES: usize = 0x5A4;
+
+const NFCT_SHORTS_FIELDLOST_SENSE: u32 = 1 << 1;
+const NFCT_SHORTS_TXFRAMEEND_ENABLERXDATA: u32 = 1 << 5;
+
+/// Frame configuration with parity, start of frame, and CRC.
+const NFCT_FRAMECONFIG_STANDARD: u32 = 0b1_0101;
+/// Frame configuration for 4-bit ACK/NACK frames, which only have a start of frame.
+const NFCT_FRAMECONFIG_SHORT: u32 = 0b0_0100;
+
+/// ATQA of a Type 2 Tag with a double size (7 byte) NFCID1, using bit frame anticollision.
+const NFCT_SENSRES_T2T: u32 = 0x0044;
+
+/// Address of the `DEVICEID` registers in the FICR.
+const FICR_DEVICEID: usize = 0x1000_0060;
+
+/// Type 2 Tag READ command. Reads 4 blocks (16 bytes), starting at the given block.
+const T2T_READ: u8 = 0x30;
+/// ISO/IEC 14443-3 HLTA command. Puts the tag to sleep until the reader wakes it up again.
+const T2T_HLTA: u8 = 0x50;
+/// 4-bit NACK sent in response to commands that the tag doesn't support.
+const T2T_NACK: u8 = 0x0;
+
+/// NFC Forum IC manufacturer code for Nordic Semiconductor, used as the first byte of the UID.
+const NFC_MANUFACTURER_ID: u8 = 0x5F;
+
+/// Size of the tag's header (UID, lock bytes and capability container), in bytes.
+const TAG_HEADER_SIZE: usize = 16;
+
+/// Size of the tag's data area, in bytes. This must be a multiple of 8.
+const TAG_DATA_SIZE: usize = 128;
+
+/// Total size of the tag's memory.
+const TAG_SIZE: usize = TAG_HEADER_SIZE + TAG_DATA_SIZE;
+
+/// Maximum length of the keyboard's name in the OOB record. Longer names are shortened.
+const OOB_NAME_MAX_LEN: usize = 32;
+
+/// MIME type of a Bluetooth LE OOB record.
+const OOB_RECORD_TYPE: &[u8] = b"application/vnd.bluetooth.le.oob";
+
+/// How often the NFCT peripheral is polled while a reader is nearby.
+const ACTIVE_POLL_INTERVAL: Duration = Duration::from_micros(500);
+
+/// How often to check for a reader's field when no reader is nearby.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Temporary key that is currently stored in the NFC tag.
+static OOB_TK: BlockingMutex<Cell<Option<[u8; 16]>>> = BlockingMutex::new(Cell::new(None));
+
+/// Signal used to generate a new temporary key after the current one has been used.
+static OOB_TK_USED: Signal<RawMutex, ()> = Signal::new();
+
+/// Whether the NFC tag contains a temporary key that host devices can pair with.
+pub(crate) fn oob_available() -> bool {
+    OOB_TK.lock(Cell::get).is_some()
+}
+
+/// Reply to a pairing request with the temporary key from the NFC tag. The key is then replaced,
+/// so that it can only be used once.
+pub(crate) fn reply_oob(reply: OutOfBandReply) {
+    let tk = OOB_TK.lock(Cell::take);
+
+    if let Err(error) = reply.reply(tk.as_ref()) {
+        error!(
+            "[BT_NFC] Could not reply with OOB data: {}",
+            Debug2Format(&error)
+        );
+    }
+
+    OOB_TK_USED.signal(());
+}
+
+/// Write to an NFCT register.
+unsafe fn write_reg(offset: usize, value: u32) {
+    core::ptr::write_volatile((NFCT + offset) as *mut u32, value);
+}
+
+/// Read an NFCT register.
+unsafe fn read_reg(offset: usize) -> u32 {
+    core::ptr::read_volatile((NFCT + offset) as *const u32)
+}
+
+/// Check if an NFCT event has occurred, and clear it.
+fn take_event(offset: usize) -> bool {
+    unsafe {
+        if read_reg(offset) == 0 {
+            return false;
+        }
+
+        write_reg(offset, 0);
+        true
+    }
+}
+
+/// Get the UID of the tag. This is derived from the MCU's unique device ID, so that it stays the
+/// same across reboots.
+fn tag_uid() -> [u8; 7] {
+    // The FICR is read-only, so it is safe to read the DEVICEID registers directly
+    let device_id = unsafe { core::ptr::read_volatile(FICR_DEVICEID as *const [u8; 8]) };
+
+    let mut uid = [0; 7];
+    uid[0] = NFC_MANUFACTURER_ID;
+    uid[1..].copy_from_slice(&device_id[..6]);
+    uid
+}
+
+/// Generate a random temporary key using the softdevice's random number generator.
+async fn generate_tk(sd: &Softdevice) -> [u8; 16] {
+    let mut tk = [0; 16];
+
+    // The random number pool may need some time to fill up
+    while let Err(error) = nrf_softdevice::random_bytes(sd, &mut tk) {
+        debug!(
+            "[BT_NFC] Waiting for random numbers: {}",
+            Debug2Format(&error)
+        );
+        Timer::after(Duration::from_millis(10)).await;
+    }
+
+    tk
+}
+
+/// Build the payload of the Bluetooth LE OOB record, which uses the same format as advertising
+/// data.
+fn oob_payload<K: BluetoothKeyboard>(address: Address, tk: &[u8; 16]) -> Vec<u8, 72> {
+    let name = K::PRODUCT.as_bytes();
+    let name_len = name.len().min(OOB_NAME_MAX_LEN);
+    let name_type = if name_len < name.len() {
+        0x08 // Shortened name
+    } else {
+        0x09 // Complete name
+    };
+    let address_type = match address.address_type() {
+        AddressType::Public => 0x00,
+        _ => 0x01,
+    };
+
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&[0x08, 0x1B]).unwrap(); // LE bluetooth device address
+    payload.extend_from_slice(&address.bytes()).unwrap();
+    payload.push(address_type).unwrap();
+    payload.extend_from_slice(&[0x02, 0x1C, 0x00]).unwrap(); // LE role: Only peripheral
+    payload.extend_from_slice(&[0x11, 0x10]).unwrap(); // Security manager TK value
+    payload.extend_from_slice(tk).unwrap();
+    payload
+        .extend_from_slice(&[0x03, 0x19, 0xC1, 0x03])
+        .unwrap(); // Appearance: Keyboard
+    payload.extend_from_slice(&[0x02, 0x01, 0x06]).unwrap(); // Flags: LE only, general discoverable
+    payload.push(name_len as u8 + 1).unwrap();
+    payload.push(name_type).unwrap();
+    payload.extend_from_slice(&name[..name_len]).unwrap();
+
+    payload
+}
+
+/// Build the memory of the Type 2 Tag, containing an NDEF message with a single Bluetooth LE OOB
+/// record.
+fn tag_memory<K: BluetoothKeyboard>(
+    uid: &[u8; 7],
+    address: Address,
+    tk: &[u8; 16],
+) -> [u8; TAG_SIZE] {
+    let payload = oob_payload::<K>(address, tk);
+    let message_len = 3 + OOB_RECORD_TYPE.len() + payload.len();
+
+    let mut memory = [0; TAG_SIZE];
+
+    // UID, with its check bytes
+    memory[0..3].copy_from_slice(&uid[0..3]);
+    memory[3] = 0x88 ^ uid[0] ^ uid[1] ^ uid[2];
+    memory[4..8].copy_from_slice(&uid[3..7]);
+    memory[8] = uid[3] ^ uid[4] ^ uid[5] ^ uid[6];
+
+    // Static lock bytes, locking the tag
+    memory[10] = 0xFF;
+    memory[11] = 0xFF;
+
+    // Capability container: NDEF magic number, version 1.0, data area size, and read-only access
+    memory[12..16].copy_from_slice(&[0xE1, 0x10, (TAG_DATA_SIZE / 8) as u8, 0x0F]);
+
+    let mut data: Vec<u8, TAG_DATA_SIZE> = Vec::new();
+    data.extend_from_slice(&[0x03, message_len as u8]).unwrap(); // NDEF message TLV
+                                                                 // Record header: message begin, message end, short record, and a MIME media type
+    data.push(0xD2).unwrap();
+    data.push(OOB_RECORD_TYPE.len() as u8).unwrap();
+    data.push(payload.len() as u8).unwrap();
+    data.extend_from_slice(OOB_RECORD_TYPE).unwrap();
+    data.extend_from_slice(&payload).unwrap();
+    data.push(0xFE).unwrap(); // Terminator TLV
+
+    memory[TAG_HEADER_SIZE..(TAG_HEADER_SIZE + data.len())].copy_from_slice(&data);
+
+    memory
+}
+
+/// Configure the NFCT peripheral to emulate a Type 2 Tag with the given UID.
+fn setup_nfct(uid: &[u8; 7]) {
+    unsafe {
+        write_reg(
+            NFCT_NFCID1_2ND_LAST,
+            u32::from_be_bytes([0, uid[0], uid[1], uid[2]]),
+        );
+        write_reg(
+            NFCT_NFCID1_LAST,
+            u32::from_be_bytes([uid[3], uid[4], uid[5], uid[6]]),
+        );
+        write_reg(NFCT_SENSRES, NFCT_SENSRES_T2T);
+        write_reg(NFCT_SELRES, 0x00);
+
+        // Responses are sent as soon as they are ready
+        write_reg(NFCT_FRAMEDELAYMODE, 0);
+        write_reg(NFCT_RXD_FRAMECONFIG, NFCT_FRAMECONFIG_STANDARD);
+        write_reg(
+            NFCT_SHORTS,
+            NFCT_SHORTS_FIELDLOST_SENSE | NFCT_SHORTS_TXFRAMEEND_ENABLERXDATA,
+        );
+    }
+}
+
+/// Start receiving a frame from the reader into `frame`.
+fn start_rx(frame: &mut [u8; 16]) {
+    unsafe {
+        write_reg(NFCT_PACKETPTR, frame.as_mut_ptr() as u32);
+        write_reg(NFCT_MAXLEN, frame.len() as u32);
+        write_reg(NFCT_TASKS_ENABLERXDATA, 1);
+    }
+}
+
+/// Send the first `bits` bits of `frame` to the reader. Frames that aren't a whole number of bytes
+/// are sent without parity or a CRC.
+fn start_tx(frame: &[u8; 16], bits: usize) {
+    let config = if bits % 8 == 0 {
+        NFCT_FRAMECONFIG_STANDARD
+    } else {
+        NFCT_FRAMECONFIG_SHORT
+    };
+
+    unsafe {
+        write_reg(NFCT_PACKETPTR, frame.as_ptr() as u32);
+        write_reg(NFCT_TXD_FRAMECONFIG, config);
+        write_reg(NFCT_TXD_AMOUNT, bits as u32);
+        write_reg(NFCT_TASKS_STARTTX, 1);
+    }
+}
+
+/// Request the high frequency crystal oscillator from the softdevice, which is needed by the NFCT
+/// peripheral, and wait for it to start.
+async fn request_hfclk() {
+    unsafe { raw::sd_clock_hfclk_request() };
+
+    loop {
+        let mut running = 0;
+        unsafe { raw::sd_clock_hfclk_is_running(&mut running) };
+        if running != 0 {
+            break;
+        }
+
+        Timer::after(Duration::from_micros(100)).await;
+    }
+}
+
+/// Respond to commands from the reader until its field is lost.
+async fn serve_tag(memory: &[u8; TAG_SIZE]) {
+    let mut frame = [0; 16];
+
+    loop {
+        if take_event(NFCT_EVENTS_FIELDLOST) {
+            return;
+        }
+
+        if take_event(NFCT_EVENTS_SELECTED) {
+            debug!("[BT_NFC] Tag selected by reader");
+
+            // Ignore anything that was received before the tag was selected
+            take_event(NFCT_EVENTS_RXFRAMEEND);
+            take_event(NFCT_EVENTS_RXERROR);
+            start_rx(&mut frame);
+        }
+
+        if take_event(NFCT_EVENTS_RXERROR) {
+            unsafe { write_reg(NFCT_FRAMESTATUS_RX, read_reg(NFCT_FRAMESTATUS_RX)) };
+        }
+
+        if take_event(NFCT_EVENTS_RXFRAMEEND) {
+            let len = unsafe { (read_reg(NFCT_RXD_AMOUNT) >> 3) & 0x1FF } as usize;
+
+            match frame[..len.min(frame.len())] {
+                [T2T_READ, block, ..] => {
+                    let start = block as usize * 4;
+                    for (i, byte) in frame.iter_mut().enumerate() {
+                        // Reads past the end of the tag wrap around to the start
+                        *byte = memory[(start + i) % TAG_SIZE];
+                    }
+                    start_tx(&frame, frame.len() * 8);
+                }
+                [T2T_HLTA, ..] => {
+                    debug!("[BT_NFC] Tag halted by reader");
+                    unsafe { write_reg(NFCT_TASKS_GOSLEEP, 1) };
+                }
+                _ => {
+                    frame[0] = T2T_NACK;
+                    start_tx(&frame, 4);
+                }
+            }
+        }
+
+        // The TXFRAMEEND_ENABLERXDATA short starts receiving the next command
+        take_event(NFCT_EVENTS_TXFRAMEEND);
+
+        Timer::after(ACTIVE_POLL_INTERVAL).await;
+    }
+}
+
+#[rumcake_macros::task]
+pub async fn nfc_pairing_task<K: BluetoothKeyboard>(_k: K, sd: &'static Softdevice) {
+    let address = get_address(sd);
+    if address.address_type() == AddressType::RandomPrivateResolvable {
+        warn!("[BT_NFC] NFC pairing requires a fixed bluetooth address, host devices may not be able to connect");
+    }
+
+    let uid = tag_uid();
+    setup_nfct(&uid);
+
+    loop {
+        let tk = generate_tk(sd).await;
+        OOB_TK.lock(|oob_tk| oob_tk.set(Some(tk)));
+        OOB_TK_USED.reset();
+        let memory = tag_memory::<K>(&uid, address, &tk);
+
+        info!("[BT_NFC] NFC tag ready for pairing");
+
+        unsafe { write_reg(NFCT_TASKS_SENSE, 1) };
+
+        while !OOB_TK_USED.signaled() {
+            if !take_event(NFCT_EVENTS_FIELDDETECTED) {
+                Timer::after(IDLE_POLL_INTERVAL).await;
+                continue;
+            }
+
+            info!("[BT_NFC] NFC reader detected");
+
+            request_hfclk().await;
+            unsafe { write_reg(NFCT_TASKS_ACTIVATE, 1) };
+            serve_tag(&memory).await;
+            unsafe { raw::sd_clock_hfclk_release() };
+
+            info!("[BT_NFC] NFC reader removed");
+        }
+    }
+}
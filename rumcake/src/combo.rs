@@ -0,0 +1,347 @@
+//! Combos, which allow multiple keys that are pressed at the same time to perform a different
+//! action.
+//!
+//! Combos can be defined in your layout by implementing [`KeyboardLayout::COMBOS`]. If you use
+//! Vial, combos can also be created at runtime using the Vial app.
+//!
+//! When a key that belongs to a combo is pressed, it is held back from the layout until either:
+//! - all of the keys of a combo have been pressed, in which case the combo's action is pressed, or
+//! - [`KeyboardLayout::COMBO_TERM`] has passed, or a key that can't complete a combo is pressed or
+//!   released, in which case the held back keys are sent to the layout as normal.
+//!
+//! The combo's action is released as soon as one of its keys is released.
+//!
+//! [`KeyboardLayout::COMBOS`]: crate::keyboard::KeyboardLayout::COMBOS
+//! [`KeyboardLayout::COMBO_TERM`]: crate::keyboard::KeyboardLayout::COMBO_TERM
+
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use keyberon::action::Action;
+use keyberon::layout::{Event, Layout as KeyberonLayout, VIRTUAL_ROW};
+
+use crate::keyboard::{Keycode, AUTO_MOUSE_VIRTUAL_KEY};
+
+#[cfg(feature = "vial")]
+use embassy_sync::mutex::{Mutex, MutexGuard};
+#[cfg(feature = "vial")]
+use keyberon::key_code::KeyCode;
+
+#[cfg(feature = "vial")]
+use crate::hw::mcu::RawMutex;
+
+/// Maximum number of keys in a combo.
+pub const MAX_COMBO_KEYS: usize = 4;
+
+/// Default value of [`crate::keyboard::KeyboardLayout::COMBO_TERM`], in milliseconds.
+pub const DEFAULT_COMBO_TERM: u16 = 50;
+
+/// A combo defined in your layout.
+#[derive(Clone, Copy)]
+pub struct Combo {
+    /// Layout positions (row, column) of the keys that must be pressed to trigger the combo.
+    /// Combos can have up to [`MAX_COMBO_KEYS`] keys.
+    pub keys: &'static [(u8, u8)],
+    /// Action to perform when the combo is triggered.
+    pub action: Action<Keycode>,
+}
+
+/// Number of virtual keys that can be used by combos. The virtual keys after them are used by the
+/// automatic mouse layer, injected keys and the leader key.
+const COMBO_VIRTUAL_KEYS: u8 = AUTO_MOUSE_VIRTUAL_KEY;
+
+/// Maximum number of combos that can be created at runtime.
+#[cfg(feature = "vial")]
+pub(crate) const MAX_DYNAMIC_COMBOS: usize = 32;
+
+/// A combo created at runtime. Unlike [`Combo`], keys are matched using the keycode they produce
+/// on the current layer, instead of their position.
+#[cfg(feature = "vial")]
+pub(crate) struct DynamicCombo {
+    pub(crate) keys: Vec<KeyCode, MAX_COMBO_KEYS>,
+    pub(crate) action: Action<Keycode>,
+}
+
+/// Combos created at runtime, which are used in addition to [`KeyboardLayout::COMBOS`].
+///
+/// [`KeyboardLayout::COMBOS`]: crate::keyboard::KeyboardLayout::COMBOS
+#[cfg(feature = "vial")]
+pub(crate) static DYNAMIC_COMBOS: Mutex<RawMutex, Vec<DynamicCombo, MAX_DYNAMIC_COMBOS>> =
+    Mutex::new(Vec::new());
+
+#[derive(Clone, Copy)]
+enum ComboKeys<'a> {
+    Positions(&'a [(u8, u8)]),
+    #[cfg(feature = "vial")]
+    KeyCodes(&'a [KeyCode]),
+}
+
+impl<'a> ComboKeys<'a> {
+    fn len(&self) -> usize {
+        match self {
+            ComboKeys::Positions(keys) => keys.len(),
+            #[cfg(feature = "vial")]
+            ComboKeys::KeyCodes(keys) => keys.len(),
+        }
+    }
+
+    #[cfg_attr(not(feature = "vial"), allow(unused_variables))]
+    fn contains<const C: usize, const R: usize, const L: usize>(
+        &self,
+        layout: &KeyberonLayout<C, R, L, Keycode>,
+        coord: (u8, u8),
+    ) -> bool {
+        match self {
+            ComboKeys::Positions(keys) => keys.contains(&coord),
+            #[cfg(feature = "vial")]
+            ComboKeys::KeyCodes(keys) => {
+                matches!(layout.resolve_action(coord), Action::KeyCode(key) if keys.contains(&key))
+            }
+        }
+    }
+}
+
+struct Candidates<'a> {
+    combos: &'a [Combo],
+    #[cfg(feature = "vial")]
+    dynamic: Option<MutexGuard<'a, RawMutex, Vec<DynamicCombo, MAX_DYNAMIC_COMBOS>>>,
+}
+
+impl<'a> Candidates<'a> {
+    fn new(combos: &'a [Combo]) -> Self {
+        Self {
+            combos,
+            // The layout can't wait for the combos to be updated, so they are skipped if they are
+            // being changed.
+            #[cfg(feature = "vial")]
+            dynamic: DYNAMIC_COMBOS.try_lock().ok(),
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (ComboKeys<'_>, Action<Keycode>)> {
+        let iter = self
+            .combos
+            .iter()
+            .map(|combo| (ComboKeys::Positions(combo.keys), combo.action));
+
+        #[cfg(feature = "vial")]
+        let iter = iter.chain(
+            self.dynamic
+                .iter()
+                .flat_map(|combos| combos.iter())
+                .map(|combo| (ComboKeys::KeyCodes(&combo.keys), combo.action)),
+        );
+
+        iter
+    }
+}
+
+struct ActiveCombo {
+    slot: u8,
+    keys: Vec<(u8, u8), MAX_COMBO_KEYS>,
+    released: bool,
+}
+
+/// Events produced by [`ComboProcessor`], which should be sent to the layout in order.
+pub(crate) type ComboEvents = Vec<Event, { MAX_COMBO_KEYS + 2 }>;
+
+/// Holds back key presses that may belong to a combo, and turns completed combos into presses of
+/// the layout's virtual keys.
+pub(crate) struct ComboProcessor {
+    pending: Vec<(u8, u8), MAX_COMBO_KEYS>,
+    pending_since: Instant,
+    active: Vec<ActiveCombo, { COMBO_VIRTUAL_KEYS as usize }>,
+    next_slot: u8,
+}
+
+impl ComboProcessor {
+    pub(crate) const fn new() -> Self {
+        Self {
+            pending: Vec::new(),
+            pending_since: Instant::from_ticks(0),
+            active: Vec::new(),
+            next_slot: 0,
+        }
+    }
+
+    /// Process a matrix event, returning the events that should be sent to the layout.
+    pub(crate) fn event<const C: usize, const R: usize, const L: usize>(
+        &mut self,
+        layout: &mut KeyberonLayout<C, R, L, Keycode>,
+        combos: &[Combo],
+        event: Event,
+    ) -> ComboEvents {
+        let candidates = Candidates::new(combos);
+        let mut events = ComboEvents::new();
+        let coord = event.coord();
+
+        match event {
+            Event::Press(_, _) => {
+                if self.try_add(layout, &candidates, coord, &mut events) {
+                    return events;
+                }
+
+                // The key can't complete the pending combo, but it may start a new one
+                self.resolve(layout, &candidates, &mut events);
+                if !self.try_add(layout, &candidates, coord, &mut events) {
+                    let _ = events.push(event);
+                }
+            }
+            Event::Release(_, _) => {
+                if self.pending.contains(&coord) {
+                    self.resolve(layout, &candidates, &mut events);
+                }
+
+                if let Some(idx) = self
+                    .active
+                    .iter()
+                    .position(|active| active.keys.contains(&coord))
+                {
+                    let active = &mut self.active[idx];
+                    active.keys.retain(|key| *key != coord);
+                    if !active.released {
+                        active.released = true;
+                        let _ = events.push(Event::Release(VIRTUAL_ROW, active.slot));
+                    }
+                    if active.keys.is_empty() {
+                        self.active.swap_remove(idx);
+                    }
+                } else {
+                    let _ = events.push(event);
+                }
+            }
+        }
+
+        events
+    }
+
+    /// Resolve the pending keys if the combo term has passed, returning the events that should be
+    /// sent to the layout.
+    pub(crate) fn tick<const C: usize, const R: usize, const L: usize>(
+        &mut self,
+        layout: &mut KeyberonLayout<C, R, L, Keycode>,
+        combos: &[Combo],
+        term: Duration,
+    ) -> ComboEvents {
+        let mut events = ComboEvents::new();
+
+        if !self.pending.is_empty() && self.pending_since.elapsed() >= term {
+            self.resolve(layout, &Candidates::new(combos), &mut events);
+        }
+
+        events
+    }
+
+    /// Add a pressed key to the pending keys, if it can be part of a combo along with the other
+    /// pending keys. Returns `false` if the key was not added.
+    fn try_add<const C: usize, const R: usize, const L: usize>(
+        &mut self,
+        layout: &mut KeyberonLayout<C, R, L, Keycode>,
+        candidates: &Candidates,
+        coord: (u8, u8),
+        events: &mut ComboEvents,
+    ) -> bool {
+        if self.pending.push(coord).is_err() {
+            return false;
+        }
+
+        let mut partial = false;
+        let mut complete = None;
+        for (keys, action) in candidates.iter() {
+            if keys.len() < self.pending.len()
+                || !self.pending.iter().all(|key| keys.contains(layout, *key))
+            {
+                continue;
+            }
+
+            if keys.len() == self.pending.len() {
+                complete = complete.or(Some(action));
+            } else {
+                partial = true;
+            }
+        }
+
+        match (complete, partial) {
+            (None, false) => {
+                self.pending.pop();
+                return false;
+            }
+            // Trigger the combo immediately if no larger combo can be completed
+            (Some(action), false) => {
+                if !self.trigger(layout, action, events) {
+                    self.flush(events);
+                }
+            }
+            _ => {
+                if self.pending.len() == 1 {
+                    self.pending_since = Instant::now();
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Trigger a combo using the pending keys if they complete one, otherwise send them to the
+    /// layout.
+    fn resolve<const C: usize, const R: usize, const L: usize>(
+        &mut self,
+        layout: &mut KeyberonLayout<C, R, L, Keycode>,
+        candidates: &Candidates,
+        events: &mut ComboEvents,
+    ) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        let complete = candidates.iter().find_map(|(keys, action)| {
+            (keys.len() == self.pending.len()
+                && self.pending.iter().all(|key| keys.contains(layout, *key)))
+            .then_some(action)
+        });
+
+        if !complete.is_some_and(|action| self.trigger(layout, action, events)) {
+            self.flush(events);
+        }
+    }
+
+    /// Press a virtual key with the given action, and track the pending keys as an active combo.
+    /// Returns `false` if all virtual keys are in use.
+    fn trigger<const C: usize, const R: usize, const L: usize>(
+        &mut self,
+        layout: &mut KeyberonLayout<C, R, L, Keycode>,
+        action: Action<Keycode>,
+        events: &mut ComboEvents,
+    ) -> bool {
+        let Some(slot) = (0..COMBO_VIRTUAL_KEYS)
+            .map(|i| (self.next_slot + i) % COMBO_VIRTUAL_KEYS)
+            .find(|slot| self.active.iter().all(|active| active.slot != *slot))
+        else {
+            return false;
+        };
+
+        if layout.set_virtual_key(slot, action).is_err() {
+            return false;
+        }
+
+        // Slots are used in turn, so that a virtual key that was just released isn't changed
+        // before the layout has processed its events.
+        self.next_slot = (slot + 1) % COMBO_VIRTUAL_KEYS;
+        let _ = self.active.push(ActiveCombo {
+            slot,
+            keys: self.pending.clone(),
+            released: false,
+        });
+        self.pending.clear();
+        let _ = events.push(Event::Press(VIRTUAL_ROW, slot));
+
+        true
+    }
+
+    /// Send the pending keys to the layout as normal key presses.
+    fn flush(&mut self, events: &mut ComboEvents) {
+        for (row, col) in self.pending.iter() {
+            let _ = events.push(Event::Press(*row, *col));
+        }
+        self.pending.clear();
+    }
+}
@@ -0,0 +1,419 @@
+//! Serial console.
+//!
+//! This adds a USB CDC-ACM (serial) interface to your keyboard, which can be opened with any
+//! serial terminal. This is useful for seeing what your firmware is doing without a debug probe.
+//!
+//! The console carries text written with [`console_print`](crate::console_print) and
+//! [`console_println`](crate::console_println), and provides a small command shell. Type `help`
+//! in the console for a list of available commands.
+//!
+//! With the `hid-console` feature, the same text is also sent over a QMK-compatible HID console
+//! interface, which can be read using `hid_listen` or QMK Toolbox.
+//!
+//! With the `nus-console` feature, the console (including the command shell) is also available
+//! over bluetooth, using the Nordic UART Service. This can be opened from a phone using a BLE
+//! UART app, such as nRF Toolbox or Bluefruit Connect.
+
+use core::cell::Cell;
+use core::fmt::Write;
+
+#[cfg(any(feature = "console", feature = "nus-console"))]
+use defmt::info;
+use embassy_sync::pipe::Pipe;
+use keyberon::layout::Event;
+
+use crate::hw::mcu::{BlockingMutex, RawMutex};
+
+#[cfg(any(feature = "console", feature = "nus-console"))]
+/// Maximum length of a command entered in the console.
+const CONSOLE_LINE_SIZE: usize = 64;
+
+#[cfg(feature = "console")]
+/// Bytes to be written to the console. Output is dropped if the pipe is full.
+pub(crate) static CONSOLE_OUTPUT_PIPE: Pipe<RawMutex, 256> = Pipe::new();
+
+#[cfg(feature = "nus-console")]
+/// Bytes to be written to the bluetooth (NUS) console. Output is dropped if the pipe is full.
+pub(crate) static NUS_CONSOLE_OUTPUT_PIPE: Pipe<RawMutex, 256> = Pipe::new();
+
+#[cfg(any(feature = "console", feature = "nus-console"))]
+/// Bytes received from the console.
+pub(crate) static CONSOLE_INPUT_PIPE: Pipe<RawMutex, 64> = Pipe::new();
+
+#[cfg(feature = "hid-console")]
+/// Size of HID console reports.
+pub const HID_CONSOLE_REPORT_SIZE: usize = 32;
+
+#[cfg(feature = "hid-console")]
+/// Report descriptor used for the HID console. Pulled from QMK.
+pub(crate) const HID_CONSOLE_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x31, 0xFF, // Usage Page (Vendor Defined - PJRC Teensy compatible)
+    0x09, 0x74, // Usage (Vendor Defined - PJRC Teensy compatible)
+    0xA1, 0x01, // Collection (Application)
+    // Data to host
+    0x09, 0x75, //   Usage (Vendor Defined)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x95, 0x20, //   Report Count
+    0x75, 0x08, //   Report Size (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0xC0, // End Collection
+];
+
+#[cfg(feature = "hid-console")]
+/// Bytes to be written to the HID console. Output is dropped if the pipe is full.
+pub(crate) static HID_CONSOLE_OUTPUT_PIPE: Pipe<RawMutex, 256> = Pipe::new();
+
+/// Whether debug output (e.g. matrix events) should be written to the console. This can be
+/// toggled using the `debug` command, or [`crate::keyboard::Keycode::DebugToggle`].
+static DEBUG_ENABLED: BlockingMutex<Cell<bool>> = BlockingMutex::new(Cell::new(false));
+
+/// Print text to the console. Uses the same syntax as [`core::format_args`].
+///
+/// If the console is not connected, or the output buffer is full, the text will be dropped.
+#[macro_export]
+macro_rules! console_print {
+    ($($arg:tt)*) => {
+        $crate::console::_print(::core::format_args!($($arg)*))
+    };
+}
+
+/// Print text to the console, followed by a newline. Uses the same syntax as
+/// [`core::format_args`].
+///
+/// If the console is not connected, or the output buffer is full, the text will be dropped.
+#[macro_export]
+macro_rules! console_println {
+    () => {
+        $crate::console_print!("\r\n")
+    };
+    ($($arg:tt)*) => {{
+        $crate::console_print!($($arg)*);
+        $crate::console_print!("\r\n");
+    }};
+}
+
+/// Print text to the console, followed by a newline, but only if debug output has been enabled.
+/// Uses the same syntax as [`core::format_args`].
+///
+/// This is similar to QMK's `dprintf`. If the `console`, `hid-console` and `nus-console` features
+/// are all disabled, this does nothing, so it can be left in your code.
+#[macro_export]
+macro_rules! console_debug {
+    ($($arg:tt)*) => {
+        if $crate::console::debug_enabled() {
+            $crate::console_println!($($arg)*);
+        }
+    };
+}
+
+fn write_to_pipe<const N: usize>(pipe: &Pipe<RawMutex, N>, mut bytes: &[u8]) {
+    while !bytes.is_empty() {
+        match pipe.try_write(bytes) {
+            Ok(written) => bytes = &bytes[written..],
+            Err(_) => break, // Pipe is full, drop the rest of the output
+        }
+    }
+}
+
+struct ConsoleWriter;
+
+impl Write for ConsoleWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        #[cfg(feature = "console")]
+        write_to_pipe(&CONSOLE_OUTPUT_PIPE, s.as_bytes());
+
+        #[cfg(feature = "hid-console")]
+        write_to_pipe(&HID_CONSOLE_OUTPUT_PIPE, s.as_bytes());
+
+        #[cfg(feature = "nus-console")]
+        write_to_pipe(&NUS_CONSOLE_OUTPUT_PIPE, s.as_bytes());
+
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: core::fmt::Arguments) {
+    let _ = ConsoleWriter.write_fmt(args);
+}
+
+/// Returns `true` if debug output has been enabled with the `debug` command.
+pub fn debug_enabled() -> bool {
+    DEBUG_ENABLED.lock(Cell::get)
+}
+
+/// Enable or disable debug output.
+pub fn set_debug_enabled(enabled: bool) {
+    DEBUG_ENABLED.lock(|debug| debug.set(enabled));
+}
+
+pub(crate) fn log_matrix_event(event: Event) {
+    if debug_enabled() {
+        let (row, col) = event.coord();
+        crate::console_println!(
+            "[KEYBOARD] {} ({}, {})",
+            if event.is_press() { "Press" } else { "Release" },
+            row,
+            col
+        );
+    }
+}
+
+#[cfg(any(feature = "console", feature = "nus-console"))]
+pub(crate) fn print_prompt() {
+    crate::console_print!("> ");
+}
+
+#[cfg(any(feature = "console", feature = "nus-console"))]
+/// Echo input back to the consoles that accept commands, so that the user can see what they typed.
+fn echo(bytes: &[u8]) {
+    #[cfg(feature = "console")]
+    let _ = CONSOLE_OUTPUT_PIPE.try_write(bytes);
+
+    #[cfg(feature = "nus-console")]
+    let _ = NUS_CONSOLE_OUTPUT_PIPE.try_write(bytes);
+}
+
+#[cfg(any(feature = "console", feature = "nus-console"))]
+async fn run_command(line: &str) {
+    let mut args = line.split_whitespace();
+
+    match args.next() {
+        Some("help") => {
+            crate::console_println!("Available commands:");
+            crate::console_println!("  help             Show this message");
+            crate::console_println!("  battery          Show the current battery status");
+            crate::console_println!("  storage          Show storage usage statistics");
+            crate::console_println!("  debug [on|off]   Toggle debug output (e.g. matrix events)");
+            crate::console_println!(
+                "  diag [on|off|clear]   Show matrix diagnostics (stuck keys, ghosting, scan rate)"
+            );
+            #[cfg(feature = "dongle")]
+            crate::console_println!(
+                "  dongle [pair|unpair <slot>|unpair all]   Show or manage the dongle's keyboards"
+            );
+        }
+        Some("battery") => {
+            let status = crate::hw::BATTERY_STATE.get().await;
+            crate::console_println!("Battery level: {}%", status.level);
+            if let Some(voltage_mv) = status.voltage_mv {
+                crate::console_println!("Voltage: {} mV", voltage_mv);
+            }
+            crate::console_println!("Charging state: {:?}", status.charge_state);
+        }
+        Some("storage") => print_storage_stats().await,
+        Some("debug") => {
+            let enabled = match args.next() {
+                Some("on") => true,
+                Some("off") => false,
+                _ => !debug_enabled(),
+            };
+            set_debug_enabled(enabled);
+            crate::console_println!(
+                "Debug output {}",
+                if enabled { "enabled" } else { "disabled" }
+            );
+        }
+        Some("diag") => run_diagnostics_command(args.next()),
+        #[cfg(feature = "dongle")]
+        Some("dongle") => run_dongle_command(args.next(), args.next()).await,
+        Some(command) => {
+            crate::console_println!(
+                "Unknown command: {}. Type `help` for a list of commands.",
+                command
+            );
+        }
+        None => {}
+    }
+}
+
+#[cfg(any(feature = "console", feature = "nus-console"))]
+fn run_diagnostics_command(command: Option<&str>) {
+    use crate::diagnostics::{
+        clear, diagnostics_enabled, report, scan_metrics, set_diagnostics_enabled,
+    };
+
+    match command {
+        Some("on") => set_diagnostics_enabled(true),
+        Some("off") => set_diagnostics_enabled(false),
+        Some("clear") => clear(),
+        Some(_) => {
+            crate::console_println!("Usage: diag [on|off|clear]");
+            return;
+        }
+        None => {}
+    }
+
+    let report = report();
+    crate::console_println!(
+        "Diagnostics {}",
+        if diagnostics_enabled() {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+    crate::console_println!("Stuck keys: {:?}", report.stuck_keys.as_slice());
+    crate::console_println!("Possible ghost key presses: {}", report.ghost_count);
+    if let Some((row, col)) = report.last_ghost {
+        crate::console_println!("Last possible ghost key press: ({}, {})", row, col);
+    }
+
+    let metrics = scan_metrics();
+    crate::console_println!("Scan rate: {} Hz", metrics.scan_rate);
+    crate::console_println!(
+        "Latency: {} us (last report), {} us (worst case)",
+        metrics.last_latency_us,
+        metrics.max_latency_us
+    );
+}
+
+#[cfg(all(any(feature = "console", feature = "nus-console"), feature = "dongle"))]
+async fn run_dongle_command(command: Option<&str>, arg: Option<&str>) {
+    use crate::dongle::{DongleCommand, DONGLE_COMMAND_CHANNEL, ESB_KEYBOARD_SLOT};
+
+    let command = match (command, arg) {
+        (None, _) => {
+            let keyboards = crate::dongle::KEYBOARDS_STATE.get().await;
+            for (slot, keyboard) in keyboards.iter().enumerate() {
+                let transport = if slot == ESB_KEYBOARD_SLOT {
+                    "ESB"
+                } else {
+                    "BLE"
+                };
+
+                match (keyboard.connected, keyboard.battery_level) {
+                    (true, Some(level)) => crate::console_println!(
+                        "Slot {} ({}): connected, battery {}%",
+                        slot,
+                        transport,
+                        level
+                    ),
+                    (true, None) => {
+                        crate::console_println!("Slot {} ({}): connected", slot, transport)
+                    }
+                    (false, _) => {
+                        crate::console_println!("Slot {} ({}): disconnected", slot, transport)
+                    }
+                }
+            }
+            return;
+        }
+        (Some("pair"), _) => DongleCommand::OpenPairing,
+        (Some("unpair"), Some("all")) => DongleCommand::UnpairAll,
+        (Some("unpair"), Some(slot)) => match slot.parse() {
+            Ok(slot) => DongleCommand::Unpair(slot),
+            Err(_) => {
+                crate::console_println!("Invalid slot: {}", slot);
+                return;
+            }
+        },
+        _ => {
+            crate::console_println!("Usage: dongle [pair|unpair <slot>|unpair all]");
+            return;
+        }
+    };
+
+    if DONGLE_COMMAND_CHANNEL.try_send(command).is_err() {
+        crate::console_println!("Dongle is busy, try again later");
+    }
+}
+
+#[cfg(all(any(feature = "console", feature = "nus-console"), feature = "storage"))]
+async fn print_storage_stats() {
+    use embassy_time::{with_timeout, Duration};
+
+    crate::storage::STORAGE_STATS_SIGNAL.reset();
+    crate::storage::STORAGE_STATS_REQUEST_SIGNAL.signal(());
+
+    match with_timeout(
+        Duration::from_secs(1),
+        crate::storage::STORAGE_STATS_SIGNAL.wait(),
+    )
+    .await
+    {
+        Ok(Ok(stats)) => {
+            crate::console_println!(
+                "Usage: {}/{} bytes used, {} bytes free, {} free regions",
+                stats.used_bytes,
+                stats.total_bytes,
+                stats.free_bytes,
+                stats.free_regions
+            );
+            crate::console_println!(
+                "{} invalidated keys ({} bytes) awaiting garbage collection",
+                stats.invalidated_keys,
+                stats.invalidated_bytes
+            );
+            crate::console_println!(
+                "Erase cycles: {} to {} per page, page {} is the most erased",
+                stats.min_erase_count,
+                stats.max_erase_count,
+                stats.most_erased_page
+            );
+        }
+        Ok(Err(error)) => {
+            crate::console_println!("Could not obtain storage statistics: {:?}", error);
+        }
+        Err(_) => {
+            crate::console_println!("Storage has not been set up");
+        }
+    }
+}
+
+#[cfg(all(
+    any(feature = "console", feature = "nus-console"),
+    not(feature = "storage")
+))]
+async fn print_storage_stats() {
+    crate::console_println!("Storage is not enabled");
+}
+
+#[cfg(any(feature = "console", feature = "nus-console"))]
+#[rumcake_macros::task]
+pub async fn console_task() {
+    let mut line = heapless::Vec::<u8, CONSOLE_LINE_SIZE>::new();
+    let mut last_byte = 0;
+    let mut buf = [0; 16];
+
+    loop {
+        let len = CONSOLE_INPUT_PIPE.read(&mut buf).await;
+
+        for &byte in &buf[..len] {
+            match byte {
+                // Treat "\r\n" as a single line ending
+                b'\n' if last_byte == b'\r' => {}
+                b'\r' | b'\n' => {
+                    crate::console_println!();
+
+                    match core::str::from_utf8(&line) {
+                        Ok(command) => {
+                            info!("[CONSOLE] Running command: {}", command);
+                            run_command(command).await;
+                        }
+                        Err(_) => {
+                            crate::console_println!("Commands must be valid UTF-8");
+                        }
+                    }
+
+                    line.clear();
+                    print_prompt();
+                }
+                // Backspace or delete
+                0x08 | 0x7F => {
+                    if line.pop().is_some() {
+                        crate::console_print!("\x08 \x08");
+                    }
+                }
+                _ => {
+                    if line.push(byte).is_ok() {
+                        echo(&[byte]);
+                    }
+                }
+            }
+
+            last_byte = byte;
+        }
+    }
+}
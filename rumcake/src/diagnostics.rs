@@ -0,0 +1,357 @@
+//! Matrix diagnostics, which help find soldering and wiring issues.
+//!
+//! Two kinds of issues are detected, using the positions (row, column) of keys in your matrix,
+//! before they are remapped to your layout:
+//! - Stuck keys: keys that have been held since your keyboard started, which usually means that a
+//!   switch is shorted, or that a row or column is connected to the wrong pin. A stuck key is no
+//!   longer considered stuck once it is released.
+//! - Ghosting: a key that is pressed while three other held keys form a rectangle with it in the
+//!   matrix. On matrices without diodes (or with a missing or reversed diode), holding three
+//!   corners of a rectangle makes the fourth corner appear to be pressed.
+//!
+//! Stuck keys are always tracked. Ghosting is only checked while diagnostics are enabled, using
+//! [`set_diagnostics_enabled`], the `diag` console command, or [`handle_raw_hid_report`]. While
+//! enabled, issues are also printed to the console as they are detected.
+//!
+//! The scan rate of the matrix, and the latency between a key event being detected and the
+//! resulting keyboard report being queued, are also measured. See [`ScanMetrics`].
+
+use core::cell::{Cell, RefCell};
+
+use defmt::{debug, warn};
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use keyberon::layout::Event;
+
+use crate::hw::mcu::BlockingMutex;
+
+/// Keys pressed within this many milliseconds of startup, that haven't been released since, are
+/// considered stuck.
+const STUCK_KEY_STARTUP_WINDOW_MS: u64 = 1000;
+
+/// Maximum number of held keys that are tracked.
+const MAX_TRACKED_KEYS: usize = 32;
+
+/// Maximum number of stuck keys that are reported.
+pub const MAX_STUCK_KEYS: usize = 12;
+
+/// First byte of the raw HID reports handled by [`handle_raw_hid_report`].
+pub const DIAGNOSTICS_COMMAND_ID: u8 = 0xA2;
+
+const SUBCOMMAND_GET: u8 = 0x01;
+const SUBCOMMAND_SET_ENABLED: u8 = 0x02;
+const SUBCOMMAND_CLEAR: u8 = 0x03;
+const SUBCOMMAND_GET_SCAN_METRICS: u8 = 0x04;
+const SUBCOMMAND_RESET_SCAN_METRICS: u8 = 0x05;
+const SUBCOMMAND_ERROR: u8 = 0xFF;
+
+/// Results of the matrix diagnostics.
+#[derive(Debug, Clone)]
+pub struct DiagnosticsReport {
+    /// Matrix positions (row, column) of keys that have been held since startup.
+    pub stuck_keys: Vec<(u8, u8), MAX_STUCK_KEYS>,
+    /// Number of key presses that completed a rectangle of held keys, since diagnostics were
+    /// enabled or cleared.
+    pub ghost_count: u16,
+    /// Matrix position (row, column) of the last key press that completed a rectangle of held
+    /// keys.
+    pub last_ghost: Option<(u8, u8)>,
+}
+
+impl DiagnosticsReport {
+    const fn new() -> Self {
+        Self {
+            stuck_keys: Vec::new(),
+            ghost_count: 0,
+            last_ghost: None,
+        }
+    }
+}
+
+/// Scan rate and latency of the matrix.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScanMetrics {
+    /// Number of times the matrix was scanned during the last second.
+    pub scan_rate: u32,
+    /// Time between a key event being detected by a matrix scan, and the resulting keyboard report
+    /// being queued, for the last report that was caused by a key event, in microseconds. This
+    /// doesn't include the time spent waiting for the host to receive the report.
+    pub last_latency_us: u32,
+    /// Longest latency between a key event being detected and its keyboard report being queued,
+    /// since startup or [`reset_scan_metrics`], in microseconds.
+    pub max_latency_us: u32,
+}
+
+impl ScanMetrics {
+    const fn new() -> Self {
+        Self {
+            scan_rate: 0,
+            last_latency_us: 0,
+            max_latency_us: 0,
+        }
+    }
+}
+
+static SCAN_METRICS: BlockingMutex<Cell<ScanMetrics>> =
+    BlockingMutex::new(Cell::new(ScanMetrics::new()));
+
+// Time at which the key event that is waiting to be processed by the layout was detected
+static EVENT_SCAN_TIME: BlockingMutex<Cell<Option<Instant>>> = BlockingMutex::new(Cell::new(None));
+
+/// Obtain the scan rate and latency of the matrix.
+pub fn scan_metrics() -> ScanMetrics {
+    SCAN_METRICS.lock(Cell::get)
+}
+
+/// Reset the longest latency in [`ScanMetrics`].
+pub fn reset_scan_metrics() {
+    SCAN_METRICS.lock(|metrics| {
+        metrics.set(ScanMetrics {
+            max_latency_us: 0,
+            ..metrics.get()
+        })
+    });
+}
+
+/// Record the time at which a key event was detected, before it is sent to the layout. If the
+/// previous event hasn't been processed yet, its time is kept.
+pub(crate) fn event_scanned() {
+    EVENT_SCAN_TIME.lock(|time| {
+        if time.get().is_none() {
+            time.set(Some(Instant::now()))
+        }
+    });
+}
+
+/// Obtain the time at which the key event received by the layout was detected.
+pub(crate) fn take_event_scan_time() -> Option<Instant> {
+    EVENT_SCAN_TIME.lock(Cell::take)
+}
+
+/// Record the latency of a keyboard report caused by a key event detected at `scanned_at`.
+pub(crate) fn report_queued(scanned_at: Instant) {
+    let latency_us = scanned_at
+        .elapsed()
+        .as_micros()
+        .try_into()
+        .unwrap_or(u32::MAX);
+    SCAN_METRICS.lock(|metrics| {
+        let metrics_value = metrics.get();
+        metrics.set(ScanMetrics {
+            last_latency_us: latency_us,
+            max_latency_us: metrics_value.max_latency_us.max(latency_us),
+            ..metrics_value
+        })
+    });
+}
+
+static DIAGNOSTICS_ENABLED: BlockingMutex<Cell<bool>> = BlockingMutex::new(Cell::new(false));
+
+static DIAGNOSTICS_REPORT: BlockingMutex<RefCell<DiagnosticsReport>> =
+    BlockingMutex::new(RefCell::new(DiagnosticsReport::new()));
+
+/// Returns `true` if diagnostics have been enabled.
+pub fn diagnostics_enabled() -> bool {
+    DIAGNOSTICS_ENABLED.lock(Cell::get)
+}
+
+/// Enable or disable ghosting checks, and printing issues to the console as they are detected.
+pub fn set_diagnostics_enabled(enabled: bool) {
+    DIAGNOSTICS_ENABLED.lock(|diagnostics| diagnostics.set(enabled));
+}
+
+/// Obtain the results of the matrix diagnostics.
+pub fn report() -> DiagnosticsReport {
+    DIAGNOSTICS_REPORT.lock(|report| report.borrow().clone())
+}
+
+/// Reset the number of detected ghost key presses, and the longest latency in [`ScanMetrics`].
+/// Stuck keys are not affected.
+pub fn clear() {
+    DIAGNOSTICS_REPORT.lock(|report| {
+        let mut report = report.borrow_mut();
+        report.ghost_count = 0;
+        report.last_ghost = None;
+    });
+    reset_scan_metrics();
+}
+
+/// Keeps track of the keys that are held in the matrix, and how often it is scanned.
+pub(crate) struct MatrixDiagnostics {
+    held: Vec<(u8, u8), MAX_TRACKED_KEYS>,
+    scans: u32,
+    scans_since: Option<Instant>,
+}
+
+impl MatrixDiagnostics {
+    pub(crate) const fn new() -> Self {
+        Self {
+            held: Vec::new(),
+            scans: 0,
+            scans_since: None,
+        }
+    }
+
+    /// Count a scan of the matrix. The scan rate is updated every second.
+    pub(crate) fn scanned(&mut self) {
+        let now = Instant::now();
+        let since = *self.scans_since.get_or_insert(now);
+        self.scans += 1;
+
+        let elapsed = now - since;
+        if elapsed >= Duration::from_secs(1) {
+            let scan_rate = (self.scans as u64 * 1000 / elapsed.as_millis()) as u32;
+            SCAN_METRICS.lock(|metrics| {
+                metrics.set(ScanMetrics {
+                    scan_rate,
+                    ..metrics.get()
+                })
+            });
+            debug!(
+                "[DIAGNOSTICS] Scan rate: {} Hz, worst-case latency: {} us",
+                scan_rate,
+                scan_metrics().max_latency_us
+            );
+
+            self.scans = 0;
+            self.scans_since = Some(now);
+        }
+    }
+
+    /// Process a matrix event, before it is remapped to the layout.
+    pub(crate) fn event(&mut self, event: Event) {
+        let (row, col) = event.coord();
+
+        if event.is_release() {
+            self.held.retain(|key| *key != (row, col));
+
+            let was_stuck = DIAGNOSTICS_REPORT.lock(|report| {
+                let mut report = report.borrow_mut();
+                let idx = report.stuck_keys.iter().position(|key| *key == (row, col));
+                idx.map(|idx| report.stuck_keys.swap_remove(idx)).is_some()
+            });
+            if was_stuck {
+                warn!("[DIAGNOSTICS] Stuck key ({}, {}) was released", row, col);
+                print_issue("Stuck key was released", row, col);
+            }
+            return;
+        }
+
+        if Instant::now().as_millis() < STUCK_KEY_STARTUP_WINDOW_MS {
+            let added = DIAGNOSTICS_REPORT
+                .lock(|report| report.borrow_mut().stuck_keys.push((row, col)).is_ok());
+            if added {
+                warn!("[DIAGNOSTICS] Key ({}, {}) is held at startup", row, col);
+            }
+        }
+
+        if diagnostics_enabled() && self.completes_rectangle(row, col) {
+            warn!(
+                "[DIAGNOSTICS] Key press at ({}, {}) may be a ghost, check your diodes",
+                row, col
+            );
+            print_issue("Possible ghost key press, check your diodes", row, col);
+            DIAGNOSTICS_REPORT.lock(|report| {
+                let mut report = report.borrow_mut();
+                report.ghost_count = report.ghost_count.saturating_add(1);
+                report.last_ghost = Some((row, col));
+            });
+        }
+
+        if !self.held.contains(&(row, col)) {
+            let _ = self.held.push((row, col));
+        }
+    }
+
+    /// Whether there are held keys at the other three corners of a rectangle with the given key.
+    fn completes_rectangle(&self, row: u8, col: u8) -> bool {
+        self.held
+            .iter()
+            .filter(|(r, c)| *r == row && *c != col)
+            .any(|(_, other_col)| {
+                self.held
+                    .iter()
+                    .filter(|(r, c)| *r != row && *c == col)
+                    .any(|(other_row, _)| self.held.contains(&(*other_row, *other_col)))
+            })
+    }
+}
+
+#[cfg(any(feature = "console", feature = "hid-console", feature = "nus-console"))]
+fn print_issue(message: &str, row: u8, col: u8) {
+    if diagnostics_enabled() {
+        crate::console_println!("[DIAGNOSTICS] {}: ({}, {})", message, row, col);
+    }
+}
+
+#[cfg(not(any(feature = "console", feature = "hid-console", feature = "nus-console")))]
+fn print_issue(_message: &str, _row: u8, _col: u8) {}
+
+/// Handle a raw HID report used to get the results of the matrix diagnostics. This can be called
+/// from [`crate::raw_hid::RawHIDDevice::handle_raw_hid_report`], or
+/// [`crate::via::ViaKeyboard::handle_via_command`] if you are using Via or Vial.
+///
+/// Reports that start with [`DIAGNOSTICS_COMMAND_ID`] are handled, and modified in place to form a
+/// response. Returns `false` for other reports, so that they can be handled by your own code. The
+/// second byte contains a subcommand:
+/// - `0x01` (get): the response contains whether diagnostics are enabled (0 or 1), the number of
+///   stuck keys, and the number of ghost key presses (big endian) in bytes 2 to 5. Bytes 6 and 7
+///   contain the position (row, column) of the last ghost key press, or `0xFF` if there are none.
+///   The positions of the stuck keys follow from byte 8.
+/// - `0x02` (set enabled): byte 2 contains 1 to enable diagnostics, or 0 to disable them.
+/// - `0x03` (clear): reset the number of ghost key presses, and the longest latency.
+/// - `0x04` (get scan metrics): the response contains the scan rate (in Hz), the latency of the
+///   last report and the longest latency (in microseconds) in bytes 2 to 13, as 32-bit big endian
+///   numbers. See [`ScanMetrics`].
+/// - `0x05` (reset scan metrics): reset the longest latency.
+///
+/// If the subcommand fails, the second byte of the response is set to `0xFF`.
+pub fn handle_raw_hid_report(data: &mut [u8]) -> bool {
+    if data.len() < 3 || data[0] != DIAGNOSTICS_COMMAND_ID {
+        return false;
+    }
+
+    let success = match data[1] {
+        SUBCOMMAND_GET if data.len() >= 8 => {
+            let report = report();
+            data[2] = diagnostics_enabled() as u8;
+            data[3] = report.stuck_keys.len() as u8;
+            data[4..6].copy_from_slice(&report.ghost_count.to_be_bytes());
+            (data[6], data[7]) = report.last_ghost.unwrap_or((0xFF, 0xFF));
+            for (key, bytes) in report.stuck_keys.iter().zip(data[8..].chunks_exact_mut(2)) {
+                (bytes[0], bytes[1]) = *key;
+            }
+            true
+        }
+        SUBCOMMAND_SET_ENABLED if data[2] <= 1 => {
+            set_diagnostics_enabled(data[2] == 1);
+            true
+        }
+        SUBCOMMAND_CLEAR => {
+            clear();
+            true
+        }
+        SUBCOMMAND_GET_SCAN_METRICS if data.len() >= 14 => {
+            let metrics = scan_metrics();
+            data[2..6].copy_from_slice(&metrics.scan_rate.to_be_bytes());
+            data[6..10].copy_from_slice(&metrics.last_latency_us.to_be_bytes());
+            data[10..14].copy_from_slice(&metrics.max_latency_us.to_be_bytes());
+            true
+        }
+        SUBCOMMAND_RESET_SCAN_METRICS => {
+            reset_scan_metrics();
+            true
+        }
+        _ => false,
+    };
+
+    if !success {
+        warn!(
+            "[DIAGNOSTICS] Could not handle diagnostics command {:X}",
+            data[1]
+        );
+        data[1] = SUBCOMMAND_ERROR;
+    }
+
+    true
+}
@@ -0,0 +1,114 @@
+//! Digitizer (absolute pointer) features.
+//!
+//! This allows devices like trackpads and touch panels to report absolute coordinates to the
+//! host, instead of emulating a relative mouse. To report a position, create a
+//! [`DigitizerReport`] and pass it to [`send_digitizer_report`].
+
+use defmt::{debug, warn, Debug2Format};
+use embassy_sync::channel::Channel;
+use packed_struct::prelude::PackedStruct;
+
+use crate::hw::mcu::RawMutex;
+use crate::hw::{HIDOutput, CURRENT_OUTPUT_STATE};
+
+/// Maximum value of the X and Y coordinates in a [`DigitizerReport`]. The host scales this range
+/// to the size of the screen.
+pub const DIGITIZER_MAX_COORDINATE: u16 = 0x7FFF;
+
+/// Report descriptor for a single-contact digitizer, with a tip switch, barrel switch, in range
+/// indicator, and absolute X and Y coordinates from 0 to [`DIGITIZER_MAX_COORDINATE`].
+pub const DIGITIZER_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x0D, // Usage Page (Digitizers)
+    0x09, 0x02, // Usage (Pen)
+    0xA1, 0x01, // Collection (Application)
+    0x09, 0x20, //   Usage (Stylus)
+    0xA1, 0x00, //   Collection (Physical)
+    // Switches
+    0x09, 0x42, //     Usage (Tip Switch)
+    0x09, 0x44, //     Usage (Barrel Switch)
+    0x09, 0x32, //     Usage (In Range)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x75, 0x01, //     Report Size (1)
+    0x95, 0x03, //     Report Count (3)
+    0x81, 0x02, //     Input (Data, Variable, Absolute)
+    // 5 bits of padding
+    0x95, 0x05, //     Report Count (5)
+    0x81, 0x03, //     Input (Constant)
+    // Coordinates
+    0x05, 0x01, //     Usage Page (Generic Desktop)
+    0x09, 0x30, //     Usage (X)
+    0x09, 0x31, //     Usage (Y)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x26, 0xFF, 0x7F, //     Logical Maximum (32767)
+    0x75, 0x10, //     Report Size (16)
+    0x95, 0x02, //     Report Count (2)
+    0x81, 0x02, //     Input (Data, Variable, Absolute)
+    0xC0, //   End Collection
+    0xC0, // End Collection
+];
+
+/// A HID report for the digitizer described by [`DIGITIZER_REPORT_DESCRIPTOR`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PackedStruct)]
+#[packed_struct(endian = "lsb", bit_numbering = "msb0")]
+pub struct DigitizerReport {
+    /// Bitmap of switches. See [`DigitizerReport::TIP_SWITCH`],
+    /// [`DigitizerReport::BARREL_SWITCH`] and [`DigitizerReport::IN_RANGE`].
+    #[packed_field]
+    pub switches: u8,
+    /// Absolute X coordinate, from 0 to [`DIGITIZER_MAX_COORDINATE`].
+    #[packed_field]
+    pub x: u16,
+    /// Absolute Y coordinate, from 0 to [`DIGITIZER_MAX_COORDINATE`].
+    #[packed_field]
+    pub y: u16,
+}
+
+impl DigitizerReport {
+    /// Set when the contact is touching the surface.
+    pub const TIP_SWITCH: u8 = 1 << 0;
+    /// Set when the barrel switch (usually a secondary button) is pressed.
+    pub const BARREL_SWITCH: u8 = 1 << 1;
+    /// Set when the contact is within range of the surface. The host will ignore the coordinates
+    /// if this is not set.
+    pub const IN_RANGE: u8 = 1 << 2;
+
+    /// Create a report for a contact at the given coordinates. Coordinates larger than
+    /// [`DIGITIZER_MAX_COORDINATE`] will be clamped.
+    pub fn new(x: u16, y: u16, touching: bool) -> Self {
+        Self {
+            switches: Self::IN_RANGE | if touching { Self::TIP_SWITCH } else { 0 },
+            x: x.min(DIGITIZER_MAX_COORDINATE),
+            y: y.min(DIGITIZER_MAX_COORDINATE),
+        }
+    }
+
+    /// Create a report indicating that the contact has left the surface.
+    pub fn out_of_range() -> Self {
+        Self::default()
+    }
+}
+
+/// Channel for sending digitizer HID reports.
+///
+/// Channel messages should be consumed by the USB task, so user-level code should **not**
+/// attempt to receive messages from the channel, otherwise commands may not be processed
+/// appropriately. It is recommended to use [`send_digitizer_report`] instead of sending to this
+/// channel directly.
+pub static DIGITIZER_REPORT_HID_SEND_CHANNEL: Channel<RawMutex, DigitizerReport, 1> =
+    Channel::new();
+
+/// Send a digitizer report to the host.
+pub async fn send_digitizer_report(report: DigitizerReport) {
+    // Digitizer reports are currently only supported over USB. If USB is not the current output,
+    // the channel can become filled, so we discard the report in that case.
+    if matches!(CURRENT_OUTPUT_STATE.get().await, Some(HIDOutput::Usb)) {
+        debug!(
+            "[DIGITIZER] Sending digitizer report: {:?}",
+            Debug2Format(&report)
+        );
+        DIGITIZER_REPORT_HID_SEND_CHANNEL.send(report).await;
+    } else {
+        warn!("[DIGITIZER] Discarding report");
+    }
+}
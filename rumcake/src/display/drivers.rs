@@ -67,7 +67,7 @@ macro_rules! on_update_default {
             let mut string: String<8> = String::from("BAT: ");
             string
                 .push_str(&String::<3>::from(
-                    crate::hw::BATTERY_LEVEL_STATE.get().await,
+                    crate::hw::BATTERY_STATE.get().await.level,
                 ))
                 .unwrap();
             string
@@ -84,9 +84,76 @@ macro_rules! on_update_default {
             match crate::hw::OUTPUT_MODE_STATE.get().await {
                 crate::hw::OutputMode::Usb => "MODE: USB",
                 crate::hw::OutputMode::Bluetooth => "MODE: BT",
+                crate::hw::OutputMode::Auto => "MODE: AUTO",
+                #[cfg(feature = "esb")]
+                crate::hw::OutputMode::Esb => "MODE: ESB",
             }
         ));
 
+        // Layer
+        let layer = {
+            let layers = crate::keyboard::LAYER_STATE.get().await;
+            let mut string: String<10> = String::from("LAYER: ");
+            string
+                .push_str(&String::<3>::from(layers.checked_ilog2().unwrap_or(0) as u8))
+                .unwrap();
+            string
+        };
+
+        let contents = contents.append(text_box!(bounding_box, $text_type, &layer));
+
+        // Bluetooth status
+        #[cfg(feature = "bluetooth")]
+        let bluetooth_status = {
+            let status = crate::bluetooth::BLUETOOTH_STATUS_STATE.get().await;
+            let mut string: String<16> = String::from("BT");
+            string
+                .push_str(&String::<3>::from(status.profile + 1))
+                .unwrap();
+            string
+                .push_str(match status.connection {
+                    crate::bluetooth::BluetoothConnectionStatus::Idle => ": IDLE",
+                    crate::bluetooth::BluetoothConnectionStatus::Advertising => ": ADV",
+                    crate::bluetooth::BluetoothConnectionStatus::Pairing => ": PAIR",
+                    crate::bluetooth::BluetoothConnectionStatus::Connected => ": CON",
+                    crate::bluetooth::BluetoothConnectionStatus::Bonded => ": OK",
+                })
+                .unwrap();
+            if let Some(rssi) = status.rssi {
+                string.push(' ').unwrap();
+                string.push_str(&String::<4>::from(rssi)).unwrap();
+            }
+            string
+        };
+
+        #[cfg(feature = "bluetooth")]
+        let contents = contents.append(text_box!(bounding_box, $text_type, &bluetooth_status));
+
+        // Passkey prompt
+        #[cfg(feature = "bluetooth")]
+        let passkey_prompt = {
+            let mut string: String<11> = String::new();
+            match crate::bluetooth::PASSKEY_PROMPT_STATE.get().await {
+                crate::bluetooth::PasskeyPrompt::None => {}
+                crate::bluetooth::PasskeyPrompt::Display(passkey) => {
+                    string.push_str("PIN: ").unwrap();
+                    string
+                        .push_str(core::str::from_utf8(&passkey).unwrap_or_default())
+                        .unwrap();
+                }
+                crate::bluetooth::PasskeyPrompt::Entry(typed) => {
+                    string.push_str("PIN: ").unwrap();
+                    for _ in 0..typed {
+                        string.push('*').unwrap();
+                    }
+                }
+            }
+            string
+        };
+
+        #[cfg(feature = "bluetooth")]
+        let contents = contents.append(text_box!(bounding_box, $text_type, &passkey_prompt));
+
         embedded_layout::layout::linear::LinearLayout::$direction(contents)
             .with_spacing(embedded_layout::layout::linear::FixedMargin($margin))
             .align_to(
@@ -117,6 +184,11 @@ pub enum Orientation {
 /// - Battery level (BAT): `nrf-ble` must be enabled.
 /// - Mode: `usb` and `bluetooth` enabled at the same time. See
 /// [`rumcake::bluetooth::BluetoothCommand::ToggleOutput`]
+/// - The highest active layer (LAYER). See [`rumcake::keyboard::LAYER_STATE`]
+/// - Bluetooth status (BT), including the active profile and the signal strength: `bluetooth`
+/// must be enabled. See [`rumcake::bluetooth::BLUETOOTH_STATUS_STATE`]
+/// - Passkey (PIN) for a pairing request: `bluetooth` must be enabled. See
+/// [`rumcake::bluetooth::PairingMode`]
 pub async fn on_update_default(
     display: &mut impl DrawTarget<Color = BinaryColor, Error = impl Debug>,
     orientation: Orientation,
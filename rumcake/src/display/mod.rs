@@ -15,6 +15,11 @@ use crate::hw::mcu::RawMutex;
 
 pub(crate) static OUTPUT_MODE_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
 pub(crate) static BATTERY_LEVEL_LISTENER: Signal<RawMutex, ()> = Signal::new();
+pub(crate) static LAYER_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+#[cfg(feature = "bluetooth")]
+pub(crate) static PASSKEY_PROMPT_LISTENER: Signal<RawMutex, ()> = Signal::new();
+#[cfg(feature = "bluetooth")]
+pub(crate) static BLUETOOTH_STATUS_LISTENER: Signal<RawMutex, ()> = Signal::new();
 
 /// A trait that keyboards must implement to use a display.
 pub trait DisplayDevice {
@@ -53,6 +58,11 @@ pub async fn display_task<K: DisplayDevice>(_k: K, mut display: impl DisplayDriv
                 let mut result = select_array([
                     OUTPUT_MODE_STATE_LISTENER.wait(),
                     BATTERY_LEVEL_LISTENER.wait(),
+                    LAYER_STATE_LISTENER.wait(),
+                    #[cfg(feature = "bluetooth")]
+                    PASSKEY_PROMPT_LISTENER.wait(),
+                    #[cfg(feature = "bluetooth")]
+                    BLUETOOTH_STATUS_LISTENER.wait(),
                 ])
                 .await;
                 result.1 += 1;
@@ -70,8 +80,9 @@ pub async fn display_task<K: DisplayDevice>(_k: K, mut display: impl DisplayDriv
             match select(update_fut, timer).await {
                 Either::First(((), idx)) => {
                     match idx {
-                        0 | 1 => {
-                            // Turn the display on in the event of a tick, or change in USB state.
+                        0 | 1 | 3 | 4 => {
+                            // Turn the display on in the event of a tick, change in USB state,
+                            // layer change, or a passkey prompt.
                             if !display_on {
                                 display.turn_on().await;
                                 display_on = true;
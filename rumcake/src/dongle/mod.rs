@@ -0,0 +1,410 @@
+//! Dongle role.
+//!
+//! A dongle is a USB device running `rumcake`, which receives HID reports from one or more
+//! wireless keyboards, and forwards them to its USB host. Keyboards can send their reports to the
+//! dongle over bluetooth, in which case the dongle connects to them as a bluetooth central, or
+//! using ESB (see [`crate::esb`]). Reports from multiple keyboards are merged, so keys can be held
+//! on different keyboards at the same time.
+//!
+//! The dongle also reports the battery level of its keyboards to the USB host, and sends lock LED
+//! changes from the USB host back to the keyboards.
+//!
+//! Keyboards using bluetooth must be paired with the dongle before they can be used. Pairing is
+//! opened automatically if no keyboards have been paired yet. Otherwise, it can be managed using
+//! [`DongleCommand`]s.
+//!
+//! This module is also used by keyboards, to send their reports to a dongle. Bluetooth keyboards
+//! that have the `dongle` feature enabled will send their HID reports to the dongle, instead of
+//! using the HID service, once the dongle connects to them.
+
+#[cfg(any(all(feature = "nrf", feature = "nrf-ble"), doc))]
+pub mod nrf_ble;
+
+use defmt::{debug, info, warn, Debug2Format};
+use embassy_futures::select::{select, Either};
+use embassy_sync::channel::Channel;
+use embassy_sync::pubsub::PubSubChannel;
+use embassy_sync::signal::Signal;
+use packed_struct::prelude::PackedStruct;
+use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
+use usbd_human_interface_device::device::consumer::MultipleConsumerReport;
+use usbd_human_interface_device::device::keyboard::NKROBootKeyboardReport;
+
+use crate::hw::mcu::RawMutex;
+use crate::hw::LED_INDICATORS_STATE;
+use crate::keyboard::{
+    queue_report, ReportOverflowPolicy, CONSUMER_REPORT_HID_SEND_CHANNEL,
+    KEYBOARD_REPORT_HID_SEND_CHANNEL,
+};
+use crate::system_control::{SystemControlReport, SYSTEM_CONTROL_REPORT_HID_SEND_CHANNEL};
+use crate::State;
+
+type KeyboardReportBytes = <NKROBootKeyboardReport as PackedStruct>::ByteArray;
+type ConsumerReportBytes = <MultipleConsumerReport as PackedStruct>::ByteArray;
+type SystemControlReportBytes = <SystemControlReport as PackedStruct>::ByteArray;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, MaxSize)]
+/// Possible messages that can be sent from a keyboard to a dongle.
+pub enum MessageToDongle {
+    /// A packed [`NKROBootKeyboardReport`].
+    Keyboard(KeyboardReportBytes),
+    /// A packed [`MultipleConsumerReport`].
+    Consumer(ConsumerReportBytes),
+    /// A packed [`SystemControlReport`].
+    SystemControl(SystemControlReportBytes),
+    /// Battery level of the keyboard, as a percentage from 0 to 100.
+    BatteryLevel(u8),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, MaxSize)]
+/// Possible messages that can be sent from a dongle to a keyboard. When using ESB, these are
+/// attached to the acknowledgement of each packet received by the dongle.
+pub enum MessageToKeyboard {
+    /// Lock LEDs reported by the dongle's USB host. Contains the bits of a
+    /// [`crate::hw::LedIndicators`].
+    LedIndicators(u8),
+}
+
+/// Size of the buffer used to send a [`MessageToDongle`] over bluetooth.
+pub const MESSAGE_TO_DONGLE_BUFFER_SIZE: usize = MessageToDongle::POSTCARD_MAX_SIZE + 3;
+
+/// Size of the buffer used to send a [`MessageToKeyboard`] over bluetooth.
+pub const MESSAGE_TO_KEYBOARD_BUFFER_SIZE: usize = MessageToKeyboard::POSTCARD_MAX_SIZE + 3;
+
+/// UUID of the dongle service (`7A4E0001-3C8D-4B5F-9A61-2D0E8F1B6C93`), registered by bluetooth
+/// keyboards.
+pub(crate) const DONGLE_SERVICE_UUID: [u8; 16] = [
+    0x93, 0x6C, 0x1B, 0x8F, 0x0E, 0x2D, 0x61, 0x9A, 0x5F, 0x4B, 0x8D, 0x3C, 0x01, 0x00, 0x4E, 0x7A,
+];
+
+/// UUID of the characteristic used to notify the dongle of a [`MessageToDongle`]
+/// (`7A4E0002-3C8D-4B5F-9A61-2D0E8F1B6C93`).
+pub(crate) const MESSAGE_TO_DONGLE_UUID: [u8; 16] = [
+    0x93, 0x6C, 0x1B, 0x8F, 0x0E, 0x2D, 0x61, 0x9A, 0x5F, 0x4B, 0x8D, 0x3C, 0x02, 0x00, 0x4E, 0x7A,
+];
+
+/// UUID of the characteristic written by the dongle to send a [`MessageToKeyboard`]
+/// (`7A4E0003-3C8D-4B5F-9A61-2D0E8F1B6C93`).
+pub(crate) const MESSAGE_TO_KEYBOARD_UUID: [u8; 16] = [
+    0x93, 0x6C, 0x1B, 0x8F, 0x0E, 0x2D, 0x61, 0x9A, 0x5F, 0x4B, 0x8D, 0x3C, 0x03, 0x00, 0x4E, 0x7A,
+];
+
+/// Maximum number of keyboards that can be paired with a dongle over bluetooth.
+pub const MAX_BLE_KEYBOARDS: usize = 3;
+
+/// Keyboard slot used by the ESB keyboard. The slots before it are used by bluetooth keyboards.
+pub const ESB_KEYBOARD_SLOT: usize = MAX_BLE_KEYBOARDS;
+
+/// Number of keyboards that can be connected to a dongle at the same time.
+pub const KEYBOARD_SLOTS: usize = MAX_BLE_KEYBOARDS + 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Status of the keyboard using a slot of the dongle.
+pub struct KeyboardStatus {
+    /// Whether the keyboard is currently connected to the dongle.
+    pub connected: bool,
+    /// Battery level last reported by the keyboard, as a percentage from 0 to 100.
+    pub battery_level: Option<u8>,
+}
+
+const DISCONNECTED: KeyboardStatus = KeyboardStatus {
+    connected: false,
+    battery_level: None,
+};
+
+/// State that contains the status of the keyboard using each slot of the dongle.
+pub static KEYBOARDS_STATE: State<[KeyboardStatus; KEYBOARD_SLOTS]> = State::new(
+    [DISCONNECTED; KEYBOARD_SLOTS],
+    &[
+        #[cfg(feature = "usb")]
+        &crate::usb::DONGLE_KEYBOARDS_STATE_LISTENER,
+    ],
+);
+
+/// Returns the lowest battery level reported by the connected keyboards, if any of them have
+/// reported one.
+pub fn lowest_battery_level(keyboards: &[KeyboardStatus]) -> Option<u8> {
+    keyboards
+        .iter()
+        .filter(|keyboard| keyboard.connected)
+        .filter_map(|keyboard| keyboard.battery_level)
+        .min()
+}
+
+#[derive(Debug, Clone, Copy)]
+/// Commands used to manage the keyboards paired with a dongle over bluetooth.
+pub enum DongleCommand {
+    /// Pair with the next keyboard found advertising nearby, using the first free slot. Pairing
+    /// is closed after a keyboard has been paired, or after 60 seconds.
+    OpenPairing,
+    /// Forget the keyboard paired with the given slot.
+    Unpair(u8),
+    /// Forget all paired keyboards.
+    UnpairAll,
+}
+
+/// Channel for sending [`DongleCommand`]s.
+///
+/// Channel messages should be consumed by the dongle's bluetooth task, so user-level code should
+/// **not** attempt to receive messages from the channel, otherwise commands may not be processed
+/// appropriately. You should only send to this channel.
+pub static DONGLE_COMMAND_CHANNEL: Channel<RawMutex, DongleCommand, 2> = Channel::new();
+
+/// Events sent by a transport to [`dongle_task`] for the keyboard using a slot.
+pub(crate) enum DongleEvent {
+    Connected,
+    Message(MessageToDongle),
+    Disconnected,
+}
+
+/// Channel used by the transports to send [`DongleEvent`]s, along with the slot of the keyboard
+/// that they came from.
+pub(crate) static DONGLE_EVENT_CHANNEL: Channel<RawMutex, (usize, DongleEvent), 16> =
+    Channel::new();
+
+/// Messages to send to every keyboard connected to the dongle over bluetooth.
+pub(crate) static MESSAGES_TO_KEYBOARDS: PubSubChannel<
+    RawMutex,
+    MessageToKeyboard,
+    4,
+    MAX_BLE_KEYBOARDS,
+    1,
+> = PubSubChannel::new();
+
+pub(crate) static LED_INDICATORS_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+/// Report descriptor used to report the battery level of the dongle's keyboards to the USB host.
+pub(crate) const BATTERY_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x06, // Usage Page (Generic Device Controls)
+    0x09, 0x20, // Usage (Battery Strength)
+    0xA1, 0x01, // Collection (Application)
+    0x09, 0x20, //   Usage (Battery Strength)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x64, //   Logical Maximum (100)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x01, //   Report Count (1)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0xC0, // End Collection
+];
+
+/// Size of the reports described by [`BATTERY_REPORT_DESCRIPTOR`].
+pub const BATTERY_REPORT_SIZE: usize = 1;
+
+/// Last reports received from the keyboard using a slot.
+#[derive(Default)]
+struct KeyboardReports {
+    keyboard: KeyboardReportBytes,
+    consumer: ConsumerReportBytes,
+    system_control: SystemControlReportBytes,
+}
+
+/// Byte offset of the boot keys in a packed [`NKROBootKeyboardReport`]. The boot keys are
+/// preceded by the modifiers and a reserved byte, and followed by the NKRO bitmap.
+const BOOT_KEYS_START: usize = 2;
+const BOOT_KEYS_END: usize = BOOT_KEYS_START + 6;
+
+/// Usage reported in every boot key when too many keys are held to fit in a boot report.
+const KEYBOARD_ERROR_ROLL_OVER: u8 = 0x01;
+
+/// Combine the keyboard reports of every slot, so that keys held on one keyboard are not released
+/// by a report from another keyboard.
+fn merge_keyboard_reports(reports: &[KeyboardReports]) -> KeyboardReportBytes {
+    let mut merged: KeyboardReportBytes = Default::default();
+    let mut boot_keys = 0;
+
+    for report in reports.iter().map(|reports| &reports.keyboard) {
+        // Modifiers
+        merged[0] |= report[0];
+
+        // NKRO bitmap
+        for (bits, byte) in merged[BOOT_KEYS_END..]
+            .iter_mut()
+            .zip(&report[BOOT_KEYS_END..])
+        {
+            *bits |= byte;
+        }
+
+        for &key in &report[BOOT_KEYS_START..BOOT_KEYS_END] {
+            if key == 0 || merged[BOOT_KEYS_START..BOOT_KEYS_START + boot_keys].contains(&key) {
+                continue;
+            }
+
+            if BOOT_KEYS_START + boot_keys == BOOT_KEYS_END {
+                merged[BOOT_KEYS_START..BOOT_KEYS_END].fill(KEYBOARD_ERROR_ROLL_OVER);
+                break;
+            }
+
+            merged[BOOT_KEYS_START + boot_keys] = key;
+            boot_keys += 1;
+        }
+    }
+
+    merged
+}
+
+/// Combine the consumer reports of every slot. Each consumer report contains up to 4 little
+/// endian usage codes.
+fn merge_consumer_reports(reports: &[KeyboardReports]) -> ConsumerReportBytes {
+    let mut merged: ConsumerReportBytes = Default::default();
+    let mut len = 0;
+
+    for code in reports
+        .iter()
+        .flat_map(|reports| reports.consumer.chunks_exact(2))
+        .filter(|code| code.iter().any(|&byte| byte != 0))
+    {
+        if len == merged.len() {
+            break;
+        }
+
+        if merged[..len].chunks_exact(2).any(|merged| merged == code) {
+            continue;
+        }
+
+        merged[len..len + 2].copy_from_slice(code);
+        len += 2;
+    }
+
+    merged
+}
+
+/// Combine the system control reports of every slot. Only one system control key can be reported
+/// at a time, so the first one found is used.
+fn merge_system_control_reports(reports: &[KeyboardReports]) -> SystemControlReportBytes {
+    reports
+        .iter()
+        .map(|reports| reports.system_control)
+        .find(|report| report.iter().any(|&byte| byte != 0))
+        .unwrap_or_default()
+}
+
+/// Store a report received from a keyboard. Returns `false` if the report is invalid.
+fn store_report(reports: &mut KeyboardReports, message: MessageToDongle) -> bool {
+    match message {
+        MessageToDongle::Keyboard(bytes) => {
+            if let Err(err) = NKROBootKeyboardReport::unpack(&bytes) {
+                warn!("[DONGLE] Invalid keyboard report: {:?}", Debug2Format(&err));
+                return false;
+            }
+            reports.keyboard = bytes;
+        }
+        MessageToDongle::Consumer(bytes) => {
+            if let Err(err) = MultipleConsumerReport::unpack(&bytes) {
+                warn!("[DONGLE] Invalid consumer report: {:?}", Debug2Format(&err));
+                return false;
+            }
+            reports.consumer = bytes;
+        }
+        MessageToDongle::SystemControl(bytes) => {
+            if let Err(err) = SystemControlReport::unpack(&bytes) {
+                warn!(
+                    "[DONGLE] Invalid system control report: {:?}",
+                    Debug2Format(&err)
+                );
+                return false;
+            }
+            reports.system_control = bytes;
+        }
+        MessageToDongle::BatteryLevel(_) => return false,
+    }
+
+    true
+}
+
+/// Reports last sent to the USB host.
+#[derive(Default)]
+struct MergedReports {
+    keyboard: KeyboardReportBytes,
+    consumer: ConsumerReportBytes,
+    system_control: SystemControlReportBytes,
+}
+
+/// Merge the reports of every slot, and send them to the USB host if they have changed. Since
+/// reports are validated by [`store_report`] before they are merged, the merged reports can
+/// always be unpacked.
+fn send_merged_reports(reports: &[KeyboardReports], last: &mut MergedReports) {
+    let policy = ReportOverflowPolicy::DropOldest;
+
+    let keyboard = merge_keyboard_reports(reports);
+    if keyboard != last.keyboard {
+        last.keyboard = keyboard;
+        let report = NKROBootKeyboardReport::unpack(&keyboard).unwrap();
+        queue_report(&KEYBOARD_REPORT_HID_SEND_CHANNEL, report, policy);
+    }
+
+    let consumer = merge_consumer_reports(reports);
+    if consumer != last.consumer {
+        last.consumer = consumer;
+        let report = MultipleConsumerReport::unpack(&consumer).unwrap();
+        queue_report(&CONSUMER_REPORT_HID_SEND_CHANNEL, report, policy);
+    }
+
+    let system_control = merge_system_control_reports(reports);
+    if system_control != last.system_control {
+        last.system_control = system_control;
+        let report = SystemControlReport::unpack(&system_control).unwrap();
+        queue_report(&SYSTEM_CONTROL_REPORT_HID_SEND_CHANNEL, report, policy);
+    }
+}
+
+/// Task that merges the HID reports received from the dongle's keyboards, and forwards them to
+/// the USB host. This also keeps track of the status of each keyboard, and sends lock LED changes
+/// to the keyboards.
+#[rumcake_macros::task]
+pub async fn dongle_task() {
+    let mut reports: [KeyboardReports; KEYBOARD_SLOTS] = Default::default();
+    let mut last = MergedReports::default();
+    let publisher = MESSAGES_TO_KEYBOARDS.immediate_publisher();
+
+    info!("[DONGLE] Dongle started");
+
+    loop {
+        let (slot, event) = match select(
+            DONGLE_EVENT_CHANNEL.receive(),
+            LED_INDICATORS_STATE_LISTENER.wait(),
+        )
+        .await
+        {
+            Either::First(event) => event,
+            Either::Second(()) => {
+                let leds = LED_INDICATORS_STATE.get().await;
+                publisher.publish_immediate(MessageToKeyboard::LedIndicators(leds.bits()));
+                continue;
+            }
+        };
+
+        match event {
+            DongleEvent::Connected => {
+                info!("[DONGLE] Keyboard connected to slot {}", slot);
+                KEYBOARDS_STATE
+                    .update(|keyboards| keyboards[slot].connected = true)
+                    .await;
+            }
+            DongleEvent::Message(MessageToDongle::BatteryLevel(level)) => {
+                debug!("[DONGLE] Keyboard {} battery level: {}%", slot, level);
+                KEYBOARDS_STATE
+                    .update(|keyboards| keyboards[slot].battery_level = Some(level))
+                    .await;
+            }
+            DongleEvent::Message(message) => {
+                if store_report(&mut reports[slot], message) {
+                    send_merged_reports(&reports, &mut last);
+                }
+            }
+            DongleEvent::Disconnected => {
+                if !KEYBOARDS_STATE.get().await[slot].connected {
+                    continue;
+                }
+
+                // Release any keys that were held on the keyboard when it went out of range
+                warn!("[DONGLE] Keyboard disconnected from slot {}", slot);
+                reports[slot] = KeyboardReports::default();
+                send_merged_reports(&reports, &mut last);
+                KEYBOARDS_STATE
+                    .update(|keyboards| keyboards[slot] = DISCONNECTED)
+                    .await;
+            }
+        }
+    }
+}
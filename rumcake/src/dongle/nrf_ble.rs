@@ -0,0 +1,661 @@
+//! Bluetooth transport for the dongle role, using [`nrf-softdevice`].
+//!
+//! The dongle connects to its keyboards as a bluetooth central, and receives their HID reports
+//! through the dongle service, which is registered by bluetooth keyboards that have the `dongle`
+//! feature enabled.
+
+use core::cell::Cell;
+
+use defmt::{debug, error, info, warn, Debug2Format};
+use embassy_futures::join::join;
+use embassy_futures::select::{select, select3, select_slice, Either};
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Timer};
+use heapless::Vec;
+use nrf_softdevice::ble::central::{self, connect_with_security};
+use nrf_softdevice::ble::gatt_client::{self, discover};
+use nrf_softdevice::ble::security::{IoCapabilities, SecurityHandler};
+use nrf_softdevice::ble::{
+    Address, AddressType, Connection, EncryptionInfo, IdentityKey, IdentityResolutionKey, MasterId,
+    SecurityMode,
+};
+use nrf_softdevice::Softdevice;
+use serde::{Deserialize, Serialize};
+use static_cell::StaticCell;
+
+use crate::hw::mcu::{BlockingMutex, RawMutex};
+use crate::hw::LED_INDICATORS_STATE;
+use crate::State;
+
+use super::{
+    DongleCommand, DongleEvent, MessageToDongle, MessageToKeyboard, DONGLE_COMMAND_CHANNEL,
+    DONGLE_EVENT_CHANNEL, MAX_BLE_KEYBOARDS, MESSAGES_TO_KEYBOARDS, MESSAGE_TO_DONGLE_BUFFER_SIZE,
+    MESSAGE_TO_KEYBOARD_BUFFER_SIZE,
+};
+
+/// How long pairing stays open after [`DongleCommand::OpenPairing`].
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long to scan for a keyboard before releasing the scanner, so that other keyboards get a
+/// chance to connect.
+const SCAN_WINDOW: Duration = Duration::from_secs(2);
+
+/// How long to wait for the connection with a keyboard to be encrypted, or for a new keyboard to
+/// bond with the dongle.
+const SECURITY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// ATT MTU requested when connecting to a keyboard. This must fit a [`MessageToDongle`] in a
+/// single notification, which has a 3 byte header.
+const ATT_MTU: u16 = MESSAGE_TO_DONGLE_BUFFER_SIZE as u16 + 3;
+
+/// Bond information for a keyboard paired with the dongle.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PairedKeyboard {
+    ediv: u16,
+    rand: [u8; 8],
+    ltk: [u8; 16],
+    ltk_flags: u8,
+    irk: [u8; 16],
+    address_type: u8,
+    address: [u8; 6],
+}
+
+impl PairedKeyboard {
+    fn new(master_id: MasterId, key: EncryptionInfo, peer_id: IdentityKey) -> Self {
+        Self {
+            ediv: master_id.ediv,
+            rand: master_id.rand,
+            ltk: key.ltk,
+            ltk_flags: key.flags,
+            irk: peer_id.irk.as_raw().irk,
+            address_type: peer_id.addr.address_type() as u8,
+            address: peer_id.addr.bytes(),
+        }
+    }
+
+    fn master_id(&self) -> MasterId {
+        MasterId {
+            ediv: self.ediv,
+            rand: self.rand,
+        }
+    }
+
+    fn key(&self) -> EncryptionInfo {
+        EncryptionInfo {
+            ltk: self.ltk,
+            flags: self.ltk_flags,
+        }
+    }
+
+    fn identity(&self) -> Option<IdentityKey> {
+        let address_type = AddressType::try_from(self.address_type).ok()?;
+
+        Some(IdentityKey {
+            irk: IdentityResolutionKey::from_raw(nrf_softdevice::raw::ble_gap_irk_t {
+                irk: self.irk,
+            }),
+            addr: Address::new(address_type, self.address),
+        })
+    }
+
+    fn is_match(&self, address: Address) -> bool {
+        self.identity()
+            .is_some_and(|identity| identity.is_match(address))
+    }
+}
+
+/// Keyboards paired with the dongle, and the slots that they use.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PairedKeyboards {
+    keyboards: [Option<PairedKeyboard>; MAX_BLE_KEYBOARDS],
+}
+
+impl PairedKeyboards {
+    /// Returns `true` if a keyboard is paired with the given slot.
+    pub fn is_paired(&self, slot: u8) -> bool {
+        self.keyboards
+            .get(slot as usize)
+            .is_some_and(|keyboard| keyboard.is_some())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.keyboards.iter().all(Option::is_none)
+    }
+
+    fn free_slot(&self) -> Option<usize> {
+        self.keyboards.iter().position(Option::is_none)
+    }
+}
+
+#[cfg(feature = "storage")]
+impl crate::storage::StoredData for PairedKeyboards {
+    const SCHEMA_VERSION: u16 = 1;
+}
+
+const NO_KEYBOARD: Option<PairedKeyboard> = None;
+
+/// State that contains the keyboards paired with the dongle. Changes should be made using
+/// [`DongleCommand`]s.
+pub static PAIRED_KEYBOARDS_STATE: State<PairedKeyboards> = State::new(
+    PairedKeyboards {
+        keyboards: [NO_KEYBOARD; MAX_BLE_KEYBOARDS],
+    },
+    &[
+        &PAIRED_KEYBOARDS_STATE_LISTENER,
+        #[cfg(feature = "storage")]
+        &storage::PAIRED_KEYBOARDS_STATE_STORAGE_LISTENER,
+    ],
+);
+
+static PAIRED_KEYBOARDS_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+/// Whether pairing was opened with [`DongleCommand::OpenPairing`].
+static OPEN_PAIRING: BlockingMutex<Cell<bool>> = BlockingMutex::new(Cell::new(false));
+
+static OPEN_PAIRING_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+/// Slot that will be used by the keyboard that is currently being paired, if any.
+static PAIRING_SLOT: BlockingMutex<Cell<Option<usize>>> = BlockingMutex::new(Cell::new(None));
+
+/// The softdevice can only scan for one set of keyboards at a time.
+static SCANNER_MUTEX: Mutex<RawMutex, ()> = Mutex::new(());
+
+async fn update_paired_keyboards(f: impl FnOnce(&mut PairedKeyboards)) {
+    PAIRED_KEYBOARDS_STATE.update(|paired| f(paired)).await;
+
+    // Pairing changes are infrequent, so save them immediately
+    #[cfg(feature = "storage")]
+    storage::PAIRED_KEYBOARDS_SAVE_SIGNAL.signal(());
+}
+
+struct Bonder;
+
+impl SecurityHandler for Bonder {
+    fn io_capabilities(&self) -> IoCapabilities {
+        IoCapabilities::None
+    }
+
+    fn can_bond(&self, _conn: &Connection) -> bool {
+        PAIRING_SLOT.lock(Cell::get).is_some()
+    }
+
+    fn on_security_update(&self, _conn: &Connection, security_mode: SecurityMode) {
+        debug!(
+            "[DONGLE_BT] new security mode: {}",
+            Debug2Format(&security_mode)
+        );
+    }
+
+    fn on_bonded(
+        &self,
+        _conn: &Connection,
+        master_id: MasterId,
+        key: EncryptionInfo,
+        peer_id: IdentityKey,
+    ) {
+        let Some(slot) = PAIRING_SLOT.lock(Cell::take) else {
+            return;
+        };
+
+        debug!(
+            "[DONGLE_BT] storing bond for slot {}: id: {}",
+            slot, master_id
+        );
+
+        let stored = PAIRED_KEYBOARDS_STATE.try_get().is_some_and(|mut paired| {
+            paired.keyboards[slot] = Some(PairedKeyboard::new(master_id, key, peer_id));
+            PAIRED_KEYBOARDS_STATE.try_set(paired)
+        });
+
+        if stored {
+            // Losing a bond would require the keyboard to be paired again
+            #[cfg(feature = "storage")]
+            storage::PAIRED_KEYBOARDS_SAVE_SIGNAL.signal(());
+        } else {
+            error!("[DONGLE_BT] Could not store bond");
+        }
+    }
+
+    fn get_peripheral_key(&self, conn: &Connection) -> Option<(MasterId, EncryptionInfo)> {
+        // Reconnecting to a paired keyboard
+        PAIRED_KEYBOARDS_STATE.try_get().and_then(|paired| {
+            paired
+                .keyboards
+                .iter()
+                .flatten()
+                .find(|keyboard| keyboard.is_match(conn.peer_address()))
+                .map(|keyboard| (keyboard.master_id(), keyboard.key()))
+        })
+    }
+}
+
+#[nrf_softdevice::gatt_client(uuid = "7a4e0001-3c8d-4b5f-9a61-2d0e8f1b6c93")]
+struct DongleServiceClient {
+    #[characteristic(uuid = "7a4e0002-3c8d-4b5f-9a61-2d0e8f1b6c93", read, notify)]
+    message_to_dongle: [u8; MESSAGE_TO_DONGLE_BUFFER_SIZE],
+
+    #[characteristic(uuid = "7a4e0003-3c8d-4b5f-9a61-2d0e8f1b6c93", write)]
+    message_to_keyboard: [u8; MESSAGE_TO_KEYBOARD_BUFFER_SIZE],
+}
+
+/// Returns `true` if the advertising data contains the HID service.
+fn is_keyboard_advertisement(mut data: &[u8]) -> bool {
+    while let [len, rest @ ..] = data {
+        let len = *len as usize;
+        if len == 0 || len > rest.len() {
+            break;
+        }
+
+        let (field, remaining) = rest.split_at(len);
+
+        // Incomplete or complete list of 16 bit services
+        if matches!(field[0], 0x02 | 0x03)
+            && field[1..].chunks_exact(2).any(|uuid| *uuid == [0x12, 0x18])
+        {
+            return true;
+        }
+
+        data = remaining;
+    }
+
+    false
+}
+
+/// Scan for a keyboard that is advertising nearby, skipping the keyboards in `ignored`.
+async fn scan_for_keyboard(sd: &'static Softdevice, ignored: &[Address]) -> Address {
+    loop {
+        let config = central::ScanConfig::default();
+
+        let result = {
+            let _lock = SCANNER_MUTEX.lock().await;
+            with_timeout(
+                SCAN_WINDOW,
+                central::scan(sd, &config, |report| {
+                    let address = Address::from_raw(report.peer_addr);
+                    let data = unsafe {
+                        core::slice::from_raw_parts(report.data.p_data, report.data.len as usize)
+                    };
+
+                    (is_keyboard_advertisement(data) && !ignored.contains(&address))
+                        .then_some(address)
+                }),
+            )
+            .await
+        };
+
+        match result {
+            Ok(Ok(address)) => return address,
+            Ok(Err(error)) => {
+                warn!("[DONGLE_BT] BLE scan error: {}", Debug2Format(&error));
+            }
+            Err(_) => {}
+        }
+
+        // Give other keyboards a chance to use the scanner
+        Timer::after(Duration::from_millis(50)).await;
+    }
+}
+
+/// Try to connect to the keyboard with the given address. Returns `None` if the keyboard could
+/// not be found within [`SCAN_WINDOW`].
+async fn connect(
+    sd: &'static Softdevice,
+    address: Address,
+    bonder: &'static Bonder,
+) -> Option<Connection> {
+    let whitelist = [&address];
+    let mut config = central::ConnectConfig::default();
+    config.scan_config.whitelist = Some(&whitelist);
+    config.conn_params.min_conn_interval = 6;
+    config.conn_params.max_conn_interval = 6;
+    config.att_mtu = Some(ATT_MTU);
+
+    let result = {
+        let _lock = SCANNER_MUTEX.lock().await;
+        with_timeout(SCAN_WINDOW, connect_with_security(sd, &config, bonder)).await
+    };
+
+    match result {
+        Ok(Ok(connection)) => Some(connection),
+        Ok(Err(error)) => {
+            warn!("[DONGLE_BT] BLE connection error: {}", Debug2Format(&error));
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+/// Wait for the connection to be encrypted.
+async fn wait_for_encryption(connection: &Connection) -> bool {
+    with_timeout(SECURITY_TIMEOUT, async {
+        while connection.security_mode() == SecurityMode::Open {
+            Timer::after(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .is_ok()
+}
+
+async fn write_message(client: &DongleServiceClient, message: MessageToKeyboard) {
+    let mut buf = [0; MESSAGE_TO_KEYBOARD_BUFFER_SIZE];
+    postcard::to_slice_cobs(&message, &mut buf).unwrap();
+
+    debug!(
+        "[DONGLE_BT] Writing message to keyboard: {:?}",
+        Debug2Format(&message)
+    );
+
+    if let Err(err) = client.message_to_keyboard_write(&buf).await {
+        error!(
+            "[DONGLE_BT] Couldn't write message to keyboard: {:?}",
+            Debug2Format(&err)
+        );
+    }
+}
+
+/// Receive messages from a connected keyboard, and forward them to the dongle task. Returns when
+/// the connection is lost.
+async fn run_connection(slot: usize, connection: &Connection) {
+    // Encrypt the connection using the bond that was created during pairing
+    if let Err(error) = connection.request_security() {
+        warn!(
+            "[DONGLE_BT] Could not encrypt connection with keyboard {}: {}",
+            slot,
+            Debug2Format(&error)
+        );
+        return;
+    }
+
+    if !wait_for_encryption(connection).await {
+        warn!(
+            "[DONGLE_BT] Connection with keyboard {} was not encrypted. The keyboard may need to be paired again.",
+            slot
+        );
+        return;
+    }
+
+    let client: DongleServiceClient = match discover(connection).await {
+        Ok(client) => client,
+        Err(error) => {
+            warn!(
+                "[DONGLE_BT] BLE GATT discovery error: {}",
+                Debug2Format(&error)
+            );
+            return;
+        }
+    };
+
+    // Enabling notifications makes the keyboard send its reports to us instead of using the HID
+    // service
+    if let Err(error) = client.message_to_dongle_cccd_write(true).await {
+        warn!(
+            "[DONGLE_BT] Could not subscribe to keyboard {}: {}",
+            slot,
+            Debug2Format(&error)
+        );
+        return;
+    }
+
+    let mut subscriber = MESSAGES_TO_KEYBOARDS.subscriber().unwrap();
+    DONGLE_EVENT_CHANNEL
+        .send((slot, DongleEvent::Connected))
+        .await;
+
+    let leds = LED_INDICATORS_STATE.get().await;
+    write_message(&client, MessageToKeyboard::LedIndicators(leds.bits())).await;
+
+    let client_fut = gatt_client::run(connection, &client, |event| match event {
+        DongleServiceClientEvent::MessageToDongleNotification(mut message) => {
+            match postcard::from_bytes_cobs::<MessageToDongle>(&mut message) {
+                Ok(message) => {
+                    if DONGLE_EVENT_CHANNEL
+                        .try_send((slot, DongleEvent::Message(message)))
+                        .is_err()
+                    {
+                        error!(
+                            "[DONGLE_BT] Could not consume message from keyboard {}: {:?}",
+                            slot,
+                            Debug2Format(&message)
+                        );
+                    }
+                }
+                Err(err) => {
+                    warn!(
+                        "[DONGLE_BT] Invalid message from keyboard {}: {:?}",
+                        slot,
+                        Debug2Format(&err)
+                    );
+                }
+            }
+        }
+    });
+
+    let subscriber_fut = async {
+        loop {
+            let message = subscriber.next_message_pure().await;
+            write_message(&client, message).await;
+        }
+    };
+
+    if let Either::First(error) = select(client_fut, subscriber_fut).await {
+        warn!(
+            "[DONGLE_BT] Connection to keyboard {} lost: {}",
+            slot,
+            Debug2Format(&error)
+        );
+    }
+}
+
+/// Keep the keyboard paired with the given slot connected.
+async fn keyboard_connection(
+    sd: &'static Softdevice,
+    bonder: &'static Bonder,
+    slot: usize,
+    keyboard: Option<PairedKeyboard>,
+) {
+    let Some(identity) = keyboard.as_ref().and_then(PairedKeyboard::identity) else {
+        return core::future::pending().await;
+    };
+
+    loop {
+        let Some(connection) = connect(sd, identity.addr, bonder).await else {
+            // Give other keyboards a chance to use the scanner
+            Timer::after(Duration::from_millis(50)).await;
+            continue;
+        };
+
+        info!("[DONGLE_BT] Connection established with keyboard {}", slot);
+        run_connection(slot, &connection).await;
+
+        DONGLE_EVENT_CHANNEL
+            .send((slot, DongleEvent::Disconnected))
+            .await;
+    }
+}
+
+/// Pair the next rumcake keyboard found advertising nearby with the given slot.
+async fn pair_keyboard(sd: &'static Softdevice, bonder: &'static Bonder, slot: usize) {
+    // Devices that were found advertising, but are not rumcake keyboards
+    let mut ignored: Vec<Address, 8> = Vec::new();
+
+    loop {
+        let address = scan_for_keyboard(sd, &ignored).await;
+        let Some(connection) = connect(sd, address, bonder).await else {
+            continue;
+        };
+
+        // Keyboards that can't send their reports to the dongle don't have the dongle service
+        if discover::<DongleServiceClient>(&connection).await.is_err() {
+            info!("[DONGLE_BT] Ignoring device without the dongle service");
+            if ignored.push(address).is_err() {
+                ignored.clear();
+            }
+            continue;
+        }
+
+        info!("[DONGLE_BT] Pairing keyboard with slot {}", slot);
+        PAIRING_SLOT.lock(|pairing_slot| pairing_slot.set(Some(slot)));
+
+        if let Err(error) = connection.request_security() {
+            warn!(
+                "[DONGLE_BT] Could not request pairing: {}",
+                Debug2Format(&error)
+            );
+            PAIRING_SLOT.lock(|pairing_slot| pairing_slot.set(None));
+            continue;
+        }
+
+        // The slot is taken once the bond has been stored
+        let bonded = with_timeout(SECURITY_TIMEOUT, async {
+            while PAIRING_SLOT.lock(Cell::get).is_some() {
+                Timer::after(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .is_ok();
+
+        if bonded {
+            info!("[DONGLE_BT] Keyboard paired with slot {}", slot);
+            return;
+        }
+
+        warn!("[DONGLE_BT] Pairing failed. Make sure that the keyboard is using a bluetooth profile that is not bonded yet.");
+        PAIRING_SLOT.lock(|pairing_slot| pairing_slot.set(None));
+    }
+}
+
+/// Open pairing when requested, or when no keyboards have been paired yet.
+async fn pairing(sd: &'static Softdevice, bonder: &'static Bonder) {
+    loop {
+        let paired = PAIRED_KEYBOARDS_STATE.get().await;
+
+        // Pairing is always open while no keyboards have been paired
+        let always_open = paired.is_empty();
+        if !always_open && !OPEN_PAIRING.lock(Cell::get) {
+            OPEN_PAIRING_SIGNAL.wait().await;
+            continue;
+        }
+
+        let Some(slot) = paired.free_slot() else {
+            warn!("[DONGLE_BT] All slots are in use. Unpair a keyboard to pair a new one.");
+            OPEN_PAIRING.lock(|open_pairing| open_pairing.set(false));
+            continue;
+        };
+
+        info!(
+            "[DONGLE_BT] Looking for a keyboard to pair with slot {}",
+            slot
+        );
+
+        if always_open {
+            pair_keyboard(sd, bonder, slot).await;
+        } else if with_timeout(PAIRING_TIMEOUT, pair_keyboard(sd, bonder, slot))
+            .await
+            .is_err()
+        {
+            info!("[DONGLE_BT] No keyboard was paired, closing pairing");
+        }
+
+        OPEN_PAIRING.lock(|open_pairing| open_pairing.set(false));
+    }
+}
+
+/// Task that connects to the keyboards paired with the dongle, and forwards their HID reports
+/// to the dongle task. This also handles [`DongleCommand`]s.
+#[rumcake_macros::task]
+pub async fn nrf_ble_dongle_task(sd: &'static Softdevice) {
+    static BONDER: StaticCell<Bonder> = StaticCell::new();
+    let bonder = BONDER.init(Bonder);
+
+    info!("[DONGLE_BT] Bluetooth services started");
+
+    let connections_fut = async {
+        loop {
+            let paired = PAIRED_KEYBOARDS_STATE.get().await;
+
+            let mut keyboard_futs = paired
+                .keyboards
+                .iter()
+                .enumerate()
+                .map(|(slot, keyboard)| keyboard_connection(sd, bonder, slot, keyboard.clone()))
+                .collect::<Vec<_, MAX_BLE_KEYBOARDS>>();
+
+            select3(
+                select_slice(&mut keyboard_futs),
+                pairing(sd, bonder),
+                PAIRED_KEYBOARDS_STATE_LISTENER.wait(),
+            )
+            .await;
+
+            // A keyboard was paired or unpaired, so reconnect using the new bonds
+            drop(keyboard_futs);
+            for slot in 0..MAX_BLE_KEYBOARDS {
+                DONGLE_EVENT_CHANNEL
+                    .send((slot, DongleEvent::Disconnected))
+                    .await;
+            }
+        }
+    };
+
+    let command_fut = async {
+        loop {
+            match DONGLE_COMMAND_CHANNEL.receive().await {
+                DongleCommand::OpenPairing => {
+                    info!("[DONGLE_BT] Opening pairing");
+                    OPEN_PAIRING.lock(|open_pairing| open_pairing.set(true));
+                    OPEN_PAIRING_SIGNAL.signal(());
+                }
+                DongleCommand::Unpair(slot) => {
+                    if slot as usize >= MAX_BLE_KEYBOARDS {
+                        warn!("[DONGLE_BT] Slot {} does not exist", slot);
+                        continue;
+                    }
+
+                    update_paired_keyboards(|paired| paired.keyboards[slot as usize] = None).await;
+                }
+                DongleCommand::UnpairAll => {
+                    update_paired_keyboards(|paired| paired.keyboards.fill(NO_KEYBOARD)).await;
+                }
+            }
+        }
+    };
+
+    join(command_fut, connections_fut).await;
+}
+
+#[cfg(feature = "storage")]
+pub mod storage {
+    use embassy_sync::signal::Signal;
+
+    use crate::hw::mcu::RawMutex;
+    use crate::storage::{FlashStorage, StorageDevice};
+
+    use super::PAIRED_KEYBOARDS_STATE;
+
+    pub(super) static PAIRED_KEYBOARDS_STATE_STORAGE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+    /// Signal used to save the paired keyboards immediately, instead of waiting for the save
+    /// policy defined in [`StorageDevice`].
+    pub(super) static PAIRED_KEYBOARDS_SAVE_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+    /// Task that restores the keyboards paired with the dongle, and saves any changes to them, so
+    /// that keyboards do not need to be paired again after a reboot.
+    #[rumcake_macros::task]
+    pub async fn dongle_paired_keyboards_storage_task<K: StorageDevice, F: FlashStorage>(
+        _k: K,
+        database: &crate::storage::StorageService<'static, F>,
+    ) where
+        [(); F::ERASE_SIZE]:,
+        [(); F::REGION_SIZE]:,
+    {
+        database
+            .persist_state::<K, _>(
+                crate::storage::StorageKey::DonglePairedKeyboards,
+                &PAIRED_KEYBOARDS_STATE,
+                &PAIRED_KEYBOARDS_STATE_STORAGE_LISTENER,
+                &PAIRED_KEYBOARDS_SAVE_SIGNAL,
+            )
+            .await
+    }
+}
@@ -0,0 +1,339 @@
+//! Rumcake driver implementations for 2-axis analog joysticks (e.g. thumbsticks), read using an
+//! ADC.
+//!
+//! This driver provides an implementation for [`PointingDevice`], so it can be used with the
+//! pointing feature. The result of [`setup_driver`] should be passed to
+//! [`crate::pointing::pointing_task`].
+//!
+//! Each axis of the joystick is read from a channel of an ADC sampler (see
+//! [`crate::hw::mcu::setup_adc_sampler`]). Depending on the [`JoystickMode`], the joystick can
+//! move the mouse cursor, scroll, or hold down the arrow keys. Unlike a mouse sensor, the
+//! joystick reports how far it is tilted, so the cursor moves (or scrolls) at a speed proportional
+//! to the tilt, after the dead zone and [`JoystickCurve`] are applied.
+
+use core::ops::Range;
+
+use defmt::{debug, warn};
+use embassy_time::{Duration, Instant, Timer};
+use keyberon::action::Action;
+use keyberon::key_code::KeyCode;
+
+use crate::keyboard::{InjectedEvent, MatrixSampler, INJECTED_EVENTS_CHANNEL, INJECTED_KEYS};
+use crate::pointing::{PointingDevice, PointingReport};
+
+/// Maximum tilt of the joystick, after the dead zone and curve have been applied.
+const MAX_DEFLECTION: i64 = 1024;
+
+/// Maximum time between polls used to calculate movement. This prevents the cursor from jumping
+/// if the joystick hasn't been polled in a while.
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Time to wait for the ADC to take its first samples before finding the center of the joystick.
+const CENTER_DELAY: Duration = Duration::from_millis(50);
+
+/// What the joystick is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoystickMode {
+    /// Move the mouse cursor.
+    Mouse,
+    /// Scroll vertically and horizontally.
+    Scroll,
+    /// Hold down the arrow keys in the direction that the joystick is tilted.
+    Arrows,
+}
+
+/// Response curve, which changes how the speed of the cursor depends on the tilt of the joystick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoystickCurve {
+    /// Speed is proportional to the tilt.
+    Linear,
+    /// Speed is proportional to the square of the tilt, for more precise control when the
+    /// joystick is tilted slightly.
+    Quadratic,
+    /// Speed is proportional to the cube of the tilt, for even more precise control when the
+    /// joystick is tilted slightly.
+    Cubic,
+}
+
+impl JoystickCurve {
+    fn apply(self, deflection: i64) -> i64 {
+        match self {
+            JoystickCurve::Linear => deflection,
+            JoystickCurve::Quadratic => deflection * deflection / MAX_DEFLECTION,
+            JoystickCurve::Cubic => {
+                deflection * deflection * deflection / (MAX_DEFLECTION * MAX_DEFLECTION)
+            }
+        }
+    }
+}
+
+/// An axis of the joystick.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JoystickAxis {
+    /// Index of the analog pin, and the multiplexer output (if the pin is connected to a
+    /// multiplexer) that the axis is connected to. The indices are the same ones used by
+    /// [`crate::keyboard::build_analog_matrix`].
+    pub channel: (u8, u8),
+    /// Range of values that the ADC generates for this axis, from one end of the axis to the
+    /// other.
+    pub range: Range<u16>,
+    /// Invert the direction of the axis. Without this, the start of [`Self::range`] is left (for
+    /// the X axis) or up (for the Y axis).
+    pub invert: bool,
+}
+
+/// Settings for an analog joystick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JoystickConfig {
+    /// What the joystick is used for.
+    pub mode: JoystickMode,
+    /// Percentage of the joystick's range around its center that is ignored, to prevent the
+    /// cursor from drifting when the joystick is at rest.
+    pub dead_zone: u8,
+    /// Response curve applied to the tilt of the joystick, after the dead zone.
+    pub curve: JoystickCurve,
+    /// Speed of the cursor when the joystick is fully tilted, in counts per second. Used by
+    /// [`JoystickMode::Mouse`].
+    pub mouse_speed: u16,
+    /// Scrolling speed when the joystick is fully tilted, in wheel detents per second. Used by
+    /// [`JoystickMode::Scroll`].
+    pub scroll_speed: u16,
+    /// Percentage of the joystick's range that it must be tilted by to hold down an arrow key.
+    /// Used by [`JoystickMode::Arrows`].
+    pub arrow_threshold: u8,
+    /// Index of the first injected key (see [`crate::keyboard::INJECTED_EVENTS_CHANNEL`]) used
+    /// to hold down the arrow keys. The left, right, up and down arrows use 4 consecutive indices
+    /// starting from this one. Used by [`JoystickMode::Arrows`].
+    pub arrow_key_index: u8,
+}
+
+impl JoystickConfig {
+    /// Default joystick settings, which move the mouse cursor.
+    pub const DEFAULT: Self = Self {
+        mode: JoystickMode::Mouse,
+        dead_zone: 10,
+        curve: JoystickCurve::Quadratic,
+        mouse_speed: 1500,
+        scroll_speed: 20,
+        arrow_threshold: 50,
+        arrow_key_index: INJECTED_KEYS - 4,
+    };
+}
+
+impl Default for JoystickConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Arrow keys held by the joystick, in the order of their injected keys.
+const ARROW_KEYS: [KeyCode; 4] = [KeyCode::Left, KeyCode::Right, KeyCode::Up, KeyCode::Down];
+
+/// 2-axis analog joystick, read using an ADC sampler.
+pub struct AnalogJoystick<'a, S> {
+    sampler: &'a S,
+    axes: [JoystickAxis; 2],
+    centers: [u16; 2],
+    config: JoystickConfig,
+    last_poll: Instant,
+    /// Movement that hasn't been reported yet, in units of `1 / (MAX_DEFLECTION * 1_000_000)`.
+    remainder: [i64; 2],
+    held_arrows: [bool; 4],
+}
+
+/// Create an instance of the analog joystick driver, which reads the X and Y axes from the
+/// provided ADC sampler.
+pub fn setup_driver<S: MatrixSampler>(
+    sampler: &S,
+    x: JoystickAxis,
+    y: JoystickAxis,
+    config: JoystickConfig,
+) -> AnalogJoystick<'_, S>
+where
+    u32: From<S::SampleType>,
+{
+    let centers = [midpoint(&x.range), midpoint(&y.range)];
+
+    AnalogJoystick {
+        sampler,
+        axes: [x, y],
+        centers,
+        config,
+        last_poll: Instant::now(),
+        remainder: [0; 2],
+        held_arrows: [false; 4],
+    }
+}
+
+fn to_wheel_delta(delta: i16) -> i8 {
+    delta.clamp(-(i8::MAX as i16), i8::MAX as i16) as i8
+}
+
+fn midpoint(range: &Range<u16>) -> u16 {
+    range.start + (range.end.saturating_sub(range.start)) / 2
+}
+
+impl<S: MatrixSampler> AnalogJoystick<'_, S>
+where
+    u32: From<S::SampleType>,
+{
+    /// Change what the joystick is used for. Any arrow keys held by the joystick are released the
+    /// next time it is polled.
+    pub fn set_mode(&mut self, mode: JoystickMode) {
+        self.config.mode = mode;
+    }
+
+    fn read_axis(&self, axis: usize) -> Option<u16> {
+        let (ch, sub_ch) = self.axes[axis].channel;
+        self.sampler
+            .get_sample(ch as usize, sub_ch as usize)
+            .map(|sample| u32::from(sample).min(u16::MAX as u32) as u16)
+    }
+
+    /// Obtain the tilt of an axis, from `-MAX_DEFLECTION` to `MAX_DEFLECTION`, ignoring any tilt
+    /// within `dead_zone` percent of the center.
+    fn deflection(&self, axis: usize, dead_zone: u8) -> i64 {
+        let Some(sample) = self.read_axis(axis) else {
+            return 0;
+        };
+
+        let JoystickAxis { range, invert, .. } = &self.axes[axis];
+        let center = self.centers[axis] as i64;
+        let sample = sample as i64;
+
+        let span = if sample >= center {
+            range.end as i64 - center
+        } else {
+            center - range.start as i64
+        };
+        if span <= 0 {
+            return 0;
+        }
+
+        let tilt = ((sample - center).abs() * MAX_DEFLECTION / span).min(MAX_DEFLECTION);
+        let dead_zone = dead_zone.min(99) as i64 * MAX_DEFLECTION / 100;
+        if tilt <= dead_zone {
+            return 0;
+        }
+
+        // Rescale the tilt outside of the dead zone, so that the speed starts from 0
+        let tilt = (tilt - dead_zone) * MAX_DEFLECTION / (MAX_DEFLECTION - dead_zone);
+
+        if (sample < center) != *invert {
+            -tilt
+        } else {
+            tilt
+        }
+    }
+
+    /// Add the movement since the last poll to the remainder of an axis, and take the whole
+    /// number of counts (or detents) out of it.
+    fn take_movement(&mut self, axis: usize, speed: u16, elapsed: Duration) -> i16 {
+        let JoystickConfig {
+            dead_zone, curve, ..
+        } = self.config;
+        let deflection = curve.apply(self.deflection(axis, dead_zone));
+        self.remainder[axis] += deflection * speed as i64 * elapsed.as_micros() as i64;
+
+        let unit = MAX_DEFLECTION * 1_000_000;
+        let whole = self.remainder[axis] / unit;
+        self.remainder[axis] -= whole * unit;
+        whole.clamp(i16::MIN as i64, i16::MAX as i64) as i16
+    }
+
+    async fn set_arrow(&mut self, arrow: usize, held: bool) {
+        if self.held_arrows[arrow] == held {
+            return;
+        }
+
+        let index = self.config.arrow_key_index.wrapping_add(arrow as u8);
+        if index >= INJECTED_KEYS {
+            warn!("[JOYSTICK] Injected key index for the arrow keys is out of bounds.");
+            return;
+        }
+
+        self.held_arrows[arrow] = held;
+        let event = if held {
+            InjectedEvent::Press(index, Action::KeyCode(ARROW_KEYS[arrow]))
+        } else {
+            InjectedEvent::Release(index)
+        };
+        INJECTED_EVENTS_CHANNEL.send(event).await;
+    }
+
+    /// Hold down the arrow keys in the direction that the joystick is tilted, and release the
+    /// rest. If `enabled` is `false`, all of the arrow keys are released.
+    async fn update_arrows(&mut self, enabled: bool) {
+        let (x, y) = if enabled {
+            let threshold = self.config.arrow_threshold;
+            (self.deflection(0, threshold), self.deflection(1, threshold))
+        } else {
+            (0, 0)
+        };
+
+        self.set_arrow(0, x < 0).await;
+        self.set_arrow(1, x > 0).await;
+        self.set_arrow(2, y < 0).await;
+        self.set_arrow(3, y > 0).await;
+    }
+}
+
+impl<S: MatrixSampler> PointingDevice for AnalogJoystick<'_, S>
+where
+    u32: From<S::SampleType>,
+{
+    async fn init(&mut self) {
+        // Find the center of the joystick while it is at rest
+        Timer::after(CENTER_DELAY).await;
+
+        for axis in 0..2 {
+            match self.read_axis(axis) {
+                Some(center) => self.centers[axis] = center,
+                None => warn!(
+                    "[JOYSTICK] Could not read the center of axis {}, using the middle of its range.",
+                    axis
+                ),
+            }
+        }
+
+        debug!(
+            "[JOYSTICK] Joystick centered at ({}, {}).",
+            self.centers[0], self.centers[1]
+        );
+
+        self.last_poll = Instant::now();
+    }
+
+    async fn poll(&mut self) -> PointingReport {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_poll).min(MAX_POLL_INTERVAL);
+        self.last_poll = now;
+
+        let mode = self.config.mode;
+        self.update_arrows(mode == JoystickMode::Arrows).await;
+
+        match mode {
+            JoystickMode::Mouse => {
+                let speed = self.config.mouse_speed;
+                PointingReport {
+                    x: self.take_movement(0, speed, elapsed),
+                    y: self.take_movement(1, speed, elapsed),
+                    ..Default::default()
+                }
+            }
+            JoystickMode::Scroll => {
+                let speed = self.config.scroll_speed;
+                let horizontal = self.take_movement(0, speed, elapsed);
+                let vertical = self.take_movement(1, speed, elapsed);
+
+                // Tilting the joystick up scrolls up
+                PointingReport {
+                    vertical_wheel: to_wheel_delta(vertical.saturating_neg()),
+                    horizontal_wheel: to_wheel_delta(horizontal),
+                    ..Default::default()
+                }
+            }
+            JoystickMode::Arrows => PointingReport::default(),
+        }
+    }
+}
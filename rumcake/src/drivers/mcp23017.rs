@@ -0,0 +1,67 @@
+//! Rumcake driver implementations for the MCP23017 16-bit I2C GPIO expander.
+//!
+//! This driver provides implementations for [`IoExpander`], so it can be used to scan a keyboard
+//! matrix. The result of [`setup_driver`] should be passed to
+//! [`crate::keyboard::setup_io_expander_keyboard_matrix`].
+//!
+//! Pins `GPA0` to `GPA7` are pins 0 to 7, and pins `GPB0` to `GPB7` are pins 8 to 15. Both
+//! interrupt pins (`INTA` and `INTB`) are triggered by changes on any of the input pins.
+
+use core::fmt::Debug;
+
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+
+use crate::keyboard::IoExpander;
+
+const IODIRA: u8 = 0x00;
+const GPINTENA: u8 = 0x04;
+const IOCON: u8 = 0x0A;
+const GPPUA: u8 = 0x0C;
+const GPIOA: u8 = 0x12;
+const OLATA: u8 = 0x14;
+
+/// Mirror the interrupt pins, so that both of them are triggered by changes on any port.
+const IOCON_MIRROR: u8 = 0b0100_0000;
+
+/// MCP23017 GPIO expander, connected over I2C.
+pub struct Mcp23017<I2C> {
+    i2c: I2C,
+    addr: u8,
+}
+
+/// Create an instance of the MCP23017 driver with the provided I2C peripheral, and address.
+/// The address is `0x20` if all of the address pins are connected to ground.
+pub fn setup_driver<E: Debug, I2C: Write<Error = E> + WriteRead<Error = E>>(
+    i2c: I2C,
+    addr: u8,
+) -> Mcp23017<I2C> {
+    Mcp23017 { i2c, addr }
+}
+
+impl<E: Debug, I2C: Write<Error = E> + WriteRead<Error = E>> Mcp23017<I2C> {
+    fn write_register(&mut self, register: u8, value: u16) -> Result<(), E> {
+        let [a, b] = value.to_le_bytes();
+        self.i2c.write(self.addr, &[register, a, b])
+    }
+}
+
+impl<E: Debug, I2C: Write<Error = E> + WriteRead<Error = E>> IoExpander for Mcp23017<I2C> {
+    type Error = E;
+
+    fn init(&mut self, outputs: u16, inputs: u16) -> Result<(), Self::Error> {
+        self.i2c.write(self.addr, &[IOCON, IOCON_MIRROR])?;
+        self.write_register(IODIRA, !outputs)?;
+        self.write_register(GPPUA, inputs)?;
+        self.write_register(GPINTENA, inputs)
+    }
+
+    fn write(&mut self, state: u16) -> Result<(), Self::Error> {
+        self.write_register(OLATA, state)
+    }
+
+    fn read(&mut self) -> Result<u16, Self::Error> {
+        let mut buf = [0; 2];
+        self.i2c.write_read(self.addr, &[GPIOA], &mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+}
@@ -2,15 +2,30 @@
 
 use embedded_io_async::{Read, Write};
 
+#[cfg(feature = "analog-joystick")]
+pub mod analog_joystick;
+
 #[cfg(feature = "is31fl3731")]
 pub mod is31fl3731;
 
+#[cfg(feature = "mcp23017")]
+pub mod mcp23017;
+
 #[cfg(feature = "nrf-ble")]
 pub mod nrf_ble;
 
+#[cfg(feature = "pcf8574")]
+pub mod pcf8574;
+
+#[cfg(feature = "pmw33xx")]
+pub mod pmw33xx;
+
 #[cfg(feature = "ssd1306")]
 pub mod ssd1306;
 
+#[cfg(feature = "trackpoint")]
+pub mod trackpoint;
+
 #[cfg(feature = "ws2812-bitbang")]
 pub mod ws2812_bitbang;
 
@@ -0,0 +1,49 @@
+//! Rumcake driver implementations for the PCF8574 8-bit I2C GPIO expander.
+//!
+//! This driver provides implementations for [`IoExpander`], so it can be used to scan a keyboard
+//! matrix. The result of [`setup_driver`] should be passed to
+//! [`crate::keyboard::setup_io_expander_keyboard_matrix`].
+//!
+//! Pins `P0` to `P7` are pins 0 to 7. The PCF8574 doesn't have separate input and output modes.
+//! Instead, pins that are set high are weakly pulled up, and can be used as inputs. The interrupt
+//! pin (`INT`) is triggered by changes on any pin, and is cleared when the pins are read.
+
+use core::fmt::Debug;
+
+use embedded_hal::blocking::i2c::{Read, Write};
+
+use crate::keyboard::IoExpander;
+
+/// PCF8574 GPIO expander, connected over I2C.
+pub struct Pcf8574<I2C> {
+    i2c: I2C,
+    addr: u8,
+}
+
+/// Create an instance of the PCF8574 driver with the provided I2C peripheral, and address.
+/// The address is `0x20` if all of the address pins are connected to ground (`0x38` for the
+/// PCF8574A).
+pub fn setup_driver<E: Debug, I2C: Write<Error = E> + Read<Error = E>>(
+    i2c: I2C,
+    addr: u8,
+) -> Pcf8574<I2C> {
+    Pcf8574 { i2c, addr }
+}
+
+impl<E: Debug, I2C: Write<Error = E> + Read<Error = E>> IoExpander for Pcf8574<I2C> {
+    type Error = E;
+
+    fn init(&mut self, _outputs: u16, _inputs: u16) -> Result<(), Self::Error> {
+        self.write(u16::MAX)
+    }
+
+    fn write(&mut self, state: u16) -> Result<(), Self::Error> {
+        self.i2c.write(self.addr, &[state as u8])
+    }
+
+    fn read(&mut self) -> Result<u16, Self::Error> {
+        let mut buf = [0; 1];
+        self.i2c.read(self.addr, &mut buf)?;
+        Ok(buf[0] as u16)
+    }
+}
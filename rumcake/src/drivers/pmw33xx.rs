@@ -0,0 +1,279 @@
+//! Rumcake driver implementations for the PixArt PMW3360 and PMW3389 optical sensors.
+//!
+//! This driver provides an implementation for [`PointingDevice`], so it can be used with the
+//! pointing feature. The result of [`setup_driver`] should be passed to
+//! [`crate::pointing::pointing_task`].
+//!
+//! The sensor must be connected to an SPI bus using SPI mode 3, at a frequency of up to 2 MHz.
+//! The chip select (`NCS`) pin is controlled by the driver, so it should be connected to a
+//! regular output pin. The motion pin is not used, since the sensor is polled.
+
+use defmt::{debug, error, info, warn, Debug2Format};
+use embassy_time::Timer;
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal_async::spi::SpiBus;
+
+use crate::pointing::{PointingDevice, PointingReport};
+
+const PRODUCT_ID: u8 = 0x00;
+const MOTION: u8 = 0x02;
+const DELTA_Y_H: u8 = 0x06;
+const RESOLUTION_L: u8 = 0x0E;
+const RESOLUTION_H: u8 = 0x0F;
+/// Contains the resolution on the PMW3360, which only uses one register for it.
+const CONFIG1: u8 = 0x0F;
+const CONFIG2: u8 = 0x10;
+const SROM_ENABLE: u8 = 0x13;
+const SROM_ID: u8 = 0x2A;
+const POWER_UP_RESET: u8 = 0x3A;
+const MOTION_BURST: u8 = 0x50;
+const SROM_LOAD_BURST: u8 = 0x62;
+
+/// Set in the first byte of a motion burst if the sensor has moved.
+const MOTION_MOT: u8 = 0b1000_0000;
+/// Set in the first byte of a motion burst if the sensor has been lifted off of the surface.
+const MOTION_LIFT: u8 = 0b0000_1000;
+/// Bits that are always cleared in the first byte of a valid motion burst.
+const MOTION_RESERVED: u8 = 0b0000_0111;
+
+/// Delay between the address byte of a read, and reading the data (tSRAD), in microseconds.
+const READ_DELAY_US: u64 = 160;
+/// Delay between the address byte of a motion burst, and reading the data (tSRAD_MOTBR), in
+/// microseconds.
+const BURST_READ_DELAY_US: u64 = 35;
+/// Delay after a read before the next command (tSRR/tSRW), in microseconds.
+const AFTER_READ_DELAY_US: u64 = 20;
+/// Delay after a write before the next command (tSWW/tSWR), in microseconds.
+const AFTER_WRITE_DELAY_US: u64 = 180;
+
+/// Sensors supported by this driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sensor {
+    /// PMW3360, with a resolution of 100 to 12000 CPI.
+    Pmw3360,
+    /// PMW3389, with a resolution of 50 to 16000 CPI.
+    Pmw3389,
+}
+
+impl Sensor {
+    fn product_id(self) -> u8 {
+        match self {
+            Sensor::Pmw3360 => 0x42,
+            Sensor::Pmw3389 => 0x47,
+        }
+    }
+}
+
+/// PMW3360 or PMW3389 optical sensor, connected over SPI.
+pub struct Pmw33xx<SPI, CS> {
+    spi: SPI,
+    cs: CS,
+    sensor: Sensor,
+    firmware: Option<&'static [u8]>,
+    in_burst: bool,
+}
+
+/// Create an instance of the PMW33xx driver with the provided SPI bus, and chip select pin.
+///
+/// If `firmware` is provided, it is uploaded to the sensor's SROM when the sensor is initialized.
+/// The firmware is provided by PixArt, and is not included with rumcake.
+pub fn setup_driver<SPI: SpiBus, CS: OutputPin>(
+    spi: SPI,
+    cs: CS,
+    sensor: Sensor,
+    firmware: Option<&'static [u8]>,
+) -> Pmw33xx<SPI, CS> {
+    Pmw33xx {
+        spi,
+        cs,
+        sensor,
+        firmware,
+        in_burst: false,
+    }
+}
+
+impl<SPI: SpiBus, CS: OutputPin> Pmw33xx<SPI, CS> {
+    fn select(&mut self) {
+        self.cs.set_low().ok();
+    }
+
+    fn deselect(&mut self) {
+        self.cs.set_high().ok();
+    }
+
+    async fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), SPI::Error> {
+        self.spi.write(bytes).await?;
+        self.spi.flush().await
+    }
+
+    async fn read_after_address(
+        &mut self,
+        address: u8,
+        delay_us: u64,
+        buf: &mut [u8],
+    ) -> Result<(), SPI::Error> {
+        self.write_bytes(&[address]).await?;
+        Timer::after_micros(delay_us).await;
+        self.spi.read(buf).await?;
+        self.spi.flush().await
+    }
+
+    async fn read_register(&mut self, register: u8) -> Result<u8, SPI::Error> {
+        self.in_burst = false;
+
+        let mut value = [0];
+        self.select();
+        let result = self
+            .read_after_address(register & 0x7F, READ_DELAY_US, &mut value)
+            .await;
+        self.deselect();
+        Timer::after_micros(AFTER_READ_DELAY_US).await;
+
+        result.map(|()| value[0])
+    }
+
+    async fn write_register(&mut self, register: u8, value: u8) -> Result<(), SPI::Error> {
+        self.in_burst = false;
+
+        self.select();
+        let result = self.write_bytes(&[register | 0x80, value]).await;
+        self.deselect();
+        Timer::after_micros(AFTER_WRITE_DELAY_US).await;
+
+        result
+    }
+
+    async fn write_firmware_bytes(&mut self, firmware: &[u8]) -> Result<(), SPI::Error> {
+        self.write_bytes(&[SROM_LOAD_BURST | 0x80]).await?;
+        for byte in firmware {
+            Timer::after_micros(15).await;
+            self.write_bytes(&[*byte]).await?;
+        }
+        Ok(())
+    }
+
+    async fn upload_firmware(&mut self, firmware: &[u8]) -> Result<(), SPI::Error> {
+        // Disable rest mode, and start the SROM download
+        self.write_register(CONFIG2, 0x00).await?;
+        self.write_register(SROM_ENABLE, 0x1D).await?;
+        Timer::after_millis(10).await;
+        self.write_register(SROM_ENABLE, 0x18).await?;
+
+        self.select();
+        let result = self.write_firmware_bytes(firmware).await;
+        self.deselect();
+        Timer::after_micros(200).await;
+        result?;
+
+        match self.read_register(SROM_ID).await? {
+            0 => warn!("[PMW33XX] Firmware upload failed."),
+            id => debug!("[PMW33XX] Firmware uploaded, SROM ID: {:02X}", id),
+        }
+
+        Ok(())
+    }
+
+    /// Reset the sensor, and upload the firmware if there is any. Returns `false` if the sensor's
+    /// product ID doesn't match the chosen sensor.
+    async fn power_up(&mut self) -> Result<bool, SPI::Error> {
+        // Reset the SPI port
+        self.deselect();
+        self.select();
+        self.deselect();
+
+        self.write_register(POWER_UP_RESET, 0x5A).await?;
+        Timer::after_millis(50).await;
+
+        // The motion registers must be read once after a reset
+        for register in MOTION..=DELTA_Y_H {
+            self.read_register(register).await?;
+        }
+
+        if let Some(firmware) = self.firmware {
+            self.upload_firmware(firmware).await?;
+        }
+
+        // Wired mode (rest mode disabled)
+        self.write_register(CONFIG2, 0x00).await?;
+
+        Ok(self.read_register(PRODUCT_ID).await? == self.sensor.product_id())
+    }
+
+    async fn read_burst(&mut self) -> Result<[u8; 6], SPI::Error> {
+        if !self.in_burst {
+            self.write_register(MOTION_BURST, 0x00).await?;
+            self.in_burst = true;
+        }
+
+        let mut data = [0; 6];
+        self.select();
+        let result = self
+            .read_after_address(MOTION_BURST, BURST_READ_DELAY_US, &mut data)
+            .await;
+        self.deselect();
+
+        result.map(|()| data)
+    }
+
+    async fn write_cpi(&mut self, cpi: u16) -> Result<(), SPI::Error> {
+        match self.sensor {
+            Sensor::Pmw3360 => {
+                let value = cpi.clamp(100, 12000) / 100 - 1;
+                self.write_register(CONFIG1, value as u8).await
+            }
+            Sensor::Pmw3389 => {
+                let [low, high] = (cpi.clamp(50, 16000) / 50).to_le_bytes();
+                self.write_register(RESOLUTION_L, low).await?;
+                self.write_register(RESOLUTION_H, high).await
+            }
+        }
+    }
+}
+
+impl<SPI: SpiBus, CS: OutputPin> PointingDevice for Pmw33xx<SPI, CS> {
+    async fn init(&mut self) {
+        match self.power_up().await {
+            Ok(true) => info!("[PMW33XX] Sensor initialized."),
+            Ok(false) => error!(
+                "[PMW33XX] Unexpected product ID. Check the sensor's wiring, and the chosen sensor."
+            ),
+            Err(err) => error!(
+                "[PMW33XX] Could not initialize the sensor: {}",
+                Debug2Format(&err)
+            ),
+        }
+    }
+
+    async fn poll(&mut self) -> PointingReport {
+        let data = match self.read_burst().await {
+            Ok(data) => data,
+            Err(err) => {
+                warn!("[PMW33XX] Could not read motion: {}", Debug2Format(&err));
+                self.in_burst = false;
+                return PointingReport::default();
+            }
+        };
+
+        // If the reserved bits are set, the burst was invalid, so it needs to be restarted
+        if data[0] & MOTION_RESERVED != 0 {
+            self.in_burst = false;
+            return PointingReport::default();
+        }
+
+        if data[0] & MOTION_MOT == 0 || data[0] & MOTION_LIFT != 0 {
+            return PointingReport::default();
+        }
+
+        PointingReport {
+            x: i16::from_le_bytes([data[2], data[3]]),
+            y: i16::from_le_bytes([data[4], data[5]]),
+            ..Default::default()
+        }
+    }
+
+    async fn set_cpi(&mut self, cpi: u16) {
+        if let Err(err) = self.write_cpi(cpi).await {
+            warn!("[PMW33XX] Could not set resolution: {}", Debug2Format(&err));
+        }
+    }
+}
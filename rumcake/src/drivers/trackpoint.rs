@@ -0,0 +1,312 @@
+//! Rumcake driver implementations for PS/2 TrackPoint modules (e.g. from ThinkPad keyboards).
+//!
+//! This driver provides an implementation for [`PointingDevice`], so it can be used with the
+//! pointing feature. The result of [`setup_driver`] should be passed to
+//! [`crate::pointing::pointing_task`].
+//!
+//! The PS/2 protocol is bit-banged using two open-drain pins with pull-ups, one for the clock
+//! line, and one for the data line. The clock pin must also support waiting for edges. Buttons
+//! that are connected to the TrackPoint module are reported as mouse buttons.
+//!
+//! Some TrackPoint modules have a reset pin, which must be pulsed high after the module is powered
+//! on. This driver does not control the reset pin, so it should be connected to a reset circuit.
+
+use defmt::{debug, error, info, warn, Debug2Format};
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use embedded_hal_async::digital::Wait;
+
+use crate::pointing::{PointingDevice, PointingReport};
+
+const RESET: u8 = 0xFF;
+const ENABLE_DATA_REPORTING: u8 = 0xF4;
+const ACK: u8 = 0xFA;
+const SELF_TEST_PASSED: u8 = 0xAA;
+
+/// Prefix for TrackPoint extended commands.
+const TP_COMMAND: u8 = 0xE2;
+/// Extended command used to write to the TrackPoint's RAM.
+const TP_WRITE_MEM: u8 = 0x81;
+/// Location of the sensitivity setting in the TrackPoint's RAM.
+const TP_SENSITIVITY: u8 = 0x4A;
+
+/// Sensitivity that TrackPoint modules use by default.
+pub const DEFAULT_SENSITIVITY: u8 = 0x80;
+
+/// Always set in the first byte of a movement packet.
+const PACKET_ALWAYS_SET: u8 = 0b0000_1000;
+const PACKET_X_SIGN: u8 = 0b0001_0000;
+const PACKET_Y_SIGN: u8 = 0b0010_0000;
+const PACKET_BUTTONS: u8 = 0b0000_0111;
+
+/// Maximum time between the host requesting to send a byte, and the device starting to generate
+/// the clock.
+const CLOCK_TIMEOUT: Duration = Duration::from_millis(15);
+/// Maximum time between clock edges while a byte is being sent or received.
+const BIT_TIMEOUT: Duration = Duration::from_millis(2);
+/// Maximum time to wait for a response to a command.
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(25);
+/// Maximum time to wait for the device to finish its self-test after a reset.
+const SELF_TEST_TIMEOUT: Duration = Duration::from_millis(1000);
+/// Time to wait for a movement packet each time the TrackPoint is polled.
+const PACKET_TIMEOUT: Duration = Duration::from_millis(2);
+
+/// Errors that can occur while communicating with a PS/2 device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// The device did not respond in time.
+    Timeout,
+    /// A byte was received with an invalid start, stop or parity bit.
+    Frame,
+    /// The device did not acknowledge a byte that was sent to it. Contains the device's response
+    /// if there was one.
+    NoAck(Option<u8>),
+    /// One of the pins could not be read or written.
+    Pin,
+}
+
+/// PS/2 host, which bit-bangs the PS/2 protocol using a clock pin and a data pin.
+pub struct Ps2<CLK, DATA> {
+    clock: CLK,
+    data: DATA,
+}
+
+impl<CLK: InputPin + OutputPin + Wait, DATA: InputPin + OutputPin> Ps2<CLK, DATA> {
+    /// Create a PS/2 host using the provided clock and data pins.
+    pub fn new(clock: CLK, data: DATA) -> Self {
+        Self { clock, data }
+    }
+
+    /// Prevent the device from sending data, by holding the clock line low. The device will hold
+    /// on to any data that it wants to send, until the clock line is released by
+    /// [`Ps2::read_byte`] or [`Ps2::write_byte`].
+    pub fn inhibit(&mut self) {
+        self.clock.set_low().ok();
+    }
+
+    async fn wait_for_falling_edge(&mut self, timeout: Duration) -> Result<(), Ps2Error> {
+        match with_timeout(timeout, self.clock.wait_for_falling_edge()).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => Err(Ps2Error::Pin),
+            Err(_) => Err(Ps2Error::Timeout),
+        }
+    }
+
+    fn read_data(&self) -> Result<bool, Ps2Error> {
+        self.data.is_high().map_err(|_| Ps2Error::Pin)
+    }
+
+    fn write_data(&mut self, high: bool) -> Result<(), Ps2Error> {
+        if high {
+            self.data.set_high().map_err(|_| Ps2Error::Pin)
+        } else {
+            self.data.set_low().map_err(|_| Ps2Error::Pin)
+        }
+    }
+
+    /// Receive a byte from the device, waiting up to `timeout` for the device to start sending it.
+    pub async fn read_byte(&mut self, timeout: Duration) -> Result<u8, Ps2Error> {
+        self.clock.set_high().map_err(|_| Ps2Error::Pin)?;
+
+        // Start bit
+        self.wait_for_falling_edge(timeout).await?;
+        if self.read_data()? {
+            return Err(Ps2Error::Frame);
+        }
+
+        let mut byte = 0;
+        for bit in 0..8 {
+            self.wait_for_falling_edge(BIT_TIMEOUT).await?;
+            if self.read_data()? {
+                byte |= 1 << bit;
+            }
+        }
+
+        // Odd parity
+        self.wait_for_falling_edge(BIT_TIMEOUT).await?;
+        let parity = self.read_data()?;
+
+        // Stop bit
+        self.wait_for_falling_edge(BIT_TIMEOUT).await?;
+        let stop = self.read_data()?;
+
+        if !stop || parity != (byte.count_ones() % 2 == 0) {
+            return Err(Ps2Error::Frame);
+        }
+
+        Ok(byte)
+    }
+
+    async fn write_frame(&mut self, byte: u8) -> Result<(), Ps2Error> {
+        // The device generates the clock, and reads each bit while the clock is high
+        self.wait_for_falling_edge(CLOCK_TIMEOUT).await?;
+        for bit in 0..8 {
+            self.write_data(byte & (1 << bit) != 0)?;
+            self.wait_for_falling_edge(BIT_TIMEOUT).await?;
+        }
+
+        // Odd parity
+        self.write_data(byte.count_ones() % 2 == 0)?;
+        self.wait_for_falling_edge(BIT_TIMEOUT).await?;
+
+        // Stop bit
+        self.write_data(true)?;
+        self.wait_for_falling_edge(BIT_TIMEOUT).await?;
+
+        // The device acknowledges the byte by pulling the data line low
+        let acknowledged = !self.read_data()?;
+        match with_timeout(BIT_TIMEOUT, self.clock.wait_for_high()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => return Err(Ps2Error::Pin),
+            Err(_) => return Err(Ps2Error::Timeout),
+        }
+
+        if !acknowledged {
+            return Err(Ps2Error::NoAck(None));
+        }
+
+        Ok(())
+    }
+
+    /// Send a byte to the device.
+    pub async fn write_byte(&mut self, byte: u8) -> Result<(), Ps2Error> {
+        // Request to send, by holding the clock low, and then pulling the data line low (start
+        // bit) before releasing the clock
+        self.inhibit();
+        Timer::after_micros(100).await;
+        self.write_data(false)?;
+        self.clock.set_high().map_err(|_| Ps2Error::Pin)?;
+
+        let result = self.write_frame(byte).await;
+
+        // Make sure the data line is released, even if sending failed
+        self.data.set_high().ok();
+
+        result
+    }
+
+    /// Send a command byte to the device, and wait for the device to acknowledge it.
+    pub async fn command(&mut self, byte: u8) -> Result<(), Ps2Error> {
+        self.write_byte(byte).await?;
+
+        match self.read_byte(RESPONSE_TIMEOUT).await? {
+            ACK => Ok(()),
+            response => Err(Ps2Error::NoAck(Some(response))),
+        }
+    }
+}
+
+/// TrackPoint module, connected over PS/2.
+pub struct TrackPoint<CLK, DATA> {
+    ps2: Ps2<CLK, DATA>,
+    sensitivity: u8,
+}
+
+/// Create an instance of the TrackPoint driver with the provided clock and data pins, and
+/// sensitivity. Higher sensitivity values make the cursor move faster. Most TrackPoint modules use
+/// a sensitivity of [`DEFAULT_SENSITIVITY`] by default.
+pub fn setup_driver<CLK: InputPin + OutputPin + Wait, DATA: InputPin + OutputPin>(
+    clock: CLK,
+    data: DATA,
+    sensitivity: u8,
+) -> TrackPoint<CLK, DATA> {
+    TrackPoint {
+        ps2: Ps2::new(clock, data),
+        sensitivity,
+    }
+}
+
+impl<CLK: InputPin + OutputPin + Wait, DATA: InputPin + OutputPin> TrackPoint<CLK, DATA> {
+    async fn write_ram(&mut self, location: u8, value: u8) -> Result<(), Ps2Error> {
+        for byte in [TP_COMMAND, TP_WRITE_MEM, location, value] {
+            self.ps2.command(byte).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn reset(&mut self) -> Result<(), Ps2Error> {
+        self.ps2.command(RESET).await?;
+
+        match self.ps2.read_byte(SELF_TEST_TIMEOUT).await? {
+            SELF_TEST_PASSED => {}
+            result => warn!("[TRACKPOINT] Self-test failed: {:02X}", result),
+        }
+
+        let id = self.ps2.read_byte(RESPONSE_TIMEOUT).await?;
+        debug!("[TRACKPOINT] Device ID: {:02X}", id);
+
+        self.write_ram(TP_SENSITIVITY, self.sensitivity).await?;
+        self.ps2.command(ENABLE_DATA_REPORTING).await
+    }
+
+    async fn read_packet(&mut self) -> Result<Option<PointingReport>, Ps2Error> {
+        let status = match self.ps2.read_byte(PACKET_TIMEOUT).await {
+            Ok(status) => status,
+            Err(Ps2Error::Timeout) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        if status & PACKET_ALWAYS_SET == 0 {
+            return Err(Ps2Error::Frame);
+        }
+
+        let x = self.ps2.read_byte(PACKET_TIMEOUT).await?;
+        let y = self.ps2.read_byte(PACKET_TIMEOUT).await?;
+
+        // Movement is sent as 9-bit two's complement values, with the sign bits in the first byte
+        let x = if status & PACKET_X_SIGN != 0 {
+            x as i16 - 256
+        } else {
+            x as i16
+        };
+        let y = if status & PACKET_Y_SIGN != 0 {
+            y as i16 - 256
+        } else {
+            y as i16
+        };
+
+        Ok(Some(PointingReport {
+            x,
+            // PS/2 mice report upwards movement as positive
+            y: -y,
+            buttons: status & PACKET_BUTTONS,
+            ..Default::default()
+        }))
+    }
+}
+
+impl<CLK: InputPin + OutputPin + Wait, DATA: InputPin + OutputPin> PointingDevice
+    for TrackPoint<CLK, DATA>
+{
+    async fn init(&mut self) {
+        let result = self.reset().await;
+        self.ps2.inhibit();
+
+        match result {
+            Ok(()) => info!("[TRACKPOINT] TrackPoint initialized."),
+            Err(err) => error!(
+                "[TRACKPOINT] Could not initialize the TrackPoint: {}",
+                Debug2Format(&err)
+            ),
+        }
+    }
+
+    async fn poll(&mut self) -> PointingReport {
+        // The TrackPoint can only send data while the clock line is released, so it holds on to
+        // any movement packets until the next poll
+        let result = self.read_packet().await;
+        self.ps2.inhibit();
+
+        match result {
+            Ok(report) => report.unwrap_or_default(),
+            Err(err) => {
+                warn!(
+                    "[TRACKPOINT] Could not read movement: {}",
+                    Debug2Format(&err)
+                );
+                PointingReport::default()
+            }
+        }
+    }
+}
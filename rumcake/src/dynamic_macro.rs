@@ -0,0 +1,236 @@
+//! Dynamic macros, which allow key sequences to be recorded and played back at runtime.
+//!
+//! Recording is started with [`DynamicMacroCommand::StartRecording`]. While recording, every key
+//! that is pressed and released is recorded, along with the time between each key event. When
+//! recording is stopped, the macro is stored in [`DYNAMIC_MACROS_STATE`]. If you specified a
+//! storage driver, and enabled the `storage` feature, recorded macros are saved, and restored when
+//! your keyboard restarts.
+
+use defmt::{info, warn};
+use embassy_time::Instant;
+use heapless::Vec;
+use keyberon::layout::Layout as KeyberonLayout;
+use serde::{Deserialize, Serialize};
+use usbd_human_interface_device::page::Keyboard as KeyboardKeycode;
+
+use crate::keyboard::Keycode;
+use crate::send_string::{push_delay, push_key_event, SS_DOWN_CODE, SS_UP_CODE};
+use crate::State;
+
+/// Number of dynamic macros that can be recorded.
+pub const DYNAMIC_MACRO_COUNT: usize = 2;
+
+/// Number of bytes available to store each dynamic macro. Each key press or release uses 3 bytes,
+/// and each delay between key events uses 4 to 8 bytes.
+pub const DYNAMIC_MACRO_SIZE: usize = 128;
+
+/// Longest delay between two key events that can be recorded, in milliseconds. Longer delays are
+/// shortened to this value.
+const MAX_RECORDED_DELAY_MS: u64 = 10000;
+
+/// Recorded dynamic macros. Each macro is stored in the same format as
+/// [`keyberon::action::Action::Sequence`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DynamicMacros {
+    macros: [Vec<u8, DYNAMIC_MACRO_SIZE>; DYNAMIC_MACRO_COUNT],
+}
+
+impl DynamicMacros {
+    /// No recorded macros.
+    pub const EMPTY: Self = Self {
+        macros: [Vec::new(), Vec::new()],
+    };
+
+    /// Check if the macro in the given slot has been recorded.
+    pub fn is_recorded(&self, slot: u8) -> bool {
+        self.macros
+            .get(slot as usize)
+            .is_some_and(|m| !m.is_empty())
+    }
+}
+
+#[cfg(feature = "storage")]
+impl crate::storage::StoredData for DynamicMacros {
+    const SCHEMA_VERSION: u16 = 1;
+}
+
+#[derive(Debug, Clone, Copy)]
+/// An enumeration of possible commands used to record and play dynamic macros.
+pub enum DynamicMacroCommand {
+    /// Start recording the macro in the given slot. If a macro is already being recorded, it is
+    /// stopped and saved instead.
+    StartRecording(u8),
+    /// Stop recording, and save the recorded macro.
+    StopRecording,
+    /// Play the macro in the given slot.
+    Play(u8),
+}
+
+/// State that contains the recorded dynamic macros.
+pub static DYNAMIC_MACROS_STATE: State<DynamicMacros> = State::new(
+    DynamicMacros::EMPTY,
+    &[
+        #[cfg(feature = "storage")]
+        &storage::DYNAMIC_MACROS_STATE_STORAGE_LISTENER,
+    ],
+);
+
+/// Buffers that hold the macros being played. Sequences must have a `'static` lifetime to be
+/// typed by the layout, so macros are copied here before they are played.
+static mut PLAYBACK_BUFFERS: [[u8; DYNAMIC_MACRO_SIZE]; DYNAMIC_MACRO_COUNT] =
+    [[0; DYNAMIC_MACRO_SIZE]; DYNAMIC_MACRO_COUNT];
+
+struct Recording {
+    slot: u8,
+    sequence: Vec<u8, DYNAMIC_MACRO_SIZE>,
+    last_event: Instant,
+}
+
+/// Records the keys sent to the host, and plays back recorded macros.
+pub(crate) struct DynamicMacroRecorder {
+    recording: Option<Recording>,
+}
+
+impl DynamicMacroRecorder {
+    pub(crate) const fn new() -> Self {
+        Self { recording: None }
+    }
+
+    pub(crate) async fn process_command<const C: usize, const R: usize, const L: usize>(
+        &mut self,
+        layout: &mut KeyberonLayout<C, R, L, Keycode>,
+        command: DynamicMacroCommand,
+    ) {
+        match command {
+            DynamicMacroCommand::StartRecording(slot) => {
+                if self.recording.is_some() {
+                    self.stop().await;
+                } else if (slot as usize) < DYNAMIC_MACRO_COUNT {
+                    info!("[DYNAMIC_MACRO] Recording macro {}", slot);
+                    self.recording = Some(Recording {
+                        slot,
+                        sequence: Vec::new(),
+                        last_event: Instant::now(),
+                    });
+                }
+            }
+            DynamicMacroCommand::StopRecording => {
+                self.stop().await;
+            }
+            DynamicMacroCommand::Play(slot) => {
+                if self
+                    .recording
+                    .as_ref()
+                    .is_some_and(|recording| recording.slot == slot)
+                {
+                    warn!(
+                        "[DYNAMIC_MACRO] Can't play macro {} while recording it",
+                        slot
+                    );
+                    return;
+                }
+
+                let macros = DYNAMIC_MACROS_STATE.get().await;
+                let Some(sequence) = macros.macros.get(slot as usize) else {
+                    return;
+                };
+
+                // SAFETY: the playback buffers are only accessed by the layout task
+                let buffer = unsafe { &mut PLAYBACK_BUFFERS[slot as usize] };
+                buffer[..sequence.len()].copy_from_slice(sequence);
+                let buffer: &'static [u8] = buffer;
+                layout.start_sequence(&buffer[..sequence.len()]);
+            }
+        }
+    }
+
+    /// Record the keys that changed between two keyboard reports.
+    pub(crate) async fn record(&mut self, last_keys: &[KeyboardKeycode], keys: &[KeyboardKeycode]) {
+        let Some(recording) = &mut self.recording else {
+            return;
+        };
+
+        let now = Instant::now();
+        let delay = now
+            .duration_since(recording.last_event)
+            .as_millis()
+            .min(MAX_RECORDED_DELAY_MS);
+        let mut sequence = recording.sequence.clone();
+
+        if encode_events(&mut sequence, delay, last_keys, keys).is_ok() {
+            recording.sequence = sequence;
+            recording.last_event = now;
+        } else {
+            warn!("[DYNAMIC_MACRO] Macro is full, stopping recording");
+            self.stop().await;
+        }
+    }
+
+    async fn stop(&mut self) {
+        if let Some(recording) = self.recording.take() {
+            info!("[DYNAMIC_MACRO] Saving macro {}", recording.slot);
+            DYNAMIC_MACROS_STATE
+                .update(|macros| macros.macros[recording.slot as usize] = recording.sequence)
+                .await;
+        }
+    }
+}
+
+/// Append a delay, followed by the key releases and presses between two keyboard reports, to a
+/// sequence.
+fn encode_events<const N: usize>(
+    sequence: &mut Vec<u8, N>,
+    delay_ms: u64,
+    last_keys: &[KeyboardKeycode],
+    keys: &[KeyboardKeycode],
+) -> Result<(), ()> {
+    // There is no delay between events in the same report, or before the first event
+    if !sequence.is_empty() {
+        push_delay(sequence, delay_ms)?;
+    }
+
+    for key in last_keys.iter().filter(|key| !keys.contains(key)) {
+        push_key_event(sequence, SS_UP_CODE, *key as u8)?;
+    }
+
+    for key in keys.iter().filter(|key| !last_keys.contains(key)) {
+        push_key_event(sequence, SS_DOWN_CODE, *key as u8)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "storage")]
+pub mod storage {
+    use embassy_sync::signal::Signal;
+
+    use crate::hw::mcu::RawMutex;
+    use crate::storage::{FlashStorage, StorageDevice};
+
+    use super::DYNAMIC_MACROS_STATE;
+
+    pub(super) static DYNAMIC_MACROS_STATE_STORAGE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+    /// Signal used to save the recorded dynamic macros immediately, instead of waiting for the
+    /// save policy defined in [`StorageDevice`].
+    pub(crate) static DYNAMIC_MACROS_SAVE_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+    /// Task that restores the recorded dynamic macros, and saves any newly recorded macros.
+    #[rumcake_macros::task]
+    pub async fn dynamic_macros_storage_task<K: StorageDevice, F: FlashStorage>(
+        _k: K,
+        database: &crate::storage::StorageService<'static, F>,
+    ) where
+        [(); F::ERASE_SIZE]:,
+        [(); F::REGION_SIZE]:,
+    {
+        database
+            .persist_state::<K, _>(
+                crate::storage::StorageKey::DynamicMacros,
+                &DYNAMIC_MACROS_STATE,
+                &DYNAMIC_MACROS_STATE_STORAGE_LISTENER,
+                &DYNAMIC_MACROS_SAVE_SIGNAL,
+            )
+            .await
+    }
+}
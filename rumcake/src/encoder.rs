@@ -0,0 +1,372 @@
+//! Rotary encoders.
+//!
+//! Keyboards can have any number of encoders. Each encoder is bound to two positions on the
+//! keyboard layout, one for each direction it can be turned in. Turning an encoder by one detent
+//! taps the key at the corresponding position, so encoders work with layers, and anything else
+//! that can be put on your layout.
+//!
+//! Encoders can optionally accelerate when they are turned quickly, by tapping their keys more
+//! than once for each detent, or by tapping a different pair of keys. See [`EncoderConfig`].
+
+use defmt::{info, warn, Debug2Format};
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::digital::v2::InputPin;
+use heapless::Vec;
+use keyberon::layout::Event;
+use serde::{Deserialize, Serialize};
+
+use crate::hw::mcu::RawMutex;
+use crate::keyboard::{POLLED_EVENTS_CHANNEL, SUSPENDED_POLL_INTERVAL};
+use crate::State;
+
+pub use rumcake_macros::build_encoders;
+
+/// Default number of pulses that an encoder sends for each detent.
+pub const DEFAULT_ENCODER_RESOLUTION: u8 = 4;
+
+/// Maximum number of encoders that support acceleration. Encoders after this are never
+/// accelerated.
+pub const MAX_ENCODERS: usize = 16;
+
+/// Trait that allows you to add rotary encoders to your keyboard.
+pub trait Encoders {
+    /// Layout positions (row, column) that each encoder is bound to, in the same order as the
+    /// encoders returned by [`Encoders::get_encoders`]. The first position is tapped when the
+    /// encoder is turned clockwise, and the second position is tapped when it is turned
+    /// counter-clockwise.
+    ///
+    /// It is recommended to use the [`build_encoders`] macro to set this constant.
+    const ENCODER_POSITIONS: &'static [((u8, u8), (u8, u8))];
+
+    /// Layout positions (row, column) that each encoder taps instead of the ones in
+    /// [`Encoders::ENCODER_POSITIONS`] when it is turned quickly, if its acceleration is set to
+    /// [`EncoderAcceleration::FastPositions`]. Encoders that don't have fast positions use their
+    /// normal positions.
+    const ENCODER_FAST_POSITIONS: &'static [((u8, u8), (u8, u8))] = &[];
+
+    /// Default acceleration settings of the encoder with the given index. By default, encoders
+    /// don't accelerate.
+    ///
+    /// This is used for encoders that don't have settings stored in [`ENCODER_CONFIGS_STATE`].
+    fn default_encoder_config(_idx: u8) -> EncoderConfig {
+        EncoderConfig::DEFAULT
+    }
+
+    /// Create the encoders.
+    ///
+    /// It is recommended to use the [`build_encoders`] macro to implement this function.
+    fn get_encoders() -> &'static PollableEncoders<impl EncoderSet>;
+}
+
+/// What an encoder does when it is turned quickly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EncoderAcceleration {
+    /// Each detent taps the encoder's key once, no matter how fast the encoder is turned.
+    Off,
+    /// Each detent taps the encoder's key the given number of times.
+    Multiply(u8),
+    /// Each detent taps the key at the encoder's position in
+    /// [`Encoders::ENCODER_FAST_POSITIONS`] instead. This can be used to switch to a different
+    /// action (e.g. coarse volume steps instead of fine ones).
+    FastPositions,
+}
+
+/// Acceleration settings of an encoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncoderConfig {
+    /// What the encoder does when it is turned quickly.
+    pub acceleration: EncoderAcceleration,
+    /// Speed, in detents per second, at which the encoder is considered to be turned quickly.
+    pub fast_speed: u8,
+}
+
+impl EncoderConfig {
+    /// Default settings, which don't accelerate the encoder.
+    pub const DEFAULT: Self = Self {
+        acceleration: EncoderAcceleration::Off,
+        fast_speed: 10,
+    };
+
+    /// Time between two detents, below which the encoder is considered to be turned quickly.
+    fn fast_interval(&self) -> Duration {
+        Duration::from_micros(1_000_000 / self.fast_speed.max(1) as u64)
+    }
+}
+
+/// Acceleration settings of the encoders that have been changed from the defaults, along with
+/// the index of the encoder.
+pub type EncoderConfigs = Vec<(u8, EncoderConfig), MAX_ENCODERS>;
+
+#[cfg(feature = "storage")]
+impl crate::storage::StoredData for EncoderConfigs {
+    const SCHEMA_VERSION: u16 = 1;
+}
+
+/// State that contains the acceleration settings of the encoders that have been changed from the
+/// defaults.
+pub static ENCODER_CONFIGS_STATE: State<EncoderConfigs> = State::new(
+    Vec::new(),
+    &[
+        #[cfg(feature = "storage")]
+        &storage::ENCODER_CONFIGS_STATE_STORAGE_LISTENER,
+    ],
+);
+
+/// Obtain the settings of an encoder from `configs`, or its default settings if they haven't been
+/// changed.
+fn find_encoder_config<K: Encoders>(configs: &EncoderConfigs, idx: u8) -> EncoderConfig {
+    configs
+        .iter()
+        .find(|(encoder, _)| *encoder == idx)
+        .map_or_else(|| K::default_encoder_config(idx), |(_, config)| *config)
+}
+
+/// Obtain the acceleration settings of the encoder with the given index. Returns `None` if the
+/// encoder doesn't exist.
+pub async fn get_encoder_config<K: Encoders>(idx: u8) -> Option<EncoderConfig> {
+    if idx as usize >= K::ENCODER_POSITIONS.len() {
+        return None;
+    }
+
+    Some(find_encoder_config::<K>(
+        &ENCODER_CONFIGS_STATE.get().await,
+        idx,
+    ))
+}
+
+/// Change the acceleration settings of the encoder with the given index. If storage is enabled,
+/// the new settings are saved. Returns `false` if the encoder doesn't exist, or doesn't support
+/// acceleration (see [`MAX_ENCODERS`]).
+pub async fn set_encoder_config<K: Encoders>(idx: u8, config: EncoderConfig) -> bool {
+    if idx as usize >= K::ENCODER_POSITIONS.len().min(MAX_ENCODERS) {
+        warn!("[ENCODER] Tried to change the settings of an encoder that doesn't exist.");
+        return false;
+    }
+
+    ENCODER_CONFIGS_STATE
+        .update(|configs| {
+            if let Some((_, existing)) = configs.iter_mut().find(|(encoder, _)| *encoder == idx) {
+                *existing = config;
+                return true;
+            }
+
+            configs.push((idx, config)).is_ok()
+        })
+        .await
+}
+
+/// Restore the default acceleration settings of every encoder, as defined by
+/// [`Encoders::default_encoder_config`]. If storage is enabled, the saved settings are also
+/// erased.
+pub async fn reset() {
+    ENCODER_CONFIGS_STATE.set(Vec::new()).await;
+}
+
+/// Trait that allows you to implement polling functionality for a single rotary encoder.
+pub trait Encoder {
+    /// Poll the encoder, returning the number of detents that it was turned by since it was last
+    /// polled. Positive values are clockwise, and negative values are counter-clockwise.
+    fn poll(&mut self) -> i8;
+}
+
+/// Trait that allows you to poll a group of encoders. This is already implemented for arrays of
+/// types that implement [`Encoder`], and for pairs containing an [`Encoder`] followed by another
+/// [`EncoderSet`], which can be used to combine different types of encoders.
+pub trait EncoderSet {
+    /// Poll the encoders, returning the index of each encoder that was turned, and the number of
+    /// detents that it was turned by.
+    fn events(&mut self) -> impl Iterator<Item = (usize, i8)>;
+}
+
+impl<E: Encoder, const N: usize> EncoderSet for [E; N] {
+    fn events(&mut self) -> impl Iterator<Item = (usize, i8)> {
+        self.iter_mut()
+            .map(|encoder| encoder.poll())
+            .enumerate()
+            .filter(|&(_, detents)| detents != 0)
+    }
+}
+
+impl<E: Encoder, S: EncoderSet> EncoderSet for (E, S) {
+    fn events(&mut self) -> impl Iterator<Item = (usize, i8)> {
+        let detents = self.0.poll();
+        (detents != 0)
+            .then_some((0, detents))
+            .into_iter()
+            .chain(self.1.events().map(|(idx, detents)| (idx + 1, detents)))
+    }
+}
+
+pub struct PollableEncoders<T> {
+    pub(crate) encoders: Mutex<RawMutex, T>,
+}
+
+impl<T: EncoderSet> PollableEncoders<T> {
+    pub const fn new(encoders: T) -> Self {
+        Self {
+            encoders: Mutex::new(encoders),
+        }
+    }
+}
+
+// Change in position for each transition between the previous and current state of the A and B
+// pins, indexed by `previous << 2 | current`. Invalid transitions (where both pins change) are
+// ignored.
+const QUADRATURE_TABLE: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+/// A rotary encoder with its A and B pins connected to GPIO pins, using pull-up resistors.
+pub struct GpioEncoder<I: InputPin> {
+    a: I,
+    b: I,
+    resolution: u8,
+    state: u8,
+    pulses: i8,
+}
+
+impl<I: InputPin> GpioEncoder<I> {
+    /// Create a new encoder. `resolution` is the number of pulses that the encoder sends for each
+    /// detent.
+    pub fn new(a: I, b: I, resolution: u8) -> Self {
+        let mut encoder = Self {
+            a,
+            b,
+            resolution: resolution.max(1),
+            state: 0,
+            pulses: 0,
+        };
+        encoder.state = encoder.read();
+        encoder
+    }
+
+    fn read(&self) -> u8 {
+        (self.a.is_high().unwrap_or_default() as u8) << 1
+            | self.b.is_high().unwrap_or_default() as u8
+    }
+}
+
+impl<I: InputPin> Encoder for GpioEncoder<I> {
+    fn poll(&mut self) -> i8 {
+        let state = self.read();
+        if state == self.state {
+            return 0;
+        }
+
+        self.pulses += QUADRATURE_TABLE[(self.state << 2 | state) as usize];
+        self.state = state;
+
+        let detents = self.pulses / self.resolution as i8;
+        self.pulses %= self.resolution as i8;
+        detents
+    }
+}
+
+#[rumcake_macros::task]
+pub async fn encoder_poll<K: Encoders + 'static>(_k: K) {
+    let encoders = K::get_encoders();
+
+    // Time that each encoder was last turned, used to measure how fast it is being turned
+    let mut last_turned: [Option<Instant>; MAX_ENCODERS] = [None; MAX_ENCODERS];
+
+    loop {
+        {
+            let mut encoders = encoders.encoders.lock().await;
+            for (idx, detents) in encoders.events() {
+                let Some(&positions) = K::ENCODER_POSITIONS.get(idx) else {
+                    continue;
+                };
+
+                let now = Instant::now();
+                let config =
+                    find_encoder_config::<K>(&ENCODER_CONFIGS_STATE.get().await, idx as u8);
+                let fast = last_turned.get_mut(idx).is_some_and(|last| {
+                    let fast = detents.unsigned_abs() > 1
+                        || last.is_some_and(|last| now - last < config.fast_interval());
+                    *last = Some(now);
+                    fast
+                });
+
+                let ((clockwise, counter_clockwise), taps) = match config.acceleration {
+                    EncoderAcceleration::Multiply(taps) if fast => (positions, taps.max(1)),
+                    EncoderAcceleration::FastPositions if fast => (
+                        K::ENCODER_FAST_POSITIONS
+                            .get(idx)
+                            .copied()
+                            .unwrap_or(positions),
+                        1,
+                    ),
+                    _ => (positions, 1),
+                };
+                let (row, col) = if detents > 0 {
+                    clockwise
+                } else {
+                    counter_clockwise
+                };
+
+                info!(
+                    "[ENCODER] Encoder {} turned by {} detents, Fast: {}, Position: {:?}",
+                    idx,
+                    detents,
+                    fast,
+                    Debug2Format(&(row, col))
+                );
+
+                #[cfg(feature = "usb")]
+                crate::usb::request_remote_wakeup();
+
+                for _ in 0..detents.unsigned_abs() as u16 * taps as u16 {
+                    POLLED_EVENTS_CHANNEL.send(Event::Press(row, col)).await;
+                    POLLED_EVENTS_CHANNEL.send(Event::Release(row, col)).await;
+                }
+            }
+        }
+
+        #[cfg(feature = "usb")]
+        let suspended = crate::usb::USB_SUSPENDED_STATE.get().await;
+        #[cfg(not(feature = "usb"))]
+        let suspended = false;
+
+        Timer::after(if suspended {
+            SUSPENDED_POLL_INTERVAL
+        } else {
+            Duration::from_millis(1)
+        })
+        .await;
+    }
+}
+
+#[cfg(feature = "storage")]
+pub mod storage {
+    use embassy_sync::signal::Signal;
+
+    use crate::hw::mcu::RawMutex;
+    use crate::storage::{FlashStorage, StorageDevice, StorageKey};
+
+    use super::ENCODER_CONFIGS_STATE;
+
+    pub(super) static ENCODER_CONFIGS_STATE_STORAGE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+    /// Signal used to save the encoder settings immediately, instead of waiting for the save
+    /// policy defined in [`StorageDevice`].
+    pub(crate) static ENCODER_CONFIGS_SAVE_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+    /// Task that restores the acceleration settings of the encoders, and saves any changes to
+    /// them.
+    #[rumcake_macros::task]
+    pub async fn encoder_configs_storage_task<K: StorageDevice, F: FlashStorage>(
+        _k: K,
+        database: &crate::storage::StorageService<'static, F>,
+    ) where
+        [(); F::ERASE_SIZE]:,
+        [(); F::REGION_SIZE]:,
+    {
+        database
+            .persist_state::<K, _>(
+                StorageKey::EncoderConfigs,
+                &ENCODER_CONFIGS_STATE,
+                &ENCODER_CONFIGS_STATE_STORAGE_LISTENER,
+                &ENCODER_CONFIGS_SAVE_SIGNAL,
+            )
+            .await
+    }
+}
@@ -0,0 +1,49 @@
+//! Proprietary 2.4 GHz wireless communication using Enhanced ShockBurst (ESB).
+//!
+//! A keyboard using ESB sends its HID reports to a USB dongle, which also runs `rumcake`, and
+//! forwards them to the USB host (see [`crate::dongle`]). ESB has much less overhead than
+//! bluetooth, so reports reach the host with less latency. Bluetooth can still be used as a
+//! fallback while the dongle is unavailable. See [`crate::hw::OutputMode::Esb`].
+//!
+//! To use ESB, keyboards and dongles must implement [`EsbDevice`]. The keyboard and the dongle
+//! must use the same [`EsbDevice::ESB_ADDRESS`] and [`EsbDevice::ESB_CHANNEL`].
+
+#[cfg(any(feature = "nrf", doc))]
+pub mod nrf_esb;
+
+use embassy_sync::signal::Signal;
+use postcard::experimental::max_size::MaxSize;
+
+use crate::dongle::{MessageToDongle, MessageToKeyboard};
+use crate::hw::mcu::RawMutex;
+use crate::State;
+
+/// A trait that keyboards and dongles must implement to communicate with each other using ESB.
+pub trait EsbDevice {
+    /// Address used to identify ESB packets sent between your keyboard and your dongle. Packets
+    /// with a different address are ignored, so you should use a different address for each
+    /// keyboard and dongle pair. The first byte must not be `0x00`, `0x55` or `0xAA`, as
+    /// addresses made up of alternating bits can be mistaken for the packet preamble.
+    const ESB_ADDRESS: [u8; 5];
+
+    /// RF channel used to send and receive ESB packets. The radio will use a frequency of `2400 +
+    /// ESB_CHANNEL` MHz. Must be a value from 0 to 100. Defaults to 40 (2440 MHz), which sits
+    /// between the bluetooth advertising channels.
+    const ESB_CHANNEL: u8 = 40;
+}
+
+/// Maximum length of an ESB payload. Payloads of this length are supported by all nRF5x chips.
+pub const ESB_MAX_PAYLOAD_LEN: usize = 32;
+
+const _: () = assert!(
+    MessageToDongle::POSTCARD_MAX_SIZE <= ESB_MAX_PAYLOAD_LEN
+        && MessageToKeyboard::POSTCARD_MAX_SIZE <= ESB_MAX_PAYLOAD_LEN,
+    "ESB messages must fit in a single packet"
+);
+
+/// Whether the keyboard can currently reach its ESB dongle. This is updated by the ESB transmitter
+/// task every time it sends a packet.
+pub(crate) static ESB_CONNECTED_STATE: State<bool> =
+    State::new(false, &[&crate::hw::ESB_CONNECTED_STATE_LISTENER]);
+
+pub(crate) static CURRENT_OUTPUT_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
@@ -0,0 +1,788 @@
+//! Enhanced ShockBurst implementation for nRF5x MCUs.
+//!
+//! The radio is shared with the softdevice using the softdevice's radio timeslot API, so ESB can
+//! be used at the same time as bluetooth. Packets are only sent and received during the
+//! timeslots that the softdevice grants us. Packets use a 2 Mbps data rate, a dynamic payload
+//! length of up to [`ESB_MAX_PAYLOAD_LEN`] bytes, and a 16-bit CRC.
+//!
+//! Timeslot callbacks run at the softdevice's highest interrupt priority, where critical sections
+//! (and by extension, embassy's synchronization primitives) can't be used. All data shared
+//! between the callbacks and the tasks in this module is coordinated using atomics instead.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
+
+use defmt::{debug, error, info, warn, Debug2Format};
+use embassy_futures::select::{self, select, select3};
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use heapless::Vec;
+use nrf_softdevice::{raw, RawError, Softdevice};
+use packed_struct::prelude::PackedStruct;
+
+use crate::dongle::{
+    DongleEvent, MessageToDongle, MessageToKeyboard, DONGLE_EVENT_CHANNEL, ESB_KEYBOARD_SLOT,
+};
+use crate::hw::mcu::RawMutex;
+use crate::hw::{
+    HIDOutput, LedIndicators, BATTERY_STATE, CURRENT_OUTPUT_STATE, LED_INDICATORS_STATE,
+};
+use crate::keyboard::{CONSUMER_REPORT_HID_SEND_CHANNEL, KEYBOARD_REPORT_HID_SEND_CHANNEL};
+use crate::system_control::SYSTEM_CONTROL_REPORT_HID_SEND_CHANNEL;
+
+use super::{EsbDevice, CURRENT_OUTPUT_STATE_LISTENER, ESB_CONNECTED_STATE, ESB_MAX_PAYLOAD_LEN};
+
+const RADIO: usize = 0x4000_1000;
+const RADIO_TASKS_TXEN: usize = 0x000;
+const RADIO_TASKS_RXEN: usize = 0x004;
+const RADIO_TASKS_DISABLE: usize = 0x010;
+const RADIO_EVENTS_ADDRESS: usize = 0x104;
+const RADIO_EVENTS_DISABLED: usize = 0x110;
+const RADIO_SHORTS: usize = 0x200;
+const RADIO_INTENSET: usize = 0x304;
+const RADIO_INTENCLR: usize = 0x308;
+const RADIO_CRCSTATUS: usize = 0x400;
+const RADIO_RXCRC: usize = 0x40C;
+const RADIO_PACKETPTR: usize = 0x504;
+const RADIO_FREQUENCY: usize = 0x508;
+const RADIO_TXPOWER: usize = 0x50C;
+const RADIO_MODE: usize = 0x510;
+const RADIO_PCNF0: usize = 0x514;
+const RADIO_PCNF1: usize = 0x518;
+const RADIO_BASE0: usize = 0x51C;
+const RADIO_PREFIX0: usize = 0x524;
+const RADIO_TXADDRESS: usize = 0x52C;
+const RADIO_RXADDRESSES: usize = 0x530;
+const RADIO_CRCCNF: usize = 0x534;
+const RADIO_CRCPOLY: usize = 0x538;
+const RADIO_CRCINIT: usize = 0x53C;
+
+const RADIO_SHORTS_READY_START: u32 = 1 << 0;
+const RADIO_SHORTS_END_DISABLE: u32 = 1 << 1;
+const RADIO_SHORTS_DISABLED_TXEN: u32 = 1 << 2;
+const RADIO_SHORTS_DISABLED_RXEN: u32 = 1 << 3;
+const RADIO_INTEN_DISABLED: u32 = 1 << 4;
+const RADIO_MODE_NRF_2MBIT: u32 = 1;
+
+// The softdevice starts TIMER0 at 1 MHz at the beginning of each timeslot, so compare values are
+// in microseconds since the start of the timeslot.
+const TIMER0: usize = 0x4000_8000;
+const TIMER_TASKS_CAPTURE1: usize = 0x044;
+const TIMER_EVENTS_COMPARE0: usize = 0x140;
+const TIMER_EVENTS_COMPARE1: usize = 0x144;
+const TIMER_INTENSET: usize = 0x304;
+const TIMER_INTENCLR: usize = 0x308;
+const TIMER_CC0: usize = 0x540;
+const TIMER_CC1: usize = 0x544;
+
+const TIMER_INTEN_COMPARE0: u32 = 1 << 16;
+const TIMER_INTEN_COMPARE1: u32 = 1 << 17;
+
+const SIGNAL_START: u8 = raw::NRF_RADIO_CALLBACK_SIGNAL_TYPE_START as u8;
+const SIGNAL_TIMER0: u8 = raw::NRF_RADIO_CALLBACK_SIGNAL_TYPE_TIMER0 as u8;
+const SIGNAL_RADIO: u8 = raw::NRF_RADIO_CALLBACK_SIGNAL_TYPE_RADIO as u8;
+
+const ACTION_NONE: u8 = raw::NRF_RADIO_SIGNAL_CALLBACK_ACTION_NONE as u8;
+const ACTION_END: u8 = raw::NRF_RADIO_SIGNAL_CALLBACK_ACTION_END as u8;
+const ACTION_REQUEST_AND_END: u8 = raw::NRF_RADIO_SIGNAL_CALLBACK_ACTION_REQUEST_AND_END as u8;
+
+/// Length of the timeslots used to send a packet. This is long enough to fit all of the
+/// retransmits of a packet.
+const TRANSMITTER_TIMESLOT_US: u32 = 5_000;
+
+/// Length of the timeslots used to receive packets. The receiver requests a new timeslot at the
+/// end of each one, so that it is listening whenever the softdevice doesn't need the radio.
+const RECEIVER_TIMESLOT_US: u32 = 20_000;
+
+/// Time reserved at the end of each timeslot to stop the radio before giving it back to the
+/// softdevice.
+const TIMESLOT_MARGIN_US: u32 = 300;
+
+/// Maximum time to wait for the softdevice to grant a timeslot.
+const TIMESLOT_TIMEOUT_US: u32 = 20_000;
+
+/// Time to wait for an acknowledgement after sending a packet, before retransmitting it.
+const ACK_TIMEOUT_US: u32 = 250;
+
+/// Number of times a packet is sent in a single timeslot before giving up.
+const MAX_ATTEMPTS: u8 = 6;
+
+/// Number of timeslots in a row in which a packet could not be delivered, before the dongle is
+/// considered to be out of range.
+const MAX_FAILED_TIMESLOTS: u8 = 3;
+
+/// How often the keyboard sends its battery level to the dongle, when there are no HID reports to
+/// send. This is also used to detect whether the dongle is in range.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time without any packets from the keyboard, after which the dongle considers it disconnected.
+const LINK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How often the dongle checks for received packets.
+const RECEIVER_POLL_INTERVAL: Duration = Duration::from_micros(250);
+
+/// Size of an ESB packet in RAM: a length byte, an S1 byte containing the packet ID, and the
+/// payload.
+const PACKET_LEN: usize = ESB_MAX_PAYLOAD_LEN + 2;
+
+/// Number of received packets that can be queued on the dongle before they get dropped.
+const RX_QUEUE_SIZE: usize = 8;
+
+/// Data shared between the timeslot callbacks and the tasks in this module.
+struct SharedCell<T>(UnsafeCell<T>);
+
+// SAFETY: Access to each cell is coordinated using the atomics next to it.
+unsafe impl<T> Sync for SharedCell<T> {}
+
+impl<T> SharedCell<T> {
+    const fn new(value: T) -> Self {
+        Self(UnsafeCell::new(value))
+    }
+
+    fn get(&self) -> *mut T {
+        self.0.get()
+    }
+}
+
+type PacketBuffer = SharedCell<[u8; PACKET_LEN]>;
+
+impl PacketBuffer {
+    /// SAFETY: The caller must have exclusive access to the buffer.
+    unsafe fn payload(&self) -> &[u8] {
+        let packet = &*self.get();
+        let len = (packet[0] as usize).min(ESB_MAX_PAYLOAD_LEN);
+        &packet[2..(2 + len)]
+    }
+
+    /// SAFETY: The caller must have exclusive access to the buffer.
+    unsafe fn pid(&self) -> u8 {
+        ((*self.get())[1] >> 1) & 0b11
+    }
+
+    /// SAFETY: The caller must have exclusive access to the buffer.
+    unsafe fn set_payload(&self, pid: u8, payload: &[u8]) {
+        let packet = &mut *self.get();
+        let len = payload.len().min(ESB_MAX_PAYLOAD_LEN);
+        packet[0] = len as u8;
+        packet[1] = pid << 1;
+        packet[2..(2 + len)].copy_from_slice(&payload[..len]);
+    }
+}
+
+/// Write to a RADIO or TIMER0 register. These peripherals belong to the softdevice outside of our
+/// timeslots, so this must only be called from a timeslot callback.
+unsafe fn write_reg(peripheral: usize, offset: usize, value: u32) {
+    core::ptr::write_volatile((peripheral + offset) as *mut u32, value);
+}
+
+/// Read a RADIO or TIMER0 register. This must only be called from a timeslot callback.
+unsafe fn read_reg(peripheral: usize, offset: usize) -> u32 {
+    core::ptr::read_volatile((peripheral + offset) as *const u32)
+}
+
+/// Signal used to notify the ESB tasks that the softdevice has stopped scheduling our timeslots,
+/// either because the last one has ended, or because a requested timeslot was blocked or
+/// cancelled. This is signalled by [`crate::hw::mcu::softdevice_task`].
+pub(crate) static TIMESLOT_EVENT_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+static RETURN_PARAM: SharedCell<raw::nrf_radio_signal_callback_return_param_t> =
+    SharedCell::new(unsafe { core::mem::zeroed() });
+
+const fn earliest_request(length_us: u32) -> raw::nrf_radio_request_t {
+    raw::nrf_radio_request_t {
+        request_type: raw::NRF_RADIO_REQ_TYPE_EARLIEST as u8,
+        params: raw::nrf_radio_request_t__bindgen_ty_1 {
+            earliest: raw::nrf_radio_request_earliest_t {
+                hfclk: raw::NRF_RADIO_HFCLK_CFG_XTAL_GUARANTEED as u8,
+                priority: raw::NRF_RADIO_PRIORITY_NORMAL as u8,
+                length_us,
+                timeout_us: TIMESLOT_TIMEOUT_US,
+            },
+        },
+    }
+}
+
+static TRANSMITTER_REQUEST: SharedCell<raw::nrf_radio_request_t> =
+    SharedCell::new(earliest_request(TRANSMITTER_TIMESLOT_US));
+static RECEIVER_REQUEST: SharedCell<raw::nrf_radio_request_t> =
+    SharedCell::new(earliest_request(RECEIVER_TIMESLOT_US));
+
+/// Configure the radio for ESB. The softdevice uses the radio between our timeslots, so this must
+/// be done at the start of every timeslot.
+unsafe fn configure_radio(address: [u8; 5], channel: u8) {
+    write_reg(RADIO, RADIO_MODE, RADIO_MODE_NRF_2MBIT);
+    write_reg(RADIO, RADIO_TXPOWER, 0); // 0 dBm
+    write_reg(RADIO, RADIO_FREQUENCY, channel as u32);
+
+    // 6-bit length field, followed by a 3-bit S1 field (2-bit packet ID, and a "no ACK" bit)
+    write_reg(RADIO, RADIO_PCNF0, 6 | (3 << 16));
+    // Maximum payload length, 4-byte base address (+1 byte prefix), big endian
+    write_reg(
+        RADIO,
+        RADIO_PCNF1,
+        ESB_MAX_PAYLOAD_LEN as u32 | (4 << 16) | (1 << 24),
+    );
+
+    let [prefix, base @ ..] = address;
+    write_reg(RADIO, RADIO_BASE0, u32::from_be_bytes(base));
+    write_reg(RADIO, RADIO_PREFIX0, prefix as u32);
+    write_reg(RADIO, RADIO_TXADDRESS, 0);
+    write_reg(RADIO, RADIO_RXADDRESSES, 1);
+
+    // 16-bit CRC, covering the address and the payload
+    write_reg(RADIO, RADIO_CRCCNF, 2);
+    write_reg(RADIO, RADIO_CRCINIT, 0xFFFF);
+    write_reg(RADIO, RADIO_CRCPOLY, 0x11021);
+
+    write_reg(RADIO, RADIO_EVENTS_DISABLED, 0);
+    write_reg(RADIO, RADIO_INTENSET, RADIO_INTEN_DISABLED);
+}
+
+/// Make sure that the radio is stopped before the end of the timeslot.
+unsafe fn arm_timeslot_guard(length_us: u32) {
+    write_reg(TIMER0, TIMER_CC0, length_us - TIMESLOT_MARGIN_US);
+    write_reg(TIMER0, TIMER_EVENTS_COMPARE0, 0);
+    write_reg(TIMER0, TIMER_INTENSET, TIMER_INTEN_COMPARE0);
+}
+
+/// Stop the radio, and give it back to the softdevice.
+unsafe fn end_timeslot() -> u8 {
+    write_reg(RADIO, RADIO_SHORTS, 0);
+    write_reg(RADIO, RADIO_INTENCLR, u32::MAX);
+    write_reg(TIMER0, TIMER_INTENCLR, u32::MAX);
+    write_reg(RADIO, RADIO_TASKS_DISABLE, 1);
+    ACTION_END
+}
+
+unsafe fn finish_signal(action: u8) -> *mut raw::nrf_radio_signal_callback_return_param_t {
+    (*RETURN_PARAM.get()).callback_action = action;
+    RETURN_PARAM.get()
+}
+
+// Transmitter (keyboard)
+
+const TX_IDLE: u8 = 0;
+const TX_PENDING: u8 = 1;
+const TX_ACTIVE: u8 = 2;
+const TX_ACKED: u8 = 3;
+const TX_FAILED: u8 = 4;
+
+/// Progress of the packet in [`TX_PACKET`]. The task owns [`TX_PACKET`] and [`ACK_PACKET`] while
+/// this is [`TX_IDLE`], [`TX_ACKED`] or [`TX_FAILED`], and the timeslot callbacks own them
+/// otherwise.
+static TX_STATE: AtomicU8 = AtomicU8::new(TX_IDLE);
+static TX_PACKET: PacketBuffer = SharedCell::new([0; PACKET_LEN]);
+static ACK_PACKET: PacketBuffer = SharedCell::new([0; PACKET_LEN]);
+
+const PHASE_TX: u8 = 0;
+const PHASE_WAIT_ACK: u8 = 1;
+const PHASE_RETRANSMIT: u8 = 2;
+const PHASE_RX: u8 = 3;
+const PHASE_ACK: u8 = 4;
+const PHASE_RESTART: u8 = 5;
+
+/// What the radio is currently doing. Only used by the timeslot callbacks.
+static PHASE: AtomicU8 = AtomicU8::new(PHASE_TX);
+static TX_ATTEMPTS: AtomicU8 = AtomicU8::new(0);
+
+unsafe fn start_transmit() {
+    TX_ATTEMPTS.fetch_add(1, Ordering::Relaxed);
+    PHASE.store(PHASE_TX, Ordering::Relaxed);
+
+    // Switch to RX as soon as the packet has been sent, to receive the acknowledgement
+    write_reg(
+        RADIO,
+        RADIO_SHORTS,
+        RADIO_SHORTS_READY_START | RADIO_SHORTS_END_DISABLE | RADIO_SHORTS_DISABLED_RXEN,
+    );
+    write_reg(RADIO, RADIO_PACKETPTR, TX_PACKET.get() as u32);
+    write_reg(RADIO, RADIO_TASKS_TXEN, 1);
+}
+
+unsafe fn retransmit() -> u8 {
+    if TX_ATTEMPTS.load(Ordering::Relaxed) >= MAX_ATTEMPTS {
+        TX_STATE.store(TX_FAILED, Ordering::Release);
+        return end_timeslot();
+    }
+
+    start_transmit();
+    ACTION_NONE
+}
+
+unsafe fn arm_ack_timeout(timeout_us: u32) {
+    write_reg(TIMER0, TIMER_TASKS_CAPTURE1, 1);
+    let now = read_reg(TIMER0, TIMER_CC1);
+    write_reg(TIMER0, TIMER_CC1, now + timeout_us);
+    write_reg(TIMER0, TIMER_EVENTS_COMPARE1, 0);
+    write_reg(TIMER0, TIMER_INTENSET, TIMER_INTEN_COMPARE1);
+}
+
+unsafe fn disarm_ack_timeout() {
+    write_reg(TIMER0, TIMER_INTENCLR, TIMER_INTEN_COMPARE1);
+    write_reg(TIMER0, TIMER_EVENTS_COMPARE1, 0);
+}
+
+unsafe fn on_transmitter_radio_event() -> u8 {
+    if read_reg(RADIO, RADIO_EVENTS_DISABLED) == 0 {
+        return ACTION_NONE;
+    }
+    write_reg(RADIO, RADIO_EVENTS_DISABLED, 0);
+
+    match PHASE.load(Ordering::Relaxed) {
+        PHASE_TX => {
+            // The packet was sent, and the radio is now switching to RX
+            write_reg(
+                RADIO,
+                RADIO_SHORTS,
+                RADIO_SHORTS_READY_START | RADIO_SHORTS_END_DISABLE,
+            );
+            write_reg(RADIO, RADIO_PACKETPTR, ACK_PACKET.get() as u32);
+            write_reg(RADIO, RADIO_EVENTS_ADDRESS, 0);
+            arm_ack_timeout(ACK_TIMEOUT_US);
+            PHASE.store(PHASE_WAIT_ACK, Ordering::Relaxed);
+            ACTION_NONE
+        }
+        PHASE_WAIT_ACK => {
+            disarm_ack_timeout();
+
+            if read_reg(RADIO, RADIO_CRCSTATUS) == 1 {
+                TX_STATE.store(TX_ACKED, Ordering::Release);
+                end_timeslot()
+            } else {
+                retransmit()
+            }
+        }
+        _ => retransmit(),
+    }
+}
+
+unsafe fn on_transmitter_timer_event() -> u8 {
+    if read_reg(TIMER0, TIMER_EVENTS_COMPARE0) != 0 {
+        write_reg(TIMER0, TIMER_EVENTS_COMPARE0, 0);
+        TX_STATE.store(TX_FAILED, Ordering::Release);
+        return end_timeslot();
+    }
+
+    if read_reg(TIMER0, TIMER_EVENTS_COMPARE1) != 0 {
+        disarm_ack_timeout();
+
+        // If an acknowledgement is already being received, wait for it to finish
+        if read_reg(RADIO, RADIO_EVENTS_ADDRESS) == 0 {
+            PHASE.store(PHASE_RETRANSMIT, Ordering::Relaxed);
+            write_reg(RADIO, RADIO_SHORTS, 0);
+            write_reg(RADIO, RADIO_TASKS_DISABLE, 1);
+        }
+    }
+
+    ACTION_NONE
+}
+
+unsafe extern "C" fn transmitter_signal_callback<K: EsbDevice>(
+    signal_type: u8,
+) -> *mut raw::nrf_radio_signal_callback_return_param_t {
+    let action = match signal_type {
+        SIGNAL_START => {
+            if TX_STATE
+                .compare_exchange(TX_PENDING, TX_ACTIVE, Ordering::AcqRel, Ordering::Acquire)
+                .is_err()
+            {
+                // The task stopped waiting for this timeslot
+                ACTION_END
+            } else {
+                configure_radio(K::ESB_ADDRESS, K::ESB_CHANNEL);
+                arm_timeslot_guard(TRANSMITTER_TIMESLOT_US);
+                TX_ATTEMPTS.store(0, Ordering::Relaxed);
+                start_transmit();
+                ACTION_NONE
+            }
+        }
+        SIGNAL_RADIO => on_transmitter_radio_event(),
+        SIGNAL_TIMER0 => on_transmitter_timer_event(),
+        _ => ACTION_NONE,
+    };
+
+    finish_signal(action)
+}
+
+#[derive(Debug)]
+enum TransmitError {
+    /// The softdevice rejected the timeslot request.
+    Request,
+    /// The softdevice could not grant a timeslot in time.
+    Blocked,
+    /// The dongle did not acknowledge the packet.
+    NoAck,
+}
+
+/// Send the packet in [`TX_PACKET`], and return the payload attached to the acknowledgement.
+async fn transmit() -> Result<Option<MessageToKeyboard>, TransmitError> {
+    TIMESLOT_EVENT_SIGNAL.reset();
+    TX_STATE.store(TX_PENDING, Ordering::Release);
+
+    if let Err(err) = RawError::convert(unsafe { raw::sd_radio_request(TRANSMITTER_REQUEST.get()) })
+    {
+        warn!("[ESB] Could not request radio timeslot: {:?}", err);
+        TX_STATE.store(TX_IDLE, Ordering::Release);
+        return Err(TransmitError::Request);
+    }
+
+    loop {
+        // The softdevice reports when our timeslot has ended. The timeout is just a precaution, in
+        // case that event gets lost.
+        let _ = with_timeout(
+            Duration::from_micros(TIMESLOT_TIMEOUT_US as u64),
+            TIMESLOT_EVENT_SIGNAL.wait(),
+        )
+        .await;
+
+        match TX_STATE.load(Ordering::Acquire) {
+            TX_ACKED => {
+                let ack = postcard::from_bytes(unsafe { ACK_PACKET.payload() }).ok();
+                TX_STATE.store(TX_IDLE, Ordering::Release);
+                return Ok(ack);
+            }
+            TX_FAILED => {
+                TX_STATE.store(TX_IDLE, Ordering::Release);
+                return Err(TransmitError::NoAck);
+            }
+            TX_PENDING => {
+                // The timeslot hasn't started, so it must have been blocked or cancelled
+                if TX_STATE
+                    .compare_exchange(TX_PENDING, TX_IDLE, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+                {
+                    return Err(TransmitError::Blocked);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Wait for the next HID report to send to the dongle.
+async fn receive_hid_report() -> MessageToDongle {
+    let keyboard_fut = async {
+        let report = KEYBOARD_REPORT_HID_SEND_CHANNEL.receive().await;
+        MessageToDongle::Keyboard(report.pack().unwrap())
+    };
+    let consumer_fut = async {
+        let report = CONSUMER_REPORT_HID_SEND_CHANNEL.receive().await;
+        MessageToDongle::Consumer(report.pack().unwrap())
+    };
+    let system_control_fut = async {
+        let report = SYSTEM_CONTROL_REPORT_HID_SEND_CHANNEL.receive().await;
+        MessageToDongle::SystemControl(report.pack().unwrap())
+    };
+
+    match select3(keyboard_fut, consumer_fut, system_control_fut).await {
+        select::Either3::First(message)
+        | select::Either3::Second(message)
+        | select::Either3::Third(message) => message,
+    }
+}
+
+/// Wait for the next message to send to the dongle. HID reports are only sent while the dongle is
+/// the current output, but the battery level is always sent periodically, to check whether the
+/// dongle is in range.
+async fn next_message() -> MessageToDongle {
+    loop {
+        let keepalive_fut = async {
+            Timer::after(KEEPALIVE_INTERVAL).await;
+            MessageToDongle::BatteryLevel(BATTERY_STATE.get().await.level)
+        };
+
+        if matches!(CURRENT_OUTPUT_STATE.get().await, Some(HIDOutput::Esb)) {
+            let message_fut = select(receive_hid_report(), keepalive_fut);
+
+            match select(CURRENT_OUTPUT_STATE_LISTENER.wait(), message_fut).await {
+                select::Either::First(()) => continue,
+                select::Either::Second(
+                    select::Either::First(message) | select::Either::Second(message),
+                ) => return message,
+            }
+        } else {
+            match select(CURRENT_OUTPUT_STATE_LISTENER.wait(), keepalive_fut).await {
+                select::Either::First(()) => continue,
+                select::Either::Second(message) => return message,
+            }
+        }
+    }
+}
+
+/// Task that sends HID reports to the ESB dongle while [`HIDOutput::Esb`] is the current output,
+/// and keeps track of whether the dongle is in range.
+#[rumcake_macros::task]
+pub async fn esb_transmitter_task<K: EsbDevice>(_k: K, _sd: &'static Softdevice) {
+    if let Err(err) = RawError::convert(unsafe {
+        raw::sd_radio_session_open(Some(transmitter_signal_callback::<K>))
+    }) {
+        error!("[ESB] Could not open radio timeslot session: {:?}", err);
+        return;
+    }
+
+    info!("[ESB] ESB transmitter started");
+
+    let mut pid = 0;
+    let mut buf = [0; ESB_MAX_PAYLOAD_LEN];
+
+    loop {
+        let message = next_message().await;
+        let payload = match postcard::to_slice(&message, &mut buf) {
+            Ok(payload) => payload,
+            Err(err) => {
+                error!(
+                    "[ESB] Could not serialize message {:?}: {:?}",
+                    Debug2Format(&message),
+                    Debug2Format(&err)
+                );
+                continue;
+            }
+        };
+
+        // A new packet ID lets the dongle tell this packet apart from a retransmit of the last one
+        pid = (pid + 1) % 4;
+        unsafe { TX_PACKET.set_payload(pid, payload) };
+
+        let mut failed_timeslots = 0;
+        loop {
+            match transmit().await {
+                Ok(ack) => {
+                    ESB_CONNECTED_STATE.set(true).await;
+
+                    // Only use LED reports from the dongle's host if it is the one receiving our
+                    // HID reports
+                    if let Some(MessageToKeyboard::LedIndicators(bits)) = ack {
+                        if matches!(CURRENT_OUTPUT_STATE.get().await, Some(HIDOutput::Esb)) {
+                            LED_INDICATORS_STATE
+                                .set(LedIndicators::from_bits_truncate(bits))
+                                .await;
+                        }
+                    }
+
+                    break;
+                }
+                Err(err) => {
+                    debug!("[ESB] Could not send packet: {:?}", Debug2Format(&err));
+                    failed_timeslots += 1;
+
+                    if failed_timeslots >= MAX_FAILED_TIMESLOTS {
+                        if ESB_CONNECTED_STATE.get().await {
+                            warn!("[ESB] Lost connection to dongle");
+                        }
+                        ESB_CONNECTED_STATE.set(false).await;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Receiver (dongle)
+
+/// Packets received by the timeslot callbacks, waiting to be processed by the receiver task. The
+/// callbacks only write to [`RX_QUEUE_HEAD`], and the task only writes to [`RX_QUEUE_TAIL`].
+static RX_QUEUE: [PacketBuffer; RX_QUEUE_SIZE] = {
+    const EMPTY: PacketBuffer = SharedCell::new([0; PACKET_LEN]);
+    [EMPTY; RX_QUEUE_SIZE]
+};
+static RX_QUEUE_HEAD: AtomicUsize = AtomicUsize::new(0);
+static RX_QUEUE_TAIL: AtomicUsize = AtomicUsize::new(0);
+
+/// Only used by the timeslot callbacks.
+static RX_PACKET: PacketBuffer = SharedCell::new([0; PACKET_LEN]);
+static RX_ACK_PACKET: PacketBuffer = SharedCell::new([0; PACKET_LEN]);
+
+/// CRC and packet ID of the last packet received, used to discard retransmitted packets. Only used
+/// by the timeslot callbacks.
+static RX_LAST_PACKET_ID: AtomicU32 = AtomicU32::new(u32::MAX);
+
+/// Lock LEDs to send back to the keyboard in acknowledgements.
+static ACK_LED_INDICATORS: AtomicU8 = AtomicU8::new(0);
+
+unsafe fn start_receive() {
+    PHASE.store(PHASE_RX, Ordering::Relaxed);
+
+    // Switch to TX as soon as a packet has been received, to send the acknowledgement
+    write_reg(
+        RADIO,
+        RADIO_SHORTS,
+        RADIO_SHORTS_READY_START | RADIO_SHORTS_END_DISABLE | RADIO_SHORTS_DISABLED_TXEN,
+    );
+    write_reg(RADIO, RADIO_PACKETPTR, RX_PACKET.get() as u32);
+    write_reg(RADIO, RADIO_TASKS_RXEN, 1);
+}
+
+unsafe fn queue_received_packet() {
+    let head = RX_QUEUE_HEAD.load(Ordering::Relaxed);
+    let next = (head + 1) % RX_QUEUE_SIZE;
+
+    // Drop the packet if the queue is full
+    if next == RX_QUEUE_TAIL.load(Ordering::Acquire) {
+        return;
+    }
+
+    *RX_QUEUE[head].get() = *RX_PACKET.get();
+    RX_QUEUE_HEAD.store(next, Ordering::Release);
+}
+
+fn dequeue_received_packet() -> Option<Vec<u8, ESB_MAX_PAYLOAD_LEN>> {
+    let tail = RX_QUEUE_TAIL.load(Ordering::Relaxed);
+    if tail == RX_QUEUE_HEAD.load(Ordering::Acquire) {
+        return None;
+    }
+
+    let payload = Vec::from_slice(unsafe { RX_QUEUE[tail].payload() }).ok();
+    RX_QUEUE_TAIL.store((tail + 1) % RX_QUEUE_SIZE, Ordering::Release);
+    payload
+}
+
+unsafe fn on_receiver_radio_event() -> u8 {
+    if read_reg(RADIO, RADIO_EVENTS_DISABLED) == 0 {
+        return ACTION_NONE;
+    }
+    write_reg(RADIO, RADIO_EVENTS_DISABLED, 0);
+
+    match PHASE.load(Ordering::Relaxed) {
+        PHASE_RX if read_reg(RADIO, RADIO_CRCSTATUS) == 1 => {
+            // The radio is now switching to TX, so the acknowledgement must be ready before the
+            // radio finishes ramping up
+            let pid = RX_PACKET.pid();
+            let mut ack = [0; ESB_MAX_PAYLOAD_LEN];
+            let leds = ACK_LED_INDICATORS.load(Ordering::Relaxed);
+            let ack = postcard::to_slice(&MessageToKeyboard::LedIndicators(leds), &mut ack)
+                .map(|ack| &*ack)
+                .unwrap_or_default();
+            RX_ACK_PACKET.set_payload(pid, ack);
+
+            write_reg(
+                RADIO,
+                RADIO_SHORTS,
+                RADIO_SHORTS_READY_START | RADIO_SHORTS_END_DISABLE | RADIO_SHORTS_DISABLED_RXEN,
+            );
+            write_reg(RADIO, RADIO_PACKETPTR, RX_ACK_PACKET.get() as u32);
+            PHASE.store(PHASE_ACK, Ordering::Relaxed);
+
+            // Retransmitted packets are acknowledged again, but not processed again
+            let packet_id = (read_reg(RADIO, RADIO_RXCRC) << 2) | pid as u32;
+            if RX_LAST_PACKET_ID.swap(packet_id, Ordering::Relaxed) != packet_id {
+                queue_received_packet();
+            }
+        }
+        PHASE_RX => {
+            // Corrupted packets are not acknowledged, so stop the radio from switching to TX
+            write_reg(RADIO, RADIO_SHORTS, 0);
+            write_reg(RADIO, RADIO_TASKS_DISABLE, 1);
+            PHASE.store(PHASE_RESTART, Ordering::Relaxed);
+        }
+        PHASE_ACK => {
+            // The acknowledgement was sent, and the radio is now switching back to RX
+            write_reg(
+                RADIO,
+                RADIO_SHORTS,
+                RADIO_SHORTS_READY_START | RADIO_SHORTS_END_DISABLE | RADIO_SHORTS_DISABLED_TXEN,
+            );
+            write_reg(RADIO, RADIO_PACKETPTR, RX_PACKET.get() as u32);
+            PHASE.store(PHASE_RX, Ordering::Relaxed);
+        }
+        _ => start_receive(),
+    }
+
+    ACTION_NONE
+}
+
+unsafe extern "C" fn receiver_signal_callback<K: EsbDevice>(
+    signal_type: u8,
+) -> *mut raw::nrf_radio_signal_callback_return_param_t {
+    let action = match signal_type {
+        SIGNAL_START => {
+            configure_radio(K::ESB_ADDRESS, K::ESB_CHANNEL);
+            arm_timeslot_guard(RECEIVER_TIMESLOT_US);
+            start_receive();
+            ACTION_NONE
+        }
+        SIGNAL_RADIO => on_receiver_radio_event(),
+        SIGNAL_TIMER0 if read_reg(TIMER0, TIMER_EVENTS_COMPARE0) != 0 => {
+            write_reg(TIMER0, TIMER_EVENTS_COMPARE0, 0);
+            end_timeslot();
+
+            // Keep listening in the next timeslot
+            (*RETURN_PARAM.get()).params.request.p_next = RECEIVER_REQUEST.get();
+            ACTION_REQUEST_AND_END
+        }
+        _ => ACTION_NONE,
+    };
+
+    finish_signal(action)
+}
+
+/// Task that receives packets from an ESB keyboard, and forwards them to the dongle task. This
+/// should be used by the dongle.
+#[rumcake_macros::task]
+pub async fn esb_receiver_task<K: EsbDevice>(_k: K, _sd: &'static Softdevice) {
+    if let Err(err) = RawError::convert(unsafe {
+        raw::sd_radio_session_open(Some(receiver_signal_callback::<K>))
+    }) {
+        error!("[ESB] Could not open radio timeslot session: {:?}", err);
+        return;
+    }
+
+    info!("[ESB] ESB receiver started");
+
+    let mut listening = false;
+    let mut connected = false;
+    let mut last_packet = Instant::now();
+
+    loop {
+        if !listening {
+            match RawError::convert(unsafe { raw::sd_radio_request(RECEIVER_REQUEST.get()) }) {
+                Ok(()) => listening = true,
+                Err(err) => warn!("[ESB] Could not request radio timeslot: {:?}", err),
+            }
+        }
+
+        while let Some(payload) = dequeue_received_packet() {
+            last_packet = Instant::now();
+            if !connected {
+                info!("[ESB] Keyboard connected");
+                connected = true;
+                DONGLE_EVENT_CHANNEL
+                    .send((ESB_KEYBOARD_SLOT, DongleEvent::Connected))
+                    .await;
+            }
+
+            match postcard::from_bytes::<MessageToDongle>(&payload) {
+                Ok(message) => {
+                    DONGLE_EVENT_CHANNEL
+                        .send((ESB_KEYBOARD_SLOT, DongleEvent::Message(message)))
+                        .await
+                }
+                Err(err) => warn!("[ESB] Invalid message: {:?}", Debug2Format(&err)),
+            }
+        }
+
+        // The dongle task releases any keys that were held when the keyboard went out of range
+        if connected && last_packet.elapsed() > LINK_TIMEOUT {
+            warn!("[ESB] Lost connection to keyboard");
+            connected = false;
+            DONGLE_EVENT_CHANNEL
+                .send((ESB_KEYBOARD_SLOT, DongleEvent::Disconnected))
+                .await;
+        }
+
+        if let Some(leds) = LED_INDICATORS_STATE.try_get() {
+            ACK_LED_INDICATORS.store(leds.bits(), Ordering::Relaxed);
+        }
+
+        // Our chain of timeslots stops if the softdevice blocks or cancels one of them, in which
+        // case a new chain must be started
+        if let select::Either::First(()) = select(
+            TIMESLOT_EVENT_SIGNAL.wait(),
+            Timer::after(RECEIVER_POLL_INTERVAL),
+        )
+        .await
+        {
+            listening = false;
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! Gamepad features.
+//!
+//! Gamepad buttons and axes can be bound to keys using [`crate::keyboard::Keycode::Gamepad`].
+//! Analog inputs (e.g. a thumbstick connected to an ADC) can be reported by sending
+//! [`GamepadCommand::SetAxis`] to [`GAMEPAD_COMMAND_CHANNEL`].
+
+use defmt::{debug, warn, Debug2Format};
+use embassy_sync::channel::Channel;
+use packed_struct::prelude::PackedStruct;
+
+use crate::hw::mcu::RawMutex;
+use crate::hw::{HIDOutput, CURRENT_OUTPUT_STATE};
+
+/// Report descriptor for a gamepad with 32 buttons and 4 axes (X, Y, Z and Rz). The axes are
+/// usually used for two thumbsticks.
+pub const GAMEPAD_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x05, // Usage (Game Pad)
+    0xA1, 0x01, // Collection (Application)
+    // Buttons
+    0x05, 0x09, //   Usage Page (Button)
+    0x19, 0x01, //   Usage Minimum (1)
+    0x29, 0x20, //   Usage Maximum (32)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x20, //   Report Count (32)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    // Axes
+    0x05, 0x01, //   Usage Page (Generic Desktop)
+    0x09, 0x30, //   Usage (X)
+    0x09, 0x31, //   Usage (Y)
+    0x09, 0x32, //   Usage (Z)
+    0x09, 0x35, //   Usage (Rz)
+    0x15, 0x81, //   Logical Minimum (-127)
+    0x25, 0x7F, //   Logical Maximum (127)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x04, //   Report Count (4)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0xC0, // End Collection
+];
+
+/// A HID report for the gamepad described by [`GAMEPAD_REPORT_DESCRIPTOR`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PackedStruct)]
+#[packed_struct(endian = "lsb", bit_numbering = "msb0")]
+pub struct GamepadReport {
+    /// Bitmap of pressed buttons. Bit 0 corresponds to button 1.
+    #[packed_field]
+    pub buttons: u32,
+    /// X axis.
+    #[packed_field]
+    pub x: i8,
+    /// Y axis.
+    #[packed_field]
+    pub y: i8,
+    /// Z axis.
+    #[packed_field]
+    pub z: i8,
+    /// Rz axis.
+    #[packed_field]
+    pub rz: i8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// An enumeration of the axes available in a [`GamepadReport`].
+pub enum GamepadAxis {
+    /// X axis, usually the horizontal axis of the left thumbstick.
+    X,
+    /// Y axis, usually the vertical axis of the left thumbstick.
+    Y,
+    /// Z axis, usually the horizontal axis of the right thumbstick.
+    Z,
+    /// Rz axis, usually the vertical axis of the right thumbstick.
+    Rz,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// An enumeration of possible gamepad actions that can be used in a keyboard layout.
+pub enum GamepadKeycode {
+    /// Press a gamepad button, from 0 to 31.
+    Button(u8),
+    /// Move an axis to the given value while the key is held. The axis returns to 0 when the key
+    /// is released.
+    Axis(GamepadAxis, i8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// An enumeration of possible commands that will be processed by the gamepad task.
+pub enum GamepadCommand {
+    /// Press a gamepad button, from 0 to 31.
+    PressButton(u8),
+    /// Release a gamepad button, from 0 to 31.
+    ReleaseButton(u8),
+    /// Set the value of an axis. This can be used to report analog inputs.
+    SetAxis(GamepadAxis, i8),
+}
+
+/// Channel for sending gamepad commands.
+///
+/// Channel messages should be consumed by the [`gamepad_task`], so user-level code should
+/// **not** attempt to receive messages from the channel, otherwise commands may not be processed
+/// appropriately. You should only send to this channel.
+pub static GAMEPAD_COMMAND_CHANNEL: Channel<RawMutex, GamepadCommand, 4> = Channel::new();
+
+/// Channel for sending gamepad HID reports.
+///
+/// Channel messages should be consumed by the USB task, so user-level code should **not**
+/// attempt to receive messages from the channel, otherwise commands may not be processed
+/// appropriately. You should only send to this channel.
+pub static GAMEPAD_REPORT_HID_SEND_CHANNEL: Channel<RawMutex, GamepadReport, 1> = Channel::new();
+
+impl From<(GamepadKeycode, bool)> for GamepadCommand {
+    fn from((keycode, pressed): (GamepadKeycode, bool)) -> Self {
+        match keycode {
+            GamepadKeycode::Button(button) => {
+                if pressed {
+                    GamepadCommand::PressButton(button)
+                } else {
+                    GamepadCommand::ReleaseButton(button)
+                }
+            }
+            GamepadKeycode::Axis(axis, value) => {
+                GamepadCommand::SetAxis(axis, if pressed { value } else { 0 })
+            }
+        }
+    }
+}
+
+#[rumcake_macros::task]
+pub async fn gamepad_task() {
+    let mut report = GamepadReport::default();
+
+    loop {
+        let command = GAMEPAD_COMMAND_CHANNEL.receive().await;
+        let last_report = report;
+
+        match command {
+            GamepadCommand::PressButton(button) | GamepadCommand::ReleaseButton(button)
+                if button > 31 =>
+            {
+                warn!("[GAMEPAD] Ignoring invalid gamepad button: {}", button);
+            }
+            GamepadCommand::PressButton(button) => {
+                report.buttons |= 1 << button;
+            }
+            GamepadCommand::ReleaseButton(button) => {
+                report.buttons &= !(1 << button);
+            }
+            GamepadCommand::SetAxis(axis, value) => {
+                // -128 can't be represented by the report descriptor
+                let value = value.max(-127);
+                match axis {
+                    GamepadAxis::X => report.x = value,
+                    GamepadAxis::Y => report.y = value,
+                    GamepadAxis::Z => report.z = value,
+                    GamepadAxis::Rz => report.rz = value,
+                }
+            }
+        }
+
+        if report == last_report {
+            continue;
+        }
+
+        // Gamepad reports are currently only supported over USB. If USB is not the current
+        // output, the channel can become filled, so we discard the report in that case.
+        if matches!(CURRENT_OUTPUT_STATE.get().await, Some(HIDOutput::Usb)) {
+            debug!(
+                "[GAMEPAD] Sending gamepad report: {:?}",
+                Debug2Format(&report)
+            );
+            GAMEPAD_REPORT_HID_SEND_CHANNEL.send(report).await;
+        } else {
+            warn!("[GAMEPAD] Discarding report");
+        }
+    }
+}
@@ -14,8 +14,9 @@ use embassy_nrf::bind_interrupts;
 use embassy_nrf::gpio::Output;
 use embassy_nrf::interrupt::{InterruptExt, Priority};
 use embassy_nrf::nvmc::Nvmc;
-use embassy_nrf::peripherals::SAADC;
+use embassy_nrf::peripherals::{QDEC, SAADC};
 use embassy_nrf::ppi::ConfigurableChannel;
+use embassy_nrf::qdec::Qdec;
 use embassy_nrf::saadc::{ChannelConfig, Input, Saadc, VddhDiv5Input};
 use embassy_nrf::timer::Instance;
 use embassy_nrf::usb::Driver;
@@ -26,11 +27,13 @@ use embassy_sync::signal::Signal;
 use embassy_time::{Duration, Timer};
 use static_cell::StaticCell;
 
-use crate::hw::BATTERY_LEVEL_STATE;
+use crate::encoder::Encoder;
+use crate::hw::BATTERY_STATE;
 use crate::keyboard::MatrixSampler;
 
 pub use rumcake_macros::{
-    input_pin, output_pin, setup_adc_sampler, setup_buffered_uarte, setup_i2c, setup_i2c_blocking,
+    input_pin, interrupt_input_pin, open_drain_pin, output_pin, setup_adc_sampler,
+    setup_buffered_uarte, setup_i2c, setup_i2c_blocking,
 };
 
 pub use embassy_nrf;
@@ -43,11 +46,67 @@ use super::Multiplexer;
 #[cfg(feature = "nrf52840")]
 pub const SYSCLK: u32 = 48_000_000;
 
+/// Start address of the MCU's internal flash.
+pub const FLASH_ORIGIN: usize = 0x0000_0000;
+
 pub type RawMutex = ThreadModeRawMutex;
 pub type BlockingMutex<T> = ThreadModeMutex<T>;
 
+/// Address of the `DEVICEID` registers in the FICR.
+const FICR_DEVICEID: usize = 0x1000_0060;
+
+/// Get the unique device ID of the MCU.
+pub fn device_id() -> &'static [u8] {
+    // The FICR is read-only, so it is safe to treat the DEVICEID registers as a static slice
+    unsafe { core::slice::from_raw_parts(FICR_DEVICEID as *const u8, 8) }
+}
+
+/// Address of the `GPREGRET` register in the `POWER` peripheral. This register is retained
+/// through a reset, and is used to tell the bootloader to enter DFU mode.
+const POWER_GPREGRET: usize = 0x4000_051C;
+
+/// Value written to `GPREGRET` that tells the Adafruit nRF52 bootloader to enter UF2 mode.
+const DFU_MAGIC_UF2_RESET: u8 = 0x57;
+
+#[cfg(feature = "ota")]
+/// Value written to `GPREGRET` that tells the Adafruit nRF52 bootloader to enter OTA (Bluetooth)
+/// DFU mode.
+const DFU_MAGIC_OTA_RESET: u8 = 0xA8;
+
+/// Write `magic` to `GPREGRET`, and reset, so that the bootloader can read it.
+fn reset_to_bootloader(magic: u8) -> ! {
+    // If the SoftDevice is enabled, we can't access the POWER peripheral directly
+    #[cfg(feature = "nrf-ble")]
+    unsafe {
+        let mut enabled = 0;
+        if nrf_softdevice::raw::sd_softdevice_is_enabled(&mut enabled) == 0 && enabled != 0 {
+            nrf_softdevice::raw::sd_power_gpregret_clr(0, 0xFF);
+            nrf_softdevice::raw::sd_power_gpregret_set(0, magic as u32);
+            nrf_softdevice::raw::sd_nvic_SystemReset();
+        }
+    }
+
+    unsafe { core::ptr::write_volatile(POWER_GPREGRET as *mut u32, magic as u32) };
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+/// A function that allows you to jump to the bootloader, usually for re-flashing the firmware.
+///
+/// This assumes that you are using the [Adafruit nRF52
+/// bootloader](https://github.com/adafruit/Adafruit_nRF52_Bootloader), which is also used by the
+/// nice!nano. The bootloader will start in UF2 mode.
 pub fn jump_to_bootloader() {
-    // TODO
+    reset_to_bootloader(DFU_MAGIC_UF2_RESET);
+}
+
+#[cfg(feature = "ota")]
+/// Jump to the bootloader in OTA DFU mode, so that new firmware can be flashed over Bluetooth.
+///
+/// Like [`jump_to_bootloader`], this assumes that you are using the Adafruit nRF52 bootloader. The
+/// bootloader will advertise as `AdaDFU`, and accept firmware updates from apps like nRF Connect or
+/// Adafruit Bluefruit Connect.
+pub fn jump_to_ota_bootloader() {
+    reset_to_bootloader(DFU_MAGIC_OTA_RESET);
 }
 
 pub fn initialize_rcc() {
@@ -67,7 +126,13 @@ static VBUS_DETECT: once_cell::sync::OnceCell<embassy_nrf::usb::vbus_detect::Sof
 /// communicates with a host device over USB.
 pub fn setup_usb_driver<K: crate::usb::USBKeyboard + 'static>() -> embassy_usb::Builder<
     'static,
-    Driver<'static, embassy_nrf::peripherals::USBD, impl embassy_nrf::usb::vbus_detect::VbusDetect>,
+    crate::os_detection::OsDetectionDriver<
+        Driver<
+            'static,
+            embassy_nrf::peripherals::USBD,
+            impl embassy_nrf::usb::vbus_detect::VbusDetect,
+        >,
+    >,
 > {
     unsafe {
         #[cfg(feature = "nrf52840")]
@@ -85,6 +150,7 @@ pub fn setup_usb_driver<K: crate::usb::USBKeyboard + 'static>() -> embassy_usb::
         config.manufacturer.replace(K::MANUFACTURER);
         config.product.replace(K::PRODUCT);
         config.serial_number.replace(K::SERIAL_NUMBER);
+        config.supports_remote_wakeup = K::USB_REMOTE_WAKEUP;
         config.max_power = 100;
 
         #[cfg(feature = "nrf-ble")]
@@ -111,15 +177,19 @@ pub fn setup_usb_driver<K: crate::usb::USBKeyboard + 'static>() -> embassy_usb::
         static CONTROL_BUF: StaticCell<[u8; 128]> = StaticCell::new();
         let control_buf = CONTROL_BUF.init([0; 128]);
 
-        embassy_usb::Builder::new(
-            usb_driver,
+        let mut builder = embassy_usb::Builder::new(
+            crate::os_detection::OsDetectionDriver::new(usb_driver),
             config,
             device_descriptor,
             config_descriptor,
             bos_descriptor,
             msos_descriptor,
             control_buf,
-        )
+        );
+
+        K::setup_usb_interfaces(&mut builder);
+
+        builder
     }
 }
 
@@ -135,6 +205,8 @@ impl crate::storage::FlashStorage for Flash {
 
     const ERASE_SIZE: usize = <Nvmc as embedded_storage::nor_flash::NorFlash>::ERASE_SIZE;
 
+    const WRITE_SIZE: usize = <Nvmc as embedded_storage::nor_flash::NorFlash>::WRITE_SIZE;
+
     async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
         use embedded_storage::nor_flash::NorFlash;
         self.flash.erase(from, to)
@@ -397,7 +469,12 @@ pub async fn adc_task<'a, const MP: usize, const N: usize>(
                 (mv * 2 / 15 - 459) as u8
             };
 
-            BATTERY_LEVEL_STATE.set(pct).await;
+            BATTERY_STATE
+                .update(|status| {
+                    status.level = pct;
+                    status.voltage_mv = Some(mv.max(0) as u16);
+                })
+                .await;
 
             Timer::after(Duration::from_secs(10)).await;
         }
@@ -408,21 +485,132 @@ pub async fn adc_task<'a, const MP: usize, const N: usize>(
     error!("[NRF_ADC] ADC sampler has stopped. This should not happen.");
 }
 
+/// Address of the `READCLRACC` task of the `QDEC` peripheral, which copies the accumulated
+/// position into `ACCREAD`, and clears it.
+const QDEC_TASKS_READCLRACC: usize = 0x4001_2008;
+
+/// Address of the `ACCREAD` register of the `QDEC` peripheral.
+const QDEC_ACCREAD: usize = 0x4001_2518;
+
+/// A rotary encoder decoded by the QDEC peripheral. The QDEC peripheral samples and debounces the
+/// encoder pins, and accumulates the encoder's position in hardware, so fast spins don't miss any
+/// detents, and the CPU only needs to read the accumulated position when the encoder is polled.
+///
+/// The nRF52840 only has one QDEC peripheral, so only one encoder can use it. It is recommended
+/// to use [`crate::encoder::build_encoders`] to create this.
+pub struct QdecEncoder {
+    _qdec: Qdec<'static, QDEC>,
+    resolution: u8,
+    pulses: i32,
+}
+
+impl QdecEncoder {
+    /// Create a new encoder using an instance of the QDEC driver. `resolution` is the number of
+    /// pulses that the encoder sends for each detent.
+    pub fn new(qdec: Qdec<'static, QDEC>, resolution: u8) -> Self {
+        Self {
+            _qdec: qdec,
+            resolution: resolution.max(1),
+            pulses: 0,
+        }
+    }
+}
+
+impl Encoder for QdecEncoder {
+    fn poll(&mut self) -> i8 {
+        self.pulses += unsafe {
+            core::ptr::write_volatile(QDEC_TASKS_READCLRACC as *mut u32, 1);
+            core::ptr::read_volatile(QDEC_ACCREAD as *const i32)
+        };
+
+        let detents = self.pulses / self.resolution as i32;
+        self.pulses %= self.resolution as i32;
+        detents.clamp(i8::MIN as i32, i8::MAX as i32) as i8
+    }
+}
+
 #[cfg(feature = "nrf-ble")]
 /// A mutex that is locked when the softdevice is advertising. This is mainly to prevent
 /// [`nrf_softdevice::ble::peripheral::ADV_PORTAL`] from being opened by more than one task at the
 /// same time.
 pub static BLUETOOTH_ADVERTISING_MUTEX: Mutex<RawMutex, ()> = Mutex::new(());
 
+#[cfg(feature = "nrf-ble")]
+/// Possible ways of choosing the bluetooth address used by the keyboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BluetoothAddressMode {
+    /// Always use [`BluetoothDevice::BLUETOOTH_ADDRESS`].
+    Fixed,
+    /// Always use a random static address derived from the MCU's unique device address.
+    /// [`BluetoothDevice::BLUETOOTH_ADDRESS`] is ignored, so the same firmware can be flashed onto
+    /// multiple keyboards without their addresses clashing.
+    DeviceId,
+    /// Use resolvable private addresses, which change every `rotation_secs` seconds, so that the
+    /// keyboard can't be tracked by devices that it isn't bonded with. Bonded host devices can
+    /// still recognize the keyboard. [`BluetoothDevice::BLUETOOTH_ADDRESS`] is used as the
+    /// identity address. `rotation_secs` must be between 1 and 41400.
+    Private { rotation_secs: u16 },
+}
+
 #[cfg(feature = "nrf-ble")]
 /// A basic trait that all nRF5x-based devices that use bluetooth features must implement.
 pub trait BluetoothDevice {
+    /// "Random Static" bluetooth address used by the keyboard. See
+    /// [`BluetoothDevice::BLUETOOTH_ADDRESS_MODE`].
     const BLUETOOTH_ADDRESS: [u8; 6];
+
+    /// How the bluetooth address of the keyboard is chosen. Defaults to
+    /// [`BluetoothAddressMode::Fixed`]. Split keyboards must use [`BluetoothAddressMode::Fixed`],
+    /// so that the halves can find each other.
+    const BLUETOOTH_ADDRESS_MODE: BluetoothAddressMode = BluetoothAddressMode::Fixed;
+}
+
+#[cfg(feature = "nrf-ble")]
+/// Address of the `IR` (identity root) registers in the FICR.
+const FICR_IR: usize = 0x1000_0090;
+
+#[cfg(feature = "nrf-ble")]
+/// Address of the `DEVICEADDR` registers in the FICR.
+const FICR_DEVICEADDR: usize = 0x1000_00A4;
+
+#[cfg(feature = "nrf-ble")]
+/// Get a random static bluetooth address derived from the MCU's unique device address.
+fn device_address() -> [u8; 6] {
+    // The FICR is read-only, so it is safe to read the DEVICEADDR registers directly
+    let mut address = unsafe { core::ptr::read_volatile(FICR_DEVICEADDR as *const [u8; 6]) };
+
+    // The two most significant bits of a random static address must be set
+    address[5] |= 0xC0;
+    address
+}
+
+#[cfg(feature = "nrf-ble")]
+/// Advertise using resolvable private addresses. The identity resolving key is taken from the
+/// FICR, so that it stays the same across reboots, and bonded host devices can keep resolving
+/// the addresses.
+fn enable_privacy(rotation_secs: u16) {
+    use nrf_softdevice::raw;
+
+    let mut irk = raw::ble_gap_irk_t {
+        irk: unsafe { core::ptr::read_volatile(FICR_IR as *const [u8; 16]) },
+    };
+
+    let params = raw::ble_gap_privacy_params_t {
+        privacy_mode: raw::BLE_GAP_PRIVACY_MODE_DEVICE_PRIVACY as u8,
+        private_addr_type: raw::BLE_GAP_ADDR_TYPE_RANDOM_PRIVATE_RESOLVABLE as u8,
+        private_addr_cycle_s: rotation_secs,
+        p_device_irk: &mut irk,
+    };
+
+    let result = unsafe { raw::sd_ble_gap_privacy_set(&params) };
+    if result != raw::NRF_SUCCESS {
+        error!("[NRF_BLE] Could not enable private addresses: {}", result);
+    }
 }
 
 #[cfg(feature = "nrf-ble")]
-/// Initialize the softdevice. This sets the bluetooth address to the one defined in
-/// [`BluetoothDevice::BLUETOOTH_ADDRESS`], and configures the softdevice with some defaults for
+/// Initialize the softdevice. This sets the bluetooth address according to
+/// [`BluetoothDevice::BLUETOOTH_ADDRESS_MODE`], and configures the softdevice with some defaults for
 /// [`nrf_softdevice::Config`].
 pub fn setup_softdevice<K: BluetoothDevice + crate::keyboard::Keyboard>(
 ) -> &'static mut nrf_softdevice::Softdevice {
@@ -447,13 +635,18 @@ pub fn setup_softdevice<K: BluetoothDevice + crate::keyboard::Keyboard>(
             adv_set_count: 1,
             periph_role_count: 4,
             central_role_count: 4,
+            // A dongle bonds with its keyboards as a central
+            #[cfg(feature = "dongle")]
+            central_sec_count: crate::dongle::MAX_BLE_KEYBOARDS as u8,
+            #[cfg(not(feature = "dongle"))]
             central_sec_count: 0,
             _bitfield_1: nrf_softdevice::raw::ble_gap_cfg_role_count_t::new_bitfield_1(0),
         }),
         gap_device_name: Some(nrf_softdevice::raw::ble_gap_cfg_device_name_t {
             p_value: K::PRODUCT.as_ptr() as _,
             current_len: K::PRODUCT.len() as u16,
-            max_len: K::PRODUCT.len() as u16,
+            // Leave room for a bluetooth profile name suffix (e.g. " #2")
+            max_len: K::PRODUCT.len() as u16 + 3,
             write_perm: unsafe { core::mem::zeroed() },
             _bitfield_1: nrf_softdevice::raw::ble_gap_cfg_device_name_t::new_bitfield_1(
                 nrf_softdevice::raw::BLE_GATTS_VLOC_STACK as u8,
@@ -464,10 +657,15 @@ pub fn setup_softdevice<K: BluetoothDevice + crate::keyboard::Keyboard>(
 
     let sd = nrf_softdevice::Softdevice::enable(&config);
 
-    set_address(
-        sd,
-        &Address::new(AddressType::RandomStatic, K::BLUETOOTH_ADDRESS),
-    );
+    let address = match K::BLUETOOTH_ADDRESS_MODE {
+        BluetoothAddressMode::DeviceId => device_address(),
+        BluetoothAddressMode::Fixed | BluetoothAddressMode::Private { .. } => K::BLUETOOTH_ADDRESS,
+    };
+    set_address(sd, &Address::new(AddressType::RandomStatic, address));
+
+    if let BluetoothAddressMode::Private { rotation_secs } = K::BLUETOOTH_ADDRESS_MODE {
+        enable_privacy(rotation_secs);
+    }
 
     sd
 }
@@ -494,6 +692,12 @@ pub async fn softdevice_task(sd: &'static nrf_softdevice::Softdevice) {
         nrf_softdevice::SocEvent::PowerUsbRemoved => {
             vbus_detect.detected(false);
         }
+        #[cfg(feature = "esb")]
+        nrf_softdevice::SocEvent::RadioSessionIdle
+        | nrf_softdevice::SocEvent::RadioBlocked
+        | nrf_softdevice::SocEvent::RadioCanceled => {
+            crate::esb::nrf_esb::TIMESLOT_EVENT_SIGNAL.signal(());
+        }
         _ => {}
     })
     .await;
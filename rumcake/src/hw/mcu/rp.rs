@@ -19,9 +19,11 @@ use embassy_rp::rom_data::reset_to_usb_boot;
 use embassy_rp::usb::Driver;
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
 use embassy_sync::blocking_mutex::ThreadModeMutex;
+use once_cell::sync::OnceCell;
 
 pub use rumcake_macros::{
-    input_pin, output_pin, setup_adc_sampler, setup_buffered_uart, setup_dma_channel, setup_i2c,
+    input_pin, interrupt_input_pin, open_drain_pin, output_pin, setup_adc_sampler,
+    setup_buffered_uart, setup_dma_channel, setup_i2c,
 };
 
 pub use embassy_rp;
@@ -32,9 +34,22 @@ use super::Multiplexer;
 
 pub const SYSCLK: u32 = 125_000_000;
 
+/// Start address of the MCU's internal flash.
+pub const FLASH_ORIGIN: usize = 0x1000_0000;
+
 pub type RawMutex = ThreadModeRawMutex;
 pub type BlockingMutex<T> = ThreadModeMutex<T>;
 
+/// Unique ID of the external flash chip, obtained in [`setup_internal_flash`].
+static DEVICE_ID: OnceCell<[u8; 8]> = OnceCell::new();
+
+/// Get the unique device ID. The RP2040 does not have a unique ID of its own, so the unique ID
+/// of the flash chip is used instead. This will be empty if [`setup_internal_flash`] hasn't been
+/// called yet.
+pub fn device_id() -> &'static [u8] {
+    DEVICE_ID.get().map_or(&[], |id| id)
+}
+
 /// A function that allows you to jump to the bootloader, usually for re-flashing the firmware.
 pub fn jump_to_bootloader() {
     reset_to_usb_boot(0, 0);
@@ -56,7 +71,7 @@ pub fn initialize_rcc() {
 /// need to pass this to [`crate::usb::setup_usb_hid_nkro_writer`] to set up a keyboard that
 /// communicates with a host device over USB.
 pub fn setup_usb_driver<K: crate::usb::USBKeyboard>(
-) -> embassy_usb::Builder<'static, Driver<'static, USB>> {
+) -> embassy_usb::Builder<'static, crate::os_detection::OsDetectionDriver<Driver<'static, USB>>> {
     unsafe {
         #[cfg(feature = "rp2040")]
         bind_interrupts!(
@@ -69,6 +84,7 @@ pub fn setup_usb_driver<K: crate::usb::USBKeyboard>(
         config.manufacturer.replace(K::MANUFACTURER);
         config.product.replace(K::PRODUCT);
         config.serial_number.replace(K::SERIAL_NUMBER);
+        config.supports_remote_wakeup = K::USB_REMOTE_WAKEUP;
         config.max_power = 500;
 
         let usb_driver = Driver::new(USB::steal(), Irqs);
@@ -86,15 +102,19 @@ pub fn setup_usb_driver<K: crate::usb::USBKeyboard>(
         static CONTROL_BUF: static_cell::StaticCell<[u8; 128]> = static_cell::StaticCell::new();
         let control_buf = CONTROL_BUF.init([0; 128]);
 
-        embassy_usb::Builder::new(
-            usb_driver,
+        let mut builder = embassy_usb::Builder::new(
+            crate::os_detection::OsDetectionDriver::new(usb_driver),
             config,
             device_descriptor,
             config_descriptor,
             bos_descriptor,
             msos_descriptor,
             control_buf,
-        )
+        );
+
+        K::setup_usb_interfaces(&mut builder);
+
+        builder
     }
 }
 
@@ -185,5 +205,12 @@ pub fn setup_internal_flash<'a, const FLASH_SIZE: usize>(
     channel: impl crate::hw::mcu::embassy_rp::Peripheral<P = impl crate::hw::mcu::embassy_rp::dma::Channel>
         + 'a,
 ) -> Flash<'a, FLASH_SIZE> {
-    unsafe { Flash::new(FLASH::steal(), channel) }
+    let mut flash = unsafe { Flash::new(FLASH::steal(), channel) };
+
+    let mut id = [0; 8];
+    if flash.blocking_unique_id(&mut id).is_ok() {
+        DEVICE_ID.get_or_init(|| id);
+    }
+
+    flash
 }
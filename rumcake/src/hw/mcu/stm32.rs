@@ -24,7 +24,8 @@ use embassy_sync::blocking_mutex::ThreadModeMutex;
 use static_cell::StaticCell;
 
 pub use rumcake_macros::{
-    input_pin, output_pin, setup_adc_sampler, setup_buffered_uart, setup_i2c,
+    input_pin, interrupt_input_pin, open_drain_pin, output_pin, setup_adc_sampler,
+    setup_buffered_uart, setup_i2c,
 };
 
 pub use embassy_stm32;
@@ -39,9 +40,17 @@ pub const SYSCLK: u32 = 48_000_000;
 #[cfg(feature = "stm32f303cb")]
 pub const SYSCLK: u32 = 72_000_000;
 
+/// Start address of the MCU's internal flash.
+pub const FLASH_ORIGIN: usize = 0x0800_0000;
+
 pub type RawMutex = ThreadModeRawMutex;
 pub type BlockingMutex<T> = ThreadModeMutex<T>;
 
+/// Get the unique device ID of the MCU.
+pub fn device_id() -> &'static [u8] {
+    embassy_stm32::uid::uid()
+}
+
 /// A function that allows you to jump to the bootloader, usually for re-flashing the firmware.
 pub fn jump_to_bootloader() {
     #[cfg(feature = "stm32f072cb")]
@@ -113,7 +122,7 @@ pub fn initialize_rcc() {
 /// need to pass this to [`crate::usb::setup_usb_hid_nkro_writer`] to set up a keyboard that
 /// communicates with a host device over USB.
 pub fn setup_usb_driver<K: crate::usb::USBKeyboard>(
-) -> embassy_usb::Builder<'static, Driver<'static, USB>> {
+) -> embassy_usb::Builder<'static, crate::os_detection::OsDetectionDriver<Driver<'static, USB>>> {
     unsafe {
         #[cfg(feature = "stm32f072cb")]
         bind_interrupts!(
@@ -133,6 +142,7 @@ pub fn setup_usb_driver<K: crate::usb::USBKeyboard>(
         config.manufacturer.replace(K::MANUFACTURER);
         config.product.replace(K::PRODUCT);
         config.serial_number.replace(K::SERIAL_NUMBER);
+        config.supports_remote_wakeup = K::USB_REMOTE_WAKEUP;
         config.max_power = 500;
 
         let usb_driver = Driver::new(USB::steal(), Irqs, PA12::steal(), PA11::steal());
@@ -150,15 +160,19 @@ pub fn setup_usb_driver<K: crate::usb::USBKeyboard>(
         static CONTROL_BUF: static_cell::StaticCell<[u8; 128]> = static_cell::StaticCell::new();
         let control_buf = CONTROL_BUF.init([0; 128]);
 
-        embassy_usb::Builder::new(
-            usb_driver,
+        let mut builder = embassy_usb::Builder::new(
+            crate::os_detection::OsDetectionDriver::new(usb_driver),
             config,
             device_descriptor,
             config_descriptor,
             bos_descriptor,
             msos_descriptor,
             control_buf,
-        )
+        );
+
+        K::setup_usb_interfaces(&mut builder);
+
+        builder
     }
 }
 
@@ -443,6 +457,8 @@ impl crate::storage::FlashStorage for Flash {
 
     const ERASE_SIZE: usize = <HALFlash as embedded_storage::nor_flash::NorFlash>::ERASE_SIZE;
 
+    const WRITE_SIZE: usize = <HALFlash as embedded_storage::nor_flash::NorFlash>::WRITE_SIZE;
+
     async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
         use embedded_storage::nor_flash::NorFlash;
         self.flash.erase(from, to)
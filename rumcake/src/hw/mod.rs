@@ -17,6 +17,7 @@ pub mod mcu;
 
 use crate::hw::mcu::jump_to_bootloader;
 use crate::State;
+use bitflags::bitflags;
 use core::cell::UnsafeCell;
 use core::mem::MaybeUninit;
 use core::ptr::read_volatile;
@@ -25,44 +26,101 @@ use embassy_futures::select;
 use embassy_sync::signal::Signal;
 use embassy_time::Timer;
 use embedded_hal::digital::v2::OutputPin;
+use serde::{Deserialize, Serialize};
 
 use mcu::RawMutex;
 
-/// State that contains the current battery level. `rumcake` may or may not use this
-/// static internally, depending on what MCU is being used. The contents of this state
-/// is usually set by a task in the [`mcu`] module. For example, on nRF5x-based MCUs,
-/// this is controlled by a task called `adc_task`.
-pub static BATTERY_LEVEL_STATE: State<u8> = State::new(
-    100,
+/// Charging state of the battery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BatteryChargeState {
+    /// The charging state is not known. This is the default, since most keyboards do not have a
+    /// way to detect it.
+    #[default]
+    Unknown,
+    /// The battery is powering the keyboard.
+    Discharging,
+    /// The battery is being charged by an external power source.
+    Charging,
+    /// An external power source is connected, but the battery is fully charged.
+    Full,
+}
+
+/// Information about the keyboard's battery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatteryStatus {
+    /// Battery level, as a percentage from 0 to 100.
+    pub level: u8,
+    /// Battery voltage in millivolts, if it can be measured.
+    pub voltage_mv: Option<u16>,
+    /// Whether the battery is currently being charged.
+    pub charge_state: BatteryChargeState,
+}
+
+/// State that contains the current battery status. `rumcake` may or may not use this
+/// static internally, depending on what MCU is being used. The battery level and voltage are
+/// usually set by a task in the [`mcu`] module. For example, on nRF5x-based MCUs, this is
+/// controlled by a task called `adc_task`.
+///
+/// `rumcake` does not know how to detect whether your battery is charging. If your keyboard can
+/// detect this (e.g. using the status pin of a charger IC), you can update
+/// [`BatteryStatus::charge_state`] from your own code using [`State::update`].
+pub static BATTERY_STATE: State<BatteryStatus> = State::new(
+    BatteryStatus {
+        level: 100,
+        voltage_mv: None,
+        charge_state: BatteryChargeState::Unknown,
+    },
     &[
         #[cfg(feature = "display")]
         &crate::display::BATTERY_LEVEL_LISTENER,
         #[cfg(feature = "bluetooth")]
         &crate::bluetooth::BATTERY_LEVEL_LISTENER,
+        #[cfg(feature = "split-peripheral")]
+        &crate::split::peripheral::BATTERY_LEVEL_LISTENER,
     ],
 );
 
 /// Possible settings used to determine how the firmware will choose the destination for HID
 /// reports.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum OutputMode {
     Usb,
     Bluetooth,
+    /// Send HID reports to the USB host when a USB cable is connected, and to the Bluetooth host
+    /// otherwise. If the `esb` feature is enabled, the ESB dongle is preferred over the Bluetooth
+    /// host while it is in range.
+    Auto,
+    #[cfg(feature = "esb")]
+    /// Send HID reports to the ESB dongle. While the dongle is out of range, HID reports are sent
+    /// to the Bluetooth host instead, if one is connected.
+    Esb,
+}
+
+#[cfg(feature = "storage")]
+impl crate::storage::StoredData for OutputMode {
+    const SCHEMA_VERSION: u16 = 1;
 }
 
+#[cfg(all(feature = "usb", any(feature = "bluetooth", feature = "esb")))]
+const DEFAULT_OUTPUT_MODE: OutputMode = OutputMode::Auto;
+#[cfg(all(not(feature = "usb"), feature = "esb"))]
+const DEFAULT_OUTPUT_MODE: OutputMode = OutputMode::Esb;
+#[cfg(all(not(feature = "usb"), not(feature = "esb"), feature = "bluetooth"))]
+const DEFAULT_OUTPUT_MODE: OutputMode = OutputMode::Bluetooth;
+#[cfg(not(any(feature = "bluetooth", feature = "esb")))]
+const DEFAULT_OUTPUT_MODE: OutputMode = OutputMode::Usb;
+
 /// State that contains the desired output mode. This configures how the firmware will decide to
 /// send HID reports. This doesn't not represent the actual destination of HID reports. Use
 /// [`CURRENT_OUTPUT_STATE`] for that.
 pub static OUTPUT_MODE_STATE: State<OutputMode> = State::new(
-    if cfg!(feature = "bluetooth") {
-        OutputMode::Bluetooth
-    } else {
-        OutputMode::Usb
-    },
+    DEFAULT_OUTPUT_MODE,
     &[
         &OUTPUT_MODE_STATE_LISTENER,
         #[cfg(feature = "display")]
         &crate::display::OUTPUT_MODE_STATE_LISTENER,
+        #[cfg(feature = "storage")]
+        &storage::OUTPUT_MODE_STATE_STORAGE_LISTENER,
     ],
 );
 
@@ -71,6 +129,8 @@ pub static OUTPUT_MODE_STATE: State<OutputMode> = State::new(
 pub enum HIDOutput {
     Usb,
     Bluetooth,
+    #[cfg(feature = "esb")]
+    Esb,
 }
 
 /// State that contains the current destination of HID reports.
@@ -81,20 +141,57 @@ pub static CURRENT_OUTPUT_STATE: State<Option<HIDOutput>> = State::new(
         &crate::usb::KB_CURRENT_OUTPUT_STATE_LISTENER,
         #[cfg(feature = "usb")]
         &crate::usb::CONSUMER_CURRENT_OUTPUT_STATE_LISTENER,
-        #[cfg(all(feature = "usb", feature = "via"))]
-        &crate::usb::VIA_CURRENT_OUTPUT_STATE_LISTENER,
+        #[cfg(feature = "usb")]
+        &crate::usb::SYSTEM_CONTROL_CURRENT_OUTPUT_STATE_LISTENER,
+        #[cfg(all(feature = "usb", feature = "mouse"))]
+        &crate::usb::MOUSE_CURRENT_OUTPUT_STATE_LISTENER,
+        #[cfg(all(feature = "usb", feature = "gamepad"))]
+        &crate::usb::GAMEPAD_CURRENT_OUTPUT_STATE_LISTENER,
+        #[cfg(all(feature = "usb", feature = "digitizer"))]
+        &crate::usb::DIGITIZER_CURRENT_OUTPUT_STATE_LISTENER,
+        #[cfg(all(feature = "usb", feature = "raw-hid"))]
+        &crate::usb::RAW_HID_CURRENT_OUTPUT_STATE_LISTENER,
+        #[cfg(feature = "xap")]
+        &crate::usb::XAP_CURRENT_OUTPUT_STATE_LISTENER,
         #[cfg(feature = "bluetooth")]
         &crate::bluetooth::CURRENT_OUTPUT_STATE_LISTENER,
+        #[cfg(feature = "esb")]
+        &crate::esb::CURRENT_OUTPUT_STATE_LISTENER,
+    ],
+);
+
+bitflags! {
+    /// Lock LEDs reported by the host in HID keyboard output reports.
+    ///
+    /// Bits correspond to the LED usages in the HID LED usage page.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct LedIndicators: u8 {
+        const NUM_LOCK = 0b00000001;
+        const CAPS_LOCK = 0b00000010;
+        const SCROLL_LOCK = 0b00000100;
+        const COMPOSE = 0b00001000;
+        const KANA = 0b00010000;
+    }
+}
+
+/// State that contains the lock LEDs (Caps Lock, Num Lock, etc.) last reported by the host
+/// receiving our HID reports. This is updated by both USB and Bluetooth hosts.
+pub static LED_INDICATORS_STATE: State<LedIndicators> = State::new(
+    LedIndicators::empty(),
+    &[
+        #[cfg(feature = "dongle")]
+        &crate::dongle::LED_INDICATORS_STATE_LISTENER,
     ],
 );
 
 pub(crate) static OUTPUT_MODE_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
 pub(crate) static USB_RUNNING_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
 pub(crate) static BLUETOOTH_CONNECTED_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+pub(crate) static ESB_CONNECTED_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
 
 #[rumcake_macros::task]
 pub async fn output_switcher() {
-    // This task doesn't need to run if only one of USB or Bluetooth is enabled.
+    // This task doesn't need to run if only one of USB, Bluetooth or ESB is enabled.
     loop {
         let output = match OUTPUT_MODE_STATE.get().await {
             #[cfg(feature = "usb")]
@@ -107,12 +204,65 @@ pub async fn output_switcher() {
             }
             #[cfg(feature = "bluetooth")]
             OutputMode::Bluetooth => {
+                // Temporarily send reports to the USB host while the bluetooth connection is lost
+                #[cfg(feature = "usb")]
+                let usb_fallback = crate::bluetooth::USB_FALLBACK_STATE.get().await
+                    && crate::usb::USB_RUNNING_STATE.get().await
+                    && crate::usb::USB_CONFIGURED_STATE.get().await;
+                #[cfg(not(feature = "usb"))]
+                let usb_fallback = false;
+
                 if crate::bluetooth::BLUETOOTH_CONNECTED_STATE.get().await {
                     Some(HIDOutput::Bluetooth)
+                } else if usb_fallback {
+                    Some(HIDOutput::Usb)
                 } else {
                     None
                 }
             }
+            #[cfg(all(feature = "usb", any(feature = "bluetooth", feature = "esb")))]
+            OutputMode::Auto => {
+                #[cfg(feature = "esb")]
+                let esb_output = crate::esb::ESB_CONNECTED_STATE
+                    .get()
+                    .await
+                    .then_some(HIDOutput::Esb);
+                #[cfg(not(feature = "esb"))]
+                let esb_output = None;
+
+                #[cfg(feature = "bluetooth")]
+                let bluetooth_output = crate::bluetooth::BLUETOOTH_CONNECTED_STATE
+                    .get()
+                    .await
+                    .then_some(HIDOutput::Bluetooth);
+                #[cfg(not(feature = "bluetooth"))]
+                let bluetooth_output = None;
+
+                if crate::usb::USB_RUNNING_STATE.get().await
+                    && crate::usb::USB_CONFIGURED_STATE.get().await
+                {
+                    Some(HIDOutput::Usb)
+                } else {
+                    esb_output.or(bluetooth_output)
+                }
+            }
+            #[cfg(feature = "esb")]
+            OutputMode::Esb => {
+                // Temporarily send reports to the bluetooth host while the dongle is out of range
+                #[cfg(feature = "bluetooth")]
+                let bluetooth_fallback = crate::bluetooth::BLUETOOTH_CONNECTED_STATE
+                    .get()
+                    .await
+                    .then_some(HIDOutput::Bluetooth);
+                #[cfg(not(feature = "bluetooth"))]
+                let bluetooth_fallback = None;
+
+                if crate::esb::ESB_CONNECTED_STATE.get().await {
+                    Some(HIDOutput::Esb)
+                } else {
+                    bluetooth_fallback
+                }
+            }
             #[allow(unreachable_patterns)]
             _ => None,
         };
@@ -121,20 +271,76 @@ pub async fn output_switcher() {
         defmt::info!("[HW] Output updated: {:?}", defmt::Debug2Format(&output));
 
         // Wait for a change in state before attempting to update the output again.
-        select::select3(
+        select::select4(
             USB_RUNNING_STATE_LISTENER.wait(),
             BLUETOOTH_CONNECTED_STATE_LISTENER.wait(),
+            ESB_CONNECTED_STATE_LISTENER.wait(),
             OUTPUT_MODE_STATE_LISTENER.wait(),
         )
         .await;
     }
 }
 
+#[cfg(feature = "storage")]
+pub mod storage {
+    use embassy_sync::signal::Signal;
+
+    use crate::hw::mcu::RawMutex;
+    use crate::storage::{FlashStorage, StorageDevice};
+
+    use super::OUTPUT_MODE_STATE;
+
+    pub(super) static OUTPUT_MODE_STATE_STORAGE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+    /// Signal used to save the output mode immediately, instead of waiting for the save policy
+    /// defined in [`StorageDevice`].
+    pub(crate) static OUTPUT_MODE_SAVE_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+    /// Task that restores the output mode that was last selected, and saves any changes to it, so
+    /// that the keyboard sends HID reports to the same host after a reboot.
+    #[rumcake_macros::task]
+    pub async fn output_mode_storage_task<K: StorageDevice, F: FlashStorage>(
+        _k: K,
+        database: &crate::storage::StorageService<'static, F>,
+    ) where
+        [(); F::ERASE_SIZE]:,
+        [(); F::REGION_SIZE]:,
+    {
+        database
+            .persist_state::<K, _>(
+                crate::storage::StorageKey::OutputMode,
+                &OUTPUT_MODE_STATE,
+                &OUTPUT_MODE_STATE_STORAGE_LISTENER,
+                &OUTPUT_MODE_SAVE_SIGNAL,
+            )
+            .await
+    }
+}
+
+/// Reset the microcontroller.
+pub fn reset() -> ! {
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
 const BOOTLOADER_MAGIC: u32 = 0xDEADBEEF;
 
+/// Flag used to detect a double tap of the reset button. This is placed in a section of RAM that
+/// is not initialized on startup, so that it keeps its value through a reset.
 #[link_section = ".uninit.FLAG"]
 static mut FLAG: UnsafeCell<MaybeUninit<u32>> = UnsafeCell::new(MaybeUninit::uninit());
 
+/// Jump to the bootloader if the keyboard was reset twice within `timeout` milliseconds. This is
+/// useful for keyboards where the bootloader is hard to enter (e.g. the BOOT button or pins are
+/// covered by the case), but the reset button is still accessible.
+///
+/// This should be called as early as possible after the MCU has been initialized. This is
+/// usually done for you by adding `bootloader_double_tap_reset` to your `keyboard` macro
+/// invocation.
+///
+/// # Safety
+///
+/// This reads and writes a flag stored in uninitialized RAM, which is only safe to do from one
+/// place. This must only be called once.
 pub async unsafe fn check_double_tap_bootloader(timeout: u64) {
     if read_volatile(FLAG.get().cast::<u32>()) == BOOTLOADER_MAGIC {
         write_volatile(FLAG.get().cast(), 0);
@@ -151,8 +357,10 @@ pub async unsafe fn check_double_tap_bootloader(timeout: u64) {
 
 extern "C" {
     /// This static value will have an address equal to the `__config_start` address in your
-    /// `memory.x` file. You must set this, along with [`__config_end`], if you're using on-chip
-    /// flash with the storage task (which is the default). Keep in mind that the start and end
+    /// `memory.x` file. You only need to set this, along with [`__config_end`], if you're using
+    /// on-chip flash with the storage task, and you specify `use_memory_x` in the `storage`
+    /// settings of the `#[keyboard]` macro, instead of reserving the config partition with
+    /// [`crate::storage::config_partition`]. Keep in mind that the start and end
     /// address must be relative to the address of your chip's flash. For example, on STM32F072CBx,
     /// flash memory is located at `0x08000000`, so if you want your config data to start at
     /// `0x08100000`, your start address must be `0x00100000`.
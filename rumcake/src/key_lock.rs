@@ -0,0 +1,71 @@
+//! Key lock, which keeps the next key that is pressed held down until it is pressed again.
+//!
+//! After [`crate::keyboard::Keycode::KeyLock`] is pressed, the next key that types a keycode is
+//! locked, and stays held after it is released. Pressing the locked key again unlocks it, so that
+//! it is released as normal. Key lock works on the keycodes that are sent to the host, so it works
+//! for modifiers and regular keys alike. Pressing the key lock key again before another key is
+//! pressed cancels it.
+
+use defmt::{debug, Debug2Format};
+use heapless::Vec;
+use keyberon::key_code::KeyCode;
+
+/// Maximum number of keys that can be locked at once.
+const MAX_LOCKED_KEYS: usize = 8;
+
+/// Keeps track of the keys that have been locked.
+pub(crate) struct KeyLock {
+    armed: bool,
+    locked: Vec<KeyCode, MAX_LOCKED_KEYS>,
+    // Keycodes that were pressed in the layout during the last update
+    pressed: Vec<KeyCode, 24>,
+}
+
+impl KeyLock {
+    pub(crate) const fn new() -> Self {
+        Self {
+            armed: false,
+            locked: Vec::new(),
+            pressed: Vec::new(),
+        }
+    }
+
+    /// Lock the next key that is pressed, or cancel it if the key lock was already pressed.
+    pub(crate) fn toggle(&mut self) {
+        self.armed = !self.armed;
+    }
+
+    /// Update the locked keys using the keycodes that are pressed in the layout, returning the
+    /// locked keycodes that should be reported along with them.
+    pub(crate) fn update(
+        &mut self,
+        keycodes: impl Iterator<Item = KeyCode>,
+    ) -> impl Iterator<Item = KeyCode> + '_ {
+        let previous = core::mem::take(&mut self.pressed);
+
+        for key in keycodes {
+            if self.pressed.contains(&key) || self.pressed.push(key).is_err() {
+                continue;
+            }
+
+            if previous.contains(&key) {
+                continue;
+            }
+
+            // The key was just pressed, so it's either unlocked, or locked if the key lock was
+            // pressed before it
+            if let Some(idx) = self.locked.iter().position(|locked| *locked == key) {
+                debug!("[KEY_LOCK] Unlocked {}", Debug2Format(&key));
+                self.locked.swap_remove(idx);
+            } else if self.armed && self.locked.push(key).is_ok() {
+                debug!("[KEY_LOCK] Locked {}", Debug2Format(&key));
+                self.armed = false;
+            }
+        }
+
+        self.locked
+            .iter()
+            .filter(|key| !self.pressed.contains(key))
+            .copied()
+    }
+}
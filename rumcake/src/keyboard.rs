@@ -7,17 +7,25 @@ use core::convert::Infallible;
 use core::ops::Range;
 
 use defmt::{debug, info, warn, Debug2Format};
-use embassy_sync::channel::Channel;
+use embassy_futures::select::{select, select3, select_slice, Either, Either3};
+use embassy_sync::channel::{Channel, TrySendError};
 use embassy_sync::mutex::{Mutex, MutexGuard};
 use embassy_sync::pubsub::{PubSubBehavior, PubSubChannel};
-use embassy_time::{Duration, Ticker, Timer};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Ticker, Timer};
 use embedded_hal::digital::v2::{InputPin, OutputPin};
+use embedded_hal_async::digital::Wait;
 use heapless::Vec;
+use keyberon::action::{Action, HoldTapConfig};
 use keyberon::analog::{AnalogActuator, AnalogAcutationMode};
 use keyberon::debounce::Debouncer;
-use keyberon::layout::{CustomEvent, Event, Layers, Layout as KeyberonLayout};
-use keyberon::matrix::{AnalogMatrix, DirectPinMatrix, Matrix};
+use keyberon::key_code::KeyCode;
+use keyberon::layout::{CustomEvent, Event, Layers, Layout as KeyberonLayout, VIRTUAL_ROW};
+use keyberon::matrix::{
+    AnalogMatrix, DirectPinMatrix, DuplexMatrix, Matrix, RoundRobinMatrix, ShiftRegisterMatrix,
+};
 use num_traits::SaturatingSub;
+use serde::{Deserialize, Serialize};
 use usbd_human_interface_device::device::consumer::MultipleConsumerReport;
 use usbd_human_interface_device::{
     device::keyboard::NKROBootKeyboardReport, page::Keyboard as KeyboardKeycode,
@@ -26,11 +34,29 @@ use usbd_human_interface_device::{
 #[cfg(feature = "media-keycodes")]
 pub use usbd_human_interface_device::page::Consumer;
 
+use crate::accessibility::{
+    process_accessibility_command, AccessibilityCommand, AccessibilityEvents, AccessibilityFilter,
+    ACCESSIBILITY_CONFIG_STATE,
+};
+use crate::analog::{
+    apply_key_configs, poll_calibration, AnalogCalibrator, AnalogKeyConfig,
+    ANALOG_KEY_CONFIGS_MATRIX_LISTENER, ANALOG_KEY_CONFIGS_STATE,
+};
+use crate::combo::{Combo, ComboProcessor, DEFAULT_COMBO_TERM};
+use crate::diagnostics::MatrixDiagnostics;
+use crate::dynamic_macro::{DynamicMacroCommand, DynamicMacroRecorder};
 use crate::hw::mcu::RawMutex;
 use crate::hw::CURRENT_OUTPUT_STATE;
+use crate::key_lock::KeyLock;
+use crate::leader::{LeaderProcessor, LeaderSequence, DEFAULT_LEADER_TIMEOUT, LEADER_VIRTUAL_KEY};
+use crate::swap_hands::{SwapHandsCommand, SwapHandsProcessor};
+use crate::unicode::{UnicodeCommand, UnicodeTyper};
+use crate::State;
 
 pub use rumcake_macros::{
-    build_analog_matrix, build_direct_pin_matrix, build_layout, build_standard_matrix, remap_matrix,
+    build_analog_matrix, build_direct_pin_matrix, build_duplex_matrix, build_extra_keys,
+    build_interrupt_matrix, build_layout, build_round_robin_matrix, build_shift_register_matrix,
+    build_standard_matrix, remap_matrix,
 };
 
 /// Basic keyboard trait that must be implemented to use rumcake. Defines basic keyboard information.
@@ -47,8 +73,9 @@ pub trait Keyboard {
     /// Hardware version number for your keyboard.
     const HARDWARE_REVISION: &'static str = "1";
 
-    /// Firmware version number for your keyboard.
-    const FIRMWARE_REVISION: &'static str = "1";
+    /// Firmware version number for your keyboard. Defaults to the version of `rumcake`. To report
+    /// the version of your own firmware crate, you can set this to `env!("CARGO_PKG_VERSION")`.
+    const FIRMWARE_REVISION: &'static str = env!("CARGO_PKG_VERSION");
 }
 
 /// A trait that must be implemented on a device that communicates with the host device.
@@ -89,6 +116,49 @@ pub trait KeyboardLayout {
     /// `press` is set to `true` if the event was a key press. Otherwise, it will be `false`. `id`
     /// corresponds to the `id` used in your keyboard layout.
     fn on_custom_keycode(_id: u8, _press: bool) {}
+
+    /// Layout position (row, column) of a key that triggers a factory reset if it is held down
+    /// while the keyboard is starting up. This can be used to recover from bad settings without
+    /// needing to flash the keyboard. By default, this is disabled.
+    #[cfg(feature = "storage")]
+    const FACTORY_RESET_KEY: Option<(u8, u8)> = None;
+
+    /// Change the [`TapHoldConfig`] used by the tap-hold key at the given layout position (row,
+    /// column), when it is pressed. This can be used to give some keys (e.g. home row mods) a
+    /// different tapping term or strategy than the rest of your layout. By default, `config` is
+    /// returned unchanged.
+    fn tap_hold_config(_coord: (u8, u8), config: TapHoldConfig) -> TapHoldConfig {
+        config
+    }
+
+    /// Combos that can be triggered by pressing multiple keys at the same time. By default, there
+    /// are no combos. See [`crate::combo`] for more information.
+    const COMBOS: &'static [Combo] = &[];
+
+    /// Maximum time between the first and last key press of a combo, in milliseconds.
+    const COMBO_TERM: u16 = DEFAULT_COMBO_TERM;
+
+    /// Sequences that can be typed after pressing [`Keycode::Leader`]. By default, there are no
+    /// leader sequences. See [`crate::leader`] for more information.
+    const LEADER_SEQUENCES: &'static [LeaderSequence] = &[];
+
+    /// Time to wait for the next key of a leader sequence before it is finished, in milliseconds.
+    const LEADER_TIMEOUT: u16 = DEFAULT_LEADER_TIMEOUT;
+
+    /// Position (row, column) that each key is mirrored to when swap hands is active. Each row of
+    /// this map corresponds to a row of your layout. Keys that are not in the map are not
+    /// mirrored. By default, the map is empty. See [`crate::swap_hands`] for more information.
+    const SWAP_HANDS_MAP: &'static [&'static [(u8, u8)]] = &[];
+
+    /// Time to wait after each key press or release typed by a macro, in milliseconds. This
+    /// applies to [`crate::send_string`] macros, Via macros and dynamic macros. Increase this if
+    /// your host misses keys typed by macros. By default, there is no delay.
+    const SEND_STRING_DELAY: u16 = 0;
+
+    /// What to do when the keyboard or consumer report queue is full. This can happen if the host
+    /// isn't reading reports quickly enough (e.g. a Bluetooth connection has stalled). See
+    /// [`ReportOverflowPolicy`] for more information.
+    const REPORT_OVERFLOW_POLICY: ReportOverflowPolicy = ReportOverflowPolicy::DropOldest;
 }
 
 /// A mutex-guaraded [`keyberon::layout::Layout`]. This also stores the original layout, so that it
@@ -119,6 +189,34 @@ pub trait KeyboardMatrix {
     /// Debounce setting.
     const DEBOUNCE_MS: u16 = 5;
 
+    /// Actuation point used by analog matrices, from 0 (unpressed) to 255 (fully pressed). Keys are
+    /// pressed once they travel past this point.
+    const ANALOG_ACTUATION_POINT: u8 = 127;
+
+    /// Actuation mode used by analog matrices. Set this to [`AnalogAcutationMode::Rapid`] or
+    /// [`AnalogAcutationMode::ContinuousRapid`] to enable rapid trigger.
+    const ANALOG_ACTUATION_MODE: AnalogAcutationMode = AnalogAcutationMode::Static;
+
+    /// Default rapid trigger sensitivity used by analog matrices, from 0 to 255. With rapid
+    /// trigger enabled, this is how far a key must travel in the opposite direction to be released
+    /// or pressed again. Lower values make keys more sensitive. With rapid trigger disabled, this
+    /// is used as hysteresis around the actuation point, to avoid chattering.
+    const ANALOG_RAPID_TRIGGER_SENSITIVITY: u8 = 5;
+
+    /// How long calibration of an analog matrix lasts (in milliseconds), unless it is finished
+    /// early. See [`crate::analog`].
+    const ANALOG_CALIBRATION_TIMEOUT_MS: u32 = 15000;
+
+    /// Minimum difference between the raw readings of a key at rest and fully pressed, for its
+    /// calibration to be used. Keys that travel less than this while calibrating (e.g. keys that
+    /// weren't pressed) keep their previous range.
+    const ANALOG_CALIBRATION_MIN_TRAVEL: u16 = 100;
+
+    /// How long (in milliseconds) all keys must be released before the matrix stops being polled.
+    /// This only applies to matrices that can wait for a key to be pressed, like the one created by
+    /// [`build_interrupt_matrix`]. Other matrices are always polled.
+    const IDLE_TIMEOUT_MS: u32 = 1000;
+
     /// Number of matrix columns.
     ///
     /// It is recommended to use one of the `build_*_matrix` macros to set this constant.
@@ -142,6 +240,38 @@ pub trait KeyboardMatrix {
     fn remap_to_layout(row: u8, col: u8) -> (u8, u8) {
         (row, col)
     }
+
+    /// Optional function to set the rapid trigger sensitivity of each key in an analog matrix. By
+    /// default, all keys use [`KeyboardMatrix::ANALOG_RAPID_TRIGGER_SENSITIVITY`].
+    ///
+    /// This is called with the matrix position (row, column) of each key, when the matrix is
+    /// created.
+    fn analog_rapid_trigger_sensitivity(_row: u8, _col: u8) -> u8 {
+        Self::ANALOG_RAPID_TRIGGER_SENSITIVITY
+    }
+}
+
+/// Trait that allows you to add switches that aren't part of your keyboard matrix (e.g. encoder
+/// push buttons, buttons on your case, or foot pedals). Each extra key is connected to its own GPIO
+/// pin, and is bound to a position on the keyboard layout defined by
+/// [`KeyboardLayout::get_layout`].
+///
+/// Extra keys are scanned by their own task, so they don't need to fit into the rows and columns
+/// of your matrix.
+pub trait ExtraKeys {
+    /// Debounce setting for the extra keys.
+    const EXTRA_KEYS_DEBOUNCE_MS: u16 = 5;
+
+    /// Layout positions (row, column) that each extra key is bound to, in the same order as the
+    /// pins returned by [`ExtraKeys::get_extra_keys`].
+    ///
+    /// It is recommended to use the [`build_extra_keys`] macro to set this constant.
+    const EXTRA_KEY_POSITIONS: &'static [(u8, u8)];
+
+    /// Create a diodeless matrix with a single row, containing the GPIO pins of the extra keys.
+    ///
+    /// It is recommended to use the [`build_extra_keys`] macro to implement this function.
+    fn get_extra_keys() -> &'static PollableMatrix<impl Pollable>;
 }
 
 /// Setup a traditional keyboard matrix with diodes, with a debouncer. The output of this function
@@ -158,12 +288,149 @@ pub fn setup_standard_keyboard_matrix<
     debounce_ms: u16,
 ) -> Result<PollableStandardMatrix<I, O, CS, RS>, E> {
     let matrix = Matrix::new(cols, rows)?;
-    let debouncer = Debouncer::new([[false; CS]; RS], [[false; CS]; RS], debounce_ms);
+    let debouncer = Debouncer::new(
+        [[false; CS]; RS],
+        [[false; CS]; RS],
+        debounce_scans(debounce_ms, POLL_INTERVAL),
+    );
+    Ok((matrix, debouncer))
+}
+
+/// Setup a keyboard matrix with diodes facing from the rows to the columns (row2col), with a
+/// debouncer. The output of this function can be passed to the matrix polling task directly.
+///
+/// This is the opposite of [`setup_standard_keyboard_matrix`]: the columns are strobed, and the
+/// rows are read. Keys are still reported using their row and column.
+pub fn setup_row2col_keyboard_matrix<
+    E,
+    I: InputPin<Error = E>,
+    O: OutputPin<Error = E>,
+    const CS: usize,
+    const RS: usize,
+>(
+    rows: [I; RS],
+    cols: [O; CS],
+    debounce_ms: u16,
+) -> Result<PollableRow2ColMatrix<I, O, CS, RS>, E> {
+    let matrix = Row2ColMatrix::new(rows, cols)?;
+    let debouncer = Debouncer::new(
+        [[false; RS]; CS],
+        [[false; RS]; CS],
+        debounce_scans(debounce_ms, POLL_INTERVAL),
+    );
+    Ok((matrix, debouncer))
+}
+
+/// Setup a keyboard matrix with diodes, which stops being polled while idle, with a debouncer. The
+/// output of this function can be passed to the matrix polling task directly.
+///
+/// The column pins must be able to wait for a falling edge (e.g. using GPIOTE on nRF, or EXTI on
+/// STM32). See [`InterruptMatrix`] for more information.
+pub fn setup_interrupt_keyboard_matrix<
+    E,
+    I: InputPin<Error = E> + Wait,
+    O: OutputPin<Error = E>,
+    const CS: usize,
+    const RS: usize,
+>(
+    cols: [I; CS],
+    rows: [O; RS],
+    debounce_ms: u16,
+) -> Result<PollableInterruptMatrix<I, O, CS, RS>, E> {
+    let matrix = InterruptMatrix::new(cols, rows)?;
+    let debouncer = Debouncer::new(
+        [[false; CS]; RS],
+        [[false; CS]; RS],
+        debounce_scans(debounce_ms, POLL_INTERVAL),
+    );
+    Ok((matrix, debouncer))
+}
+
+/// Setup a duplex keyboard matrix, where each row and column pair is connected to two switches
+/// with diodes facing opposite directions, with a debouncer. The output of this function can be
+/// passed to the matrix polling task directly.
+///
+/// All pins must be open-drain outputs with pull-ups. Switches found by pulling a row low are
+/// reported on even rows (`row * 2`), and switches found by pulling a column low are reported on
+/// odd rows (`row * 2 + 1`), so the matrix has twice as many rows as row pins.
+pub fn setup_duplex_keyboard_matrix<
+    E,
+    P: InputPin<Error = E> + OutputPin<Error = E>,
+    const CS: usize,
+    const RS: usize,
+>(
+    cols: [P; CS],
+    rows: [P; RS],
+    debounce_ms: u16,
+) -> Result<PollableDuplexMatrix<P, CS, RS>, E> {
+    let matrix = DuplexMatrix::new(cols, rows)?;
+    let row_to_col = Debouncer::new(
+        [[false; CS]; RS],
+        [[false; CS]; RS],
+        debounce_scans(debounce_ms, POLL_INTERVAL),
+    );
+    let col_to_row = Debouncer::new(
+        [[false; CS]; RS],
+        [[false; CS]; RS],
+        debounce_scans(debounce_ms, POLL_INTERVAL),
+    );
+    Ok((matrix, row_to_col, col_to_row))
+}
+
+/// Setup a round-robin keyboard matrix without diodes, where a switch is connected between every
+/// pair of pins, with a debouncer. The output of this function can be passed to the matrix polling
+/// task directly.
+///
+/// All pins must be open-drain outputs with pull-ups. The switch between pin `a` and pin `b`
+/// (where `a < b`) is reported on row `a` and column `b`, so the matrix has as many rows and
+/// columns as there are pins.
+pub fn setup_round_robin_keyboard_matrix<
+    E,
+    P: InputPin<Error = E> + OutputPin<Error = E>,
+    const N: usize,
+>(
+    pins: [P; N],
+    debounce_ms: u16,
+) -> Result<PollableRoundRobinMatrix<P, N>, E> {
+    let matrix = RoundRobinMatrix::new(pins)?;
+    let debouncer = Debouncer::new(
+        [[false; N]; N],
+        [[false; N]; N],
+        debounce_scans(debounce_ms, POLL_INTERVAL),
+    );
+    Ok((matrix, debouncer))
+}
+
+/// Setup a keyboard matrix with diodes, where the columns are strobed by one or more chained
+/// 74HC595 shift registers, with a debouncer. The output of this function can be passed to the
+/// matrix polling task directly.
+pub fn setup_shift_register_keyboard_matrix<
+    E,
+    I: InputPin<Error = E>,
+    O: OutputPin<Error = E>,
+    const CS: usize,
+    const RS: usize,
+>(
+    rows: [I; RS],
+    data: O,
+    clock: O,
+    latch: O,
+    debounce_ms: u16,
+) -> Result<PollableShiftRegisterMatrix<I, O, CS, RS>, E> {
+    let matrix = ShiftRegisterMatrix::new(rows, data, clock, latch)?;
+    let debouncer = Debouncer::new(
+        [[false; CS]; RS],
+        [[false; CS]; RS],
+        debounce_scans(debounce_ms, POLL_INTERVAL),
+    );
     Ok((matrix, debouncer))
 }
 
 /// Setup a diodeless keyboard matrix, with a debouncer. The output of this function can be passed
 /// to the matrix polling task directly.
+///
+/// Each switch has its own pin, so no pins are strobed while scanning. A switch is pressed when
+/// its pin is low, so the pins should use pull-up resistors.
 pub fn setup_direct_pin_keyboard_matrix<
     E,
     I: InputPin<Error = E>,
@@ -174,7 +441,38 @@ pub fn setup_direct_pin_keyboard_matrix<
     debounce_ms: u16,
 ) -> Result<PollableDirectPinMatrix<I, CS, RS>, E> {
     let matrix = DirectPinMatrix::new(pins)?;
-    let debouncer = Debouncer::new([[false; CS]; RS], [[false; CS]; RS], debounce_ms);
+    let debouncer = Debouncer::new(
+        [[false; CS]; RS],
+        [[false; CS]; RS],
+        debounce_scans(debounce_ms, POLL_INTERVAL),
+    );
+    Ok((matrix, debouncer))
+}
+
+/// Setup a keyboard matrix with diodes, which is connected to a GPIO expander, with a debouncer.
+/// The output of this function can be passed to the matrix polling task directly.
+///
+/// `cols` and `rows` are the indices of the pins on the GPIO expander. If `interrupt` is
+/// connected to the interrupt pin of the GPIO expander, the matrix is only scanned when a key is
+/// pressed or held.
+pub fn setup_io_expander_keyboard_matrix<
+    E: IoExpander,
+    I: InputPin<Error = Infallible>,
+    const CS: usize,
+    const RS: usize,
+>(
+    expander: E,
+    cols: [u8; CS],
+    rows: [u8; RS],
+    interrupt: Option<I>,
+    debounce_ms: u16,
+) -> Result<PollableIoExpanderMatrix<E, I, CS, RS>, E::Error> {
+    let matrix = IoExpanderMatrix::new(expander, cols, rows, interrupt)?;
+    let debouncer = Debouncer::new(
+        [[false; CS]; RS],
+        [[false; CS]; RS],
+        debounce_scans(debounce_ms, POLL_INTERVAL),
+    );
     Ok((matrix, debouncer))
 }
 
@@ -184,11 +482,20 @@ pub fn setup_analog_keyboard_matrix<S: MatrixSampler, const CS: usize, const RS:
     sampler: &S,
     pos_to_ch: [[(u8, u8); CS]; RS],
     ranges: [[Range<S::SampleType>; CS]; RS],
+    actuation_point: u8,
+    mode: AnalogAcutationMode,
+    sensitivity: impl Fn(u8, u8) -> u8,
 ) -> PollableAnalogMatrix<S, CS, RS> {
     let sampler = AnalogMatrixSampler { pos_to_ch, sampler };
     let matrix = AnalogMatrix::new(ranges);
-    let actuator = AnalogActuator::new([[AnalogAcutationMode::default(); CS]; RS], [[127; CS]; RS]);
-    (sampler, matrix, actuator)
+    let mut actuator = AnalogActuator::new([[mode; CS]; RS], [[actuation_point; CS]; RS]);
+    for row in 0..RS {
+        for col in 0..CS {
+            let sensitivity = sensitivity(row as u8, col as u8);
+            let _ = actuator.set_thresholds(row, col, sensitivity, sensitivity);
+        }
+    }
+    (sampler, matrix, actuator, AnalogCalibrator::new())
 }
 
 /// Custom keycodes used to interact with other rumcake features.
@@ -204,6 +511,27 @@ pub enum Keycode {
     /// Media keycode, which can be any variant in [`usbd_human_interface_device::page::Consumer`]
     Media(usbd_human_interface_device::page::Consumer),
 
+    #[cfg(feature = "media-keycodes")]
+    /// System control keycode, which can be any variant in
+    /// [`crate::system_control::SystemControlKeycode`]
+    SystemControl(crate::system_control::SystemControlKeycode),
+
+    #[cfg(feature = "mouse")]
+    /// Mouse keycode, which can be any variant in [`crate::mouse::MouseKeycode`]
+    Mouse(crate::mouse::MouseKeycode),
+
+    #[cfg(feature = "pointing")]
+    /// Pointing device keycode, which can be any variant in [`crate::pointing::PointingCommand`]
+    Pointing(crate::pointing::PointingCommand),
+
+    #[cfg(feature = "gamepad")]
+    /// Gamepad keycode, which can be any variant in [`crate::gamepad::GamepadKeycode`]
+    Gamepad(crate::gamepad::GamepadKeycode),
+
+    #[cfg(feature = "midi")]
+    /// MIDI keycode, which can be any variant in [`crate::midi::MidiKeycode`]
+    Midi(crate::midi::MidiKeycode),
+
     #[cfg(feature = "underglow")]
     /// Underglow keycode, which can be any variant in [`crate::underglow::animations::UnderglowCommand`]
     Underglow(crate::underglow::animations::UnderglowCommand),
@@ -226,18 +554,356 @@ pub enum Keycode {
     #[cfg(feature = "bluetooth")]
     /// Bluetooth keycode, which can be any variant in [`crate::bluetooth::BluetoothCommand`]
     Bluetooth(crate::bluetooth::BluetoothCommand),
+
+    #[cfg(feature = "storage")]
+    /// Erase all stored data and reset the keyboard, restoring all settings to their defaults.
+    /// See [`crate::storage::StorageService::factory_reset`].
+    FactoryReset,
+
+    /// NKRO keycode, which can be any variant in [`NKROCommand`]
+    NKRO(NKROCommand),
+
+    /// Jump to the bootloader, so that new firmware can be flashed. See
+    /// [`crate::hw::mcu::jump_to_bootloader`].
+    Bootloader,
+
+    #[cfg(all(feature = "nrf", feature = "ota"))]
+    /// Jump to the bootloader in OTA DFU mode, so that new firmware can be flashed over
+    /// Bluetooth. See [`crate::hw::mcu::jump_to_ota_bootloader`].
+    OtaBootloader,
+
+    /// Reset the keyboard. See [`crate::hw::reset`].
+    Reset,
+
+    /// Tap-hold keycode, which can be any variant in [`TapHoldCommand`]
+    TapHold(TapHoldCommand),
+
+    /// Start typing a leader sequence. See [`crate::leader`].
+    Leader,
+
+    /// Dynamic macro keycode, which can be any variant in [`DynamicMacroCommand`]
+    DynamicMacro(DynamicMacroCommand),
+
+    /// Unicode keycode, which can be any variant in [`UnicodeCommand`]
+    Unicode(UnicodeCommand),
+
+    /// Swap hands keycode, which can be any variant in [`SwapHandsCommand`]
+    SwapHands(SwapHandsCommand),
+
+    /// Accessibility keycode, which can be any variant in [`AccessibilityCommand`]
+    Accessibility(AccessibilityCommand),
+
+    /// Types Escape, or Grave (`` ` ``) if Shift or GUI is held. Shift + Grave types a tilde
+    /// (`~`).
+    GraveEscape,
+
+    /// Keep the next key that is pressed held down until it is pressed again. See
+    /// [`crate::key_lock`].
+    KeyLock,
+
+    /// Start calibrating the analog matrix. See [`crate::analog`].
+    AnalogCalibrate,
+
+    #[cfg(any(feature = "console", feature = "hid-console", feature = "nus-console"))]
+    /// Toggle debug output on the console. See [`crate::console::debug_enabled`].
+    DebugToggle,
+}
+
+#[derive(Debug, Clone, Copy)]
+/// An enumeration of possible commands used to change the state of [`NKRO_STATE`].
+pub enum NKROCommand {
+    /// Allow more than 6 keys to be reported at once. Every held key is included in the report.
+    Enable,
+    /// Only report up to 6 keys at once (excluding modifiers).
+    Disable,
+    /// Switch between NKRO and 6KRO.
+    Toggle,
+}
+
+/// State that determines whether keyboard reports can contain more than 6 keys (excluding
+/// modifiers). If this is `false`, only the first 6 pressed keys will be reported. The report
+/// format does not change, since the keyboard always uses boot-compatible NKRO reports, so this
+/// only limits how many keys are included in each report. This can be disabled for hosts that
+/// misbehave when more than 6 keys are reported, like some KVM switches.
+pub static NKRO_STATE: State<bool> = State::new(true, &[]);
+
+/// State that contains the layers that are currently active, as a bitmask. Bit `n` is set if layer
+/// `n` is active. The default layer is always active, and layers above 31 are not included. This
+/// can be used to show the active layers on your own indicators (e.g. underglow), by waiting on
+/// [`LAYER_STATE_LISTENER`]. On split keyboards, this state is also sent to the peripherals.
+pub static LAYER_STATE: State<u32> = State::new(
+    1,
+    &[
+        &LAYER_STATE_LISTENER,
+        #[cfg(feature = "display")]
+        &crate::display::LAYER_STATE_LISTENER,
+        #[cfg(feature = "split-central")]
+        &crate::split::central::LAYER_STATE_LISTENER,
+    ],
+);
+
+/// Signal that is notified when [`LAYER_STATE`] changes. This is reserved for user-level code, so
+/// only one of your tasks should wait on it.
+pub static LAYER_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+/// State that contains the default layer of the layout. The default layer can be changed using
+/// [`keyberon::action::Action::DefaultLayer`] actions in your layout (e.g. `{d(1)}`), or by
+/// setting this state. If you specified a storage driver, and enabled the `storage` feature, the
+/// default layer is saved, and restored when your keyboard restarts.
+pub static DEFAULT_LAYER_STATE: State<u8> = State::new(
+    0,
+    &[
+        #[cfg(feature = "storage")]
+        &storage::DEFAULT_LAYER_STATE_STORAGE_LISTENER,
+    ],
+);
+
+/// Tapping term used as a starting point by [`TapHoldCommand::IncreaseTappingTerm`] and
+/// [`TapHoldCommand::DecreaseTappingTerm`], if [`TapHoldConfig::tapping_term`] is not set.
+pub const DEFAULT_TAPPING_TERM: u16 = 200;
+
+/// Possible ways of deciding whether a tap-hold key is held, when another key is pressed before
+/// its tapping term ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TapHoldStrategy {
+    /// The tap-hold key is only held if it is pressed for longer than the tapping term.
+    TappingTerm,
+    /// The tap-hold key is held if another key is pressed and released while it is pressed.
+    PermissiveHold,
+    /// The tap-hold key is held as soon as another key is pressed.
+    HoldOnOtherKeyPress,
+}
+
+impl From<TapHoldStrategy> for HoldTapConfig {
+    fn from(strategy: TapHoldStrategy) -> Self {
+        match strategy {
+            TapHoldStrategy::TappingTerm => HoldTapConfig::Default,
+            TapHoldStrategy::PermissiveHold => HoldTapConfig::PermissiveHold,
+            TapHoldStrategy::HoldOnOtherKeyPress => HoldTapConfig::HoldOnOtherKeyPress,
+        }
+    }
+}
+
+/// Settings used by tap-hold keys (e.g. mod-tap and layer-tap keys), defined in your layout with
+/// [`keyberon::action::Action::HoldTap`]. Settings that are set to `None` use the values from the
+/// [`keyberon::action::HoldTapAction`] of each key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TapHoldConfig {
+    /// How long a tap-hold key must be pressed before it is held, in milliseconds.
+    pub tapping_term: Option<u16>,
+    /// How a tap-hold key is resolved when another key is pressed before the tapping term ends.
+    pub strategy: Option<TapHoldStrategy>,
+}
+
+impl TapHoldConfig {
+    /// A config that uses the tapping term and strategy defined for each key in your layout.
+    pub const LAYOUT: Self = Self {
+        tapping_term: None,
+        strategy: None,
+    };
+}
+
+#[cfg(feature = "storage")]
+impl crate::storage::StoredData for TapHoldConfig {
+    const SCHEMA_VERSION: u16 = 1;
+}
+
+#[derive(Debug, Clone, Copy)]
+/// An enumeration of possible commands used to change the state of [`TAP_HOLD_CONFIG_STATE`].
+pub enum TapHoldCommand {
+    /// Set the tapping term used by all tap-hold keys, in milliseconds.
+    SetTappingTerm(u16),
+    /// Increase the tapping term by the given number of milliseconds.
+    IncreaseTappingTerm(u16),
+    /// Decrease the tapping term by the given number of milliseconds.
+    DecreaseTappingTerm(u16),
+    /// Set the strategy used by all tap-hold keys.
+    SetStrategy(TapHoldStrategy),
+    /// Use the tapping term and strategy defined for each key in your layout.
+    Reset,
+}
+
+/// State that contains the settings used by tap-hold keys. This can be changed at runtime using
+/// [`TapHoldCommand`]s. See [`KeyboardLayout::tap_hold_config`] to change the settings of
+/// individual keys.
+pub static TAP_HOLD_CONFIG_STATE: State<TapHoldConfig> = State::new(
+    TapHoldConfig::LAYOUT,
+    &[
+        #[cfg(feature = "storage")]
+        &storage::TAP_HOLD_CONFIG_STATE_STORAGE_LISTENER,
+    ],
+);
+
+async fn process_tap_hold_command(command: TapHoldCommand) {
+    TAP_HOLD_CONFIG_STATE
+        .update(|config| match command {
+            TapHoldCommand::SetTappingTerm(term) => config.tapping_term = Some(term),
+            TapHoldCommand::IncreaseTappingTerm(amount) => {
+                config.tapping_term = Some(
+                    config
+                        .tapping_term
+                        .unwrap_or(DEFAULT_TAPPING_TERM)
+                        .saturating_add(amount),
+                )
+            }
+            TapHoldCommand::DecreaseTappingTerm(amount) => {
+                config.tapping_term = Some(
+                    config
+                        .tapping_term
+                        .unwrap_or(DEFAULT_TAPPING_TERM)
+                        .saturating_sub(amount),
+                )
+            }
+            TapHoldCommand::SetStrategy(strategy) => config.strategy = Some(strategy),
+            TapHoldCommand::Reset => **config = TapHoldConfig::LAYOUT,
+        })
+        .await;
+
+    info!(
+        "[KEYBOARD] Tap-hold config: {:?}",
+        Debug2Format(&TAP_HOLD_CONFIG_STATE.get().await)
+    );
+}
+
+/// Apply [`TAP_HOLD_CONFIG_STATE`] and [`KeyboardLayout::tap_hold_config`] to a tap-hold key when
+/// it is pressed. This is called by the layout, so the state must be read without waiting.
+fn apply_tap_hold_config<K: KeyboardLayout>(
+    coord: (u8, u8),
+    timeout: u16,
+    config: HoldTapConfig,
+) -> (u16, HoldTapConfig) {
+    let tap_hold_config = K::tap_hold_config(
+        coord,
+        TAP_HOLD_CONFIG_STATE
+            .try_get()
+            .unwrap_or(TapHoldConfig::LAYOUT),
+    );
+
+    (
+        tap_hold_config.tapping_term.unwrap_or(timeout),
+        tap_hold_config.strategy.map_or(config, HoldTapConfig::from),
+    )
+}
+
+/// Settings used by the keyboard matrix. Settings that are set to `None` use the values from your
+/// [`KeyboardMatrix`] implementation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MatrixConfig {
+    /// Debounce setting, used instead of [`KeyboardMatrix::DEBOUNCE_MS`].
+    pub debounce_ms: Option<u16>,
+}
+
+impl MatrixConfig {
+    /// A config that uses the settings from your [`KeyboardMatrix`] implementation.
+    pub const DEFAULT: Self = Self { debounce_ms: None };
+}
+
+#[cfg(feature = "storage")]
+impl crate::storage::StoredData for MatrixConfig {
+    const SCHEMA_VERSION: u16 = 1;
+}
+
+pub(crate) static MATRIX_CONFIG_MATRIX_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+/// State that contains the settings used by the keyboard matrix. This can be changed at runtime
+/// by setting this state, or using [`handle_matrix_config_report`]. Changes are applied the next
+/// time the matrix is scanned. If you specified a storage driver, and enabled the `storage`
+/// feature, the settings are saved, and restored when your keyboard restarts.
+pub static MATRIX_CONFIG_STATE: State<MatrixConfig> = State::new(
+    MatrixConfig::DEFAULT,
+    &[
+        &MATRIX_CONFIG_MATRIX_LISTENER,
+        #[cfg(feature = "storage")]
+        &storage::MATRIX_CONFIG_STATE_STORAGE_LISTENER,
+    ],
+);
+
+/// First byte of the raw HID reports handled by [`handle_matrix_config_report`].
+pub const MATRIX_CONFIG_COMMAND_ID: u8 = 0xA1;
+
+const MATRIX_CONFIG_SUBCOMMAND_GET_DEBOUNCE: u8 = 0x01;
+const MATRIX_CONFIG_SUBCOMMAND_SET_DEBOUNCE: u8 = 0x02;
+const MATRIX_CONFIG_SUBCOMMAND_RESET: u8 = 0x03;
+const MATRIX_CONFIG_SUBCOMMAND_SAVE: u8 = 0x04;
+const MATRIX_CONFIG_SUBCOMMAND_ERROR: u8 = 0xFF;
+
+/// Handle a raw HID report used to get or change the matrix settings. This can be called from
+/// [`crate::raw_hid::RawHIDDevice::handle_raw_hid_report`], or
+/// [`crate::via::ViaKeyboard::handle_via_command`] if you are using Via or Vial.
+///
+/// Reports that start with [`MATRIX_CONFIG_COMMAND_ID`] are handled, and modified in place to form
+/// a response. Returns `false` for other reports, so that they can be handled by your own code.
+/// The second byte contains a subcommand:
+/// - `0x01` (get debounce): the response contains the debounce setting in bytes 2 and 3 (big
+///   endian).
+/// - `0x02` (set debounce): bytes 2 and 3 contain the new debounce setting (big endian).
+/// - `0x03` (reset): use the settings from your [`KeyboardMatrix`] implementation.
+/// - `0x04` (save): save the settings immediately, instead of waiting for the save policy defined
+///   in [`crate::storage::StorageDevice`].
+///
+/// If the subcommand fails, the second byte of the response is set to `0xFF`.
+pub fn handle_matrix_config_report<K: KeyboardMatrix>(data: &mut [u8]) -> bool {
+    if data.len() < 4 || data[0] != MATRIX_CONFIG_COMMAND_ID {
+        return false;
+    }
+
+    let success = match data[1] {
+        MATRIX_CONFIG_SUBCOMMAND_GET_DEBOUNCE => match MATRIX_CONFIG_STATE.try_get() {
+            Some(config) => {
+                let debounce_ms = config.debounce_ms.unwrap_or(K::DEBOUNCE_MS);
+                data[2..4].copy_from_slice(&debounce_ms.to_be_bytes());
+                true
+            }
+            None => false,
+        },
+        MATRIX_CONFIG_SUBCOMMAND_SET_DEBOUNCE => match MATRIX_CONFIG_STATE.try_get() {
+            Some(mut config) => {
+                config.debounce_ms = Some(u16::from_be_bytes([data[2], data[3]]));
+                MATRIX_CONFIG_STATE.try_set(config)
+            }
+            None => false,
+        },
+        MATRIX_CONFIG_SUBCOMMAND_RESET => MATRIX_CONFIG_STATE.try_set(MatrixConfig::DEFAULT),
+        MATRIX_CONFIG_SUBCOMMAND_SAVE => {
+            #[cfg(feature = "storage")]
+            storage::MATRIX_CONFIG_SAVE_SIGNAL.signal(());
+            true
+        }
+        _ => false,
+    };
+
+    if !success {
+        warn!(
+            "[KEYBOARD] Could not handle matrix config command {:X}",
+            data[1]
+        );
+        data[1] = MATRIX_CONFIG_SUBCOMMAND_ERROR;
+    }
+
+    true
 }
 
 pub struct PollableMatrix<T> {
-    matrix: Mutex<RawMutex, T>,
+    pub(crate) matrix: Mutex<RawMutex, T>,
+    /// Signal used to interrupt the matrix polling task while it waits for a key press, so that it
+    /// releases the matrix.
+    wake: Signal<RawMutex, ()>,
 }
 
 impl<T: Pollable> PollableMatrix<T> {
     pub const fn new(m: T) -> Self {
         Self {
             matrix: Mutex::new(m),
+            wake: Signal::new(),
         }
     }
+
+    /// Obtain exclusive access to the matrix. If the matrix polling task is waiting for a key
+    /// press, it is woken up so that it releases the matrix.
+    pub(crate) async fn lock(&self) -> MutexGuard<'_, RawMutex, T> {
+        self.wake.signal(());
+        self.matrix.lock().await
+    }
 }
 
 /// Trait that allows you to implement matrix polling functionality. This trait is already
@@ -247,6 +913,60 @@ impl<T: Pollable> PollableMatrix<T> {
 pub trait Pollable {
     /// Poll the matrix for events
     fn events(&mut self) -> impl Iterator<Item = Event>;
+
+    /// Change the debounce setting of the matrix. `debounce_scans` is the number of consecutive
+    /// scans that a key must stay in the same state for before an event is produced. This is
+    /// called by the matrix polling task when [`MATRIX_CONFIG_STATE`] or the poll interval
+    /// changes, so you don't need to call it yourself. By default, this does nothing.
+    fn set_debounce(&mut self, _debounce_scans: u16) {}
+
+    /// Wait for a key to be pressed, without scanning the matrix. This is called by the matrix
+    /// polling task when all keys have been released for [`KeyboardMatrix::IDLE_TIMEOUT_MS`]. By
+    /// default, this returns immediately, so the matrix keeps being polled.
+    ///
+    /// Waiting for a key press is opt-in, since it requires pins that can wait for an edge. Out of
+    /// the existing matrices, only [`InterruptMatrix`] implements this.
+    ///
+    /// The returned future may be dropped before a key is pressed, if the matrix settings change,
+    /// or if another task needs access to the matrix. The matrix must still be able to be scanned
+    /// afterwards.
+    async fn wait_for_key_press(&mut self) {}
+
+    /// Change the actuation settings of a key in an analog matrix. Returns `false` if this isn't
+    /// an analog matrix, or if the key is out of bounds. By default, this does nothing.
+    ///
+    /// This is called by the matrix polling task when the settings in
+    /// [`crate::analog::ANALOG_KEY_CONFIGS_STATE`] change, so you don't need to call it yourself.
+    fn set_analog_key_config(&mut self, _row: u8, _col: u8, _config: AnalogKeyConfig) -> bool {
+        false
+    }
+
+    /// Start calibrating an analog matrix. Returns `false` if this isn't an analog matrix. By
+    /// default, this does nothing.
+    fn start_analog_calibration(&mut self) -> bool {
+        false
+    }
+
+    /// Finish calibrating an analog matrix, using the recorded readings of each key that travelled
+    /// at least `min_travel`. Returns `false` if the matrix wasn't being calibrated. By default,
+    /// this does nothing.
+    fn finish_analog_calibration(&mut self, _min_travel: u16) -> bool {
+        false
+    }
+
+    /// Obtain the range of raw readings of a key in an analog matrix, from the key at rest to the
+    /// key fully pressed. Returns `None` if this isn't an analog matrix, or the key isn't used. By
+    /// default, this returns `None`.
+    fn analog_calibration(&self, _row: u8, _col: u8) -> Option<Range<u16>> {
+        None
+    }
+
+    /// Change the range of raw readings of a key in an analog matrix, from the key at rest to the
+    /// key fully pressed. Returns `false` if this isn't an analog matrix, or the key isn't used. By
+    /// default, this does nothing.
+    fn set_analog_calibration(&mut self, _row: u8, _col: u8, _range: Range<u16>) -> bool {
+        false
+    }
 }
 
 pub type PollableStandardMatrix<I, O, const CS: usize, const RS: usize> =
@@ -268,6 +988,273 @@ impl<
                 .unwrap(),
         )
     }
+
+    fn set_debounce(&mut self, debounce_scans: u16) {
+        self.1.set_nb_bounce(debounce_scans);
+    }
+}
+
+/// A pin that is active when it is high, instead of low.
+///
+/// The built-in matrices strobe their outputs by setting them low, and detect pressed keys when
+/// their inputs are low. Wrapping the pins of a matrix with this type allows it to strobe its
+/// outputs by setting them high instead, and detect pressed keys when its inputs are high (e.g.
+/// with pull-down resistors).
+pub struct ActiveHigh<P>(pub P);
+
+impl<P: InputPin> InputPin for ActiveHigh<P> {
+    type Error = P::Error;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        self.0.is_low()
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        self.0.is_high()
+    }
+}
+
+impl<P: OutputPin> OutputPin for ActiveHigh<P> {
+    type Error = P::Error;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0.set_high()
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.0.set_low()
+    }
+}
+
+/// A keyboard matrix with diodes facing from the rows to the columns (row2col).
+///
+/// Columns are pulled low one at a time, and rows are read using pull-up resistors. The keys
+/// found while scanning are transposed, so that they are indexed by row, then column.
+pub struct Row2ColMatrix<I: InputPin, O: OutputPin, const CS: usize, const RS: usize> {
+    matrix: Matrix<I, O, RS, CS>,
+}
+
+impl<I: InputPin, O: OutputPin, const CS: usize, const RS: usize> Row2ColMatrix<I, O, CS, RS> {
+    /// Create a new matrix. Rows should use pull-up resistors.
+    pub fn new<E>(rows: [I; RS], cols: [O; CS]) -> Result<Self, E>
+    where
+        I: InputPin<Error = E>,
+        O: OutputPin<Error = E>,
+    {
+        Ok(Self {
+            matrix: Matrix::new(rows, cols)?,
+        })
+    }
+
+    /// Scan the matrix and check which keys are pressed. The result is indexed by column, then
+    /// row.
+    pub fn get_with_delay<F: FnMut(), E>(&mut self, delay: F) -> Result<[[bool; RS]; CS], E>
+    where
+        I: InputPin<Error = E>,
+        O: OutputPin<Error = E>,
+    {
+        self.matrix.get_with_delay(delay)
+    }
+}
+
+pub type PollableRow2ColMatrix<I, O, const CS: usize, const RS: usize> =
+    (Row2ColMatrix<I, O, CS, RS>, Debouncer<[[bool; RS]; CS]>);
+
+impl<
+        I: InputPin<Error = Infallible>,
+        O: OutputPin<Error = Infallible>,
+        const CS: usize,
+        const RS: usize,
+    > Pollable for PollableRow2ColMatrix<I, O, CS, RS>
+{
+    fn events(&mut self) -> impl Iterator<Item = Event> {
+        self.1
+            .events(
+                self.0
+                    .get_with_delay(|| {
+                        embassy_time::block_for(Duration::from_ticks(2));
+                    })
+                    .unwrap(),
+            )
+            .map(|e| e.transform(|col, row| (row, col)))
+    }
+
+    fn set_debounce(&mut self, debounce_scans: u16) {
+        self.1.set_nb_bounce(debounce_scans);
+    }
+}
+
+/// A keyboard matrix with diodes, which can wait for a key to be pressed without being scanned.
+///
+/// This is scanned like [`Matrix`], but the column pins must also be able to wait for a falling
+/// edge. While idle, all rows are pulled low, so that pressing any key pulls its column low, which
+/// wakes up the matrix polling task. This allows the MCU to sleep instead of scanning the matrix,
+/// which saves power on wireless keyboards.
+pub struct InterruptMatrix<I: InputPin, O: OutputPin, const CS: usize, const RS: usize> {
+    matrix: Matrix<I, O, CS, RS>,
+}
+
+impl<I: InputPin + Wait, O: OutputPin, const CS: usize, const RS: usize>
+    InterruptMatrix<I, O, CS, RS>
+{
+    /// Create a new matrix. Columns should use pull-up resistors.
+    pub fn new<E>(cols: [I; CS], rows: [O; RS]) -> Result<Self, E>
+    where
+        I: InputPin<Error = E>,
+        O: OutputPin<Error = E>,
+    {
+        Ok(Self {
+            matrix: Matrix::new(cols, rows)?,
+        })
+    }
+
+    /// Scan the matrix and check which keys are pressed.
+    pub fn get_with_delay<F: FnMut(), E>(&mut self, delay: F) -> Result<[[bool; CS]; RS], E>
+    where
+        I: InputPin<Error = E>,
+        O: OutputPin<Error = E>,
+    {
+        self.matrix.get_with_delay(delay)
+    }
+
+    /// Wait for any key to be pressed. The rows are released afterwards, so the matrix can be
+    /// scanned again.
+    pub async fn wait_for_key_press<E>(&mut self) -> Result<(), E>
+    where
+        I: InputPin<Error = E>,
+        O: OutputPin<Error = E>,
+    {
+        self.matrix.drive_all_rows()?;
+
+        {
+            let mut cols = self
+                .matrix
+                .cols_mut()
+                .iter_mut()
+                .map(|col| col.wait_for_low())
+                .collect::<Vec<_, CS>>();
+            select_slice(&mut cols).await;
+        }
+
+        self.matrix.release_rows()
+    }
+}
+
+pub type PollableInterruptMatrix<I, O, const CS: usize, const RS: usize> =
+    (InterruptMatrix<I, O, CS, RS>, Debouncer<[[bool; CS]; RS]>);
+
+impl<
+        I: InputPin<Error = Infallible> + Wait,
+        O: OutputPin<Error = Infallible>,
+        const CS: usize,
+        const RS: usize,
+    > Pollable for PollableInterruptMatrix<I, O, CS, RS>
+{
+    fn events(&mut self) -> impl Iterator<Item = Event> {
+        self.1.events(
+            self.0
+                .get_with_delay(|| {
+                    embassy_time::block_for(Duration::from_ticks(2));
+                })
+                .unwrap(),
+        )
+    }
+
+    fn set_debounce(&mut self, debounce_scans: u16) {
+        self.1.set_nb_bounce(debounce_scans);
+    }
+
+    async fn wait_for_key_press(&mut self) {
+        debug!("[KEYBOARD] Matrix is idle, waiting for a key press");
+        self.0.wait_for_key_press().await.unwrap();
+    }
+}
+
+pub type PollableDuplexMatrix<P, const CS: usize, const RS: usize> = (
+    DuplexMatrix<P, CS, RS>,
+    Debouncer<[[bool; CS]; RS]>,
+    Debouncer<[[bool; CS]; RS]>,
+);
+
+impl<
+        P: InputPin<Error = Infallible> + OutputPin<Error = Infallible>,
+        const CS: usize,
+        const RS: usize,
+    > Pollable for PollableDuplexMatrix<P, CS, RS>
+{
+    fn events(&mut self) -> impl Iterator<Item = Event> {
+        let (row_to_col, col_to_row) = self
+            .0
+            .get_with_delay(|| {
+                embassy_time::block_for(Duration::from_ticks(2));
+            })
+            .unwrap();
+
+        // Each row pin is split into two rows, depending on the direction the switch was scanned in
+        let row_to_col = self
+            .1
+            .events(row_to_col)
+            .map(|e| e.transform(|row, col| (row * 2, col)));
+        let col_to_row = self
+            .2
+            .events(col_to_row)
+            .map(|e| e.transform(|row, col| (row * 2 + 1, col)));
+
+        row_to_col.chain(col_to_row)
+    }
+
+    fn set_debounce(&mut self, debounce_scans: u16) {
+        self.1.set_nb_bounce(debounce_scans);
+        self.2.set_nb_bounce(debounce_scans);
+    }
+}
+
+pub type PollableRoundRobinMatrix<P, const N: usize> =
+    (RoundRobinMatrix<P, N>, Debouncer<[[bool; N]; N]>);
+
+impl<P: InputPin<Error = Infallible> + OutputPin<Error = Infallible>, const N: usize> Pollable
+    for PollableRoundRobinMatrix<P, N>
+{
+    fn events(&mut self) -> impl Iterator<Item = Event> {
+        self.1.events(
+            self.0
+                .get_with_delay(|| {
+                    embassy_time::block_for(Duration::from_ticks(2));
+                })
+                .unwrap(),
+        )
+    }
+
+    fn set_debounce(&mut self, debounce_scans: u16) {
+        self.1.set_nb_bounce(debounce_scans);
+    }
+}
+
+pub type PollableShiftRegisterMatrix<I, O, const CS: usize, const RS: usize> = (
+    ShiftRegisterMatrix<I, O, CS, RS>,
+    Debouncer<[[bool; CS]; RS]>,
+);
+
+impl<
+        I: InputPin<Error = Infallible>,
+        O: OutputPin<Error = Infallible>,
+        const CS: usize,
+        const RS: usize,
+    > Pollable for PollableShiftRegisterMatrix<I, O, CS, RS>
+{
+    fn events(&mut self) -> impl Iterator<Item = Event> {
+        self.1.events(
+            self.0
+                .get_with_delay(|| {
+                    embassy_time::block_for(Duration::from_ticks(2));
+                })
+                .unwrap(),
+        )
+    }
+
+    fn set_debounce(&mut self, debounce_scans: u16) {
+        self.1.set_nb_bounce(debounce_scans);
+    }
 }
 
 pub type PollableDirectPinMatrix<I, const CS: usize, const RS: usize> =
@@ -279,6 +1266,135 @@ impl<I: InputPin<Error = Infallible>, const CS: usize, const RS: usize> Pollable
     fn events(&mut self) -> impl Iterator<Item = Event> {
         self.1.events(self.0.get().unwrap())
     }
+
+    fn set_debounce(&mut self, debounce_scans: u16) {
+        self.1.set_nb_bounce(debounce_scans);
+    }
+}
+
+/// Trait that allows you to use a GPIO expander (e.g. over I2C or SPI) to scan a keyboard matrix.
+/// Pins are identified by their index on the expander, and the state of all pins is represented by
+/// a bitmask, where bit `n` is the state of pin `n`.
+///
+/// This trait is implemented by the built-in MCP23017 and PCF8574 drivers. You can also implement
+/// this trait for your own types to use other GPIO expanders.
+pub trait IoExpander {
+    /// Type of error returned by the GPIO expander.
+    type Error: core::fmt::Debug;
+
+    /// Configure the pins used by the matrix. `outputs` contains the pins that are driven by the
+    /// matrix, and `inputs` contains the pins that are read, which should use pull-up resistors.
+    /// If the GPIO expander supports it, changes on the input pins should trigger its interrupt
+    /// pin.
+    fn init(&mut self, outputs: u16, inputs: u16) -> Result<(), Self::Error>;
+
+    /// Set the state of the output pins. Bits for input pins are always set.
+    fn write(&mut self, state: u16) -> Result<(), Self::Error>;
+
+    /// Read the state of all pins.
+    fn read(&mut self) -> Result<u16, Self::Error>;
+}
+
+/// A keyboard matrix with diodes, where the rows and columns are connected to a GPIO expander.
+///
+/// Rows are pulled low one at a time, and columns are read using pull-up resistors. If the
+/// interrupt pin of the GPIO expander is connected, all rows are kept low between scans, so that
+/// the matrix only needs to be scanned when the interrupt pin is asserted (low), or a key is held.
+pub struct IoExpanderMatrix<E, I, const CS: usize, const RS: usize> {
+    expander: E,
+    cols: [u8; CS],
+    rows: [u8; RS],
+    interrupt: Option<I>,
+    keys: [[bool; CS]; RS],
+    // Whether no keys were pressed during the last scan
+    idle: bool,
+}
+
+impl<E: IoExpander, I: InputPin<Error = Infallible>, const CS: usize, const RS: usize>
+    IoExpanderMatrix<E, I, CS, RS>
+{
+    /// Create a new matrix, using the given pins of the GPIO expander as columns and rows.
+    pub fn new(
+        mut expander: E,
+        cols: [u8; CS],
+        rows: [u8; RS],
+        interrupt: Option<I>,
+    ) -> Result<Self, E::Error> {
+        let outputs = rows.iter().fold(0, |mask, pin| mask | 1 << pin);
+        let inputs = cols.iter().fold(0, |mask, pin| mask | 1 << pin);
+        expander.init(outputs, inputs)?;
+        expander.write(!outputs)?;
+
+        Ok(Self {
+            expander,
+            cols,
+            rows,
+            interrupt,
+            keys: [[false; CS]; RS],
+            idle: false,
+        })
+    }
+
+    fn row_mask(&self) -> u16 {
+        self.rows.iter().fold(0, |mask, pin| mask | 1 << pin)
+    }
+
+    /// Scan the matrix and check which keys are pressed.
+    pub fn get(&mut self) -> Result<[[bool; CS]; RS], E::Error> {
+        if self.idle
+            && self
+                .interrupt
+                .as_ref()
+                .is_some_and(|interrupt| interrupt.is_high().unwrap())
+        {
+            return Ok(self.keys);
+        }
+
+        let mut keys = [[false; CS]; RS];
+
+        for (ri, row) in self.rows.iter().enumerate() {
+            self.expander.write(!(1 << row))?;
+            let state = self.expander.read()?;
+            for (ci, col) in self.cols.iter().enumerate() {
+                keys[ri][ci] = state & (1 << col) == 0;
+            }
+        }
+
+        // Keep all rows low between scans, so that key presses trigger the interrupt pin
+        let outputs = if self.interrupt.is_some() {
+            !self.row_mask()
+        } else {
+            u16::MAX
+        };
+        self.expander.write(outputs)?;
+
+        self.keys = keys;
+        self.idle = keys.iter().flatten().all(|pressed| !pressed);
+        Ok(keys)
+    }
+}
+
+pub type PollableIoExpanderMatrix<E, I, const CS: usize, const RS: usize> =
+    (IoExpanderMatrix<E, I, CS, RS>, Debouncer<[[bool; CS]; RS]>);
+
+impl<E: IoExpander, I: InputPin<Error = Infallible>, const CS: usize, const RS: usize> Pollable
+    for PollableIoExpanderMatrix<E, I, CS, RS>
+{
+    fn events(&mut self) -> impl Iterator<Item = Event> {
+        let keys = self.0.get().unwrap_or_else(|error| {
+            warn!(
+                "[KEYBOARD] Could not scan the GPIO expander matrix: {}",
+                Debug2Format(&error)
+            );
+            self.0.keys
+        });
+
+        self.1.events(keys)
+    }
+
+    fn set_debounce(&mut self, debounce_scans: u16) {
+        self.1.set_nb_bounce(debounce_scans);
+    }
 }
 
 /// Trait that allows you to use ADC hardware to pull samples for an analog matrix.
@@ -315,25 +1431,110 @@ pub type PollableAnalogMatrix<'a, S, const CS: usize, const RS: usize> = (
     AnalogMatrixSampler<'a, S, CS, RS>,
     AnalogMatrix<<S as MatrixSampler>::SampleType, CS, RS>,
     AnalogActuator<CS, RS>,
+    AnalogCalibrator<CS, RS>,
 );
 
 impl<S: MatrixSampler, const CS: usize, const RS: usize> Pollable
     for PollableAnalogMatrix<'_, S, CS, RS>
 where
     u32: From<S::SampleType>,
+    S::SampleType: Copy + TryFrom<u16>,
 {
     fn events(&mut self) -> impl Iterator<Item = Event> {
-        let matrix_state = self
-            .1
-            .get(|row, col| {
-                self.0
-                    .get_key_state(row, col)
-                    .ok_or(SampleError::NoSampleForKeyPosition(row, col))
-            })
-            .unwrap();
+        let matrix_state = if self.3.is_active() {
+            for row in 0..RS {
+                for col in 0..CS {
+                    if let Some(sample) = self.0.get_key_state(row, col) {
+                        self.3.record(row, col, sample_to_u16(sample));
+                    }
+                }
+            }
+
+            // Release all keys, and ignore new presses while calibrating
+            [[0; CS]; RS]
+        } else {
+            self.1
+                .get(|row, col| {
+                    self.0
+                        .get_key_state(row, col)
+                        .ok_or(SampleError::NoSampleForKeyPosition(row, col))
+                })
+                .unwrap()
+        };
 
         self.2.events(matrix_state)
     }
+
+    fn set_analog_key_config(&mut self, row: u8, col: u8, config: AnalogKeyConfig) -> bool {
+        let (row, col) = (row as usize, col as usize);
+        let sensitivity = config.rapid_trigger_sensitivity;
+
+        self.2.set_mode(row, col, config.mode).is_ok()
+            && self
+                .2
+                .set_actuation_point(row, col, config.actuation_point)
+                .is_ok()
+            && self
+                .2
+                .set_thresholds(row, col, sensitivity, sensitivity)
+                .is_ok()
+    }
+
+    fn start_analog_calibration(&mut self) -> bool {
+        self.3.start();
+        true
+    }
+
+    fn finish_analog_calibration(&mut self, min_travel: u16) -> bool {
+        if !self.3.stop() {
+            return false;
+        }
+
+        for row in 0..RS {
+            for col in 0..CS {
+                if let Some(range) = self.3.recorded_range(row, col, min_travel) {
+                    self.set_analog_calibration(row as u8, col as u8, range);
+                }
+            }
+        }
+
+        true
+    }
+
+    fn analog_calibration(&self, row: u8, col: u8) -> Option<Range<u16>> {
+        self.1
+            .range(row as usize, col as usize)
+            .filter(|range| !range.is_empty())
+            .map(|range| sample_to_u16(range.start)..sample_to_u16(range.end))
+    }
+
+    fn set_analog_calibration(&mut self, row: u8, col: u8, range: Range<u16>) -> bool {
+        let (row, col) = (row as usize, col as usize);
+
+        // Unused positions have an empty range, and stay unused
+        if self
+            .1
+            .range(row, col)
+            .map_or(true, |range| range.is_empty())
+        {
+            return false;
+        }
+
+        match (
+            S::SampleType::try_from(range.start),
+            S::SampleType::try_from(range.end),
+        ) {
+            (Ok(start), Ok(end)) => self.1.set_range(row, col, start..end).is_some(),
+            _ => false,
+        }
+    }
+}
+
+fn sample_to_u16<T>(sample: T) -> u16
+where
+    u32: From<T>,
+{
+    u32::from(sample).min(u16::MAX as u32) as u16
 }
 
 /// Channel with keyboard events polled from the swtich matrix
@@ -342,16 +1543,158 @@ where
 /// [`KeyboardMatrix::remap_to_layout`].
 pub(crate) static POLLED_EVENTS_CHANNEL: Channel<RawMutex, Event, 1> = Channel::new();
 
+/// Number of injected keys that can be held at once. Injected keys are identified by an index from
+/// 0 to `INJECTED_KEYS - 1`.
+pub const INJECTED_KEYS: u8 = 8;
+
+/// First virtual key used by injected keys.
+pub(crate) const INJECTED_VIRTUAL_KEY: u8 = LEADER_VIRTUAL_KEY - INJECTED_KEYS;
+
+/// Virtual key used to hold the automatic mouse layer (see
+/// [`crate::pointing::PointingKeyboard::POINTING_AUTO_MOUSE_LAYER`]). Combos use the virtual keys
+/// before it.
+pub(crate) const AUTO_MOUSE_VIRTUAL_KEY: u8 = INJECTED_VIRTUAL_KEY - 1;
+
+/// A key event that doesn't come from the switch matrix, sent using [`INJECTED_EVENTS_CHANNEL`].
+#[derive(Clone, Copy)]
+pub enum InjectedEvent {
+    /// Press the injected key with the given index, which performs the given action.
+    Press(u8, Action<Keycode>),
+    /// Release the injected key with the given index.
+    Release(u8),
+}
+
+/// Channel used to inject key events into the layout from your own tasks. This can be used to
+/// turn external inputs (e.g. touch pads, foot switches, or commands received over serial) into
+/// key presses, without assigning them a position on the switch matrix.
+///
+/// Injected keys are processed like the keys on your matrix, so they can trigger leader sequences
+/// and Vial combos, but they can't be used in combos defined with layout positions. Make sure to
+/// release every injected key that you press.
+pub static INJECTED_EVENTS_CHANNEL: Channel<RawMutex, InjectedEvent, 4> = Channel::new();
+
+/// Convert an [`InjectedEvent`] into an event for the layout, using the virtual key of the
+/// injected key. Returns `None` if the index of the injected key is out of bounds.
+fn inject_event<const C: usize, const R: usize, const L: usize>(
+    layout: &mut KeyberonLayout<C, R, L, Keycode>,
+    injected: InjectedEvent,
+) -> Option<Event> {
+    match injected {
+        InjectedEvent::Press(index, action) if index < INJECTED_KEYS => {
+            layout
+                .set_virtual_key(INJECTED_VIRTUAL_KEY + index, action)
+                .ok()?;
+            Some(Event::Press(VIRTUAL_ROW, INJECTED_VIRTUAL_KEY + index))
+        }
+        InjectedEvent::Release(index) if index < INJECTED_KEYS => {
+            Some(Event::Release(VIRTUAL_ROW, INJECTED_VIRTUAL_KEY + index))
+        }
+        _ => {
+            warn!("[KEYBOARD] Injected key index is out of bounds.");
+            None
+        }
+    }
+}
+
+/// Time between matrix scans while the USB host is asleep. Scanning less often allows the MCU to
+/// spend more time sleeping, to stay within the USB suspend current budget.
+pub(crate) const SUSPENDED_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Time between matrix scans while the keyboard is in use.
+const POLL_INTERVAL: Duration = Duration::from_micros(500);
+
+/// Convert a debounce time to the number of consecutive scans that a key must stay in the same
+/// state for, when the matrix is scanned every `poll_interval`.
+fn debounce_scans(debounce_ms: u16, poll_interval: Duration) -> u16 {
+    (debounce_ms as u64 * 1000)
+        .div_ceil(poll_interval.as_micros())
+        .min(u16::MAX as u64) as u16
+}
+
 #[rumcake_macros::task]
 pub async fn matrix_poll<K: KeyboardMatrix + 'static>(_k: K) {
     let matrix = K::get_matrix();
+    let mut calibration_started = None;
+    let mut pressed_keys: usize = 0;
+    let mut last_change = Instant::now();
+    let mut diagnostics = MatrixDiagnostics::new();
+
+    // Poll interval that the debounce setting was last converted with
+    let mut debounce_interval = None;
 
     loop {
+        #[cfg(feature = "usb")]
+        let suspended = crate::usb::USB_SUSPENDED_STATE.get().await;
+        #[cfg(not(feature = "usb"))]
+        let suspended = false;
+
+        let poll_interval = if suspended {
+            SUSPENDED_POLL_INTERVAL
+        } else {
+            POLL_INTERVAL
+        };
+
+        if pressed_keys == 0
+            && last_change.elapsed() >= Duration::from_millis(K::IDLE_TIMEOUT_MS as u64)
+        {
+            let mut idle_matrix = matrix.matrix.lock().await;
+
+            // Stop waiting if the matrix settings change, or if another task needs the matrix. The
+            // matrix is released after waiting, so that other tasks can lock it between scans.
+            match select3(
+                idle_matrix.wait_for_key_press(),
+                matrix.wake.wait(),
+                select(
+                    MATRIX_CONFIG_MATRIX_LISTENER.wait(),
+                    ANALOG_KEY_CONFIGS_MATRIX_LISTENER.wait(),
+                ),
+            )
+            .await
+            {
+                Either3::First(()) => last_change = Instant::now(),
+                Either3::Second(()) => {}
+                // Waiting on the listeners resets them, so signal them again so that the new
+                // settings are applied below.
+                Either3::Third(Either::First(())) => MATRIX_CONFIG_MATRIX_LISTENER.signal(()),
+                Either3::Third(Either::Second(())) => ANALOG_KEY_CONFIGS_MATRIX_LISTENER.signal(()),
+            }
+        }
+
         {
-            debug!("[KEYBOARD] Scanning matrix");
             let mut matrix = matrix.matrix.lock().await;
+
+            debug!("[KEYBOARD] Scanning matrix");
+
+            poll_calibration::<K>(&mut *matrix, &mut calibration_started);
+
+            // The debouncer counts scans, so the debounce setting must be converted again whenever
+            // the poll interval changes
+            if MATRIX_CONFIG_MATRIX_LISTENER.signaled() || debounce_interval != Some(poll_interval)
+            {
+                MATRIX_CONFIG_MATRIX_LISTENER.reset();
+                let config = MATRIX_CONFIG_STATE.get().await;
+                let debounce_ms = config.debounce_ms.unwrap_or(K::DEBOUNCE_MS);
+                matrix.set_debounce(debounce_scans(debounce_ms, poll_interval));
+                debounce_interval = Some(poll_interval);
+            }
+
+            if ANALOG_KEY_CONFIGS_MATRIX_LISTENER.signaled() {
+                ANALOG_KEY_CONFIGS_MATRIX_LISTENER.reset();
+                let configs = ANALOG_KEY_CONFIGS_STATE.get().await;
+                apply_key_configs::<K>(&mut *matrix, &configs);
+            }
+
             let events = matrix.events();
+            diagnostics.scanned();
             for e in events {
+                last_change = Instant::now();
+                diagnostics.event(e);
+                if e.is_press() {
+                    pressed_keys += 1;
+                } else {
+                    pressed_keys = pressed_keys.saturating_sub(1);
+                }
+
                 let (row, col) = e.coord();
                 let (new_row, new_col) = K::remap_to_layout(row, col);
 
@@ -366,10 +1709,60 @@ pub async fn matrix_poll<K: KeyboardMatrix + 'static>(_k: K) {
                     Debug2Format(&remapped_event)
                 );
 
+                #[cfg(feature = "usb")]
+                if remapped_event.is_press() {
+                    crate::usb::request_remote_wakeup();
+                }
+
+                crate::diagnostics::event_scanned();
                 POLLED_EVENTS_CHANNEL.send(remapped_event).await;
             }
         }
-        Timer::after(Duration::from_micros(500)).await;
+
+        Timer::after(poll_interval).await;
+    }
+}
+
+#[rumcake_macros::task]
+pub async fn extra_keys_poll<K: ExtraKeys + 'static>(_k: K) {
+    let extra_keys = K::get_extra_keys();
+
+    loop {
+        {
+            let mut extra_keys = extra_keys.matrix.lock().await;
+            for e in extra_keys.events() {
+                let (_, idx) = e.coord();
+                let Some(&(row, col)) = K::EXTRA_KEY_POSITIONS.get(idx as usize) else {
+                    continue;
+                };
+                let event = e.transform(|_, _| (row, col));
+
+                info!(
+                    "[KEYBOARD] Extra key event: {:?}, Position: {:?}",
+                    Debug2Format(&e),
+                    Debug2Format(&event)
+                );
+
+                #[cfg(feature = "usb")]
+                if event.is_press() {
+                    crate::usb::request_remote_wakeup();
+                }
+
+                POLLED_EVENTS_CHANNEL.send(event).await;
+            }
+        }
+
+        #[cfg(feature = "usb")]
+        let suspended = crate::usb::USB_SUSPENDED_STATE.get().await;
+        #[cfg(not(feature = "usb"))]
+        let suspended = false;
+
+        Timer::after(if suspended {
+            SUSPENDED_POLL_INTERVAL
+        } else {
+            Duration::from_millis(1)
+        })
+        .await;
     }
 }
 
@@ -377,26 +1770,89 @@ pub async fn matrix_poll<K: KeyboardMatrix + 'static>(_k: K) {
 /// backlight reactive effects) The coordinates received will be remapped according to the
 /// implementation of [`KeyboardMatrix::remap_to_layout`].
 ///
-/// There can be a maximum of 4 subscribers, and the number of subscribers actually used
+/// There can be a maximum of 5 subscribers, and the number of subscribers actually used
 /// depend on what features you have enabled. With underglow and backlight enabled, 2 subscriber
-/// slots will be used.
-pub static MATRIX_EVENTS: PubSubChannel<RawMutex, Event, 4, 4, 1> = PubSubChannel::new();
+/// slots will be used. With bluetooth enabled, 1 subscriber slot will be used while advertising
+/// is paused.
+pub static MATRIX_EVENTS: PubSubChannel<RawMutex, Event, 4, 5, 1> = PubSubChannel::new();
+
+/// Maximum number of keyboard, consumer or system control reports that can be queued before they
+/// are sent to the host.
+pub const HID_REPORT_QUEUE_SIZE: usize = 8;
+
+/// Possible actions to take when a report queue is full.
+///
+/// Keyboard and consumer reports contain the state of all pressed keys, so the last report in the
+/// queue always describes the latest state. With either policy, the newest report is always
+/// queued, so the host will not miss any key releases.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportOverflowPolicy {
+    /// Discard all queued reports, and queue the newest report. Intermediate states (e.g. quick
+    /// taps) that haven't been sent yet will be lost, but the host will catch up immediately.
+    Coalesce,
+    /// Discard the oldest queued report to make room for the newest report.
+    DropOldest,
+}
+
+/// Queue a report without waiting, applying the overflow `policy` if the queue is full. This
+/// prevents a host that isn't reading reports from blocking the layout task.
+pub(crate) fn queue_report<T>(
+    channel: &Channel<RawMutex, T, HID_REPORT_QUEUE_SIZE>,
+    report: T,
+    policy: ReportOverflowPolicy,
+) {
+    if let Err(TrySendError::Full(report)) = channel.try_send(report) {
+        warn!(
+            "[KEYBOARD] Report queue is full, applying overflow policy: {:?}",
+            Debug2Format(&policy)
+        );
+
+        match policy {
+            ReportOverflowPolicy::Coalesce => while channel.try_receive().is_ok() {},
+            ReportOverflowPolicy::DropOldest => {
+                let _ = channel.try_receive();
+            }
+        }
+
+        let _ = channel.try_send(report);
+    }
+}
 
 /// Channel for sending NKRO HID keyboard reports.
 ///
 /// Channel messages should be consumed by the bluetooth task or USB task, so user-level code
 /// should **not** attempt to receive messages from the channel, otherwise commands may not be
 /// processed appropriately. You should only send to this channel.
-pub static KEYBOARD_REPORT_HID_SEND_CHANNEL: Channel<RawMutex, NKROBootKeyboardReport, 1> =
-    Channel::new();
+pub static KEYBOARD_REPORT_HID_SEND_CHANNEL: Channel<
+    RawMutex,
+    NKROBootKeyboardReport,
+    HID_REPORT_QUEUE_SIZE,
+> = Channel::new();
 
 /// Channel for sending consumer HID reports.
 ///
 /// Channel messages should be consumed by the bluetooth task or USB task, so user-level code
 /// should **not** attempt to receive messages from the channel, otherwise commands may not be
 /// processed appropriately. You should only send to this channel.
-pub static CONSUMER_REPORT_HID_SEND_CHANNEL: Channel<RawMutex, MultipleConsumerReport, 1> =
-    Channel::new();
+pub static CONSUMER_REPORT_HID_SEND_CHANNEL: Channel<
+    RawMutex,
+    MultipleConsumerReport,
+    HID_REPORT_QUEUE_SIZE,
+> = Channel::new();
+
+/// Amount of time after startup during which pressing [`KeyboardLayout::FACTORY_RESET_KEY`] will
+/// trigger a factory reset.
+#[cfg(feature = "storage")]
+const FACTORY_RESET_KEY_BOOT_WINDOW_MS: u64 = 1000;
+
+/// Time between each tick of the layout. This matches the fastest USB polling interval (1ms), so
+/// that a new keyboard report can be produced every time the host polls the keyboard.
+const LAYOUT_TICK_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Maximum number of distinct keys (including modifiers) that can be collected for a keyboard
+/// report. Keyboard keycodes fit in a `u8`, so every key can be held at once and still be included
+/// in the report's bitmap.
+const MAX_REPORTED_KEYS: usize = 256;
 
 #[rumcake_macros::task]
 pub async fn layout_collect<K: KeyboardLayout + 'static>(_k: K)
@@ -405,25 +1861,132 @@ where
     [(); K::LAYOUT_COLS]:,
     [(); K::LAYOUT_ROWS]:,
 {
-    let mut last_keys = Vec::<KeyboardKeycode, 24>::new();
+    let mut last_keys = Vec::<KeyboardKeycode, MAX_REPORTED_KEYS>::new();
     let layout = K::get_layout();
+    {
+        let mut layout = layout.lock().await;
+        layout.set_hold_tap_override(Some(apply_tap_hold_config::<K>));
+        layout.set_sequence_delay(K::SEND_STRING_DELAY as u32);
+    }
 
     #[cfg(feature = "media-keycodes")]
     let mut codes = [Consumer::Unassigned; 4];
 
-    let mut ticker = Ticker::every(Duration::from_millis(1));
+    #[cfg(feature = "media-keycodes")]
+    let mut system_control_key = None;
+
+    let mut combo_processor = ComboProcessor::new();
+    let combo_term = Duration::from_millis(K::COMBO_TERM as u64);
+    let mut leader = LeaderProcessor::new();
+    let leader_timeout = Duration::from_millis(K::LEADER_TIMEOUT as u64);
+    let mut dynamic_macro_recorder = DynamicMacroRecorder::new();
+    let mut unicode_typer = UnicodeTyper::new();
+    let mut swap_hands = SwapHandsProcessor::new();
+    let mut accessibility = AccessibilityFilter::new();
+    let mut grave_escape = None;
+    let mut key_lock = KeyLock::new();
+    let mut default_layer = 0;
+    let mut layer_state = 1;
+
+    let mut ticker = Ticker::every(LAYOUT_TICK_INTERVAL);
 
     loop {
+        let mut event_scanned_at = None;
+
         let keys = {
+            let accessibility_config = ACCESSIBILITY_CONFIG_STATE.get().await;
+            let stored_default_layer = DEFAULT_LAYER_STATE.get().await;
             let mut layout = layout.lock().await;
+            let mut events = AccessibilityEvents::new();
+
+            // The default layer was changed outside of the layout (e.g. restored from storage)
+            if stored_default_layer != default_layer {
+                layout.set_default_layer(stored_default_layer as usize);
+                default_layer = stored_default_layer;
+            }
 
             if let Ok(event) = POLLED_EVENTS_CHANNEL.try_receive() {
-                layout.event(event);
+                event_scanned_at = crate::diagnostics::take_event_scan_time();
+
+                #[cfg(feature = "storage")]
+                if event.is_press()
+                    && K::FACTORY_RESET_KEY == Some(event.coord())
+                    && embassy_time::Instant::now().as_millis() < FACTORY_RESET_KEY_BOOT_WINDOW_MS
+                {
+                    warn!("[KEYBOARD] Factory reset key held during startup.");
+                    crate::storage::FACTORY_RESET_SIGNAL.signal(());
+                }
+
+                let swapped = swap_hands.event(K::SWAP_HANDS_MAP, event);
+                events = accessibility.event(&*layout, &accessibility_config, swapped);
                 MATRIX_EVENTS.publish_immediate(event); // Just immediately publish since we don't want to hold up any key events to be converted into keycodes.
+
+                #[cfg(any(feature = "console", feature = "hid-console", feature = "nus-console"))]
+                crate::console::log_matrix_event(event);
             };
 
+            if let Ok(injected) = INJECTED_EVENTS_CHANNEL.try_receive() {
+                if let Some(event) = inject_event(&mut *layout, injected) {
+                    #[cfg(feature = "usb")]
+                    if event.is_press() {
+                        crate::usb::request_remote_wakeup();
+                    }
+
+                    let _ = events.push(event);
+                }
+            }
+
+            #[cfg(feature = "pointing")]
+            if let Some(layer) = crate::pointing::AUTO_MOUSE_LAYER_SIGNAL.try_take() {
+                match layer {
+                    Some(layer) => {
+                        if layout
+                            .set_virtual_key(AUTO_MOUSE_VIRTUAL_KEY, Action::Layer(layer as usize))
+                            .is_ok()
+                        {
+                            let _ = events.push(Event::Press(VIRTUAL_ROW, AUTO_MOUSE_VIRTUAL_KEY));
+                        }
+                    }
+                    None => {
+                        let _ = events.push(Event::Release(VIRTUAL_ROW, AUTO_MOUSE_VIRTUAL_KEY));
+                    }
+                }
+            }
+
+            for event in events
+                .into_iter()
+                .chain(accessibility.tick(&*layout, &accessibility_config))
+            {
+                if !leader.event(&mut *layout, K::LEADER_SEQUENCES, event) {
+                    for event in combo_processor.event(&mut *layout, K::COMBOS, event) {
+                        layout.event(event);
+                    }
+                }
+            }
+
+            for event in combo_processor.tick(&mut *layout, K::COMBOS, combo_term) {
+                layout.event(event);
+            }
+            leader.tick(&mut *layout, K::LEADER_SEQUENCES, leader_timeout);
+
             let tick = layout.tick();
 
+            if layout.default_layer() as u8 != default_layer {
+                default_layer = layout.default_layer() as u8;
+                info!("[KEYBOARD] Default layer: {}", default_layer);
+                DEFAULT_LAYER_STATE.set(default_layer).await;
+            }
+
+            let active_layers = layout
+                .active_layers()
+                .filter(|layer| *layer < 32)
+                .fold(0, |mask, layer| mask | 1 << layer);
+            if active_layers != layer_state {
+                layer_state = active_layers;
+                debug!("[KEYBOARD] Active layers: {:b}", layer_state);
+                LAYER_STATE.set(layer_state).await;
+            }
+
             debug!("[KEYBOARD] Processing rumcake feature keycodes");
 
             match tick {
@@ -439,10 +2002,45 @@ where
                         {
                             *c = keycode;
                         }
-                        CONSUMER_REPORT_HID_SEND_CHANNEL
-                            .send(MultipleConsumerReport { codes })
+                        queue_report(
+                            &CONSUMER_REPORT_HID_SEND_CHANNEL,
+                            MultipleConsumerReport { codes },
+                            K::REPORT_OVERFLOW_POLICY,
+                        );
+                    }
+                    #[cfg(feature = "media-keycodes")]
+                    Keycode::SystemControl(keycode) => {
+                        system_control_key = Some(keycode);
+                        queue_report(
+                            &crate::system_control::SYSTEM_CONTROL_REPORT_HID_SEND_CHANNEL,
+                            crate::system_control::SystemControlReport {
+                                usage: keycode as u16,
+                            },
+                            K::REPORT_OVERFLOW_POLICY,
+                        );
+                    }
+                    #[cfg(feature = "mouse")]
+                    Keycode::Mouse(keycode) => {
+                        crate::mouse::MOUSE_KEYS_EVENT_CHANNEL
+                            .send((keycode, true))
                             .await;
                     }
+                    #[cfg(feature = "pointing")]
+                    Keycode::Pointing(command) => {
+                        crate::pointing::POINTING_COMMAND_CHANNEL
+                            .send((command, true))
+                            .await;
+                    }
+                    #[cfg(feature = "gamepad")]
+                    Keycode::Gamepad(keycode) => {
+                        crate::gamepad::GAMEPAD_COMMAND_CHANNEL
+                            .send((keycode, true).into())
+                            .await;
+                    }
+                    #[cfg(feature = "midi")]
+                    Keycode::Midi(keycode) => {
+                        crate::midi::process_midi_keycode(keycode, true).await;
+                    }
                     #[cfg(feature = "underglow")]
                     Keycode::Underglow(command) => {
                         crate::underglow::UNDERGLOW_COMMAND_CHANNEL
@@ -489,6 +2087,81 @@ where
                             .send(command)
                             .await;
                     }
+                    #[cfg(feature = "storage")]
+                    Keycode::FactoryReset => {
+                        crate::storage::FACTORY_RESET_SIGNAL.signal(());
+                    }
+                    Keycode::NKRO(command) => {
+                        NKRO_STATE
+                            .update(|enabled| {
+                                **enabled = match command {
+                                    NKROCommand::Enable => true,
+                                    NKROCommand::Disable => false,
+                                    NKROCommand::Toggle => !**enabled,
+                                }
+                            })
+                            .await;
+                    }
+                    Keycode::Bootloader => {
+                        warn!("[KEYBOARD] Jumping to bootloader.");
+                        crate::hw::mcu::jump_to_bootloader();
+                    }
+                    #[cfg(all(feature = "nrf", feature = "ota"))]
+                    Keycode::OtaBootloader => {
+                        warn!("[KEYBOARD] Jumping to bootloader in OTA DFU mode.");
+                        crate::hw::mcu::jump_to_ota_bootloader();
+                    }
+                    Keycode::Reset => {
+                        warn!("[KEYBOARD] Resetting keyboard.");
+                        crate::hw::reset();
+                    }
+                    #[cfg(any(
+                        feature = "console",
+                        feature = "hid-console",
+                        feature = "nus-console"
+                    ))]
+                    Keycode::DebugToggle => {
+                        crate::console::set_debug_enabled(!crate::console::debug_enabled());
+                    }
+                    Keycode::TapHold(command) => {
+                        process_tap_hold_command(command).await;
+                    }
+                    Keycode::Leader => {
+                        leader.start();
+                    }
+                    Keycode::DynamicMacro(command) => {
+                        dynamic_macro_recorder
+                            .process_command(&mut *layout, command)
+                            .await;
+                    }
+                    Keycode::Unicode(command) => {
+                        unicode_typer.process_command(&mut *layout, command).await;
+                    }
+                    Keycode::SwapHands(command) => {
+                        swap_hands.process_command(command, true);
+                    }
+                    Keycode::Accessibility(command) => {
+                        process_accessibility_command(command).await;
+                    }
+                    Keycode::GraveEscape => {
+                        let shifted = layout.keycodes().any(|k| {
+                            matches!(
+                                k,
+                                KeyCode::LShift | KeyCode::RShift | KeyCode::LGui | KeyCode::RGui
+                            )
+                        });
+                        grave_escape = Some(if shifted {
+                            KeyCode::Grave
+                        } else {
+                            KeyCode::Escape
+                        });
+                    }
+                    Keycode::KeyLock => {
+                        key_lock.toggle();
+                    }
+                    Keycode::AnalogCalibrate => {
+                        crate::analog::start_calibration();
+                    }
                 },
                 CustomEvent::Release(keycode) => match keycode {
                     Keycode::Custom(id) => {
@@ -499,10 +2172,53 @@ where
                         if let Some(c) = codes.iter_mut().find(|c| **c == keycode) {
                             *c = Consumer::Unassigned;
                         }
-                        CONSUMER_REPORT_HID_SEND_CHANNEL
-                            .send(MultipleConsumerReport { codes })
+                        queue_report(
+                            &CONSUMER_REPORT_HID_SEND_CHANNEL,
+                            MultipleConsumerReport { codes },
+                            K::REPORT_OVERFLOW_POLICY,
+                        );
+                    }
+                    #[cfg(feature = "media-keycodes")]
+                    Keycode::SystemControl(keycode) => {
+                        // Only one system control key can be reported at a time, so only release
+                        // the key if it is the one that was pressed last
+                        if system_control_key == Some(keycode) {
+                            system_control_key = None;
+                            queue_report(
+                                &crate::system_control::SYSTEM_CONTROL_REPORT_HID_SEND_CHANNEL,
+                                crate::system_control::SystemControlReport::default(),
+                                K::REPORT_OVERFLOW_POLICY,
+                            );
+                        }
+                    }
+                    #[cfg(feature = "mouse")]
+                    Keycode::Mouse(keycode) => {
+                        crate::mouse::MOUSE_KEYS_EVENT_CHANNEL
+                            .send((keycode, false))
+                            .await;
+                    }
+                    #[cfg(feature = "pointing")]
+                    Keycode::Pointing(command) => {
+                        crate::pointing::POINTING_COMMAND_CHANNEL
+                            .send((command, false))
                             .await;
                     }
+                    #[cfg(feature = "gamepad")]
+                    Keycode::Gamepad(keycode) => {
+                        crate::gamepad::GAMEPAD_COMMAND_CHANNEL
+                            .send((keycode, false).into())
+                            .await;
+                    }
+                    #[cfg(feature = "midi")]
+                    Keycode::Midi(keycode) => {
+                        crate::midi::process_midi_keycode(keycode, false).await;
+                    }
+                    Keycode::SwapHands(command) => {
+                        swap_hands.process_command(command, false);
+                    }
+                    Keycode::GraveEscape => {
+                        grave_escape = None;
+                    }
                     #[allow(unreachable_patterns)]
                     _ => {}
                 },
@@ -510,10 +2226,33 @@ where
 
             debug!("[KEYBOARD] Collecting keyboard keycodes");
 
-            let keys = layout
+            let nkro = NKRO_STATE.get().await;
+            let mut pressed = 0;
+
+            let locked = key_lock.update(layout.keycodes());
+            let pressed_keys = layout
                 .keycodes()
+                .chain(grave_escape)
+                .chain(locked)
                 .filter_map(|k| KeyboardKeycode::try_from(k as u8).ok())
-                .collect::<Vec<KeyboardKeycode, 24>>();
+                .filter(|k| {
+                    // Modifiers are reported separately, so they don't count towards the 6 key limit
+                    if (KeyboardKeycode::LeftControl as u8..=KeyboardKeycode::RightGUI as u8)
+                        .contains(&(*k as u8))
+                    {
+                        return true;
+                    }
+                    pressed += 1;
+                    nkro || pressed <= 6
+                });
+
+            // Each keycode is only collected once, so every held key fits in the buffer
+            let mut keys = Vec::<KeyboardKeycode, MAX_REPORTED_KEYS>::new();
+            for key in pressed_keys {
+                if !keys.contains(&key) {
+                    let _ = keys.push(key);
+                }
+            }
 
             debug!("[KEYBOARD] Collected {:?}", Debug2Format(&keys));
 
@@ -521,18 +2260,35 @@ where
         }; // unlock the layout, so that another task can register new layout events
 
         if last_keys != keys {
+            // Keys typed while entering a bluetooth passkey are not sent to the host
+            #[cfg(feature = "bluetooth")]
+            let entering_passkey = crate::bluetooth::process_passkey_keys(&last_keys, &keys).await;
+            #[cfg(not(feature = "bluetooth"))]
+            let entering_passkey = false;
+
+            if !entering_passkey {
+                dynamic_macro_recorder.record(&last_keys, &keys).await;
+            }
+
             last_keys.clone_from(&keys);
 
             debug!("[KEYBOARD] Preparing new report");
 
-            // Use send instead of try_send to avoid dropped inputs, which can happen if keycodes
-            // update really quickly (this is usually the case for macros). If USB and Bluetooth
-            // are both not connected, this channel can become filled, so we discard the report in
-            // that case.
-            if CURRENT_OUTPUT_STATE.get().await.is_some() {
-                KEYBOARD_REPORT_HID_SEND_CHANNEL
-                    .send(NKROBootKeyboardReport::new(keys))
-                    .await;
+            // Reports are queued without waiting, so that a slow host can't hold up the layout.
+            // If USB and Bluetooth are both not connected, this channel can become filled, so we
+            // discard the report in that case.
+            if entering_passkey {
+                debug!("[KEYBOARD] Passkey is being entered, discarding report");
+            } else if CURRENT_OUTPUT_STATE.get().await.is_some() {
+                queue_report(
+                    &KEYBOARD_REPORT_HID_SEND_CHANNEL,
+                    NKROBootKeyboardReport::new(keys),
+                    K::REPORT_OVERFLOW_POLICY,
+                );
+
+                if let Some(scanned_at) = event_scanned_at {
+                    crate::diagnostics::report_queued(scanned_at);
+                }
             } else {
                 warn!("[KEYBOARD] Discarding report");
             }
@@ -541,3 +2297,88 @@ where
         ticker.next().await;
     }
 }
+
+#[cfg(feature = "storage")]
+pub mod storage {
+    use embassy_sync::signal::Signal;
+
+    use crate::hw::mcu::RawMutex;
+    use crate::storage::{FlashStorage, StorageDevice};
+
+    use super::{DEFAULT_LAYER_STATE, MATRIX_CONFIG_STATE, TAP_HOLD_CONFIG_STATE};
+
+    pub(super) static TAP_HOLD_CONFIG_STATE_STORAGE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+    /// Signal used to save the tap-hold settings immediately, instead of waiting for the save
+    /// policy defined in [`StorageDevice`].
+    pub(crate) static TAP_HOLD_CONFIG_SAVE_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+    /// Task that restores the tap-hold settings, and saves any changes to them.
+    #[rumcake_macros::task]
+    pub async fn tap_hold_config_storage_task<K: StorageDevice, F: FlashStorage>(
+        _k: K,
+        database: &crate::storage::StorageService<'static, F>,
+    ) where
+        [(); F::ERASE_SIZE]:,
+        [(); F::REGION_SIZE]:,
+    {
+        database
+            .persist_state::<K, _>(
+                crate::storage::StorageKey::TapHoldConfig,
+                &TAP_HOLD_CONFIG_STATE,
+                &TAP_HOLD_CONFIG_STATE_STORAGE_LISTENER,
+                &TAP_HOLD_CONFIG_SAVE_SIGNAL,
+            )
+            .await
+    }
+
+    pub(super) static MATRIX_CONFIG_STATE_STORAGE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+    /// Signal used to save the matrix settings immediately, instead of waiting for the save policy
+    /// defined in [`StorageDevice`].
+    pub(crate) static MATRIX_CONFIG_SAVE_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+    /// Task that restores the matrix settings, and saves any changes to them.
+    #[rumcake_macros::task]
+    pub async fn matrix_config_storage_task<K: StorageDevice, F: FlashStorage>(
+        _k: K,
+        database: &crate::storage::StorageService<'static, F>,
+    ) where
+        [(); F::ERASE_SIZE]:,
+        [(); F::REGION_SIZE]:,
+    {
+        database
+            .persist_state::<K, _>(
+                crate::storage::StorageKey::MatrixConfig,
+                &MATRIX_CONFIG_STATE,
+                &MATRIX_CONFIG_STATE_STORAGE_LISTENER,
+                &MATRIX_CONFIG_SAVE_SIGNAL,
+            )
+            .await
+    }
+
+    pub(super) static DEFAULT_LAYER_STATE_STORAGE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+    /// Signal used to save the default layer immediately, instead of waiting for the save policy
+    /// defined in [`StorageDevice`].
+    pub(crate) static DEFAULT_LAYER_SAVE_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+    /// Task that restores the default layer, and saves any changes to it.
+    #[rumcake_macros::task]
+    pub async fn default_layer_storage_task<K: StorageDevice, F: FlashStorage>(
+        _k: K,
+        database: &crate::storage::StorageService<'static, F>,
+    ) where
+        [(); F::ERASE_SIZE]:,
+        [(); F::REGION_SIZE]:,
+    {
+        database
+            .persist_state::<K, _>(
+                crate::storage::StorageKey::DefaultLayer,
+                &DEFAULT_LAYER_STATE,
+                &DEFAULT_LAYER_STATE_STORAGE_LISTENER,
+                &DEFAULT_LAYER_SAVE_SIGNAL,
+            )
+            .await
+    }
+}
@@ -0,0 +1,404 @@
+//! HID LampArray support.
+//!
+//! This exposes the LEDs of your RGB backlight matrix to the host as a HID LampArray, allowing
+//! Windows Dynamic Lighting (and other host software that supports LampArrays) to set the color
+//! of each LED.
+//!
+//! While the host is in control of the LEDs, the backlight will use the
+//! [`BacklightEffect::DirectSet`] effect. When the host hands control back to the keyboard (by
+//! enabling autonomous mode), the effect that was in use beforehand will be restored.
+//!
+//! To use LampArray, you will need to implement [`LampArrayDevice`].
+
+use defmt::debug;
+use embassy_sync::channel::Channel;
+use smart_leds::RGB8;
+
+use crate::backlight::rgb_backlight_matrix::animations::{BacklightCommand, BacklightEffect};
+use crate::backlight::rgb_backlight_matrix::{
+    BACKLIGHT_COMMAND_CHANNEL, BACKLIGHT_CONFIG_STATE, DIRECT_SET_CHANNEL,
+};
+use crate::backlight::{BacklightMatrix, BacklightMatrixDevice, LEDFlags};
+use crate::hw::mcu::RawMutex;
+
+pub(crate) const LAMP_ARRAY_ATTRIBUTES_REPORT_ID: u8 = 0x01;
+pub(crate) const LAMP_ATTRIBUTES_REQUEST_REPORT_ID: u8 = 0x02;
+pub(crate) const LAMP_ATTRIBUTES_RESPONSE_REPORT_ID: u8 = 0x03;
+pub(crate) const LAMP_MULTI_UPDATE_REPORT_ID: u8 = 0x04;
+pub(crate) const LAMP_RANGE_UPDATE_REPORT_ID: u8 = 0x05;
+pub(crate) const LAMP_ARRAY_CONTROL_REPORT_ID: u8 = 0x06;
+
+/// Maximum number of lamps that can be updated by a single multi-update report.
+const LAMP_MULTI_UPDATE_LAMP_COUNT: usize = 8;
+
+/// Report descriptor for a LampArray, using the reports defined by the HID Lighting and
+/// Illumination usage page. All reports are feature reports.
+pub(crate) const LAMP_ARRAY_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x59, // Usage Page (Lighting And Illumination)
+    0x09, 0x01, // Usage (LampArray)
+    0xA1, 0x01, // Collection (Application)
+    // LampArrayAttributesReport
+    0x85, 0x01, //   Report ID (1)
+    0x09, 0x02, //   Usage (LampArrayAttributesReport)
+    0xA1, 0x02, //   Collection (Logical)
+    0x09, 0x03, //     Usage (LampCount)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x27, 0xFF, 0xFF, 0x00, 0x00, //     Logical Maximum (65535)
+    0x75, 0x10, //     Report Size (16)
+    0x95, 0x01, //     Report Count (1)
+    0xB1, 0x03, //     Feature (Constant, Variable, Absolute)
+    0x09, 0x04, //     Usage (BoundingBoxWidthInMicrometers)
+    0x09, 0x05, //     Usage (BoundingBoxHeightInMicrometers)
+    0x09, 0x06, //     Usage (BoundingBoxDepthInMicrometers)
+    0x09, 0x07, //     Usage (LampArrayKind)
+    0x09, 0x08, //     Usage (MinUpdateIntervalInMicroseconds)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x27, 0xFF, 0xFF, 0xFF, 0x7F, //     Logical Maximum (2147483647)
+    0x75, 0x20, //     Report Size (32)
+    0x95, 0x05, //     Report Count (5)
+    0xB1, 0x03, //     Feature (Constant, Variable, Absolute)
+    0xC0, //   End Collection
+    // LampAttributesRequestReport
+    0x85, 0x02, //   Report ID (2)
+    0x09, 0x20, //   Usage (LampAttributesRequestReport)
+    0xA1, 0x02, //   Collection (Logical)
+    0x09, 0x21, //     Usage (LampId)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x27, 0xFF, 0xFF, 0x00, 0x00, //     Logical Maximum (65535)
+    0x75, 0x10, //     Report Size (16)
+    0x95, 0x01, //     Report Count (1)
+    0xB1, 0x02, //     Feature (Data, Variable, Absolute)
+    0xC0, //   End Collection
+    // LampAttributesResponseReport
+    0x85, 0x03, //   Report ID (3)
+    0x09, 0x22, //   Usage (LampAttributesResponseReport)
+    0xA1, 0x02, //   Collection (Logical)
+    0x09, 0x21, //     Usage (LampId)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x27, 0xFF, 0xFF, 0x00, 0x00, //     Logical Maximum (65535)
+    0x75, 0x10, //     Report Size (16)
+    0x95, 0x01, //     Report Count (1)
+    0xB1, 0x02, //     Feature (Data, Variable, Absolute)
+    0x09, 0x23, //     Usage (PositionXInMicrometers)
+    0x09, 0x24, //     Usage (PositionYInMicrometers)
+    0x09, 0x25, //     Usage (PositionZInMicrometers)
+    0x09, 0x27, //     Usage (UpdateLatencyInMicroseconds)
+    0x09, 0x26, //     Usage (LampPurposes)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x27, 0xFF, 0xFF, 0xFF, 0x7F, //     Logical Maximum (2147483647)
+    0x75, 0x20, //     Report Size (32)
+    0x95, 0x05, //     Report Count (5)
+    0xB1, 0x02, //     Feature (Data, Variable, Absolute)
+    0x09, 0x28, //     Usage (RedLevelCount)
+    0x09, 0x29, //     Usage (GreenLevelCount)
+    0x09, 0x2A, //     Usage (BlueLevelCount)
+    0x09, 0x2B, //     Usage (IntensityLevelCount)
+    0x09, 0x2C, //     Usage (IsProgrammable)
+    0x09, 0x2D, //     Usage (InputBinding)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x26, 0xFF, 0x00, //     Logical Maximum (255)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x06, //     Report Count (6)
+    0xB1, 0x02, //     Feature (Data, Variable, Absolute)
+    0xC0, //   End Collection
+    // LampMultiUpdateReport
+    0x85, 0x04, //   Report ID (4)
+    0x09, 0x50, //   Usage (LampMultiUpdateReport)
+    0xA1, 0x02, //   Collection (Logical)
+    0x09, 0x03, //     Usage (LampCount)
+    0x09, 0x55, //     Usage (LampUpdateFlags)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x08, //     Logical Maximum (8)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x02, //     Report Count (2)
+    0xB1, 0x02, //     Feature (Data, Variable, Absolute)
+    0x09, 0x21, //     Usage (LampId)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x27, 0xFF, 0xFF, 0x00, 0x00, //     Logical Maximum (65535)
+    0x75, 0x10, //     Report Size (16)
+    0x95, 0x08, //     Report Count (8)
+    0xB1, 0x02, //     Feature (Data, Variable, Absolute)
+    0x09, 0x51, //     Usage (RedUpdateChannel)
+    0x09, 0x52, //     Usage (GreenUpdateChannel)
+    0x09, 0x53, //     Usage (BlueUpdateChannel)
+    0x09, 0x54, //     Usage (IntensityUpdateChannel)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x26, 0xFF, 0x00, //     Logical Maximum (255)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x20, //     Report Count (32)
+    0xB1, 0x02, //     Feature (Data, Variable, Absolute)
+    0xC0, //   End Collection
+    // LampRangeUpdateReport
+    0x85, 0x05, //   Report ID (5)
+    0x09, 0x60, //   Usage (LampRangeUpdateReport)
+    0xA1, 0x02, //   Collection (Logical)
+    0x09, 0x55, //     Usage (LampUpdateFlags)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x08, //     Logical Maximum (8)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x01, //     Report Count (1)
+    0xB1, 0x02, //     Feature (Data, Variable, Absolute)
+    0x09, 0x61, //     Usage (LampIdStart)
+    0x09, 0x62, //     Usage (LampIdEnd)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x27, 0xFF, 0xFF, 0x00, 0x00, //     Logical Maximum (65535)
+    0x75, 0x10, //     Report Size (16)
+    0x95, 0x02, //     Report Count (2)
+    0xB1, 0x02, //     Feature (Data, Variable, Absolute)
+    0x09, 0x51, //     Usage (RedUpdateChannel)
+    0x09, 0x52, //     Usage (GreenUpdateChannel)
+    0x09, 0x53, //     Usage (BlueUpdateChannel)
+    0x09, 0x54, //     Usage (IntensityUpdateChannel)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x26, 0xFF, 0x00, //     Logical Maximum (255)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x04, //     Report Count (4)
+    0xB1, 0x02, //     Feature (Data, Variable, Absolute)
+    0xC0, //   End Collection
+    // LampArrayControlReport
+    0x85, 0x06, //   Report ID (6)
+    0x09, 0x70, //   Usage (LampArrayControlReport)
+    0xA1, 0x02, //   Collection (Logical)
+    0x09, 0x71, //     Usage (AutonomousMode)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x01, //     Report Count (1)
+    0xB1, 0x02, //     Feature (Data, Variable, Absolute)
+    0xC0, //   End Collection
+    0xC0, // End Collection
+];
+
+/// A trait that keyboards must implement to expose their RGB backlight matrix as a HID
+/// LampArray.
+///
+/// Each LED that has a position in [`BacklightMatrix::layout`] becomes a lamp. Lamp IDs are
+/// assigned in the order that the LEDs appear in the layout (row by row), skipping any unused
+/// positions.
+pub trait LampArrayDevice: BacklightMatrixDevice {
+    /// Width of the area covered by your LEDs, in micrometers. X coordinates in your LED layout
+    /// (0-255) will be scaled to this width.
+    const LAMP_ARRAY_WIDTH_UM: u32;
+
+    /// Height (front to back) of the area covered by your LEDs, in micrometers. Y coordinates in
+    /// your LED layout (0-255) will be scaled to this height.
+    const LAMP_ARRAY_HEIGHT_UM: u32;
+
+    /// Depth of the area covered by your LEDs, in micrometers. All LEDs are reported to be at the
+    /// top of this area.
+    const LAMP_ARRAY_DEPTH_UM: u32 = 1000;
+}
+
+/// Lamp purposes, reported to the host for each lamp.
+const LAMP_PURPOSE_CONTROL: u32 = 0x01;
+const LAMP_PURPOSE_ACCENT: u32 = 0x02;
+const LAMP_PURPOSE_STATUS: u32 = 0x08;
+
+/// LampArrayKind reported to the host.
+const LAMP_ARRAY_KIND_KEYBOARD: u32 = 0x01;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum LampArrayCommand {
+    /// Set the colors of `count` lamps, using the lamp IDs in `ids`, and colors in `colors`.
+    MultiUpdate {
+        count: u8,
+        ids: [u16; LAMP_MULTI_UPDATE_LAMP_COUNT],
+        colors: [RGB8; LAMP_MULTI_UPDATE_LAMP_COUNT],
+    },
+    /// Set the colors of all lamps between `start` and `end` (inclusive) to `color`.
+    RangeUpdate { start: u16, end: u16, color: RGB8 },
+    /// Whether the keyboard should control its own LEDs (`true`), or let the host control them
+    /// (`false`).
+    SetAutonomousMode(bool),
+}
+
+/// Channel used to receive LampArray commands from the host.
+pub(crate) static LAMP_ARRAY_COMMAND_CHANNEL: Channel<RawMutex, LampArrayCommand, 4> =
+    Channel::new();
+
+/// Iterate over the LEDs in a backlight matrix that have a position, in order of their lamp ID.
+/// Items contain the index of the LED (`row * LIGHTING_COLS + col`), its position and its flags.
+fn lamps<const C: usize, const R: usize>(
+    matrix: &BacklightMatrix<C, R>,
+) -> impl Iterator<Item = (u16, (u8, u8), LEDFlags)> + '_ {
+    matrix
+        .layout
+        .iter()
+        .flatten()
+        .zip(matrix.flags.iter().flatten())
+        .enumerate()
+        .filter_map(|(led, (position, flags))| {
+            position.map(|position| (led as u16, position, *flags))
+        })
+}
+
+/// Number of lamps that will be reported to the host.
+pub(crate) fn lamp_count<K: LampArrayDevice>() -> u16
+where
+    [(); K::LIGHTING_COLS]:,
+    [(); K::LIGHTING_ROWS]:,
+{
+    lamps(&K::get_backlight_matrix()).count() as u16
+}
+
+fn update_interval_us<K: LampArrayDevice>() -> u32 {
+    1_000_000 / K::FPS as u32
+}
+
+/// Write a LampArrayAttributesReport (including the report ID) to `buf`, returning the length of
+/// the report.
+pub(crate) fn write_lamp_array_attributes_report<K: LampArrayDevice>(buf: &mut [u8]) -> usize
+where
+    [(); K::LIGHTING_COLS]:,
+    [(); K::LIGHTING_ROWS]:,
+{
+    buf[0] = LAMP_ARRAY_ATTRIBUTES_REPORT_ID;
+    buf[1..3].copy_from_slice(&lamp_count::<K>().to_le_bytes());
+    buf[3..7].copy_from_slice(&K::LAMP_ARRAY_WIDTH_UM.to_le_bytes());
+    buf[7..11].copy_from_slice(&K::LAMP_ARRAY_HEIGHT_UM.to_le_bytes());
+    buf[11..15].copy_from_slice(&K::LAMP_ARRAY_DEPTH_UM.to_le_bytes());
+    buf[15..19].copy_from_slice(&LAMP_ARRAY_KIND_KEYBOARD.to_le_bytes());
+    buf[19..23].copy_from_slice(&update_interval_us::<K>().to_le_bytes());
+    23
+}
+
+/// Write a LampAttributesResponseReport (including the report ID) for the given lamp to `buf`,
+/// returning the length of the report.
+pub(crate) fn write_lamp_attributes_report<K: LampArrayDevice>(
+    lamp_id: u16,
+    buf: &mut [u8],
+) -> usize
+where
+    [(); K::LIGHTING_COLS]:,
+    [(); K::LIGHTING_ROWS]:,
+{
+    let ((x, y), flags) = lamps(&K::get_backlight_matrix())
+        .nth(lamp_id as usize)
+        .map_or(((0, 0), LEDFlags::NONE), |(_, position, flags)| {
+            (position, flags)
+        });
+
+    let purposes = if flags.contains(LEDFlags::INDICATOR) {
+        LAMP_PURPOSE_STATUS
+    } else if flags.intersects(LEDFlags::KEYLIGHT | LEDFlags::ALPHA) {
+        LAMP_PURPOSE_CONTROL
+    } else {
+        LAMP_PURPOSE_ACCENT
+    };
+
+    buf[0] = LAMP_ATTRIBUTES_RESPONSE_REPORT_ID;
+    buf[1..3].copy_from_slice(&lamp_id.to_le_bytes());
+    buf[3..7].copy_from_slice(&(x as u32 * K::LAMP_ARRAY_WIDTH_UM / 255).to_le_bytes());
+    buf[7..11].copy_from_slice(&(y as u32 * K::LAMP_ARRAY_HEIGHT_UM / 255).to_le_bytes());
+    buf[11..15].copy_from_slice(&0u32.to_le_bytes());
+    buf[15..19].copy_from_slice(&update_interval_us::<K>().to_le_bytes());
+    buf[19..23].copy_from_slice(&purposes.to_le_bytes());
+    buf[23] = 255; // Red level count
+    buf[24] = 255; // Green level count
+    buf[25] = 255; // Blue level count
+    buf[26] = 1; // Intensity level count
+    buf[27] = 1; // Is programmable
+    buf[28] = 0; // Input binding
+    29
+}
+
+fn scale_color(data: &[u8]) -> RGB8 {
+    let intensity = data[3] as u16;
+    RGB8::new(
+        (data[0] as u16 * intensity / 255) as u8,
+        (data[1] as u16 * intensity / 255) as u8,
+        (data[2] as u16 * intensity / 255) as u8,
+    )
+}
+
+/// Parse a feature report sent by the host (including the report ID). Returns `None` if the
+/// report is not a command, or is malformed.
+pub(crate) fn parse_lamp_array_command(id: u8, data: &[u8]) -> Option<LampArrayCommand> {
+    match id {
+        LAMP_MULTI_UPDATE_REPORT_ID if data.len() >= 51 => {
+            let mut ids = [0; LAMP_MULTI_UPDATE_LAMP_COUNT];
+            let mut colors = [RGB8::default(); LAMP_MULTI_UPDATE_LAMP_COUNT];
+            for i in 0..LAMP_MULTI_UPDATE_LAMP_COUNT {
+                ids[i] = u16::from_le_bytes([data[3 + i * 2], data[4 + i * 2]]);
+                colors[i] = scale_color(&data[(19 + i * 4)..(23 + i * 4)]);
+            }
+
+            Some(LampArrayCommand::MultiUpdate {
+                count: data[1].min(LAMP_MULTI_UPDATE_LAMP_COUNT as u8),
+                ids,
+                colors,
+            })
+        }
+        LAMP_RANGE_UPDATE_REPORT_ID if data.len() >= 10 => Some(LampArrayCommand::RangeUpdate {
+            start: u16::from_le_bytes([data[2], data[3]]),
+            end: u16::from_le_bytes([data[4], data[5]]),
+            color: scale_color(&data[6..10]),
+        }),
+        LAMP_ARRAY_CONTROL_REPORT_ID if data.len() >= 2 => {
+            Some(LampArrayCommand::SetAutonomousMode(data[1] != 0))
+        }
+        _ => None,
+    }
+}
+
+#[rumcake_macros::task]
+pub async fn lamp_array_task<K: LampArrayDevice + 'static>(_k: K)
+where
+    [(); K::LIGHTING_COLS]:,
+    [(); K::LIGHTING_ROWS]:,
+{
+    // Backlight state to restore when the host hands control back to the keyboard. This is
+    // `None` while the keyboard is in autonomous mode.
+    let mut saved: Option<(bool, BacklightEffect)> = None;
+
+    loop {
+        match LAMP_ARRAY_COMMAND_CHANNEL.receive().await {
+            LampArrayCommand::SetAutonomousMode(false) => {
+                if saved.is_none() {
+                    let config = BACKLIGHT_CONFIG_STATE.get().await;
+                    saved = Some((config.enabled, config.effect));
+                    BACKLIGHT_COMMAND_CHANNEL
+                        .send(BacklightCommand::SetEffect(BacklightEffect::DirectSet))
+                        .await;
+                    BACKLIGHT_COMMAND_CHANNEL
+                        .send(BacklightCommand::TurnOn)
+                        .await;
+                }
+            }
+            LampArrayCommand::SetAutonomousMode(true) => {
+                if let Some((enabled, effect)) = saved.take() {
+                    BACKLIGHT_COMMAND_CHANNEL
+                        .send(BacklightCommand::SetEffect(effect))
+                        .await;
+                    if !enabled {
+                        BACKLIGHT_COMMAND_CHANNEL
+                            .send(BacklightCommand::TurnOff)
+                            .await;
+                    }
+                }
+            }
+            _ if saved.is_none() => {
+                debug!(
+                    "[LAMP_ARRAY] Ignoring lamp update, since the keyboard is in autonomous mode."
+                );
+            }
+            LampArrayCommand::MultiUpdate { count, ids, colors } => {
+                let matrix = K::get_backlight_matrix();
+                for i in 0..count as usize {
+                    if let Some((led, _, _)) = lamps(&matrix).nth(ids[i] as usize) {
+                        DIRECT_SET_CHANNEL.send((led, colors[i])).await;
+                    }
+                }
+            }
+            LampArrayCommand::RangeUpdate { start, end, color } => {
+                let matrix = K::get_backlight_matrix();
+                for (led, _, _) in lamps(&matrix)
+                    .skip(start as usize)
+                    .take((end as usize + 1).saturating_sub(start as usize))
+                {
+                    DIRECT_SET_CHANNEL.send((led, color)).await;
+                }
+            }
+        }
+    }
+}
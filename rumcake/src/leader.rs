@@ -0,0 +1,170 @@
+//! Leader key, which allows a sequence of keys to be typed after pressing [`Keycode::Leader`] to
+//! perform an action.
+//!
+//! Leader sequences can be defined in your layout by implementing
+//! [`KeyboardLayout::LEADER_SEQUENCES`]. After the leader key is pressed, the keys that are typed
+//! are not sent to the layout. Instead, they are matched against your leader sequences, using the
+//! keycode that they produce on the current layer. If the typed keys match a sequence, the
+//! sequence's action is tapped. If no sequence can be matched, or if no key is pressed for
+//! [`KeyboardLayout::LEADER_TIMEOUT`], the leader key is cancelled.
+//!
+//! [`KeyboardLayout::LEADER_SEQUENCES`]: crate::keyboard::KeyboardLayout::LEADER_SEQUENCES
+//! [`KeyboardLayout::LEADER_TIMEOUT`]: crate::keyboard::KeyboardLayout::LEADER_TIMEOUT
+
+use defmt::{debug, info, Debug2Format};
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use keyberon::action::{Action, HoldTapAction};
+use keyberon::key_code::KeyCode;
+use keyberon::layout::{Event, Layout as KeyberonLayout, VIRTUAL_KEYS, VIRTUAL_ROW};
+
+use crate::keyboard::Keycode;
+
+/// Maximum number of keys in a leader sequence.
+pub const MAX_LEADER_KEYS: usize = 5;
+
+/// Default value of [`crate::keyboard::KeyboardLayout::LEADER_TIMEOUT`], in milliseconds.
+pub const DEFAULT_LEADER_TIMEOUT: u16 = 300;
+
+/// Virtual key used to tap the action of a leader sequence. Combos and injected keys use the other
+/// virtual keys.
+pub(crate) const LEADER_VIRTUAL_KEY: u8 = VIRTUAL_KEYS as u8 - 1;
+
+/// A leader sequence defined in your layout.
+#[derive(Clone, Copy)]
+pub struct LeaderSequence {
+    /// Keycodes that must be typed after the leader key to trigger this sequence. Sequences can
+    /// have up to [`MAX_LEADER_KEYS`] keys.
+    pub keys: &'static [KeyCode],
+    /// Action to tap when the sequence is typed. This can be a keycode, or a
+    /// [`keyberon::action::Action::Sequence`] to type a macro.
+    pub action: Action<Keycode>,
+}
+
+/// Collects the keys typed after the leader key, and taps the action of the matching
+/// [`LeaderSequence`].
+pub(crate) struct LeaderProcessor {
+    active: bool,
+    keys: Vec<KeyCode, MAX_LEADER_KEYS>,
+    last_press: Instant,
+    // Keys pressed while the leader key was active, whose releases should not be sent to the layout
+    ignored: Vec<(u8, u8), MAX_LEADER_KEYS>,
+}
+
+impl LeaderProcessor {
+    pub(crate) const fn new() -> Self {
+        Self {
+            active: false,
+            keys: Vec::new(),
+            last_press: Instant::from_ticks(0),
+            ignored: Vec::new(),
+        }
+    }
+
+    /// Start collecting a new leader sequence.
+    pub(crate) fn start(&mut self) {
+        debug!("[LEADER] Leader key pressed");
+        self.active = true;
+        self.keys.clear();
+        self.last_press = Instant::now();
+    }
+
+    /// Process a matrix event. Returns `true` if the event was used by the leader key, in which
+    /// case it should not be sent to the layout.
+    pub(crate) fn event<const C: usize, const R: usize, const L: usize>(
+        &mut self,
+        layout: &mut KeyberonLayout<C, R, L, Keycode>,
+        sequences: &[LeaderSequence],
+        event: Event,
+    ) -> bool {
+        let coord = event.coord();
+
+        if event.is_release() {
+            if let Some(idx) = self.ignored.iter().position(|key| *key == coord) {
+                self.ignored.swap_remove(idx);
+                return true;
+            }
+            return false;
+        }
+
+        if !self.active {
+            return false;
+        }
+
+        // Keys that don't produce a keycode (e.g. layer keys) are sent to the layout as normal
+        let key = match layout.resolve_action(coord) {
+            Action::KeyCode(key)
+            | Action::HoldTap(&HoldTapAction {
+                tap: Action::KeyCode(key),
+                ..
+            }) => key,
+            _ => return false,
+        };
+
+        let _ = self.ignored.push(coord);
+        self.last_press = Instant::now();
+
+        if self.keys.push(key).is_err() {
+            self.finish(layout, None);
+            return true;
+        }
+
+        let exact = sequences
+            .iter()
+            .find(|sequence| sequence.keys == self.keys.as_slice());
+        let longer = sequences.iter().any(|sequence| {
+            sequence.keys.len() > self.keys.len() && sequence.keys.starts_with(&self.keys)
+        });
+
+        match (exact, longer) {
+            (None, false) => self.finish(layout, None),
+            (Some(sequence), false) => self.finish(layout, Some(sequence.action)),
+            // Wait for more keys, or the timeout
+            _ => {}
+        }
+
+        true
+    }
+
+    /// Finish the leader sequence if no key has been pressed for the given `timeout`.
+    pub(crate) fn tick<const C: usize, const R: usize, const L: usize>(
+        &mut self,
+        layout: &mut KeyberonLayout<C, R, L, Keycode>,
+        sequences: &[LeaderSequence],
+        timeout: Duration,
+    ) {
+        if self.active && self.last_press.elapsed() >= timeout {
+            let action = sequences
+                .iter()
+                .find(|sequence| sequence.keys == self.keys.as_slice())
+                .map(|sequence| sequence.action);
+            self.finish(layout, action);
+        }
+    }
+
+    fn finish<const C: usize, const R: usize, const L: usize>(
+        &mut self,
+        layout: &mut KeyberonLayout<C, R, L, Keycode>,
+        action: Option<Action<Keycode>>,
+    ) {
+        self.active = false;
+
+        let Some(action) = action else {
+            info!(
+                "[LEADER] No leader sequence matches {:?}",
+                Debug2Format(&self.keys)
+            );
+            return;
+        };
+
+        info!(
+            "[LEADER] Leader sequence typed: {:?}",
+            Debug2Format(&self.keys)
+        );
+
+        if layout.set_virtual_key(LEADER_VIRTUAL_KEY, action).is_ok() {
+            layout.event(Event::Press(VIRTUAL_ROW, LEADER_VIRTUAL_KEY));
+            layout.event(Event::Release(VIRTUAL_ROW, LEADER_VIRTUAL_KEY));
+        }
+    }
+}
@@ -49,6 +49,12 @@ impl<'a, T: Clone + PartialEq> State<'a, T> {
         self.data.lock().await.clone()
     }
 
+    /// Obtain the state's current value, without waiting. This can be used to read state from a
+    /// non-async context. Returns `None` if the state is currently locked.
+    pub fn try_get(&self) -> Option<T> {
+        self.data.try_lock().ok().map(|data| data.clone())
+    }
+
     async fn set_inner(&self, value: T) -> bool {
         let mut data = self.data.lock().await;
         let changed = *data != value;
@@ -63,6 +69,22 @@ impl<'a, T: Clone + PartialEq> State<'a, T> {
         }
     }
 
+    /// Update state and notify listeners, without waiting. This can be used to update state from
+    /// a non-async context. Returns `false` if the state is currently locked, in which case the
+    /// state will not be updated.
+    pub fn try_set(&self, value: T) -> bool {
+        let Ok(mut data) = self.data.try_lock() else {
+            return false;
+        };
+
+        if *data != value {
+            *data = value;
+            self.notify_listeners();
+        }
+
+        true
+    }
+
     /// Update state without notifying listeners
     pub async fn quiet_set(&self, value: T) {
         self.set_inner(value).await;
@@ -120,8 +142,24 @@ pub use once_cell;
 
 pub use rumcake_macros::keyboard_main as keyboard;
 
+pub mod accessibility;
+pub mod analog;
+pub mod combo;
+pub mod diagnostics;
+pub mod dynamic_macro;
+pub mod encoder;
+pub mod key_lock;
 pub mod keyboard;
+pub mod leader;
 mod math;
+#[cfg(feature = "usb")]
+pub mod os_detection;
+pub mod send_string;
+pub mod space_cadet;
+pub mod swap_hands;
+pub mod unicode;
+
+pub mod system_control;
 
 #[cfg(feature = "storage")]
 pub mod storage;
@@ -135,18 +173,60 @@ pub mod backlight;
 #[cfg(feature = "usb")]
 pub mod usb;
 
+#[cfg(feature = "mouse")]
+pub mod mouse;
+
+#[cfg(feature = "pointing")]
+pub mod pointing;
+
+#[cfg(feature = "gamepad")]
+pub mod gamepad;
+
+#[cfg(feature = "digitizer")]
+pub mod digitizer;
+
+#[cfg(feature = "midi")]
+pub mod midi;
+
+#[cfg(feature = "raw-hid")]
+pub mod raw_hid;
+
 #[cfg(feature = "via")]
 pub mod via;
 
 #[cfg(feature = "vial")]
 pub mod vial;
 
+#[cfg(feature = "xap")]
+pub mod xap;
+
+#[cfg(any(feature = "console", feature = "hid-console", feature = "nus-console"))]
+pub mod console;
+
+#[cfg(not(any(feature = "console", feature = "hid-console", feature = "nus-console")))]
+#[macro_export]
+/// Does nothing, because the `console`, `hid-console` and `nus-console` features are all disabled.
+macro_rules! console_debug {
+    ($($arg:tt)*) => {{
+        let _ = ::core::format_args!($($arg)*);
+    }};
+}
+
+#[cfg(feature = "lamp-array")]
+pub mod lamp_array;
+
 #[cfg(any(feature = "split-peripheral", feature = "split-central"))]
 pub mod split;
 
 #[cfg(feature = "bluetooth")]
 pub mod bluetooth;
 
+#[cfg(feature = "esb")]
+pub mod esb;
+
+#[cfg(feature = "dongle")]
+pub mod dongle;
+
 #[cfg(feature = "display")]
 pub mod display;
 
@@ -155,8 +235,30 @@ pub mod hw;
 pub mod drivers;
 
 pub mod tasks {
+    #[cfg(feature = "storage")]
+    pub use crate::accessibility::storage::__accessibility_config_storage_task;
+    #[cfg(feature = "storage")]
+    pub use crate::analog::storage::{
+        __analog_calibration_storage_task, __analog_key_configs_storage_task,
+    };
+    #[cfg(feature = "storage")]
+    pub use crate::dynamic_macro::storage::__dynamic_macros_storage_task;
+    pub use crate::encoder::__encoder_poll;
+    #[cfg(feature = "storage")]
+    pub use crate::encoder::storage::__encoder_configs_storage_task;
     pub use crate::hw::__output_switcher;
-    pub use crate::keyboard::{__layout_collect, __matrix_poll};
+    #[cfg(feature = "storage")]
+    pub use crate::hw::storage::__output_mode_storage_task;
+    #[cfg(feature = "storage")]
+    pub use crate::keyboard::storage::{
+        __default_layer_storage_task, __matrix_config_storage_task, __tap_hold_config_storage_task,
+    };
+    pub use crate::keyboard::{__extra_keys_poll, __layout_collect, __matrix_poll};
+    #[cfg(feature = "storage")]
+    pub use crate::unicode::storage::__unicode_mode_storage_task;
+
+    #[cfg(feature = "storage")]
+    pub use crate::storage::__storage_gc_task;
 
     #[cfg(feature = "simple-backlight")]
     pub use crate::backlight::simple_backlight::__simple_backlight_task;
@@ -182,12 +284,50 @@ pub mod tasks {
     pub use crate::display::__display_task;
 
     #[cfg(feature = "usb")]
-    pub use crate::usb::{__start_usb, __usb_hid_consumer_write_task, __usb_hid_kb_write_task};
-
-    #[cfg(all(feature = "via", feature = "usb"))]
-    pub use crate::usb::__usb_hid_via_read_task;
-    #[cfg(all(feature = "via", feature = "usb"))]
-    pub use crate::usb::__usb_hid_via_write_task;
+    pub use crate::os_detection::__os_detection_task;
+    #[cfg(feature = "usb")]
+    pub use crate::usb::{
+        __start_usb, __usb_hid_consumer_write_task, __usb_hid_kb_write_task,
+        __usb_hid_system_control_write_task,
+    };
+
+    #[cfg(feature = "mouse")]
+    pub use crate::mouse::__mouse_keys_task;
+    #[cfg(all(feature = "mouse", feature = "usb"))]
+    pub use crate::usb::__usb_hid_mouse_write_task;
+
+    #[cfg(feature = "pointing")]
+    pub use crate::pointing::__pointing_task;
+    #[cfg(all(feature = "pointing", feature = "storage"))]
+    pub use crate::pointing::storage::__pointing_config_storage_task;
+
+    #[cfg(feature = "gamepad")]
+    pub use crate::gamepad::__gamepad_task;
+    #[cfg(all(feature = "gamepad", feature = "usb"))]
+    pub use crate::usb::__usb_hid_gamepad_write_task;
+
+    #[cfg(all(feature = "digitizer", feature = "usb"))]
+    pub use crate::usb::__usb_hid_digitizer_write_task;
+
+    #[cfg(feature = "midi")]
+    pub use crate::usb::__usb_midi_write_task;
+
+    #[cfg(any(feature = "console", feature = "nus-console"))]
+    pub use crate::console::__console_task;
+    #[cfg(feature = "console")]
+    pub use crate::usb::__usb_console_task;
+    #[cfg(feature = "hid-console")]
+    pub use crate::usb::__usb_hid_console_write_task;
+
+    #[cfg(feature = "dfu")]
+    pub use crate::usb::__usb_dfu_detach_task;
+
+    #[cfg(feature = "raw-hid")]
+    pub use crate::raw_hid::__raw_hid_process_task;
+    #[cfg(all(feature = "raw-hid", feature = "usb"))]
+    pub use crate::usb::__usb_hid_raw_read_task;
+    #[cfg(all(feature = "raw-hid", feature = "usb"))]
+    pub use crate::usb::__usb_hid_raw_write_task;
     #[cfg(feature = "via")]
     pub use crate::via::__via_process_task;
     #[cfg(all(feature = "via", feature = "storage"))]
@@ -198,9 +338,19 @@ pub mod tasks {
     #[cfg(all(feature = "vial", feature = "storage"))]
     pub use crate::vial::storage::__vial_storage_task;
 
+    #[cfg(feature = "xap")]
+    pub use crate::usb::{__usb_hid_xap_read_task, __usb_hid_xap_write_task};
+    #[cfg(feature = "xap")]
+    pub use crate::xap::__xap_process_task;
+
+    #[cfg(feature = "lamp-array")]
+    pub use crate::lamp_array::__lamp_array_task;
+
     #[cfg(feature = "split-central")]
     pub use crate::split::central::__central_task;
 
+    #[cfg(all(feature = "split-peripheral", feature = "pointing"))]
+    pub use crate::split::peripheral::__peripheral_pointing_task;
     #[cfg(feature = "split-peripheral")]
     pub use crate::split::peripheral::__peripheral_task;
 
@@ -210,8 +360,33 @@ pub mod tasks {
     #[cfg(feature = "nrf-ble")]
     pub use crate::hw::mcu::__softdevice_task;
 
+    #[cfg(feature = "bluetooth")]
+    pub use crate::bluetooth::__bluetooth_status_led_task;
     #[cfg(all(feature = "nrf", feature = "bluetooth"))]
     pub use crate::bluetooth::nrf_ble::__nrf_ble_task;
+    #[cfg(all(feature = "nrf", feature = "bluetooth", feature = "storage"))]
+    pub use crate::bluetooth::nrf_ble::storage::__bluetooth_profiles_storage_task;
+    #[cfg(feature = "nfc-pairing")]
+    pub use crate::bluetooth::nrf_nfc::__nfc_pairing_task;
+    #[cfg(all(feature = "bluetooth", feature = "storage"))]
+    pub use crate::bluetooth::storage::__tx_power_storage_task;
+
+    #[cfg(all(feature = "nrf", feature = "esb"))]
+    pub use crate::esb::nrf_esb::{__esb_receiver_task, __esb_transmitter_task};
+
+    #[cfg(feature = "dongle")]
+    pub use crate::dongle::__dongle_task;
+    #[cfg(all(feature = "nrf", feature = "nrf-ble", feature = "dongle"))]
+    pub use crate::dongle::nrf_ble::__nrf_ble_dongle_task;
+    #[cfg(all(
+        feature = "nrf",
+        feature = "nrf-ble",
+        feature = "dongle",
+        feature = "storage"
+    ))]
+    pub use crate::dongle::nrf_ble::storage::__dongle_paired_keyboards_storage_task;
+    #[cfg(all(feature = "dongle", feature = "usb"))]
+    pub use crate::usb::__usb_hid_battery_write_task;
 
     #[cfg(all(feature = "nrf-ble", feature = "split-central"))]
     pub use crate::drivers::nrf_ble::central::__nrf_ble_central_task;
@@ -0,0 +1,172 @@
+//! MIDI features.
+//!
+//! MIDI messages can be sent using [`crate::keyboard::Keycode::Midi`] in your keyboard layout,
+//! or by sending a [`MidiMessage`] to [`MIDI_MESSAGE_SEND_CHANNEL`]. MIDI messages are sent to
+//! the host using a USB MIDI interface.
+
+use defmt::{debug, warn, Debug2Format};
+use embassy_sync::channel::Channel;
+
+use crate::hw::mcu::RawMutex;
+use crate::State;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// An enumeration of possible MIDI messages that can be sent to the host.
+pub enum MidiMessage {
+    /// Start playing a note. `channel` ranges from 0 to 15, `note` and `velocity` range from 0 to
+    /// 127.
+    NoteOn {
+        /// MIDI channel, from 0 to 15.
+        channel: u8,
+        /// Note number, from 0 to 127.
+        note: u8,
+        /// Velocity, from 0 to 127.
+        velocity: u8,
+    },
+    /// Stop playing a note.
+    NoteOff {
+        /// MIDI channel, from 0 to 15.
+        channel: u8,
+        /// Note number, from 0 to 127.
+        note: u8,
+        /// Velocity, from 0 to 127.
+        velocity: u8,
+    },
+    /// Change the value of a controller.
+    ControlChange {
+        /// MIDI channel, from 0 to 15.
+        channel: u8,
+        /// Controller number, from 0 to 127.
+        controller: u8,
+        /// Controller value, from 0 to 127.
+        value: u8,
+    },
+}
+
+impl MidiMessage {
+    /// Convert the message into a USB-MIDI event packet, using virtual cable 0.
+    pub fn to_usb_packet(&self) -> [u8; 4] {
+        // The first byte of a USB-MIDI event packet contains the cable number in the upper nibble,
+        // and a code index number (CIN) in the lower nibble. For channel voice messages, the CIN
+        // is the same as the upper nibble of the MIDI status byte.
+        let (status, data1, data2) = match *self {
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => (0x90 | (channel & 0x0F), note, velocity),
+            MidiMessage::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => (0x80 | (channel & 0x0F), note, velocity),
+            MidiMessage::ControlChange {
+                channel,
+                controller,
+                value,
+            } => (0xB0 | (channel & 0x0F), controller, value),
+        };
+
+        [status >> 4, status, data1 & 0x7F, data2 & 0x7F]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// An enumeration of possible MIDI actions that can be used in a keyboard layout.
+pub enum MidiKeycode {
+    /// Play a note (0 to 127) while the key is held, using the current [`MIDI_CHANNEL_STATE`] and
+    /// [`MIDI_VELOCITY_STATE`].
+    Note(u8),
+    /// Set a controller (0 to 127) to the given value while the key is held. The controller is set
+    /// back to 0 when the key is released. For example, `ControlChange(64, 127)` can be used as a
+    /// sustain pedal.
+    ControlChange(u8, u8),
+    /// Stop all notes on the current channel.
+    AllNotesOff,
+    /// Switch to the next MIDI channel.
+    ChannelUp,
+    /// Switch to the previous MIDI channel.
+    ChannelDown,
+    /// Increase the velocity used for notes.
+    VelocityUp,
+    /// Decrease the velocity used for notes.
+    VelocityDown,
+}
+
+/// Amount that [`MidiKeycode::VelocityUp`] and [`MidiKeycode::VelocityDown`] change the velocity
+/// by.
+const VELOCITY_STEP: u8 = 12;
+
+/// State that contains the MIDI channel (0 to 15) used by [`MidiKeycode`]s.
+pub static MIDI_CHANNEL_STATE: State<u8> = State::new(0, &[]);
+
+/// State that contains the velocity (0 to 127) used by [`MidiKeycode::Note`].
+pub static MIDI_VELOCITY_STATE: State<u8> = State::new(127, &[]);
+
+/// Channel for sending MIDI messages.
+///
+/// Channel messages should be consumed by the USB task, so user-level code should **not**
+/// attempt to receive messages from the channel, otherwise messages may not be processed
+/// appropriately. You should only send to this channel.
+pub static MIDI_MESSAGE_SEND_CHANNEL: Channel<RawMutex, MidiMessage, 4> = Channel::new();
+
+pub(crate) async fn process_midi_keycode(keycode: MidiKeycode, pressed: bool) {
+    let channel = MIDI_CHANNEL_STATE.get().await;
+
+    let message = match keycode {
+        MidiKeycode::Note(note) => {
+            let velocity = MIDI_VELOCITY_STATE.get().await;
+            if pressed {
+                MidiMessage::NoteOn {
+                    channel,
+                    note,
+                    velocity,
+                }
+            } else {
+                MidiMessage::NoteOff {
+                    channel,
+                    note,
+                    velocity,
+                }
+            }
+        }
+        MidiKeycode::ControlChange(controller, value) => MidiMessage::ControlChange {
+            channel,
+            controller,
+            value: if pressed { value } else { 0 },
+        },
+        MidiKeycode::AllNotesOff if pressed => MidiMessage::ControlChange {
+            channel,
+            controller: 123,
+            value: 0,
+        },
+        MidiKeycode::ChannelUp if pressed => {
+            MIDI_CHANNEL_STATE.set((channel % 16 + 1) % 16).await;
+            return;
+        }
+        MidiKeycode::ChannelDown if pressed => {
+            MIDI_CHANNEL_STATE.set((channel % 16 + 15) % 16).await;
+            return;
+        }
+        MidiKeycode::VelocityUp if pressed => {
+            MIDI_VELOCITY_STATE
+                .update(|velocity| **velocity = velocity.saturating_add(VELOCITY_STEP).min(127))
+                .await;
+            return;
+        }
+        MidiKeycode::VelocityDown if pressed => {
+            MIDI_VELOCITY_STATE
+                .update(|velocity| **velocity = velocity.saturating_sub(VELOCITY_STEP))
+                .await;
+            return;
+        }
+        _ => return,
+    };
+
+    // Use try_send so that the layout task doesn't get blocked if the host isn't reading from the
+    // MIDI interface.
+    debug!("[MIDI] Sending MIDI message: {:?}", Debug2Format(&message));
+    if MIDI_MESSAGE_SEND_CHANNEL.try_send(message).is_err() {
+        warn!("[MIDI] Discarding message");
+    }
+}
@@ -0,0 +1,442 @@
+//! Mouse features.
+//!
+//! To use mouse keys, keyboards must implement [`MouseKeysDevice`]. Mouse keys can be added to
+//! your keyboard layout using [`crate::keyboard::Keycode::Mouse`]. For pointing devices like
+//! trackballs, see the `pointing` module.
+
+use core::cell::Cell;
+
+use defmt::{debug, warn, Debug2Format};
+use embassy_futures::select::{select, Either};
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Ticker};
+use packed_struct::prelude::PackedStruct;
+
+use crate::hw::mcu::{BlockingMutex, RawMutex};
+use crate::hw::{HIDOutput, CURRENT_OUTPUT_STATE};
+
+/// A trait that keyboards must implement to use mouse keys.
+pub trait MouseKeysDevice {
+    /// Time between mouse reports while a movement or wheel key is held, in milliseconds.
+    const MOUSE_KEYS_INTERVAL_MS: u64 = 16;
+
+    /// Amount of cursor movement (in mouse units) per report when a movement key is first
+    /// pressed.
+    const MOUSE_KEYS_MOVE_DELTA: i8 = 4;
+
+    /// Maximum amount of cursor movement (in mouse units) per report. The cursor will accelerate
+    /// from [`MouseKeysDevice::MOUSE_KEYS_MOVE_DELTA`] up to this value while a movement key is
+    /// held.
+    const MOUSE_KEYS_MAX_SPEED: i8 = 20;
+
+    /// Number of reports it takes for the cursor to reach
+    /// [`MouseKeysDevice::MOUSE_KEYS_MAX_SPEED`].
+    const MOUSE_KEYS_TIME_TO_MAX: u16 = 30;
+
+    /// Number of reports to wait between each wheel step while a wheel key is held.
+    const MOUSE_KEYS_WHEEL_DELAY: u16 = 5;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// An enumeration of possible mouse actions that can be used in a keyboard layout.
+pub enum MouseKeycode {
+    /// Move the cursor up.
+    Up,
+    /// Move the cursor down.
+    Down,
+    /// Move the cursor left.
+    Left,
+    /// Move the cursor right.
+    Right,
+    /// Scroll the wheel up.
+    WheelUp,
+    /// Scroll the wheel down.
+    WheelDown,
+    /// Scroll the wheel left.
+    WheelLeft,
+    /// Scroll the wheel right.
+    WheelRight,
+    /// Press a mouse button. Buttons are numbered from 0 to 7, starting with the left button
+    /// (0), the right button (1), and the middle button (2).
+    Button(u8),
+}
+
+/// HID report descriptor for a mouse with 16-bit X and Y movement, and 8-bit vertical and
+/// horizontal wheels. Reports are described by [`MouseReport`].
+///
+/// Most mice only report movement from -127 to 127, which high-resolution sensors can exceed
+/// within a single report during fast movements. Using 16-bit fields avoids having to split that
+/// movement across multiple reports.
+#[rustfmt::skip]
+pub const MOUSE_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x02, // Usage (Mouse)
+    0xA1, 0x01, // Collection (Application)
+    0x09, 0x01, //   Usage (Pointer)
+    0xA1, 0x00, //   Collection (Physical)
+    0x05, 0x09, //     Usage Page (Button)
+    0x19, 0x01, //     Usage Minimum (1)
+    0x29, 0x08, //     Usage Maximum (8)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x75, 0x01, //     Report Size (1)
+    0x95, 0x08, //     Report Count (8)
+    0x81, 0x02, //     Input (Data, Variable, Absolute)
+    0x05, 0x01, //     Usage Page (Generic Desktop)
+    0x09, 0x30, //     Usage (X)
+    0x09, 0x31, //     Usage (Y)
+    0x16, 0x01, 0x80, // Logical Minimum (-32767)
+    0x26, 0xFF, 0x7F, // Logical Maximum (32767)
+    0x75, 0x10, //     Report Size (16)
+    0x95, 0x02, //     Report Count (2)
+    0x81, 0x06, //     Input (Data, Variable, Relative)
+    0x09, 0x38, //     Usage (Wheel)
+    0x15, 0x81, //     Logical Minimum (-127)
+    0x25, 0x7F, //     Logical Maximum (127)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x01, //     Report Count (1)
+    0x81, 0x06, //     Input (Data, Variable, Relative)
+    0x05, 0x0C, //     Usage Page (Consumer)
+    0x0A, 0x38, 0x02, // Usage (AC Pan)
+    0x15, 0x81, //     Logical Minimum (-127)
+    0x25, 0x7F, //     Logical Maximum (127)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x01, //     Report Count (1)
+    0x81, 0x06, //     Input (Data, Variable, Relative)
+    0xC0,       //   End Collection
+    0xC0,       // End Collection
+];
+
+/// A HID report for the mouse described by [`MOUSE_REPORT_DESCRIPTOR`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PackedStruct)]
+#[packed_struct(endian = "lsb", bit_numbering = "msb0")]
+pub struct MouseReport {
+    /// Bitmap of held buttons, starting with the left button in bit 0.
+    #[packed_field]
+    pub buttons: u8,
+    /// Movement along the X axis, from -32767 to 32767. Positive values move the cursor to the
+    /// right.
+    #[packed_field]
+    pub x: i16,
+    /// Movement along the Y axis, from -32767 to 32767. Positive values move the cursor down.
+    #[packed_field]
+    pub y: i16,
+    /// Vertical wheel movement, from -127 to 127. Positive values scroll up.
+    #[packed_field]
+    pub vertical_wheel: i8,
+    /// Horizontal wheel movement, from -127 to 127. Positive values scroll right.
+    #[packed_field]
+    pub horizontal_wheel: i8,
+}
+
+/// Number of high-resolution wheel units in each wheel detent, when the host has enabled
+/// high-resolution scrolling.
+pub const HI_RES_SCROLL_MULTIPLIER: u8 = 8;
+
+/// HID report descriptor for a mouse with high-resolution scrolling. Reports use the same format
+/// as [`MouseReport`], so this can be used as
+/// [`crate::usb::USBKeyboard::USB_MOUSE_REPORT_DESCRIPTOR`].
+///
+/// The vertical and horizontal wheels each have a resolution multiplier, which is a feature
+/// report that the host can set to enable high-resolution scrolling. Hosts that don't support it
+/// will leave the multipliers unset, and wheel values will be reported in whole detents.
+#[rustfmt::skip]
+pub const HI_RES_WHEEL_MOUSE_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x02, // Usage (Mouse)
+    0xA1, 0x01, // Collection (Application)
+    0x09, 0x01, //   Usage (Pointer)
+    0xA1, 0x00, //   Collection (Physical)
+    0x05, 0x09, //     Usage Page (Button)
+    0x19, 0x01, //     Usage Minimum (1)
+    0x29, 0x08, //     Usage Maximum (8)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x75, 0x01, //     Report Size (1)
+    0x95, 0x08, //     Report Count (8)
+    0x81, 0x02, //     Input (Data, Variable, Absolute)
+    0x05, 0x01, //     Usage Page (Generic Desktop)
+    0x09, 0x30, //     Usage (X)
+    0x09, 0x31, //     Usage (Y)
+    0x16, 0x01, 0x80, // Logical Minimum (-32767)
+    0x26, 0xFF, 0x7F, // Logical Maximum (32767)
+    0x75, 0x10, //     Report Size (16)
+    0x95, 0x02, //     Report Count (2)
+    0x81, 0x06, //     Input (Data, Variable, Relative)
+    0xA1, 0x02, //     Collection (Logical)
+    0x09, 0x48, //       Usage (Resolution Multiplier)
+    0x15, 0x00, //       Logical Minimum (0)
+    0x25, 0x01, //       Logical Maximum (1)
+    0x35, 0x01, //       Physical Minimum (1)
+    0x45, HI_RES_SCROLL_MULTIPLIER, // Physical Maximum
+    0x75, 0x02, //       Report Size (2)
+    0x95, 0x01, //       Report Count (1)
+    0xB1, 0x02, //       Feature (Data, Variable, Absolute)
+    0x35, 0x00, //       Physical Minimum (0)
+    0x45, 0x00, //       Physical Maximum (0)
+    0x09, 0x38, //       Usage (Wheel)
+    0x15, 0x81, //       Logical Minimum (-127)
+    0x25, 0x7F, //       Logical Maximum (127)
+    0x75, 0x08, //       Report Size (8)
+    0x95, 0x01, //       Report Count (1)
+    0x81, 0x06, //       Input (Data, Variable, Relative)
+    0xC0,       //     End Collection
+    0xA1, 0x02, //     Collection (Logical)
+    0x09, 0x48, //       Usage (Resolution Multiplier)
+    0x15, 0x00, //       Logical Minimum (0)
+    0x25, 0x01, //       Logical Maximum (1)
+    0x35, 0x01, //       Physical Minimum (1)
+    0x45, HI_RES_SCROLL_MULTIPLIER, // Physical Maximum
+    0x75, 0x02, //       Report Size (2)
+    0x95, 0x01, //       Report Count (1)
+    0xB1, 0x02, //       Feature (Data, Variable, Absolute)
+    0x35, 0x00, //       Physical Minimum (0)
+    0x45, 0x00, //       Physical Maximum (0)
+    0x05, 0x0C, //       Usage Page (Consumer)
+    0x0A, 0x38, 0x02, // Usage (AC Pan)
+    0x15, 0x81, //       Logical Minimum (-127)
+    0x25, 0x7F, //       Logical Maximum (127)
+    0x75, 0x08, //       Report Size (8)
+    0x95, 0x01, //       Report Count (1)
+    0x81, 0x06, //       Input (Data, Variable, Relative)
+    0xC0,       //     End Collection
+    0x75, 0x04, //     Report Size (4)
+    0x95, 0x01, //     Report Count (1)
+    0xB1, 0x03, //     Feature (Constant), padding for the resolution multipliers
+    0xC0,       //   End Collection
+    0xC0,       // End Collection
+];
+
+/// Feature report containing the resolution multipliers set by the host. Bits 0-1 contain the
+/// vertical wheel's multiplier, and bits 2-3 contain the horizontal wheel's multiplier.
+static RESOLUTION_MULTIPLIER_REPORT: BlockingMutex<Cell<u8>> = BlockingMutex::new(Cell::new(0));
+
+/// Get the number of wheel units that should be reported for each wheel detent, for the vertical
+/// and horizontal wheels respectively. This is [`HI_RES_SCROLL_MULTIPLIER`] if the host has
+/// enabled high-resolution scrolling for that wheel, or 1 otherwise.
+pub fn resolution_multiplier() -> (u8, u8) {
+    let report = RESOLUTION_MULTIPLIER_REPORT.lock(Cell::get);
+    let multiplier = |bits: u8| {
+        if bits & 0b11 != 0 {
+            HI_RES_SCROLL_MULTIPLIER
+        } else {
+            1
+        }
+    };
+    (multiplier(report), multiplier(report >> 2))
+}
+
+pub(crate) fn get_resolution_multiplier_report() -> u8 {
+    RESOLUTION_MULTIPLIER_REPORT.lock(Cell::get)
+}
+
+pub(crate) fn set_resolution_multiplier_report(report: u8) {
+    debug!("[MOUSE] Host set resolution multipliers: {:02X}", report);
+    RESOLUTION_MULTIPLIER_REPORT.lock(|multiplier| multiplier.set(report & 0b1111));
+}
+
+/// Channel for sending mouse HID reports.
+///
+/// Channel messages should be consumed by the USB task, so user-level code should **not**
+/// attempt to receive messages from the channel, otherwise commands may not be processed
+/// appropriately. You should only send to this channel.
+pub static MOUSE_REPORT_HID_SEND_CHANNEL: Channel<RawMutex, MouseReport, 1> = Channel::new();
+
+/// Channel for mouse key presses and releases. This is sent to by the layout task, and consumed
+/// by the [`mouse_keys_task`].
+pub(crate) static MOUSE_KEYS_EVENT_CHANNEL: Channel<RawMutex, (MouseKeycode, bool), 4> =
+    Channel::new();
+
+/// Sources of mouse button presses.
+#[derive(Clone, Copy)]
+pub(crate) enum ButtonSource {
+    MouseKeys,
+    #[cfg(feature = "pointing")]
+    Pointing,
+}
+
+/// Mouse buttons that are currently held by each [`ButtonSource`]. Each mouse report contains the
+/// buttons held by every source, so that reports sent for one source don't release the buttons
+/// held by another.
+static HELD_BUTTONS: BlockingMutex<Cell<[u8; 2]>> = BlockingMutex::new(Cell::new([0; 2]));
+
+/// Update the mouse buttons held by `source`, returning the buttons that are held by all sources,
+/// as a bitmask.
+pub(crate) fn update_held_buttons(source: ButtonSource, buttons: u8) -> u8 {
+    HELD_BUTTONS.lock(|held| {
+        let mut sources = held.get();
+        sources[source as usize] = buttons;
+        held.set(sources);
+        sources.iter().fold(0, |all, buttons| all | buttons)
+    })
+}
+
+/// Obtain the mouse buttons that are held by all sources, as a bitmask.
+#[cfg(feature = "pointing")]
+pub(crate) fn held_buttons() -> u8 {
+    HELD_BUTTONS.lock(|held| held.get().iter().fold(0, |all, buttons| all | buttons))
+}
+
+#[derive(Default)]
+struct MouseKeysState {
+    buttons: u8,
+    up: bool,
+    down: bool,
+    left: bool,
+    right: bool,
+    wheel_up: bool,
+    wheel_down: bool,
+    wheel_left: bool,
+    wheel_right: bool,
+}
+
+impl MouseKeysState {
+    fn update(&mut self, keycode: MouseKeycode, pressed: bool) {
+        match keycode {
+            MouseKeycode::Up => self.up = pressed,
+            MouseKeycode::Down => self.down = pressed,
+            MouseKeycode::Left => self.left = pressed,
+            MouseKeycode::Right => self.right = pressed,
+            MouseKeycode::WheelUp => self.wheel_up = pressed,
+            MouseKeycode::WheelDown => self.wheel_down = pressed,
+            MouseKeycode::WheelLeft => self.wheel_left = pressed,
+            MouseKeycode::WheelRight => self.wheel_right = pressed,
+            MouseKeycode::Button(button) => {
+                if button > 7 {
+                    warn!("[MOUSE] Ignoring invalid mouse button: {}", button);
+                    return;
+                }
+
+                if pressed {
+                    self.buttons |= 1 << button;
+                } else {
+                    self.buttons &= !(1 << button);
+                }
+            }
+        }
+    }
+
+    fn is_moving(&self) -> bool {
+        self.up || self.down || self.left || self.right
+    }
+
+    fn is_scrolling(&self) -> bool {
+        self.wheel_up || self.wheel_down || self.wheel_left || self.wheel_right
+    }
+}
+
+fn axis(negative: bool, positive: bool) -> i8 {
+    positive as i8 - negative as i8
+}
+
+/// Add one report's worth of scrolling to `remainder`, returning the wheel value that should be
+/// reported. Wheel keys scroll by `multiplier` units every `delay` reports, so with a multiplier
+/// above 1, the wheel scrolls by a fraction of a detent in each report.
+fn wheel_step(remainder: &mut i16, direction: i8, multiplier: u8, delay: u16) -> i8 {
+    let delay = delay.max(1) as i16;
+    *remainder += direction as i16 * multiplier as i16;
+    let value = *remainder / delay;
+    *remainder -= value * delay;
+    value as i8
+}
+
+pub(crate) async fn send_mouse_report(report: MouseReport) {
+    // Mouse reports are currently only supported over USB. If USB is not the current output, the
+    // channel can become filled, so we discard the report in that case.
+    if matches!(CURRENT_OUTPUT_STATE.get().await, Some(HIDOutput::Usb)) {
+        debug!("[MOUSE] Sending mouse report: {:?}", Debug2Format(&report));
+        MOUSE_REPORT_HID_SEND_CHANNEL.send(report).await;
+    } else {
+        warn!("[MOUSE] Discarding report");
+    }
+}
+
+#[rumcake_macros::task]
+pub async fn mouse_keys_task<K: MouseKeysDevice>(_k: K) {
+    let mut state = MouseKeysState::default();
+
+    // Number of reports sent since the mouse keys started moving or scrolling
+    let mut ticks: u16 = 0;
+
+    // Scrolling that hasn't been reported yet, for the vertical and horizontal wheels
+    let mut wheel_remainder: (i16, i16) = (0, 0);
+
+    let mut ticker = Ticker::every(Duration::from_millis(K::MOUSE_KEYS_INTERVAL_MS));
+
+    loop {
+        let event = if state.is_moving() || state.is_scrolling() {
+            match select(ticker.next(), MOUSE_KEYS_EVENT_CHANNEL.receive()).await {
+                Either::First(()) => None,
+                Either::Second(event) => Some(event),
+            }
+        } else {
+            let event = MOUSE_KEYS_EVENT_CHANNEL.receive().await;
+            ticks = 0;
+            ticker.reset();
+            Some(event)
+        };
+
+        let mut report = MouseReport::default();
+
+        match event {
+            Some((keycode, pressed)) => {
+                let buttons = state.buttons;
+                state.update(keycode, pressed);
+
+                // Movement reports are sent on the next tick. Button changes, and the first step
+                // of a wheel key are sent immediately, so that a wheel key scrolls even if it is
+                // released before the next tick (e.g. when it is tapped by an encoder).
+                let (vertical, horizontal) = match keycode {
+                    MouseKeycode::WheelUp if pressed => (1, 0),
+                    MouseKeycode::WheelDown if pressed => (-1, 0),
+                    MouseKeycode::WheelLeft if pressed => (0, -1),
+                    MouseKeycode::WheelRight if pressed => (0, 1),
+                    _ => (0, 0),
+                };
+
+                if vertical != 0 || horizontal != 0 {
+                    let (vertical_multiplier, horizontal_multiplier) = resolution_multiplier();
+                    report.vertical_wheel = vertical * vertical_multiplier as i8;
+                    report.horizontal_wheel = horizontal * horizontal_multiplier as i8;
+                    wheel_remainder = (0, 0);
+                } else if buttons == state.buttons {
+                    continue;
+                }
+            }
+            None => {
+                ticks = ticks.saturating_add(1);
+
+                if state.is_moving() {
+                    let progress = ticks.min(K::MOUSE_KEYS_TIME_TO_MAX) as i32;
+                    let speed = K::MOUSE_KEYS_MOVE_DELTA as i32
+                        + (K::MOUSE_KEYS_MAX_SPEED as i32 - K::MOUSE_KEYS_MOVE_DELTA as i32)
+                            * progress
+                            / K::MOUSE_KEYS_TIME_TO_MAX.max(1) as i32;
+                    let speed = speed as i16;
+
+                    report.x = axis(state.left, state.right) as i16 * speed;
+                    report.y = axis(state.up, state.down) as i16 * speed;
+                }
+
+                if state.is_scrolling() {
+                    let (vertical_multiplier, horizontal_multiplier) = resolution_multiplier();
+                    report.vertical_wheel = wheel_step(
+                        &mut wheel_remainder.0,
+                        axis(state.wheel_down, state.wheel_up),
+                        vertical_multiplier,
+                        K::MOUSE_KEYS_WHEEL_DELAY,
+                    );
+                    report.horizontal_wheel = wheel_step(
+                        &mut wheel_remainder.1,
+                        axis(state.wheel_left, state.wheel_right),
+                        horizontal_multiplier,
+                        K::MOUSE_KEYS_WHEEL_DELAY,
+                    );
+                }
+            }
+        }
+
+        report.buttons = update_held_buttons(ButtonSource::MouseKeys, state.buttons);
+        send_mouse_report(report).await;
+    }
+}
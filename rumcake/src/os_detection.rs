@@ -0,0 +1,254 @@
+//! Host OS detection, which guesses the OS of the USB host from the way that it enumerates the
+//! keyboard.
+//!
+//! Each OS requests the keyboard's USB string descriptors a different number of times, with
+//! different lengths. The USB driver returned by `setup_usb_driver` records these requests, and
+//! once the host has stopped making them, the guessed OS is stored in [`HOST_OS_STATE`]. Other
+//! features can use this state to adapt to the host. For example, see
+//! [`USBKeyboard::UNICODE_MODE_FROM_HOST_OS`].
+//!
+//! Detection is not always accurate, especially when the keyboard is connected through a hub or
+//! a KVM switch.
+//!
+//! [`USBKeyboard::UNICODE_MODE_FROM_HOST_OS`]: crate::usb::USBKeyboard::UNICODE_MODE_FROM_HOST_OS
+
+use core::cell::Cell;
+
+use defmt::{info, warn, Debug2Format};
+use embassy_futures::select::{select, Either};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use embassy_usb::driver::{ControlPipe, Driver, EndpointAllocError, EndpointError, EndpointType};
+
+use crate::hw::mcu::{BlockingMutex, RawMutex};
+use crate::unicode::{UnicodeMode, UNICODE_MODE_STATE};
+use crate::usb::USBKeyboard;
+use crate::State;
+
+/// Operating systems that can be detected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostOs {
+    /// The host has not been detected yet, or its OS could not be guessed.
+    Unknown,
+    /// Linux, including Android and ChromeOS.
+    Linux,
+    /// Windows.
+    Windows,
+    /// macOS.
+    MacOS,
+    /// iOS and iPadOS.
+    IOS,
+}
+
+/// State that contains the detected OS of the USB host. This is [`HostOs::Unknown`] until a host
+/// has enumerated the keyboard.
+pub static HOST_OS_STATE: State<HostOs> = State::new(HostOs::Unknown, &[]);
+
+/// Time to wait after the last string descriptor request before guessing the host's OS.
+const OS_DETECTION_DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// Counts of the string descriptor requests made by the host, grouped by their `wLength`.
+#[derive(Debug, Clone, Copy)]
+struct SetupCounts {
+    total: u8,
+    len_02: u8,
+    len_04: u8,
+    len_ff: u8,
+    last_len: u16,
+}
+
+impl SetupCounts {
+    const EMPTY: Self = Self {
+        total: 0,
+        len_02: 0,
+        len_04: 0,
+        len_ff: 0,
+        last_len: 0,
+    };
+
+    fn record(&mut self, len: u16) {
+        self.total = self.total.saturating_add(1);
+        self.last_len = len;
+        match len {
+            0x02 => self.len_02 = self.len_02.saturating_add(1),
+            0x04 => self.len_04 = self.len_04.saturating_add(1),
+            0xFF => self.len_ff = self.len_ff.saturating_add(1),
+            _ => {}
+        }
+    }
+
+    /// Guess the host OS. These fingerprints are the same as the ones used by QMK.
+    fn guess(&self) -> HostOs {
+        if self.total < 3 {
+            HostOs::Unknown
+        } else if self.len_ff >= 2 && self.len_04 >= 1 {
+            HostOs::Windows
+        } else if self.total == self.len_ff {
+            HostOs::Linux
+        } else if self.total == 5 && self.last_len == 0xFF && self.len_ff == 1 && self.len_02 == 2 {
+            HostOs::MacOS
+        } else if self.total == 4 && self.len_ff == 0 && self.len_02 == 2 {
+            HostOs::IOS
+        } else if (self.len_ff == 0 && self.len_02 == 3 && self.len_04 == 1)
+            || (self.len_ff >= 1 && self.len_02 == 0 && self.len_04 == 0)
+        {
+            // Game consoles (PS5, Nintendo Switch) and VR headsets, which all run Linux or BSD
+            HostOs::Linux
+        } else {
+            HostOs::Unknown
+        }
+    }
+}
+
+static SETUP_COUNTS: BlockingMutex<Cell<SetupCounts>> =
+    BlockingMutex::new(Cell::new(SetupCounts::EMPTY));
+
+/// Signal used to notify the OS detection task that the host has made a string descriptor
+/// request.
+static SETUP_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+/// Forget the requests made by the previous host. This is called when the USB cable is
+/// disconnected.
+pub(crate) fn reset() {
+    SETUP_COUNTS.lock(|counts| counts.set(SetupCounts::EMPTY));
+    if !HOST_OS_STATE.try_set(HostOs::Unknown) {
+        warn!("[OS_DETECTION] Could not reset the host OS state");
+    }
+}
+
+const REQUEST_TYPE_DEVICE_TO_HOST: u8 = 0x80;
+const REQUEST_GET_DESCRIPTOR: u8 = 0x06;
+const DESCRIPTOR_TYPE_STRING: u8 = 0x03;
+
+/// A USB driver that records the string descriptor requests made by the host, which are used to
+/// detect the host's OS. All other functionality is provided by the wrapped driver.
+pub struct OsDetectionDriver<D> {
+    driver: D,
+}
+
+impl<D> OsDetectionDriver<D> {
+    pub fn new(driver: D) -> Self {
+        Self { driver }
+    }
+}
+
+impl<'d, D: Driver<'d>> Driver<'d> for OsDetectionDriver<D> {
+    type EndpointOut = D::EndpointOut;
+    type EndpointIn = D::EndpointIn;
+    type ControlPipe = OsDetectionControlPipe<D::ControlPipe>;
+    type Bus = D::Bus;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, EndpointAllocError> {
+        self.driver
+            .alloc_endpoint_out(ep_type, max_packet_size, interval_ms)
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, EndpointAllocError> {
+        self.driver
+            .alloc_endpoint_in(ep_type, max_packet_size, interval_ms)
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
+        let (bus, control) = self.driver.start(control_max_packet_size);
+        (bus, OsDetectionControlPipe { control })
+    }
+}
+
+/// Control pipe used by [`OsDetectionDriver`].
+pub struct OsDetectionControlPipe<C> {
+    control: C,
+}
+
+impl<C: ControlPipe> ControlPipe for OsDetectionControlPipe<C> {
+    fn max_packet_size(&self) -> usize {
+        self.control.max_packet_size()
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        let setup = self.control.setup().await;
+
+        // bmRequestType, bRequest, wValue (descriptor index, descriptor type), wIndex, wLength
+        if let [REQUEST_TYPE_DEVICE_TO_HOST, REQUEST_GET_DESCRIPTOR, _, DESCRIPTOR_TYPE_STRING, _, _, len_lo, len_hi] =
+            setup
+        {
+            let len = u16::from_le_bytes([len_lo, len_hi]);
+            SETUP_COUNTS.lock(|counts| {
+                let mut updated = counts.get();
+                updated.record(len);
+                counts.set(updated);
+            });
+            SETUP_SIGNAL.signal(());
+        }
+
+        setup
+    }
+
+    async fn data_out(
+        &mut self,
+        buf: &mut [u8],
+        first: bool,
+        last: bool,
+    ) -> Result<usize, EndpointError> {
+        self.control.data_out(buf, first, last).await
+    }
+
+    async fn data_in(&mut self, data: &[u8], first: bool, last: bool) -> Result<(), EndpointError> {
+        self.control.data_in(data, first, last).await
+    }
+
+    async fn accept(&mut self) {
+        self.control.accept().await
+    }
+
+    async fn reject(&mut self) {
+        self.control.reject().await
+    }
+
+    async fn accept_set_address(&mut self, addr: u8) {
+        self.control.accept_set_address(addr).await
+    }
+}
+
+/// Task that guesses the host's OS once it has finished making string descriptor requests.
+#[rumcake_macros::task]
+pub async fn os_detection_task<K: USBKeyboard + 'static>(_k: K) {
+    loop {
+        SETUP_SIGNAL.wait().await;
+
+        // Wait until the host stops making requests
+        while let Either::First(()) =
+            select(SETUP_SIGNAL.wait(), Timer::after(OS_DETECTION_DEBOUNCE)).await
+        {}
+
+        let counts = SETUP_COUNTS.lock(Cell::get);
+        let os = counts.guess();
+        info!(
+            "[OS_DETECTION] Detected host OS: {:?} ({:?})",
+            Debug2Format(&os),
+            Debug2Format(&counts)
+        );
+        HOST_OS_STATE.set(os).await;
+
+        if K::UNICODE_MODE_FROM_HOST_OS {
+            let mode = match os {
+                HostOs::Linux => Some(UnicodeMode::Linux),
+                HostOs::Windows => Some(UnicodeMode::WinCompose),
+                HostOs::MacOS | HostOs::IOS => Some(UnicodeMode::MacOS),
+                HostOs::Unknown => None,
+            };
+            if let Some(mode) = mode {
+                UNICODE_MODE_STATE.set(mode).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,645 @@
+//! Pointing device feature.
+//!
+//! To use a pointing device (e.g. the optical sensor of a trackball), keyboards must implement
+//! [`PointingKeyboard`], and provide a driver that implements [`PointingDevice`]. You can implement
+//! [`PointingDevice`] yourself to use any other sensor, and combine multiple devices into one.
+//! Movement is sent to the host using the mouse HID interface, so it works alongside mouse keys
+//! (see [`crate::mouse`]).
+//!
+//! The resolution and acceleration of the pointing device can be changed at runtime using
+//! [`crate::keyboard::Keycode::Pointing`], or by setting [`POINTING_CONFIG_STATE`]. The same
+//! keycodes can be used to turn movement into scrolling (drag scroll), or to temporarily lower the
+//! resolution for precise movements (sniping).
+//!
+//! A layer can also be activated automatically while the pointing device is in use (see
+//! [`PointingKeyboard::POINTING_AUTO_MOUSE_LAYER`]), so that mouse buttons can be placed under
+//! your fingers.
+
+use defmt::{debug, info, Debug2Format};
+use embassy_futures::join::join;
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Ticker};
+use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
+
+use crate::hw::mcu::RawMutex;
+use crate::mouse::{
+    held_buttons, resolution_multiplier, send_mouse_report, update_held_buttons, ButtonSource,
+    MouseReport,
+};
+use crate::State;
+
+/// A trait that keyboards must implement to use a pointing device.
+pub trait PointingKeyboard {
+    /// Time between each poll of the pointing device, in milliseconds.
+    const POINTING_POLL_INTERVAL_MS: u64 = 1;
+
+    /// Resolution of the pointing device, in counts per inch (CPI). This is used if the resolution
+    /// hasn't been changed using [`PointingCommand`]s.
+    const POINTING_DEFAULT_CPI: u16 = 1600;
+
+    /// Amount that the resolution changes by for each [`PointingCommand::IncreaseCpi`] or
+    /// [`PointingCommand::DecreaseCpi`].
+    const POINTING_CPI_STEP: u16 = 400;
+
+    /// Lowest resolution that can be set using [`PointingCommand`]s.
+    const POINTING_MIN_CPI: u16 = 400;
+
+    /// Highest resolution that can be set using [`PointingCommand`]s.
+    const POINTING_MAX_CPI: u16 = 3200;
+
+    /// Swap the X and Y axes of the pointing device. This is applied before
+    /// [`PointingKeyboard::POINTING_INVERT_X`] and [`PointingKeyboard::POINTING_INVERT_Y`], so
+    /// together, they can be used to rotate the sensor by any multiple of 90 degrees.
+    const POINTING_SWAP_XY: bool = false;
+
+    /// Invert movement along the X axis.
+    const POINTING_INVERT_X: bool = false;
+
+    /// Invert movement along the Y axis.
+    const POINTING_INVERT_Y: bool = false;
+
+    /// Amount of movement along the X axis (in counts) for each unit of cursor movement that is
+    /// reported to the host. This can be used to slow down sensors that can't be set to a low
+    /// enough resolution. Fractions of a unit are kept until the next report, so slow movements
+    /// aren't lost.
+    const POINTING_DIVISOR_X: u16 = 1;
+
+    /// Amount of movement along the Y axis (in counts) for each unit of cursor movement that is
+    /// reported to the host. See [`PointingKeyboard::POINTING_DIVISOR_X`].
+    const POINTING_DIVISOR_Y: u16 = 1;
+
+    /// Resolution of the pointing device while sniping, in counts per inch. This is used if the
+    /// sniping resolution hasn't been changed in [`POINTING_CONFIG_STATE`].
+    const POINTING_SNIPING_CPI: u16 = 200;
+
+    /// Amount of movement (in counts) needed to scroll by one wheel detent while drag scroll is
+    /// active. Higher values make drag scroll slower.
+    const POINTING_DRAG_SCROLL_DIVISOR: u16 = 16;
+
+    /// Acceleration curve used if it hasn't been changed using [`PointingCommand`]s.
+    const POINTING_DEFAULT_ACCELERATION: PointingAcceleration = PointingAcceleration::Off;
+
+    /// Speed of the pointing device, in counts per millisecond, at which acceleration doubles the
+    /// movement of the cursor.
+    const POINTING_ACCELERATION_SPEED: u16 = 8;
+
+    /// Maximum factor that acceleration can multiply the movement of the cursor by.
+    const POINTING_MAX_ACCELERATION: u8 = 4;
+
+    /// Layer that is activated automatically when the pointing device moves, or `None` to disable
+    /// the automatic mouse layer. The layer stays active while the pointing device is moving, or
+    /// while mouse buttons are held, and is deactivated after
+    /// [`PointingKeyboard::POINTING_AUTO_MOUSE_TIMEOUT_MS`] of inactivity.
+    const POINTING_AUTO_MOUSE_LAYER: Option<u8> = None;
+
+    /// Time after the pointing device stops moving, in milliseconds, before the automatic mouse
+    /// layer is deactivated.
+    const POINTING_AUTO_MOUSE_TIMEOUT_MS: u32 = 650;
+
+    /// Amount of movement, in counts, needed to activate the automatic mouse layer. This prevents
+    /// the layer from being activated when the pointing device is bumped while typing.
+    const POINTING_AUTO_MOUSE_THRESHOLD: u16 = 8;
+}
+
+/// Movement and buttons reported by a [`PointingDevice`] each time it is polled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct PointingReport {
+    /// Movement along the X axis, in counts. Positive values move the cursor to the right.
+    pub x: i16,
+    /// Movement along the Y axis, in counts. Positive values move the cursor down.
+    pub y: i16,
+    /// Mouse buttons that are currently held on the device, as a bitmask. Bit 0 is the left
+    /// button, bit 1 is the right button, and bit 2 is the middle button.
+    pub buttons: u8,
+    /// Movement of the vertical wheel, in detents. Positive values scroll up.
+    pub vertical_wheel: i8,
+    /// Movement of the horizontal wheel, in detents. Positive values scroll right.
+    pub horizontal_wheel: i8,
+}
+
+impl PointingReport {
+    /// Combine this report with a report from another device. Movement from both reports is
+    /// added together, and buttons that are held on either device are held in the result.
+    pub fn merge(self, other: Self) -> Self {
+        Self {
+            x: self.x.saturating_add(other.x),
+            y: self.y.saturating_add(other.y),
+            buttons: self.buttons | other.buttons,
+            vertical_wheel: self.vertical_wheel.saturating_add(other.vertical_wheel),
+            horizontal_wheel: self.horizontal_wheel.saturating_add(other.horizontal_wheel),
+        }
+    }
+
+    /// Combine this report with a newer report from the same device. Movement from both reports
+    /// is added together, and the buttons from the newer report are used.
+    #[cfg(any(feature = "split-central", feature = "split-peripheral"))]
+    pub(crate) fn accumulate(self, newer: Self) -> Self {
+        Self {
+            buttons: newer.buttons,
+            ..self.merge(newer)
+        }
+    }
+}
+
+/// Trait that pointing devices must implement to work with the [`pointing_task`].
+///
+/// This is already implemented for arrays of types that implement [`PointingDevice`], and for
+/// pairs of pointing devices, which can be used to combine different types of devices. The
+/// reports from each device are combined using [`PointingReport::merge`].
+pub trait PointingDevice {
+    /// Set up the pointing device. This is called once, before the device is first polled.
+    async fn init(&mut self) {}
+
+    /// Poll the pointing device, returning the amount that it has moved since it was last polled,
+    /// and the buttons that are currently held on it.
+    async fn poll(&mut self) -> PointingReport;
+
+    /// Change the resolution of the pointing device, in counts per inch. Devices that don't
+    /// support changing their resolution can ignore this.
+    async fn set_cpi(&mut self, _cpi: u16) {}
+}
+
+impl<D: PointingDevice, const N: usize> PointingDevice for [D; N] {
+    async fn init(&mut self) {
+        for device in self.iter_mut() {
+            device.init().await;
+        }
+    }
+
+    async fn poll(&mut self) -> PointingReport {
+        let mut report = PointingReport::default();
+        for device in self.iter_mut() {
+            report = report.merge(device.poll().await);
+        }
+        report
+    }
+
+    async fn set_cpi(&mut self, cpi: u16) {
+        for device in self.iter_mut() {
+            device.set_cpi(cpi).await;
+        }
+    }
+}
+
+impl<A: PointingDevice, B: PointingDevice> PointingDevice for (A, B) {
+    async fn init(&mut self) {
+        join(self.0.init(), self.1.init()).await;
+    }
+
+    async fn poll(&mut self) -> PointingReport {
+        let (a, b) = join(self.0.poll(), self.1.poll()).await;
+        a.merge(b)
+    }
+
+    async fn set_cpi(&mut self, cpi: u16) {
+        join(self.0.set_cpi(cpi), self.1.set_cpi(cpi)).await;
+    }
+}
+
+/// Acceleration curves, which make the cursor move further when the pointing device moves
+/// quickly. The movement of the cursor is multiplied by a factor that depends on the speed of the
+/// pointing device, up to [`PointingKeyboard::POINTING_MAX_ACCELERATION`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PointingAcceleration {
+    /// No acceleration. The cursor moves the same distance regardless of speed.
+    Off,
+    /// The factor increases linearly with speed, reaching 2 at
+    /// [`PointingKeyboard::POINTING_ACCELERATION_SPEED`].
+    Linear,
+    /// The factor increases with the square of the speed, reaching 2 at
+    /// [`PointingKeyboard::POINTING_ACCELERATION_SPEED`]. Slow movements stay precise, while fast
+    /// movements are accelerated more.
+    Quadratic,
+}
+
+impl PointingAcceleration {
+    fn next(self) -> Self {
+        match self {
+            PointingAcceleration::Off => PointingAcceleration::Linear,
+            PointingAcceleration::Linear => PointingAcceleration::Quadratic,
+            PointingAcceleration::Quadratic => PointingAcceleration::Off,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// An enumeration of possible commands used to change the settings in [`POINTING_CONFIG_STATE`].
+pub enum PointingCommand {
+    /// Increase the resolution by [`PointingKeyboard::POINTING_CPI_STEP`], up to
+    /// [`PointingKeyboard::POINTING_MAX_CPI`].
+    IncreaseCpi,
+    /// Decrease the resolution by [`PointingKeyboard::POINTING_CPI_STEP`], down to
+    /// [`PointingKeyboard::POINTING_MIN_CPI`].
+    DecreaseCpi,
+    /// Increase the resolution by [`PointingKeyboard::POINTING_CPI_STEP`], wrapping around to
+    /// [`PointingKeyboard::POINTING_MIN_CPI`] after reaching
+    /// [`PointingKeyboard::POINTING_MAX_CPI`].
+    CycleCpi,
+    /// Restore the resolution to [`PointingKeyboard::POINTING_DEFAULT_CPI`].
+    ResetCpi,
+    /// Turn movement into scrolling while the key is held.
+    DragScroll,
+    /// Toggle drag scroll, which turns movement into scrolling.
+    ToggleDragScroll,
+    /// Use the sniping resolution while the key is held.
+    Sniping,
+    /// Toggle sniping, which uses a lower resolution for precise movements.
+    ToggleSniping,
+    /// Switch to the next [`PointingAcceleration`] curve.
+    CycleAcceleration,
+    /// Use the given [`PointingAcceleration`] curve.
+    SetAcceleration(PointingAcceleration),
+}
+
+/// Settings used by the pointing device. Settings that are set to `None` use the values from your
+/// [`PointingKeyboard`] implementation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PointingConfig {
+    /// Resolution setting, used instead of [`PointingKeyboard::POINTING_DEFAULT_CPI`].
+    pub cpi: Option<u16>,
+    /// Sniping resolution setting, used instead of [`PointingKeyboard::POINTING_SNIPING_CPI`].
+    pub sniping_cpi: Option<u16>,
+    /// Whether sniping is toggled on.
+    pub sniping: bool,
+    /// Whether drag scroll is toggled on.
+    pub drag_scroll: bool,
+    /// Acceleration setting, used instead of
+    /// [`PointingKeyboard::POINTING_DEFAULT_ACCELERATION`].
+    pub acceleration: Option<PointingAcceleration>,
+}
+
+impl PointingConfig {
+    /// A config that uses the settings from your [`PointingKeyboard`] implementation.
+    pub const DEFAULT: Self = Self {
+        cpi: None,
+        sniping_cpi: None,
+        sniping: false,
+        drag_scroll: false,
+        acceleration: None,
+    };
+
+    /// Obtain the resolution that the pointing device should use. `sniping` should be `true` if
+    /// a [`PointingCommand::Sniping`] key is held.
+    fn active_cpi<K: PointingKeyboard>(&self, sniping: bool) -> u16 {
+        if self.sniping || sniping {
+            self.sniping_cpi.unwrap_or(K::POINTING_SNIPING_CPI)
+        } else {
+            self.cpi.unwrap_or(K::POINTING_DEFAULT_CPI)
+        }
+    }
+}
+
+#[cfg(feature = "storage")]
+impl crate::storage::StoredData for PointingConfig {
+    const SCHEMA_VERSION: u16 = 2;
+}
+
+pub(crate) static POINTING_CONFIG_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+/// State that contains the settings used by the pointing device. Changes to this state are applied
+/// to the pointing device immediately. If you specified a storage driver, and enabled the
+/// `storage` feature, the settings are saved, and restored when your keyboard restarts.
+pub static POINTING_CONFIG_STATE: State<PointingConfig> = State::new(
+    PointingConfig::DEFAULT,
+    &[
+        &POINTING_CONFIG_STATE_LISTENER,
+        #[cfg(feature = "storage")]
+        &storage::POINTING_CONFIG_STATE_STORAGE_LISTENER,
+    ],
+);
+
+/// Channel for pointing commands, and whether the key that sent the command was pressed or
+/// released. This is sent to by the layout task, and consumed by the [`pointing_task`].
+pub(crate) static POINTING_COMMAND_CHANNEL: Channel<RawMutex, (PointingCommand, bool), 4> =
+    Channel::new();
+
+/// Signal used to activate (`Some(layer)`) or deactivate (`None`) the automatic mouse layer. This
+/// is sent to by the [`pointing_task`], and consumed by the layout task.
+pub(crate) static AUTO_MOUSE_LAYER_SIGNAL: Signal<RawMutex, Option<u8>> = Signal::new();
+
+/// Activates [`PointingKeyboard::POINTING_AUTO_MOUSE_LAYER`] when the pointing device moves, and
+/// deactivates it once the pointing device has been inactive for long enough.
+struct AutoMouseLayer {
+    active: bool,
+    /// Movement since the pointing device was last inactive, in counts.
+    motion: u32,
+    last_activity: Instant,
+}
+
+impl AutoMouseLayer {
+    fn new() -> Self {
+        Self {
+            active: false,
+            motion: 0,
+            last_activity: Instant::now(),
+        }
+    }
+
+    /// Update the automatic mouse layer, after the pointing device moved by `motion` counts.
+    fn update<K: PointingKeyboard>(&mut self, motion: u32) {
+        let Some(layer) = K::POINTING_AUTO_MOUSE_LAYER else {
+            return;
+        };
+
+        // Held mouse buttons keep the layer active, so that dragging doesn't time out
+        if motion > 0 || (self.active && held_buttons() != 0) {
+            self.last_activity = Instant::now();
+        } else if self.last_activity.elapsed()
+            >= Duration::from_millis(K::POINTING_AUTO_MOUSE_TIMEOUT_MS as u64)
+        {
+            self.motion = 0;
+            if self.active {
+                self.active = false;
+                debug!("[POINTING] Deactivating automatic mouse layer");
+                AUTO_MOUSE_LAYER_SIGNAL.signal(None);
+            }
+            return;
+        }
+
+        self.motion = self.motion.saturating_add(motion);
+        if !self.active && self.motion >= K::POINTING_AUTO_MOUSE_THRESHOLD as u32 {
+            self.active = true;
+            debug!("[POINTING] Activating automatic mouse layer {}", layer);
+            AUTO_MOUSE_LAYER_SIGNAL.signal(Some(layer));
+        }
+    }
+}
+
+/// Apply a command that changes the settings in `config`. Commands that only apply while a key is
+/// held ([`PointingCommand::DragScroll`] and [`PointingCommand::Sniping`]) are ignored.
+fn process_command<K: PointingKeyboard>(
+    command: PointingCommand,
+    config: PointingConfig,
+) -> PointingConfig {
+    let cpi = config.cpi.unwrap_or(K::POINTING_DEFAULT_CPI);
+    let set_cpi = |cpi: u16| PointingConfig {
+        cpi: Some(cpi.clamp(K::POINTING_MIN_CPI, K::POINTING_MAX_CPI)),
+        ..config
+    };
+
+    match command {
+        PointingCommand::IncreaseCpi => set_cpi(cpi.saturating_add(K::POINTING_CPI_STEP)),
+        PointingCommand::DecreaseCpi => set_cpi(cpi.saturating_sub(K::POINTING_CPI_STEP)),
+        PointingCommand::CycleCpi if cpi >= K::POINTING_MAX_CPI => set_cpi(K::POINTING_MIN_CPI),
+        PointingCommand::CycleCpi => set_cpi(cpi.saturating_add(K::POINTING_CPI_STEP)),
+        PointingCommand::ResetCpi => PointingConfig {
+            cpi: None,
+            ..config
+        },
+        PointingCommand::ToggleDragScroll => PointingConfig {
+            drag_scroll: !config.drag_scroll,
+            ..config
+        },
+        PointingCommand::ToggleSniping => PointingConfig {
+            sniping: !config.sniping,
+            ..config
+        },
+        PointingCommand::CycleAcceleration => PointingConfig {
+            acceleration: Some(
+                config
+                    .acceleration
+                    .unwrap_or(K::POINTING_DEFAULT_ACCELERATION)
+                    .next(),
+            ),
+            ..config
+        },
+        PointingCommand::SetAcceleration(acceleration) => PointingConfig {
+            acceleration: Some(acceleration),
+            ..config
+        },
+        PointingCommand::DragScroll | PointingCommand::Sniping => config,
+    }
+}
+
+/// Multiply the movement of the pointing device by the factor given by `acceleration`, in 1/256
+/// units. Fractions of a count that are left over are kept in `fraction`, so that slow movements
+/// aren't lost.
+fn accelerate<K: PointingKeyboard>(
+    acceleration: PointingAcceleration,
+    (x, y): (i32, i32),
+    fraction: &mut (i32, i32),
+) -> (i32, i32) {
+    if acceleration == PointingAcceleration::Off {
+        return (x, y);
+    }
+
+    // Approximate the speed of the pointing device, in 1/256 counts per millisecond
+    let (larger, smaller) = (x.abs().max(y.abs()), x.abs().min(y.abs()));
+    let speed = (larger + smaller / 2) * 256 / K::POINTING_POLL_INTERVAL_MS.max(1) as i32;
+    let threshold = K::POINTING_ACCELERATION_SPEED.max(1) as i32;
+
+    let factor = match acceleration {
+        PointingAcceleration::Off => 256,
+        PointingAcceleration::Linear => 256 + speed / threshold,
+        PointingAcceleration::Quadratic => {
+            256 + (speed / threshold).saturating_mul(speed / threshold) / 256
+        }
+    }
+    .min(K::POINTING_MAX_ACCELERATION.max(1) as i32 * 256);
+
+    let x = x * factor + fraction.0;
+    let y = y * factor + fraction.1;
+    *fraction = (x % 256, y % 256);
+    (x / 256, y / 256)
+}
+
+/// Take as much of `remainder` as fits in the X or Y field of a mouse report, after dividing it by
+/// `divisor`. Movement that doesn't add up to a whole unit is left in `remainder`.
+fn take_report_delta(remainder: &mut i32, divisor: u16) -> i16 {
+    let divisor = divisor.max(1) as i32;
+    let delta = (*remainder / divisor).clamp(-(i16::MAX as i32), i16::MAX as i32);
+    *remainder -= delta * divisor;
+    delta as i16
+}
+
+/// Take as much of `remainder` as fits in a wheel field of a mouse report.
+fn take_wheel_delta(remainder: &mut i32) -> i8 {
+    let delta = (*remainder).clamp(-(i8::MAX as i32), i8::MAX as i32);
+    *remainder -= delta;
+    delta as i8
+}
+
+/// Apply a new resolution to the pointing device, if it has changed.
+async fn update_cpi(device: &mut impl PointingDevice, cpi: &mut u16, new_cpi: u16) {
+    if new_cpi != *cpi {
+        *cpi = new_cpi;
+        device.set_cpi(new_cpi).await;
+        info!("[POINTING] Resolution changed to {} CPI", new_cpi);
+    }
+}
+
+#[rumcake_macros::task]
+pub async fn pointing_task<K: PointingKeyboard>(_k: K, mut device: impl PointingDevice) {
+    device.init().await;
+
+    let mut config = POINTING_CONFIG_STATE.get().await;
+    let mut cpi = config.active_cpi::<K>(false);
+    device.set_cpi(cpi).await;
+    info!("[POINTING] Pointing device initialized with {} CPI", cpi);
+
+    // Whether a drag scroll or sniping key is currently held
+    let mut drag_scroll_held = false;
+    let mut sniping_held = false;
+
+    // Movement and scrolling that hasn't been reported yet, because it didn't fit in the previous
+    // report, or didn't add up to a whole unit after applying the divisors
+    let mut remainder: (i32, i32) = (0, 0);
+    let mut wheel_remainder: (i32, i32) = (0, 0);
+
+    // Fractions of a count left over after acceleration, in 1/256 counts
+    let mut acceleration_fraction: (i32, i32) = (0, 0);
+
+    // Movement that hasn't been turned into scrolling yet while drag scroll is active
+    let mut drag_scroll_remainder: (i32, i32) = (0, 0);
+
+    // Buttons held on the pointing device when it was last polled
+    let mut buttons = 0;
+
+    let mut auto_mouse_layer = AutoMouseLayer::new();
+
+    let mut ticker = Ticker::every(Duration::from_millis(K::POINTING_POLL_INTERVAL_MS));
+
+    loop {
+        match select3(
+            ticker.next(),
+            POINTING_COMMAND_CHANNEL.receive(),
+            POINTING_CONFIG_STATE_LISTENER.wait(),
+        )
+        .await
+        {
+            Either3::First(()) => {
+                let report = device.poll().await;
+
+                let (x, y) = if K::POINTING_SWAP_XY {
+                    (report.y, report.x)
+                } else {
+                    (report.x, report.y)
+                };
+                let x = if K::POINTING_INVERT_X {
+                    -(x as i32)
+                } else {
+                    x as i32
+                };
+                let y = if K::POINTING_INVERT_Y {
+                    -(y as i32)
+                } else {
+                    y as i32
+                };
+
+                auto_mouse_layer.update::<K>(x.unsigned_abs() + y.unsigned_abs());
+
+                let (vertical_multiplier, horizontal_multiplier) = resolution_multiplier();
+                wheel_remainder.0 += report.vertical_wheel as i32 * vertical_multiplier as i32;
+                wheel_remainder.1 += report.horizontal_wheel as i32 * horizontal_multiplier as i32;
+
+                if config.drag_scroll || drag_scroll_held {
+                    // Moving up scrolls up, and moving right scrolls right
+                    let divisor = K::POINTING_DRAG_SCROLL_DIVISOR.max(1) as i32;
+                    drag_scroll_remainder.0 -= y * vertical_multiplier as i32;
+                    drag_scroll_remainder.1 += x * horizontal_multiplier as i32;
+                    wheel_remainder.0 += drag_scroll_remainder.0 / divisor;
+                    wheel_remainder.1 += drag_scroll_remainder.1 / divisor;
+                    drag_scroll_remainder.0 %= divisor;
+                    drag_scroll_remainder.1 %= divisor;
+                } else {
+                    let acceleration = config
+                        .acceleration
+                        .unwrap_or(K::POINTING_DEFAULT_ACCELERATION);
+                    let (x, y) = accelerate::<K>(acceleration, (x, y), &mut acceleration_fraction);
+                    remainder.0 += x;
+                    remainder.1 += y;
+                }
+
+                let x = take_report_delta(&mut remainder.0, K::POINTING_DIVISOR_X);
+                let y = take_report_delta(&mut remainder.1, K::POINTING_DIVISOR_Y);
+                let vertical_wheel = take_wheel_delta(&mut wheel_remainder.0);
+                let horizontal_wheel = take_wheel_delta(&mut wheel_remainder.1);
+
+                if (x, y, vertical_wheel, horizontal_wheel) == (0, 0, 0, 0)
+                    && report.buttons == buttons
+                {
+                    continue;
+                }
+
+                buttons = report.buttons;
+
+                let report = MouseReport {
+                    buttons: update_held_buttons(ButtonSource::Pointing, buttons),
+                    x,
+                    y,
+                    vertical_wheel,
+                    horizontal_wheel,
+                };
+                send_mouse_report(report).await;
+            }
+            Either3::Second((command, pressed)) => {
+                debug!(
+                    "[POINTING] Processing command: {:?}, pressed: {}",
+                    Debug2Format(&command),
+                    pressed
+                );
+
+                match command {
+                    PointingCommand::DragScroll => {
+                        drag_scroll_held = pressed;
+                        drag_scroll_remainder = (0, 0);
+                    }
+                    PointingCommand::Sniping => {
+                        sniping_held = pressed;
+                        update_cpi(&mut device, &mut cpi, config.active_cpi::<K>(sniping_held))
+                            .await;
+                    }
+                    _ if pressed => {
+                        POINTING_CONFIG_STATE
+                            .set(process_command::<K>(command, config))
+                            .await;
+                    }
+                    _ => {}
+                }
+            }
+            Either3::Third(()) => {
+                config = POINTING_CONFIG_STATE.get().await;
+                drag_scroll_remainder = (0, 0);
+                update_cpi(&mut device, &mut cpi, config.active_cpi::<K>(sniping_held)).await;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "storage")]
+pub mod storage {
+    use embassy_sync::signal::Signal;
+
+    use crate::hw::mcu::RawMutex;
+    use crate::storage::{FlashStorage, StorageDevice, StorageKey};
+
+    use super::POINTING_CONFIG_STATE;
+
+    pub(super) static POINTING_CONFIG_STATE_STORAGE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+    /// Signal used to save the pointing device settings immediately, instead of waiting for the
+    /// save policy defined in [`StorageDevice`].
+    pub(crate) static POINTING_CONFIG_SAVE_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+    /// Task that restores the pointing device settings, and saves any changes to them.
+    #[rumcake_macros::task]
+    pub async fn pointing_config_storage_task<K: StorageDevice, F: FlashStorage>(
+        _k: K,
+        database: &crate::storage::StorageService<'static, F>,
+    ) where
+        [(); F::ERASE_SIZE]:,
+        [(); F::REGION_SIZE]:,
+    {
+        database
+            .persist_state::<K, _>(
+                StorageKey::PointingConfig,
+                &POINTING_CONFIG_STATE,
+                &POINTING_CONFIG_STATE_STORAGE_LISTENER,
+                &POINTING_CONFIG_SAVE_SIGNAL,
+            )
+            .await
+    }
+}
@@ -0,0 +1,65 @@
+//! Raw HID communication.
+//!
+//! This provides a 32-byte vendor-defined HID interface that can be used by configurators and
+//! custom host software. Via and Vial use this interface to communicate with the host. If you are
+//! not using Via or Vial, you can handle raw HID reports yourself by implementing
+//! [`RawHIDDevice`].
+
+use embassy_sync::channel::Channel;
+
+use crate::hw::mcu::RawMutex;
+
+/// Size of raw HID reports, in both directions.
+pub const RAW_HID_REPORT_SIZE: usize = 32;
+
+/// Report descriptor used for raw HID. Pulled from QMK.
+pub(crate) const RAW_HID_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x60, 0xFF, // Usage Page (Vendor Defined)
+    0x09, 0x61, // Usage (Vendor Defined)
+    0xA1, 0x01, // Collection (Application)
+    // Data to host
+    0x09, 0x62, //   Usage (Vendor Defined)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x95, 0x20, //   Report Count
+    0x75, 0x08, //   Report Size (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    // Data from host
+    0x09, 0x63, //   Usage (Vendor Defined)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x95, 0x20, //   Report Count
+    0x75, 0x08, //   Report Size (8)
+    0x91, 0x02, //   Output (Data, Variable, Absolute)
+    0xC0, // End Collection
+];
+
+/// Channel used to receive raw HID reports from the host. Reports that are sent to this channel
+/// will be processed by the Via/Vial task if either is enabled, or [`raw_hid_process_task`]
+/// otherwise.
+pub static RAW_HID_REPORT_RECEIVE_CHANNEL: Channel<RawMutex, [u8; RAW_HID_REPORT_SIZE], 1> =
+    Channel::new();
+
+/// Channel used to send raw HID reports to the host.
+pub static RAW_HID_REPORT_SEND_CHANNEL: Channel<RawMutex, [u8; RAW_HID_REPORT_SIZE], 1> =
+    Channel::new();
+
+/// A trait that keyboards must implement to handle raw HID reports without Via or Vial.
+pub trait RawHIDDevice {
+    /// Handle a raw HID report sent by the host.
+    ///
+    /// `data` can be modified in place to form a response. Returning `true` will send `data` back
+    /// to the host, while returning `false` will not send a response.
+    fn handle_raw_hid_report(data: &mut [u8; RAW_HID_REPORT_SIZE]) -> bool;
+}
+
+#[rumcake_macros::task]
+pub async fn raw_hid_process_task<K: RawHIDDevice>(_k: K) {
+    loop {
+        let mut report = RAW_HID_REPORT_RECEIVE_CHANNEL.receive().await;
+
+        if K::handle_raw_hid_report(&mut report) {
+            RAW_HID_REPORT_SEND_CHANNEL.send(report).await;
+        }
+    }
+}
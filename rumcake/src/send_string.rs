@@ -0,0 +1,69 @@
+//! Send-string macros, which type text and key events when a key is pressed.
+//!
+//! Macros are stored as [`keyberon::action::Action::Sequence`]s, and can be created at compile
+//! time with [`send_string`]. Each macro is a list of bytes, which are processed in order:
+//! - Printable ASCII characters are typed as-is. `\n`, `\t`, `\x08` and `\x1b` type Enter, Tab,
+//!   Backspace and Escape.
+//! - `[1, 1, keycode]` taps a key.
+//! - `[1, 2, keycode]` presses a key.
+//! - `[1, 3, keycode]` releases a key.
+//! - `[1, 4, digits..., b'|']` waits for the given number of milliseconds, written as ASCII digits.
+//!
+//! This is the same format used by macros created in the Via app, so Via macros, dynamic macros
+//! and send-string macros are all typed the same way. [`KeyboardLayout::SEND_STRING_DELAY`] can be
+//! used to add a delay after every key event typed by any of these macros.
+//!
+//! [`KeyboardLayout::SEND_STRING_DELAY`]: crate::keyboard::KeyboardLayout::SEND_STRING_DELAY
+
+use heapless::Vec;
+
+pub use rumcake_macros::send_string;
+
+/// Prefix byte that starts a key event or delay in a sequence.
+pub(crate) const SS_QMK_PREFIX: u8 = 1;
+
+/// Sequence event that taps a key.
+pub(crate) const SS_TAP_CODE: u8 = 1;
+
+/// Sequence event that presses a key.
+pub(crate) const SS_DOWN_CODE: u8 = 2;
+
+/// Sequence event that releases a key.
+pub(crate) const SS_UP_CODE: u8 = 3;
+
+/// Sequence event that waits for a number of milliseconds.
+pub(crate) const SS_DELAY_CODE: u8 = 4;
+
+/// Append a key event to a sequence.
+pub(crate) fn push_key_event<const N: usize>(
+    sequence: &mut Vec<u8, N>,
+    code: u8,
+    keycode: u8,
+) -> Result<(), ()> {
+    sequence.extend_from_slice(&[SS_QMK_PREFIX, code, keycode])
+}
+
+/// Append a delay to a sequence. Delays can't start with a 0 digit, so nothing is added if
+/// `delay_ms` is 0.
+pub(crate) fn push_delay<const N: usize>(
+    sequence: &mut Vec<u8, N>,
+    delay_ms: u64,
+) -> Result<(), ()> {
+    if delay_ms == 0 {
+        return Ok(());
+    }
+
+    let mut digits = [0; 20];
+    let mut remaining = delay_ms;
+    let mut len = 0;
+    while remaining > 0 {
+        digits[len] = b'0' + (remaining % 10) as u8;
+        remaining /= 10;
+        len += 1;
+    }
+    digits[..len].reverse();
+
+    sequence.extend_from_slice(&[SS_QMK_PREFIX, SS_DELAY_CODE])?;
+    sequence.extend_from_slice(&digits[..len])?;
+    sequence.push(b'|').map_err(|_| ())
+}
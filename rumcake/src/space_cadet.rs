@@ -0,0 +1,49 @@
+//! Space Cadet keys, which act as a modifier when held, and type a symbol when tapped.
+//!
+//! Each key in this module is a ready-made tap-hold action, which can be added to your layout like
+//! any other action (e.g. `{SC_LSPO}`). For example, [`SC_LSPO`] acts as Left Shift when held,
+//! and types `(` when tapped on its own. Space Cadet keys are resolved as a hold as soon as another
+//! key is pressed, so typing quickly while holding one of them will not type a symbol.
+//!
+//! Since Space Cadet keys are tap-hold keys, they use the tapping term in
+//! [`crate::keyboard::TAP_HOLD_CONFIG_STATE`], and can be changed by
+//! [`crate::keyboard::KeyboardLayout::tap_hold_config`] like any other tap-hold key. Changing
+//! the tap-hold strategy will also change how Space Cadet keys are resolved.
+
+use keyberon::action::{Action, HoldTapAction, HoldTapConfig};
+use keyberon::key_code::KeyCode;
+
+use crate::keyboard::{Keycode, DEFAULT_TAPPING_TERM};
+
+macro_rules! space_cadet {
+    ($hold:ident, $($tap:ident),+) => {
+        Action::HoldTap(&HoldTapAction {
+            timeout: DEFAULT_TAPPING_TERM,
+            hold: Action::KeyCode(KeyCode::$hold),
+            tap: Action::MultipleKeyCodes(&[$(KeyCode::$tap),+].as_slice()),
+            config: HoldTapConfig::HoldOnOtherKeyPress,
+            tap_hold_interval: 0,
+        })
+    };
+}
+
+/// Left Shift when held, `(` when tapped.
+pub const SC_LSPO: Action<Keycode> = space_cadet!(LShift, LShift, Kb9);
+
+/// Right Shift when held, `)` when tapped.
+pub const SC_RSPC: Action<Keycode> = space_cadet!(RShift, RShift, Kb0);
+
+/// Left Control when held, `(` when tapped.
+pub const SC_LCPO: Action<Keycode> = space_cadet!(LCtrl, LShift, Kb9);
+
+/// Right Control when held, `)` when tapped.
+pub const SC_RCPC: Action<Keycode> = space_cadet!(RCtrl, RShift, Kb0);
+
+/// Left Alt when held, `(` when tapped.
+pub const SC_LAPO: Action<Keycode> = space_cadet!(LAlt, LShift, Kb9);
+
+/// Right Alt when held, `)` when tapped.
+pub const SC_RAPC: Action<Keycode> = space_cadet!(RAlt, RShift, Kb0);
+
+/// Right Shift when held, Enter when tapped.
+pub const SC_SENT: Action<Keycode> = space_cadet!(RShift, Enter);
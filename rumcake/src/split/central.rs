@@ -7,13 +7,22 @@
 //! will also be responsible for sending their related commands to the peripherals (see
 //! [`MessageToPeripheral`]).
 
+#[cfg(feature = "pointing")]
+use core::cell::Cell;
+
 use defmt::{error, Debug2Format};
-use embassy_futures::select::{select, Either};
+use embassy_futures::select::{select3, Either3};
 use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
 
+#[cfg(feature = "pointing")]
+use crate::hw::mcu::BlockingMutex;
 use crate::hw::mcu::RawMutex;
-use crate::keyboard::POLLED_EVENTS_CHANNEL;
+use crate::keyboard::{LAYER_STATE, POLLED_EVENTS_CHANNEL};
+#[cfg(feature = "pointing")]
+use crate::pointing::{PointingDevice, PointingReport};
 use crate::split::MessageToCentral;
+use crate::State;
 
 use super::drivers::CentralDeviceDriver;
 use super::MessageToPeripheral;
@@ -25,20 +34,55 @@ use super::MessageToPeripheral;
 /// appropriately. You should only send to this channel.
 pub static MESSAGE_TO_PERIPHERALS: Channel<RawMutex, MessageToPeripheral, 4> = Channel::new();
 
+/// State that contains the last battery level reported by a peripheral. If the central device
+/// communicates with the host over bluetooth, this is exposed to the host as a second battery
+/// service.
+pub static PERIPHERAL_BATTERY_LEVEL_STATE: State<u8> = State::new(
+    100,
+    &[
+        #[cfg(feature = "bluetooth")]
+        &crate::bluetooth::PERIPHERAL_BATTERY_LEVEL_LISTENER,
+    ],
+);
+
+pub(crate) static LAYER_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+/// Movement and buttons received from the peripherals' pointing devices, that haven't been polled
+/// yet.
+#[cfg(feature = "pointing")]
+static PERIPHERAL_POINTING_REPORT: BlockingMutex<Cell<Option<PointingReport>>> =
+    BlockingMutex::new(Cell::new(None));
+
 #[rumcake_macros::task]
 pub async fn central_task(mut driver: impl CentralDeviceDriver) {
     loop {
-        match select(
+        match select3(
             driver.receive_message_from_peripherals(),
             MESSAGE_TO_PERIPHERALS.receive(),
+            LAYER_STATE_LISTENER.wait(),
         )
         .await
         {
-            Either::First(message) => match message {
+            Either3::First(message) => match message {
                 Ok(event) => match event {
                     MessageToCentral::KeyPress(_, _) | MessageToCentral::KeyRelease(_, _) => {
                         POLLED_EVENTS_CHANNEL.send(event.try_into().unwrap()).await;
                     }
+                    MessageToCentral::BatteryLevel(level) => {
+                        PERIPHERAL_BATTERY_LEVEL_STATE.set(level).await;
+                    }
+                    #[cfg(feature = "pointing")]
+                    MessageToCentral::Pointing(report) => {
+                        // If the pointing device hasn't been polled since the last report, the
+                        // movement is added to it
+                        PERIPHERAL_POINTING_REPORT.lock(|pending| {
+                            pending.set(Some(
+                                pending
+                                    .get()
+                                    .map_or(report, |pending| pending.accumulate(report)),
+                            ))
+                        });
+                    }
                 },
                 Err(err) => {
                     error!(
@@ -47,7 +91,7 @@ pub async fn central_task(mut driver: impl CentralDeviceDriver) {
                     )
                 }
             },
-            Either::Second(message) => {
+            Either3::Second(message) => {
                 if let Err(err) = driver.broadcast_message_to_peripherals(message).await {
                     error!(
                         "[SPLIT_CENTRAL] Error sending matrix events to peripheral: {}",
@@ -55,6 +99,64 @@ pub async fn central_task(mut driver: impl CentralDeviceDriver) {
                     )
                 };
             }
+            Either3::Third(()) => {
+                let message = MessageToPeripheral::LayerState(LAYER_STATE.get().await);
+                if let Err(err) = driver.broadcast_message_to_peripherals(message).await {
+                    error!(
+                        "[SPLIT_CENTRAL] Error sending layer state to peripheral: {}",
+                        Debug2Format(&err)
+                    )
+                };
+            }
         }
     }
 }
+
+/// A pointing device that is connected to a peripheral. Movement and buttons are received from the
+/// peripheral (see [`crate::split::peripheral::peripheral_pointing_task`]), and changes to the
+/// resolution are sent to it.
+///
+/// This can be passed to [`crate::pointing::pointing_task`] on the central device, or combined
+/// with a pointing device on the central device.
+#[cfg(feature = "pointing")]
+pub struct PeripheralPointingDevice {
+    buttons: u8,
+}
+
+#[cfg(feature = "pointing")]
+impl PeripheralPointingDevice {
+    /// Create a pointing device that receives its movement from the peripherals.
+    pub const fn new() -> Self {
+        Self { buttons: 0 }
+    }
+}
+
+#[cfg(feature = "pointing")]
+impl Default for PeripheralPointingDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "pointing")]
+impl PointingDevice for PeripheralPointingDevice {
+    async fn poll(&mut self) -> PointingReport {
+        match PERIPHERAL_POINTING_REPORT.lock(|pending| pending.take()) {
+            Some(report) => {
+                self.buttons = report.buttons;
+                report
+            }
+            // Buttons stay held until the peripheral reports that they were released
+            None => PointingReport {
+                buttons: self.buttons,
+                ..Default::default()
+            },
+        }
+    }
+
+    async fn set_cpi(&mut self, cpi: u16) {
+        MESSAGE_TO_PERIPHERALS
+            .send(MessageToPeripheral::PointingCpi(cpi))
+            .await;
+    }
+}
@@ -19,6 +19,13 @@ pub enum MessageToCentral {
     KeyPress(u8, u8),
     /// Key release in the form of (row, col).
     KeyRelease(u8, u8),
+    /// Battery level of the peripheral, as a percentage from 0 to 100.
+    BatteryLevel(u8),
+
+    #[cfg(feature = "pointing")]
+    /// Movement and buttons of the peripheral's pointing device, accumulated since the last
+    /// message. See [`peripheral_pointing_task`](crate::split::peripheral::peripheral_pointing_task).
+    Pointing(crate::pointing::PointingReport),
 }
 
 /// Size of buffer used when sending messages to a central device
@@ -40,6 +47,7 @@ impl TryFrom<MessageToCentral> for Event {
         match message {
             MessageToCentral::KeyPress(row, col) => Ok(Event::Press(row, col)),
             MessageToCentral::KeyRelease(row, col) => Ok(Event::Release(row, col)),
+            _ => Err(()),
         }
     }
 }
@@ -67,6 +75,14 @@ pub enum MessageToPeripheral {
     #[cfg(feature = "underglow")]
     /// An [`UnderglowCommand`](crate::underglow::animations::UnderglowCommand) to be processed by the peripheral's backlight animator.
     Underglow(crate::underglow::animations::UnderglowCommand),
+
+    /// The layers that are active on the central device. See
+    /// [`LAYER_STATE`](crate::keyboard::LAYER_STATE).
+    LayerState(u32),
+
+    #[cfg(feature = "pointing")]
+    /// Resolution that the peripheral's pointing device should use, in counts per inch.
+    PointingCpi(u16),
 }
 
 /// Size of buffer used when sending messages to a peripheral device
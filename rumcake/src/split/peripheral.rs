@@ -6,26 +6,66 @@
 //! extra features, then all the peripherals should receive the related commands from the central
 //! device (see [`MessageToPeripheral`]).
 
+#[cfg(feature = "pointing")]
+use core::cell::Cell;
+
+#[cfg(feature = "pointing")]
+use defmt::info;
 use defmt::{error, Debug2Format};
+#[cfg(feature = "pointing")]
 use embassy_futures::select::{select, Either};
+use embassy_futures::select::{select4, Either4};
 use embassy_sync::pubsub::PubSubBehavior;
+use embassy_sync::signal::Signal;
+#[cfg(feature = "pointing")]
+use embassy_time::{Duration, Ticker};
 
-use crate::keyboard::{MATRIX_EVENTS, POLLED_EVENTS_CHANNEL};
-use crate::split::MessageToPeripheral;
+#[cfg(feature = "pointing")]
+use crate::hw::mcu::BlockingMutex;
+use crate::hw::mcu::RawMutex;
+use crate::hw::BATTERY_STATE;
+use crate::keyboard::{LAYER_STATE, MATRIX_EVENTS, POLLED_EVENTS_CHANNEL};
+#[cfg(feature = "pointing")]
+use crate::pointing::{PointingDevice, PointingKeyboard, PointingReport};
+use crate::split::{MessageToCentral, MessageToPeripheral};
 
 use super::drivers::PeripheralDeviceDriver;
 
+pub(crate) static BATTERY_LEVEL_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+#[cfg(feature = "pointing")]
+static POINTING_REPORT_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+/// Movement and buttons of the pointing device that haven't been sent to the central device yet.
+#[cfg(feature = "pointing")]
+static PENDING_POINTING_REPORT: BlockingMutex<Cell<Option<PointingReport>>> =
+    BlockingMutex::new(Cell::new(None));
+
+/// Signal used to change the resolution of the pointing device, when requested by the central
+/// device.
+#[cfg(feature = "pointing")]
+static POINTING_CPI_SIGNAL: Signal<RawMutex, u16> = Signal::new();
+
 // This task replaces the `layout_collect` task, which is usually used on non-split keyboards for sending events to the keyboard layout
 #[rumcake_macros::task]
 pub async fn peripheral_task(mut driver: impl PeripheralDeviceDriver) {
+    let mut last_battery_level = None;
+
     loop {
-        match select(
+        #[cfg(feature = "pointing")]
+        let pointing_fut = POINTING_REPORT_LISTENER.wait();
+        #[cfg(not(feature = "pointing"))]
+        let pointing_fut = core::future::pending::<()>();
+
+        match select4(
             driver.receive_message_from_central(),
             POLLED_EVENTS_CHANNEL.receive(),
+            BATTERY_LEVEL_LISTENER.wait(),
+            pointing_fut,
         )
         .await
         {
-            Either::First(message) => match message {
+            Either4::First(message) => match message {
                 Ok(message) => match message {
                     #[cfg(feature = "simple-backlight")]
                     MessageToPeripheral::SimpleBacklight(command) => {
@@ -51,6 +91,13 @@ pub async fn peripheral_task(mut driver: impl PeripheralDeviceDriver) {
                             .send(command)
                             .await
                     }
+                    MessageToPeripheral::LayerState(layers) => {
+                        LAYER_STATE.set(layers).await;
+                    }
+                    #[cfg(feature = "pointing")]
+                    MessageToPeripheral::PointingCpi(cpi) => {
+                        POINTING_CPI_SIGNAL.signal(cpi);
+                    }
                     #[allow(unreachable_patterns)]
                     _ => {}
                 },
@@ -61,7 +108,7 @@ pub async fn peripheral_task(mut driver: impl PeripheralDeviceDriver) {
                     )
                 }
             },
-            Either::Second(event) => {
+            Either4::Second(event) => {
                 MATRIX_EVENTS.publish_immediate(event);
 
                 if let Err(err) = driver.send_message_to_central(event.into()).await {
@@ -71,6 +118,100 @@ pub async fn peripheral_task(mut driver: impl PeripheralDeviceDriver) {
                     )
                 };
             }
+            Either4::Third(()) => {
+                // The battery state also changes when the voltage changes, so we only forward it
+                // to the central device when the level changes.
+                let level = BATTERY_STATE.get().await.level;
+                if last_battery_level == Some(level) {
+                    continue;
+                }
+
+                if let Err(err) = driver
+                    .send_message_to_central(MessageToCentral::BatteryLevel(level))
+                    .await
+                {
+                    error!(
+                        "[SPLIT_PERIPHERAL] Error sending battery level to central: {}",
+                        Debug2Format(&err)
+                    )
+                } else {
+                    last_battery_level = Some(level);
+                };
+            }
+            #[cfg(feature = "pointing")]
+            Either4::Fourth(()) => {
+                if let Some(report) = PENDING_POINTING_REPORT.lock(|pending| pending.take()) {
+                    if let Err(err) = driver
+                        .send_message_to_central(MessageToCentral::Pointing(report))
+                        .await
+                    {
+                        error!(
+                            "[SPLIT_PERIPHERAL] Error sending pointing device report to central: {}",
+                            Debug2Format(&err)
+                        )
+                    };
+                }
+            }
+            #[cfg(not(feature = "pointing"))]
+            Either4::Fourth(()) => {}
+        }
+    }
+}
+
+/// Task that polls a pointing device on a peripheral, and forwards its movement and buttons to the
+/// central device, which sends them to the host. This replaces [`crate::pointing::pointing_task`]
+/// on peripherals.
+///
+/// Only [`PointingKeyboard::POINTING_POLL_INTERVAL_MS`] and
+/// [`PointingKeyboard::POINTING_DEFAULT_CPI`] are used from the peripheral's [`PointingKeyboard`]
+/// implementation. The rest of the settings (e.g. orientation and acceleration) are applied by the
+/// central device, which must use
+/// [`PeripheralPointingDevice`](crate::split::central::PeripheralPointingDevice) as its pointing
+/// device.
+#[cfg(feature = "pointing")]
+#[rumcake_macros::task]
+pub async fn peripheral_pointing_task<K: PointingKeyboard>(_k: K, mut device: impl PointingDevice) {
+    device.init().await;
+    device.set_cpi(K::POINTING_DEFAULT_CPI).await;
+
+    // Buttons held on the pointing device when it was last polled
+    let mut buttons = 0;
+
+    let mut ticker = Ticker::every(Duration::from_millis(K::POINTING_POLL_INTERVAL_MS));
+
+    loop {
+        match select(ticker.next(), POINTING_CPI_SIGNAL.wait()).await {
+            Either::First(()) => {
+                let report = device.poll().await;
+
+                let idle = PointingReport {
+                    buttons,
+                    ..Default::default()
+                };
+                if report == idle {
+                    continue;
+                }
+
+                buttons = report.buttons;
+
+                // If the central device hasn't received the last report yet, the movement is added
+                // to it
+                PENDING_POINTING_REPORT.lock(|pending| {
+                    pending.set(Some(
+                        pending
+                            .get()
+                            .map_or(report, |pending| pending.accumulate(report)),
+                    ))
+                });
+                POINTING_REPORT_LISTENER.signal(());
+            }
+            Either::Second(cpi) => {
+                device.set_cpi(cpi).await;
+                info!(
+                    "[SPLIT_PERIPHERAL] Pointing device resolution changed to {} CPI",
+                    cpi
+                );
+            }
         }
     }
 }
@@ -4,9 +4,10 @@
 //! your MCU's flash. As a result, a user will be able to configure things like backlight/underglow
 //! effect settings, or dynamic keymaps without losing their changes between keyboard restarts.
 //!
-//! To use this feature, you will need to add a `CONFIG` section, and its start and end address to
-//! your `memory.x` file. Refer to [`crate::hw::__config_start`], and the corresponding
-//! `feature-storage.md` doc for more information.
+//! To use this feature, you will need to reserve a section of flash for your config data, using the
+//! [`config_partition`] macro, and the `rumcake_config.x` linker script. Alternatively, you can add
+//! a `CONFIG` section to your `memory.x` file (see [`crate::hw::__config_start`]). Refer to the
+//! corresponding `feature-storage.md` doc for more information.
 
 use core::cell::{Cell, RefCell};
 use core::fmt::Debug;
@@ -14,7 +15,11 @@ use core::hash::{Hash, Hasher, SipHasher};
 
 use defmt::{assert, debug};
 use defmt::{error, info, warn, Debug2Format};
+use embassy_futures::select::{select, select3, Either, Either3};
 use embassy_sync::mutex::{Mutex, MutexGuard};
+use embassy_sync::pubsub::{DynSubscriber, PubSubChannel};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
 use embedded_storage::nor_flash::ReadNorFlash;
 use embedded_storage_async::nor_flash::{
     ErrorType, NorFlash as AsyncNorFlash, ReadNorFlash as AsyncReadNorFlash,
@@ -26,7 +31,41 @@ use serde::Serialize;
 use tickv::success_codes::SuccessCode;
 use tickv::{AsyncTicKV, ErrorCode, FlashController, MAIN_KEY};
 
-use crate::hw::mcu::RawMutex;
+use crate::hw::mcu::{BlockingMutex, RawMutex};
+use crate::State;
+
+pub use rumcake_macros::config_partition;
+
+/// Bounds of the flash section reserved for config data. The addresses are relative to the start
+/// address of your MCU's flash.
+#[derive(Debug, Clone, Copy)]
+pub struct ConfigPartition {
+    /// Start address of the config partition.
+    pub start: usize,
+    /// End address of the config partition.
+    pub end: usize,
+}
+
+extern "Rust" {
+    fn __rumcake_config_partition() -> ConfigPartition;
+}
+
+/// Obtain the bounds of the config partition reserved with the [`config_partition`] macro. This is
+/// used by default, unless `use_memory_x` is specified in the `storage` settings of the
+/// `#[keyboard]` macro.
+pub fn config_partition() -> ConfigPartition {
+    unsafe { __rumcake_config_partition() }
+}
+
+/// Key of one of the two slots used to store the nonce counter.
+#[cfg(feature = "storage-encryption")]
+fn nonce_counter_key(slot: u64) -> [u8; 3] {
+    [
+        StorageKey::NonceCounter as u8,
+        StorageKeyType::Data as u8,
+        slot as u8,
+    ]
+}
 
 fn get_hashed_key(key: &[u8]) -> u64 {
     let mut hasher = SipHasher::new();
@@ -48,6 +87,12 @@ pub enum StorageKey {
     UnderglowConfig = 0x10,
     /// Key to store bluetooth profiles, used by the `nrf-ble` implementation of bluetooth host communication.
     BluetoothProfiles = 0x20,
+    /// Key to store the selected [`crate::hw::OutputMode`].
+    OutputMode = 0x21,
+    /// Key to store the selected [`crate::bluetooth::TxPower`].
+    TxPower = 0x22,
+    /// Key to store the keyboards paired with a dongle, used by the `nrf-ble` implementation of the dongle role.
+    DonglePairedKeyboards = 0x23,
     /// Key to store the currently set Via layout option.
     LayoutOptions = 0x30,
     /// Key to store the current state of the Via dynamic keyboard layout.
@@ -62,6 +107,181 @@ pub enum StorageKey {
     DynamicKeymapCombo = 0x41,
     /// Key to store the current state of the key overrides in the Vial dynamic keyboard layout.
     DynamicKeymapKeyOverride = 0x42,
+    /// Key to store the [`crate::keyboard::TapHoldConfig`].
+    TapHoldConfig = 0x50,
+    /// Key to store the [`crate::dynamic_macro::DynamicMacros`].
+    DynamicMacros = 0x51,
+    /// Key to store the [`crate::unicode::UnicodeMode`].
+    UnicodeMode = 0x52,
+    /// Key to store the [`crate::accessibility::AccessibilityConfig`].
+    AccessibilityConfig = 0x53,
+    /// Key to store the default layer of the layout. See [`crate::keyboard::DEFAULT_LAYER_STATE`].
+    DefaultLayer = 0x54,
+    /// Key to store the [`crate::analog::AnalogKeyConfigs`].
+    AnalogKeyConfigs = 0x55,
+    /// Key to store the calibration of the analog matrix. See [`crate::analog`].
+    AnalogCalibration = 0x56,
+    /// Key to store the [`crate::keyboard::MatrixConfig`].
+    MatrixConfig = 0x57,
+    /// Key to store the [`crate::encoder::EncoderConfigs`].
+    EncoderConfigs = 0x58,
+    /// Key to store the [`crate::pointing::PointingConfig`].
+    PointingConfig = 0x59,
+    /// Key to store the counter used to generate nonces for encrypted data.
+    NonceCounter = 0x5A,
+}
+
+impl StorageKey {
+    /// Whether data stored at this key is encrypted before it is written to flash. Data that may
+    /// contain sensitive information (e.g. passwords in macros) is encrypted if the
+    /// `storage-encryption` feature is enabled.
+    pub fn is_encrypted(self) -> bool {
+        cfg!(feature = "storage-encryption") && matches!(self, StorageKey::DynamicKeymapMacro)
+    }
+
+    /// Offset in the stored value at which the data for this key starts. For encrypted keys, the
+    /// data is preceded by the nonce that was used to encrypt it.
+    fn data_offset(self) -> usize {
+        #[cfg(feature = "storage-encryption")]
+        if self.is_encrypted() {
+            return encryption::NONCE_LEN;
+        }
+
+        0
+    }
+
+    /// Number of bytes stored after the data for this key. For encrypted keys, the data is
+    /// followed by its authentication tag.
+    fn tag_len(self) -> usize {
+        #[cfg(feature = "storage-encryption")]
+        if self.is_encrypted() {
+            return encryption::TAG_LEN;
+        }
+
+        0
+    }
+
+    /// Encrypt the data contained in `buffer[..len]`, starting at [`StorageKey::data_offset`], if
+    /// this key is encrypted. The nonce, obtained from [`StorageService::next_nonce`], is written
+    /// to the start of `buffer`, and the authentication tag is written after the data. Returns the
+    /// length of the value that should be stored.
+    #[cfg_attr(not(feature = "storage-encryption"), allow(unused_variables))]
+    fn encrypt(self, buffer: &mut [u8], len: usize, nonce: u64) -> Result<usize, StorageError> {
+        #[cfg(feature = "storage-encryption")]
+        if self.is_encrypted() {
+            let (value, rest) = buffer.split_at_mut(len);
+            let (nonce_bytes, data) = value.split_at_mut(encryption::NONCE_LEN);
+            nonce_bytes.copy_from_slice(&nonce.to_le_bytes());
+            let tag = encryption::encrypt(self, nonce_bytes, data)?;
+            rest[..encryption::TAG_LEN].copy_from_slice(&tag);
+            return Ok(len + encryption::TAG_LEN);
+        }
+
+        Ok(len)
+    }
+
+    /// Decrypt a value read from the database if this key is encrypted, returning the data
+    /// contained in it.
+    fn decrypt(self, value: &mut [u8]) -> Result<&mut [u8], StorageError> {
+        #[cfg(feature = "storage-encryption")]
+        if self.is_encrypted() {
+            if value.len() < encryption::NONCE_LEN + encryption::TAG_LEN {
+                return Err(StorageError::Corrupted);
+            }
+            let (nonce, rest) = value.split_at_mut(encryption::NONCE_LEN);
+            let (data, tag) = rest.split_at_mut(rest.len() - encryption::TAG_LEN);
+            encryption::decrypt(self, nonce, data, tag)?;
+            return Ok(data);
+        }
+
+        Ok(value)
+    }
+}
+
+/// Encryption for sensitive stored data, using ChaCha20-Poly1305 with a key derived from the
+/// unique ID of the MCU. This prevents the data from being read from a flash dump, or from a
+/// backup that is restored on another device, and prevents modified data from being accepted.
+/// Keep in mind that the key can still be derived by anyone with access to the device itself.
+#[cfg(feature = "storage-encryption")]
+mod encryption {
+    use chacha20poly1305::aead::AeadInPlace;
+    use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce, Tag};
+    use defmt::error;
+    use once_cell::sync::OnceCell;
+
+    use super::{StorageError, StorageKey};
+
+    /// Length of the nonce stored before each encrypted value.
+    pub(super) const NONCE_LEN: usize = 8;
+
+    /// Length of the authentication tag stored after each encrypted value.
+    pub(super) const TAG_LEN: usize = 16;
+
+    static DEVICE_KEY: OnceCell<[u8; 32]> = OnceCell::new();
+
+    /// Obtain the key used to encrypt stored data. Without a device ID, there is nothing unique to
+    /// derive the key from, so encrypted data can't be read or written.
+    fn device_key() -> Result<&'static [u8; 32], StorageError> {
+        DEVICE_KEY.get_or_try_init(|| {
+            let device_id = crate::hw::mcu::device_id();
+            if device_id.is_empty() {
+                error!("[STORAGE] Device ID is not available, encrypted data can't be used.");
+                return Err(StorageError::EncryptionUnavailable);
+            }
+
+            // Use the device ID as a key to derive the actual encryption key
+            let mut seed = [0; 32];
+            for (i, byte) in device_id.iter().enumerate() {
+                seed[i % seed.len()] ^= byte;
+            }
+            let mut key = *b"rumcake storage encryption key!!";
+            ChaCha20Poly1305::new(&seed.into())
+                .encrypt_in_place_detached(&Nonce::default(), &[], &mut key)
+                .map_err(|_| StorageError::EncryptionUnavailable)?;
+            Ok(key)
+        })
+    }
+
+    fn cipher() -> Result<ChaCha20Poly1305, StorageError> {
+        device_key().map(|key| ChaCha20Poly1305::new(key.into()))
+    }
+
+    fn iv(key: StorageKey, nonce: &[u8]) -> Nonce {
+        let mut iv = Nonce::default();
+        iv[0] = key as u8;
+        iv[(12 - NONCE_LEN)..].copy_from_slice(nonce);
+        iv
+    }
+
+    /// Buffer used to read and write the nonce counter. See
+    /// [`super::StorageService::next_nonce`].
+    pub(super) fn counter_buffer() -> &'static mut [u8] {
+        static mut COUNTER_BUFFER: [u8; NONCE_LEN] = [0; NONCE_LEN];
+        unsafe { &mut COUNTER_BUFFER }
+    }
+
+    /// Encrypt `data` in place, returning its authentication tag.
+    pub(super) fn encrypt(
+        key: StorageKey,
+        nonce: &[u8],
+        data: &mut [u8],
+    ) -> Result<Tag, StorageError> {
+        cipher()?
+            .encrypt_in_place_detached(&iv(key, nonce), &[], data)
+            .map_err(|_| StorageError::Serialization)
+    }
+
+    /// Decrypt `data` in place, after checking it against its authentication tag.
+    pub(super) fn decrypt(
+        key: StorageKey,
+        nonce: &[u8],
+        data: &mut [u8],
+        tag: &[u8],
+    ) -> Result<(), StorageError> {
+        cipher()?
+            .decrypt_in_place_detached(&iv(key, nonce), &[], data, Tag::from_slice(tag))
+            .map_err(|_| StorageError::Authentication)
+    }
 }
 
 #[repr(u8)]
@@ -70,30 +290,158 @@ enum StorageKeyType {
     Metadata,
 }
 
+/// Errors that can occur when using a [`StorageService`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StorageError {
+    /// There is no data stored for the requested key.
+    KeyNotFound,
+    /// The stored data could not be deserialized. This can happen if the stored data is corrupted.
+    Deserialization,
+    /// The data could not be serialized. This usually means that the provided buffer is too small
+    /// to store the serialized data.
+    Serialization,
+    /// The provided buffer is too small to store the data that was read.
+    BufferTooSmall,
+    /// There is no space left in the config partition to store the data.
+    Full,
+    /// The requested address range is outside of the config partition, or is not aligned to the
+    /// flash peripheral's write size.
+    InvalidAddress,
+    /// The stored data failed TicKV's checksum validation, or the database structure is
+    /// corrupted. Corrupted values are deleted when they are read.
+    Corrupted,
+    /// The flash peripheral failed to read, write or erase data.
+    Flash,
+    /// Encrypted data can't be read or written, because the MCU does not provide a unique ID to
+    /// derive the encryption key from.
+    EncryptionUnavailable,
+    /// Encrypted data failed authentication. This happens if the stored data was modified, or if
+    /// it was written by a different keyboard (e.g. after restoring another keyboard's backup).
+    Authentication,
+    /// The backup being restored has an invalid header, was taken from a config partition with a
+    /// different size, or failed CRC validation. This is also returned if the chunks of a backup
+    /// are not restored in order. See [`BackupRestore`].
+    InvalidBackup,
+    /// Some other error was returned by the underlying TicKV database.
+    Database(ErrorCode),
+}
+
+impl From<ErrorCode> for StorageError {
+    fn from(error: ErrorCode) -> Self {
+        match error {
+            ErrorCode::KeyNotFound => StorageError::KeyNotFound,
+            ErrorCode::BufferTooSmall(_) => StorageError::BufferTooSmall,
+            ErrorCode::RegionFull | ErrorCode::FlashFull => StorageError::Full,
+            ErrorCode::CorruptData | ErrorCode::InvalidCheckSum => StorageError::Corrupted,
+            ErrorCode::ReadFail | ErrorCode::WriteFail | ErrorCode::EraseFail => {
+                StorageError::Flash
+            }
+            error => StorageError::Database(error),
+        }
+    }
+}
+
+/// Storage usage and wear statistics, obtained from [`StorageService::stats`].
+#[derive(Debug, Clone)]
+pub struct StorageStats {
+    /// Total amount of space available to the database, in bytes. This excludes the header at the
+    /// start of each page (see [`PAGE_HEADER_LEN`]).
+    pub total_bytes: usize,
+    /// Number of bytes taken up by stored objects, including invalidated objects.
+    pub used_bytes: usize,
+    /// Number of bytes available for new objects, without needing garbage collection.
+    pub free_bytes: usize,
+    /// Number of pages (regions) that do not contain any objects.
+    pub free_regions: usize,
+    /// Number of invalidated keys that are awaiting garbage collection.
+    pub invalidated_keys: usize,
+    /// Number of bytes that will be reclaimed by garbage collection.
+    pub invalidated_bytes: usize,
+    /// Number of pages in the config partition.
+    pub pages: usize,
+    /// Lowest number of times that a page in the config partition has been erased. Erase counts
+    /// are stored in flash, so they cover the whole lifetime of the config partition.
+    pub min_erase_count: u32,
+    /// Highest number of times that a page in the config partition has been erased. The erase
+    /// count of each page can be obtained with [`StorageService::erase_count`].
+    pub max_erase_count: u32,
+    /// Index of the page with the highest erase count.
+    pub most_erased_page: usize,
+}
+
 /// A wrapper around a TicKV instance which allows you to receive requests to read, write or delete
 /// data from a storage peripheral.
 pub struct StorageService<'a, F: FlashStorage>
 where
     [(); F::ERASE_SIZE]:,
+    [(); F::REGION_SIZE]:,
 {
-    database: OnceCell<Mutex<RawMutex, AsyncTicKV<'a, FlashDevice<'a, F>, { F::ERASE_SIZE }>>>,
+    database: OnceCell<Mutex<RawMutex, AsyncTicKV<'a, FlashDevice<'a, F>, { F::REGION_SIZE }>>>,
+    invalidations: BlockingMutex<Cell<usize>>,
+    gc_signal: Signal<RawMutex, ()>,
+    flush_channel: OnceCell<&'a dyn FlushTarget>,
+    /// Last value of the nonce counter that was saved to the database, or `None` if it hasn't
+    /// been read yet.
+    #[cfg(feature = "storage-encryption")]
+    nonce_counter: BlockingMutex<Cell<Option<u64>>>,
+}
+
+/// Channel used by [`StorageService::flush`] to tell every persisted state to save its pending
+/// changes. Each state persisted with [`StorageService::persist_state`] takes up one of the
+/// `SUBS` subscriber slots. The `#[keyboard]` macro creates this channel with one slot for each
+/// storage task that it spawns.
+pub type FlushChannel<const SUBS: usize> = PubSubChannel<RawMutex, (), 1, SUBS, 1>;
+
+/// A [`FlushChannel`] of any size.
+trait FlushTarget: Sync {
+    fn subscriber(&self) -> Option<DynSubscriber<'_, ()>>;
+    fn flush(&self);
+}
+
+impl<const SUBS: usize> FlushTarget for FlushChannel<SUBS> {
+    fn subscriber(&self) -> Option<DynSubscriber<'_, ()>> {
+        self.dyn_subscriber().ok()
+    }
+
+    fn flush(&self) {
+        self.immediate_publisher().publish_immediate(());
+    }
+}
+
+/// Wait until a save is requested using `save_signal`, or [`StorageService::flush`].
+async fn wait_for_forced_save(
+    save_signal: &Signal<RawMutex, ()>,
+    flush_subscriber: &mut Option<DynSubscriber<'_, ()>>,
+) {
+    match flush_subscriber {
+        Some(subscriber) => {
+            select(save_signal.wait(), subscriber.next_message_pure()).await;
+        }
+        None => save_signal.wait().await,
+    }
 }
 
 impl<'a, F: FlashStorage> StorageService<'a, F>
 where
     [(); F::ERASE_SIZE]:,
+    [(); F::REGION_SIZE]:,
 {
     /// Create a new instance of a [`StorageService`]. You should call [`StorageService::setup()`]
     /// before calling any other methods.
     pub const fn new() -> Self {
         StorageService {
             database: OnceCell::new(),
+            invalidations: BlockingMutex::new(Cell::new(0)),
+            gc_signal: Signal::new(),
+            flush_channel: OnceCell::new(),
+            #[cfg(feature = "storage-encryption")]
+            nonce_counter: BlockingMutex::new(Cell::new(None)),
         }
     }
 
     async fn get_database(
         &self,
-    ) -> MutexGuard<RawMutex, AsyncTicKV<'a, FlashDevice<'a, F>, { F::ERASE_SIZE }>> {
+    ) -> MutexGuard<RawMutex, AsyncTicKV<'a, FlashDevice<'a, F>, { F::REGION_SIZE }>> {
         let mutex = self
             .database
             .get()
@@ -101,22 +449,221 @@ where
         mutex.lock().await
     }
 
+    /// Record that a key has been invalidated, and notify [`storage_gc_task`] so that it can
+    /// reclaim the space later.
+    fn notify_invalidation(&self) {
+        self.invalidations.lock(|count| count.set(count.get() + 1));
+        self.gc_signal.signal(());
+    }
+
+    /// Delete data that failed validation, so that it can be replaced with default values.
+    async fn discard_corrupted(
+        &self,
+        database: &mut AsyncTicKV<'a, FlashDevice<'a, F>, { F::REGION_SIZE }>,
+        key: StorageKey,
+    ) {
+        warn!(
+            "[STORAGE] Stored {} data is corrupted, deleting it.",
+            Debug2Format(&key)
+        );
+        let _ = invalidate_key(database, &[key as u8, StorageKeyType::Data as u8]).await;
+        self.notify_invalidation();
+    }
+
+    /// Obtain the nonce used to encrypt a new value for `key`, or 0 if `key` is not encrypted.
+    ///
+    /// Nonces are generated from a counter, which is saved to the database before the nonce is
+    /// returned. This guarantees that a nonce is never used twice with the same encryption key,
+    /// even after the keyboard restarts. Reusing a nonce would allow two stored values to be
+    /// compared, revealing information about their contents.
+    #[cfg_attr(not(feature = "storage-encryption"), allow(unused_variables))]
+    async fn next_nonce(
+        &self,
+        database: &mut AsyncTicKV<'a, FlashDevice<'a, F>, { F::REGION_SIZE }>,
+        key: StorageKey,
+    ) -> Result<u64, StorageError> {
+        #[cfg(feature = "storage-encryption")]
+        if key.is_encrypted() {
+            let next = self.last_nonce(database).await? + 1;
+            self.save_nonce_counter(database, next).await?;
+            return Ok(next);
+        }
+
+        Ok(0)
+    }
+
+    /// Obtain the last nonce that was used to encrypt a value.
+    #[cfg(feature = "storage-encryption")]
+    async fn last_nonce(
+        &self,
+        database: &mut AsyncTicKV<'a, FlashDevice<'a, F>, { F::REGION_SIZE }>,
+    ) -> Result<u64, StorageError> {
+        if let Some(last) = self.nonce_counter.lock(Cell::get) {
+            return Ok(last);
+        }
+
+        let mut last = 0;
+        for slot in 0..2 {
+            let buf = encryption::counter_buffer();
+            match get_key(database, &nonce_counter_key(slot), buf).await {
+                (Ok(_), Some(buf), len) if len == encryption::NONCE_LEN => {
+                    last = last.max(u64::from_le_bytes(
+                        buf[..encryption::NONCE_LEN].try_into().unwrap(),
+                    ));
+                }
+                (Err(ErrorCode::KeyNotFound), _, _) => {}
+                (result, _, _) => {
+                    // If the counter can't be read, we can't know which nonces have already been
+                    // used, so encrypted data can't be written until storage is reset
+                    error!(
+                        "[STORAGE] Could not read the nonce counter, encrypted data will not be written: {}",
+                        Debug2Format(&result)
+                    );
+                    return Err(
+                        result.map_or_else(StorageError::from, |_code| StorageError::Corrupted)
+                    );
+                }
+            }
+        }
+
+        Ok(last)
+    }
+
+    /// Save the nonce counter, so that nonces up to `value` are never used again.
+    #[cfg(feature = "storage-encryption")]
+    async fn save_nonce_counter(
+        &self,
+        database: &mut AsyncTicKV<'a, FlashDevice<'a, F>, { F::REGION_SIZE }>,
+        value: u64,
+    ) -> Result<(), StorageError> {
+        // The counter alternates between two slots, so that the last saved value is still
+        // available if the keyboard loses power while the other slot is being replaced
+        let buf = encryption::counter_buffer();
+        buf.copy_from_slice(&value.to_le_bytes());
+
+        let _ = invalidate_key(database, &nonce_counter_key(value % 2)).await;
+        self.notify_invalidation();
+        append_key_or_collect(
+            database,
+            &nonce_counter_key(value % 2),
+            buf,
+            encryption::NONCE_LEN,
+        )
+        .await
+        .0
+        .map_err(|error| {
+            error!(
+                "[STORAGE] Could not save the nonce counter: {}",
+                Debug2Format(&error)
+            );
+            StorageError::from(error)
+        })?;
+        self.nonce_counter.lock(|counter| counter.set(Some(value)));
+
+        Ok(())
+    }
+
+    /// Immediately save any pending changes to states persisted with
+    /// [`StorageService::persist_state`], ignoring the save policy defined in [`StorageDevice`].
+    /// This can be used to make sure that changes are saved before powering down.
+    pub fn flush(&self) {
+        if let Some(flush_channel) = self.flush_channel.get() {
+            flush_channel.flush();
+        }
+    }
+
+    /// Run garbage collection on the database, reclaiming space taken up by invalidated keys.
+    /// Normally, garbage collection is run by [`storage_gc_task`] when the storage service is idle,
+    /// so you don't need to call this yourself.
+    pub async fn garbage_collect(&self) -> Result<(), StorageError> {
+        let mut database = self.get_database().await;
+
+        debug!("[STORAGE] Running garbage collection.");
+
+        let result = garbage_collect(&mut database).await.0.map_err(|error| {
+            error!(
+                "[STORAGE] Garbage collection error: {}",
+                Debug2Format(&error)
+            );
+            StorageError::from(error)
+        });
+        self.invalidations.lock(|count| count.set(0));
+
+        result.map(|_code| {})
+    }
+
+    /// Erase all of the data in the config partition, and re-initialize the database. Features
+    /// that use storage will keep their current settings in memory until the keyboard is reset
+    /// (see [`crate::hw::reset`]), after which they will use their default settings.
+    ///
+    /// To erase all data and reset the keyboard at once, you can signal [`FACTORY_RESET_SIGNAL`],
+    /// or use [`crate::keyboard::Keycode::FactoryReset`] in your layout.
+    pub async fn factory_reset(&self) -> Result<(), StorageError> {
+        let mut database = self.get_database().await;
+
+        warn!("[STORAGE] Erasing all stored data.");
+
+        format(&mut database).await.map_err(|error| {
+            error!(
+                "[STORAGE] Could not erase the config partition: {}",
+                Debug2Format(&error)
+            );
+            StorageError::Flash
+        })?;
+        self.invalidations.lock(|count| count.set(0));
+
+        initialise(&mut database)
+            .await
+            .map(|_code| {})
+            .map_err(|error| {
+                error!(
+                    "[STORAGE] Could not initialize the database: {}",
+                    Debug2Format(&error)
+                );
+                StorageError::from(error)
+            })
+    }
+
     /// Set up the storage service with the provided flash peripheral and buffers. The storage
     /// service will only operate on the flash addresses between `config_start` and `config_end`.
-    pub async fn setup(
+    /// `op_buf` must be able to hold a whole page, while `read_buf` only needs to hold a TicKV
+    /// region, which excludes the page header (see [`FlashStorage::REGION_SIZE`]).
+    ///
+    /// `flush_channel` must have a subscriber slot for each state that will be persisted with
+    /// this storage service. See [`FlushChannel`].
+    pub async fn setup<const SUBS: usize>(
         &self,
         flash: F,
         config_start: usize,
         config_end: usize,
-        read_buf: &'a mut [u8; F::ERASE_SIZE],
+        read_buf: &'a mut [u8; F::REGION_SIZE],
         op_buf: &'a mut [u8; F::ERASE_SIZE],
+        flush_channel: &'a FlushChannel<SUBS>,
     ) {
+        self.flush_channel.get_or_init(|| flush_channel);
+
         let driver = FlashDevice::new(flash, config_start, config_end, op_buf);
-        let flash_size = driver.end - driver.start;
+        // TicKV doesn't have access to the page headers, so its regions are slightly smaller than
+        // the pages of the config partition
+        let flash_size = driver.pages() * F::REGION_SIZE;
         let mut database = AsyncTicKV::new(driver, read_buf, flash_size);
 
         // Initialize the database, formatting if needed
-        initialise(&mut database).await.unwrap();
+        if let Err(error) = initialise(&mut database).await {
+            // Reformat the config partition instead of panicking, so that the keyboard can still
+            // boot. Features using storage will fall back to their default values.
+            error!(
+                "[STORAGE] Could not initialize the database, reformatting the config partition: {}",
+                Debug2Format(&error)
+            );
+            if format(&mut database).await.is_err() || initialise(&mut database).await.is_err() {
+                error!("[STORAGE] Could not recover the database, storage will not be available.");
+            } else {
+                warn!(
+                    "[STORAGE] Config partition has been reformatted, stored data has been reset."
+                );
+            }
+        }
 
         self.database.get_or_init(|| Mutex::new(database));
     }
@@ -129,7 +676,7 @@ where
         buffer: &'static mut [u8],
         key: StorageKey,
         current_metadata: &[u8],
-    ) -> Result<(), ()> {
+    ) -> Result<(), StorageError> {
         let mut database = self.get_database().await;
 
         // Verify if the underlying data type has changed since last boot
@@ -175,11 +722,11 @@ where
             // Invalidate old data
             let _ =
                 invalidate_key(&mut database, &[key as u8, StorageKeyType::Metadata as u8]).await;
-            garbage_collect(&mut database).await.0.unwrap();
+            self.notify_invalidation();
 
             // Add new metadata
             let length = current_metadata.len();
-            append_key(
+            append_key_or_collect(
                 &mut database,
                 &[key as u8, StorageKeyType::Metadata as u8],
                 buf,
@@ -187,19 +734,131 @@ where
             )
             .await
             .0
-            .unwrap();
+            .map_err(|error| {
+                error!(
+                    "[STORAGE] Could not update metadata for {}: {}",
+                    Debug2Format(&key),
+                    Debug2Format(&error)
+                );
+                StorageError::from(error)
+            })?;
         }
 
         Ok(())
     }
 
+    /// This function checks the stored schema version for the given key. If the stored version
+    /// differs from `current_version`, the stored data is passed to [`StorageDevice::migrate_data`]
+    /// so that it can be converted to the current schema. If the data can't be migrated, it is
+    /// deleted, and the feature using it will fall back to its default values.
+    pub(crate) async fn check_schema_version<K: StorageDevice>(
+        &self,
+        key: StorageKey,
+        current_version: u16,
+    ) -> Result<(), StorageError> {
+        let mut database = self.get_database().await;
+
+        let (result, buf, len) = get_key(
+            &mut database,
+            &[key as u8, StorageKeyType::Metadata as u8],
+            K::get_storage_buffer(),
+        )
+        .await;
+
+        let stored_version = match (result, buf) {
+            (Ok(_), Some(buf)) if len == 2 => Some(u16::from_be_bytes([buf[0], buf[1]])),
+            // Metadata stored by older firmware (without a schema version) is treated as version 0
+            (Ok(_), _) => Some(0),
+            (Err(_), _) => None,
+        };
+
+        if stored_version == Some(current_version) {
+            return Ok(());
+        }
+
+        // Attempt to migrate the stored data to the current schema
+        let (result, buf, len) = get_key(
+            &mut database,
+            &[key as u8, StorageKeyType::Data as u8],
+            K::get_storage_buffer(),
+        )
+        .await;
+
+        let migrated = match (stored_version, result, buf) {
+            (Some(stored_version), Ok(_), Some(buf)) => {
+                warn!(
+                    "[STORAGE] Schema version for {} has changed ({} -> {}), migrating data.",
+                    Debug2Format(&key),
+                    stored_version,
+                    current_version
+                );
+                K::migrate_data(key, stored_version, buf, len).map(|new_len| (buf, new_len))
+            }
+            _ => None,
+        };
+
+        let _ = invalidate_key(&mut database, &[key as u8, StorageKeyType::Data as u8]).await;
+        self.notify_invalidation();
+
+        match migrated {
+            Some((buf, new_len)) => {
+                append_key_or_collect(
+                    &mut database,
+                    &[key as u8, StorageKeyType::Data as u8],
+                    buf,
+                    new_len,
+                )
+                .await
+                .0
+                .map_err(|error| {
+                    error!(
+                        "[STORAGE] Could not write migrated data for {}: {}",
+                        Debug2Format(&key),
+                        Debug2Format(&error)
+                    );
+                    StorageError::from(error)
+                })?;
+            }
+            None if stored_version.is_some() => {
+                warn!(
+                    "[STORAGE] Could not migrate {} data, deleting old data.",
+                    Debug2Format(&key),
+                );
+            }
+            None => {}
+        }
+
+        // Update the stored schema version
+        let _ = invalidate_key(&mut database, &[key as u8, StorageKeyType::Metadata as u8]).await;
+        self.notify_invalidation();
+        let buf = K::get_storage_buffer();
+        buf[..2].copy_from_slice(&current_version.to_be_bytes());
+        append_key_or_collect(
+            &mut database,
+            &[key as u8, StorageKeyType::Metadata as u8],
+            buf,
+            2,
+        )
+        .await
+        .0
+        .map_err(|error| {
+            error!(
+                "[STORAGE] Could not update schema version for {}: {}",
+                Debug2Format(&key),
+                Debug2Format(&error)
+            );
+            StorageError::from(error)
+        })
+        .map(|_code| {})
+    }
+
     /// Read and deserialize data from the storage peripheral, using the given
     /// key to look it up. Uses [`postcard`] for deserialization.
     pub async fn read<T: DeserializeOwned>(
         &self,
         buffer: &'static mut [u8],
         key: StorageKey,
-    ) -> Result<T, ()> {
+    ) -> Result<T, StorageError> {
         let mut database = self.get_database().await;
 
         info!(
@@ -214,26 +873,30 @@ where
         )
         .await;
 
-        result
-            .map_err(|error| {
+        let result = result.map_err(|error| {
+            error!(
+                "[STORAGE] Read error for {}: {}",
+                Debug2Format(&<StorageKey as num::FromPrimitive>::from_u8(key as u8).unwrap()),
+                Debug2Format(&error)
+            );
+            StorageError::from(error)
+        });
+
+        if matches!(result, Err(StorageError::Corrupted)) {
+            self.discard_corrupted(&mut database, key).await;
+        }
+
+        result.and_then(|_code| match buf {
+            Some(buf) => postcard::from_bytes(key.decrypt(&mut buf[..len])?).map_err(|error| {
                 error!(
-                    "[STORAGE] Read error for {}: {}",
+                    "[STORAGE] Deserialization error while reading {}: {}",
                     Debug2Format(&<StorageKey as num::FromPrimitive>::from_u8(key as u8).unwrap()),
                     Debug2Format(&error)
                 );
-            })
-            .and_then(|_code| match buf {
-                Some(buf) => postcard::from_bytes(&buf[..len]).map_err(|error| {
-                    error!(
-                        "[STORAGE] Deserialization error while reading {}: {}",
-                        Debug2Format(
-                            &<StorageKey as num::FromPrimitive>::from_u8(key as u8).unwrap()
-                        ),
-                        Debug2Format(&error)
-                    );
-                }),
-                None => unreachable!(),
-            })
+                StorageError::Deserialization
+            }),
+            None => unreachable!(),
+        })
     }
 
     /// Read data from the storage peripheral, using the given key to look it up. This skips the
@@ -242,7 +905,7 @@ where
         &self,
         buffer: &'static mut [u8],
         key: StorageKey,
-    ) -> Result<(&[u8], usize), ()> {
+    ) -> Result<(&[u8], usize), StorageError> {
         let mut database = self.get_database().await;
 
         info!(
@@ -257,15 +920,24 @@ where
         )
         .await;
 
-        result
-            .map_err(|error| {
-                error!(
-                    "[STORAGE] Read error for {}: {}",
-                    Debug2Format(&<StorageKey as num::FromPrimitive>::from_u8(key as u8).unwrap()),
-                    Debug2Format(&error)
-                );
-            })
-            .map(|_code| (&*buf.unwrap(), len))
+        let result = result.map_err(|error| {
+            error!(
+                "[STORAGE] Read error for {}: {}",
+                Debug2Format(&<StorageKey as num::FromPrimitive>::from_u8(key as u8).unwrap()),
+                Debug2Format(&error)
+            );
+            StorageError::from(error)
+        });
+
+        if matches!(result, Err(StorageError::Corrupted)) {
+            self.discard_corrupted(&mut database, key).await;
+        }
+
+        result.and_then(|_code| {
+            let data = key.decrypt(&mut buf.unwrap()[..len])?;
+            let data_len = data.len();
+            Ok((&*data, data_len))
+        })
     }
 
     /// Write data to the storage peripheral, at the given key. This will serialize the given data
@@ -275,7 +947,7 @@ where
         buffer: &'static mut [u8],
         key: StorageKey,
         data: T,
-    ) -> Result<(), ()> {
+    ) -> Result<(), StorageError> {
         let mut database = self.get_database().await;
 
         info!(
@@ -283,16 +955,21 @@ where
             Debug2Format(&<StorageKey as num::FromPrimitive>::from_u8(key as u8).unwrap()),
         );
 
-        let result = match postcard::to_slice(&data, buffer) {
-            Ok(serialized) => {
+        let nonce = self.next_nonce(&mut database, key).await?;
+        let offset = key.data_offset();
+        let end = buffer.len().saturating_sub(key.tag_len()).max(offset);
+        let result = match postcard::to_slice(&data, &mut buffer[offset..end]).map(|s| s.len()) {
+            Ok(serialized_len) => {
+                let len = key.encrypt(buffer, offset + serialized_len, nonce)?;
+
                 let _ =
                     invalidate_key(&mut database, &[key as u8, StorageKeyType::Data as u8]).await;
-                garbage_collect(&mut database).await.0.unwrap();
-                append_key(
+                self.notify_invalidation();
+                append_key_or_collect(
                     &mut database,
                     &[key as u8, StorageKeyType::Data as u8],
-                    serialized,
-                    serialized.len(),
+                    buffer,
+                    len,
                 )
                 .await
                 .0
@@ -304,6 +981,7 @@ where
                         ),
                         Debug2Format(&error)
                     );
+                    StorageError::from(error)
                 })
             }
             Err(error) => {
@@ -312,7 +990,7 @@ where
                     Debug2Format(&<StorageKey as num::FromPrimitive>::from_u8(key as u8).unwrap()),
                     Debug2Format(&error)
                 );
-                Err(())
+                Err(StorageError::Serialization)
             }
         };
 
@@ -326,7 +1004,7 @@ where
         buffer: &'static mut [u8],
         key: StorageKey,
         data: &[u8],
-    ) -> Result<(), ()> {
+    ) -> Result<(), StorageError> {
         let mut database = self.get_database().await;
 
         info!(
@@ -334,15 +1012,22 @@ where
             Debug2Format(&<StorageKey as num::FromPrimitive>::from_u8(key as u8).unwrap()),
         );
 
-        buffer[..data.len()].copy_from_slice(data);
+        let offset = key.data_offset();
+        let len = offset + data.len();
+        if buffer.len() < len + key.tag_len() {
+            return Err(StorageError::BufferTooSmall);
+        }
+        let nonce = self.next_nonce(&mut database, key).await?;
+        buffer[offset..len].copy_from_slice(data);
+        let len = key.encrypt(buffer, len, nonce)?;
 
         let _ = invalidate_key(&mut database, &[key as u8, StorageKeyType::Data as u8]).await;
-        garbage_collect(&mut database).await.0.unwrap();
-        let result = append_key(
+        self.notify_invalidation();
+        let result = append_key_or_collect(
             &mut database,
             &[key as u8, StorageKeyType::Data as u8],
             buffer,
-            data.len(),
+            len,
         )
         .await
         .0
@@ -352,49 +1037,448 @@ where
                 Debug2Format(&<StorageKey as num::FromPrimitive>::from_u8(key as u8).unwrap()),
                 Debug2Format(&error)
             );
+            StorageError::from(error)
         });
 
         result.map(|_code| {})
     }
 
-    /// Deletes the data at a given key.
-    pub async fn delete(&self, key: StorageKey) -> Result<(), ()> {
-        let mut database = self.get_database().await;
-
-        info!(
-            "[STORAGE] Deleting {} data.",
-            Debug2Format(&<StorageKey as num::FromPrimitive>::from_u8(key as u8).unwrap()),
-        );
+    /// Load the stored value for the given [`State`], and save it back to storage any time it
+    /// changes. To protect the flash from excessive writes, the value is only written once it
+    /// hasn't changed for [`StorageDevice::SAVE_IDLE_TIMEOUT_MS`], and at most once every
+    /// [`StorageDevice::SAVE_MIN_INTERVAL_MS`]. If `save_signal` is signalled, or
+    /// [`StorageService::flush`] is called, the value is written immediately.
+    ///
+    /// `state_listener` must be one of the listeners registered to `state`. If the schema version
+    /// of the stored data differs from [`StoredData::SCHEMA_VERSION`], the data is migrated using
+    /// [`StorageDevice::migrate_data`]. This should be used by storage tasks for any feature that
+    /// needs to persist its state.
+    pub(crate) async fn persist_state<K: StorageDevice, T>(
+        &self,
+        key: StorageKey,
+        state: &State<'_, T>,
+        state_listener: &Signal<RawMutex, ()>,
+        save_signal: &Signal<RawMutex, ()>,
+    ) -> !
+    where
+        T: StoredData + Clone + PartialEq + Debug,
+    {
+        let get_buffer = K::get_storage_buffer;
 
-        let result = invalidate_key(&mut database, &[key as u8, StorageKeyType::Data as u8])
-            .await
-            .0
+        {
+            // Check the stored schema version, migrating the stored data if it has changed
+            let _ = self.check_schema_version::<K>(key, T::SCHEMA_VERSION).await;
+
+            // Get the stored value
+            match self.read(get_buffer(), key).await {
+                Ok(value) => {
+                    info!(
+                        "[STORAGE] Obtained {} data from storage: {}",
+                        Debug2Format(&key),
+                        Debug2Format(&value)
+                    );
+                    // Notify other listeners so that they pick up the stored value, but clear our
+                    // own listener so that we don't save the value to storage again
+                    state.set(value).await;
+                    state_listener.reset();
+                }
+                Err(StorageError::KeyNotFound) => {
+                    info!(
+                        "[STORAGE] No {} data stored yet, using default value.",
+                        Debug2Format(&key)
+                    );
+                }
+                Err(error) => {
+                    warn!(
+                        "[STORAGE] Could not get {} data from storage, using default value: {}",
+                        Debug2Format(&key),
+                        Debug2Format(&error)
+                    );
+                }
+            }
+        }
+
+        let idle_timeout = Duration::from_millis(K::SAVE_IDLE_TIMEOUT_MS as u64);
+        let min_interval = Duration::from_millis(K::SAVE_MIN_INTERVAL_MS as u64);
+
+        // Without a subscriber, the value is still saved, but `flush` won't save it immediately
+        let mut flush_subscriber = self
+            .flush_channel
+            .get()
+            .and_then(|flush_channel| flush_channel.subscriber());
+        if flush_subscriber.is_none() {
+            warn!(
+                "[STORAGE] No flush channel slots left, {} data will not be saved when flushing.",
+                Debug2Format(&key)
+            );
+        }
+
+        let mut last_saved = state.get().await;
+        let mut last_save_time: Option<Instant> = None;
+
+        loop {
+            let forced = match select(
+                wait_for_forced_save(save_signal, &mut flush_subscriber),
+                state_listener.wait(),
+            )
+            .await
+            {
+                Either::First(_) => true,
+                Either::Second(_) => {
+                    // Wait for the value to stop changing
+                    let forced = loop {
+                        match select3(
+                            Timer::after(idle_timeout),
+                            wait_for_forced_save(save_signal, &mut flush_subscriber),
+                            state_listener.wait(),
+                        )
+                        .await
+                        {
+                            Either3::First(_) => break false,
+                            Either3::Second(_) => break true,
+                            Either3::Third(_) => {}
+                        }
+                    };
+
+                    // Limit how often the value gets written
+                    match last_save_time {
+                        Some(last_save_time)
+                            if !forced && last_save_time + min_interval > Instant::now() =>
+                        {
+                            match select(
+                                Timer::at(last_save_time + min_interval),
+                                wait_for_forced_save(save_signal, &mut flush_subscriber),
+                            )
+                            .await
+                            {
+                                Either::First(_) => false,
+                                Either::Second(_) => true,
+                            }
+                        }
+                        _ => forced,
+                    }
+                }
+            };
+
+            let value = state.get().await;
+            if value == last_saved {
+                debug!(
+                    "[STORAGE] {} data has not changed, skipping save (forced = {}).",
+                    Debug2Format(&key),
+                    forced
+                );
+                continue;
+            }
+
+            if self.write(get_buffer(), key, value.clone()).await.is_ok() {
+                last_saved = value;
+                last_save_time = Some(Instant::now());
+            }
+        }
+    }
+
+    /// Size of a backup of the config partition, in bytes. This includes the backup header (see
+    /// [`BACKUP_HEADER_LEN`]).
+    pub async fn backup_size(&self) -> usize {
+        BACKUP_HEADER_LEN + self.partition_writer().await.size()
+    }
+
+    /// Read part of a backup of the config partition, starting at `offset`. A backup starts with a
+    /// header containing the size of the config partition, and a CRC of its contents, followed by
+    /// the raw contents of the config partition. Backups can be restored with
+    /// [`StorageService::restore_backup`].
+    ///
+    /// The CRC is calculated whenever the header is read, so if stored data changes while a backup
+    /// is being read, the backup will fail validation when it is restored. To avoid this, read the
+    /// header again once the rest of the backup has been read, and check that it has not changed.
+    pub async fn read_backup(&self, offset: usize, buf: &mut [u8]) -> Result<(), StorageError> {
+        let mut writer = self.partition_writer().await;
+        let partition_size = writer.size();
+
+        if offset + buf.len() > BACKUP_HEADER_LEN + partition_size {
+            return Err(StorageError::InvalidAddress);
+        }
+
+        let mut read = 0;
+        if offset < BACKUP_HEADER_LEN {
+            let header = BackupHeader {
+                length: partition_size,
+                crc: writer.crc().await?,
+            }
+            .to_bytes();
+            read = (BACKUP_HEADER_LEN - offset).min(buf.len());
+            buf[..read].copy_from_slice(&header[offset..(offset + read)]);
+        }
+
+        if read < buf.len() {
+            writer
+                .read(offset + read - BACKUP_HEADER_LEN, &mut buf[read..])
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Start restoring a backup obtained with [`StorageService::read_backup`]. `header` must
+    /// contain the first [`BACKUP_HEADER_LEN`] bytes of the backup. The header is validated, and
+    /// checked against the size of this keyboard's config partition, but nothing is erased until
+    /// the rest of the backup has also been validated. See [`BackupRestore`].
+    pub async fn restore_backup(
+        &self,
+        header: &[u8],
+    ) -> Result<BackupRestore<'_, 'a, F>, StorageError> {
+        let Some(header) = BackupHeader::from_bytes(header) else {
+            warn!("[STORAGE] Backup header is invalid, or was made by an incompatible version.");
+            return Err(StorageError::InvalidBackup);
+        };
+
+        let partition_size = self.partition_writer().await.size();
+        if header.length != partition_size {
+            warn!(
+                "[STORAGE] Backup is for a {} byte config partition, but this config partition is {} bytes.",
+                header.length, partition_size
+            );
+            return Err(StorageError::InvalidBackup);
+        }
+
+        Ok(BackupRestore {
+            storage: self,
+            header,
+            crc: Crc32::new(),
+            offset: BACKUP_HEADER_LEN,
+            verified: false,
+            writer: None,
+            #[cfg(feature = "storage-encryption")]
+            last_nonce: None,
+        })
+    }
+
+    /// Obtain exclusive access to the config partition. The database stays locked until the
+    /// returned [`PartitionWriter`] is dropped, so storage tasks and [`storage_gc_task`] can not
+    /// modify the partition while it is being read or restored.
+    async fn partition_writer(&self) -> PartitionWriter<'_, 'a, F> {
+        PartitionWriter {
+            database: self.get_database().await,
+        }
+    }
+
+    /// Obtain usage and wear statistics for the storage peripheral. This can be used to decide how
+    /// large your config partition should be, or to spot pages that are being erased too often.
+    pub async fn stats(&self) -> Result<StorageStats, StorageError> {
+        let mut database = self.get_database().await;
+        let controller = &mut database.tickv.controller;
+
+        let mut stats = StorageStats {
+            total_bytes: controller.pages() * F::REGION_SIZE,
+            used_bytes: 0,
+            free_bytes: 0,
+            free_regions: 0,
+            invalidated_keys: 0,
+            invalidated_bytes: 0,
+            pages: controller.pages(),
+            min_erase_count: u32::MAX,
+            max_erase_count: 0,
+            most_erased_page: 0,
+        };
+
+        for page in 0..controller.pages() {
+            if controller.read(page).await.is_err() {
+                return Err(StorageError::Flash);
+            }
+
+            let op_buf = controller.op_buf.borrow();
+
+            let erase_count = parse_page_header(&op_buf[..PAGE_HEADER_LEN]);
+            stats.min_erase_count = stats.min_erase_count.min(erase_count);
+            if erase_count > stats.max_erase_count {
+                stats.max_erase_count = erase_count;
+                stats.most_erased_page = page;
+            }
+
+            let region = &op_buf[PAGE_HEADER_LEN..];
+            let mut offset = 0;
+
+            // Walk through the objects stored in the region. Refer to the TicKV spec for the object
+            // layout: https://github.com/tock/tock/blob/master/libraries/tickv/SPEC.md
+            while offset + OBJECT_HEADER_LEN <= F::REGION_SIZE {
+                let version = region[offset];
+                if version == 0xFF {
+                    // Erased flash, no more objects in this region
+                    break;
+                }
+
+                let flags = region[offset + 1] >> 4;
+                let len =
+                    (((region[offset + 1] & 0x0F) as usize) << 8) | region[offset + 2] as usize;
+                if len == 0 {
+                    break;
+                }
+
+                if flags & OBJECT_FLAG_VALID == 0 {
+                    stats.invalidated_keys += 1;
+                    stats.invalidated_bytes += len;
+                }
+
+                offset += len;
+            }
+
+            let offset = offset.min(F::REGION_SIZE);
+            if offset == 0 {
+                stats.free_regions += 1;
+            }
+            stats.used_bytes += offset;
+            stats.free_bytes += F::REGION_SIZE - offset;
+        }
+
+        Ok(stats)
+    }
+
+    /// Obtain the number of times that a page in the config partition has been erased. Erase
+    /// counts are stored in the header of each page, so they are kept after the keyboard restarts,
+    /// and after a factory reset.
+    pub async fn erase_count(&self, page: usize) -> Result<u32, StorageError> {
+        let mut database = self.get_database().await;
+        let controller = &mut database.tickv.controller;
+
+        if page >= controller.pages() {
+            return Err(StorageError::InvalidAddress);
+        }
+
+        controller
+            .erase_count(page)
+            .await
+            .map_err(|_error| StorageError::Flash)
+    }
+
+    /// Log the current usage and wear statistics for the storage peripheral. See
+    /// [`StorageService::stats`]. The erase count of each page is also logged.
+    pub async fn log_stats(&self) {
+        match self.stats().await {
+            Ok(stats) => {
+                info!(
+                    "[STORAGE] Usage: {}/{} bytes used, {} bytes free, {} free regions",
+                    stats.used_bytes, stats.total_bytes, stats.free_bytes, stats.free_regions
+                );
+                info!(
+                    "[STORAGE] {} invalidated keys ({} bytes) awaiting garbage collection",
+                    stats.invalidated_keys, stats.invalidated_bytes
+                );
+                info!(
+                    "[STORAGE] Erase cycles: {} to {} per page, page {} is the most erased",
+                    stats.min_erase_count, stats.max_erase_count, stats.most_erased_page
+                );
+                for page in 0..stats.pages {
+                    if let Ok(count) = self.erase_count(page).await {
+                        info!("[STORAGE] Page {}: {} erase cycles", page, count);
+                    }
+                }
+            }
+            Err(error) => {
+                error!(
+                    "[STORAGE] Could not obtain storage statistics: {}",
+                    Debug2Format(&error)
+                );
+            }
+        }
+    }
+
+    /// Deletes the data at a given key.
+    pub async fn delete(&self, key: StorageKey) -> Result<(), StorageError> {
+        let mut database = self.get_database().await;
+
+        info!(
+            "[STORAGE] Deleting {} data.",
+            Debug2Format(&<StorageKey as num::FromPrimitive>::from_u8(key as u8).unwrap()),
+        );
+
+        let result = invalidate_key(&mut database, &[key as u8, StorageKeyType::Data as u8])
+            .await
+            .0
             .map_err(|error| {
                 error!("[STORAGE] Delete error: {}", Debug2Format(&error));
+                StorageError::from(error)
             });
-        garbage_collect(&mut database).await.0.unwrap();
+        self.notify_invalidation();
 
         result.map(|_code| {})
     }
 }
 
+/// Signal used to erase all stored data and reset the keyboard. This is handled by
+/// [`storage_gc_task`], which calls [`StorageService::factory_reset`].
+pub static FACTORY_RESET_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+/// Signal used to request storage statistics from [`storage_gc_task`]. The statistics will be
+/// signalled using [`STORAGE_STATS_SIGNAL`].
+pub(crate) static STORAGE_STATS_REQUEST_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+/// Signal containing the storage statistics requested with [`STORAGE_STATS_REQUEST_SIGNAL`].
+pub(crate) static STORAGE_STATS_SIGNAL: Signal<RawMutex, Result<StorageStats, StorageError>> =
+    Signal::new();
+
+/// Number of invalidated keys that can accumulate before garbage collection is run, even if the
+/// storage service is still busy.
+const GC_INVALIDATION_THRESHOLD: usize = 8;
+
+/// Amount of time that the storage service must be idle for before garbage collection is run.
+const GC_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Task that runs garbage collection on a [`StorageService`] in the background. Garbage collection
+/// is deferred until no keys have been invalidated for [`GC_IDLE_TIMEOUT`], or until more than
+/// [`GC_INVALIDATION_THRESHOLD`] keys have been invalidated, so that writes don't have to wait
+/// for it.
+///
+/// This task also handles [`FACTORY_RESET_SIGNAL`] and [`STORAGE_STATS_REQUEST_SIGNAL`].
+#[rumcake_macros::task]
+pub async fn storage_gc_task<F: FlashStorage>(database: &StorageService<'static, F>)
+where
+    [(); F::ERASE_SIZE]:,
+    [(); F::REGION_SIZE]:,
+{
+    loop {
+        match select3(
+            database.gc_signal.wait(),
+            FACTORY_RESET_SIGNAL.wait(),
+            STORAGE_STATS_REQUEST_SIGNAL.wait(),
+        )
+        .await
+        {
+            Either3::First(()) => {
+                // Wait until the storage service is idle, or until too many keys have been invalidated
+                while database.invalidations.lock(Cell::get) < GC_INVALIDATION_THRESHOLD {
+                    match select(Timer::after(GC_IDLE_TIMEOUT), database.gc_signal.wait()).await {
+                        Either::First(()) => break,
+                        Either::Second(()) => {}
+                    }
+                }
+
+                let _ = database.garbage_collect().await;
+            }
+            Either3::Second(()) => {
+                let _ = database.factory_reset().await;
+                crate::hw::reset();
+            }
+            Either3::Third(()) => {
+                STORAGE_STATS_SIGNAL.signal(database.stats().await);
+            }
+        }
+    }
+}
+
 async fn perform_pending_flash_op<'a, F: FlashStorage>(
-    database: &mut AsyncTicKV<'a, FlashDevice<'a, F>, { F::ERASE_SIZE }>,
+    database: &mut AsyncTicKV<'a, FlashDevice<'a, F>, { F::REGION_SIZE }>,
 ) -> Result<(), ErrorCode> {
     let operation = database.tickv.controller.pending.get();
     database.tickv.controller.pending.set(None);
     match operation {
         Some(PendingOperation::Read(page)) => {
-            if database
-                .tickv
-                .controller
-                .read(page * F::ERASE_SIZE)
-                .await
-                .is_err()
-            {
+            if database.tickv.controller.read(page).await.is_err() {
                 return Err(ErrorCode::ReadFail);
             }
-            database.set_read_buffer(database.tickv.controller.op_buf.borrow_mut().as_mut());
+            // Skip the page header, which is not part of the TicKV region
+            database.set_read_buffer(
+                &mut database.tickv.controller.op_buf.borrow_mut()[PAGE_HEADER_LEN..],
+            );
         }
         Some(PendingOperation::Write(address, len)) => {
             // Data should already by contained in `op_buf`, so we just need to pass the length of
@@ -407,13 +1491,7 @@ async fn perform_pending_flash_op<'a, F: FlashStorage>(
             }
         }
         Some(PendingOperation::Delete(page)) => {
-            if database
-                .tickv
-                .controller
-                .erase(page * F::ERASE_SIZE)
-                .await
-                .is_err()
-            {
+            if database.tickv.controller.erase(page).await.is_err() {
                 return Err(ErrorCode::EraseFail);
             }
         }
@@ -423,7 +1501,7 @@ async fn perform_pending_flash_op<'a, F: FlashStorage>(
 }
 
 async fn continue_to_completion<'a, F: FlashStorage>(
-    database: &mut AsyncTicKV<'a, FlashDevice<'a, F>, { F::ERASE_SIZE }>,
+    database: &mut AsyncTicKV<'a, FlashDevice<'a, F>, { F::REGION_SIZE }>,
 ) -> (
     Result<SuccessCode, ErrorCode>,
     Option<&'static mut [u8]>,
@@ -448,13 +1526,15 @@ async fn continue_to_completion<'a, F: FlashStorage>(
     };
 
     // Take care of any leftover pending flash operations (usually a write) when the TicKV operation is complete
-    perform_pending_flash_op(database).await.unwrap();
+    if let Err(e) = perform_pending_flash_op(database).await {
+        return (Err(e), ret.1, ret.2);
+    }
 
     ret
 }
 
 async fn initialise<'a, F: FlashStorage>(
-    database: &mut AsyncTicKV<'a, FlashDevice<'a, F>, { F::ERASE_SIZE }>,
+    database: &mut AsyncTicKV<'a, FlashDevice<'a, F>, { F::REGION_SIZE }>,
 ) -> Result<SuccessCode, ErrorCode> {
     let mut ret = database.initialise(get_hashed_key(MAIN_KEY));
     if ret.is_err() {
@@ -463,8 +1543,19 @@ async fn initialise<'a, F: FlashStorage>(
     ret
 }
 
+/// Erase every page in the config partition.
+async fn format<'a, F: FlashStorage>(
+    database: &mut AsyncTicKV<'a, FlashDevice<'a, F>, { F::REGION_SIZE }>,
+) -> Result<(), F::Error> {
+    let controller = &mut database.tickv.controller;
+    for page in 0..controller.pages() {
+        controller.erase(page).await?;
+    }
+    Ok(())
+}
+
 async fn append_key<'a, F: FlashStorage>(
-    database: &mut AsyncTicKV<'a, FlashDevice<'a, F>, { F::ERASE_SIZE }>,
+    database: &mut AsyncTicKV<'a, FlashDevice<'a, F>, { F::REGION_SIZE }>,
     key: &[u8],
     value: &'static mut [u8],
     length: usize,
@@ -480,8 +1571,32 @@ async fn append_key<'a, F: FlashStorage>(
     }
 }
 
+/// Append a key to the database. If there is no space left for the new key, garbage collection is
+/// forced before trying again.
+async fn append_key_or_collect<'a, F: FlashStorage>(
+    database: &mut AsyncTicKV<'a, FlashDevice<'a, F>, { F::REGION_SIZE }>,
+    key: &[u8],
+    value: &'static mut [u8],
+    length: usize,
+) -> (
+    Result<SuccessCode, ErrorCode>,
+    Option<&'static mut [u8]>,
+    usize,
+) {
+    match append_key(database, key, value, length).await {
+        (Err(ErrorCode::RegionFull | ErrorCode::FlashFull), Some(value), _len) => {
+            warn!("[STORAGE] Database is full, forcing garbage collection.");
+            if let Err(error) = garbage_collect(database).await.0 {
+                return (Err(error), Some(value), 0);
+            }
+            append_key(database, key, value, length).await
+        }
+        ret => ret,
+    }
+}
+
 async fn get_key<'a, F: FlashStorage>(
-    database: &mut AsyncTicKV<'a, FlashDevice<'a, F>, { F::ERASE_SIZE }>,
+    database: &mut AsyncTicKV<'a, FlashDevice<'a, F>, { F::REGION_SIZE }>,
     key: &[u8],
     buf: &'static mut [u8],
 ) -> (
@@ -497,7 +1612,7 @@ async fn get_key<'a, F: FlashStorage>(
 }
 
 async fn invalidate_key<'a, F: FlashStorage>(
-    database: &mut AsyncTicKV<'a, FlashDevice<'a, F>, { F::ERASE_SIZE }>,
+    database: &mut AsyncTicKV<'a, FlashDevice<'a, F>, { F::REGION_SIZE }>,
     key: &[u8],
 ) -> (
     Result<SuccessCode, ErrorCode>,
@@ -512,7 +1627,7 @@ async fn invalidate_key<'a, F: FlashStorage>(
 }
 
 async fn garbage_collect<'a, F: FlashStorage>(
-    database: &mut AsyncTicKV<'a, FlashDevice<'a, F>, { F::ERASE_SIZE }>,
+    database: &mut AsyncTicKV<'a, FlashDevice<'a, F>, { F::REGION_SIZE }>,
 ) -> (
     Result<SuccessCode, ErrorCode>,
     Option<&'static mut [u8]>,
@@ -539,8 +1654,61 @@ pub trait StorageDevice {
         static mut STORAGE_BUFFER: [u8; 1024] = [0; 1024];
         unsafe { &mut STORAGE_BUFFER }
     }
+
+    /// Amount of time (in milliseconds) that a persisted value must remain unchanged before it is
+    /// written to storage. This prevents flash writes from occurring on every keypress when
+    /// adjusting settings (e.g. repeatedly increasing backlight brightness).
+    const SAVE_IDLE_TIMEOUT_MS: u32 = 5000;
+
+    /// Minimum amount of time (in milliseconds) between writes of the same persisted value. Saves
+    /// that are explicitly requested (e.g. using [`StorageService::flush`]) ignore this limit.
+    const SAVE_MIN_INTERVAL_MS: u32 = 10000;
+
+    /// Migrate data that was stored by a firmware version that used a different schema version
+    /// for the data stored at `key`. `buffer` initially contains `old_len` bytes of data, which was
+    /// serialized by [`postcard`] using schema version `old_version`. Data stored by firmware that
+    /// did not keep track of schema versions will have an `old_version` of 0.
+    ///
+    /// To migrate the data, write the new serialized data to `buffer` and return its length. If
+    /// `None` is returned, the stored data will be deleted, and default values will be used
+    /// instead. By default, this deletes the stored data.
+    fn migrate_data(
+        key: StorageKey,
+        old_version: u16,
+        buffer: &mut [u8],
+        old_len: usize,
+    ) -> Option<usize> {
+        let _ = (key, old_version, buffer, old_len);
+        None
+    }
 }
 
+/// Trait for data types that can be persisted using a [`StorageService`].
+pub trait StoredData: Serialize + DeserializeOwned {
+    /// Version of the serialized layout of this type. This must be incremented whenever the
+    /// layout of the type changes, so that existing data can be migrated with
+    /// [`StorageDevice::migrate_data`].
+    const SCHEMA_VERSION: u16;
+}
+
+impl StoredData for u8 {
+    const SCHEMA_VERSION: u16 = 1;
+}
+
+/// Length of a TicKV object header: version (1 byte), flags and length (2 bytes), and the hashed
+/// key (8 bytes).
+const OBJECT_HEADER_LEN: usize = 11;
+
+/// Flag in a TicKV object header, set if the object has not been invalidated.
+const OBJECT_FLAG_VALID: u8 = 0b1000;
+
+/// Length of the header at the start of each page in the config partition, which is not used by
+/// TicKV. The header contains the number of times the page has been erased (4 bytes,
+/// little-endian), followed by the bitwise complement of that count, so that headers left by an
+/// interrupted erase or by older firmware can be detected. The rest of the header is left erased.
+/// This must be a multiple of [`FlashStorage::WRITE_SIZE`].
+pub const PAGE_HEADER_LEN: usize = 32;
+
 #[derive(Debug, Clone, Copy)]
 enum PendingOperation {
     Read(usize),
@@ -556,6 +1724,15 @@ pub trait FlashStorage {
     /// Erase size of the flash device. This may also be called "region size" or "page size".
     const ERASE_SIZE: usize;
 
+    /// Minimum write size of the flash device. Writes must be a multiple of this size, and must
+    /// start on an address that is aligned to this size.
+    const WRITE_SIZE: usize;
+
+    /// Size of each TicKV region. This is the erase size, minus the header at the start of each
+    /// page which stores the page's erase count (see [`PAGE_HEADER_LEN`]). This should not be
+    /// overridden.
+    const REGION_SIZE: usize = Self::ERASE_SIZE - PAGE_HEADER_LEN;
+
     /// Equivalent to [`embedded_storage_async::nor_flash::NorFlash::erase`].
     ///
     /// Erase data between the given addresses (inclusive). The implementor does not necessarily
@@ -588,6 +1765,8 @@ impl<F: ReadNorFlash + AsyncNorFlash> FlashStorage for F {
 
     const ERASE_SIZE: usize = Self::ERASE_SIZE;
 
+    const WRITE_SIZE: usize = Self::WRITE_SIZE;
+
     async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
         self.erase(from, to).await
     }
@@ -605,6 +1784,374 @@ impl<F: ReadNorFlash + AsyncNorFlash> FlashStorage for F {
     }
 }
 
+/// Exclusive access to the config partition, used to back up and restore the stored data. This is
+/// obtained using [`StorageService::partition_writer`], and holds the database lock until it is
+/// dropped.
+struct PartitionWriter<'s, 'a, F: FlashStorage>
+where
+    [(); F::ERASE_SIZE]:,
+    [(); F::REGION_SIZE]:,
+{
+    database: MutexGuard<'s, RawMutex, AsyncTicKV<'a, FlashDevice<'a, F>, { F::REGION_SIZE }>>,
+}
+
+impl<'s, 'a, F: FlashStorage> PartitionWriter<'s, 'a, F>
+where
+    [(); F::ERASE_SIZE]:,
+    [(); F::REGION_SIZE]:,
+{
+    /// Size of the config partition, in bytes.
+    pub fn size(&self) -> usize {
+        self.database.tickv.controller.end - self.database.tickv.controller.start
+    }
+
+    /// Read raw bytes from the config partition, starting at `offset` (relative to the start of
+    /// the config partition).
+    pub async fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), StorageError> {
+        let controller = &self.database.tickv.controller;
+
+        if offset + buf.len() > controller.end - controller.start {
+            return Err(StorageError::InvalidAddress);
+        }
+
+        controller
+            .flash
+            .borrow_mut()
+            .read((controller.start + offset) as u32, buf)
+            .await
+            .map_err(|error| {
+                error!(
+                    "[STORAGE] Could not read config partition: {}",
+                    Debug2Format(&error)
+                );
+                StorageError::Flash
+            })
+    }
+
+    /// Calculate the CRC of the whole config partition.
+    async fn crc(&mut self) -> Result<u32, StorageError> {
+        let mut crc = Crc32::new();
+        let mut buf = [0; 64];
+        for offset in (0..self.size()).step_by(buf.len()) {
+            let len = buf.len().min(self.size() - offset);
+            self.read(offset, &mut buf[..len]).await?;
+            crc.update(&buf[..len]);
+        }
+        Ok(crc.finish())
+    }
+
+    /// Write raw bytes to the config partition, starting at `offset` (relative to the start of the
+    /// config partition). Pages are erased when a write starts at the beginning of a page, so the
+    /// partition must be written sequentially, starting from the first page. `offset` and the
+    /// length of `data` must be multiples of [`FlashStorage::WRITE_SIZE`].
+    ///
+    /// The header at the start of each page (see [`PAGE_HEADER_LEN`]) is not overwritten, so the
+    /// erase counts of this keyboard's flash are kept.
+    ///
+    /// This bypasses the database, so once all data has been written, the keyboard should be reset
+    /// (see [`crate::hw::reset`]) so that features re-read their stored data.
+    pub async fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), StorageError> {
+        let controller = &mut self.database.tickv.controller;
+
+        if offset + data.len() > controller.end - controller.start
+            || offset % F::WRITE_SIZE != 0
+            || data.len() % F::WRITE_SIZE != 0
+        {
+            return Err(StorageError::InvalidAddress);
+        }
+
+        let mut written = 0;
+        while written < data.len() {
+            let address = offset + written;
+            if address % F::ERASE_SIZE == 0 {
+                controller
+                    .erase(address / F::ERASE_SIZE)
+                    .await
+                    .map_err(|_| StorageError::Flash)?;
+            }
+
+            // Keep this keyboard's page headers, so that erase counts aren't replaced by the
+            // erase counts of the keyboard that the backup was taken from
+            if address % F::ERASE_SIZE < PAGE_HEADER_LEN {
+                written += (PAGE_HEADER_LEN - address % F::ERASE_SIZE).min(data.len() - written);
+                continue;
+            }
+
+            let page_remaining = F::ERASE_SIZE - address % F::ERASE_SIZE;
+            // Large writes are split into chunks, so that interrupts aren't disabled for too long
+            let len = page_remaining
+                .min(data.len() - written)
+                .min(FlashDevice::<F>::CHUNK_SIZE);
+
+            controller
+                .flash
+                .borrow_mut()
+                .write(
+                    (controller.start + address) as u32,
+                    &data[written..(written + len)],
+                )
+                .await
+                .map_err(|error| {
+                    error!(
+                        "[STORAGE] Could not write to config partition: {}",
+                        Debug2Format(&error)
+                    );
+                    StorageError::Flash
+                })?;
+
+            written += len;
+        }
+
+        Ok(())
+    }
+}
+
+/// Length of the header at the start of a backup. See [`StorageService::read_backup`].
+pub const BACKUP_HEADER_LEN: usize = 16;
+
+/// Bytes at the start of every backup header.
+const BACKUP_MAGIC: [u8; 4] = *b"RCBK";
+
+/// Version of the backup format. This must be incremented whenever the layout of backups or of the
+/// config partition changes, so that incompatible backups are rejected before they are restored.
+const BACKUP_VERSION: u16 = 1;
+
+/// Header at the start of a backup. The header contains [`BACKUP_MAGIC`], [`BACKUP_VERSION`] (2
+/// bytes), 2 reserved bytes, the size of the config partition (4 bytes), and the CRC of the
+/// contents of the config partition (4 bytes). Values are little-endian.
+struct BackupHeader {
+    length: usize,
+    crc: u32,
+}
+
+impl BackupHeader {
+    fn to_bytes(&self) -> [u8; BACKUP_HEADER_LEN] {
+        let mut bytes = [0; BACKUP_HEADER_LEN];
+        bytes[0..4].copy_from_slice(&BACKUP_MAGIC);
+        bytes[4..6].copy_from_slice(&BACKUP_VERSION.to_le_bytes());
+        bytes[8..12].copy_from_slice(&(self.length as u32).to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.crc.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != BACKUP_HEADER_LEN
+            || bytes[0..4] != BACKUP_MAGIC
+            || bytes[4..6] != BACKUP_VERSION.to_le_bytes()
+        {
+            return None;
+        }
+
+        Some(BackupHeader {
+            length: u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize,
+            crc: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+        })
+    }
+}
+
+/// CRC-32 (IEEE 802.3), used to validate backups.
+#[derive(Clone, Copy)]
+struct Crc32(u32);
+
+impl Crc32 {
+    const fn new() -> Self {
+        Crc32(0xFFFFFFFF)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.0 ^= *byte as u32;
+            for _ in 0..8 {
+                self.0 = if self.0 & 1 == 1 {
+                    (self.0 >> 1) ^ 0xEDB88320
+                } else {
+                    self.0 >> 1
+                };
+            }
+        }
+    }
+
+    fn finish(self) -> u32 {
+        !self.0
+    }
+}
+
+/// A backup that is being restored, obtained with [`StorageService::restore_backup`].
+///
+/// Restoring a backup happens in two passes, so that the whole backup is validated before
+/// anything is erased:
+///
+/// 1. Every chunk of the backup after the header is passed to [`BackupRestore::verify`], in
+///    order. Once the last chunk has been verified, its CRC is checked against the header.
+/// 2. The same chunks are passed to [`BackupRestore::write`], in order. The first write locks the
+///    database, so that storage tasks and [`storage_gc_task`] can not modify the config partition
+///    while it is being overwritten.
+///
+/// Once every chunk has been written, [`BackupRestore::finish`] checks that the written data
+/// matches the verified backup, and releases the database. The keyboard should then be reset (see
+/// [`crate::hw::reset`]), so that features re-read their stored data. If the restore can't be
+/// completed, [`BackupRestore::abort`] must be called to release the database.
+///
+/// Values encrypted with the `storage-encryption` feature can only be decrypted by the keyboard
+/// that encrypted them. If a backup is restored on a different keyboard, those values fail
+/// authentication (see [`StorageError::Authentication`]), and default values are used instead.
+pub struct BackupRestore<'s, 'a, F: FlashStorage>
+where
+    [(); F::ERASE_SIZE]:,
+    [(); F::REGION_SIZE]:,
+{
+    storage: &'s StorageService<'a, F>,
+    header: BackupHeader,
+    crc: Crc32,
+    /// Offset of the next chunk, relative to the start of the backup.
+    offset: usize,
+    verified: bool,
+    writer: Option<PartitionWriter<'s, 'a, F>>,
+    /// Last nonce used before the restore started, so that nonces aren't reused if the backup
+    /// contains an older nonce counter.
+    #[cfg(feature = "storage-encryption")]
+    last_nonce: Option<u64>,
+}
+
+impl<'s, 'a, F: FlashStorage> BackupRestore<'s, 'a, F>
+where
+    [(); F::ERASE_SIZE]:,
+    [(); F::REGION_SIZE]:,
+{
+    /// Check that `offset` is where the next chunk should start, and that `data` doesn't go past
+    /// the end of the backup.
+    fn check_chunk(&self, offset: usize, data: &[u8]) -> Result<(), StorageError> {
+        if offset != self.offset {
+            warn!(
+                "[STORAGE] Expected backup chunk at offset {}, but got offset {}.",
+                self.offset, offset
+            );
+            return Err(StorageError::InvalidBackup);
+        }
+
+        if offset + data.len() > BACKUP_HEADER_LEN + self.header.length {
+            return Err(StorageError::InvalidAddress);
+        }
+
+        Ok(())
+    }
+
+    /// Whether the restore has started overwriting the config partition. If so, the database is
+    /// locked until the restore is finished or aborted.
+    pub fn is_writing(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    /// Size of the backup being restored, including the header.
+    pub fn size(&self) -> usize {
+        BACKUP_HEADER_LEN + self.header.length
+    }
+
+    /// Verify a chunk of the backup, starting at `offset` (relative to the start of the backup,
+    /// including the header). Chunks must be verified in order. Once the last chunk has been
+    /// verified, this returns [`StorageError::InvalidBackup`] if the backup does not match the CRC
+    /// in its header, in which case the backup can be verified again from the start.
+    pub fn verify(&mut self, offset: usize, data: &[u8]) -> Result<(), StorageError> {
+        if self.verified {
+            return Err(StorageError::InvalidBackup);
+        }
+        self.check_chunk(offset, data)?;
+
+        self.crc.update(data);
+        self.offset += data.len();
+
+        if self.offset == self.size() {
+            let crc = core::mem::replace(&mut self.crc, Crc32::new()).finish();
+            self.offset = BACKUP_HEADER_LEN;
+
+            if crc != self.header.crc {
+                warn!("[STORAGE] Backup failed CRC validation.");
+                return Err(StorageError::InvalidBackup);
+            }
+
+            self.verified = true;
+        }
+
+        Ok(())
+    }
+
+    /// Write a chunk of the backup to the config partition, starting at `offset` (relative to the
+    /// start of the backup, including the header). This can only be used once the whole backup
+    /// has been verified with [`BackupRestore::verify`], and chunks must be written in order. The
+    /// length of each chunk must be a multiple of [`FlashStorage::WRITE_SIZE`].
+    pub async fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), StorageError> {
+        if !self.verified {
+            warn!("[STORAGE] Backup must be verified before it is written.");
+            return Err(StorageError::InvalidBackup);
+        }
+        self.check_chunk(offset, data)?;
+
+        if self.writer.is_none() {
+            let mut writer = self.storage.partition_writer().await;
+
+            #[cfg(feature = "storage-encryption")]
+            {
+                self.last_nonce = self.storage.last_nonce(&mut writer.database).await.ok();
+            }
+
+            info!("[STORAGE] Restoring backup.");
+            self.writer = Some(writer);
+        }
+
+        let writer = self.writer.as_mut().unwrap();
+        writer.write(offset - BACKUP_HEADER_LEN, data).await?;
+        self.crc.update(data);
+        self.offset += data.len();
+
+        Ok(())
+    }
+
+    /// Finish restoring the backup, and release the database. If the backup hasn't been completely
+    /// written, or the written data does not match the verified backup, the restore is aborted
+    /// (see [`BackupRestore::abort`]), and [`StorageError::InvalidBackup`] is returned.
+    #[cfg_attr(not(feature = "storage-encryption"), allow(unused_mut))]
+    pub async fn finish(mut self) -> Result<(), StorageError> {
+        if self.writer.is_none()
+            || self.offset != self.size()
+            || self.crc.finish() != self.header.crc
+        {
+            warn!("[STORAGE] Backup was not completely restored.");
+            self.abort().await;
+            return Err(StorageError::InvalidBackup);
+        }
+
+        #[cfg(feature = "storage-encryption")]
+        if let (Some(last), Some(writer)) = (self.last_nonce, &mut self.writer) {
+            // The restored nonce counter may be older than the nonces that have already been used
+            self.storage.nonce_counter.lock(|counter| counter.set(None));
+            match self.storage.last_nonce(&mut writer.database).await {
+                Ok(restored) if restored >= last => {}
+                _ => {
+                    let _ = self
+                        .storage
+                        .save_nonce_counter(&mut writer.database, last)
+                        .await;
+                }
+            }
+        }
+
+        info!("[STORAGE] Backup has been restored.");
+        Ok(())
+    }
+
+    /// Stop restoring the backup, and release the database. If the config partition has already
+    /// been partially overwritten, it is erased, since the stored data would be inconsistent.
+    pub async fn abort(self) {
+        if let Some(writer) = self.writer {
+            drop(writer);
+            warn!("[STORAGE] Backup restore was aborted, erasing the partially restored data.");
+            let _ = self.storage.factory_reset().await;
+        } else {
+            info!("[STORAGE] Backup restore was aborted before any data was written.");
+        }
+    }
+}
+
 /// Data structure that wraps around an implementor of [`FlashStorage`]. If you want to read, write
 /// or delete existing data (like [`crate::underglow::animations::UnderglowConfig`]), see
 /// [`StorageService`]. Reading, writing or deleting *custom* data using the same storage
@@ -612,6 +2159,7 @@ impl<F: ReadNorFlash + AsyncNorFlash> FlashStorage for F {
 struct FlashDevice<'a, F: FlashStorage>
 where
     [(); F::ERASE_SIZE]:,
+    [(); F::REGION_SIZE]:,
 {
     flash: RefCell<F>,
     start: usize,
@@ -620,10 +2168,43 @@ where
     op_buf: RefCell<&'a mut [u8; F::ERASE_SIZE]>,
 }
 
+/// Maximum amount of data to write to the flash peripheral at once. Writing a full page at once
+/// would keep interrupts disabled for too long, causing assertion failures in nrf-softdevice.
+const MAX_WRITE_CHUNK_SIZE: usize = 512;
+
+/// Parse a page header (see [`PAGE_HEADER_LEN`]), returning the number of times the page has been
+/// erased. Pages with an invalid header are assumed to have never been erased.
+fn parse_page_header(header: &[u8]) -> u32 {
+    let count = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let check = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    if count == !check {
+        count
+    } else {
+        0
+    }
+}
+
+/// Fill `header` with a page header (see [`PAGE_HEADER_LEN`]) containing the given erase count.
+fn fill_page_header(header: &mut [u8], erase_count: u32) {
+    header.fill(0xFF);
+    header[0..4].copy_from_slice(&erase_count.to_le_bytes());
+    header[4..8].copy_from_slice(&(!erase_count).to_le_bytes());
+}
+
 impl<'a, F: FlashStorage> FlashDevice<'a, F>
 where
     [(); F::ERASE_SIZE]:,
+    [(); F::REGION_SIZE]:,
 {
+    /// Size of each chunk written to the flash peripheral. This is the largest multiple of
+    /// [`FlashStorage::WRITE_SIZE`] that fits in [`MAX_WRITE_CHUNK_SIZE`], or `WRITE_SIZE` itself if
+    /// the flash peripheral requires larger writes.
+    const CHUNK_SIZE: usize = if F::WRITE_SIZE >= MAX_WRITE_CHUNK_SIZE {
+        F::WRITE_SIZE
+    } else {
+        MAX_WRITE_CHUNK_SIZE - MAX_WRITE_CHUNK_SIZE % F::WRITE_SIZE
+    };
+
     /// Create an instance of [`FlashDevice`], using a provided implementor of
     /// [`embedded_storage_async::nor_flash::NorFlash`].
     pub fn new(
@@ -645,6 +2226,14 @@ where
             config_start % F::ERASE_SIZE == 0,
             "Config partition must start on an address that is a multiple of the page size."
         );
+        assert!(
+            F::WRITE_SIZE > 0 && F::ERASE_SIZE % F::WRITE_SIZE == 0,
+            "Page size must be a multiple of the flash write size."
+        );
+        assert!(
+            PAGE_HEADER_LEN % F::WRITE_SIZE == 0 && F::ERASE_SIZE > PAGE_HEADER_LEN,
+            "Page header length must be a multiple of the flash write size, and smaller than the page size."
+        );
 
         FlashDevice {
             flash: RefCell::new(driver),
@@ -655,20 +2244,35 @@ where
         }
     }
 
-    pub(crate) async fn read(&mut self, address: usize) -> Result<(), F::Error> {
+    /// Number of pages in the config partition.
+    fn pages(&self) -> usize {
+        (self.end - self.start) / F::ERASE_SIZE
+    }
+
+    /// Read the number of times that `page` has been erased from its header.
+    pub(crate) async fn erase_count(&mut self, page: usize) -> Result<u32, F::Error> {
+        let mut header = [0; PAGE_HEADER_LEN];
+        self.flash
+            .borrow_mut()
+            .read((self.start + page * F::ERASE_SIZE) as u32, &mut header)
+            .await?;
+        Ok(parse_page_header(&header))
+    }
+
+    /// Read a whole page, including its header, into `op_buf`.
+    pub(crate) async fn read(&mut self, page: usize) -> Result<(), F::Error> {
         debug!(
-            "[STORAGE_DRIVER] Reading {} bytes from config page {}, offset {} (address = {:x})",
+            "[STORAGE_DRIVER] Reading {} bytes from config page {} (address = {:x})",
             F::ERASE_SIZE,
-            address / F::ERASE_SIZE,
-            address % F::ERASE_SIZE,
-            self.start + address
+            page,
+            self.start + page * F::ERASE_SIZE
         );
 
         if let Err(err) = self
             .flash
             .borrow_mut()
             .read(
-                (self.start + address) as u32,
+                (self.start + page * F::ERASE_SIZE) as u32,
                 self.op_buf.borrow_mut().as_mut(),
             )
             .await
@@ -683,16 +2287,23 @@ where
         Ok(())
     }
 
+    /// Write the page containing the TicKV `address`, using the contents of `op_buf`. `op_buf`
+    /// must contain the whole page, including its header, with `len` bytes of new data at
+    /// `address`.
     pub(crate) async fn write(&mut self, address: usize, len: usize) -> Result<(), F::Error>
     where
         [(); F::ERASE_SIZE]:,
     {
+        let page = address / F::REGION_SIZE;
+        let offset = PAGE_HEADER_LEN + address % F::REGION_SIZE;
+        let page_start = self.start + page * F::ERASE_SIZE;
+
         debug!(
             "[STORAGE_DRIVER] Writing to address {:x} (config page {}, offset {}). data: {}",
-            self.start + address,
-            address / F::ERASE_SIZE,
-            address % F::ERASE_SIZE,
-            &self.op_buf.borrow()[..len]
+            page_start + offset,
+            page,
+            offset,
+            &self.op_buf.borrow()[offset..(offset + len)]
         );
 
         // In the `write` method in the FlashController trait implementation, copied the existing
@@ -703,10 +2314,7 @@ where
         if let Err(err) = self
             .flash
             .borrow_mut()
-            .erase(
-                (self.start + address - address % F::ERASE_SIZE) as u32,
-                (self.start + address - address % F::ERASE_SIZE + F::ERASE_SIZE) as u32,
-            )
+            .erase(page_start as u32, (page_start + F::ERASE_SIZE) as u32)
             .await
         {
             error!(
@@ -716,15 +2324,25 @@ where
             return Err(err);
         };
 
-        // Write in chunks of 512 bytes at a time, so that we don't keep interrupts disabled for too long
-        // Otherwise, writing a full page at once would cause assertion failures in nrf-softdevice
-        for start in (0..F::ERASE_SIZE).step_by(512) {
+        // The page header was read along with the rest of the page, so it can be updated with the
+        // new erase count before the page is written back
+        {
+            let mut op_buf = self.op_buf.borrow_mut();
+            let erase_count = parse_page_header(&op_buf[..PAGE_HEADER_LEN]).saturating_add(1);
+            fill_page_header(&mut op_buf[..PAGE_HEADER_LEN], erase_count);
+        }
+
+        // Write the whole page in chunks that are aligned to the flash's write size. Since the page
+        // was erased, and op_buf spans the whole page, we never write a partial or misaligned word,
+        // and we never program the same word twice.
+        for start in (0..F::ERASE_SIZE).step_by(Self::CHUNK_SIZE) {
+            let end = (start + Self::CHUNK_SIZE).min(F::ERASE_SIZE);
             if let Err(err) = self
                 .flash
                 .borrow_mut()
                 .write(
-                    (self.start + ((address / F::ERASE_SIZE) * F::ERASE_SIZE) + start) as u32,
-                    &self.op_buf.borrow()[start..(start + 512)],
+                    (page_start + start) as u32,
+                    &self.op_buf.borrow()[start..end],
                 )
                 .await
             {
@@ -739,17 +2357,18 @@ where
         Ok(())
     }
 
-    pub(crate) async fn erase(&mut self, address: usize) -> Result<(), F::Error> {
-        let start = self.start + address;
-        let end = self.start + address + F::ERASE_SIZE;
+    /// Erase a page, and write a new header containing its incremented erase count.
+    pub(crate) async fn erase(&mut self, page: usize) -> Result<(), F::Error> {
+        let start = self.start + page * F::ERASE_SIZE;
+        let end = start + F::ERASE_SIZE;
 
         debug!(
             "[STORAGE_DRIVER] Erasing config page {} (start addr = {:x}, end addr = {:x}).",
-            address / F::ERASE_SIZE,
-            start,
-            end
+            page, start, end
         );
 
+        let erase_count = self.erase_count(page).await?.saturating_add(1);
+
         if let Err(err) = self
             .flash
             .borrow_mut()
@@ -763,16 +2382,30 @@ where
             return Err(err);
         }
 
+        let mut header = [0; PAGE_HEADER_LEN];
+        fill_page_header(&mut header, erase_count);
+        if let Err(err) = self.flash.borrow_mut().write(start as u32, &header).await {
+            error!(
+                "[STORAGE_DRIVER] Failed to write page header: {}",
+                defmt::Debug2Format(&err)
+            );
+            return Err(err);
+        }
+
         Ok(())
     }
 }
 
-impl<'a, F: FlashStorage> FlashController<{ F::ERASE_SIZE }> for FlashDevice<'a, F> {
+impl<'a, F: FlashStorage> FlashController<{ F::REGION_SIZE }> for FlashDevice<'a, F>
+where
+    [(); F::ERASE_SIZE]:,
+    [(); F::REGION_SIZE]:,
+{
     fn read_region(
         &self,
         region_number: usize,
         _offset: usize,
-        _buf: &mut [u8; F::ERASE_SIZE],
+        _buf: &mut [u8; F::REGION_SIZE],
     ) -> Result<(), tickv::ErrorCode> {
         self.pending
             .set(Some(PendingOperation::Read(region_number)));
@@ -780,12 +2413,14 @@ impl<'a, F: FlashStorage> FlashController<{ F::ERASE_SIZE }> for FlashDevice<'a,
     }
 
     fn write(&self, address: usize, buf: &[u8]) -> Result<(), tickv::ErrorCode> {
-        // Write the data to op_buf where the data should be in the page.
-        let offset = address % F::ERASE_SIZE;
+        // Read the whole page (including its header) to op_buf, and write the data where it
+        // should be in the page.
+        let page = address / F::REGION_SIZE;
+        let offset = PAGE_HEADER_LEN + address % F::REGION_SIZE;
         let mut op_buf = self.op_buf.borrow_mut();
         self.flash
             .borrow_mut()
-            .blocking_read((self.start + address - offset) as u32, op_buf.as_mut())
+            .blocking_read((self.start + page * F::ERASE_SIZE) as u32, op_buf.as_mut())
             .map_err(|_| tickv::ErrorCode::WriteFail)?;
         op_buf[offset..(offset + buf.len())].copy_from_slice(buf);
         self.pending
@@ -799,3 +2434,426 @@ impl<'a, F: FlashStorage> FlashController<{ F::ERASE_SIZE }> for FlashDevice<'a,
         Err(tickv::ErrorCode::EraseNotReady(region_number))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use embassy_futures::block_on;
+    use embedded_storage_async::nor_flash::{NorFlashError, NorFlashErrorKind};
+
+    use super::*;
+
+    const PAGE_SIZE: usize = 1024;
+    const REGION_SIZE: usize = PAGE_SIZE - PAGE_HEADER_LEN;
+    const PARTITION_SIZE: usize = PAGE_SIZE * 4;
+
+    /// Each test uses a separate partition of the flash, so that tests can run in parallel.
+    const FLASH_SIZE: usize = PARTITION_SIZE * 5;
+
+    static FLASH: BlockingMutex<RefCell<[u8; FLASH_SIZE]>> =
+        BlockingMutex::new(RefCell::new([0xFF; FLASH_SIZE]));
+
+    #[derive(Debug)]
+    struct RamFlashError;
+
+    impl NorFlashError for RamFlashError {
+        fn kind(&self) -> NorFlashErrorKind {
+            NorFlashErrorKind::Other
+        }
+    }
+
+    /// Flash peripheral backed by [`FLASH`], so that its contents are kept when a new
+    /// [`StorageService`] is created, like they would be after the keyboard restarts.
+    struct RamFlash;
+
+    impl ErrorType for RamFlash {
+        type Error = RamFlashError;
+    }
+
+    impl ReadNorFlash for RamFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            FLASH.lock(|flash| {
+                bytes.copy_from_slice(&flash.borrow()[offset..(offset + bytes.len())]);
+            });
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            FLASH_SIZE
+        }
+    }
+
+    impl AsyncReadNorFlash for RamFlash {
+        const READ_SIZE: usize = 1;
+
+        async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            ReadNorFlash::read(self, offset, bytes)
+        }
+
+        fn capacity(&self) -> usize {
+            FLASH_SIZE
+        }
+    }
+
+    impl AsyncNorFlash for RamFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = PAGE_SIZE;
+
+        async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            FLASH.lock(|flash| flash.borrow_mut()[(from as usize)..(to as usize)].fill(0xFF));
+            Ok(())
+        }
+
+        async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            FLASH.lock(|flash| {
+                flash.borrow_mut()[offset..(offset + bytes.len())].copy_from_slice(bytes);
+            });
+            Ok(())
+        }
+    }
+
+    /// Obtain a buffer to read or write values with. Every call returns a different buffer, so
+    /// that tests running in parallel don't share buffers.
+    fn value_buffer() -> &'static mut [u8] {
+        static mut VALUE_BUFFERS: [[u8; 64]; 32] = [[0; 64]; 32];
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let index = NEXT.fetch_add(1, Ordering::Relaxed);
+        unsafe { &mut VALUE_BUFFERS[index] }
+    }
+
+    /// Set up a storage service using the given partition of [`FLASH`].
+    async fn setup_storage(
+        partition: usize,
+        read_buf: &'static mut [u8; REGION_SIZE],
+        op_buf: &'static mut [u8; PAGE_SIZE],
+    ) -> StorageService<'static, RamFlash> {
+        static FLUSH_CHANNEL: FlushChannel<0> = FlushChannel::new();
+        let storage = StorageService::new();
+        let start = partition * PARTITION_SIZE;
+        storage
+            .setup(
+                RamFlash,
+                start,
+                start + PARTITION_SIZE,
+                read_buf,
+                op_buf,
+                &FLUSH_CHANNEL,
+            )
+            .await;
+        storage
+    }
+
+    /// Chunk size used when backing up and restoring. This matches the chunk size used by Via.
+    const BACKUP_CHUNK_SIZE: usize = BACKUP_HEADER_LEN;
+    const BACKUP_SIZE: usize = BACKUP_HEADER_LEN + PARTITION_SIZE;
+
+    /// Read a whole backup of `storage` into `backup`.
+    async fn read_backup(storage: &StorageService<'static, RamFlash>, backup: &mut [u8]) {
+        assert_eq!(storage.backup_size().await, BACKUP_SIZE);
+        for (chunk, buf) in backup.chunks_mut(BACKUP_CHUNK_SIZE).enumerate() {
+            storage
+                .read_backup(chunk * BACKUP_CHUNK_SIZE, buf)
+                .await
+                .unwrap();
+        }
+    }
+
+    /// Verify every chunk of `backup` after the header, returning the result of the last chunk.
+    fn verify_backup(
+        restore: &mut BackupRestore<'_, 'static, RamFlash>,
+        backup: &[u8],
+    ) -> Result<(), StorageError> {
+        let mut result = Ok(());
+        for (chunk, buf) in backup.chunks(BACKUP_CHUNK_SIZE).enumerate().skip(1) {
+            result = restore.verify(chunk * BACKUP_CHUNK_SIZE, buf);
+        }
+        result
+    }
+
+    /// Obtain the nonce that is stored before the encrypted value of `key`.
+    #[cfg(feature = "storage-encryption")]
+    async fn stored_nonce(
+        storage: &StorageService<'static, RamFlash>,
+        key: StorageKey,
+    ) -> [u8; encryption::NONCE_LEN] {
+        let mut database = storage.get_database().await;
+        let (result, buf, _len) = get_key(
+            &mut database,
+            &[key as u8, StorageKeyType::Data as u8],
+            value_buffer(),
+        )
+        .await;
+        result.unwrap();
+        buf.unwrap()[..encryption::NONCE_LEN].try_into().unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "storage-encryption")]
+    fn consecutive_writes_use_different_nonces() {
+        static mut READ_BUFS: [[u8; REGION_SIZE]; 2] = [[0; REGION_SIZE]; 2];
+        static mut OP_BUFS: [[u8; PAGE_SIZE]; 2] = [[0; PAGE_SIZE]; 2];
+        let [read_buf, restarted_read_buf] = unsafe { &mut READ_BUFS };
+        let [op_buf, restarted_op_buf] = unsafe { &mut OP_BUFS };
+        let key = StorageKey::DynamicKeymapMacro;
+
+        block_on(async {
+            let storage = setup_storage(0, read_buf, op_buf).await;
+
+            storage
+                .write_raw(value_buffer(), key, &[1, 2, 3])
+                .await
+                .unwrap();
+            let first = stored_nonce(&storage, key).await;
+            storage
+                .write_raw(value_buffer(), key, &[1, 2, 3])
+                .await
+                .unwrap();
+            let second = stored_nonce(&storage, key).await;
+            assert_ne!(first, second);
+
+            // Nonces must not be reused after the keyboard restarts
+            let restarted = setup_storage(0, restarted_read_buf, restarted_op_buf).await;
+            restarted
+                .write_raw(value_buffer(), key, &[1, 2, 3])
+                .await
+                .unwrap();
+            let third = stored_nonce(&restarted, key).await;
+            assert_ne!(third, first);
+            assert_ne!(third, second);
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "storage-encryption")]
+    fn modified_values_fail_authentication() {
+        static mut READ_BUF: [u8; REGION_SIZE] = [0; REGION_SIZE];
+        static mut OP_BUF: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+        let key = StorageKey::DynamicKeymapMacro;
+        let db_key = [key as u8, StorageKeyType::Data as u8];
+
+        block_on(async {
+            let storage = setup_storage(1, unsafe { &mut READ_BUF }, unsafe { &mut OP_BUF }).await;
+            storage
+                .write_raw(value_buffer(), key, &[1, 2, 3])
+                .await
+                .unwrap();
+
+            // Flip a bit in the encrypted data, and store the modified value
+            {
+                let mut database = storage.get_database().await;
+                let (result, buf, len) = get_key(&mut database, &db_key, value_buffer()).await;
+                result.unwrap();
+                let buf = buf.unwrap();
+                buf[encryption::NONCE_LEN] ^= 1;
+                invalidate_key(&mut database, &db_key).await.0.unwrap();
+                append_key_or_collect(&mut database, &db_key, buf, len)
+                    .await
+                    .0
+                    .unwrap();
+            }
+
+            assert_eq!(
+                storage.read_raw(value_buffer(), key).await.err(),
+                Some(StorageError::Authentication)
+            );
+        });
+    }
+
+    #[test]
+    fn erase_counts_are_kept() {
+        static mut READ_BUFS: [[u8; REGION_SIZE]; 2] = [[0; REGION_SIZE]; 2];
+        static mut OP_BUFS: [[u8; PAGE_SIZE]; 2] = [[0; PAGE_SIZE]; 2];
+        let [read_buf, restarted_read_buf] = unsafe { &mut READ_BUFS };
+        let [op_buf, restarted_op_buf] = unsafe { &mut OP_BUFS };
+
+        block_on(async {
+            let storage = setup_storage(2, read_buf, op_buf).await;
+            let pages = PARTITION_SIZE / PAGE_SIZE;
+
+            let mut before = [0; PARTITION_SIZE / PAGE_SIZE];
+            for (page, count) in before.iter_mut().enumerate() {
+                *count = storage.erase_count(page).await.unwrap();
+            }
+
+            // Every page is erased by a factory reset, and the counts must not be reset with it
+            storage.factory_reset().await.unwrap();
+            for (page, count) in before.iter().enumerate() {
+                assert!(storage.erase_count(page).await.unwrap() > *count);
+            }
+            assert_eq!(
+                storage.erase_count(pages).await.err(),
+                Some(StorageError::InvalidAddress)
+            );
+
+            // Counts must be kept after the keyboard restarts
+            let stats = storage.stats().await.unwrap();
+            let restarted = setup_storage(2, restarted_read_buf, restarted_op_buf).await;
+            let restarted_stats = restarted.stats().await.unwrap();
+            assert_eq!(restarted_stats.pages, pages);
+            assert_eq!(restarted_stats.min_erase_count, stats.min_erase_count);
+            assert_eq!(restarted_stats.max_erase_count, stats.max_erase_count);
+        });
+    }
+
+    #[test]
+    fn backups_can_be_restored() {
+        static mut READ_BUFS: [[u8; REGION_SIZE]; 2] = [[0; REGION_SIZE]; 2];
+        static mut OP_BUFS: [[u8; PAGE_SIZE]; 2] = [[0; PAGE_SIZE]; 2];
+        static mut BACKUP: [u8; BACKUP_SIZE] = [0; BACKUP_SIZE];
+        let [read_buf, restarted_read_buf] = unsafe { &mut READ_BUFS };
+        let [op_buf, restarted_op_buf] = unsafe { &mut OP_BUFS };
+        let backup = unsafe { &mut BACKUP };
+        let key = StorageKey::LayoutOptions;
+
+        block_on(async {
+            let storage = setup_storage(3, read_buf, op_buf).await;
+            storage
+                .write_raw(value_buffer(), key, &[1, 2, 3])
+                .await
+                .unwrap();
+            read_backup(&storage, backup).await;
+
+            storage
+                .write_raw(value_buffer(), key, &[4, 5, 6])
+                .await
+                .unwrap();
+            let erase_count = storage.erase_count(0).await.unwrap();
+
+            let mut restore = storage
+                .restore_backup(&backup[..BACKUP_HEADER_LEN])
+                .await
+                .unwrap();
+            assert_eq!(restore.size(), BACKUP_SIZE);
+            verify_backup(&mut restore, backup).unwrap();
+            assert!(!restore.is_writing());
+            for (chunk, buf) in backup.chunks(BACKUP_CHUNK_SIZE).enumerate().skip(1) {
+                restore.write(chunk * BACKUP_CHUNK_SIZE, buf).await.unwrap();
+            }
+            assert!(restore.is_writing());
+            restore.finish().await.unwrap();
+
+            // The restored data is read after the keyboard restarts
+            let restarted = setup_storage(3, restarted_read_buf, restarted_op_buf).await;
+            let (data, len) = restarted.read_raw(value_buffer(), key).await.unwrap();
+            assert_eq!(&data[..len], &[1, 2, 3]);
+
+            // This keyboard's erase counts are kept
+            assert!(restarted.erase_count(0).await.unwrap() > erase_count);
+        });
+    }
+
+    #[test]
+    fn invalid_backups_are_rejected() {
+        static mut READ_BUF: [u8; REGION_SIZE] = [0; REGION_SIZE];
+        static mut OP_BUF: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+        static mut BACKUP: [u8; BACKUP_SIZE] = [0; BACKUP_SIZE];
+        let backup = unsafe { &mut BACKUP };
+        let key = StorageKey::LayoutOptions;
+
+        block_on(async {
+            let storage = setup_storage(4, unsafe { &mut READ_BUF }, unsafe { &mut OP_BUF }).await;
+            storage
+                .write_raw(value_buffer(), key, &[1, 2, 3])
+                .await
+                .unwrap();
+            read_backup(&storage, backup).await;
+
+            // Invalid magic
+            let mut header: [u8; BACKUP_HEADER_LEN] =
+                backup[..BACKUP_HEADER_LEN].try_into().unwrap();
+            header[0] ^= 1;
+            assert_eq!(
+                storage.restore_backup(&header).await.err(),
+                Some(StorageError::InvalidBackup)
+            );
+
+            // Backup of a config partition with a different size
+            let mut header: [u8; BACKUP_HEADER_LEN] =
+                backup[..BACKUP_HEADER_LEN].try_into().unwrap();
+            header[8..12].copy_from_slice(&(PARTITION_SIZE as u32 * 2).to_le_bytes());
+            assert_eq!(
+                storage.restore_backup(&header).await.err(),
+                Some(StorageError::InvalidBackup)
+            );
+
+            // Chunks can't be written before the backup is verified, or out of order
+            let mut restore = storage
+                .restore_backup(&backup[..BACKUP_HEADER_LEN])
+                .await
+                .unwrap();
+            assert_eq!(
+                restore
+                    .write(
+                        BACKUP_HEADER_LEN,
+                        &backup[BACKUP_HEADER_LEN..][..BACKUP_CHUNK_SIZE]
+                    )
+                    .await,
+                Err(StorageError::InvalidBackup)
+            );
+            assert_eq!(
+                restore.verify(
+                    BACKUP_HEADER_LEN + BACKUP_CHUNK_SIZE,
+                    &backup[BACKUP_HEADER_LEN..][..BACKUP_CHUNK_SIZE]
+                ),
+                Err(StorageError::InvalidBackup)
+            );
+            restore.abort().await;
+
+            // Corrupted data fails CRC validation, and nothing is erased
+            let last = BACKUP_SIZE - 1;
+            backup[last] ^= 1;
+            let mut restore = storage
+                .restore_backup(&backup[..BACKUP_HEADER_LEN])
+                .await
+                .unwrap();
+            assert_eq!(
+                verify_backup(&mut restore, backup),
+                Err(StorageError::InvalidBackup)
+            );
+            restore.abort().await;
+            backup[last] ^= 1;
+            let (data, len) = storage.read_raw(value_buffer(), key).await.unwrap();
+            assert_eq!(&data[..len], &[1, 2, 3]);
+
+            // Incomplete restores can't be finished
+            let mut restore = storage
+                .restore_backup(&backup[..BACKUP_HEADER_LEN])
+                .await
+                .unwrap();
+            verify_backup(&mut restore, backup).unwrap();
+            restore
+                .write(
+                    BACKUP_HEADER_LEN,
+                    &backup[BACKUP_HEADER_LEN..][..BACKUP_CHUNK_SIZE],
+                )
+                .await
+                .unwrap();
+            assert_eq!(restore.finish().await, Err(StorageError::InvalidBackup));
+
+            // Aborting a partially written restore erases the partition and releases the database
+            let mut restore = storage
+                .restore_backup(&backup[..BACKUP_HEADER_LEN])
+                .await
+                .unwrap();
+            verify_backup(&mut restore, backup).unwrap();
+            restore
+                .write(
+                    BACKUP_HEADER_LEN,
+                    &backup[BACKUP_HEADER_LEN..][..BACKUP_CHUNK_SIZE],
+                )
+                .await
+                .unwrap();
+            restore.abort().await;
+            assert!(storage.read_raw(value_buffer(), key).await.is_err());
+            storage
+                .write_raw(value_buffer(), key, &[4, 5, 6])
+                .await
+                .unwrap();
+        });
+    }
+}
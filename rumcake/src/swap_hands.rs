@@ -0,0 +1,102 @@
+//! Swap hands, which mirrors the keyboard so that keys on one half can be typed using the other
+//! half.
+//!
+//! To use swap hands, implement [`KeyboardLayout::SWAP_HANDS_MAP`] to define the position (row,
+//! column) that each key is mirrored to. While swap hands is active, pressing a key acts as if the
+//! key at its mirrored position was pressed instead. Keys that are pressed before swap hands is
+//! turned off are still released from their mirrored position.
+//!
+//! [`KeyboardLayout::SWAP_HANDS_MAP`]: crate::keyboard::KeyboardLayout::SWAP_HANDS_MAP
+
+use defmt::{debug, info};
+use heapless::Vec;
+use keyberon::layout::Event;
+
+/// Maximum number of mirrored keys that can be held at once.
+const MAX_SWAPPED_KEYS: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+/// An enumeration of possible commands used to control swap hands.
+pub enum SwapHandsCommand {
+    /// Mirror the keyboard while this key is held.
+    Momentary,
+    /// Stop mirroring the keyboard while this key is held.
+    MomentaryOff,
+    /// Mirror the next key that is pressed.
+    OneShot,
+    /// Toggle mirroring of the keyboard.
+    Toggle,
+    /// Start mirroring the keyboard.
+    On,
+    /// Stop mirroring the keyboard.
+    Off,
+}
+
+/// Mirrors matrix events using the keyboard's swap hands map.
+pub(crate) struct SwapHandsProcessor {
+    active: bool,
+    one_shot: bool,
+    // Keys pressed while swap hands was active, along with their mirrored positions
+    swapped: Vec<((u8, u8), (u8, u8)), MAX_SWAPPED_KEYS>,
+}
+
+impl SwapHandsProcessor {
+    pub(crate) const fn new() -> Self {
+        Self {
+            active: false,
+            one_shot: false,
+            swapped: Vec::new(),
+        }
+    }
+
+    /// Process a swap hands keycode. `press` is `true` if the key was pressed.
+    pub(crate) fn process_command(&mut self, command: SwapHandsCommand, press: bool) {
+        match (command, press) {
+            (SwapHandsCommand::Momentary, _) => self.active = press,
+            (SwapHandsCommand::MomentaryOff, _) => self.active = !press,
+            (SwapHandsCommand::OneShot, true) => self.one_shot = true,
+            (SwapHandsCommand::Toggle, true) => self.active = !self.active,
+            (SwapHandsCommand::On, true) => self.active = true,
+            (SwapHandsCommand::Off, true) => self.active = false,
+            _ => return,
+        }
+
+        info!("[SWAP_HANDS] Swap hands active: {}", self.active);
+    }
+
+    /// Mirror a matrix event if swap hands is active.
+    pub(crate) fn event(&mut self, map: &[&[(u8, u8)]], event: Event) -> Event {
+        let coord = event.coord();
+
+        match event {
+            Event::Press(row, col) => {
+                if !self.active && !self.one_shot {
+                    return event;
+                }
+                self.one_shot = false;
+
+                let Some(&mirrored) = map
+                    .get(row as usize)
+                    .and_then(|keys| keys.get(col as usize))
+                else {
+                    return event;
+                };
+
+                if self.swapped.push((coord, mirrored)).is_err() {
+                    return event;
+                }
+
+                debug!("[SWAP_HANDS] Mirrored {} to {}", coord, mirrored);
+                Event::Press(mirrored.0, mirrored.1)
+            }
+            Event::Release(_, _) => {
+                let Some(idx) = self.swapped.iter().position(|(key, _)| *key == coord) else {
+                    return event;
+                };
+
+                let (_, mirrored) = self.swapped.swap_remove(idx);
+                Event::Release(mirrored.0, mirrored.1)
+            }
+        }
+    }
+}
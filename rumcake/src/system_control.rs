@@ -0,0 +1,59 @@
+//! System control features.
+//!
+//! System control keys can be used to shut down the host, put it to sleep, or wake it up. They
+//! can be bound to keys using [`crate::keyboard::Keycode::SystemControl`], which requires the
+//! `media-keycodes` feature.
+
+use embassy_sync::channel::Channel;
+use packed_struct::prelude::PackedStruct;
+
+use crate::hw::mcu::RawMutex;
+use crate::keyboard::HID_REPORT_QUEUE_SIZE;
+
+/// Report descriptor for system control keys. Only one key can be reported at a time. This is
+/// the same layout used by QMK.
+pub const SYSTEM_CONTROL_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x80, // Usage (System Control)
+    0xA1, 0x01, // Collection (Application)
+    0x19, 0x01, //   Usage Minimum (Pointer)
+    0x2A, 0xB7, 0x00, //   Usage Maximum (System Display LCD Autoscale)
+    0x15, 0x01, //   Logical Minimum (1)
+    0x26, 0xB7, 0x00, //   Logical Maximum (0xB7)
+    0x75, 0x10, //   Report Size (16)
+    0x95, 0x01, //   Report Count (1)
+    0x81, 0x00, //   Input (Data, Array, Absolute)
+    0xC0, // End Collection
+];
+
+/// System control keys, from the Generic Desktop usage page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum SystemControlKeycode {
+    /// Shut down the host.
+    PowerDown = 0x81,
+    /// Put the host to sleep.
+    Sleep = 0x82,
+    /// Wake the host up from sleep.
+    WakeUp = 0x83,
+}
+
+/// A HID report for the system control keys described by [`SYSTEM_CONTROL_REPORT_DESCRIPTOR`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PackedStruct)]
+#[packed_struct(endian = "lsb", bit_numbering = "msb0")]
+pub struct SystemControlReport {
+    /// Usage of the pressed key, or 0 if no key is pressed.
+    #[packed_field]
+    pub usage: u16,
+}
+
+/// Channel for sending system control HID reports.
+///
+/// Channel messages should be consumed by the bluetooth task or USB task, so user-level code
+/// should **not** attempt to receive messages from the channel, otherwise commands may not be
+/// processed appropriately. You should only send to this channel.
+pub static SYSTEM_CONTROL_REPORT_HID_SEND_CHANNEL: Channel<
+    RawMutex,
+    SystemControlReport,
+    HID_REPORT_QUEUE_SIZE,
+> = Channel::new();
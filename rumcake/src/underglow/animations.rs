@@ -43,6 +43,11 @@ impl Default for UnderglowConfig {
     }
 }
 
+#[cfg(feature = "storage")]
+impl crate::storage::StoredData for UnderglowConfig {
+    const SCHEMA_VERSION: u16 = 1;
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, MaxSize)]
 pub enum UnderglowCommand {
     Toggle,
@@ -66,6 +71,8 @@ pub enum UnderglowCommand {
     #[cfg(feature = "storage")]
     SaveConfig,
     ResetTime, // normally used internally for syncing LEDs for split keyboards
+    Suspend,   // normally used internally to turn off LEDs while the USB host is asleep
+    Resume,    // normally used internally to turn LEDs back on when the USB host wakes up
 }
 
 #[generate_items_from_enum_variants("const {variant_shouty_snake_case}_ENABLED: bool = true")]
@@ -145,6 +152,7 @@ where
     pub(super) buf: [RGB8; D::NUM_LEDS],
     pub(super) twinkle_state: [(Hsv, u8); D::NUM_LEDS], // For the twinkle effect specifically, tracks the lifespan of lit LEDs.
     pub(super) tick: u32,
+    pub(super) suspended: bool, // Whether the LEDs have been turned off due to the USB host being asleep
     pub(super) time_of_last_press: u32,
     pub(super) driver: R,
     pub(super) rng: SmallRng,
@@ -158,6 +166,7 @@ where
         Self {
             config,
             tick: 0,
+            suspended: false,
             driver,
             time_of_last_press: 0,
             twinkle_state: [(
@@ -174,6 +183,10 @@ where
     }
 
     pub async fn turn_on(&mut self) {
+        if self.suspended {
+            return;
+        }
+
         if let Err(err) = self.driver.turn_on().await {
             warn!("[UNDERGLOW] Animations have been enabled, but the underglow LEDs could not be turned on: {}", Debug2Format(&err));
         };
@@ -255,6 +268,16 @@ where
             UnderglowCommand::ResetTime => {
                 self.tick = 0;
             }
+            UnderglowCommand::Suspend => {
+                self.suspended = true;
+                self.turn_off().await;
+            }
+            UnderglowCommand::Resume => {
+                self.suspended = false;
+                if self.config.enabled {
+                    self.turn_on().await;
+                }
+            }
         };
     }
 
@@ -282,7 +305,7 @@ where
     }
 
     pub async fn tick(&mut self) {
-        if !self.config.enabled {
+        if !self.config.enabled || self.suspended {
             return;
         }
 
@@ -72,7 +72,9 @@ where
     animator.tick().await; // Force a frame to be rendered in the event that the initial effect is static.
 
     loop {
-        let command = if !(animator.config.enabled && animator.config.effect.is_animated()) {
+        let command = if animator.suspended
+            || !(animator.config.enabled && animator.config.effect.is_animated())
+        {
             // We want to wait for a command if the animator is not rendering any animated effects. This allows the task to sleep when the LEDs are static.
             Some(UNDERGLOW_COMMAND_CHANNEL.receive().await)
         } else {
@@ -163,19 +165,11 @@ where
 
 #[cfg(feature = "storage")]
 pub mod storage {
-    use core::any::TypeId;
-
-    use defmt::{info, warn, Debug2Format};
-    use embassy_futures::select;
-    use embassy_futures::select::Either;
     use embassy_sync::signal::Signal;
-    use embassy_time::Duration;
-    use embassy_time::Timer;
 
     use crate::hw::mcu::RawMutex;
     use crate::storage::{FlashStorage, StorageDevice};
 
-    use super::UnderglowConfig;
     use super::UNDERGLOW_CONFIG_STATE;
 
     pub(super) static UNDERGLOW_CONFIG_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
@@ -188,79 +182,15 @@ pub mod storage {
         database: &crate::storage::StorageService<'static, F>,
     ) where
         [(); F::ERASE_SIZE]:,
+        [(); F::REGION_SIZE]:,
     {
-        {
-            // Check stored underglow config metadata (type id) to see if it has changed
-            let metadata: [u8; core::mem::size_of::<TypeId>()] =
-                unsafe { core::mem::transmute(TypeId::of::<UnderglowConfig>()) };
-            let _ = database
-                .check_metadata(
-                    K::get_storage_buffer(),
-                    crate::storage::StorageKey::UnderglowConfig,
-                    &metadata,
-                )
-                .await;
-
-            // Get underglow config from storage
-            if let Ok(config) = database
-                .read(
-                    K::get_storage_buffer(),
-                    crate::storage::StorageKey::UnderglowConfig,
-                )
-                .await
-            {
-                info!(
-                    "[UNDERGLOW] Obtained underglow config from storage: {}",
-                    Debug2Format(&config)
-                );
-                // Quietly update the config state so that we don't save the config to storage again
-                UNDERGLOW_CONFIG_STATE.quiet_set(config).await;
-            } else {
-                warn!("[UNDERGLOW] Could not get underglow config from storage, using default config.",);
-            }
-        }
-
-        let save = || async {
-            let _ = database
-                .write(
-                    K::get_storage_buffer(),
-                    crate::storage::StorageKey::UnderglowConfig,
-                    UNDERGLOW_CONFIG_STATE.get().await,
-                )
-                .await;
-        };
-
-        // Save the underglow config if it hasn't been changed in 5 seconds, or if a save was signalled
-        loop {
-            match select::select(
-                UNDERGLOW_SAVE_SIGNAL.wait(),
-                UNDERGLOW_CONFIG_STATE_LISTENER.wait(),
+        database
+            .persist_state::<K, _>(
+                crate::storage::StorageKey::UnderglowConfig,
+                &UNDERGLOW_CONFIG_STATE,
+                &UNDERGLOW_CONFIG_STATE_LISTENER,
+                &UNDERGLOW_SAVE_SIGNAL,
             )
             .await
-            {
-                Either::First(_) => {
-                    save().await;
-                }
-                Either::Second(_) => {
-                    match select::select(
-                        select::select(
-                            Timer::after(Duration::from_secs(5)),
-                            UNDERGLOW_SAVE_SIGNAL.wait(),
-                        ),
-                        UNDERGLOW_CONFIG_STATE_LISTENER.wait(),
-                    )
-                    .await
-                    {
-                        Either::First(_) => {
-                            save().await;
-                        }
-                        Either::Second(_) => {
-                            // Re-signal, so that we skip the `wait()` call at the beginning of this loop
-                            UNDERGLOW_CONFIG_STATE_LISTENER.signal(());
-                        }
-                    }
-                }
-            };
-        }
     }
 }
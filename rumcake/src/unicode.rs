@@ -0,0 +1,225 @@
+//! Unicode input, which types Unicode characters using the input method of the host's OS.
+//!
+//! Characters are typed with [`UnicodeCommand::Type`]. Since there is no standard way for a
+//! keyboard to send Unicode characters, each character is typed as a sequence of keys that the OS
+//! understands. The sequence depends on [`UNICODE_MODE_STATE`], which can be changed using the
+//! other [`UnicodeCommand`]s. If you specified a storage driver, and enabled the `storage`
+//! feature, the selected mode is saved, and restored when your keyboard restarts.
+
+use defmt::{info, Debug2Format};
+use heapless::Vec;
+use keyberon::key_code::KeyCode;
+use keyberon::layout::Layout as KeyberonLayout;
+use serde::{Deserialize, Serialize};
+
+use crate::keyboard::Keycode;
+use crate::send_string::{push_key_event, SS_DOWN_CODE, SS_TAP_CODE, SS_UP_CODE};
+use crate::State;
+
+/// Ways that Unicode characters can be typed, depending on the host's OS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UnicodeMode {
+    /// Type `Ctrl+Shift+U`, followed by the hex code point, followed by `Space`. This works with
+    /// IBus, which is used by most Linux desktops.
+    Linux,
+    /// Type the UTF-16 hex code units while holding `Alt`. This requires the "Unicode Hex Input"
+    /// input source to be selected on macOS.
+    MacOS,
+    /// Type `RAlt` (the default compose key), `U`, the hex code point, then `Enter`. This requires
+    /// [WinCompose](https://github.com/samhocevar/wincompose) to be installed on Windows.
+    WinCompose,
+}
+
+impl UnicodeMode {
+    const MODES: [Self; 3] = [Self::Linux, Self::MacOS, Self::WinCompose];
+
+    fn next(self) -> Self {
+        let idx = Self::MODES.iter().position(|mode| *mode == self).unwrap();
+        Self::MODES[(idx + 1) % Self::MODES.len()]
+    }
+
+    fn previous(self) -> Self {
+        let idx = Self::MODES.iter().position(|mode| *mode == self).unwrap();
+        Self::MODES[(idx + Self::MODES.len() - 1) % Self::MODES.len()]
+    }
+}
+
+#[cfg(feature = "storage")]
+impl crate::storage::StoredData for UnicodeMode {
+    const SCHEMA_VERSION: u16 = 1;
+}
+
+#[derive(Debug, Clone, Copy)]
+/// An enumeration of possible commands used to type Unicode characters, and change the state of
+/// [`UNICODE_MODE_STATE`].
+pub enum UnicodeCommand {
+    /// Type the given character, using the current [`UnicodeMode`].
+    Type(char),
+    /// Set the mode used to type Unicode characters.
+    SetMode(UnicodeMode),
+    /// Switch to the next Unicode mode.
+    NextMode,
+    /// Switch to the previous Unicode mode.
+    PreviousMode,
+}
+
+/// State that contains the mode used to type Unicode characters.
+pub static UNICODE_MODE_STATE: State<UnicodeMode> = State::new(
+    UnicodeMode::Linux,
+    &[
+        #[cfg(feature = "storage")]
+        &storage::UNICODE_MODE_STATE_STORAGE_LISTENER,
+    ],
+);
+
+/// Number of bytes needed to type a character in any mode.
+const UNICODE_SEQUENCE_SIZE: usize = 32;
+
+/// Number of characters that can be typed at once. This matches the number of sequences that the
+/// layout can type at once.
+const UNICODE_BUFFER_COUNT: usize = 4;
+
+/// Buffers that hold the sequences used to type characters. Sequences must have a `'static`
+/// lifetime to be typed by the layout. Buffers are used in turn, so that a buffer is not changed
+/// while its sequence is still being typed.
+static mut UNICODE_BUFFERS: [[u8; UNICODE_SEQUENCE_SIZE]; UNICODE_BUFFER_COUNT] =
+    [[0; UNICODE_SEQUENCE_SIZE]; UNICODE_BUFFER_COUNT];
+
+/// Types Unicode characters using the layout.
+pub(crate) struct UnicodeTyper {
+    next_buffer: usize,
+}
+
+impl UnicodeTyper {
+    pub(crate) const fn new() -> Self {
+        Self { next_buffer: 0 }
+    }
+
+    pub(crate) async fn process_command<const C: usize, const R: usize, const L: usize>(
+        &mut self,
+        layout: &mut KeyberonLayout<C, R, L, Keycode>,
+        command: UnicodeCommand,
+    ) {
+        match command {
+            UnicodeCommand::Type(char) => {
+                let mode = UNICODE_MODE_STATE.get().await;
+                let Ok(sequence) = encode_char::<UNICODE_SEQUENCE_SIZE>(mode, char) else {
+                    return;
+                };
+
+                // SAFETY: the unicode buffers are only accessed by the layout task
+                let buffer = unsafe { &mut UNICODE_BUFFERS[self.next_buffer] };
+                self.next_buffer = (self.next_buffer + 1) % UNICODE_BUFFER_COUNT;
+                buffer[..sequence.len()].copy_from_slice(&sequence);
+                let buffer: &'static [u8] = buffer;
+                layout.start_sequence(&buffer[..sequence.len()]);
+            }
+            UnicodeCommand::SetMode(mode) => {
+                UNICODE_MODE_STATE.set(mode).await;
+            }
+            UnicodeCommand::NextMode => {
+                UNICODE_MODE_STATE.update(|mode| **mode = mode.next()).await;
+            }
+            UnicodeCommand::PreviousMode => {
+                UNICODE_MODE_STATE
+                    .update(|mode| **mode = mode.previous())
+                    .await;
+            }
+        }
+
+        if !matches!(command, UnicodeCommand::Type(_)) {
+            info!(
+                "[UNICODE] Unicode mode: {:?}",
+                Debug2Format(&UNICODE_MODE_STATE.get().await)
+            );
+        }
+    }
+}
+
+/// Append the lowercase hex digits of a number to a sequence, without leading zeros. At least
+/// `min_digits` digits are added.
+fn push_hex<const N: usize>(
+    sequence: &mut Vec<u8, N>,
+    value: u32,
+    min_digits: usize,
+) -> Result<(), ()> {
+    let digits = (8 - value.leading_zeros() as usize / 4).max(min_digits);
+    for i in (0..digits).rev() {
+        let digit = ((value >> (i * 4)) & 0xF) as u8;
+        let char = if digit < 10 {
+            b'0' + digit
+        } else {
+            b'a' + digit - 10
+        };
+        sequence.push(char).map_err(|_| ())?;
+    }
+    Ok(())
+}
+
+/// Create the sequence used to type a character in the given mode.
+fn encode_char<const N: usize>(mode: UnicodeMode, char: char) -> Result<Vec<u8, N>, ()> {
+    let mut sequence = Vec::new();
+
+    match mode {
+        UnicodeMode::Linux => {
+            push_key_event(&mut sequence, SS_DOWN_CODE, KeyCode::LCtrl as u8)?;
+            push_key_event(&mut sequence, SS_DOWN_CODE, KeyCode::LShift as u8)?;
+            push_key_event(&mut sequence, SS_TAP_CODE, KeyCode::U as u8)?;
+            push_key_event(&mut sequence, SS_UP_CODE, KeyCode::LShift as u8)?;
+            push_key_event(&mut sequence, SS_UP_CODE, KeyCode::LCtrl as u8)?;
+            push_hex(&mut sequence, char as u32, 1)?;
+            push_key_event(&mut sequence, SS_TAP_CODE, KeyCode::Space as u8)?;
+        }
+        UnicodeMode::MacOS => {
+            push_key_event(&mut sequence, SS_DOWN_CODE, KeyCode::LAlt as u8)?;
+            let mut units = [0; 2];
+            for unit in char.encode_utf16(&mut units) {
+                push_hex(&mut sequence, *unit as u32, 4)?;
+            }
+            push_key_event(&mut sequence, SS_UP_CODE, KeyCode::LAlt as u8)?;
+        }
+        UnicodeMode::WinCompose => {
+            push_key_event(&mut sequence, SS_TAP_CODE, KeyCode::RAlt as u8)?;
+            push_key_event(&mut sequence, SS_TAP_CODE, KeyCode::U as u8)?;
+            push_hex(&mut sequence, char as u32, 1)?;
+            push_key_event(&mut sequence, SS_TAP_CODE, KeyCode::Enter as u8)?;
+        }
+    }
+
+    Ok(sequence)
+}
+
+#[cfg(feature = "storage")]
+pub mod storage {
+    use embassy_sync::signal::Signal;
+
+    use crate::hw::mcu::RawMutex;
+    use crate::storage::{FlashStorage, StorageDevice};
+
+    use super::UNICODE_MODE_STATE;
+
+    pub(super) static UNICODE_MODE_STATE_STORAGE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+    /// Signal used to save the Unicode mode immediately, instead of waiting for the save policy
+    /// defined in [`StorageDevice`].
+    pub(crate) static UNICODE_MODE_SAVE_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+    /// Task that restores the Unicode mode, and saves it when it is changed.
+    #[rumcake_macros::task]
+    pub async fn unicode_mode_storage_task<K: StorageDevice, F: FlashStorage>(
+        _k: K,
+        database: &crate::storage::StorageService<'static, F>,
+    ) where
+        [(); F::ERASE_SIZE]:,
+        [(); F::REGION_SIZE]:,
+    {
+        database
+            .persist_state::<K, _>(
+                crate::storage::StorageKey::UnicodeMode,
+                &UNICODE_MODE_STATE,
+                &UNICODE_MODE_STATE_STORAGE_LISTENER,
+                &UNICODE_MODE_SAVE_SIGNAL,
+            )
+            .await
+    }
+}
@@ -2,34 +2,63 @@
 //!
 //! To use USB host communication, keyboards must implement [`USBKeyboard`].
 
-use defmt::{error, info, Debug2Format};
+use core::cell::Cell;
+
+use defmt::{debug, error, info, warn, Debug2Format};
 use embassy_futures::select::{self, select};
 use embassy_sync::signal::Signal;
+#[cfg(feature = "console")]
+use embassy_usb::class::cdc_acm::{CdcAcmClass, State as CdcAcmState};
 use embassy_usb::class::hid::{
     Config, HidReader, HidReaderWriter, HidWriter, ReportId, RequestHandler, State as UsbState,
 };
-use embassy_usb::control::OutResponse;
-use embassy_usb::driver::Driver;
-use embassy_usb::{Builder, UsbDevice};
+#[cfg(feature = "midi")]
+use embassy_usb::class::midi::MidiClass;
+use embassy_usb::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use embassy_usb::driver::{Driver, EndpointError, EndpointIn};
+use embassy_usb::{Builder, Handler, UsbDevice};
 use packed_struct::PackedStruct;
 use static_cell::StaticCell;
 use usbd_human_interface_device::device::consumer::{
     MultipleConsumerReport, MULTIPLE_CODE_REPORT_DESCRIPTOR,
 };
-use usbd_human_interface_device::device::keyboard::{
-    NKROBootKeyboardReport, NKRO_BOOT_KEYBOARD_REPORT_DESCRIPTOR,
-};
+use usbd_human_interface_device::device::keyboard::NKRO_BOOT_KEYBOARD_REPORT_DESCRIPTOR;
 
-use crate::hw::mcu::RawMutex;
-use crate::hw::{HIDOutput, CURRENT_OUTPUT_STATE};
+#[cfg(feature = "digitizer")]
+use crate::digitizer::{DigitizerReport, DIGITIZER_REPORT_DESCRIPTOR};
+#[cfg(feature = "gamepad")]
+use crate::gamepad::{GamepadReport, GAMEPAD_REPORT_DESCRIPTOR};
+use crate::hw::mcu::{BlockingMutex, RawMutex};
+use crate::hw::{
+    HIDOutput, LedIndicators, OutputMode, CURRENT_OUTPUT_STATE, LED_INDICATORS_STATE,
+    OUTPUT_MODE_STATE,
+};
 use crate::keyboard::{
     Keyboard, KeyboardLayout, CONSUMER_REPORT_HID_SEND_CHANNEL, KEYBOARD_REPORT_HID_SEND_CHANNEL,
 };
+#[cfg(feature = "mouse")]
+use crate::mouse::{MouseReport, MOUSE_REPORT_DESCRIPTOR};
+#[cfg(feature = "raw-hid")]
+use crate::raw_hid::RAW_HID_REPORT_SIZE;
+use crate::system_control::{
+    SystemControlReport, SYSTEM_CONTROL_REPORT_DESCRIPTOR, SYSTEM_CONTROL_REPORT_HID_SEND_CHANNEL,
+};
+#[cfg(feature = "xap")]
+use crate::xap::XAP_REPORT_SIZE;
 use crate::{State, StaticArray};
 
 pub(crate) static USB_RUNNING_STATE: State<bool> =
     State::new(false, &[&crate::hw::USB_RUNNING_STATE_LISTENER]);
 
+/// Whether the USB device has been configured by a host. This is `false` if a USB cable is not
+/// connected.
+pub(crate) static USB_CONFIGURED_STATE: State<bool> =
+    State::new(false, &[&crate::hw::USB_RUNNING_STATE_LISTENER]);
+
+/// Whether the host has suspended the USB bus. While suspended, the matrix is scanned less often,
+/// and backlighting and underglow are turned off to stay within the USB suspend current budget.
+pub(crate) static USB_SUSPENDED_STATE: State<bool> = State::new(false, &[]);
+
 /// A trait that keyboards must implement to communicate with host devices over USB.
 pub trait USBKeyboard: Keyboard + KeyboardLayout {
     /// Vendor ID for the keyboard.
@@ -37,38 +66,331 @@ pub trait USBKeyboard: Keyboard + KeyboardLayout {
 
     /// Product ID for the keyboard.
     const USB_PID: u16;
+
+    /// Whether the keyboard should be allowed to wake the host from sleep when a key is pressed.
+    /// The host must also allow it (this is usually configured in your OS's power settings).
+    const USB_REMOTE_WAKEUP: bool = true;
+
+    /// How often the host should poll the keyboard's HID interfaces for new reports, in
+    /// milliseconds. This is used as the `bInterval` of the HID endpoints. The minimum (and
+    /// default) value is 1, which corresponds to a 1000Hz polling rate.
+    const USB_POLL_INTERVAL_MS: u8 = 1;
+
+    /// HID report descriptor used for the keyboard interface. You can override this to change the
+    /// usages in the descriptor, but it must still describe reports in the same format as
+    /// [`NKROBootKeyboardReport`], and must not use report IDs, otherwise the boot protocol will
+    /// not work.
+    ///
+    /// [`NKROBootKeyboardReport`]: usbd_human_interface_device::device::keyboard::NKROBootKeyboardReport
+    const USB_KEYBOARD_REPORT_DESCRIPTOR: &'static [u8] = NKRO_BOOT_KEYBOARD_REPORT_DESCRIPTOR;
+
+    /// HID report descriptor used for the consumer control interface. If you override this, it
+    /// must still describe reports in the same format as [`MultipleConsumerReport`].
+    const USB_CONSUMER_REPORT_DESCRIPTOR: &'static [u8] = MULTIPLE_CODE_REPORT_DESCRIPTOR;
+
+    /// HID report descriptor used for the system control interface. If you override this, it
+    /// must still describe reports in the same format as [`SystemControlReport`].
+    const USB_SYSTEM_CONTROL_REPORT_DESCRIPTOR: &'static [u8] = SYSTEM_CONTROL_REPORT_DESCRIPTOR;
+
+    #[cfg(feature = "mouse")]
+    /// HID report descriptor used for the mouse interface. If you override this, it must still
+    /// describe reports in the same format as [`MouseReport`].
+    const USB_MOUSE_REPORT_DESCRIPTOR: &'static [u8] = MOUSE_REPORT_DESCRIPTOR;
+
+    #[cfg(feature = "gamepad")]
+    /// HID report descriptor used for the gamepad interface. If you override this, it must still
+    /// describe reports in the same format as [`GamepadReport`].
+    const USB_GAMEPAD_REPORT_DESCRIPTOR: &'static [u8] = GAMEPAD_REPORT_DESCRIPTOR;
+
+    #[cfg(feature = "digitizer")]
+    /// HID report descriptor used for the digitizer interface. If you override this, it must
+    /// still describe reports in the same format as [`DigitizerReport`].
+    const USB_DIGITIZER_REPORT_DESCRIPTOR: &'static [u8] = DIGITIZER_REPORT_DESCRIPTOR;
+
+    /// Whether the Unicode mode should be changed to match the OS of the USB host, when it is
+    /// detected. See [`crate::os_detection`] and [`crate::unicode`] for more information.
+    const UNICODE_MODE_FROM_HOST_OS: bool = false;
+
+    /// Add your own interfaces to the USB device, such as extra vendor-defined HID interfaces.
+    /// This is called by `setup_usb_driver`, before any of `rumcake`'s interfaces are added.
+    ///
+    /// Any HID writers or readers that you create here must be stored in a `static` (e.g. using
+    /// [`StaticCell`]), and used by your own tasks.
+    fn setup_usb_interfaces<D: Driver<'static>>(_builder: &mut Builder<'static, D>) {}
+}
+
+/// HID class code.
+const USB_CLASS_HID: u8 = 0x03;
+/// HID subclass code for interfaces that support the boot protocol.
+const USB_SUBCLASS_BOOT: u8 = 0x01;
+/// HID protocol code for boot keyboards.
+const USB_PROTOCOL_KEYBOARD: u8 = 0x01;
+
+const HID_DESC_DESCTYPE_HID: u8 = 0x21;
+const HID_DESC_DESCTYPE_HID_REPORT: u8 = 0x22;
+
+const HID_REQ_GET_IDLE: u8 = 0x02;
+const HID_REQ_GET_PROTOCOL: u8 = 0x03;
+const HID_REQ_SET_REPORT: u8 = 0x09;
+const HID_REQ_SET_IDLE: u8 = 0x0A;
+const HID_REQ_SET_PROTOCOL: u8 = 0x0B;
+
+/// Length of a boot protocol keyboard report (modifiers, a reserved byte, and 6 keys).
+const BOOT_KEYBOARD_REPORT_LEN: usize = 8;
+
+/// Whether the host has requested the boot protocol using `SET_PROTOCOL`. This is usually the case
+/// in BIOS/UEFI setup screens and bootloaders.
+static BOOT_PROTOCOL: BlockingMutex<Cell<bool>> = BlockingMutex::new(Cell::new(false));
+
+/// Handles the HID requests sent to the keyboard interface. The HID class in `embassy-usb` always
+/// reports a subclass and protocol of 0, so boot protocol hosts would never find the keyboard.
+/// Instead, the keyboard interface is built by [`setup_usb_hid_nkro_writer`], and this handler
+/// implements the HID class requests for it, including `GET_PROTOCOL` and `SET_PROTOCOL`.
+struct KeyboardInterfaceHandler {
+    interface: u8,
+    report_descriptor: &'static [u8],
+    hid_descriptor: [u8; 7],
+    idle: u8,
+}
+
+impl KeyboardInterfaceHandler {
+    fn is_keyboard_request(&self, req: &Request) -> bool {
+        req.recipient == Recipient::Interface && req.index == self.interface as u16
+    }
+}
+
+impl Handler for KeyboardInterfaceHandler {
+    fn reset(&mut self) {
+        // The host must switch to the report protocol after a reset
+        BOOT_PROTOCOL.lock(|boot| boot.set(false));
+    }
+
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        if req.request_type != RequestType::Class || !self.is_keyboard_request(&req) {
+            return None;
+        }
+
+        match req.request {
+            HID_REQ_SET_REPORT => {
+                // Only output reports (report type 2) are supported, which contain the lock LEDs
+                if (req.value >> 8) as u8 != 2 || data.is_empty() {
+                    return Some(OutResponse::Rejected);
+                }
+                set_lock_leds(data[0]);
+                Some(OutResponse::Accepted)
+            }
+            HID_REQ_SET_IDLE => {
+                self.idle = (req.value >> 8) as u8;
+                Some(OutResponse::Accepted)
+            }
+            HID_REQ_SET_PROTOCOL => {
+                let boot = req.value == 0;
+                if boot {
+                    warn!("[USB] Host requested boot protocol, only 6 keys will be reported.");
+                }
+                BOOT_PROTOCOL.lock(|protocol| protocol.set(boot));
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if !self.is_keyboard_request(&req) {
+            return None;
+        }
+
+        match (req.request_type, req.request) {
+            (RequestType::Standard, Request::GET_DESCRIPTOR) => match (req.value >> 8) as u8 {
+                HID_DESC_DESCTYPE_HID_REPORT => Some(InResponse::Accepted(self.report_descriptor)),
+                HID_DESC_DESCTYPE_HID => {
+                    // The HID descriptor returned here must include the descriptor header
+                    buf[0] = (self.hid_descriptor.len() + 2) as u8;
+                    buf[1] = HID_DESC_DESCTYPE_HID;
+                    buf[2..(self.hid_descriptor.len() + 2)].copy_from_slice(&self.hid_descriptor);
+                    Some(InResponse::Accepted(&buf[..(self.hid_descriptor.len() + 2)]))
+                }
+                _ => Some(InResponse::Rejected),
+            },
+            (RequestType::Class, HID_REQ_GET_IDLE) => {
+                buf[0] = self.idle;
+                Some(InResponse::Accepted(&buf[0..1]))
+            }
+            (RequestType::Class, HID_REQ_GET_PROTOCOL) => {
+                // 0 = boot protocol, 1 = report protocol
+                buf[0] = if BOOT_PROTOCOL.lock(Cell::get) { 0 } else { 1 };
+                Some(InResponse::Accepted(&buf[0..1]))
+            }
+            (RequestType::Class, _) => Some(InResponse::Rejected),
+            _ => None,
+        }
+    }
+}
+
+/// Update the lock LEDs using an output report sent to the keyboard interface.
+fn set_lock_leds(report: u8) {
+    // Only use LED reports from the USB host if it is the one receiving our HID reports
+    if !matches!(CURRENT_OUTPUT_STATE.try_get(), Some(Some(HIDOutput::Usb))) {
+        return;
+    }
+
+    let leds = LedIndicators::from_bits_truncate(report);
+    debug!("[USB] Received lock LED report: {:?}", Debug2Format(&leds));
+    if !LED_INDICATORS_STATE.try_set(leds) {
+        warn!("[USB] Could not update lock LED state");
+    }
+}
+
+/// Whether the host has enabled remote wakeup using `SET_FEATURE(DEVICE_REMOTE_WAKEUP)`.
+static REMOTE_WAKEUP_ENABLED: BlockingMutex<Cell<bool>> = BlockingMutex::new(Cell::new(false));
+
+/// Whether the USB bus is currently suspended.
+static USB_SUSPENDED: BlockingMutex<Cell<bool>> = BlockingMutex::new(Cell::new(false));
+
+/// Signal used to tell [`start_usb`] to wake up the host.
+static REMOTE_WAKEUP_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+/// Tracks the suspend state of the USB bus, and whether the host has enabled remote wakeup, so
+/// that [`request_remote_wakeup`] can be called from other tasks.
+struct RemoteWakeupHandler;
+
+impl Handler for RemoteWakeupHandler {
+    fn reset(&mut self) {
+        // The remote wakeup feature is cleared by a bus reset
+        REMOTE_WAKEUP_ENABLED.lock(|enabled| enabled.set(false));
+    }
+
+    fn suspended(&mut self, suspended: bool) {
+        USB_SUSPENDED.lock(|state| state.set(suspended));
+    }
+
+    fn remote_wakeup_enabled(&mut self, enabled: bool) {
+        info!("[USB] Remote wakeup enabled: {}", enabled);
+        REMOTE_WAKEUP_ENABLED.lock(|state| state.set(enabled));
+    }
+}
+
+/// Tracks whether the USB device has been configured by a host, so that the output can be switched
+/// automatically when a USB cable is connected or disconnected.
+struct UsbConfiguredHandler;
+
+impl Handler for UsbConfiguredHandler {
+    fn enabled(&mut self, enabled: bool) {
+        if !enabled {
+            crate::os_detection::reset();
+            #[cfg(feature = "mouse")]
+            crate::mouse::set_resolution_multiplier_report(0);
+            if !USB_CONFIGURED_STATE.try_set(false) {
+                warn!("[USB] Could not update USB configured state");
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        // The host must enable high-resolution scrolling again after a reset
+        #[cfg(feature = "mouse")]
+        crate::mouse::set_resolution_multiplier_report(0);
+
+        if !USB_CONFIGURED_STATE.try_set(false) {
+            warn!("[USB] Could not update USB configured state");
+        }
+    }
+
+    fn configured(&mut self, configured: bool) {
+        info!("[USB] USB configured: {}", configured);
+        if !USB_CONFIGURED_STATE.try_set(configured) {
+            warn!("[USB] Could not update USB configured state");
+        }
+    }
+}
+
+/// Wake up the host if the USB bus is suspended, and the host has enabled remote wakeup. This is
+/// called by the matrix polling task when a key is pressed.
+pub fn request_remote_wakeup() {
+    if USB_SUSPENDED.lock(Cell::get) && REMOTE_WAKEUP_ENABLED.lock(Cell::get) {
+        REMOTE_WAKEUP_SIGNAL.signal(());
+    }
+}
+
+/// Trim keyboard reports to the boot report format if the host has requested the boot protocol.
+/// `NKROBootKeyboardReport` starts with a boot-compatible report, so we can just send the first
+/// few bytes.
+fn keyboard_report_bytes(bytes: &[u8]) -> &[u8] {
+    if BOOT_PROTOCOL.lock(Cell::get) {
+        &bytes[..BOOT_KEYBOARD_REPORT_LEN]
+    } else {
+        bytes
+    }
+}
+
+/// Writer for the keyboard HID interface, produced by [`setup_usb_hid_nkro_writer`].
+pub struct KeyboardHidWriter<D: Driver<'static>> {
+    ep_in: D::EndpointIn,
+}
+
+impl<D: Driver<'static>> KeyboardHidWriter<D> {
+    /// Send a keyboard report to the host. Keyboard reports are always smaller than the maximum
+    /// packet size of the endpoint, so they are sent in a single packet.
+    pub async fn write(&mut self, report: &[u8]) -> Result<(), EndpointError> {
+        self.ep_in.write(report).await
+    }
 }
 
 /// Configure the HID report writer, using boot-specification-compatible NKRO keyboard reports.
+/// If the host requests the boot protocol, reports will be sent in the boot keyboard report
+/// format instead.
+///
+/// The keyboard interface uses the boot interface subclass and the keyboard protocol, so that
+/// hosts that only support the boot protocol (e.g. BIOS/UEFI setup screens) can find it. The
+/// interface number is recorded when the interface is added, so other interfaces can be added
+/// before it.
 ///
 /// The HID writer produced should be passed to [`usb_hid_kb_write_task`].
-pub fn setup_usb_hid_nkro_writer(
+pub fn setup_usb_hid_nkro_writer<K: USBKeyboard>(
     b: &mut Builder<'static, impl Driver<'static>>,
-) -> HidWriter<
-    'static,
-    impl Driver<'static>,
-    { <<NKROBootKeyboardReport as PackedStruct>::ByteArray as StaticArray>::LEN },
-> {
+) -> KeyboardHidWriter<impl Driver<'static>> {
+    // Remote wakeup handler
+    static REMOTE_WAKEUP_HANDLER: StaticCell<RemoteWakeupHandler> = StaticCell::new();
+    b.handler(REMOTE_WAKEUP_HANDLER.init(RemoteWakeupHandler));
+
+    // USB configuration handler, used for automatic output switching
+    static USB_CONFIGURED_HANDLER: StaticCell<UsbConfiguredHandler> = StaticCell::new();
+    b.handler(USB_CONFIGURED_HANDLER.init(UsbConfiguredHandler));
+
     // Keyboard HID setup
-    static KB_STATE: StaticCell<UsbState> = StaticCell::new();
-    let kb_state = KB_STATE.init(UsbState::new());
-    let kb_hid_config = Config {
-        request_handler: None,
-        report_descriptor: NKRO_BOOT_KEYBOARD_REPORT_DESCRIPTOR,
-        poll_ms: 1,
-        max_packet_size: 64,
-    };
-    HidWriter::<_, { <<NKROBootKeyboardReport as PackedStruct>::ByteArray as StaticArray>::LEN }>::new(
-        b,
-        kb_state,
-        kb_hid_config,
-    )
+    let report_descriptor = K::USB_KEYBOARD_REPORT_DESCRIPTOR;
+    let hid_descriptor = [
+        0x11, 0x01, // bcdHID (1.11)
+        0x00, // bCountryCode
+        0x01, // bNumDescriptors
+        HID_DESC_DESCTYPE_HID_REPORT,
+        report_descriptor.len() as u8,
+        (report_descriptor.len() >> 8) as u8,
+    ];
+
+    let mut func = b.function(USB_CLASS_HID, USB_SUBCLASS_BOOT, USB_PROTOCOL_KEYBOARD);
+    let mut iface = func.interface();
+    let interface = iface.interface_number();
+    let mut alt = iface.alt_setting(USB_CLASS_HID, USB_SUBCLASS_BOOT, USB_PROTOCOL_KEYBOARD, None);
+    alt.descriptor(HID_DESC_DESCTYPE_HID, &hid_descriptor);
+    let ep_in = alt.endpoint_interrupt_in(64, K::USB_POLL_INTERVAL_MS);
+    drop(func);
+
+    static KEYBOARD_INTERFACE_HANDLER: StaticCell<KeyboardInterfaceHandler> = StaticCell::new();
+    b.handler(KEYBOARD_INTERFACE_HANDLER.init(KeyboardInterfaceHandler {
+        interface: interface.into(),
+        report_descriptor,
+        hid_descriptor,
+        idle: 0,
+    }));
+
+    KeyboardHidWriter { ep_in }
 }
 
 /// Configure the HID report writer, for consumer commands.
 ///
 /// The HID writer produced should be passed to [`usb_hid_consumer_write_task`].
-pub fn setup_usb_hid_consumer_writer(
+pub fn setup_usb_hid_consumer_writer<K: USBKeyboard>(
     b: &mut Builder<'static, impl Driver<'static>>,
 ) -> HidWriter<
     'static,
@@ -80,8 +402,8 @@ pub fn setup_usb_hid_consumer_writer(
     let consumer_state = CONSUMER_STATE.init(UsbState::new());
     let consumer_hid_config = Config {
         request_handler: None,
-        report_descriptor: MULTIPLE_CODE_REPORT_DESCRIPTOR,
-        poll_ms: 1,
+        report_descriptor: K::USB_CONSUMER_REPORT_DESCRIPTOR,
+        poll_ms: K::USB_POLL_INTERVAL_MS,
         max_packet_size: 64,
     };
     HidWriter::<_, { <<MultipleConsumerReport as PackedStruct>::ByteArray as StaticArray>::LEN }>::new(
@@ -91,6 +413,158 @@ pub fn setup_usb_hid_consumer_writer(
     )
 }
 
+/// Configure the HID report writer, for system control commands.
+///
+/// The HID writer produced should be passed to [`usb_hid_system_control_write_task`].
+pub fn setup_usb_hid_system_control_writer<K: USBKeyboard>(
+    b: &mut Builder<'static, impl Driver<'static>>,
+) -> HidWriter<
+    'static,
+    impl Driver<'static>,
+    { <<SystemControlReport as PackedStruct>::ByteArray as StaticArray>::LEN },
+> {
+    static SYSTEM_CONTROL_STATE: StaticCell<UsbState> = StaticCell::new();
+    let system_control_state = SYSTEM_CONTROL_STATE.init(UsbState::new());
+    let system_control_hid_config = Config {
+        request_handler: None,
+        report_descriptor: K::USB_SYSTEM_CONTROL_REPORT_DESCRIPTOR,
+        poll_ms: K::USB_POLL_INTERVAL_MS,
+        max_packet_size: 64,
+    };
+    HidWriter::<_, { <<SystemControlReport as PackedStruct>::ByteArray as StaticArray>::LEN }>::new(
+        b,
+        system_control_state,
+        system_control_hid_config,
+    )
+}
+
+#[cfg(feature = "mouse")]
+/// Configure the HID report writer, for relative mouse reports.
+///
+/// The HID writer produced should be passed to [`usb_hid_mouse_write_task`].
+pub fn setup_usb_hid_mouse_writer<K: USBKeyboard>(
+    b: &mut Builder<'static, impl Driver<'static>>,
+) -> HidWriter<
+    'static,
+    impl Driver<'static>,
+    { <<MouseReport as PackedStruct>::ByteArray as StaticArray>::LEN },
+> {
+    // Mouse HID setup
+    static MOUSE_STATE: StaticCell<UsbState> = StaticCell::new();
+    let mouse_state = MOUSE_STATE.init(UsbState::new());
+    let mouse_hid_config = Config {
+        request_handler: Some(&MOUSE_REQUEST_HANDLER),
+        report_descriptor: K::USB_MOUSE_REPORT_DESCRIPTOR,
+        poll_ms: K::USB_POLL_INTERVAL_MS,
+        max_packet_size: 64,
+    };
+    HidWriter::<_, { <<MouseReport as PackedStruct>::ByteArray as StaticArray>::LEN }>::new(
+        b,
+        mouse_state,
+        mouse_hid_config,
+    )
+}
+
+#[cfg(feature = "mouse")]
+/// Handles feature reports sent to the mouse interface, which contain the resolution multipliers
+/// used for high-resolution scrolling.
+struct MouseRequestHandler;
+
+#[cfg(feature = "mouse")]
+static MOUSE_REQUEST_HANDLER: MouseRequestHandler = MouseRequestHandler;
+
+#[cfg(feature = "mouse")]
+impl RequestHandler for MouseRequestHandler {
+    fn get_report(&self, id: ReportId, buf: &mut [u8]) -> Option<usize> {
+        if !matches!(id, ReportId::Feature(_)) || buf.is_empty() {
+            return None;
+        }
+
+        buf[0] = crate::mouse::get_resolution_multiplier_report();
+        Some(1)
+    }
+
+    fn set_report(&self, id: ReportId, buf: &[u8]) -> OutResponse {
+        if !matches!(id, ReportId::Feature(_)) || buf.is_empty() {
+            return OutResponse::Rejected;
+        }
+
+        crate::mouse::set_resolution_multiplier_report(buf[0]);
+
+        OutResponse::Accepted
+    }
+
+    fn get_idle_ms(&self, _id: Option<ReportId>) -> Option<u32> {
+        None
+    }
+
+    fn set_idle_ms(&self, _id: Option<ReportId>, _duration_ms: u32) {}
+}
+
+#[cfg(feature = "gamepad")]
+/// Configure the HID report writer, for gamepad reports.
+///
+/// The HID writer produced should be passed to [`usb_hid_gamepad_write_task`].
+pub fn setup_usb_hid_gamepad_writer<K: USBKeyboard>(
+    b: &mut Builder<'static, impl Driver<'static>>,
+) -> HidWriter<
+    'static,
+    impl Driver<'static>,
+    { <<GamepadReport as PackedStruct>::ByteArray as StaticArray>::LEN },
+> {
+    // Gamepad HID setup
+    static GAMEPAD_STATE: StaticCell<UsbState> = StaticCell::new();
+    let gamepad_state = GAMEPAD_STATE.init(UsbState::new());
+    let gamepad_hid_config = Config {
+        request_handler: None,
+        report_descriptor: K::USB_GAMEPAD_REPORT_DESCRIPTOR,
+        poll_ms: K::USB_POLL_INTERVAL_MS,
+        max_packet_size: 64,
+    };
+    HidWriter::<_, { <<GamepadReport as PackedStruct>::ByteArray as StaticArray>::LEN }>::new(
+        b,
+        gamepad_state,
+        gamepad_hid_config,
+    )
+}
+
+#[cfg(feature = "digitizer")]
+/// Configure the HID report writer, for absolute pointer (digitizer) reports.
+///
+/// The HID writer produced should be passed to [`usb_hid_digitizer_write_task`].
+pub fn setup_usb_hid_digitizer_writer<K: USBKeyboard>(
+    b: &mut Builder<'static, impl Driver<'static>>,
+) -> HidWriter<
+    'static,
+    impl Driver<'static>,
+    { <<DigitizerReport as PackedStruct>::ByteArray as StaticArray>::LEN },
+> {
+    // Digitizer HID setup
+    static DIGITIZER_STATE: StaticCell<UsbState> = StaticCell::new();
+    let digitizer_state = DIGITIZER_STATE.init(UsbState::new());
+    let digitizer_hid_config = Config {
+        request_handler: None,
+        report_descriptor: K::USB_DIGITIZER_REPORT_DESCRIPTOR,
+        poll_ms: K::USB_POLL_INTERVAL_MS,
+        max_packet_size: 64,
+    };
+    HidWriter::<_, { <<DigitizerReport as PackedStruct>::ByteArray as StaticArray>::LEN }>::new(
+        b,
+        digitizer_state,
+        digitizer_hid_config,
+    )
+}
+
+#[cfg(feature = "midi")]
+/// Configure a USB MIDI interface, with one input jack and one output jack.
+///
+/// The MIDI class produced should be passed to [`usb_midi_write_task`].
+pub fn setup_usb_midi(
+    b: &mut Builder<'static, impl Driver<'static>>,
+) -> MidiClass<'static, impl Driver<'static>> {
+    MidiClass::new(b, 1, 1, 64)
+}
+
 #[rumcake_macros::task]
 pub async fn start_usb(mut usb: UsbDevice<'static, impl Driver<'static>>) {
     loop {
@@ -99,49 +573,162 @@ pub async fn start_usb(mut usb: UsbDevice<'static, impl Driver<'static>>) {
         usb.run_until_suspend().await;
         info!("[USB] USB suspended");
         USB_RUNNING_STATE.set(false).await;
-        usb.wait_resume().await;
+        USB_SUSPENDED_STATE.set(true).await;
+        notify_lighting_suspended(true);
+        REMOTE_WAKEUP_SIGNAL.reset();
+
+        loop {
+            match select(usb.wait_resume(), REMOTE_WAKEUP_SIGNAL.wait()).await {
+                select::Either::First(()) => break,
+                select::Either::Second(()) => {
+                    // Avoid waking up the host if it isn't the one receiving our HID reports
+                    if !matches!(
+                        OUTPUT_MODE_STATE.get().await,
+                        OutputMode::Usb | OutputMode::Auto
+                    ) {
+                        continue;
+                    }
+
+                    info!("[USB] Waking up host");
+                    match usb.remote_wakeup().await {
+                        Ok(()) => break,
+                        Err(err) => {
+                            warn!("[USB] Could not wake up host: {:?}", Debug2Format(&err));
+                        }
+                    }
+                }
+            }
+        }
+
+        info!("[USB] USB resumed");
+        USB_SUSPENDED_STATE.set(false).await;
+        notify_lighting_suspended(false);
+    }
+}
+
+/// Tell the lighting tasks to turn off their LEDs while the host is asleep, or to turn them back
+/// on. This does not change the saved lighting config.
+fn notify_lighting_suspended(suspended: bool) {
+    #[cfg(feature = "simple-backlight")]
+    {
+        use crate::backlight::simple_backlight::animations::BacklightCommand;
+        let _ =
+            crate::backlight::simple_backlight::BACKLIGHT_COMMAND_CHANNEL.try_send(if suspended {
+                BacklightCommand::Suspend
+            } else {
+                BacklightCommand::Resume
+            });
+    }
+
+    #[cfg(feature = "simple-backlight-matrix")]
+    {
+        use crate::backlight::simple_backlight_matrix::animations::BacklightCommand;
+        let _ = crate::backlight::simple_backlight_matrix::BACKLIGHT_COMMAND_CHANNEL.try_send(
+            if suspended {
+                BacklightCommand::Suspend
+            } else {
+                BacklightCommand::Resume
+            },
+        );
+    }
+
+    #[cfg(feature = "rgb-backlight-matrix")]
+    {
+        use crate::backlight::rgb_backlight_matrix::animations::BacklightCommand;
+        let _ = crate::backlight::rgb_backlight_matrix::BACKLIGHT_COMMAND_CHANNEL.try_send(
+            if suspended {
+                BacklightCommand::Suspend
+            } else {
+                BacklightCommand::Resume
+            },
+        );
+    }
+
+    #[cfg(feature = "underglow")]
+    {
+        use crate::underglow::animations::UnderglowCommand;
+        let _ = crate::underglow::UNDERGLOW_COMMAND_CHANNEL.try_send(if suspended {
+            UnderglowCommand::Suspend
+        } else {
+            UnderglowCommand::Resume
+        });
     }
 }
 
+/// Write reports received from a channel to a USB HID interface.
+///
+/// If `$skip_duplicates` is `true`, reports that are identical to the last report written will be
+/// skipped. This should not be used for reports with relative values (e.g. mouse movement), or
+/// reports that are used as request/response messages (e.g. raw HID).
 macro_rules! usb_task_inner {
     ($hid:ident, $output_listener:path, $channel:path, $info_log:literal, $error_log:literal) => {
+        usb_task_inner!(
+            $hid,
+            $output_listener,
+            $channel,
+            $info_log,
+            $error_log,
+            core::convert::identity,
+            false
+        )
+    };
+    ($hid:ident, $output_listener:path, $channel:path, $info_log:literal, $error_log:literal, $bytes:path, $skip_duplicates:literal) => {{
+        let mut last_report = None;
+
         loop {
             if matches!(CURRENT_OUTPUT_STATE.get().await, Some(HIDOutput::Usb)) {
                 match select($output_listener.wait(), $channel.receive()).await {
-                    select::Either::First(()) => {}
+                    select::Either::First(()) => {
+                        // The host may have changed, so the next report must be sent even if it
+                        // is identical to the last one
+                        last_report = None;
+                    }
                     select::Either::Second(report) => {
+                        let bytes = report.pack().unwrap();
+                        if $skip_duplicates && last_report == Some(bytes) {
+                            debug!(
+                                "[USB] Skipping duplicate report: {:?}",
+                                Debug2Format(&report)
+                            );
+                            continue;
+                        }
+
                         info!($info_log, Debug2Format(&report));
-                        if let Err(err) = $hid.write(&report.pack().unwrap()).await {
-                            error!($error_log, Debug2Format(&err));
+                        match $hid.write($bytes(&bytes)).await {
+                            Ok(()) => {
+                                if $skip_duplicates {
+                                    last_report = Some(bytes);
+                                }
+                            }
+                            Err(err) => {
+                                error!($error_log, Debug2Format(&err));
+                            }
                         };
                     }
                 }
             } else {
                 $output_listener.wait().await;
+                last_report = None;
 
                 // Ignore any unprocessed reports due to lack of a connection
                 while $channel.try_receive().is_ok() {}
             }
         }
-    };
+    }};
 }
 
 pub(crate) static KB_CURRENT_OUTPUT_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
 
 #[rumcake_macros::task]
-pub async fn usb_hid_kb_write_task(
-    mut hid: HidWriter<
-        'static,
-        impl Driver<'static>,
-        { <<NKROBootKeyboardReport as PackedStruct>::ByteArray as StaticArray>::LEN },
-    >,
-) {
+pub async fn usb_hid_kb_write_task(mut hid: KeyboardHidWriter<impl Driver<'static>>) {
     usb_task_inner!(
         hid,
         KB_CURRENT_OUTPUT_STATE_LISTENER,
         KEYBOARD_REPORT_HID_SEND_CHANNEL,
         "[USB] Writing NKRO HID keyboard report to USB: {:?}",
-        "[USB] Couldn't write HID keyboard report: {:?}"
+        "[USB] Couldn't write HID keyboard report: {:?}",
+        keyboard_report_bytes,
+        true
     )
 }
 
@@ -160,48 +747,418 @@ pub async fn usb_hid_consumer_write_task(
         CONSUMER_CURRENT_OUTPUT_STATE_LISTENER,
         CONSUMER_REPORT_HID_SEND_CHANNEL,
         "[USB] Writing consumer HID report to USB: {:?}",
-        "[USB] Couldn't write consumer HID report: {:?}"
+        "[USB] Couldn't write consumer HID report: {:?}",
+        core::convert::identity,
+        true
+    );
+}
+
+pub(crate) static SYSTEM_CONTROL_CURRENT_OUTPUT_STATE_LISTENER: Signal<RawMutex, ()> =
+    Signal::new();
+
+#[rumcake_macros::task]
+pub async fn usb_hid_system_control_write_task(
+    mut hid: HidWriter<
+        'static,
+        impl Driver<'static>,
+        { <<SystemControlReport as PackedStruct>::ByteArray as StaticArray>::LEN },
+    >,
+) {
+    usb_task_inner!(
+        hid,
+        SYSTEM_CONTROL_CURRENT_OUTPUT_STATE_LISTENER,
+        SYSTEM_CONTROL_REPORT_HID_SEND_CHANNEL,
+        "[USB] Writing system control HID report to USB: {:?}",
+        "[USB] Couldn't write system control HID report: {:?}",
+        core::convert::identity,
+        true
+    );
+}
+
+#[cfg(feature = "mouse")]
+pub(crate) static MOUSE_CURRENT_OUTPUT_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+#[cfg(feature = "mouse")]
+#[rumcake_macros::task]
+pub async fn usb_hid_mouse_write_task(
+    mut hid: HidWriter<
+        'static,
+        impl Driver<'static>,
+        { <<MouseReport as PackedStruct>::ByteArray as StaticArray>::LEN },
+    >,
+) {
+    usb_task_inner!(
+        hid,
+        MOUSE_CURRENT_OUTPUT_STATE_LISTENER,
+        crate::mouse::MOUSE_REPORT_HID_SEND_CHANNEL,
+        "[USB] Writing mouse HID report to USB: {:?}",
+        "[USB] Couldn't write mouse HID report: {:?}"
     );
 }
 
-#[cfg(feature = "via")]
-struct ViaCommandHandler;
+#[cfg(feature = "gamepad")]
+pub(crate) static GAMEPAD_CURRENT_OUTPUT_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
 
-#[cfg(feature = "via")]
-/// Configure the HID report reader and writer for Via/Vial packets.
+#[cfg(feature = "gamepad")]
+#[rumcake_macros::task]
+pub async fn usb_hid_gamepad_write_task(
+    mut hid: HidWriter<
+        'static,
+        impl Driver<'static>,
+        { <<GamepadReport as PackedStruct>::ByteArray as StaticArray>::LEN },
+    >,
+) {
+    usb_task_inner!(
+        hid,
+        GAMEPAD_CURRENT_OUTPUT_STATE_LISTENER,
+        crate::gamepad::GAMEPAD_REPORT_HID_SEND_CHANNEL,
+        "[USB] Writing gamepad HID report to USB: {:?}",
+        "[USB] Couldn't write gamepad HID report: {:?}",
+        core::convert::identity,
+        true
+    );
+}
+
+#[cfg(feature = "digitizer")]
+pub(crate) static DIGITIZER_CURRENT_OUTPUT_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+#[cfg(feature = "digitizer")]
+#[rumcake_macros::task]
+pub async fn usb_hid_digitizer_write_task(
+    mut hid: HidWriter<
+        'static,
+        impl Driver<'static>,
+        { <<DigitizerReport as PackedStruct>::ByteArray as StaticArray>::LEN },
+    >,
+) {
+    usb_task_inner!(
+        hid,
+        DIGITIZER_CURRENT_OUTPUT_STATE_LISTENER,
+        crate::digitizer::DIGITIZER_REPORT_HID_SEND_CHANNEL,
+        "[USB] Writing digitizer HID report to USB: {:?}",
+        "[USB] Couldn't write digitizer HID report: {:?}",
+        core::convert::identity,
+        true
+    );
+}
+
+#[cfg(feature = "midi")]
+#[rumcake_macros::task]
+pub async fn usb_midi_write_task(mut class: MidiClass<'static, impl Driver<'static>>) {
+    loop {
+        class.wait_connection().await;
+        info!("[USB] MIDI interface connected");
+
+        // Ignore any unprocessed messages due to lack of a connection
+        while crate::midi::MIDI_MESSAGE_SEND_CHANNEL.try_receive().is_ok() {}
+
+        loop {
+            let message = crate::midi::MIDI_MESSAGE_SEND_CHANNEL.receive().await;
+            info!(
+                "[USB] Writing MIDI message to USB: {:?}",
+                Debug2Format(&message)
+            );
+            if let Err(err) = class.write_packet(&message.to_usb_packet()).await {
+                error!(
+                    "[USB] Couldn't write MIDI message: {:?}",
+                    Debug2Format(&err)
+                );
+
+                if matches!(err, EndpointError::Disabled) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "console")]
+/// Configure a USB CDC-ACM (serial) interface, used for the console.
 ///
-/// The reader should be passed to [`usb_hid_via_read_task`], and the writer should be passed to
-/// [`usb_hid_via_write_task`].
-pub fn setup_usb_via_hid_reader_writer(
+/// The class produced should be passed to [`usb_console_task`].
+pub fn setup_usb_console(
+    b: &mut Builder<'static, impl Driver<'static>>,
+) -> CdcAcmClass<'static, impl Driver<'static>> {
+    static CONSOLE_STATE: StaticCell<CdcAcmState> = StaticCell::new();
+    let console_state = CONSOLE_STATE.init(CdcAcmState::new());
+    CdcAcmClass::new(b, console_state, 64)
+}
+
+#[cfg(feature = "console")]
+#[rumcake_macros::task]
+pub async fn usb_console_task(class: CdcAcmClass<'static, impl Driver<'static>>) {
+    let (mut sender, mut receiver) = class.split();
+
+    let write_fut = async {
+        let mut buf = [0; 64];
+
+        loop {
+            sender.wait_connection().await;
+            info!("[USB] Console connected");
+
+            // Ignore any unprocessed output due to lack of a connection
+            while crate::console::CONSOLE_OUTPUT_PIPE
+                .try_read(&mut buf)
+                .is_ok()
+            {}
+            crate::console_println!("rumcake console. Type `help` for a list of commands.");
+            crate::console::print_prompt();
+
+            loop {
+                let len = crate::console::CONSOLE_OUTPUT_PIPE.read(&mut buf).await;
+                if let Err(err) = sender.write_packet(&buf[..len]).await {
+                    error!(
+                        "[USB] Couldn't write console output: {:?}",
+                        Debug2Format(&err)
+                    );
+
+                    if matches!(err, EndpointError::Disabled) {
+                        break;
+                    }
+                }
+            }
+        }
+    };
+
+    let read_fut = async {
+        let mut buf = [0; 64];
+
+        loop {
+            receiver.wait_connection().await;
+
+            loop {
+                match receiver.read_packet(&mut buf).await {
+                    Ok(len) => {
+                        crate::console::CONSOLE_INPUT_PIPE
+                            .write_all(&buf[..len])
+                            .await
+                    }
+                    Err(EndpointError::Disabled) => break,
+                    Err(err) => {
+                        error!(
+                            "[USB] Couldn't read console input: {:?}",
+                            Debug2Format(&err)
+                        );
+                    }
+                }
+            }
+        }
+    };
+
+    embassy_futures::join::join(write_fut, read_fut).await;
+}
+
+#[cfg(feature = "hid-console")]
+/// Configure a HID console interface, compatible with QMK's `hid_listen` and QMK Toolbox.
+///
+/// The writer produced should be passed to [`usb_hid_console_write_task`].
+pub fn setup_usb_hid_console_writer(
+    b: &mut Builder<'static, impl Driver<'static>>,
+) -> HidWriter<'static, impl Driver<'static>, { crate::console::HID_CONSOLE_REPORT_SIZE }> {
+    static HID_CONSOLE_STATE: StaticCell<UsbState> = StaticCell::new();
+    let hid_console_state = HID_CONSOLE_STATE.init(UsbState::new());
+    let hid_console_config = Config {
+        request_handler: None,
+        report_descriptor: crate::console::HID_CONSOLE_REPORT_DESCRIPTOR,
+        poll_ms: 1,
+        max_packet_size: crate::console::HID_CONSOLE_REPORT_SIZE as u16,
+    };
+    HidWriter::new(b, hid_console_state, hid_console_config)
+}
+
+#[cfg(feature = "hid-console")]
+#[rumcake_macros::task]
+pub async fn usb_hid_console_write_task(
+    mut hid: HidWriter<'static, impl Driver<'static>, { crate::console::HID_CONSOLE_REPORT_SIZE }>,
+) {
+    loop {
+        // Unused bytes are left as 0, which hid_listen ignores
+        let mut report = [0; crate::console::HID_CONSOLE_REPORT_SIZE];
+        crate::console::HID_CONSOLE_OUTPUT_PIPE
+            .read(&mut report)
+            .await;
+
+        debug!("[USB] Writing HID console report");
+
+        if let Err(err) = hid.write(&report).await {
+            error!(
+                "[USB] Couldn't write HID console report: {:?}",
+                Debug2Format(&err)
+            );
+        };
+    }
+}
+
+#[cfg(feature = "dongle")]
+pub(crate) static DONGLE_KEYBOARDS_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+#[cfg(feature = "dongle")]
+/// Configure a HID interface used by a dongle to report the battery level of its keyboards.
+///
+/// The writer produced should be passed to [`usb_hid_battery_write_task`].
+pub fn setup_usb_hid_battery_writer(
+    b: &mut Builder<'static, impl Driver<'static>>,
+) -> HidWriter<'static, impl Driver<'static>, { crate::dongle::BATTERY_REPORT_SIZE }> {
+    static BATTERY_STATE: StaticCell<UsbState> = StaticCell::new();
+    let battery_state = BATTERY_STATE.init(UsbState::new());
+    let battery_config = Config {
+        request_handler: None,
+        report_descriptor: crate::dongle::BATTERY_REPORT_DESCRIPTOR,
+        poll_ms: 255,
+        max_packet_size: crate::dongle::BATTERY_REPORT_SIZE as u16,
+    };
+    HidWriter::new(b, battery_state, battery_config)
+}
+
+#[cfg(feature = "dongle")]
+#[rumcake_macros::task]
+pub async fn usb_hid_battery_write_task(
+    mut hid: HidWriter<'static, impl Driver<'static>, { crate::dongle::BATTERY_REPORT_SIZE }>,
+) {
+    let mut last_level = None;
+
+    loop {
+        // If the keyboards have different battery levels, the lowest one is reported
+        let keyboards = crate::dongle::KEYBOARDS_STATE.get().await;
+        let level = crate::dongle::lowest_battery_level(&keyboards);
+
+        if let Some(level) = level.filter(|_| level != last_level) {
+            debug!("[USB] Writing battery level report: {}%", level);
+
+            match hid.write(&[level]).await {
+                Ok(()) => last_level = Some(level),
+                Err(err) => {
+                    error!(
+                        "[USB] Couldn't write battery level report: {:?}",
+                        Debug2Format(&err)
+                    );
+                }
+            }
+        }
+
+        DONGLE_KEYBOARDS_STATE_LISTENER.wait().await;
+    }
+}
+
+#[cfg(feature = "raw-hid")]
+struct RawHIDRequestHandler;
+
+#[cfg(feature = "raw-hid")]
+/// Configure the HID report reader and writer for raw HID reports. This is used by Via and Vial.
+///
+/// The reader should be passed to [`usb_hid_raw_read_task`], and the writer should be passed to
+/// [`usb_hid_raw_write_task`].
+pub fn setup_usb_raw_hid_reader_writer(
+    builder: &mut Builder<'static, impl Driver<'static>>,
+) -> HidReaderWriter<'static, impl Driver<'static>, RAW_HID_REPORT_SIZE, RAW_HID_REPORT_SIZE> {
+    static RAW_HID_STATE: StaticCell<UsbState> = StaticCell::new();
+    let raw_hid_state = RAW_HID_STATE.init(UsbState::new());
+    let raw_hid_config = Config {
+        request_handler: Some(&RAW_HID_REQUEST_HANDLER),
+        report_descriptor: crate::raw_hid::RAW_HID_REPORT_DESCRIPTOR,
+        poll_ms: 1,
+        max_packet_size: RAW_HID_REPORT_SIZE as u16,
+    };
+    HidReaderWriter::<_, RAW_HID_REPORT_SIZE, RAW_HID_REPORT_SIZE>::new(
+        builder,
+        raw_hid_state,
+        raw_hid_config,
+    )
+}
+
+#[cfg(feature = "raw-hid")]
+static RAW_HID_REQUEST_HANDLER: RawHIDRequestHandler = RawHIDRequestHandler;
+
+#[cfg(feature = "raw-hid")]
+impl RequestHandler for RawHIDRequestHandler {
+    fn get_report(&self, _id: ReportId, _buf: &mut [u8]) -> Option<usize> {
+        None
+    }
+
+    fn set_report(&self, _id: ReportId, buf: &[u8]) -> OutResponse {
+        let mut data: [u8; RAW_HID_REPORT_SIZE] = [0; RAW_HID_REPORT_SIZE];
+        data.copy_from_slice(buf);
+
+        if let Err(err) = crate::raw_hid::RAW_HID_REPORT_RECEIVE_CHANNEL.try_send(data) {
+            error!(
+                "[USB] Could not queue the raw HID report to be processed: {:?}",
+                err
+            );
+        };
+
+        OutResponse::Accepted
+    }
+
+    fn get_idle_ms(&self, _id: Option<ReportId>) -> Option<u32> {
+        None
+    }
+
+    fn set_idle_ms(&self, _id: Option<ReportId>, _duration_ms: u32) {}
+}
+
+#[cfg(feature = "raw-hid")]
+#[rumcake_macros::task]
+pub async fn usb_hid_raw_read_task(
+    hid: HidReader<'static, impl Driver<'static>, RAW_HID_REPORT_SIZE>,
+) {
+    hid.run(false, &RAW_HID_REQUEST_HANDLER).await;
+}
+
+#[cfg(feature = "raw-hid")]
+pub(crate) static RAW_HID_CURRENT_OUTPUT_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+
+#[cfg(feature = "raw-hid")]
+#[rumcake_macros::task]
+pub async fn usb_hid_raw_write_task(
+    mut hid: HidWriter<'static, impl Driver<'static>, RAW_HID_REPORT_SIZE>,
+) {
+    usb_task_inner!(
+        hid,
+        RAW_HID_CURRENT_OUTPUT_STATE_LISTENER,
+        crate::raw_hid::RAW_HID_REPORT_SEND_CHANNEL,
+        "[USB] Writing raw HID report: {:?}",
+        "[USB] Couldn't write raw HID report: {:?}"
+    )
+}
+
+#[cfg(feature = "xap")]
+struct XapRequestHandler;
+
+#[cfg(feature = "xap")]
+/// Configure the HID report reader and writer for XAP reports.
+///
+/// The reader should be passed to [`usb_hid_xap_read_task`], and the writer should be passed to
+/// [`usb_hid_xap_write_task`].
+pub fn setup_usb_xap_reader_writer(
     builder: &mut Builder<'static, impl Driver<'static>>,
-) -> HidReaderWriter<'static, impl Driver<'static>, 32, 32> {
-    static VIA_STATE: StaticCell<UsbState> = StaticCell::new();
-    let via_state = VIA_STATE.init(UsbState::new());
-    let via_hid_config = Config {
-        request_handler: Some(&VIA_COMMAND_HANDLER),
-        report_descriptor: crate::via::VIA_REPORT_DESCRIPTOR,
+) -> HidReaderWriter<'static, impl Driver<'static>, XAP_REPORT_SIZE, XAP_REPORT_SIZE> {
+    static XAP_STATE: StaticCell<UsbState> = StaticCell::new();
+    let xap_state = XAP_STATE.init(UsbState::new());
+    let xap_config = Config {
+        request_handler: Some(&XAP_REQUEST_HANDLER),
+        report_descriptor: crate::xap::XAP_REPORT_DESCRIPTOR,
         poll_ms: 1,
-        max_packet_size: 32,
+        max_packet_size: XAP_REPORT_SIZE as u16,
     };
-    HidReaderWriter::<_, 32, 32>::new(builder, via_state, via_hid_config)
+    HidReaderWriter::<_, XAP_REPORT_SIZE, XAP_REPORT_SIZE>::new(builder, xap_state, xap_config)
 }
 
-#[cfg(feature = "via")]
-static VIA_COMMAND_HANDLER: ViaCommandHandler = ViaCommandHandler;
+#[cfg(feature = "xap")]
+static XAP_REQUEST_HANDLER: XapRequestHandler = XapRequestHandler;
 
-#[cfg(feature = "via")]
-impl RequestHandler for ViaCommandHandler {
+#[cfg(feature = "xap")]
+impl RequestHandler for XapRequestHandler {
     fn get_report(&self, _id: ReportId, _buf: &mut [u8]) -> Option<usize> {
         None
     }
 
     fn set_report(&self, _id: ReportId, buf: &[u8]) -> OutResponse {
-        let mut data: [u8; 32] = [0; 32];
+        let mut data: [u8; XAP_REPORT_SIZE] = [0; XAP_REPORT_SIZE];
         data.copy_from_slice(buf);
 
-        if let Err(err) = crate::via::VIA_REPORT_HID_RECEIVE_CHANNEL.try_send(data) {
+        if let Err(err) = crate::xap::XAP_REPORT_RECEIVE_CHANNEL.try_send(data) {
             error!(
-                "[VIA] Could not queue the Via command to be processed: {:?}",
+                "[USB] Could not queue the XAP report to be processed: {:?}",
                 err
             );
         };
@@ -216,23 +1173,234 @@ impl RequestHandler for ViaCommandHandler {
     fn set_idle_ms(&self, _id: Option<ReportId>, _duration_ms: u32) {}
 }
 
-#[cfg(feature = "via")]
+#[cfg(feature = "xap")]
 #[rumcake_macros::task]
-pub async fn usb_hid_via_read_task(hid: HidReader<'static, impl Driver<'static>, 32>) {
-    hid.run(false, &VIA_COMMAND_HANDLER).await;
+pub async fn usb_hid_xap_read_task(hid: HidReader<'static, impl Driver<'static>, XAP_REPORT_SIZE>) {
+    hid.run(false, &XAP_REQUEST_HANDLER).await;
 }
 
-#[cfg(feature = "via")]
-pub(crate) static VIA_CURRENT_OUTPUT_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
+#[cfg(feature = "xap")]
+pub(crate) static XAP_CURRENT_OUTPUT_STATE_LISTENER: Signal<RawMutex, ()> = Signal::new();
 
-#[cfg(feature = "via")]
+#[cfg(feature = "xap")]
 #[rumcake_macros::task]
-pub async fn usb_hid_via_write_task(mut hid: HidWriter<'static, impl Driver<'static>, 32>) {
+pub async fn usb_hid_xap_write_task(
+    mut hid: HidWriter<'static, impl Driver<'static>, XAP_REPORT_SIZE>,
+) {
     usb_task_inner!(
         hid,
-        VIA_CURRENT_OUTPUT_STATE_LISTENER,
-        crate::via::VIA_REPORT_HID_SEND_CHANNEL,
-        "[USB] Writing HID via report: {:?}",
-        "[USB] Couldn't write HID via report: {:?}"
+        XAP_CURRENT_OUTPUT_STATE_LISTENER,
+        crate::xap::XAP_REPORT_SEND_CHANNEL,
+        "[USB] Writing XAP report: {:?}",
+        "[USB] Couldn't write XAP report: {:?}"
     )
 }
+
+#[cfg(feature = "lamp-array")]
+struct LampArrayRequestHandler {
+    lamp_count: u16,
+    write_lamp_array_attributes: fn(&mut [u8]) -> usize,
+    write_lamp_attributes: fn(u16, &mut [u8]) -> usize,
+    /// Lamp that will be described in the next LampAttributesResponseReport read by the host.
+    next_lamp_id: BlockingMutex<Cell<u16>>,
+}
+
+#[cfg(feature = "lamp-array")]
+/// Configure a HID interface that exposes the keyboard's RGB backlight matrix as a LampArray.
+///
+/// Reports from the host are processed by [`crate::lamp_array::lamp_array_task`].
+pub fn setup_usb_lamp_array<K: crate::lamp_array::LampArrayDevice>(
+    builder: &mut Builder<'static, impl Driver<'static>>,
+) where
+    [(); K::LIGHTING_COLS]:,
+    [(); K::LIGHTING_ROWS]:,
+{
+    static LAMP_ARRAY_REQUEST_HANDLER: StaticCell<LampArrayRequestHandler> = StaticCell::new();
+    let lamp_array_request_handler = LAMP_ARRAY_REQUEST_HANDLER.init(LampArrayRequestHandler {
+        lamp_count: crate::lamp_array::lamp_count::<K>(),
+        write_lamp_array_attributes: crate::lamp_array::write_lamp_array_attributes_report::<K>,
+        write_lamp_attributes: crate::lamp_array::write_lamp_attributes_report::<K>,
+        next_lamp_id: BlockingMutex::new(Cell::new(0)),
+    });
+
+    static LAMP_ARRAY_STATE: StaticCell<UsbState> = StaticCell::new();
+    let lamp_array_state = LAMP_ARRAY_STATE.init(UsbState::new());
+    let lamp_array_config = Config {
+        request_handler: Some(lamp_array_request_handler),
+        report_descriptor: crate::lamp_array::LAMP_ARRAY_REPORT_DESCRIPTOR,
+        poll_ms: 255,
+        max_packet_size: 8,
+    };
+
+    // All LampArray reports are feature reports, which are handled by the request handler over
+    // the control pipe. The writer is only needed to create the interface's IN endpoint, since
+    // no input reports are ever sent.
+    HidWriter::<_, 8>::new(builder, lamp_array_state, lamp_array_config);
+}
+
+#[cfg(feature = "lamp-array")]
+impl RequestHandler for LampArrayRequestHandler {
+    fn get_report(&self, id: ReportId, buf: &mut [u8]) -> Option<usize> {
+        match id {
+            ReportId::Feature(crate::lamp_array::LAMP_ARRAY_ATTRIBUTES_REPORT_ID) => {
+                Some((self.write_lamp_array_attributes)(buf))
+            }
+            ReportId::Feature(crate::lamp_array::LAMP_ATTRIBUTES_RESPONSE_REPORT_ID) => {
+                // The lamp ID increments after each read, so that the host can read the
+                // attributes of every lamp without sending a request for each one
+                let lamp_id = self.next_lamp_id.lock(|next| {
+                    let lamp_id = next.get();
+                    next.set(if lamp_id + 1 < self.lamp_count {
+                        lamp_id + 1
+                    } else {
+                        0
+                    });
+                    lamp_id
+                });
+                Some((self.write_lamp_attributes)(lamp_id, buf))
+            }
+            _ => None,
+        }
+    }
+
+    fn set_report(&self, id: ReportId, buf: &[u8]) -> OutResponse {
+        let ReportId::Feature(id) = id else {
+            return OutResponse::Rejected;
+        };
+
+        if id == crate::lamp_array::LAMP_ATTRIBUTES_REQUEST_REPORT_ID && buf.len() >= 3 {
+            let lamp_id = u16::from_le_bytes([buf[1], buf[2]]);
+            self.next_lamp_id
+                .lock(|next| next.set(lamp_id.min(self.lamp_count.saturating_sub(1))));
+            return OutResponse::Accepted;
+        }
+
+        let Some(command) = crate::lamp_array::parse_lamp_array_command(id, buf) else {
+            return OutResponse::Rejected;
+        };
+
+        if let Err(err) = crate::lamp_array::LAMP_ARRAY_COMMAND_CHANNEL.try_send(command) {
+            error!(
+                "[USB] Could not queue the LampArray report to be processed: {:?}",
+                Debug2Format(&err)
+            );
+        };
+
+        OutResponse::Accepted
+    }
+
+    fn get_idle_ms(&self, _id: Option<ReportId>) -> Option<u32> {
+        None
+    }
+
+    fn set_idle_ms(&self, _id: Option<ReportId>, _duration_ms: u32) {}
+}
+
+#[cfg(feature = "dfu")]
+const DFU_REQ_DETACH: u8 = 0x00;
+#[cfg(feature = "dfu")]
+const DFU_REQ_GETSTATUS: u8 = 0x03;
+#[cfg(feature = "dfu")]
+const DFU_REQ_GETSTATE: u8 = 0x05;
+
+#[cfg(feature = "dfu")]
+/// DFU state reported to the host. The runtime interface only ever reports `appIDLE`.
+const DFU_STATE_APP_IDLE: u8 = 0x00;
+
+#[cfg(feature = "dfu")]
+/// Time to wait after receiving `DFU_DETACH`, before jumping to the bootloader. This gives the
+/// host some time to finish the control transfer.
+const DFU_DETACH_DELAY_MS: u64 = 100;
+
+#[cfg(feature = "dfu")]
+/// Signal used to tell [`usb_dfu_detach_task`] to jump to the bootloader.
+static DFU_DETACH_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+#[cfg(feature = "dfu")]
+/// Handles DFU class requests sent to the DFU runtime interface.
+struct DfuRuntimeHandler {
+    interface: u8,
+}
+
+#[cfg(feature = "dfu")]
+impl DfuRuntimeHandler {
+    fn is_dfu_request(&self, req: &Request) -> bool {
+        req.request_type == RequestType::Class
+            && req.recipient == Recipient::Interface
+            && req.index == self.interface as u16
+    }
+}
+
+#[cfg(feature = "dfu")]
+impl Handler for DfuRuntimeHandler {
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if !self.is_dfu_request(&req) {
+            return None;
+        }
+
+        match req.request {
+            DFU_REQ_DETACH => {
+                info!("[USB] Host requested DFU detach, jumping to bootloader");
+                DFU_DETACH_SIGNAL.signal(());
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if !self.is_dfu_request(&req) {
+            return None;
+        }
+
+        match req.request {
+            DFU_REQ_GETSTATUS => {
+                // bStatus (OK), bwPollTimeout (0ms), bState, iString
+                buf[..6].copy_from_slice(&[0x00, 0x00, 0x00, 0x00, DFU_STATE_APP_IDLE, 0x00]);
+                Some(InResponse::Accepted(&buf[..6]))
+            }
+            DFU_REQ_GETSTATE => {
+                buf[0] = DFU_STATE_APP_IDLE;
+                Some(InResponse::Accepted(&buf[..1]))
+            }
+            _ => Some(InResponse::Rejected),
+        }
+    }
+}
+
+#[cfg(feature = "dfu")]
+/// Configure a DFU runtime interface. This allows DFU tools like `dfu-util -e` to ask the keyboard
+/// to jump to its bootloader, so that it can be flashed without pressing a reset button.
+///
+/// [`usb_dfu_detach_task`] must be spawned for the bootloader jump to happen.
+pub fn setup_usb_dfu_runtime(b: &mut Builder<'static, impl Driver<'static>>) {
+    let mut func = b.function(0xFE, 0x01, 0x01);
+    let mut iface = func.interface();
+    let interface = iface.interface_number();
+    let mut alt = iface.alt_setting(0xFE, 0x01, 0x01, None);
+
+    alt.descriptor(
+        0x21, // DFU functional descriptor
+        &[
+            0x09, // bmAttributes: bitWillDetach, bitCanDnload
+            0xE8, 0x03, // wDetachTimeOut (1000ms)
+            0x40, 0x00, // wTransferSize (64 bytes)
+            0x10, 0x01, // bcdDFUVersion (1.1)
+        ],
+    );
+    drop(func);
+
+    static DFU_RUNTIME_HANDLER: StaticCell<DfuRuntimeHandler> = StaticCell::new();
+    b.handler(DFU_RUNTIME_HANDLER.init(DfuRuntimeHandler {
+        interface: interface.into(),
+    }));
+}
+
+#[cfg(feature = "dfu")]
+#[rumcake_macros::task]
+pub async fn usb_dfu_detach_task() {
+    DFU_DETACH_SIGNAL.wait().await;
+
+    embassy_time::Timer::after_millis(DFU_DETACH_DELAY_MS).await;
+    crate::hw::mcu::jump_to_bootloader();
+}
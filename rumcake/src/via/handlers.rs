@@ -83,6 +83,77 @@ pub fn bootloader_jump() {
     BOOTLOADER_JUMP_SIGNAL.signal(());
 }
 
+pub(super) static RESET_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+#[cfg(feature = "storage")]
+#[derive(num_derive::FromPrimitive, Debug)]
+enum StorageBackupCommandId {
+    GetSize = 0x01,
+    Read,
+    Write,
+    Finish,
+    Verify,
+    Abort,
+}
+
+/// Handle a storage backup command. A backup (a header, followed by the contents of the config
+/// partition) can be read in chunks of [`super::storage::BACKUP_CHUNK_SIZE`] bytes to back up all
+/// of the stored settings. To restore a backup, the header is written (at offset 0), then every
+/// other chunk is verified with `Verify`, then written with `Write`, in order. A `Finish` command
+/// then completes the restore and resets the keyboard. Nothing is erased until the whole backup
+/// has passed validation. Once the first chunk after the header is written, the database stays
+/// locked until the restore is finished or aborted (with `Abort`, or after
+/// [`super::storage::RESTORE_TIMEOUT`] without any backup commands), and other storage
+/// operations are ignored.
+///
+/// `data[0]` contains the subcommand ID, and `data[1]` is set to 0 if the operation succeeded, or
+/// 1 if it failed. `data[2..=5]` contains the backup offset (or the size of the backup for
+/// `GetSize`), and `data[7..]` contains the chunk data.
+#[cfg(feature = "storage")]
+pub async fn storage_backup(data: &mut [u8]) {
+    use super::storage::BACKUP_CHUNK_SIZE;
+
+    let Some(command) = num::FromPrimitive::from_u8(data[0]) else {
+        warn!("[VIA] Unknown storage backup command {:?}", data[0]);
+        data[1] = 1;
+        return;
+    };
+
+    let offset = u32::from_be_bytes(data[2..=5].try_into().unwrap()) as usize;
+
+    let result = match command {
+        StorageBackupCommandId::GetSize => super::storage::backup_get_size().await.map(|size| {
+            data[2..=5].copy_from_slice(&(size as u32).to_be_bytes());
+            data[6] = BACKUP_CHUNK_SIZE as u8;
+        }),
+        StorageBackupCommandId::Read => {
+            super::storage::backup_read(offset, &mut data[7..(7 + BACKUP_CHUNK_SIZE)]).await
+        }
+        StorageBackupCommandId::Write => {
+            super::storage::backup_write(offset, &data[7..(7 + BACKUP_CHUNK_SIZE)]).await
+        }
+        StorageBackupCommandId::Verify => {
+            super::storage::backup_verify(offset, &data[7..(7 + BACKUP_CHUNK_SIZE)]).await
+        }
+        StorageBackupCommandId::Finish => super::storage::backup_finish()
+            .await
+            .map(|_| RESET_SIGNAL.signal(())),
+        StorageBackupCommandId::Abort => super::storage::backup_abort().await,
+    };
+
+    data[1] = match result {
+        Ok(()) => 0,
+        Err(error) => {
+            warn!(
+                "[VIA] Storage backup command {:?} failed: {}",
+                defmt::Debug2Format(&command),
+                defmt::Debug2Format(&error)
+            );
+            1
+        }
+    };
+}
+
 pub fn dynamic_keymap_macro_get_count<K: ViaKeyboard>(data: &mut [u8]) {
     data[0] = K::DYNAMIC_KEYMAP_MACRO_COUNT;
 }
@@ -180,6 +251,7 @@ pub async fn dynamic_keymap_get_keycode<K: ViaKeyboard + 'static>(
     }
 }
 
+#[cfg_attr(not(feature = "storage"), allow(unused_variables))]
 pub async fn dynamic_keymap_set_keycode<K: ViaKeyboard + 'static>(
     layer: u8,
     row: u8,
@@ -193,10 +265,7 @@ pub async fn dynamic_keymap_set_keycode<K: ViaKeyboard + 'static>(
 {
     let keycode = &data[0..=1];
 
-    if !(layer as usize >= K::DYNAMIC_KEYMAP_LAYER_COUNT
-        || row as usize >= K::LAYOUT_ROWS
-        || col as usize >= K::LAYOUT_COLS)
-    {
+    if let Some(keycode_offset) = super::keymap::keycode_offset::<K>(layer, row, col) {
         {
             let mut layout = K::get_layout().lock().await;
             if let Some(action) =
@@ -209,18 +278,12 @@ pub async fn dynamic_keymap_set_keycode<K: ViaKeyboard + 'static>(
         }
 
         #[cfg(feature = "storage")]
-        {
-            let keycode_offset = ((layer * K::LAYOUT_ROWS as u8 * K::LAYOUT_COLS as u8 * 2)
-                + (row * K::LAYOUT_COLS as u8 * 2)
-                + (col * 2)) as usize;
-
-            super::storage::update_data(
-                super::storage::ViaStorageKeys::DynamicKeymap,
-                keycode_offset,
-                keycode,
-            )
-            .await;
-        }
+        super::storage::update_data(
+            super::storage::ViaStorageKeys::DynamicKeymap,
+            keycode_offset,
+            keycode,
+        )
+        .await;
     } else {
         warn!("[VIA] Requested a dynamic keymap keycode that is out of bounds.")
     }
@@ -234,13 +297,12 @@ pub async fn dynamic_keymap_get_encoder<K: ViaKeyboard>(
 ) {
     let keycode = &mut data[0..=1];
 
-    let keycode_offset = ((layer * K::NUM_ENCODERS as u8 * 2 * 2)
-        + (encoder_id * 2 * 2)
-        + if clockwise { 0 } else { 2 }) as usize;
-
     if !(layer as usize >= K::DYNAMIC_KEYMAP_LAYER_COUNT || encoder_id as usize >= K::NUM_ENCODERS)
     {
-        //TODO: encoder support
+        if let Some(encoder_map) = K::get_encoder_map() {
+            let keycodes = encoder_map[layer as usize * K::NUM_ENCODERS + encoder_id as usize];
+            keycode.copy_from_slice(&keycodes[if clockwise { 0 } else { 1 }].to_be_bytes());
+        }
     } else {
         warn!("[VIA] Requested a dynamic keymap encoder that is out of bounds.")
     }
@@ -254,20 +316,27 @@ pub async fn dynamic_keymap_set_encoder<K: ViaKeyboard>(
 ) {
     let keycode = &data[0..=1];
 
-    let keycode_offset = ((layer * K::NUM_ENCODERS as u8 * 2 * 2)
-        + (encoder_id * 2 * 2)
-        + if clockwise { 0 } else { 2 }) as usize;
-
     if !(layer as usize >= K::DYNAMIC_KEYMAP_LAYER_COUNT || encoder_id as usize >= K::NUM_ENCODERS)
     {
-        //TODO: encoder support
+        if let Some(encoder_map) = K::get_encoder_map() {
+            let keycodes = &mut encoder_map[layer as usize * K::NUM_ENCODERS + encoder_id as usize];
+            keycodes[if clockwise { 0 } else { 1 }] =
+                u16::from_be_bytes(keycode.try_into().unwrap());
+        }
+
         #[cfg(feature = "storage")]
-        super::storage::update_data(
-            super::storage::ViaStorageKeys::DynamicKeymapEncoder,
-            keycode_offset,
-            keycode,
-        )
-        .await;
+        {
+            let keycode_offset = (layer as usize * K::NUM_ENCODERS * 2 * 2)
+                + (encoder_id as usize * 2 * 2)
+                + if clockwise { 0 } else { 2 };
+
+            super::storage::update_data(
+                super::storage::ViaStorageKeys::DynamicKeymapEncoder,
+                keycode_offset,
+                keycode,
+            )
+            .await;
+        }
     } else {
         warn!("[VIA] Attempted to set a dynamic keymap encoder out of bounds.")
     }
@@ -368,18 +437,7 @@ where
     [(); K::LAYOUT_ROWS]:,
     [(); K::LAYOUT_COLS]:,
 {
-    let mut layout = K::get_layout().lock().await;
-    let original = K::get_original_layout();
-
-    for (layer_idx, layer) in original.iter().enumerate() {
-        for (row_idx, row) in layer.iter().enumerate() {
-            for (col_idx, action) in row.iter().enumerate() {
-                layout
-                    .change_action((row_idx as u8, col_idx as u8), layer_idx, *action)
-                    .unwrap();
-            }
-        }
-    }
+    super::keymap::reset::<K>().await
 }
 
 #[cfg(feature = "simple-backlight")]
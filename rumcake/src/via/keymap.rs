@@ -0,0 +1,125 @@
+//! Dynamic keymap, which allows the keys in your layout to be remapped at runtime.
+//!
+//! The dynamic keymap is used by Via, Vial and XAP to remap keys. You can also use it from your
+//! own code (e.g. in [`ViaKeyboard::handle_via_command`], or a raw HID task) with
+//! [`get_keycode`], [`set_keycode`] and [`reset`]. Keys are represented by the same QMK keycodes
+//! used by Via.
+//!
+//! If you enabled the `storage` feature, remapped keys are saved, and restored when your keyboard
+//! restarts. Only keys that have been remapped are restored, so the rest of your keys use the
+//! layout defined in your firmware (see [`KeyboardLayout::get_original_layout`]). This means that
+//! changes to your firmware's layout still apply after flashing, for keys that haven't been
+//! remapped.
+//!
+//! [`KeyboardLayout::get_original_layout`]: crate::keyboard::KeyboardLayout::get_original_layout
+
+use defmt::warn;
+
+use super::protocol::keycodes::{convert_action_to_keycode, convert_keycode_to_action};
+use super::ViaKeyboard;
+
+/// Obtain the offset of a key's keycode in the stored dynamic keymap, or `None` if the key is out
+/// of bounds. Keycodes are ordered by layer, then row, then column, and each keycode takes up 2
+/// bytes.
+pub(super) fn keycode_offset<K: ViaKeyboard>(layer: u8, row: u8, col: u8) -> Option<usize> {
+    let (layer, row, col) = (layer as usize, row as usize, col as usize);
+
+    if layer >= K::DYNAMIC_KEYMAP_LAYER_COUNT || row >= K::LAYOUT_ROWS || col >= K::LAYOUT_COLS {
+        return None;
+    }
+
+    Some(((layer * K::LAYOUT_ROWS + row) * K::LAYOUT_COLS + col) * 2)
+}
+
+/// Obtain the keycode of the key at the given layer and position (row, column). Returns `None` if
+/// the key is out of bounds.
+pub async fn get_keycode<K: ViaKeyboard + 'static>(layer: u8, row: u8, col: u8) -> Option<u16>
+where
+    [(); K::LAYERS]:,
+    [(); K::LAYOUT_ROWS]:,
+    [(); K::LAYOUT_COLS]:,
+    [(); K::DYNAMIC_KEYMAP_MACRO_BUFFER_SIZE as usize]:,
+    [(); K::DYNAMIC_KEYMAP_MACRO_COUNT as usize]:,
+{
+    keycode_offset::<K>(layer, row, col)?;
+
+    K::get_layout()
+        .lock()
+        .await
+        .get_action((row, col), layer as usize)
+        .map(convert_action_to_keycode::<K>)
+}
+
+/// Remap the key at the given layer and position (row, column) to a keycode. If storage is
+/// enabled, the new keycode is saved. Returns `false` if the key is out of bounds, or if the
+/// keycode could not be converted to an action.
+#[cfg_attr(not(feature = "storage"), allow(unused_variables))]
+pub async fn set_keycode<K: ViaKeyboard + 'static>(
+    layer: u8,
+    row: u8,
+    col: u8,
+    keycode: u16,
+) -> bool
+where
+    [(); K::LAYERS]:,
+    [(); K::LAYOUT_ROWS]:,
+    [(); K::LAYOUT_COLS]:,
+    [(); K::DYNAMIC_KEYMAP_MACRO_BUFFER_SIZE as usize]:,
+    [(); K::DYNAMIC_KEYMAP_MACRO_COUNT as usize]:,
+{
+    let Some(offset) = keycode_offset::<K>(layer, row, col) else {
+        warn!("[VIA] Tried to remap a key that is out of bounds.");
+        return false;
+    };
+
+    let Some(action) = convert_keycode_to_action::<K>(keycode) else {
+        warn!(
+            "[VIA] Could not convert keycode {:X} to an action.",
+            keycode
+        );
+        return false;
+    };
+
+    K::get_layout()
+        .lock()
+        .await
+        .change_action((row, col), layer as usize, action)
+        .unwrap();
+
+    #[cfg(feature = "storage")]
+    super::storage::update_data(
+        super::storage::ViaStorageKeys::DynamicKeymap,
+        offset,
+        &keycode.to_be_bytes(),
+    )
+    .await;
+
+    true
+}
+
+/// Restore the layout defined in your firmware, undoing all remapped keys. If storage is enabled,
+/// the saved keycodes are also erased.
+pub async fn reset<K: ViaKeyboard + 'static>()
+where
+    [(); K::LAYERS]:,
+    [(); K::LAYOUT_ROWS]:,
+    [(); K::LAYOUT_COLS]:,
+{
+    {
+        let mut layout = K::get_layout().lock().await;
+        let original = K::get_original_layout();
+
+        for (layer_idx, layer) in original.iter().enumerate() {
+            for (row_idx, row) in layer.iter().enumerate() {
+                for (col_idx, action) in row.iter().enumerate() {
+                    layout
+                        .change_action((row_idx as u8, col_idx as u8), layer_idx, *action)
+                        .unwrap();
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "storage")]
+    super::storage::reset_keymap().await;
+}
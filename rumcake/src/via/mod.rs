@@ -7,17 +7,18 @@
 use crate::keyboard::{Keyboard, KeyboardLayout};
 use defmt::assert;
 use embassy_futures::join;
-use embassy_sync::channel::Channel;
 use embassy_sync::mutex::Mutex;
 
 use crate::hw::mcu::RawMutex;
+use crate::raw_hid::{RAW_HID_REPORT_RECEIVE_CHANNEL, RAW_HID_REPORT_SEND_CHANNEL};
 
 pub(crate) mod handlers;
+pub mod keymap;
 pub(crate) mod protocol_12;
 
 pub(crate) use protocol_12 as protocol;
 
-pub use rumcake_macros::setup_macro_buffer;
+pub use rumcake_macros::{setup_encoder_map, setup_macro_buffer};
 
 /// Data structure that contains data for macros created by Via. Requires the size of the buffer,
 /// and the number of sequences that can be created to be specified.
@@ -102,6 +103,16 @@ pub trait ViaKeyboard: Keyboard + KeyboardLayout {
         None
     }
 
+    /// Obtain a reference to the keycodes assigned to your encoders by Via/Vial. You should use
+    /// [`setup_encoder_map`] to implement this. Each element contains the Via keycodes for the
+    /// clockwise and counter-clockwise actions of an encoder, and elements are ordered by layer,
+    /// then by encoder. If this returns `Some`, the length of the slice must be equal to
+    /// [`ViaKeyboard::DYNAMIC_KEYMAP_LAYER_COUNT`] multiplied by
+    /// [`KeyboardLayout::NUM_ENCODERS`].
+    fn get_encoder_map() -> Option<&'static mut [[u16; 2]]> {
+        None
+    }
+
     /// Override for handling a Via/Vial protocol packet.
     ///
     /// Returning `true` indicates that a command is fully handled, so the Via/Vial task will not
@@ -128,36 +139,6 @@ pub trait ViaKeyboard: Keyboard + KeyboardLayout {
     }
 }
 
-/// Report descriptor used for Via. Pulled from QMK.
-pub(crate) const VIA_REPORT_DESCRIPTOR: &[u8] = &[
-    0x06, 0x60, 0xFF, // Usage Page (Vendor Defined)
-    0x09, 0x61, // Usage (Vendor Defined)
-    0xA1, 0x01, // Collection (Application)
-    // Data to host
-    0x09, 0x62, //   Usage (Vendor Defined)
-    0x15, 0x00, //   Logical Minimum (0)
-    0x26, 0xFF, 0x00, //   Logical Maximum (255)
-    0x95, 0x20, //   Report Count
-    0x75, 0x08, //   Report Size (8)
-    0x81, 0x02, //   Input (Data, Variable, Absolute)
-    // Data from host
-    0x09, 0x63, //   Usage (Vendor Defined)
-    0x15, 0x00, //   Logical Minimum (0)
-    0x26, 0xFF, 0x00, //   Logical Maximum (255)
-    0x95, 0x20, //   Report Count
-    0x75, 0x08, //   Report Size (8)
-    0x91, 0x02, //   Output (Data, Variable, Absolute)
-    0xC0, // End Collection
-];
-
-/// Channel used to receive reports from the Via app to be processed. Reports that are sent to this
-/// channel will be processed by the Via task, and call the appropriate command handler, depending
-/// on the report contents.
-pub static VIA_REPORT_HID_RECEIVE_CHANNEL: Channel<RawMutex, [u8; 32], 1> = Channel::new();
-
-/// Channel used to send Via reports back to the Via host.
-pub static VIA_REPORT_HID_SEND_CHANNEL: Channel<RawMutex, [u8; 32], 1> = Channel::new();
-
 #[rumcake_macros::task]
 pub async fn via_process_task<K: ViaKeyboard + 'static>(_k: K)
 where
@@ -186,11 +167,18 @@ where
         );
     }
 
+    if let Some(encoder_map) = K::get_encoder_map() {
+        assert!(
+            encoder_map.len() == K::DYNAMIC_KEYMAP_LAYER_COUNT * K::NUM_ENCODERS,
+            "Encoder map size must be equal to the dynamic keymap layer count multiplied by the number of encoders."
+        );
+    }
+
     let via_state: Mutex<RawMutex, protocol::ViaState<K>> = Mutex::new(Default::default());
 
     let report_fut = async {
         loop {
-            let mut report = VIA_REPORT_HID_RECEIVE_CHANNEL.receive().await;
+            let mut report = RAW_HID_REPORT_RECEIVE_CHANNEL.receive().await;
 
             if K::VIA_ENABLED {
                 {
@@ -198,7 +186,7 @@ where
                     protocol::process_via_command::<K>(&mut report, &mut via_state).await;
                 }
 
-                VIA_REPORT_HID_SEND_CHANNEL.send(report).await;
+                RAW_HID_REPORT_SEND_CHANNEL.send(report).await;
             }
         }
     };
@@ -208,12 +196,15 @@ where
 
 #[cfg(feature = "storage")]
 pub mod storage {
-    use defmt::warn;
+    use defmt::{warn, Debug2Format};
     use embassy_sync::channel::Channel;
     use embassy_sync::signal::Signal;
+    use embassy_time::{with_timeout, Duration};
 
     use crate::hw::mcu::RawMutex;
-    use crate::storage::{FlashStorage, StorageDevice, StorageKey};
+    use crate::storage::{
+        FlashStorage, StorageDevice, StorageError, StorageKey, BACKUP_HEADER_LEN,
+    };
 
     use super::ViaKeyboard;
 
@@ -239,8 +230,24 @@ pub mod storage {
     enum Operation {
         Write([u8; 32], ViaStorageKeys, usize, usize),
         Delete,
+        DeleteKeymap,
+        BackupGetSize,
+        BackupRead(usize),
+        BackupVerify(usize, [u8; BACKUP_CHUNK_SIZE]),
+        BackupWrite(usize, [u8; BACKUP_CHUNK_SIZE]),
+        BackupFinish,
+        BackupAbort,
     }
 
+    /// Number of bytes of a backup transferred in each storage backup command. This must be equal
+    /// to [`BACKUP_HEADER_LEN`], so that the header is sent in the first chunk, and a multiple of
+    /// the flash peripheral's [`FlashStorage::WRITE_SIZE`] for restores to work.
+    pub(super) const BACKUP_CHUNK_SIZE: usize = BACKUP_HEADER_LEN;
+
+    /// Amount of time without any storage backup commands, after which a backup restore is aborted.
+    /// This makes sure that the database is unlocked if the host stops restoring a backup.
+    pub(super) const RESTORE_TIMEOUT: Duration = Duration::from_secs(10);
+
     /// A function that dispatches a flash operation to the Via storage task. This will obtain a
     /// lock, and hold onto it until the storage task signals a completion. `offset` corresponds to
     /// the first byte of the stored data for the given `key` that we want to update. For example,
@@ -264,8 +271,74 @@ pub mod storage {
         OPERATION_COMPLETE.wait().await;
     }
 
+    /// Erase the stored dynamic keymap, so that all keys use the layout defined in the firmware.
+    pub(super) async fn reset_keymap() {
+        OPERATION_CHANNEL.send(Operation::DeleteKeymap).await;
+        OPERATION_COMPLETE.wait().await;
+    }
+
+    /// Obtain the size of a backup, so that the host knows how many chunks to read when backing up
+    /// the stored data.
+    pub(super) async fn backup_get_size() -> Result<usize, StorageError> {
+        OPERATION_CHANNEL.send(Operation::BackupGetSize).await;
+        let result = BACKUP_RESULT.wait().await;
+        OPERATION_COMPLETE.wait().await;
+        result.map(|bytes| u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize)
+    }
+
+    /// Read a chunk of a backup, starting at `offset`.
+    pub(super) async fn backup_read(offset: usize, data: &mut [u8]) -> Result<(), StorageError> {
+        OPERATION_CHANNEL.send(Operation::BackupRead(offset)).await;
+        let result = BACKUP_RESULT.wait().await;
+        OPERATION_COMPLETE.wait().await;
+        result.map(|bytes| data.copy_from_slice(&bytes))
+    }
+
+    /// Verify a chunk of a backup that is being restored, starting at `offset`.
+    pub(super) async fn backup_verify(offset: usize, data: &[u8]) -> Result<(), StorageError> {
+        let mut buf = [0; BACKUP_CHUNK_SIZE];
+        buf.copy_from_slice(data);
+        OPERATION_CHANNEL
+            .send(Operation::BackupVerify(offset, buf))
+            .await;
+        let result = BACKUP_RESULT.wait().await;
+        OPERATION_COMPLETE.wait().await;
+        result.map(|_| ())
+    }
+
+    /// Write a chunk of a backup, starting at `offset`. Writing the header (at offset 0) starts
+    /// restoring the backup.
+    pub(super) async fn backup_write(offset: usize, data: &[u8]) -> Result<(), StorageError> {
+        let mut buf = [0; BACKUP_CHUNK_SIZE];
+        buf.copy_from_slice(data);
+        OPERATION_CHANNEL
+            .send(Operation::BackupWrite(offset, buf))
+            .await;
+        let result = BACKUP_RESULT.wait().await;
+        OPERATION_COMPLETE.wait().await;
+        result.map(|_| ())
+    }
+
+    /// Finish restoring a backup. If this succeeds, the keyboard should be reset.
+    pub(super) async fn backup_finish() -> Result<(), StorageError> {
+        OPERATION_CHANNEL.send(Operation::BackupFinish).await;
+        let result = BACKUP_RESULT.wait().await;
+        OPERATION_COMPLETE.wait().await;
+        result.map(|_| ())
+    }
+
+    /// Abort restoring a backup, unlocking the database.
+    pub(super) async fn backup_abort() -> Result<(), StorageError> {
+        OPERATION_CHANNEL.send(Operation::BackupAbort).await;
+        let result = BACKUP_RESULT.wait().await;
+        OPERATION_COMPLETE.wait().await;
+        result.map(|_| ())
+    }
+
     static OPERATION_CHANNEL: Channel<RawMutex, Operation, 1> = Channel::new();
     static OPERATION_COMPLETE: Signal<RawMutex, ()> = Signal::new();
+    static BACKUP_RESULT: Signal<RawMutex, Result<[u8; BACKUP_CHUNK_SIZE], StorageError>> =
+        Signal::new();
 
     pub(super) static VIA_LAYOUT_OPTIONS: Signal<RawMutex, u32> = Signal::new();
 
@@ -280,6 +353,7 @@ pub mod storage {
         [(); K::DYNAMIC_KEYMAP_MACRO_BUFFER_SIZE as usize]:,
         [(); K::DYNAMIC_KEYMAP_MACRO_COUNT as usize]:,
         [(); F::ERASE_SIZE]:,
+        [(); F::REGION_SIZE]:,
         [(); K::LAYERS]:,
         [(); K::LAYOUT_ROWS]:,
         [(); K::LAYOUT_COLS]:,
@@ -320,48 +394,45 @@ pub mod storage {
                     &layout_metadata,
                 )
                 .await;
-            if let Ok((stored_data, stored_len)) = database
+            match database
                 .read_raw(
                     K::get_storage_buffer(),
                     crate::storage::StorageKey::DynamicKeymap,
                 )
                 .await
             {
-                // Load layout from flash
-                let mut layout = K::get_layout().lock().await;
-                for byte in (0..stored_len).step_by(2) {
-                    if let Some(action) = super::protocol::keycodes::convert_keycode_to_action::<K>(
-                        u16::from_be_bytes(stored_data[byte..byte + 2].try_into().unwrap()),
-                    ) {
-                        let layer = byte / (K::LAYOUT_ROWS * K::LAYOUT_COLS * 2);
-                        let row = (byte / (K::LAYOUT_COLS * 2)) % K::LAYOUT_ROWS;
-                        let col = (byte / 2) % K::LAYOUT_COLS;
-
-                        layout
-                            .change_action((row as u8, col as u8), layer, action)
-                            .unwrap();
+                Ok((stored_data, stored_len)) => {
+                    // Load layout from flash
+                    let mut layout = K::get_layout().lock().await;
+                    for byte in (0..stored_len).step_by(2) {
+                        if let Some(action) =
+                            super::protocol::keycodes::convert_keycode_to_action::<K>(
+                                u16::from_be_bytes(stored_data[byte..byte + 2].try_into().unwrap()),
+                            )
+                        {
+                            let layer = byte / (K::LAYOUT_ROWS * K::LAYOUT_COLS * 2);
+                            let row = (byte / (K::LAYOUT_COLS * 2)) % K::LAYOUT_ROWS;
+                            let col = (byte / 2) % K::LAYOUT_COLS;
+
+                            layout
+                                .change_action((row as u8, col as u8), layer, action)
+                                .unwrap();
+                        }
                     }
                 }
-            } else {
-                // Save default layout to flash
-                let mut layout = K::get_layout().lock().await;
-                let mut buf =
-                    [0; K::DYNAMIC_KEYMAP_LAYER_COUNT * K::LAYOUT_COLS * K::LAYOUT_ROWS * 2];
-                for byte in (0..buf.len()).step_by(2) {
-                    let layer = byte / (K::LAYOUT_ROWS * K::LAYOUT_COLS * 2);
-                    let row = (byte / (K::LAYOUT_COLS * 2)) % K::LAYOUT_ROWS;
-                    let col = (byte / 2) % K::LAYOUT_COLS;
-
-                    buf[(byte)..(byte + 2)].copy_from_slice(
-                        &super::protocol::keycodes::convert_action_to_keycode::<K>(
-                            layout.get_action((row as u8, col as u8), layer).unwrap(),
-                        )
-                        .to_be_bytes(),
+                Err(StorageError::KeyNotFound) => {
+                    // No keys have been remapped yet, so the default layout is used
+                }
+                Err(StorageError::Corrupted) => {
+                    warn!("[VIA] Stored dynamic keymap is corrupted, using default layout.");
+                    let _ = database.delete(StorageKey::DynamicKeymap).await;
+                }
+                Err(error) => {
+                    warn!(
+                        "[VIA] Could not read dynamic keymap, using default layout: {}",
+                        Debug2Format(&error)
                     );
                 }
-                let _ = database
-                    .write_raw(K::get_storage_buffer(), StorageKey::DynamicKeymap, &buf)
-                    .await;
             };
 
             // Initialize encoder layout
@@ -373,6 +444,24 @@ pub mod storage {
                     &encoder_metadata,
                 )
                 .await;
+            if let Ok((stored_data, stored_len)) = database
+                .read_raw(
+                    K::get_storage_buffer(),
+                    crate::storage::StorageKey::DynamicKeymapEncoder,
+                )
+                .await
+            {
+                // Load encoder layout from flash
+                if let Some(encoder_map) = K::get_encoder_map() {
+                    for (keycodes, stored) in encoder_map
+                        .iter_mut()
+                        .zip(stored_data[..stored_len].chunks_exact(4))
+                    {
+                        keycodes[0] = u16::from_be_bytes(stored[0..=1].try_into().unwrap());
+                        keycodes[1] = u16::from_be_bytes(stored[2..=3].try_into().unwrap());
+                    }
+                }
+            };
 
             // Initialize macros
             let _ = database
@@ -395,44 +484,86 @@ pub mod storage {
             };
         }
 
+        // Backup that is being restored. Once the backup starts being written, the database stays
+        // locked until the restore is finished or aborted, so that storage tasks and garbage
+        // collection can't modify the partition while it is being overwritten.
+        let mut restore = None;
+
         loop {
-            match OPERATION_CHANNEL.receive().await {
+            let operation = if restore.is_some() {
+                match with_timeout(RESTORE_TIMEOUT, OPERATION_CHANNEL.receive()).await {
+                    Ok(operation) => operation,
+                    Err(_) => {
+                        warn!("[VIA] Backup restore timed out, aborting.");
+                        if let Some(restore) = restore.take() {
+                            restore.abort().await;
+                        }
+                        continue;
+                    }
+                }
+            } else {
+                OPERATION_CHANNEL.receive().await
+            };
+
+            let writing = restore.as_ref().is_some_and(|restore| restore.is_writing());
+            if writing
+                && matches!(
+                    operation,
+                    Operation::Write(..) | Operation::Delete | Operation::DeleteKeymap
+                )
+            {
+                warn!("[VIA] A backup is being restored, ignoring storage operation.");
+                OPERATION_COMPLETE.signal(());
+                continue;
+            }
+
+            match operation {
                 Operation::Write(data, key, offset, len) => {
                     match key {
                         ViaStorageKeys::LayoutOptions => {
                             // Update data
                             // For layout options, we just overwrite all of the old data
-                            if let Err(()) = database
+                            if let Err(error) = database
                                 .write_raw(K::get_storage_buffer(), key.into(), &data[..len])
                                 .await
                             {
-                                warn!("[VIA] Could not write layout options.")
+                                warn!(
+                                    "[VIA] Could not write layout options: {}",
+                                    Debug2Format(&error)
+                                )
                             };
                         }
                         ViaStorageKeys::DynamicKeymap => {
                             let key = key.into();
-                            let mut buf = [0; K::DYNAMIC_KEYMAP_LAYER_COUNT
-                                * K::LAYOUT_COLS
-                                * K::LAYOUT_ROWS
-                                * 2];
+                            // Keys that haven't been remapped are stored as 0xFFFF, so that they
+                            // use the layout defined in the firmware
+                            let mut buf = [0xFF;
+                                K::DYNAMIC_KEYMAP_LAYER_COUNT * K::LAYOUT_COLS * K::LAYOUT_ROWS * 2];
 
                             // Read data
                             match database.read_raw(K::get_storage_buffer(), key).await {
                                 Ok((stored_data, stored_len)) => {
                                     buf[..stored_len].copy_from_slice(stored_data);
                                 }
-                                Err(()) => {
-                                    warn!("[VIA] Could not read dynamic keymap buffer.");
+                                Err(StorageError::KeyNotFound) => {}
+                                Err(error) => {
+                                    warn!(
+                                        "[VIA] Could not read dynamic keymap buffer: {}",
+                                        Debug2Format(&error)
+                                    );
                                 }
                             };
 
                             // Update data
                             buf[offset..(offset + len)].copy_from_slice(&data[..len]);
 
-                            if let Err(()) =
+                            if let Err(error) =
                                 database.write_raw(K::get_storage_buffer(), key, &buf).await
                             {
-                                warn!("[VIA] Could not write dynamic keymap buffer.",)
+                                warn!(
+                                    "[VIA] Could not write dynamic keymap buffer: {}",
+                                    Debug2Format(&error)
+                                )
                             };
                         }
                         ViaStorageKeys::DynamicKeymapMacro => {
@@ -446,8 +577,14 @@ pub mod storage {
                                         buf[..stored_len].copy_from_slice(stored_data);
                                         stored_len
                                     }
-                                    Err(()) => {
-                                        warn!("[VIA] Could not read dynamic keymap macro buffer.");
+                                    Err(StorageError::KeyNotFound) => {
+                                        0 // There is no data yet
+                                    }
+                                    Err(error) => {
+                                        warn!(
+                                            "[VIA] Could not read dynamic keymap macro buffer: {}",
+                                            Debug2Format(&error)
+                                        );
                                         0 // Assume that there is no data yet
                                     }
                                 };
@@ -457,11 +594,14 @@ pub mod storage {
 
                             let new_length = stored_len.max(offset + len);
 
-                            if let Err(()) = database
+                            if let Err(error) = database
                                 .write_raw(K::get_storage_buffer(), key, &buf[..new_length])
                                 .await
                             {
-                                warn!("[VIA] Could not write dynamic keymap macro buffer.")
+                                warn!(
+                                    "[VIA] Could not write dynamic keymap macro buffer: {}",
+                                    Debug2Format(&error)
+                                )
                             };
                         }
                         ViaStorageKeys::DynamicKeymapEncoder => {
@@ -474,18 +614,25 @@ pub mod storage {
                                 Ok((stored_data, stored_len)) => {
                                     buf[..stored_len].copy_from_slice(stored_data);
                                 }
-                                Err(()) => {
-                                    warn!("[VIA] Could not read dynamic keymap encoder.");
+                                Err(StorageError::KeyNotFound) => {}
+                                Err(error) => {
+                                    warn!(
+                                        "[VIA] Could not read dynamic keymap encoder: {}",
+                                        Debug2Format(&error)
+                                    );
                                 }
                             };
 
                             // Update data
                             buf[offset..(offset + len)].copy_from_slice(&data[..len]);
 
-                            if let Err(()) =
+                            if let Err(error) =
                                 database.write_raw(K::get_storage_buffer(), key, &buf).await
                             {
-                                warn!("[VIA] Could not write dynamic keymap encoder.")
+                                warn!(
+                                    "[VIA] Could not write dynamic keymap encoder: {}",
+                                    Debug2Format(&error)
+                                )
                             };
                         }
                     }
@@ -496,6 +643,68 @@ pub mod storage {
                     let _ = database.delete(StorageKey::DynamicKeymapMacro).await;
                     let _ = database.delete(StorageKey::DynamicKeymapEncoder).await;
                 }
+                Operation::DeleteKeymap => {
+                    let _ = database.delete(StorageKey::DynamicKeymap).await;
+                }
+                Operation::BackupGetSize => {
+                    let size = match &restore {
+                        Some(restore) => restore.size(),
+                        None => database.backup_size().await,
+                    };
+                    let mut bytes = [0; BACKUP_CHUNK_SIZE];
+                    bytes[..4].copy_from_slice(&(size as u32).to_be_bytes());
+                    BACKUP_RESULT.signal(Ok(bytes));
+                }
+                Operation::BackupRead(offset) => {
+                    let mut bytes = [0; BACKUP_CHUNK_SIZE];
+                    // The database is locked while a backup is being written
+                    let result = if writing {
+                        Err(StorageError::InvalidBackup)
+                    } else {
+                        database.read_backup(offset, &mut bytes).await
+                    };
+                    BACKUP_RESULT.signal(result.map(|_| bytes));
+                }
+                Operation::BackupVerify(offset, bytes) => {
+                    let result = match &mut restore {
+                        Some(restore) => restore.verify(offset, &bytes),
+                        None => Err(StorageError::InvalidBackup),
+                    };
+                    BACKUP_RESULT.signal(result.map(|_| bytes));
+                }
+                Operation::BackupWrite(0, bytes) => {
+                    // Writing the header starts a new restore, unless the partition is already
+                    // being overwritten
+                    let result = if writing {
+                        Err(StorageError::InvalidBackup)
+                    } else {
+                        database
+                            .restore_backup(&bytes)
+                            .await
+                            .map(|started| restore = Some(started))
+                    };
+                    BACKUP_RESULT.signal(result.map(|_| bytes));
+                }
+                Operation::BackupWrite(offset, bytes) => {
+                    let result = match &mut restore {
+                        Some(restore) => restore.write(offset, &bytes).await,
+                        None => Err(StorageError::InvalidBackup),
+                    };
+                    BACKUP_RESULT.signal(result.map(|_| bytes));
+                }
+                Operation::BackupFinish => {
+                    let result = match restore.take() {
+                        Some(restore) => restore.finish().await,
+                        None => Err(StorageError::InvalidBackup),
+                    };
+                    BACKUP_RESULT.signal(result.map(|_| [0; BACKUP_CHUNK_SIZE]));
+                }
+                Operation::BackupAbort => {
+                    if let Some(restore) = restore.take() {
+                        restore.abort().await;
+                    }
+                    BACKUP_RESULT.signal(Ok([0; BACKUP_CHUNK_SIZE]));
+                }
             }
 
             OPERATION_COMPLETE.signal(())
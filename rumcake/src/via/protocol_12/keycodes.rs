@@ -1,4 +1,4 @@
-use keyberon::action::{Action, OneShotAction, OneShotEndConfig};
+use keyberon::action::{Action, HoldTapAction, OneShotAction, OneShotEndConfig};
 use keyberon::key_code::KeyCode;
 use num_derive::FromPrimitive;
 
@@ -33,13 +33,13 @@ enum QMKKeycodeRanges {
     QK_ONE_SHOT_MOD_MAX = 0x52BF,
     QK_LAYER_TAP_TOGGLE = 0x52C0, // TODO: unhandled
     QK_LAYER_TAP_TOGGLE_MAX = 0x52DF,
-    QK_SWAP_HANDS = 0x5600, // TODO: unhandled
+    QK_SWAP_HANDS = 0x5600,
     QK_SWAP_HANDS_MAX = 0x56FF,
     QK_TAP_DANCE = 0x5700, // TODO: unhandled, switch to kanata keyberon fork
     QK_TAP_DANCE_MAX = 0x57FF,
     QK_MAGIC = 0x7000, // TODO: unhandled
     QK_MAGIC_MAX = 0x70FF,
-    QK_MIDI = 0x7100, // TODO: unhandled
+    QK_MIDI = 0x7100,
     QK_MIDI_MAX = 0x71FF,
     QK_SEQUENCER = 0x7200, // TODO: unhandled
     QK_SEQUENCER_MAX = 0x73FF,
@@ -63,7 +63,7 @@ enum QMKKeycodeRanges {
     QK_USER_MAX = 0x7FFF,
     // QK_UNICODEMAP = 0x8000, // same as QK_UNICODE
     QK_UNICODEMAP_MAX = 0xBFFF,
-    QK_UNICODE = 0x8000, // TODO: unhandled
+    QK_UNICODE = 0x8000,
     QK_UNICODE_MAX = 0xFFFF,
     QK_UNICODEMAP_PAIR = 0xC000,
     // QK_UNICODEMAP_PAIR_MAX = 0xFFFF, // same as QK_UNICODE_MAX
@@ -241,7 +241,7 @@ enum QMKKeycodes {
     // 0xA5-0xDF start (these values are reserved, but used by QMK for consumer-related keycodes)
     KC_SYSTEM_POWER = 0x00A5,
     KC_SYSTEM_SLEEP = 0x00A6,
-    KC_SYSTEM_WAKE = 0x00A7, // TODO: unhandled
+    KC_SYSTEM_WAKE = 0x00A7,
     KC_AUDIO_MUTE = 0x00A8,
     KC_AUDIO_VOL_UP = 0x00A9,
     KC_AUDIO_VOL_DOWN = 0x00AA,
@@ -253,41 +253,41 @@ enum QMKKeycodes {
     KC_MEDIA_EJECT = 0x00B0,
     KC_MAIL = 0x00B1,
     KC_CALCULATOR = 0x00B2,
-    KC_MY_COMPUTER = 0x00B3, // TODO: unhandled
+    KC_MY_COMPUTER = 0x00B3,
     KC_WWW_SEARCH = 0x00B4,
     KC_WWW_HOME = 0x00B5,
     KC_WWW_BACK = 0x00B6,
     KC_WWW_FORWARD = 0x00B7,
     KC_WWW_STOP = 0x00B8,
     KC_WWW_REFRESH = 0x00B9,
-    KC_WWW_FAVORITES = 0x00BA, // TODO: unhandled
+    KC_WWW_FAVORITES = 0x00BA,
     KC_MEDIA_FAST_FORWARD = 0x00BB,
     KC_MEDIA_REWIND = 0x00BC,
-    KC_BRIGHTNESS_UP = 0x00BD,   // TODO: unhandled
-    KC_BRIGHTNESS_DOWN = 0x00BE, // TODO: unhandled
+    KC_BRIGHTNESS_UP = 0x00BD,
+    KC_BRIGHTNESS_DOWN = 0x00BE,
     KC_CONTROL_PANEL = 0x00BF,
     KC_ASSISTANT = 0x00C0,       // TODO: unhandled
     KC_MISSION_CONTROL = 0x00C1, // TODO: unhandled
     KC_LAUNCHPAD = 0x00C2,       // TODO: unhandled
-    KC_MS_UP = 0x00CD,           // TODO: unhandled
-    KC_MS_DOWN = 0x00CE,         // TODO: unhandled
-    KC_MS_LEFT = 0x00CF,         // TODO: unhandled
-    KC_MS_RIGHT = 0x00D0,        // TODO: unhandled
-    KC_MS_BTN1 = 0x00D1,         // TODO: unhandled
-    KC_MS_BTN2 = 0x00D2,         // TODO: unhandled
-    KC_MS_BTN3 = 0x00D3,         // TODO: unhandled
-    KC_MS_BTN4 = 0x00D4,         // TODO: unhandled
-    KC_MS_BTN5 = 0x00D5,         // TODO: unhandled
-    KC_MS_BTN6 = 0x00D6,         // TODO: unhandled
-    KC_MS_BTN7 = 0x00D7,         // TODO: unhandled
-    KC_MS_BTN8 = 0x00D8,         // TODO: unhandled
-    KC_MS_WH_UP = 0x00D9,        // TODO: unhandled
-    KC_MS_WH_DOWN = 0x00DA,      // TODO: unhandled
-    KC_MS_WH_LEFT = 0x00DB,      // TODO: unhandled
-    KC_MS_WH_RIGHT = 0x00DC,     // TODO: unhandled
-    KC_MS_ACCEL0 = 0x00DD,       // TODO: unhandled
-    KC_MS_ACCEL1 = 0x00DE,       // TODO: unhandled
-    KC_MS_ACCEL2 = 0x00DF,       // TODO: unhandled
+    KC_MS_UP = 0x00CD,
+    KC_MS_DOWN = 0x00CE,
+    KC_MS_LEFT = 0x00CF,
+    KC_MS_RIGHT = 0x00D0,
+    KC_MS_BTN1 = 0x00D1,
+    KC_MS_BTN2 = 0x00D2,
+    KC_MS_BTN3 = 0x00D3,
+    KC_MS_BTN4 = 0x00D4,
+    KC_MS_BTN5 = 0x00D5,
+    KC_MS_BTN6 = 0x00D6,
+    KC_MS_BTN7 = 0x00D7,
+    KC_MS_BTN8 = 0x00D8,
+    KC_MS_WH_UP = 0x00D9,
+    KC_MS_WH_DOWN = 0x00DA,
+    KC_MS_WH_LEFT = 0x00DB,
+    KC_MS_WH_RIGHT = 0x00DC,
+    KC_MS_ACCEL0 = 0x00DD, // TODO: unhandled
+    KC_MS_ACCEL1 = 0x00DE, // TODO: unhandled
+    KC_MS_ACCEL2 = 0x00DF, // TODO: unhandled
     // 0xA5-0xDF end (these values are reserved, but used by QMK for consumer-related keycodes)
     KC_LEFT_CTRL = 0x00E0,
     KC_LEFT_SHIFT = 0x00E1,
@@ -938,7 +938,13 @@ where
                 UNKNOWN_KEYCODE
             }
         }
-        Action::HoldTap(_) => todo!(),
+        Action::HoldTap(hold_tap) => SPACE_CADET_KEYCODES
+            .iter()
+            .find(|(_, action)| match action {
+                Action::HoldTap(space_cadet) => is_same_hold_tap(space_cadet, hold_tap),
+                _ => false,
+            })
+            .map_or(UNKNOWN_KEYCODE, |(keycode, _)| *keycode),
         Action::OneShot(&OneShotAction {
             action: keyberon::action::Action::Layer(layer),
             ..
@@ -1039,8 +1045,85 @@ where
                 usbd_human_interface_device::page::Consumer::ALControlPanel => {
                     QMKKeycodes::KC_CONTROL_PANEL as u16
                 }
+                usbd_human_interface_device::page::Consumer::ALLocalMachineBrowser => {
+                    QMKKeycodes::KC_MY_COMPUTER as u16
+                }
+                usbd_human_interface_device::page::Consumer::ACBookmarks => {
+                    QMKKeycodes::KC_WWW_FAVORITES as u16
+                }
+                usbd_human_interface_device::page::Consumer::DisplayBrightnessIncrement => {
+                    QMKKeycodes::KC_BRIGHTNESS_UP as u16
+                }
+                usbd_human_interface_device::page::Consumer::DisplayBrightnessDecrement => {
+                    QMKKeycodes::KC_BRIGHTNESS_DOWN as u16
+                }
                 _ => UNKNOWN_KEYCODE,
             },
+            #[cfg(feature = "media-keycodes")]
+            Keycode::SystemControl(keycode) => match keycode {
+                crate::system_control::SystemControlKeycode::PowerDown => {
+                    QMKKeycodes::KC_SYSTEM_POWER as u16
+                }
+                crate::system_control::SystemControlKeycode::Sleep => {
+                    QMKKeycodes::KC_SYSTEM_SLEEP as u16
+                }
+                crate::system_control::SystemControlKeycode::WakeUp => {
+                    QMKKeycodes::KC_SYSTEM_WAKE as u16
+                }
+            },
+            #[cfg(feature = "mouse")]
+            Keycode::Mouse(keycode) => match keycode {
+                crate::mouse::MouseKeycode::Up => QMKKeycodes::KC_MS_UP as u16,
+                crate::mouse::MouseKeycode::Down => QMKKeycodes::KC_MS_DOWN as u16,
+                crate::mouse::MouseKeycode::Left => QMKKeycodes::KC_MS_LEFT as u16,
+                crate::mouse::MouseKeycode::Right => QMKKeycodes::KC_MS_RIGHT as u16,
+                crate::mouse::MouseKeycode::WheelUp => QMKKeycodes::KC_MS_WH_UP as u16,
+                crate::mouse::MouseKeycode::WheelDown => QMKKeycodes::KC_MS_WH_DOWN as u16,
+                crate::mouse::MouseKeycode::WheelLeft => QMKKeycodes::KC_MS_WH_LEFT as u16,
+                crate::mouse::MouseKeycode::WheelRight => QMKKeycodes::KC_MS_WH_RIGHT as u16,
+                crate::mouse::MouseKeycode::Button(button) => {
+                    if button <= 7 {
+                        QMKKeycodes::KC_MS_BTN1 as u16 + button as u16
+                    } else {
+                        UNKNOWN_KEYCODE
+                    }
+                }
+            },
+            #[cfg(feature = "gamepad")]
+            Keycode::Gamepad(keycode) => match keycode {
+                crate::gamepad::GamepadKeycode::Button(button) => {
+                    if button <= 31 {
+                        QMKKeycodes::QK_JOYSTICK_BUTTON_0 as u16 + button as u16
+                    } else {
+                        UNKNOWN_KEYCODE
+                    }
+                }
+                crate::gamepad::GamepadKeycode::Axis(_, _) => UNKNOWN_KEYCODE,
+            },
+            #[cfg(feature = "midi")]
+            Keycode::Midi(keycode) => match keycode {
+                crate::midi::MidiKeycode::Note(note) => {
+                    if (MIDI_NOTE_OFFSET..MIDI_NOTE_OFFSET + MIDI_NOTE_COUNT).contains(&note) {
+                        QMKKeycodes::QK_MIDI_NOTE_C_0 as u16 + (note - MIDI_NOTE_OFFSET) as u16
+                    } else {
+                        UNKNOWN_KEYCODE
+                    }
+                }
+                crate::midi::MidiKeycode::ControlChange(controller, 127) => match controller {
+                    64 => QMKKeycodes::QK_MIDI_SUSTAIN as u16,
+                    65 => QMKKeycodes::QK_MIDI_PORTAMENTO as u16,
+                    66 => QMKKeycodes::QK_MIDI_SOSTENUTO as u16,
+                    67 => QMKKeycodes::QK_MIDI_SOFT as u16,
+                    68 => QMKKeycodes::QK_MIDI_LEGATO as u16,
+                    _ => UNKNOWN_KEYCODE,
+                },
+                crate::midi::MidiKeycode::ControlChange(_, _) => UNKNOWN_KEYCODE,
+                crate::midi::MidiKeycode::AllNotesOff => QMKKeycodes::QK_MIDI_ALL_NOTES_OFF as u16,
+                crate::midi::MidiKeycode::ChannelUp => QMKKeycodes::QK_MIDI_CHANNEL_UP as u16,
+                crate::midi::MidiKeycode::ChannelDown => QMKKeycodes::QK_MIDI_CHANNEL_DOWN as u16,
+                crate::midi::MidiKeycode::VelocityUp => QMKKeycodes::QK_MIDI_VELOCITY_UP as u16,
+                crate::midi::MidiKeycode::VelocityDown => QMKKeycodes::QK_MIDI_VELOCITY_DOWN as u16,
+            },
             #[cfg(feature = "underglow")]
             Keycode::Underglow(command) => match command {
                 crate::underglow::animations::UnderglowCommand::Toggle => {
@@ -1203,11 +1286,97 @@ where
                 #[cfg(feature = "usb")]
                 crate::bluetooth::BluetoothCommand::OutputUSB => QMKKeycodes::QK_OUTPUT_USB as u16,
                 #[cfg(feature = "usb")]
+                crate::bluetooth::BluetoothCommand::OutputAuto => {
+                    QMKKeycodes::QK_OUTPUT_AUTO as u16
+                }
+                #[cfg(feature = "usb")]
                 crate::bluetooth::BluetoothCommand::OutputBluetooth => {
                     QMKKeycodes::QK_OUTPUT_BLUETOOTH as u16
                 }
                 _ => UNKNOWN_KEYCODE,
             },
+            #[cfg(feature = "storage")]
+            Keycode::FactoryReset => QMKKeycodes::QK_CLEAR_EEPROM as u16,
+            Keycode::NKRO(command) => match command {
+                crate::keyboard::NKROCommand::Enable => QMKKeycodes::QK_MAGIC_NKRO_ON as u16,
+                crate::keyboard::NKROCommand::Disable => QMKKeycodes::QK_MAGIC_NKRO_OFF as u16,
+                crate::keyboard::NKROCommand::Toggle => QMKKeycodes::QK_MAGIC_TOGGLE_NKRO as u16,
+            },
+            Keycode::Bootloader => QMKKeycodes::QK_BOOTLOADER as u16,
+            Keycode::Reset => QMKKeycodes::QK_REBOOT as u16,
+            Keycode::TapHold(command) => match command {
+                crate::keyboard::TapHoldCommand::IncreaseTappingTerm(TAPPING_TERM_INCREMENT) => {
+                    QMKKeycodes::QK_DYNAMIC_TAPPING_TERM_UP as u16
+                }
+                crate::keyboard::TapHoldCommand::DecreaseTappingTerm(TAPPING_TERM_INCREMENT) => {
+                    QMKKeycodes::QK_DYNAMIC_TAPPING_TERM_DOWN as u16
+                }
+                _ => UNKNOWN_KEYCODE,
+            },
+            Keycode::Leader => QMKKeycodes::QK_LEADER as u16,
+            Keycode::DynamicMacro(command) => match command {
+                crate::dynamic_macro::DynamicMacroCommand::StartRecording(0) => {
+                    QMKKeycodes::QK_DYNAMIC_MACRO_RECORD_START_1 as u16
+                }
+                crate::dynamic_macro::DynamicMacroCommand::StartRecording(1) => {
+                    QMKKeycodes::QK_DYNAMIC_MACRO_RECORD_START_2 as u16
+                }
+                crate::dynamic_macro::DynamicMacroCommand::StopRecording => {
+                    QMKKeycodes::QK_DYNAMIC_MACRO_RECORD_STOP as u16
+                }
+                crate::dynamic_macro::DynamicMacroCommand::Play(0) => {
+                    QMKKeycodes::QK_DYNAMIC_MACRO_PLAY_1 as u16
+                }
+                crate::dynamic_macro::DynamicMacroCommand::Play(1) => {
+                    QMKKeycodes::QK_DYNAMIC_MACRO_PLAY_2 as u16
+                }
+                _ => UNKNOWN_KEYCODE,
+            },
+            Keycode::Unicode(command) => match command {
+                crate::unicode::UnicodeCommand::Type(char)
+                    if char as u32
+                        <= QMKKeycodeRanges::QK_UNICODE_MAX as u32
+                            - QMKKeycodeRanges::QK_UNICODE as u32 =>
+                {
+                    QMKKeycodeRanges::QK_UNICODE as u16 + char as u16
+                }
+                crate::unicode::UnicodeCommand::SetMode(crate::unicode::UnicodeMode::Linux) => {
+                    QMKKeycodes::QK_UNICODE_MODE_LINUX as u16
+                }
+                crate::unicode::UnicodeCommand::SetMode(crate::unicode::UnicodeMode::MacOS) => {
+                    QMKKeycodes::QK_UNICODE_MODE_MACOS as u16
+                }
+                crate::unicode::UnicodeCommand::SetMode(
+                    crate::unicode::UnicodeMode::WinCompose,
+                ) => QMKKeycodes::QK_UNICODE_MODE_WINCOMPOSE as u16,
+                crate::unicode::UnicodeCommand::NextMode => {
+                    QMKKeycodes::QK_UNICODE_MODE_NEXT as u16
+                }
+                crate::unicode::UnicodeCommand::PreviousMode => {
+                    QMKKeycodes::QK_UNICODE_MODE_PREVIOUS as u16
+                }
+                _ => UNKNOWN_KEYCODE,
+            },
+            Keycode::GraveEscape => QMKKeycodes::QK_GRAVE_ESCAPE as u16,
+            Keycode::KeyLock => QMKKeycodes::QK_LOCK as u16,
+            Keycode::SwapHands(command) => match command {
+                crate::swap_hands::SwapHandsCommand::Momentary => {
+                    QMKKeycodes::QK_SWAP_HANDS_MOMENTARY_ON as u16
+                }
+                crate::swap_hands::SwapHandsCommand::MomentaryOff => {
+                    QMKKeycodes::QK_SWAP_HANDS_MOMENTARY_OFF as u16
+                }
+                crate::swap_hands::SwapHandsCommand::OneShot => {
+                    QMKKeycodes::QK_SWAP_HANDS_ONE_SHOT as u16
+                }
+                crate::swap_hands::SwapHandsCommand::Toggle => {
+                    QMKKeycodes::QK_SWAP_HANDS_TOGGLE as u16
+                }
+                crate::swap_hands::SwapHandsCommand::On => QMKKeycodes::QK_SWAP_HANDS_ON as u16,
+                crate::swap_hands::SwapHandsCommand::Off => QMKKeycodes::QK_SWAP_HANDS_OFF as u16,
+            },
+            #[cfg(any(feature = "console", feature = "hid-console", feature = "nus-console"))]
+            Keycode::DebugToggle => QMKKeycodes::QK_DEBUG_TOGGLE as u16,
             #[allow(unreachable_patterns)]
             _ => UNKNOWN_KEYCODE,
         },
@@ -1233,6 +1402,69 @@ const ONE_SHOT_LAYER_ACTIONS: [OneShotAction<Keycode, KeyCode>; 16] = {
     pool
 };
 
+/// Space Cadet keys that can be assigned by Via, along with their QMK keycodes.
+const SPACE_CADET_KEYCODES: [(u16, Action<Keycode>); 7] = [
+    (
+        QMKKeycodes::QK_SPACE_CADET_LEFT_CTRL_PARENTHESIS_OPEN as u16,
+        crate::space_cadet::SC_LCPO,
+    ),
+    (
+        QMKKeycodes::QK_SPACE_CADET_RIGHT_CTRL_PARENTHESIS_CLOSE as u16,
+        crate::space_cadet::SC_RCPC,
+    ),
+    (
+        QMKKeycodes::QK_SPACE_CADET_LEFT_SHIFT_PARENTHESIS_OPEN as u16,
+        crate::space_cadet::SC_LSPO,
+    ),
+    (
+        QMKKeycodes::QK_SPACE_CADET_RIGHT_SHIFT_PARENTHESIS_CLOSE as u16,
+        crate::space_cadet::SC_RSPC,
+    ),
+    (
+        QMKKeycodes::QK_SPACE_CADET_LEFT_ALT_PARENTHESIS_OPEN as u16,
+        crate::space_cadet::SC_LAPO,
+    ),
+    (
+        QMKKeycodes::QK_SPACE_CADET_RIGHT_ALT_PARENTHESIS_CLOSE as u16,
+        crate::space_cadet::SC_RAPC,
+    ),
+    (
+        QMKKeycodes::QK_SPACE_CADET_RIGHT_SHIFT_ENTER as u16,
+        crate::space_cadet::SC_SENT,
+    ),
+];
+
+/// Check if two tap-hold actions behave the same way. Only tap-hold actions with key code hold and
+/// tap actions are compared, since [`Keycode`] can't be compared.
+fn is_same_hold_tap(
+    a: &HoldTapAction<Keycode, KeyCode>,
+    b: &HoldTapAction<Keycode, KeyCode>,
+) -> bool {
+    let is_same_action = |a: &Action<Keycode>, b: &Action<Keycode>| match (a, b) {
+        (Action::KeyCode(a), Action::KeyCode(b)) => a == b,
+        (Action::MultipleKeyCodes(a), Action::MultipleKeyCodes(b)) => a == b,
+        _ => false,
+    };
+
+    a.timeout == b.timeout
+        && a.config == b.config
+        && a.tap_hold_interval == b.tap_hold_interval
+        && is_same_action(&a.hold, &b.hold)
+        && is_same_action(&a.tap, &b.tap)
+}
+
+/// MIDI note that `QK_MIDI_NOTE_C_0` corresponds to. This matches QMK's default octave setting.
+#[cfg(feature = "midi")]
+const MIDI_NOTE_OFFSET: u8 = 48;
+
+/// Number of note keycodes in the `QK_MIDI` range (`QK_MIDI_NOTE_C_0` to `QK_MIDI_NOTE_B_5`).
+#[cfg(feature = "midi")]
+const MIDI_NOTE_COUNT: u8 = 72;
+
+/// Number of milliseconds that `QK_DYNAMIC_TAPPING_TERM_UP` and `QK_DYNAMIC_TAPPING_TERM_DOWN`
+/// change the tapping term by. This matches QMK's default `DYNAMIC_TAPPING_TERM_INCREMENT`.
+const TAPPING_TERM_INCREMENT: u16 = 5;
+
 pub(crate) fn convert_keycode_to_action<K: ViaKeyboard + 'static>(
     keycode: u16,
 ) -> Option<Action<Keycode>>
@@ -1240,6 +1472,12 @@ where
     [(); K::DYNAMIC_KEYMAP_MACRO_BUFFER_SIZE as usize]:,
     [(); K::DYNAMIC_KEYMAP_MACRO_COUNT as usize]:,
 {
+    // Keys that haven't been remapped are stored as the unknown keycode, so they keep their
+    // original action
+    if keycode == UNKNOWN_KEYCODE {
+        return None;
+    }
+
     if keycode == QMKKeycodes::KC_NO as u16 {
         return Some(Action::NoOp);
     }
@@ -1256,14 +1494,20 @@ where
                 #[cfg(feature = "media-keycodes")]
                 {
                     if keycode == QMKKeycodes::KC_SYSTEM_POWER as u16 {
-                        return Some(Action::Custom(Keycode::Media(
-                            usbd_human_interface_device::page::Consumer::Power,
+                        return Some(Action::Custom(Keycode::SystemControl(
+                            crate::system_control::SystemControlKeycode::PowerDown,
                         )));
                     }
 
                     if keycode == QMKKeycodes::KC_SYSTEM_SLEEP as u16 {
-                        return Some(Action::Custom(Keycode::Media(
-                            usbd_human_interface_device::page::Consumer::Sleep,
+                        return Some(Action::Custom(Keycode::SystemControl(
+                            crate::system_control::SystemControlKeycode::Sleep,
+                        )));
+                    }
+
+                    if keycode == QMKKeycodes::KC_SYSTEM_WAKE as u16 {
+                        return Some(Action::Custom(Keycode::SystemControl(
+                            crate::system_control::SystemControlKeycode::WakeUp,
                         )));
                     }
 
@@ -1387,6 +1631,67 @@ where
                             usbd_human_interface_device::page::Consumer::ALControlPanel,
                         )));
                     }
+
+                    if keycode == QMKKeycodes::KC_MY_COMPUTER as u16 {
+                        return Some(Action::Custom(Keycode::Media(
+                            usbd_human_interface_device::page::Consumer::ALLocalMachineBrowser,
+                        )));
+                    }
+
+                    if keycode == QMKKeycodes::KC_WWW_FAVORITES as u16 {
+                        return Some(Action::Custom(Keycode::Media(
+                            usbd_human_interface_device::page::Consumer::ACBookmarks,
+                        )));
+                    }
+
+                    if keycode == QMKKeycodes::KC_BRIGHTNESS_UP as u16 {
+                        return Some(Action::Custom(Keycode::Media(
+                            usbd_human_interface_device::page::Consumer::DisplayBrightnessIncrement,
+                        )));
+                    }
+
+                    if keycode == QMKKeycodes::KC_BRIGHTNESS_DOWN as u16 {
+                        return Some(Action::Custom(Keycode::Media(
+                            usbd_human_interface_device::page::Consumer::DisplayBrightnessDecrement,
+                        )));
+                    }
+                }
+
+                #[cfg(feature = "mouse")]
+                {
+                    if QMKKeycodes::KC_MS_BTN1 as u16 <= keycode
+                        && keycode <= QMKKeycodes::KC_MS_BTN8 as u16
+                    {
+                        return Some(Action::Custom(Keycode::Mouse(
+                            crate::mouse::MouseKeycode::Button(
+                                (keycode - QMKKeycodes::KC_MS_BTN1 as u16) as u8,
+                            ),
+                        )));
+                    }
+
+                    let mouse_keycode = if keycode == QMKKeycodes::KC_MS_UP as u16 {
+                        Some(crate::mouse::MouseKeycode::Up)
+                    } else if keycode == QMKKeycodes::KC_MS_DOWN as u16 {
+                        Some(crate::mouse::MouseKeycode::Down)
+                    } else if keycode == QMKKeycodes::KC_MS_LEFT as u16 {
+                        Some(crate::mouse::MouseKeycode::Left)
+                    } else if keycode == QMKKeycodes::KC_MS_RIGHT as u16 {
+                        Some(crate::mouse::MouseKeycode::Right)
+                    } else if keycode == QMKKeycodes::KC_MS_WH_UP as u16 {
+                        Some(crate::mouse::MouseKeycode::WheelUp)
+                    } else if keycode == QMKKeycodes::KC_MS_WH_DOWN as u16 {
+                        Some(crate::mouse::MouseKeycode::WheelDown)
+                    } else if keycode == QMKKeycodes::KC_MS_WH_LEFT as u16 {
+                        Some(crate::mouse::MouseKeycode::WheelLeft)
+                    } else if keycode == QMKKeycodes::KC_MS_WH_RIGHT as u16 {
+                        Some(crate::mouse::MouseKeycode::WheelRight)
+                    } else {
+                        None
+                    };
+
+                    if let Some(mouse_keycode) = mouse_keycode {
+                        return Some(Action::Custom(Keycode::Mouse(mouse_keycode)));
+                    }
                 }
 
                 None
@@ -1426,6 +1731,43 @@ where
         return ONE_SHOT_LAYER_ACTIONS.get(layer).map(Action::OneShot);
     }
 
+    if QMKKeycodeRanges::QK_SWAP_HANDS as u16 <= keycode
+        && keycode <= QMKKeycodeRanges::QK_SWAP_HANDS_MAX as u16
+    {
+        let commands = [
+            (
+                QMKKeycodes::QK_SWAP_HANDS_MOMENTARY_ON as u16,
+                crate::swap_hands::SwapHandsCommand::Momentary,
+            ),
+            (
+                QMKKeycodes::QK_SWAP_HANDS_MOMENTARY_OFF as u16,
+                crate::swap_hands::SwapHandsCommand::MomentaryOff,
+            ),
+            (
+                QMKKeycodes::QK_SWAP_HANDS_ONE_SHOT as u16,
+                crate::swap_hands::SwapHandsCommand::OneShot,
+            ),
+            (
+                QMKKeycodes::QK_SWAP_HANDS_TOGGLE as u16,
+                crate::swap_hands::SwapHandsCommand::Toggle,
+            ),
+            (
+                QMKKeycodes::QK_SWAP_HANDS_ON as u16,
+                crate::swap_hands::SwapHandsCommand::On,
+            ),
+            (
+                QMKKeycodes::QK_SWAP_HANDS_OFF as u16,
+                crate::swap_hands::SwapHandsCommand::Off,
+            ),
+        ];
+
+        // TODO: swap hands tap keys (SH_T) and SH_TT are not supported
+        return commands
+            .into_iter()
+            .find(|(qmk_keycode, _)| *qmk_keycode == keycode)
+            .map(|(_, command)| Action::Custom(Keycode::SwapHands(command)));
+    }
+
     if QMKKeycodeRanges::QK_MACRO as u16 <= keycode
         && keycode <= QMKKeycodeRanges::QK_MACRO_MAX as u16
     {
@@ -1702,8 +2044,124 @@ where
     }
 
     if QMKKeycodeRanges::QK_QUANTUM as u16 <= keycode
-        && keycode <= QMKKeycodeRanges::QK_QUANTUM as u16
+        && keycode <= QMKKeycodeRanges::QK_QUANTUM_MAX as u16
     {
+        if keycode == QMKKeycodes::QK_BOOTLOADER as u16 {
+            return Some(Action::Custom(Keycode::Bootloader));
+        }
+
+        if keycode == QMKKeycodes::QK_REBOOT as u16 {
+            return Some(Action::Custom(Keycode::Reset));
+        }
+
+        if keycode == QMKKeycodes::QK_DYNAMIC_TAPPING_TERM_UP as u16 {
+            return Some(Action::Custom(Keycode::TapHold(
+                crate::keyboard::TapHoldCommand::IncreaseTappingTerm(TAPPING_TERM_INCREMENT),
+            )));
+        }
+
+        if keycode == QMKKeycodes::QK_DYNAMIC_TAPPING_TERM_DOWN as u16 {
+            return Some(Action::Custom(Keycode::TapHold(
+                crate::keyboard::TapHoldCommand::DecreaseTappingTerm(TAPPING_TERM_INCREMENT),
+            )));
+        }
+
+        if keycode == QMKKeycodes::QK_LEADER as u16 {
+            return Some(Action::Custom(Keycode::Leader));
+        }
+
+        if keycode == QMKKeycodes::QK_DYNAMIC_MACRO_RECORD_START_1 as u16 {
+            return Some(Action::Custom(Keycode::DynamicMacro(
+                crate::dynamic_macro::DynamicMacroCommand::StartRecording(0),
+            )));
+        }
+
+        if keycode == QMKKeycodes::QK_DYNAMIC_MACRO_RECORD_START_2 as u16 {
+            return Some(Action::Custom(Keycode::DynamicMacro(
+                crate::dynamic_macro::DynamicMacroCommand::StartRecording(1),
+            )));
+        }
+
+        if keycode == QMKKeycodes::QK_DYNAMIC_MACRO_RECORD_STOP as u16 {
+            return Some(Action::Custom(Keycode::DynamicMacro(
+                crate::dynamic_macro::DynamicMacroCommand::StopRecording,
+            )));
+        }
+
+        if keycode == QMKKeycodes::QK_DYNAMIC_MACRO_PLAY_1 as u16 {
+            return Some(Action::Custom(Keycode::DynamicMacro(
+                crate::dynamic_macro::DynamicMacroCommand::Play(0),
+            )));
+        }
+
+        if keycode == QMKKeycodes::QK_DYNAMIC_MACRO_PLAY_2 as u16 {
+            return Some(Action::Custom(Keycode::DynamicMacro(
+                crate::dynamic_macro::DynamicMacroCommand::Play(1),
+            )));
+        }
+
+        if keycode == QMKKeycodes::QK_GRAVE_ESCAPE as u16 {
+            return Some(Action::Custom(Keycode::GraveEscape));
+        }
+
+        if keycode == QMKKeycodes::QK_LOCK as u16 {
+            return Some(Action::Custom(Keycode::KeyLock));
+        }
+
+        if let Some((_, action)) = SPACE_CADET_KEYCODES
+            .iter()
+            .find(|(qmk_keycode, _)| *qmk_keycode == keycode)
+        {
+            return Some(*action);
+        }
+
+        if keycode == QMKKeycodes::QK_UNICODE_MODE_NEXT as u16 {
+            return Some(Action::Custom(Keycode::Unicode(
+                crate::unicode::UnicodeCommand::NextMode,
+            )));
+        }
+
+        if keycode == QMKKeycodes::QK_UNICODE_MODE_PREVIOUS as u16 {
+            return Some(Action::Custom(Keycode::Unicode(
+                crate::unicode::UnicodeCommand::PreviousMode,
+            )));
+        }
+
+        if keycode == QMKKeycodes::QK_UNICODE_MODE_LINUX as u16 {
+            return Some(Action::Custom(Keycode::Unicode(
+                crate::unicode::UnicodeCommand::SetMode(crate::unicode::UnicodeMode::Linux),
+            )));
+        }
+
+        if keycode == QMKKeycodes::QK_UNICODE_MODE_MACOS as u16 {
+            return Some(Action::Custom(Keycode::Unicode(
+                crate::unicode::UnicodeCommand::SetMode(crate::unicode::UnicodeMode::MacOS),
+            )));
+        }
+
+        if keycode == QMKKeycodes::QK_UNICODE_MODE_WINCOMPOSE as u16 {
+            return Some(Action::Custom(Keycode::Unicode(
+                crate::unicode::UnicodeCommand::SetMode(crate::unicode::UnicodeMode::WinCompose),
+            )));
+        }
+
+        #[cfg(any(feature = "console", feature = "hid-console", feature = "nus-console"))]
+        if keycode == QMKKeycodes::QK_DEBUG_TOGGLE as u16 {
+            return Some(Action::Custom(Keycode::DebugToggle));
+        }
+
+        #[cfg(feature = "storage")]
+        if keycode == QMKKeycodes::QK_CLEAR_EEPROM as u16 {
+            return Some(Action::Custom(Keycode::FactoryReset));
+        }
+
+        #[cfg(all(feature = "usb", feature = "bluetooth"))]
+        if keycode == QMKKeycodes::QK_OUTPUT_AUTO as u16 {
+            return Some(Action::Custom(Keycode::Bluetooth(
+                crate::bluetooth::BluetoothCommand::OutputAuto,
+            )));
+        }
+
         #[cfg(all(feature = "usb", feature = "bluetooth"))]
         if keycode == QMKKeycodes::QK_OUTPUT_USB as u16 {
             return Some(Action::Custom(Keycode::Bluetooth(
@@ -1719,11 +2177,95 @@ where
         }
     }
 
+    #[cfg(feature = "midi")]
+    if QMKKeycodeRanges::QK_MIDI as u16 <= keycode
+        && keycode <= QMKKeycodeRanges::QK_MIDI_MAX as u16
+    {
+        if QMKKeycodes::QK_MIDI_NOTE_C_0 as u16 <= keycode
+            && keycode <= QMKKeycodes::QK_MIDI_NOTE_B_5 as u16
+        {
+            return Some(Action::Custom(Keycode::Midi(
+                crate::midi::MidiKeycode::Note(
+                    MIDI_NOTE_OFFSET + (keycode - QMKKeycodes::QK_MIDI_NOTE_C_0 as u16) as u8,
+                ),
+            )));
+        }
+
+        let midi_keycode = if keycode == QMKKeycodes::QK_MIDI_SUSTAIN as u16 {
+            Some(crate::midi::MidiKeycode::ControlChange(64, 127))
+        } else if keycode == QMKKeycodes::QK_MIDI_PORTAMENTO as u16 {
+            Some(crate::midi::MidiKeycode::ControlChange(65, 127))
+        } else if keycode == QMKKeycodes::QK_MIDI_SOSTENUTO as u16 {
+            Some(crate::midi::MidiKeycode::ControlChange(66, 127))
+        } else if keycode == QMKKeycodes::QK_MIDI_SOFT as u16 {
+            Some(crate::midi::MidiKeycode::ControlChange(67, 127))
+        } else if keycode == QMKKeycodes::QK_MIDI_LEGATO as u16 {
+            Some(crate::midi::MidiKeycode::ControlChange(68, 127))
+        } else if keycode == QMKKeycodes::QK_MIDI_ALL_NOTES_OFF as u16 {
+            Some(crate::midi::MidiKeycode::AllNotesOff)
+        } else if keycode == QMKKeycodes::QK_MIDI_CHANNEL_UP as u16 {
+            Some(crate::midi::MidiKeycode::ChannelUp)
+        } else if keycode == QMKKeycodes::QK_MIDI_CHANNEL_DOWN as u16 {
+            Some(crate::midi::MidiKeycode::ChannelDown)
+        } else if keycode == QMKKeycodes::QK_MIDI_VELOCITY_UP as u16 {
+            Some(crate::midi::MidiKeycode::VelocityUp)
+        } else if keycode == QMKKeycodes::QK_MIDI_VELOCITY_DOWN as u16 {
+            Some(crate::midi::MidiKeycode::VelocityDown)
+        } else {
+            None
+        };
+
+        if let Some(midi_keycode) = midi_keycode {
+            return Some(Action::Custom(Keycode::Midi(midi_keycode)));
+        }
+    }
+
+    if QMKKeycodeRanges::QK_MAGIC as u16 <= keycode
+        && keycode <= QMKKeycodeRanges::QK_MAGIC_MAX as u16
+    {
+        if keycode == QMKKeycodes::QK_MAGIC_NKRO_ON as u16 {
+            return Some(Action::Custom(Keycode::NKRO(
+                crate::keyboard::NKROCommand::Enable,
+            )));
+        }
+
+        if keycode == QMKKeycodes::QK_MAGIC_NKRO_OFF as u16 {
+            return Some(Action::Custom(Keycode::NKRO(
+                crate::keyboard::NKROCommand::Disable,
+            )));
+        }
+
+        if keycode == QMKKeycodes::QK_MAGIC_TOGGLE_NKRO as u16 {
+            return Some(Action::Custom(Keycode::NKRO(
+                crate::keyboard::NKROCommand::Toggle,
+            )));
+        }
+    }
+
+    #[cfg(feature = "gamepad")]
+    if QMKKeycodes::QK_JOYSTICK_BUTTON_0 as u16 <= keycode
+        && keycode <= QMKKeycodes::QK_JOYSTICK_BUTTON_31 as u16
+    {
+        return Some(Action::Custom(Keycode::Gamepad(
+            crate::gamepad::GamepadKeycode::Button(
+                (keycode - QMKKeycodes::QK_JOYSTICK_BUTTON_0 as u16) as u8,
+            ),
+        )));
+    }
+
     if QMKKeycodeRanges::QK_KB as u16 <= keycode && keycode <= QMKKeycodeRanges::QK_KB_MAX as u16 {
         return Some(Action::Custom(Keycode::Custom(
             (keycode - QMKKeycodeRanges::QK_KB as u16) as u8,
         )));
     }
 
+    if QMKKeycodeRanges::QK_UNICODE as u16 <= keycode
+        && keycode <= QMKKeycodeRanges::QK_UNICODE_MAX as u16
+    {
+        return char::from_u32((keycode - QMKKeycodeRanges::QK_UNICODE as u16) as u32).map(
+            |char| Action::Custom(Keycode::Unicode(crate::unicode::UnicodeCommand::Type(char))),
+        );
+    }
+
     None
 }
@@ -35,6 +35,7 @@ pub(crate) enum ViaCommandId {
     DynamicKeymapSetBuffer,
     DynamicKeymapGetEncoder,
     DynamicKeymapSetEncoder,
+    StorageBackup = 0xFD,
     Unhandled = 0xFF,
 }
 
@@ -271,7 +272,9 @@ pub(crate) async fn process_via_command<K: ViaKeyboard + 'static>(
                 )
                 .await
             }
-            ViaCommandId::DynamicKeymapReset => dynamic_keymap_reset().await,
+            ViaCommandId::DynamicKeymapReset => dynamic_keymap_reset::<K>().await,
+            #[cfg(feature = "storage")]
+            ViaCommandId::StorageBackup => storage_backup(&mut data[1..]).await,
             command
                 if command == ViaCommandId::CustomGetValue
                     || command == ViaCommandId::CustomSetValue
@@ -583,13 +586,14 @@ where
     }
 
     loop {
-        match select::select(
+        match select::select3(
             subscriber.next_message_pure(),
             BOOTLOADER_JUMP_SIGNAL.wait(),
+            RESET_SIGNAL.wait(),
         )
         .await
         {
-            select::Either::First(event) => {
+            select::Either3::First(event) => {
                 let (row, col) = event.coord();
                 // (cols + 8 bits - 1) / 8 bits: we get the number of bytes needed to store the state of a
                 // row (based on number of cols). multiply this by (row + 1), subtract by 1 and subtract by
@@ -607,11 +611,16 @@ where
                         !(1 << (col as usize % u8::BITS as usize));
                 };
             }
-            select::Either::Second(()) => {
+            select::Either3::Second(()) => {
                 // Wait for 500 ms. This should give enough time to send an HID report and let the host read it
                 embassy_time::Timer::after(embassy_time::Duration::from_millis(500)).await;
                 crate::hw::mcu::jump_to_bootloader();
             }
+            select::Either3::Third(()) => {
+                // Same as above, give the host some time to read the HID report before resetting
+                embassy_time::Timer::after(embassy_time::Duration::from_millis(500)).await;
+                crate::hw::reset();
+            }
         }
     }
 }
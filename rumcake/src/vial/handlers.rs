@@ -3,7 +3,7 @@ use smart_leds::hsv::hsv2rgb;
 
 use super::protocol::via::ViaState;
 use super::protocol::{VialState, VIAL_RAW_EPSIZE};
-use super::{VialKeyboard, VIAL_DIRECT_SET_CHANNEL};
+use super::{ComboEntry, KeyOverrideEntry, TapDanceEntry, VialKeyboard, DYNAMIC_ENTRY_SIZE};
 use crate::backlight::BacklightMatrixDevice;
 
 // Unlike the other normal Via comands, Vial overwrites the command data received from the host
@@ -266,8 +266,8 @@ where
             let sat = data[(3 + (led - first_led) * 3 + 1) as usize];
             let val = data[(3 + (led - first_led) * 3 + 2) as usize];
             // TODO: use max brightness?
-            VIAL_DIRECT_SET_CHANNEL
-                .send((led, hsv2rgb(smart_leds::hsv::Hsv { hue, sat, val })))
+            crate::backlight::rgb_backlight_matrix::DIRECT_SET_CHANNEL
+                .send((led as u16, hsv2rgb(smart_leds::hsv::Hsv { hue, sat, val })))
                 .await;
         }
     }
@@ -297,28 +297,107 @@ pub fn dynamic_keymap_get_number_of_entries<K: VialKeyboard>(data: &mut [u8]) {
     data[2] = K::VIAL_KEY_OVERRIDE_ENTRIES;
 }
 
-pub fn dynamic_keymap_get_tap_dance(data: &mut [u8]) {
-    // TODO
+// Entry getters and setters respond with 0 in the first byte on success, and 1 if the entry is
+// out of bounds.
+
+pub fn dynamic_keymap_get_tap_dance<K: VialKeyboard>(data: &mut [u8]) {
+    let idx = data[3] as usize;
+    data.fill(0);
+
+    if let Some(entry) = K::get_tap_dance_entries().get(idx) {
+        data[1..=DYNAMIC_ENTRY_SIZE].copy_from_slice(&entry.to_bytes());
+    } else {
+        warn!("[VIAL] Requested a tap dance entry that is out of bounds.");
+        data[0] = 1;
+    }
 }
 
-pub fn dynamic_keymap_set_tap_dance(data: &mut [u8]) {
-    // TODO
+pub async fn dynamic_keymap_set_tap_dance<K: VialKeyboard>(data: &mut [u8]) {
+    let idx = data[3] as usize;
+
+    if let Some(entry) = K::get_tap_dance_entries().get_mut(idx) {
+        *entry = TapDanceEntry::from_bytes(&data[4..(4 + DYNAMIC_ENTRY_SIZE)]);
+
+        #[cfg(feature = "storage")]
+        super::storage::update_data(
+            super::storage::VialStorageKeys::DynamicKeymapTapDance,
+            idx * DYNAMIC_ENTRY_SIZE,
+            &data[4..(4 + DYNAMIC_ENTRY_SIZE)],
+        )
+        .await;
+
+        data[0] = 0;
+    } else {
+        warn!("[VIAL] Attempted to set a tap dance entry out of bounds.");
+        data[0] = 1;
+    }
 }
 
-pub fn dynamic_keymap_get_combo(data: &mut [u8]) {
-    // TODO
+pub fn dynamic_keymap_get_combo<K: VialKeyboard>(data: &mut [u8]) {
+    let idx = data[3] as usize;
+    data.fill(0);
+
+    if let Some(entry) = K::get_combo_entries().get(idx) {
+        data[1..=DYNAMIC_ENTRY_SIZE].copy_from_slice(&entry.to_bytes());
+    } else {
+        warn!("[VIAL] Requested a combo entry that is out of bounds.");
+        data[0] = 1;
+    }
 }
 
-pub fn dynamic_keymap_set_combo(data: &mut [u8]) {
-    // TODO
+pub async fn dynamic_keymap_set_combo<K: VialKeyboard>(data: &mut [u8]) {
+    let idx = data[3] as usize;
+
+    if let Some(entry) = K::get_combo_entries().get_mut(idx) {
+        *entry = ComboEntry::from_bytes(&data[4..(4 + DYNAMIC_ENTRY_SIZE)]);
+        super::COMBO_ENTRIES_CHANGED.signal(());
+
+        #[cfg(feature = "storage")]
+        super::storage::update_data(
+            super::storage::VialStorageKeys::DynamicKeymapCombo,
+            idx * DYNAMIC_ENTRY_SIZE,
+            &data[4..(4 + DYNAMIC_ENTRY_SIZE)],
+        )
+        .await;
+
+        data[0] = 0;
+    } else {
+        warn!("[VIAL] Attempted to set a combo entry out of bounds.");
+        data[0] = 1;
+    }
 }
 
-pub fn dynamic_keymap_get_key_override(data: &mut [u8]) {
-    // TODO
+pub fn dynamic_keymap_get_key_override<K: VialKeyboard>(data: &mut [u8]) {
+    let idx = data[3] as usize;
+    data.fill(0);
+
+    if let Some(entry) = K::get_key_override_entries().get(idx) {
+        data[1..=DYNAMIC_ENTRY_SIZE].copy_from_slice(&entry.to_bytes());
+    } else {
+        warn!("[VIAL] Requested a key override entry that is out of bounds.");
+        data[0] = 1;
+    }
 }
 
-pub fn dynamic_keymap_set_key_override(data: &mut [u8]) {
-    // TODO
+pub async fn dynamic_keymap_set_key_override<K: VialKeyboard>(data: &mut [u8]) {
+    let idx = data[3] as usize;
+
+    if let Some(entry) = K::get_key_override_entries().get_mut(idx) {
+        *entry = KeyOverrideEntry::from_bytes(&data[4..(4 + DYNAMIC_ENTRY_SIZE)]);
+
+        #[cfg(feature = "storage")]
+        super::storage::update_data(
+            super::storage::VialStorageKeys::DynamicKeymapKeyOverride,
+            idx * DYNAMIC_ENTRY_SIZE,
+            &data[4..(4 + DYNAMIC_ENTRY_SIZE)],
+        )
+        .await;
+
+        data[0] = 0;
+    } else {
+        warn!("[VIAL] Attempted to set a key override entry out of bounds.");
+        data[0] = 1;
+    }
 }
 
 pub async fn eeprom_reset() {
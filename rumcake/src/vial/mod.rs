@@ -3,20 +3,153 @@
 //! To use Vial, you will need to implement [`ViaKeyboard`] and [`VialKeyboard`].
 
 use crate::backlight::{BacklightMatrixDevice, EmptyBacklightMatrix};
-use defmt::assert;
+use defmt::{assert, warn};
 use embassy_futures::join;
-use embassy_sync::channel::Channel;
 use embassy_sync::mutex::Mutex;
-use smart_leds::RGB8;
+use embassy_sync::signal::Signal;
+use heapless::Vec;
+use keyberon::action::Action;
 
+use crate::combo::{DynamicCombo, DYNAMIC_COMBOS, MAX_DYNAMIC_COMBOS};
 use crate::hw::mcu::RawMutex;
-use crate::via::{ViaKeyboard, VIA_REPORT_HID_RECEIVE_CHANNEL, VIA_REPORT_HID_SEND_CHANNEL};
+use crate::keyboard::Keycode;
+use crate::raw_hid::{RAW_HID_REPORT_RECEIVE_CHANNEL, RAW_HID_REPORT_SEND_CHANNEL};
+use crate::via::ViaKeyboard;
 
 mod handlers;
 
 pub(crate) mod protocol;
 
-pub use rumcake_macros::enable_vial_rgb;
+pub use rumcake_macros::{enable_vial_rgb, setup_vial_dynamic_entries};
+
+/// Size of a serialized [`TapDanceEntry`], [`ComboEntry`] or [`KeyOverrideEntry`], in bytes.
+pub(crate) const DYNAMIC_ENTRY_SIZE: usize = 10;
+
+/// A tap dance entry created by Vial. Actions are stored as Via keycodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TapDanceEntry {
+    pub on_tap: u16,
+    pub on_hold: u16,
+    pub on_double_tap: u16,
+    pub on_tap_hold: u16,
+    pub tapping_term: u16,
+}
+
+/// A combo entry created by Vial. Keys are stored as Via keycodes. Unused input slots are set to
+/// `0` (`KC_NO`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComboEntry {
+    pub input: [u16; 4],
+    pub output: u16,
+}
+
+/// A key override entry created by Vial. Keys are stored as Via keycodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyOverrideEntry {
+    pub trigger: u16,
+    pub replacement: u16,
+    pub layers: u16,
+    pub trigger_mods: u8,
+    pub negative_mod_mask: u8,
+    pub suppressed_mods: u8,
+    pub options: u8,
+}
+
+// Vial sends these entries as little-endian C structs.
+
+impl TapDanceEntry {
+    pub const EMPTY: Self = Self {
+        on_tap: 0,
+        on_hold: 0,
+        on_double_tap: 0,
+        on_tap_hold: 0,
+        tapping_term: 0,
+    };
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            on_tap: u16::from_le_bytes([bytes[0], bytes[1]]),
+            on_hold: u16::from_le_bytes([bytes[2], bytes[3]]),
+            on_double_tap: u16::from_le_bytes([bytes[4], bytes[5]]),
+            on_tap_hold: u16::from_le_bytes([bytes[6], bytes[7]]),
+            tapping_term: u16::from_le_bytes([bytes[8], bytes[9]]),
+        }
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; DYNAMIC_ENTRY_SIZE] {
+        let mut bytes = [0; DYNAMIC_ENTRY_SIZE];
+        bytes[0..=1].copy_from_slice(&self.on_tap.to_le_bytes());
+        bytes[2..=3].copy_from_slice(&self.on_hold.to_le_bytes());
+        bytes[4..=5].copy_from_slice(&self.on_double_tap.to_le_bytes());
+        bytes[6..=7].copy_from_slice(&self.on_tap_hold.to_le_bytes());
+        bytes[8..=9].copy_from_slice(&self.tapping_term.to_le_bytes());
+        bytes
+    }
+}
+
+impl ComboEntry {
+    pub const EMPTY: Self = Self {
+        input: [0; 4],
+        output: 0,
+    };
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Self {
+        let mut input = [0; 4];
+        for (i, key) in input.iter_mut().enumerate() {
+            *key = u16::from_le_bytes([bytes[i * 2], bytes[i * 2 + 1]]);
+        }
+
+        Self {
+            input,
+            output: u16::from_le_bytes([bytes[8], bytes[9]]),
+        }
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; DYNAMIC_ENTRY_SIZE] {
+        let mut bytes = [0; DYNAMIC_ENTRY_SIZE];
+        for (i, key) in self.input.iter().enumerate() {
+            bytes[(i * 2)..=(i * 2 + 1)].copy_from_slice(&key.to_le_bytes());
+        }
+        bytes[8..=9].copy_from_slice(&self.output.to_le_bytes());
+        bytes
+    }
+}
+
+impl KeyOverrideEntry {
+    pub const EMPTY: Self = Self {
+        trigger: 0,
+        replacement: 0,
+        layers: 0,
+        trigger_mods: 0,
+        negative_mod_mask: 0,
+        suppressed_mods: 0,
+        options: 0,
+    };
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            trigger: u16::from_le_bytes([bytes[0], bytes[1]]),
+            replacement: u16::from_le_bytes([bytes[2], bytes[3]]),
+            layers: u16::from_le_bytes([bytes[4], bytes[5]]),
+            trigger_mods: bytes[6],
+            negative_mod_mask: bytes[7],
+            suppressed_mods: bytes[8],
+            options: bytes[9],
+        }
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; DYNAMIC_ENTRY_SIZE] {
+        let mut bytes = [0; DYNAMIC_ENTRY_SIZE];
+        bytes[0..=1].copy_from_slice(&self.trigger.to_le_bytes());
+        bytes[2..=3].copy_from_slice(&self.replacement.to_le_bytes());
+        bytes[4..=5].copy_from_slice(&self.layers.to_le_bytes());
+        bytes[6] = self.trigger_mods;
+        bytes[7] = self.negative_mod_mask;
+        bytes[8] = self.suppressed_mods;
+        bytes[9] = self.options;
+        bytes
+    }
+}
 
 /// A trait that keyboards must implement to use the Vial protocol.
 pub trait VialKeyboard: ViaKeyboard {
@@ -42,9 +175,39 @@ pub trait VialKeyboard: ViaKeyboard {
     /// [`rgb-backlight-matrix`] feature flag enabled. To enable this, you should use
     /// [`enable_vial_rgb`] instead of implementing this yourself.
     const VIALRGB_ENABLE: bool = false;
-    const VIAL_TAP_DANCE_ENTRIES: u8 = 0; // TODO: Change when tap dance is implemented
-    const VIAL_COMBO_ENTRIES: u8 = 0; // TODO: Change when combo is implemented
-    const VIAL_KEY_OVERRIDE_ENTRIES: u8 = 0; // TODO: Change when key override is implemented
+
+    /// Number of tap dance entries that can be edited in Vial. You should use
+    /// [`setup_vial_dynamic_entries`] to implement this.
+    const VIAL_TAP_DANCE_ENTRIES: u8 = 0;
+
+    /// Number of combo entries that can be edited in Vial. You should use
+    /// [`setup_vial_dynamic_entries`] to implement this.
+    const VIAL_COMBO_ENTRIES: u8 = 0;
+
+    /// Number of key override entries that can be edited in Vial. You should use
+    /// [`setup_vial_dynamic_entries`] to implement this.
+    const VIAL_KEY_OVERRIDE_ENTRIES: u8 = 0;
+
+    /// Obtain a reference to the tap dance entries created by Vial. You should use
+    /// [`setup_vial_dynamic_entries`] to implement this. The length of the slice must be equal to
+    /// [`VialKeyboard::VIAL_TAP_DANCE_ENTRIES`].
+    fn get_tap_dance_entries() -> &'static mut [TapDanceEntry] {
+        &mut []
+    }
+
+    /// Obtain a reference to the combo entries created by Vial. You should use
+    /// [`setup_vial_dynamic_entries`] to implement this. The length of the slice must be equal to
+    /// [`VialKeyboard::VIAL_COMBO_ENTRIES`].
+    fn get_combo_entries() -> &'static mut [ComboEntry] {
+        &mut []
+    }
+
+    /// Obtain a reference to the key override entries created by Vial. You should use
+    /// [`setup_vial_dynamic_entries`] to implement this. The length of the slice must be equal to
+    /// [`VialKeyboard::VIAL_KEY_OVERRIDE_ENTRIES`].
+    fn get_key_override_entries() -> &'static mut [KeyOverrideEntry] {
+        &mut []
+    }
 
     // TODO: replace with specialization if it doesn't cause an ICE
     type BacklightMatrixDevice: BacklightMatrixDevice = EmptyBacklightMatrix;
@@ -58,9 +221,36 @@ pub trait VialKeyboard: ViaKeyboard {
     }
 }
 
-/// Channel used to update the frame buffer for the
-/// [`crate::backlight::rgb_backlight_matrix::animations::BacklightEffect::DirectSet`] effect.
-pub(crate) static VIAL_DIRECT_SET_CHANNEL: Channel<RawMutex, (u8, RGB8), 4> = Channel::new();
+/// Signal used to update the combos used by the layout when the combo entries change.
+static COMBO_ENTRIES_CHANGED: Signal<RawMutex, ()> = Signal::new();
+
+/// Replace the combos used by the layout with the combo entries created by Vial. Combo keys must
+/// be basic keycodes, since they are matched using the keycodes produced by the pressed keys.
+async fn update_combos<K: VialKeyboard>(
+    convert_keycode_to_action: impl Fn(u16) -> Option<Action<Keycode>>,
+) {
+    let mut combos = DYNAMIC_COMBOS.lock().await;
+    combos.clear();
+
+    for entry in K::get_combo_entries().iter() {
+        if entry.input.iter().all(|key| *key == 0) {
+            continue;
+        }
+
+        let keys = entry
+            .input
+            .iter()
+            .filter(|key| **key != 0)
+            .map(|key| num::FromPrimitive::from_u16(*key))
+            .collect::<Option<Vec<_, _>>>();
+
+        if let (Some(keys), Some(action)) = (keys, convert_keycode_to_action(entry.output)) {
+            let _ = combos.push(DynamicCombo { keys, action });
+        } else {
+            warn!("[VIAL] Combo entry contains unsupported keycodes, it will not be used.");
+        }
+    }
+}
 
 #[rumcake_macros::task]
 pub async fn vial_process_task<K: VialKeyboard + 'static>(_k: K)
@@ -77,6 +267,16 @@ where
     assert!(K::DYNAMIC_KEYMAP_LAYER_COUNT <= K::LAYERS);
     assert!(K::DYNAMIC_KEYMAP_LAYER_COUNT <= 16);
     assert!(K::VIAL_UNLOCK_COMBO.len() < 15);
+    assert!(K::get_tap_dance_entries().len() == K::VIAL_TAP_DANCE_ENTRIES as usize);
+    assert!(K::get_combo_entries().len() == K::VIAL_COMBO_ENTRIES as usize);
+    assert!(K::VIAL_COMBO_ENTRIES as usize <= MAX_DYNAMIC_COMBOS);
+    assert!(K::get_key_override_entries().len() == K::VIAL_KEY_OVERRIDE_ENTRIES as usize);
+    if let Some(encoder_map) = K::get_encoder_map() {
+        assert!(
+            encoder_map.len() == K::DYNAMIC_KEYMAP_LAYER_COUNT * K::NUM_ENCODERS,
+            "Encoder map size must be equal to the dynamic keymap layer count multiplied by the number of encoders."
+        );
+    }
     if K::get_macro_buffer().is_some() {
         assert!(
             K::DYNAMIC_KEYMAP_MACRO_BUFFER_SIZE > 0,
@@ -102,7 +302,7 @@ where
 
     let report_fut = async {
         loop {
-            let mut report = VIA_REPORT_HID_RECEIVE_CHANNEL.receive().await;
+            let mut report = RAW_HID_REPORT_RECEIVE_CHANNEL.receive().await;
 
             if K::VIAL_ENABLED && K::VIA_ENABLED {
                 {
@@ -116,23 +316,37 @@ where
                     .await;
                 }
 
-                VIA_REPORT_HID_SEND_CHANNEL.send(report).await;
+                RAW_HID_REPORT_SEND_CHANNEL.send(report).await;
             }
         }
     };
 
-    join::join(report_fut, protocol::via::background_task::<K>(&via_state)).await;
+    let combo_fut = async {
+        loop {
+            update_combos::<K>(crate::via::protocol::keycodes::convert_keycode_to_action::<K>)
+                .await;
+            COMBO_ENTRIES_CHANGED.wait().await;
+        }
+    };
+
+    join::join3(
+        report_fut,
+        protocol::via::background_task::<K>(&via_state),
+        combo_fut,
+    )
+    .await;
 }
 
 #[cfg(feature = "storage")]
 pub mod storage {
+    use defmt::{warn, Debug2Format};
     use embassy_sync::channel::Channel;
     use embassy_sync::signal::Signal;
 
     use crate::hw::mcu::RawMutex;
-    use crate::storage::{FlashStorage, StorageDevice, StorageKey};
+    use crate::storage::{FlashStorage, StorageDevice, StorageError, StorageKey};
 
-    use super::VialKeyboard;
+    use super::{ComboEntry, KeyOverrideEntry, TapDanceEntry, VialKeyboard, DYNAMIC_ENTRY_SIZE};
 
     pub(super) enum VialStorageKeys {
         DynamicKeymapTapDance,
@@ -185,11 +399,14 @@ pub mod storage {
         database: &crate::storage::StorageService<'static, F>,
     ) where
         [(); F::ERASE_SIZE]:,
+        [(); F::REGION_SIZE]:,
+        [(); K::VIAL_TAP_DANCE_ENTRIES as usize * DYNAMIC_ENTRY_SIZE]:,
+        [(); K::VIAL_COMBO_ENTRIES as usize * DYNAMIC_ENTRY_SIZE]:,
+        [(); K::VIAL_KEY_OVERRIDE_ENTRIES as usize * DYNAMIC_ENTRY_SIZE]:,
     {
         // Initialize Vial data
         {
-            // let tap_dance_metadata: [u8; core::mem::size_of::<TypeId>()] = unsafe {core::mem::transmute(TypeId::of::<>())};
-            let tap_dance_metadata = [1];
+            let tap_dance_metadata = [K::VIAL_TAP_DANCE_ENTRIES];
             let _ = database
                 .check_metadata(
                     K::get_storage_buffer(),
@@ -197,9 +414,19 @@ pub mod storage {
                     &tap_dance_metadata,
                 )
                 .await;
+            if let Ok((stored_data, stored_len)) = database
+                .read_raw(K::get_storage_buffer(), StorageKey::DynamicKeymapTapDance)
+                .await
+            {
+                for (entry, stored) in K::get_tap_dance_entries()
+                    .iter_mut()
+                    .zip(stored_data[..stored_len].chunks_exact(DYNAMIC_ENTRY_SIZE))
+                {
+                    *entry = TapDanceEntry::from_bytes(stored);
+                }
+            };
 
-            // let combo_metadata: [u8; core::mem::size_of::<TypeId>()] = unsafe {core::mem::transmute(TypeId::of::<>())};
-            let combo_metadata = [1];
+            let combo_metadata = [K::VIAL_COMBO_ENTRIES];
             let _ = database
                 .check_metadata(
                     K::get_storage_buffer(),
@@ -207,9 +434,20 @@ pub mod storage {
                     &combo_metadata,
                 )
                 .await;
+            if let Ok((stored_data, stored_len)) = database
+                .read_raw(K::get_storage_buffer(), StorageKey::DynamicKeymapCombo)
+                .await
+            {
+                for (entry, stored) in K::get_combo_entries()
+                    .iter_mut()
+                    .zip(stored_data[..stored_len].chunks_exact(DYNAMIC_ENTRY_SIZE))
+                {
+                    *entry = ComboEntry::from_bytes(stored);
+                }
+                super::COMBO_ENTRIES_CHANGED.signal(());
+            };
 
-            // let key_override_metadata: [u8; core::mem::size_of::<TypeId>()] = unsafe {core::mem::transmute(TypeId::of::<>())};
-            let key_override_metadata = [1];
+            let key_override_metadata = [K::VIAL_KEY_OVERRIDE_ENTRIES];
             let _ = database
                 .check_metadata(
                     K::get_storage_buffer(),
@@ -217,21 +455,70 @@ pub mod storage {
                     &key_override_metadata,
                 )
                 .await;
+            if let Ok((stored_data, stored_len)) = database
+                .read_raw(
+                    K::get_storage_buffer(),
+                    StorageKey::DynamicKeymapKeyOverride,
+                )
+                .await
+            {
+                for (entry, stored) in K::get_key_override_entries()
+                    .iter_mut()
+                    .zip(stored_data[..stored_len].chunks_exact(DYNAMIC_ENTRY_SIZE))
+                {
+                    *entry = KeyOverrideEntry::from_bytes(stored);
+                }
+            };
         }
 
         loop {
             match OPERATION_CHANNEL.receive().await {
-                Operation::Write(data, key, offset, len) => match key {
-                    VialStorageKeys::DynamicKeymapTapDance => {}
-                    VialStorageKeys::DynamicKeymapCombo => {}
-                    VialStorageKeys::DynamicKeymapKeyOverride => {}
-                },
+                Operation::Write(data, key, offset, len) => {
+                    let (mut tap_dance_buf, mut combo_buf, mut key_override_buf) = (
+                        [0; K::VIAL_TAP_DANCE_ENTRIES as usize * DYNAMIC_ENTRY_SIZE],
+                        [0; K::VIAL_COMBO_ENTRIES as usize * DYNAMIC_ENTRY_SIZE],
+                        [0; K::VIAL_KEY_OVERRIDE_ENTRIES as usize * DYNAMIC_ENTRY_SIZE],
+                    );
+                    let buf: &mut [u8] = match key {
+                        VialStorageKeys::DynamicKeymapTapDance => &mut tap_dance_buf,
+                        VialStorageKeys::DynamicKeymapCombo => &mut combo_buf,
+                        VialStorageKeys::DynamicKeymapKeyOverride => &mut key_override_buf,
+                    };
+                    let key = key.into();
+
+                    // Read data
+                    match database.read_raw(K::get_storage_buffer(), key).await {
+                        Ok((stored_data, stored_len)) => {
+                            buf[..stored_len].copy_from_slice(stored_data);
+                        }
+                        Err(StorageError::KeyNotFound) => {}
+                        Err(error) => {
+                            warn!(
+                                "[VIAL] Could not read dynamic entries: {}",
+                                Debug2Format(&error)
+                            );
+                        }
+                    };
+
+                    // Update data
+                    buf[offset..(offset + len)].copy_from_slice(&data[..len]);
+
+                    if let Err(error) = database.write_raw(K::get_storage_buffer(), key, buf).await
+                    {
+                        warn!(
+                            "[VIAL] Could not write dynamic entries: {}",
+                            Debug2Format(&error)
+                        )
+                    };
+                }
                 Operation::Delete => {
                     let _ = database.delete(StorageKey::DynamicKeymapTapDance).await;
                     let _ = database.delete(StorageKey::DynamicKeymapCombo).await;
                     let _ = database.delete(StorageKey::DynamicKeymapKeyOverride).await;
                 }
             }
+
+            OPERATION_COMPLETE.signal(())
         }
     }
 }
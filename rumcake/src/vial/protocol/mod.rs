@@ -155,22 +155,22 @@ pub(crate) async fn process_vial_command<K: VialKeyboard + 'static>(
                                             dynamic_keymap_get_number_of_entries::<K>(data)
                                         }
                                         VialDynamicValue::TapDanceGet => {
-                                            dynamic_keymap_get_tap_dance(data)
+                                            dynamic_keymap_get_tap_dance::<K>(data)
                                         }
                                         VialDynamicValue::TapDanceSet => {
-                                            dynamic_keymap_set_tap_dance(data)
+                                            dynamic_keymap_set_tap_dance::<K>(data).await
                                         }
                                         VialDynamicValue::ComboGet => {
-                                            dynamic_keymap_get_combo(data)
+                                            dynamic_keymap_get_combo::<K>(data)
                                         }
                                         VialDynamicValue::ComboSet => {
-                                            dynamic_keymap_set_combo(data)
+                                            dynamic_keymap_set_combo::<K>(data).await
                                         }
                                         VialDynamicValue::KeyOverrideGet => {
-                                            dynamic_keymap_get_key_override(data)
+                                            dynamic_keymap_get_key_override::<K>(data)
                                         }
                                         VialDynamicValue::KeyOverrideSet => {
-                                            dynamic_keymap_set_key_override(data)
+                                            dynamic_keymap_set_key_override::<K>(data).await
                                         }
                                     }
                                 }
@@ -311,7 +311,7 @@ pub(crate) async fn process_via_command<K: VialKeyboard + 'static>(
                 )
                 .await
             }
-            ViaCommandId::DynamicKeymapReset => dynamic_keymap_reset().await,
+            ViaCommandId::DynamicKeymapReset => dynamic_keymap_reset::<K>().await,
             ViaCommandId::CustomSetValue => {
                 match num::FromPrimitive::from_u8(data[1]) as Option<ViaLightingValue> {
                     #[cfg(feature = "simple-backlight")]
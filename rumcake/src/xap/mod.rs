@@ -0,0 +1,190 @@
+//! Support for QMK's XAP protocol (version 0.2.0).
+//!
+//! XAP is a request/response protocol that uses its own vendor-defined HID interface, separate
+//! from the raw HID interface used by Via and Vial. It allows host software to discover the
+//! capabilities of your keyboard, read and change your keymap, and manage stored settings.
+//!
+//! To use XAP, you will need to implement [`XapKeyboard`]. XAP reuses the keycode conversions
+//! and dynamic keymap storage of [`crate::via`], so you will also need to implement
+//! [`ViaKeyboard`].
+
+use defmt::{assert, info};
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::hw::mcu::RawMutex;
+use crate::keyboard::MATRIX_EVENTS;
+use crate::usb::USBKeyboard;
+use crate::via::ViaKeyboard;
+
+pub(crate) mod protocol;
+
+/// Size of XAP reports, in both directions.
+pub const XAP_REPORT_SIZE: usize = 64;
+
+/// Report descriptor used for XAP. Pulled from QMK.
+pub(crate) const XAP_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x51, 0xFF, // Usage Page (Vendor Defined)
+    0x09, 0x58, // Usage (Vendor Defined)
+    0xA1, 0x01, // Collection (Application)
+    // Data to host
+    0x09, 0x62, //   Usage (Vendor Defined)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x95, 0x40, //   Report Count
+    0x75, 0x08, //   Report Size (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    // Data from host
+    0x09, 0x63, //   Usage (Vendor Defined)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x95, 0x40, //   Report Count
+    0x75, 0x08, //   Report Size (8)
+    0x91, 0x83, //   Output (Data, Variable, Absolute, Volatile)
+    0xC0, // End Collection
+];
+
+/// A trait that keyboards must implement to use the XAP protocol.
+pub trait XapKeyboard: ViaKeyboard + USBKeyboard {
+    /// Whether XAP should allow secure routes (e.g. remapping keys, jumping to the bootloader, or
+    /// erasing stored settings) to be used without unlocking the keyboard first.
+    const XAP_INSECURE: bool = false;
+
+    /// Layout positions that must be held down at the same time to unlock your keyboard after
+    /// the host requests an unlock. Tuples in this array should be in the form of `(row, col)`.
+    /// The combination must have less than 16 keys.
+    const XAP_UNLOCK_COMBO: &'static [(u8, u8)];
+
+    /// Time to wait for the unlock combo to be pressed after the host requests an unlock, in
+    /// seconds. If the combo is not pressed in time, the keyboard will stay locked.
+    const XAP_UNLOCK_TIMEOUT_S: u64 = 30;
+
+    /// Version number of your keyboard, reported to the host in the board identifiers route.
+    const XAP_PRODUCT_VERSION: u16 = 0x0001;
+
+    /// Unique identifier for your keyboard, reported to the host in the board identifiers route.
+    const XAP_BOARD_IDENTIFIER: u32 = 0;
+
+    /// Hardware identifier for your keyboard. This can be used by the host to differentiate
+    /// between multiple keyboards of the same model.
+    const XAP_HARDWARE_ID: [u32; 4] = [0; 4];
+
+    /// Raw bytes for a gzip-compressed QMK `info.json` describing your keyboard. Host software
+    /// can read this to get information about your keyboard's layout. By default, no config blob
+    /// is provided.
+    const XAP_CONFIG_BLOB: &'static [u8] = &[];
+}
+
+/// Channel used to receive XAP reports from the host.
+pub(crate) static XAP_REPORT_RECEIVE_CHANNEL: Channel<RawMutex, [u8; XAP_REPORT_SIZE], 1> =
+    Channel::new();
+
+/// Channel used to send XAP reports to the host.
+pub(crate) static XAP_REPORT_SEND_CHANNEL: Channel<RawMutex, [u8; XAP_REPORT_SIZE], 1> =
+    Channel::new();
+
+/// Possible states of the XAP secure lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum SecureStatus {
+    Locked = 0,
+    Unlocking = 1,
+    Unlocked = 2,
+}
+
+pub(crate) struct XapState {
+    pub(crate) secure_status: SecureStatus,
+    unlock_started: Instant,
+    /// Which keys in [`XapKeyboard::XAP_UNLOCK_COMBO`] are currently held down.
+    held: [bool; 16],
+    /// Set by the bootloader jump route, so that the jump happens after the response is sent.
+    pub(crate) bootloader_jump_requested: bool,
+}
+
+impl XapState {
+    pub(crate) fn start_unlock(&mut self) {
+        self.secure_status = SecureStatus::Unlocking;
+        self.unlock_started = Instant::now();
+        self.held = [false; 16];
+    }
+}
+
+#[rumcake_macros::task]
+pub async fn xap_process_task<K: XapKeyboard + 'static>(_k: K)
+where
+    [(); K::LAYERS]:,
+    [(); K::LAYOUT_ROWS]:,
+    [(); K::LAYOUT_COLS]:,
+    [(); K::DYNAMIC_KEYMAP_MACRO_BUFFER_SIZE as usize]:,
+    [(); K::DYNAMIC_KEYMAP_MACRO_COUNT as usize]:,
+{
+    assert!(K::XAP_UNLOCK_COMBO.len() < 16);
+
+    let mut state = XapState {
+        secure_status: if K::XAP_INSECURE {
+            SecureStatus::Unlocked
+        } else {
+            SecureStatus::Locked
+        },
+        unlock_started: Instant::now(),
+        held: [false; 16],
+        bootloader_jump_requested: false,
+    };
+
+    let mut subscriber = MATRIX_EVENTS.subscriber().unwrap();
+
+    loop {
+        let unlocking = state.secure_status == SecureStatus::Unlocking;
+        let unlock_deadline = state.unlock_started + Duration::from_secs(K::XAP_UNLOCK_TIMEOUT_S);
+        let unlock_timeout = async move {
+            if unlocking {
+                Timer::at(unlock_deadline).await
+            } else {
+                core::future::pending().await
+            }
+        };
+
+        match select3(
+            XAP_REPORT_RECEIVE_CHANNEL.receive(),
+            subscriber.next_message_pure(),
+            unlock_timeout,
+        )
+        .await
+        {
+            Either3::First(mut report) => {
+                if protocol::process_xap_request::<K>(&mut report, &mut state).await {
+                    XAP_REPORT_SEND_CHANNEL.send(report).await;
+                }
+
+                if state.bootloader_jump_requested {
+                    // Give the host some time to read the response before jumping
+                    Timer::after(Duration::from_millis(500)).await;
+                    crate::hw::mcu::jump_to_bootloader();
+                }
+            }
+            Either3::Second(event) => {
+                if state.secure_status != SecureStatus::Unlocking {
+                    continue;
+                }
+
+                let coord = event.coord();
+                if let Some(idx) = K::XAP_UNLOCK_COMBO.iter().position(|key| *key == coord) {
+                    state.held[idx] = event.is_press();
+                }
+
+                if state.held[..K::XAP_UNLOCK_COMBO.len()]
+                    .iter()
+                    .all(|held| *held)
+                {
+                    info!("[XAP] Keyboard unlocked");
+                    state.secure_status = SecureStatus::Unlocked;
+                }
+            }
+            Either3::Third(()) => {
+                info!("[XAP] Unlock timed out");
+                state.secure_status = SecureStatus::Locked;
+            }
+        }
+    }
+}
@@ -0,0 +1,370 @@
+use defmt::{info, warn};
+
+use super::{SecureStatus, XapKeyboard, XapState, XAP_REPORT_SIZE};
+use crate::via::handlers::{
+    dynamic_keymap_get_encoder, dynamic_keymap_get_keycode, dynamic_keymap_set_encoder,
+    dynamic_keymap_set_keycode,
+};
+use crate::via::protocol::keycodes::{convert_action_to_keycode, convert_keycode_to_action};
+
+pub(crate) const XAP_BCD_VERSION: u32 = 0x00000200;
+
+const XAP_RESPONSE_FLAG_FAILED: u8 = 0;
+const XAP_RESPONSE_FLAG_SUCCESS: u8 = 1 << 0;
+const XAP_RESPONSE_FLAG_SECURE_FAILURE: u8 = 1 << 1;
+
+/// Size of the response header (token, response flags and payload length).
+const XAP_RESPONSE_HEADER_SIZE: usize = 4;
+
+/// Maximum size of a response payload.
+const XAP_MAX_PAYLOAD_SIZE: usize = XAP_REPORT_SIZE - XAP_RESPONSE_HEADER_SIZE;
+
+/// Size of each chunk returned by the config blob chunk route.
+const XAP_CONFIG_BLOB_CHUNK_SIZE: usize = 32;
+
+// Subsystem IDs
+const XAP_SUBSYSTEM: u8 = 0x00;
+const QMK_SUBSYSTEM: u8 = 0x01;
+const KEYMAP_SUBSYSTEM: u8 = 0x04;
+const REMAPPING_SUBSYSTEM: u8 = 0x05;
+
+// XAP subsystem routes
+const XAP_VERSION_QUERY: u8 = 0x00;
+const XAP_CAPABILITIES_QUERY: u8 = 0x01;
+const XAP_ENABLED_SUBSYSTEM_QUERY: u8 = 0x02;
+const XAP_SECURE_STATUS: u8 = 0x03;
+const XAP_SECURE_UNLOCK: u8 = 0x04;
+const XAP_SECURE_LOCK: u8 = 0x05;
+
+// QMK subsystem routes
+const QMK_VERSION_QUERY: u8 = 0x00;
+const QMK_CAPABILITIES_QUERY: u8 = 0x01;
+const QMK_BOARD_IDENTIFIERS: u8 = 0x02;
+const QMK_BOARD_MANUFACTURER: u8 = 0x03;
+const QMK_PRODUCT_NAME: u8 = 0x04;
+const QMK_CONFIG_BLOB_LEN: u8 = 0x05;
+const QMK_CONFIG_BLOB_CHUNK: u8 = 0x06;
+const QMK_BOOTLOADER_JUMP: u8 = 0x07;
+const QMK_HARDWARE_ID: u8 = 0x08;
+const QMK_REINIT_EEPROM: u8 = 0x09;
+
+// Keymap subsystem routes
+const KEYMAP_CAPABILITIES_QUERY: u8 = 0x01;
+const KEYMAP_GET_LAYER_COUNT: u8 = 0x02;
+const KEYMAP_GET_KEYMAP_KEYCODE: u8 = 0x03;
+const KEYMAP_GET_ENCODER_KEYCODE: u8 = 0x04;
+
+// Remapping subsystem routes
+const REMAPPING_CAPABILITIES_QUERY: u8 = 0x01;
+const REMAPPING_GET_DYNAMIC_LAYER_COUNT: u8 = 0x02;
+const REMAPPING_SET_KEYMAP_KEYCODE: u8 = 0x03;
+const REMAPPING_SET_ENCODER_KEYCODE: u8 = 0x04;
+
+/// Build a capabilities bitmask, where each bit corresponds to a supported route ID.
+const fn capabilities(routes: &[u8]) -> u32 {
+    let mut mask = 0;
+    let mut i = 0;
+    while i < routes.len() {
+        mask |= 1 << routes[i];
+        i += 1;
+    }
+    mask
+}
+
+/// The result of handling a route. On success, this contains the length of the response payload.
+enum RouteResult {
+    Success(usize),
+    Failed,
+    SecureFailure,
+}
+
+/// Process an XAP request in place, turning it into a response. Returns `false` if no response
+/// should be sent to the host.
+pub(crate) async fn process_xap_request<K: XapKeyboard + 'static>(
+    data: &mut [u8; XAP_REPORT_SIZE],
+    state: &mut XapState,
+) -> bool
+where
+    [(); K::LAYERS]:,
+    [(); K::LAYOUT_ROWS]:,
+    [(); K::LAYOUT_COLS]:,
+    [(); K::DYNAMIC_KEYMAP_MACRO_BUFFER_SIZE as usize]:,
+    [(); K::DYNAMIC_KEYMAP_MACRO_COUNT as usize]:,
+{
+    // Request: token (u16), payload length (u8), then the payload, which starts with the route
+    let token = u16::from_le_bytes([data[0], data[1]]);
+
+    // Tokens 0xFFFE and 0xFFFF are reserved for broadcast messages sent from the device
+    if token >= 0xFFFE {
+        return false;
+    }
+
+    let len = (data[2] as usize).min(XAP_REPORT_SIZE - 3);
+    let mut request = [0; XAP_REPORT_SIZE - 3];
+    request[..len].copy_from_slice(&data[3..(3 + len)]);
+
+    let mut payload = [0; XAP_MAX_PAYLOAD_SIZE];
+    let result = match (len, request[0]) {
+        (0, _) => RouteResult::Failed,
+        (_, XAP_SUBSYSTEM) => handle_xap_route::<K>(&request[1..len], &mut payload, state),
+        (_, QMK_SUBSYSTEM) => handle_qmk_route::<K>(&request[1..len], &mut payload, state),
+        (_, KEYMAP_SUBSYSTEM) => handle_keymap_route::<K>(&request[1..len], &mut payload).await,
+        (_, REMAPPING_SUBSYSTEM) => {
+            handle_remapping_route::<K>(&request[1..len], &mut payload, state).await
+        }
+        (_, subsystem) => {
+            warn!("[XAP] Unknown subsystem {:?}", subsystem);
+            RouteResult::Failed
+        }
+    };
+
+    // Response: token (u16), response flags (u8), payload length (u8), then the payload
+    data.fill(0);
+    data[0..=1].copy_from_slice(&token.to_le_bytes());
+    match result {
+        RouteResult::Success(payload_len) => {
+            data[2] = XAP_RESPONSE_FLAG_SUCCESS;
+            data[3] = payload_len as u8;
+            data[XAP_RESPONSE_HEADER_SIZE..(XAP_RESPONSE_HEADER_SIZE + payload_len)]
+                .copy_from_slice(&payload[..payload_len]);
+        }
+        RouteResult::Failed => data[2] = XAP_RESPONSE_FLAG_FAILED,
+        RouteResult::SecureFailure => data[2] = XAP_RESPONSE_FLAG_SECURE_FAILURE,
+    }
+
+    true
+}
+
+fn respond_u8(payload: &mut [u8], value: u8) -> RouteResult {
+    payload[0] = value;
+    RouteResult::Success(1)
+}
+
+fn respond_u32(payload: &mut [u8], value: u32) -> RouteResult {
+    payload[0..=3].copy_from_slice(&value.to_le_bytes());
+    RouteResult::Success(4)
+}
+
+fn respond_bytes(payload: &mut [u8], bytes: &[u8]) -> RouteResult {
+    let len = bytes.len().min(payload.len());
+    payload[..len].copy_from_slice(&bytes[..len]);
+    RouteResult::Success(len)
+}
+
+fn handle_xap_route<K: XapKeyboard>(
+    route: &[u8],
+    payload: &mut [u8],
+    state: &mut XapState,
+) -> RouteResult {
+    match route.first().copied() {
+        Some(XAP_VERSION_QUERY) => respond_u32(payload, XAP_BCD_VERSION),
+        Some(XAP_CAPABILITIES_QUERY) => respond_u32(
+            payload,
+            capabilities(&[
+                XAP_VERSION_QUERY,
+                XAP_CAPABILITIES_QUERY,
+                XAP_ENABLED_SUBSYSTEM_QUERY,
+                XAP_SECURE_STATUS,
+                XAP_SECURE_UNLOCK,
+                XAP_SECURE_LOCK,
+            ]),
+        ),
+        Some(XAP_ENABLED_SUBSYSTEM_QUERY) => respond_u32(
+            payload,
+            capabilities(&[
+                XAP_SUBSYSTEM,
+                QMK_SUBSYSTEM,
+                KEYMAP_SUBSYSTEM,
+                REMAPPING_SUBSYSTEM,
+            ]),
+        ),
+        Some(XAP_SECURE_STATUS) => respond_u8(payload, state.secure_status as u8),
+        Some(XAP_SECURE_UNLOCK) => {
+            if state.secure_status == SecureStatus::Locked {
+                info!("[XAP] Unlock requested, waiting for the unlock combo to be pressed");
+                state.start_unlock();
+            }
+            RouteResult::Success(0)
+        }
+        Some(XAP_SECURE_LOCK) => {
+            if !K::XAP_INSECURE {
+                state.secure_status = SecureStatus::Locked;
+            }
+            RouteResult::Success(0)
+        }
+        _ => RouteResult::Failed,
+    }
+}
+
+fn handle_qmk_route<K: XapKeyboard>(
+    route: &[u8],
+    payload: &mut [u8],
+    state: &mut XapState,
+) -> RouteResult {
+    match route.first().copied() {
+        // rumcake is not based on QMK, so there is no QMK version to report
+        Some(QMK_VERSION_QUERY) => respond_u32(payload, 0),
+        Some(QMK_CAPABILITIES_QUERY) => respond_u32(
+            payload,
+            capabilities(&[
+                QMK_VERSION_QUERY,
+                QMK_CAPABILITIES_QUERY,
+                QMK_BOARD_IDENTIFIERS,
+                QMK_BOARD_MANUFACTURER,
+                QMK_PRODUCT_NAME,
+                QMK_CONFIG_BLOB_LEN,
+                QMK_CONFIG_BLOB_CHUNK,
+                QMK_BOOTLOADER_JUMP,
+                QMK_HARDWARE_ID,
+                QMK_REINIT_EEPROM,
+            ]),
+        ),
+        Some(QMK_BOARD_IDENTIFIERS) => {
+            payload[0..=1].copy_from_slice(&K::USB_VID.to_le_bytes());
+            payload[2..=3].copy_from_slice(&K::USB_PID.to_le_bytes());
+            payload[4..=5].copy_from_slice(&K::XAP_PRODUCT_VERSION.to_le_bytes());
+            payload[6..=9].copy_from_slice(&K::XAP_BOARD_IDENTIFIER.to_le_bytes());
+            RouteResult::Success(10)
+        }
+        Some(QMK_BOARD_MANUFACTURER) => respond_bytes(payload, K::MANUFACTURER.as_bytes()),
+        Some(QMK_PRODUCT_NAME) => respond_bytes(payload, K::PRODUCT.as_bytes()),
+        Some(QMK_CONFIG_BLOB_LEN) => {
+            payload[0..=1].copy_from_slice(&(K::XAP_CONFIG_BLOB.len() as u16).to_le_bytes());
+            RouteResult::Success(2)
+        }
+        Some(QMK_CONFIG_BLOB_CHUNK) if route.len() >= 3 => {
+            let offset = u16::from_le_bytes([route[1], route[2]]) as usize;
+            let start = offset.min(K::XAP_CONFIG_BLOB.len());
+            let end = (offset + XAP_CONFIG_BLOB_CHUNK_SIZE).min(K::XAP_CONFIG_BLOB.len());
+            payload[..(end - start)].copy_from_slice(&K::XAP_CONFIG_BLOB[start..end]);
+            RouteResult::Success(XAP_CONFIG_BLOB_CHUNK_SIZE)
+        }
+        Some(QMK_BOOTLOADER_JUMP) => {
+            if state.secure_status != SecureStatus::Unlocked {
+                return RouteResult::SecureFailure;
+            }
+
+            state.bootloader_jump_requested = true;
+            respond_u8(payload, 1)
+        }
+        Some(QMK_HARDWARE_ID) => {
+            for (i, id) in K::XAP_HARDWARE_ID.iter().enumerate() {
+                payload[(i * 4)..(i * 4 + 4)].copy_from_slice(&id.to_le_bytes());
+            }
+            RouteResult::Success(16)
+        }
+        Some(QMK_REINIT_EEPROM) => {
+            if state.secure_status != SecureStatus::Unlocked {
+                return RouteResult::SecureFailure;
+            }
+
+            // Erase all stored settings. The storage service resets the keyboard once this is done.
+            #[cfg(feature = "storage")]
+            crate::storage::FACTORY_RESET_SIGNAL.signal(());
+
+            respond_u8(payload, cfg!(feature = "storage") as u8)
+        }
+        _ => RouteResult::Failed,
+    }
+}
+
+async fn handle_keymap_route<K: XapKeyboard + 'static>(
+    route: &[u8],
+    payload: &mut [u8],
+) -> RouteResult
+where
+    [(); K::LAYERS]:,
+    [(); K::LAYOUT_ROWS]:,
+    [(); K::LAYOUT_COLS]:,
+    [(); K::DYNAMIC_KEYMAP_MACRO_BUFFER_SIZE as usize]:,
+    [(); K::DYNAMIC_KEYMAP_MACRO_COUNT as usize]:,
+{
+    match route.first().copied() {
+        Some(KEYMAP_CAPABILITIES_QUERY) => respond_u32(
+            payload,
+            capabilities(&[
+                KEYMAP_CAPABILITIES_QUERY,
+                KEYMAP_GET_LAYER_COUNT,
+                KEYMAP_GET_KEYMAP_KEYCODE,
+                KEYMAP_GET_ENCODER_KEYCODE,
+            ]),
+        ),
+        Some(KEYMAP_GET_LAYER_COUNT) => respond_u8(payload, K::DYNAMIC_KEYMAP_LAYER_COUNT as u8),
+        Some(KEYMAP_GET_KEYMAP_KEYCODE) if route.len() >= 4 => {
+            // The Via handlers use big-endian keycodes, while XAP uses little-endian keycodes
+            dynamic_keymap_get_keycode::<K>(
+                route[1],
+                route[2],
+                route[3],
+                &mut payload[0..=1],
+                convert_action_to_keycode::<K>,
+            )
+            .await;
+            payload.swap(0, 1);
+            RouteResult::Success(2)
+        }
+        Some(KEYMAP_GET_ENCODER_KEYCODE) if route.len() >= 4 => {
+            dynamic_keymap_get_encoder::<K>(route[1], route[2], route[3] != 0, &mut payload[0..=1])
+                .await;
+            payload.swap(0, 1);
+            RouteResult::Success(2)
+        }
+        _ => RouteResult::Failed,
+    }
+}
+
+async fn handle_remapping_route<K: XapKeyboard + 'static>(
+    route: &[u8],
+    payload: &mut [u8],
+    state: &mut XapState,
+) -> RouteResult
+where
+    [(); K::LAYERS]:,
+    [(); K::LAYOUT_ROWS]:,
+    [(); K::LAYOUT_COLS]:,
+    [(); K::DYNAMIC_KEYMAP_MACRO_BUFFER_SIZE as usize]:,
+    [(); K::DYNAMIC_KEYMAP_MACRO_COUNT as usize]:,
+{
+    match route.first().copied() {
+        Some(REMAPPING_CAPABILITIES_QUERY) => respond_u32(
+            payload,
+            capabilities(&[
+                REMAPPING_CAPABILITIES_QUERY,
+                REMAPPING_GET_DYNAMIC_LAYER_COUNT,
+                REMAPPING_SET_KEYMAP_KEYCODE,
+                REMAPPING_SET_ENCODER_KEYCODE,
+            ]),
+        ),
+        Some(REMAPPING_GET_DYNAMIC_LAYER_COUNT) => {
+            respond_u8(payload, K::DYNAMIC_KEYMAP_LAYER_COUNT as u8)
+        }
+        Some(REMAPPING_SET_KEYMAP_KEYCODE | REMAPPING_SET_ENCODER_KEYCODE)
+            if state.secure_status != SecureStatus::Unlocked =>
+        {
+            RouteResult::SecureFailure
+        }
+        Some(REMAPPING_SET_KEYMAP_KEYCODE) if route.len() >= 6 => {
+            // Changes are saved by the Via storage task, if storage is enabled
+            dynamic_keymap_set_keycode::<K>(
+                route[1],
+                route[2],
+                route[3],
+                &[route[5], route[4]],
+                convert_keycode_to_action::<K>,
+            )
+            .await;
+            RouteResult::Success(0)
+        }
+        Some(REMAPPING_SET_ENCODER_KEYCODE) if route.len() >= 6 => {
+            dynamic_keymap_set_encoder::<K>(
+                route[1],
+                route[2],
+                route[3] != 0,
+                &[route[5], route[4]],
+            )
+            .await;
+            RouteResult::Success(0)
+        }
+        _ => RouteResult::Failed,
+    }
+}